@@ -31,6 +31,52 @@ typedef uint8_t DivansOptionSelect;
 #define DIVANS_OPTION_PRIOR_BITMASK_DETECTION 17
 #define DIVANS_OPTION_Q9_5 18
 #define DIVANS_OPTION_FORCE_LITERAL_CONTEXT_MODE 19
+#define DIVANS_OPTION_IR_OPTIMIZER 20
+/// 0 for frequentist CDFs, 1 for blended CDFs; any decoder build reads either
+#define DIVANS_OPTION_CDF_FLAVOR 21
+/// 0 for the ANS entropy coder, 1 for the range coder, which emits its output without chunked buffering
+#define DIVANS_OPTION_ENTROPY_CODER 22
+/// 1 to append the uncompressed length and its XXH64 so the decoder can check them
+#define DIVANS_OPTION_CONTENT_TRAILER 23
+/// compress independent frames on this many threads, producing a seekable stream;
+/// the frame compressors use the builtin rust allocator rather than the custom one
+#define DIVANS_OPTION_NUM_THREADS 24
+/// 1 (fastest) through 12 (smallest): sets quality, the IR optimizer, prior detection and context mixing
+/// together, so options set afterwards override the level
+#define DIVANS_OPTION_LEVEL 25
+/// 1 codes literals from a learned mix of every literal model: slower, usually smaller
+#define DIVANS_OPTION_LITERAL_MIXER 26
+/// 1 refines literal probabilities with secondary symbol estimation: slower, usually smaller
+#define DIVANS_OPTION_LITERAL_SSE 27
+/// log2 of the entries in a hashed order 2/3 literal table mixed into literals (turns on the mixer);
+/// 0 for none, at most 22, and raised to at least 10
+#define DIVANS_OPTION_LITERAL_HASH_SIZE 28
+/// 1 mixes in a prediction that literals continue the bytes after the last copy (turns on the mixer)
+#define DIVANS_OPTION_LITERAL_MATCH 29
+
+/// what the command decoder was doing when a failure happened, as reported by divans_decompressor_last_error
+typedef uint8_t DivansCodecState;
+
+#define DIVANS_CODEC_STATE_UNKNOWN 0
+#define DIVANS_CODEC_STATE_BEGIN 1
+#define DIVANS_CODEC_STATE_LITERAL 2
+#define DIVANS_CODEC_STATE_DICT 3
+#define DIVANS_CODEC_STATE_COPY 4
+#define DIVANS_CODEC_STATE_BLOCK_SWITCH_LITERAL 5
+#define DIVANS_CODEC_STATE_BLOCK_SWITCH_COMMAND 6
+#define DIVANS_CODEC_STATE_BLOCK_SWITCH_DISTANCE 7
+#define DIVANS_CODEC_STATE_PREDICTION_MODE 8
+#define DIVANS_CODEC_STATE_POPULATE_RING_BUFFER 9
+#define DIVANS_CODEC_STATE_SUCCESS 10
+#define DIVANS_CODEC_STATE_ENCODED_SHUTDOWN_NODE 11
+#define DIVANS_CODEC_STATE_SHUTDOWN_CODER 12
+#define DIVANS_CODEC_STATE_CODER_BUFFER_DRAIN 13
+#define DIVANS_CODEC_STATE_MUX_DRAIN 14
+#define DIVANS_CODEC_STATE_WRITE_CHECKSUM 15
+#define DIVANS_CODEC_STATE_SYNC_CODERS 16
+#define DIVANS_CODEC_STATE_SYNC_OUTPUT 17
+#define DIVANS_CODEC_STATE_SYNC_CODER_DRAIN 18
+#define DIVANS_CODEC_STATE_SYNC_MUX_DRAIN 19
 
 
 /// a struct specifying custom allocators for divans to use instead of the builtin rust allocators.
@@ -50,6 +96,17 @@ DivansResult divans_set_option(struct DivansCompressorState* state, DivansOption
 DivansResult divans_encode(struct DivansCompressorState* state,
                            const uint8_t *input_buf_ptr, size_t input_size, size_t*input_offset,
                            uint8_t *output_buf_ptr, size_t output_size, size_t *output_offset);
+// fills in the length and XXH64 of the decoded content once the trailer of a frame has been verified
+DivansResult divans_decompressed_content_summary(struct DivansDecompressorState* state,
+                                                 uint64_t *length, uint64_t *hash);
+// after divans_decode returns DIVANS_FAILURE, reports where in the stream the failure happened,
+// the DIVANS_CODEC_STATE_* the command decoder was in (DIVANS_CODEC_STATE_UNKNOWN if it could not
+// be inspected) and writes a NUL terminated description of it, truncated to message_size; any
+// pointer may be NULL
+DivansResult divans_decompressor_last_error(const struct DivansDecompressorState* state,
+                                            uint64_t *compressed_offset, uint64_t *decoded_offset,
+                                            uint64_t *command_index, DivansCodecState *codec_state,
+                                            char *message, size_t message_size);
 
 DivansResult divans_encode_flush(struct DivansCompressorState* state,
                                  uint8_t *output_buf_ptr, size_t output_size, size_t *output_offset);
@@ -58,7 +115,13 @@ void divans_free_compressor(struct DivansCompressorState* mfd);
 
 
 struct DivansDecompressorState* divans_new_decompressor();
-struct DivansDecompressorState* divans_new_decompressor_with_custom_alloc(struct CAllocator alloc, uint8_t skip_crc);
+struct DivansDecompressorState* divans_new_decompressor_with_custom_alloc(struct CAllocator alloc, uint8_t skip_crc, uint8_t multithread);
+// a zero max_window_size, max_output_size or max_memory_estimate leaves that resource unbounded.
+// max_memory_estimate is compared with what the decoder computes it will allocate for each frame and
+// literal; the allocator is not metered, so allocator overhead and small bookkeeping are not included
+struct DivansDecompressorState* divans_new_decompressor_with_limits(struct CAllocator alloc, uint8_t skip_crc, uint8_t multithread,
+                                                                    uint8_t max_window_size, uint64_t max_output_size,
+                                                                    size_t max_memory_estimate);
 DivansResult divans_decode(struct DivansDecompressorState* state,
                            const uint8_t *input_buf_ptr, size_t input_size, size_t*input_offset,
                            uint8_t *output_buf_ptr, size_t output_size, size_t *output_offset);
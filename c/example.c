@@ -59,7 +59,7 @@ DivansResult compress(const unsigned char *data, size_t len, struct VecU8 *ret_b
 DivansResult decompress(const unsigned char *data, size_t len, struct VecU8 *ret_buffer) {
     unsigned char buf[BUF_SIZE];
     struct CAllocator alloc = {custom_malloc, custom_free, custom_alloc_opaque};
-    struct DivansDecompressorState *state = divans_new_decompressor_with_custom_alloc(alloc, 0);
+    struct DivansDecompressorState *state = divans_new_decompressor_with_custom_alloc(alloc, 0, 1);
     DivansResult res;
     do {
         size_t read_offset = 0;
@@ -68,6 +68,10 @@ DivansResult decompress(const unsigned char *data, size_t len, struct VecU8 *ret
                             data, len, &read_offset,
                             buf, sizeof(buf), &buf_offset);
         if (res == DIVANS_FAILURE || (res == DIVANS_NEEDS_MORE_INPUT && len == 0)) {
+            char message[256];
+            if (divans_decompressor_last_error(state, NULL, NULL, NULL, NULL, message, sizeof(message)) == DIVANS_SUCCESS) {
+                fprintf(stderr, "%s\n", message);
+            }
             divans_free_decompressor(state);
             return res;
         }
@@ -29,6 +29,16 @@ fn main() {
                 stride_detection_quality: example_opts.stride_detection_quality,
                 prior_bitmask_detection: example_opts.prior_bitmask_detection,
                 divans_ir_optimizer:example_opts.divans_ir_optimizer,
+                cdf_flavor:example_opts.cdf_flavor, // whether the priors count or blend; chosen per stream
+                entropy_coder:example_opts.entropy_coder, // ANS or the range coder; chosen per stream
+                content_trailer:example_opts.content_trailer, // append the input length and XXH64 for the decoder to check
+                num_threads:example_opts.num_threads, // ignored by the serial writer
+                literal_mixer:example_opts.literal_mixer, // mix every literal model: slower, usually smaller
+                literal_sse:example_opts.literal_sse, // refine literal probabilities in a second stage
+                literal_hash_size:example_opts.literal_hash_size, // log2 size of a hashed order 2/3 literal table for the mixer
+                framed:example_opts.framed, // set by the framed writers on each frame they produce
+                literal_match:example_opts.literal_match, // let the mixer predict that literals continue the last copy
+                sync_points:example_opts.sync_points, // allow sync_flush, which the writer here never calls
             },
             4096, // internal buffer size
         );
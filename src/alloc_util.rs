@@ -160,12 +160,70 @@ impl<T, AllocT: Allocator<T>> Allocator<T> for RepurposingAlloc<T, AllocT> {
     }
 }
 
+// bytes that the LimitedAllocs sharing it may still hand out between them
+#[cfg(not(feature="no-stdlib"))]
+#[derive(Clone, Debug)]
+pub struct MemoryBudget(::std::sync::Arc<core::sync::atomic::AtomicUsize>);
+
+#[cfg(not(feature="no-stdlib"))]
+impl MemoryBudget {
+    pub fn new(max_bytes: usize) -> Self {
+        MemoryBudget(::std::sync::Arc::new(core::sync::atomic::AtomicUsize::new(max_bytes)))
+    }
+    pub fn remaining(&self) -> usize {
+        self.0.load(core::sync::atomic::Ordering::SeqCst)
+    }
+    fn reserve(&self, bytes: usize) -> bool {
+        self.0.fetch_update(core::sync::atomic::Ordering::SeqCst,
+                            core::sync::atomic::Ordering::SeqCst,
+                            |remaining| remaining.checked_sub(bytes)).is_ok()
+    }
+    fn release(&self, bytes: usize) {
+        self.0.fetch_add(bytes, core::sync::atomic::Ordering::SeqCst);
+    }
+}
+
+/// Passes allocations through to alloc while they fit in the budget it shares with other allocators;
+/// an allocation that does not fit comes back empty and leaves the budget as it was
+#[cfg(not(feature="no-stdlib"))]
+pub struct LimitedAlloc<T, AllocT: Allocator<T>> {
+    alloc: AllocT,
+    budget: MemoryBudget,
+    marker: core::marker::PhantomData<T>,
+}
+
+#[cfg(not(feature="no-stdlib"))]
+impl<T, AllocT: Allocator<T>> LimitedAlloc<T, AllocT> {
+    pub fn new(alloc: AllocT, budget: MemoryBudget) -> Self {
+        Self {
+            alloc,
+            budget,
+            marker: core::marker::PhantomData,
+        }
+    }
+}
+
+#[cfg(not(feature="no-stdlib"))]
+impl<T, AllocT: Allocator<T>> Allocator<T> for LimitedAlloc<T, AllocT> {
+    type AllocatedMemory = AllocT::AllocatedMemory;
+    fn alloc_cell(&mut self, size: usize) -> Self::AllocatedMemory {
+        match size.checked_mul(core::mem::size_of::<T>()) {
+            Some(bytes) if self.budget.reserve(bytes) => self.alloc.alloc_cell(size),
+            _ => Self::AllocatedMemory::default(),
+        }
+    }
+    fn free_cell(&mut self, bv: Self::AllocatedMemory) {
+        self.budget.release(core::mem::size_of_val(bv.slice()));
+        self.alloc.free_cell(bv)
+    }
+}
+
 #[cfg(test)]
 #[cfg(not(feature="no-stdlib"))]
 mod test {
     use core;
     use alloc::HeapAlloc;
-    use super::{Allocator, AllocatedMemoryPrefix, RepurposingAlloc, UninitializedOnAlloc};
+    use super::{Allocator, AllocatedMemoryPrefix, LimitedAlloc, MemoryBudget, RepurposingAlloc, SliceWrapper, UninitializedOnAlloc};
     struct LoggedAllocator<T, AllocT: Allocator<T>> {
         alloc: AllocT,
         count_alloc_cell: usize,
@@ -244,4 +302,29 @@ mod test {
         assert_eq!(cached_alloc.alloc.get_base_alloc().count_alloc_cell, 4);
         assert_eq!(cached_alloc.alloc.get_base_alloc().count_free_cell, 5);
     }
+
+    #[test]
+    fn test_limited_alloc() {
+        let budget = MemoryBudget::new(1000);
+        let mut m8 = LimitedAlloc::<u8, HeapAlloc<u8>>::new(HeapAlloc::<u8>::new(0u8), budget.clone());
+        let mut m32 = LimitedAlloc::<u32, HeapAlloc<u32>>::new(HeapAlloc::<u32>::new(0u32), budget.clone());
+        let bytes = m8.alloc_cell(600);
+        assert_eq!(bytes.slice().len(), 600);
+        // both allocators draw on the same budget, counted in bytes
+        let words = m32.alloc_cell(100);
+        assert_eq!(words.slice().len(), 100);
+        assert_eq!(budget.remaining(), 0);
+        // once it is spent, allocations come back empty and cost nothing
+        assert_eq!(m8.alloc_cell(1).slice().len(), 0);
+        assert_eq!(m32.alloc_cell(usize::MAX).slice().len(), 0);
+        assert_eq!(budget.remaining(), 0);
+        m8.free_cell(bytes);
+        assert_eq!(budget.remaining(), 600);
+        assert_eq!(m32.alloc_cell(151).slice().len(), 0);
+        let more_words = m32.alloc_cell(150);
+        assert_eq!(more_words.slice().len(), 150);
+        m32.free_cell(words);
+        m32.free_cell(more_words);
+        assert_eq!(budget.remaining(), 1000);
+    }
 }
@@ -474,6 +474,10 @@ impl EntropyDecoder for ANSDecoder {
     fn flush(&mut self) -> DivansResult {
         DivansResult::Success
     }
+    fn resync(&mut self) {
+        // a chunk that ends early leaves both states at ENC_START_STATE, so no refill is pending
+        *self = ANSDecoder::default();
+    }
 }
 
 impl<AllocU8: Allocator<u8>> ArithmeticEncoderOrDecoder for ANSEncoder<AllocU8> {
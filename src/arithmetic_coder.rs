@@ -173,6 +173,8 @@ pub trait EntropyDecoder {
         ret
     }
     fn flush(&mut self) -> DivansResult;
+    // the encoder flushed at this point of the stream: drop any state tied to the old chunk
+    fn resync(&mut self);
 }
 
 
@@ -214,6 +216,10 @@ impl<Decoder:EntropyDecoder+Clone> ArithmeticEncoderOrDecoder for Decoder {
     fn close(&mut self) -> DivansResult {
         self.flush()
     }
+    fn sync(&mut self) -> DivansResult {
+        self.resync();
+        DivansResult::Success
+    }
 }
 
 macro_rules! arithmetic_encoder_or_decoder_methods(
@@ -252,6 +258,10 @@ macro_rules! arithmetic_encoder_or_decoder_methods(
                 self.flush();
                 DivansResult::Success
             }
+            fn sync(&mut self) -> DivansResult {
+                self.flush();
+                DivansResult::Success
+            }
         }
 );
 
@@ -319,6 +329,8 @@ mod test {
         fn flush(&mut self) -> DivansResult {
             DivansResult::Success
         }
+        fn resync(&mut self) {
+        }
     }
     #[allow(unused)]
     fn test_get_prob<C: CDF16>(cdf: &C,
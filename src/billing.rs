@@ -17,7 +17,7 @@ use core::mem;
 use core::iter::FromIterator;
 use core::marker::PhantomData;
 use alloc::{Allocator};
-use interface::{ArithmeticEncoderOrDecoder, BillingDesignation, NewWithAllocator, BillingCapability};
+use interface::{ArithmeticEncoderOrDecoder, BillingDesignation, EntropyCoder, NewWithAllocator, BillingCapability};
 use super::probability::{CDF16, ProbRange};
 use interface::{DivansResult, ReadableBytes, WritableBytes};
 
@@ -41,6 +41,8 @@ macro_rules! println_stderr(
 pub struct BillingArithmeticCoder<AllocU8:Allocator<u8>, Coder:ArithmeticEncoderOrDecoder> {
     coder: Coder,
     counter: billing::HashMap<BillingDesignation, (f64, f64)>,
+    // what estimate_nibble was told, kept out of the totals since none of it was coded
+    estimates: billing::HashMap<BillingDesignation, (f64, f64)>,
     movd: bool,
     _phantom: PhantomData<AllocU8>,
 }
@@ -52,6 +54,16 @@ impl<AllocU8:Allocator<u8>,
        BillingArithmeticCoder::<AllocU8, Coder>{
            coder: Coder::new(m8),
            counter: billing::HashMap::new(),
+           estimates: billing::HashMap::new(),
+           movd:false,
+           _phantom:PhantomData::<AllocU8>::default(),
+       }
+   }
+   fn new_with_entropy_coder(m8: &mut AllocU8, entropy_coder: EntropyCoder) -> Self {
+       BillingArithmeticCoder::<AllocU8, Coder>{
+           coder: Coder::new_with_entropy_coder(m8, entropy_coder),
+           counter: billing::HashMap::new(),
+           estimates: billing::HashMap::new(),
            movd:false,
            _phantom:PhantomData::<AllocU8>::default(),
        }
@@ -86,7 +98,8 @@ impl<AllocU8:Allocator<u8>, Coder:ArithmeticEncoderOrDecoder> Drop for BillingAr
         if self.movd {
             return;
         }
-        let max_key_len = self.counter.keys().map(|k| format!("{:?}", k).len()).max().unwrap_or(5);
+        let max_key_len = self.counter.keys().map(|k| format!("{:?}", k).len())
+            .chain(self.estimates.keys().map(|k| format!("{:?} estimate", k).len())).max().unwrap_or(5);
         let report = |k, v: (f64, f64)| {
             println_stderr!("{1:0$} Bit count: {2:9.1} Byte count: {3:11.3} Virtual bits: {4:7.0}",
                             max_key_len, k, v.0, v.0 / 8.0, v.1);
@@ -103,6 +116,12 @@ impl<AllocU8:Allocator<u8>, Coder:ArithmeticEncoderOrDecoder> Drop for BillingAr
             total_vbits += v.1;
         }
         report(billing::String::from("Total"), (total_bits, total_vbits));
+
+        let mut sorted_estimates = billing::Vec::from_iter(self.estimates.iter());
+        sorted_estimates.sort_by_key(|&(k, _)| format!("{:?}", k));
+        for (k, v) in sorted_estimates {
+            report(format!("{:?} estimate", k), *v);
+        }
     }
 }
 
@@ -116,6 +135,7 @@ impl<AllocU8:Allocator<u8>, Coder:ArithmeticEncoderOrDecoder> ArithmeticEncoderO
        BillingArithmeticCoder::<AllocU8, Coder>{
            coder: self.coder.mov(),
            counter: mem::replace(&mut self.counter, billing::HashMap::new()),
+           estimates: mem::replace(&mut self.estimates, billing::HashMap::new()),
            movd: false,
            _phantom:PhantomData::<AllocU8>::default(),
        }
@@ -162,9 +182,21 @@ impl<AllocU8:Allocator<u8>, Coder:ArithmeticEncoderOrDecoder> ArithmeticEncoderO
         (*v).1 += 4.0;
         ret
     }
+    fn estimate_nibble<C: CDF16>(&mut self,
+                                 nibble: u8,
+                                 prob: &C,
+                                 billing: BillingDesignation) {
+        let actual_prob = prob.pdf(nibble) as f64 / (prob.max() as f64);
+        let v = self.estimates.entry(billing).or_insert((0.0, 0.0));
+        (*v).0 += -actual_prob.log2();
+        (*v).1 += 4.0;
+    }
     fn close(&mut self) -> DivansResult {
         self.coder.close()
     }
+    fn sync(&mut self) -> DivansResult {
+        self.coder.sync()
+    }
 }
 
 // only need to implement this for feature=billing, since it's defined for any T in the default case
@@ -177,21 +209,21 @@ impl<AllocU8:Allocator<u8>, Coder:ArithmeticEncoderOrDecoder> BillingCapability
 
 #[cfg(not(feature="billing"))]
 macro_rules! DefaultEncoderType(
-    () => {::ans::ANSEncoder<AllocU8>}
+    () => {::runtime_coder::RuntimeEncoder<AllocU8>}
 );
 
 #[cfg(not(feature="billing"))]
 macro_rules! DefaultDecoderType(
-    () => {::ans::ANSDecoder}
+    () => {::runtime_coder::RuntimeDecoder}
 );
 
 
 #[cfg(feature="billing")]
 macro_rules! DefaultEncoderType(
-    () => { ::billing::BillingArithmeticCoder<AllocU8, ::ans::ANSEncoder<AllocU8>> }
+    () => { ::billing::BillingArithmeticCoder<AllocU8, ::runtime_coder::RuntimeEncoder<AllocU8>> }
 );
 
 #[cfg(feature="billing")]
 macro_rules! DefaultDecoderType(
-    () => { ::billing::BillingArithmeticCoder<AllocU8, ::ans::ANSDecoder> }
+    () => { ::billing::BillingArithmeticCoder<AllocU8, ::runtime_coder::RuntimeDecoder> }
 );
@@ -276,7 +276,7 @@ fn bench_with_ir<Run: Runner,
         let mut decompress_lambda = || {
             dv_buffer.reset_read();
             rt_buffer.reset();
-            super::decompress(&mut dv_buffer, &mut rt_buffer, buffer_size,&mut[], false, MULTI).unwrap();
+            super::decompress(&mut dv_buffer, &mut rt_buffer, buffer_size,&mut[], false, MULTI, &[]).unwrap();
             let actual_ratio =  dv_buffer.written().len() as f64 / input_buffer.slice().len() as f64;
             if !(actual_ratio <= ratio) {
                 println!("Failed: actual buffer length {} dv_buffer size: {}", input_buffer.slice().len(), dv_buffer.written().len());
@@ -380,7 +380,7 @@ fn bench_no_ir<Run: Runner,
         } else {
             dv_buffer.reset_read();
             rt_buffer.reset();
-            super::decompress(&mut dv_buffer, &mut rt_buffer, buffer_size, &mut[], false, MULTI).unwrap();
+            super::decompress(&mut dv_buffer, &mut rt_buffer, buffer_size, &mut[], false, MULTI, &[]).unwrap();
             assert_eq!(rt_buffer.written(), input_buffer.slice());
             let actual_ratio =  dv_buffer.written().len() as f64 / input_buffer.slice().len() as f64;
             if !(actual_ratio <= ratio) {
@@ -595,3 +595,74 @@ fn bench_e2e_roundtrip_simple_100k(b: &mut Bencher) {
                 &mut BenchmarkPassthrough(b));
 
 }
+
+fn compress_with<C: Compressor>(compressor: &mut C, raw_file: &[u8], buffer_size: usize) -> Vec<u8> {
+    let mut output = vec![0u8; buffer_size];
+    let mut compressed = Vec::<u8>::new();
+    let mut input_offset = 0usize;
+    while input_offset < raw_file.len() {
+        let input_end = cmp::min(raw_file.len(), input_offset + buffer_size);
+        loop {
+            let mut output_offset = 0usize;
+            let ret = compressor.encode(&raw_file[..input_end], &mut input_offset, &mut output[..], &mut output_offset);
+            compressed.extend(&output[..output_offset]);
+            match ret {
+                divans::DivansResult::NeedsMoreInput => break,
+                divans::DivansResult::NeedsMoreOutput => {},
+                _ => panic!("encode returned {:?}", ret),
+            }
+        }
+    }
+    loop {
+        let mut output_offset = 0usize;
+        let ret = compressor.flush(&mut output[..], &mut output_offset);
+        compressed.extend(&output[..output_offset]);
+        match ret {
+            DivansOutputResult::Success => return compressed,
+            DivansOutputResult::NeedsMoreOutput => {},
+            DivansOutputResult::Failure(m) => panic!("flush failed {:?}", m),
+        }
+    }
+}
+
+// compresses raw_file either on one thread or with the literals coded on a second one
+fn bench_literal_thread<Run: Runner>(raw_file: &[u8], threaded: bool, runner: &mut Run) -> Vec<u8> {
+    let mut compressed = Vec::<u8>::new();
+    runner.iter(&mut || {
+        let opts = divans::DivansCompressorOptions::default();
+        compressed = if threaded {
+            compress_with(&mut divans::DivansLiteralThreadCompressor::new(opts), raw_file, 65536)
+        } else {
+            let mut compressor = DivansCompressorFactoryStruct::<ItemVecAllocator<u8>,
+                                                                 ItemVecAllocator<divans::DefaultCDF16>>::new(
+                ItemVecAllocator::<u8>::default(),
+                ItemVecAllocator::<u32>::default(),
+                ItemVecAllocator::<divans::DefaultCDF16>::default(),
+                opts,
+                ());
+            let ret = compress_with(&mut compressor, raw_file, 65536);
+            compressor.free();
+            ret
+        };
+    });
+    compressed
+}
+
+#[test]
+fn test_literal_thread_bench_matches_serial() {
+    let raw_file = include_bytes!("../../testdata/asyoulik");
+    assert_eq!(bench_literal_thread(&raw_file[..], true, &mut Passthrough{}),
+               bench_literal_thread(&raw_file[..], false, &mut Passthrough{}));
+}
+
+#[cfg(feature="benchmark")]
+#[bench]
+fn bench_e2e_compress_serial_asyoulik(b: &mut Bencher) {
+    bench_literal_thread(&include_bytes!("../../testdata/asyoulik")[..], false, &mut BenchmarkPassthrough(b));
+}
+
+#[cfg(feature="benchmark")]
+#[bench]
+fn bench_e2e_compress_literal_thread_asyoulik(b: &mut Bencher) {
+    bench_literal_thread(&include_bytes!("../../testdata/asyoulik")[..], true, &mut BenchmarkPassthrough(b));
+}
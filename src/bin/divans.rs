@@ -88,11 +88,24 @@ use std::path::Path;
 
 
 
+// the command line reports failures in words instead of panicking on them
+fn exit_with_error(e: io::Error) -> ! {
+    println_stderr!("Error: {}", e);
+    std::process::exit(1);
+}
+
+// for arguments that make no sense together or cannot be parsed
+fn exit_with_usage(message: &str) -> ! {
+    println_stderr!("Usage error: {}", message);
+    println_stderr!("Run divans -help for the list of options");
+    std::process::exit(2);
+}
+
 #[derive(Copy,Clone,Debug)]
 struct DivansErrMsg(pub divans::ErrMsg);
 impl core::fmt::Display for DivansErrMsg {
     fn fmt(&self, f:&mut core::fmt::Formatter) -> core::result::Result<(), core::fmt::Error> {
-        <divans::ErrMsg as core::fmt::Debug>::fmt(&self.0, f)
+        <divans::ErrMsg as core::fmt::Display>::fmt(&self.0, f)
     }
 }
 
@@ -853,7 +866,8 @@ fn compress_raw<Reader:std::io::Read,
                                        mut buffer_size: usize,
                                        use_brotli: bool,
                                        force_compress: bool,
-                                       multithread: bool) -> io::Result<()> {
+                                       multithread: bool,
+                                       prior_snapshot: &[u8]) -> io::Result<()> {
     let mut basic_buffer_backing = [0u8; 16];
     let basic_buffer: &mut[u8];
     if force_compress {
@@ -879,7 +893,7 @@ fn compress_raw<Reader:std::io::Read,
         }
     }
     if force_compress == false && is_divans(basic_buffer) {
-        return decompress(r, w, buffer_size, basic_buffer, false, multithread);
+        return decompress(r, w, buffer_size, basic_buffer, false, multithread, prior_snapshot);
     }
     let mut m8 = ItemVecAllocator::<u8>::default();
     if buffer_size == 0 {
@@ -887,8 +901,29 @@ fn compress_raw<Reader:std::io::Read,
     }
     let ibuffer = m8.alloc_cell(buffer_size);
     let obuffer = m8.alloc_cell(buffer_size);
-    if use_brotli {
-        let state =BrotliFactory::new(
+    if opts.num_threads > 1 {
+        let mut state = divans::DivansParallelCompressor::new(opts, divans::DEFAULT_FRAME_SIZE);
+        if !prior_snapshot.is_empty() {
+            if let Err(m) = state.load_prior_snapshot(prior_snapshot) {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, DivansErrMsg(m)));
+            }
+        }
+        let mut free_closure = |_state_to_free:divans::DivansParallelCompressor| ->ItemVecAllocator<u8> {ItemVecAllocator::<u8>::default()};
+        compress_raw_inner(r, w,
+                           ibuffer, obuffer,
+                           state,
+                           basic_buffer,
+                           &mut free_closure)
+    } else if multithread && !use_brotli && prior_snapshot.is_empty() {
+        let state = divans::DivansLiteralThreadCompressor::new(opts);
+        let mut free_closure = |_state_to_free:divans::DivansLiteralThreadCompressor| ->ItemVecAllocator<u8> {ItemVecAllocator::<u8>::default()};
+        compress_raw_inner(r, w,
+                           ibuffer, obuffer,
+                           state,
+                           basic_buffer,
+                           &mut free_closure)
+    } else if use_brotli {
+        let mut state =BrotliFactory::new(
             m8,
             ItemVecAllocator::<u32>::default(),
             ItemVecAllocator::<divans::DefaultCDF16>::default(),
@@ -911,6 +946,11 @@ fn compress_raw<Reader:std::io::Read,
              ItemVecAllocator::<brotli::enc::StaticCommand>::default(),
             ), 
         );
+        if !prior_snapshot.is_empty() {
+            if let Err(m) = state.load_prior_snapshot(prior_snapshot) {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, DivansErrMsg(m)));
+            }
+        }
         let mut free_closure = |state_to_free:<BrotliFactory as DivansCompressorFactory<ItemVecAllocator<u8>, ItemVecAllocator<u32>, ItemVecAllocator<divans::DefaultCDF16>>>::ConstructedCompressor| ->ItemVecAllocator<u8> {state_to_free.free().0};
         compress_raw_inner(r, w,
                            ibuffer, obuffer,
@@ -921,12 +961,17 @@ fn compress_raw<Reader:std::io::Read,
         type Factory = DivansCompressorFactoryStruct<
                 ItemVecAllocator<u8>,
                 ItemVecAllocator<divans::DefaultCDF16>>;
-        let state =Factory::new(
+        let mut state =Factory::new(
             m8,
             ItemVecAllocator::<u32>::default(),
             ItemVecAllocator::<divans::DefaultCDF16>::default(),
             opts, (),
         );
+        if !prior_snapshot.is_empty() {
+            if let Err(m) = state.load_prior_snapshot(prior_snapshot) {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, DivansErrMsg(m)));
+            }
+        }
         let mut free_closure = |state_to_free:<Factory as DivansCompressorFactory<ItemVecAllocator<u8>, ItemVecAllocator<u32>, ItemVecAllocator<divans::DefaultCDF16>>>::ConstructedCompressor| ->ItemVecAllocator<u8> {state_to_free.free().0};
         compress_raw_inner(r, w,
                            ibuffer, obuffer,
@@ -970,13 +1015,82 @@ fn compress_ir<Reader:std::io::BufRead,
     compress_inner(state, r, w)
 }
 
+fn recompress_brotli_file<Reader:std::io::Read, Writer:std::io::Write>(r:&mut Reader,
+                                                                       w:&mut Writer,
+                                                                       opts: divans::DivansCompressorOptions) -> io::Result<()> {
+    let mut brotli_file = Vec::<u8>::new();
+    try!(r.read_to_end(&mut brotli_file));
+    match divans::recompress_brotli(&brotli_file[..], opts) {
+        Ok(recompressed) => w.write_all(&recompressed[..]),
+        Err(m) => Err(io::Error::new(io::ErrorKind::InvalidInput, DivansErrMsg(m))),
+    }
+}
+
+fn recompress_deflate_file<Reader:std::io::Read, Writer:std::io::Write>(r:&mut Reader,
+                                                                        w:&mut Writer,
+                                                                        opts: divans::DivansCompressorOptions) -> io::Result<()> {
+    let mut deflate_file = Vec::<u8>::new();
+    try!(r.read_to_end(&mut deflate_file));
+    match divans::recompress_deflate(&deflate_file[..], opts) {
+        Ok(recompressed) => w.write_all(&recompressed[..]),
+        Err(m) => Err(io::Error::new(io::ErrorKind::InvalidInput, DivansErrMsg(m))),
+    }
+}
+
+fn transcode_to_brotli_file<Reader:std::io::Read, Writer:std::io::Write>(r:&mut Reader,
+                                                                         w:&mut Writer) -> io::Result<()> {
+    let mut divans_file = Vec::<u8>::new();
+    try!(r.read_to_end(&mut divans_file));
+    match divans::transcode_to_brotli(&divans_file[..]) {
+        Ok(brotli_file) => w.write_all(&brotli_file[..]),
+        Err(m) => Err(io::Error::new(io::ErrorKind::InvalidInput, DivansErrMsg(m))),
+    }
+}
+
+// recompressed brotli and DEFLATE files can only be told apart by their magic number
+fn read_magic_prefix<Reader:std::io::Read>(r:&mut Reader, additional_input: &[u8]) -> io::Result<Vec<u8>> {
+    let mut prefix = additional_input.to_vec();
+    while prefix.len() < divans::interface::RECOMPRESSED_BROTLI_MAGIC.len() {
+        let mut byte = [0u8];
+        match r.read(&mut byte[..]) {
+            Ok(0) => break,
+            Ok(_) => prefix.push(byte[0]),
+            Err(e) => {
+                if e.kind() == io::ErrorKind::Interrupted {
+                    continue;
+                }
+                return Err(e);
+            }
+        }
+    }
+    Ok(prefix)
+}
+
 fn decompress<Reader:std::io::Read, Writer:std::io::Write>(r:&mut Reader,
                                                            w:&mut Writer,
                                                            buffer_size: usize,
                                                            additional_input: &mut[u8],
                                                            skip_crc: bool,
-                                                           multithread:bool,) -> io::Result<()>
+                                                           multithread:bool,
+                                                           prior_snapshot: &[u8]) -> io::Result<()>
 {
+    let mut prefix = try!(read_magic_prefix(r, additional_input));
+    if prefix.len() >= divans::interface::RECOMPRESSED_BROTLI_MAGIC.len()
+        && prefix[..divans::interface::RECOMPRESSED_BROTLI_MAGIC.len()] == divans::interface::RECOMPRESSED_BROTLI_MAGIC[..] {
+        try!(r.read_to_end(&mut prefix));
+        return match divans::reconstruct_brotli(&prefix[..]) {
+            Ok(brotli_file) => w.write_all(&brotli_file[..]),
+            Err(m) => Err(io::Error::new(io::ErrorKind::InvalidInput, DivansErrMsg(m))),
+        };
+    }
+    if prefix.len() >= divans::interface::RECOMPRESSED_DEFLATE_MAGIC.len()
+        && prefix[..divans::interface::RECOMPRESSED_DEFLATE_MAGIC.len()] == divans::interface::RECOMPRESSED_DEFLATE_MAGIC[..] {
+        try!(r.read_to_end(&mut prefix));
+        return match divans::reconstruct_deflate(&prefix[..]) {
+            Ok(deflate_file) => w.write_all(&deflate_file[..]),
+            Err(m) => Err(io::Error::new(io::ErrorKind::InvalidInput, DivansErrMsg(m))),
+        };
+    }
     let ret;
     let mut state = DivansDecompressorFactoryStruct::<ItemVecAllocator<u8>, ItemVecAllocator<divans::DefaultCDF16>, ItemVecAllocator<StaticCommand>>::new(
         ItemVecAllocator::<u8>::default(),
@@ -985,17 +1099,47 @@ fn decompress<Reader:std::io::Read, Writer:std::io::Write>(r:&mut Reader,
         skip_crc,
         multithread,
     );
-    
+    if !prior_snapshot.is_empty() {
+        if let Err(m) = state.load_prior_snapshot(prior_snapshot) {
+            state.free();
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, DivansErrMsg(m)));
+        }
+    }
     ret = decompress_generic(
         r,
         w,
         &mut state,
-        additional_input,
+        &mut prefix[..],
         buffer_size);
+    // report where in the stream a decode failure happened rather than just what it was
+    let ret = match state.last_error() {
+        Some(located) if ret.is_err() => Err(io::Error::new(io::ErrorKind::InvalidInput, located)),
+        _ => ret,
+    };
     state.free();
     ret
 }
 
+// decodes the whole input, checksum included, without writing the result anywhere; the inner
+// error names what is corrupt while the outer one reports a failure to read the input
+fn verify<Reader:std::io::Read>(r:&mut Reader,
+                                buffer_size: usize,
+                                multithread:bool,
+                                prior_snapshot: &[u8]) -> io::Result<Result<u64, Box<error::Error>>> {
+    let mut prefix = try!(read_magic_prefix(r, &[]));
+    if prefix.len() >= divans::interface::RECOMPRESSED_BROTLI_MAGIC.len()
+        && prefix[..divans::interface::RECOMPRESSED_BROTLI_MAGIC.len()] == divans::interface::RECOMPRESSED_BROTLI_MAGIC[..] {
+        try!(r.read_to_end(&mut prefix));
+        return Ok(divans::reconstruct_brotli(&prefix[..]).map(|brotli_file| brotli_file.len() as u64).map_err(Box::from));
+    }
+    if prefix.len() >= divans::interface::RECOMPRESSED_DEFLATE_MAGIC.len()
+        && prefix[..divans::interface::RECOMPRESSED_DEFLATE_MAGIC.len()] == divans::interface::RECOMPRESSED_DEFLATE_MAGIC[..] {
+        try!(r.read_to_end(&mut prefix));
+        return Ok(divans::reconstruct_deflate(&prefix[..]).map(|deflate_file| deflate_file.len() as u64).map_err(Box::from));
+    }
+    divans::verify_stream(io::Read::chain(&prefix[..], r), buffer_size, multithread,
+                          divans::DivansDecompressorLimits::default(), prior_snapshot).map(|ret| ret.map_err(Box::from))
+}
 
 #[allow(unused_assignments)]
 fn decompress_generic<Reader:std::io::Read,
@@ -1193,8 +1337,23 @@ fn main() {
     let mut do_compress = true;
     let mut raw_compress = true;
     let mut q9_5 = false;
-    let mut divans_ir_optimizer = false;
+    let mut divans_ir_optimizer = 0u8;
+    let mut cdf_flavor: Option<divans::CDFFlavor> = None;
+    let mut entropy_coder: Option<divans::EntropyCoder> = None;
+    let mut content_trailer = false;
+    let mut num_threads = 1u32;
+    let mut literal_mixer = false;
+    let mut literal_sse = false;
+    let mut literal_match = false;
+    let mut literal_hash_size: Option<u8> = None;
     let mut do_recode = false;
+    let mut do_verify = false;
+    let mut do_salvage = false;
+    let mut do_train = false;
+    let mut prior_snapshot = Vec::<u8>::new();
+    let mut do_recompress_brotli = false;
+    let mut do_transcode_to_brotli = false;
+    let mut do_recompress_deflate = false;
     let mut filenames = [std::string::String::new(), std::string::String::new()];
     let mut num_benchmarks = 1;
     let mut use_context_map = true;
@@ -1223,6 +1382,16 @@ fn main() {
                     do_compress = false;
                     continue;
                 }
+                if argument == "-t" {
+                    do_verify = true;
+                    do_compress = false;
+                    continue;
+                }
+                if argument == "-salvage" {
+                    do_salvage = true;
+                    do_compress = false;
+                    continue;
+                }
                 if argument == "-serial" {
                     parallel = false;
                     continue;
@@ -1239,6 +1408,18 @@ fn main() {
                     doubledash = true;
                     continue;
                 }
+                if argument.len() > 1 && argument.starts_with('-') && argument.split_at(1).1.bytes().all(|b| b.is_ascii_digit()) {
+                    let preset = match argument.split_at(1).1.parse::<u8>().map(divans::DivansCompressorOptions::level) {
+                        Ok(Ok(preset)) => preset,
+                        _ => exit_with_usage("compression levels run from -1 to -12"),
+                    };
+                    quality = preset.quality;
+                    q9_5 = preset.q9_5;
+                    divans_ir_optimizer = preset.divans_ir_optimizer;
+                    prior_bitmask_detection = preset.prior_bitmask_detection != 0;
+                    dynamic_context_mixing = preset.dynamic_context_mixing;
+                    continue;
+                }
                 if argument.starts_with("-bytescore") {
                     brotli_literal_byte_score = Some(argument.trim_matches(
                         '-').trim_matches(
@@ -1296,6 +1477,18 @@ fn main() {
                     do_recode = true;
                     continue;
                 }
+                if argument == "-recompressbrotli" {
+                    do_recompress_brotli = true;
+                    continue;
+                }
+                if argument == "-recompressdeflate" {
+                    do_recompress_deflate = true;
+                    continue;
+                }
+                if argument == "-tobrotli" {
+                    do_transcode_to_brotli = true;
+                    continue;
+                }
                 if argument.starts_with("-lgwin") {
                     let fs = argument.trim_matches(
                         '-').trim_matches(
@@ -1331,6 +1524,16 @@ fn main() {
                     quality=Some(fs);
                     continue;
                 }
+                if argument.starts_with("-priors=") {
+                    let filename = argument.split_at("-priors=".len()).1;
+                    match File::open(&Path::new(filename)) {
+                        Err(why) => panic!("couldn't open {:}\n{:}", filename, why),
+                        Ok(mut file) => if let Err(e) = io::Read::read_to_end(&mut file, &mut prior_snapshot) {
+                            exit_with_error(e);
+                        },
+                    }
+                    continue;
+                }
                 if argument.starts_with("-p") {
                     let fs = argument.trim_matches(
                         '-').trim_matches(
@@ -1425,9 +1628,14 @@ fn main() {
                     continue;
                 }
                 if argument.starts_with("-O") {
-                    if argument != "-O0" {
-                        divans_ir_optimizer = true
-                    }
+                    divans_ir_optimizer = if argument == "-O" {
+                        1
+                    } else {
+                        match argument.split_at("-O".len()).1.parse::<u8>() {
+                            Ok(level) => level,
+                            Err(_) => exit_with_usage("-O takes the optimizer level: -O0, -O1 or -O2"),
+                        }
+                    };
                     continue;
                 }
                 if argument == "-c" {
@@ -1462,6 +1670,57 @@ fn main() {
                         '=').parse::<i32>().unwrap() as u8);
                     continue
                 }
+                if argument == "-blend" {
+                    cdf_flavor = Some(divans::CDFFlavor::Blend);
+                    continue;
+                }
+                if argument == "-frequentist" {
+                    cdf_flavor = Some(divans::CDFFlavor::Frequentist);
+                    continue;
+                }
+                if argument == "-range" {
+                    entropy_coder = Some(divans::EntropyCoder::Range);
+                    continue;
+                }
+                if argument == "-ans" {
+                    entropy_coder = Some(divans::EntropyCoder::ANS);
+                    continue;
+                }
+                if argument == "-trailer" {
+                    content_trailer = true;
+                    continue;
+                }
+                if argument == "-mixer" {
+                    literal_mixer = true;
+                    continue;
+                }
+                if argument == "-match" {
+                    literal_match = true;
+                    continue;
+                }
+                if argument == "-sse" {
+                    literal_sse = true;
+                    continue;
+                }
+                if argument == "-train" {
+                    do_train = true;
+                    do_compress = false;
+                    continue;
+                }
+                if argument.starts_with("-hashsize=") {
+                    literal_hash_size = match argument.split_at("-hashsize=".len()).1.parse::<u8>() {
+                        Ok(size) => Some(size),
+                        Err(_) => exit_with_usage("-hashsize= takes the log2 of the literal table size"),
+                    };
+                    continue;
+                }
+                if argument.starts_with("-threads=") {
+                    num_threads = match argument.split_at("-threads=".len()).1.parse::<u32>() {
+                        Ok(threads) => threads,
+                        Err(_) => exit_with_usage("-threads= takes the number of compression threads"),
+                    };
+                    continue;
+                }
                 if argument == "-findspeed" {
                     speed_detection_quality = Some(1);
                     continue;
@@ -1561,6 +1820,19 @@ fn main() {
                 if argument == "-h" || argument == "-help" || argument == "--help" {
                     println_stderr!("Compression: divans {{-c [raw_input_file] | -i [ir_file]}} [output_file]");
                     println_stderr!("Decompression: divans [input_file] [output_file]");
+                    println_stderr!("Integrity test: divans -t [input_file]");
+                    println_stderr!("Compression on N threads, in independently decodable frames: divans -c -threads=N [raw_input_file] [output_file]");
+                    println_stderr!("Compression level from fastest to smallest: divans -c {{-1 .. -12}} [raw_input_file] [output_file]");
+                    println_stderr!("Compression mixing every literal model (slower, smaller): divans -c -mixer [raw_input_file] [output_file]");
+                    println_stderr!("Compression mixing in a prediction that literals continue the last copy (implies -mixer): divans -c -match [raw_input_file] [output_file]");
+                    println_stderr!("Compression refining literal probabilities (slower, smaller): divans -c -sse [raw_input_file] [output_file]");
+                    println_stderr!("Compression mixing in a hashed order 2/3 literal table of 2^N entries: divans -c -hashsize=N [raw_input_file] [output_file]");
+                    println_stderr!("Training a prior snapshot on a sample file: divans -train [sample_file] [snapshot_file]");
+                    println_stderr!("Compression or decompression starting from a prior snapshot: divans -priors=snapshot_file ...");
+                    println_stderr!("Recovery of a damaged file: divans -salvage [input_file] [output_file]");
+                    println_stderr!("Brotli recompression: divans -recompressbrotli [brotli_file] [output_file]");
+                    println_stderr!("DEFLATE/zlib/gzip recompression: divans -recompressdeflate [deflate_file] [output_file]");
+                    println_stderr!("Conversion to brotli: divans -tobrotli [divans_file] [output_file]");
                     return;
                 }
                 if argument == "-v" || argument == "-version" || argument == "--version" {
@@ -1599,8 +1871,109 @@ fn main() {
             speed_detection_quality: speed_detection_quality,
             prior_bitmask_detection: if prior_bitmask_detection {1} else {0},
             force_literal_context_mode: force_literal_context_mode,
-            divans_ir_optimizer: if divans_ir_optimizer {1} else {0},
+            divans_ir_optimizer: divans_ir_optimizer,
+            cdf_flavor: cdf_flavor,
+            entropy_coder: entropy_coder,
+            content_trailer: content_trailer,
+            num_threads: num_threads,
+            literal_mixer: literal_mixer,
+            literal_sse: literal_sse,
+            literal_hash_size: literal_hash_size,
+            framed: false,
+            literal_match: literal_match,
+            sync_points: false,
         };
+        if do_verify {
+            if filenames[1] != "" {
+                exit_with_usage("divans -t takes a single input file and writes no output");
+            }
+            let result = if filenames[0] != "" {
+                match File::open(&Path::new(&filenames[0])) {
+                    Err(why) => panic!("couldn't open {:}\n{:}", filenames[0], why),
+                    Ok(mut file) => verify(&mut file, buffer_size, parallel, &prior_snapshot[..]),
+                }
+            } else {
+                verify(&mut io::stdin(), buffer_size, parallel, &prior_snapshot[..])
+            };
+            let name = if filenames[0] != "" { &filenames[0][..] } else { "-" };
+            match result {
+                Ok(Ok(_)) => println!("{}: OK", name),
+                Ok(Err(m)) => {
+                    println_stderr!("{}: corrupt: {}", name, m);
+                    std::process::exit(1);
+                },
+                Err(e) => exit_with_error(e),
+            }
+            return;
+        }
+        if do_salvage {
+            let mut compressed = Vec::<u8>::new();
+            let read = if filenames[0] != "" {
+                match File::open(&Path::new(&filenames[0])) {
+                    Err(why) => panic!("couldn't open {:}\n{:}", filenames[0], why),
+                    Ok(mut file) => io::Read::read_to_end(&mut file, &mut compressed),
+                }
+            } else {
+                io::Read::read_to_end(&mut io::stdin(), &mut compressed)
+            };
+            if let Err(e) = read {
+                exit_with_error(e);
+            }
+            let limits = divans::DivansDecompressorLimits::default();
+            let result = if filenames[1] != "" {
+                match File::create(&Path::new(&filenames[1])) {
+                    Err(why) => panic!("couldn't open file for writing: {:}\n{:}", filenames[1], why),
+                    Ok(mut file) => divans::salvage_stream(&compressed[..], &mut file, buffer_size, limits),
+                }
+            } else {
+                divans::salvage_stream(&compressed[..], &mut io::stdout(), buffer_size, limits)
+            };
+            let name = if filenames[0] != "" { &filenames[0][..] } else { "-" };
+            match result {
+                Ok(report) => {
+                    for damage in report.damaged.iter() {
+                        println_stderr!("{}: skipped compressed bytes {}..{}; output is incomplete after byte {}: {}",
+                                        name, damage.compressed_start, damage.compressed_end, damage.decoded_offset,
+                                        damage.error.msg);
+                    }
+                    if !report.damaged.is_empty() {
+                        std::process::exit(1);
+                    }
+                },
+                Err(e) => exit_with_error(e),
+            }
+            return;
+        }
+        if do_train {
+            let mut sample = Vec::<u8>::new();
+            let read = if filenames[0] != "" {
+                match File::open(&Path::new(&filenames[0])) {
+                    Err(why) => panic!("couldn't open {:}\n{:}", filenames[0], why),
+                    Ok(mut file) => io::Read::read_to_end(&mut file, &mut sample),
+                }
+            } else {
+                io::Read::read_to_end(&mut io::stdin(), &mut sample)
+            };
+            if let Err(e) = read {
+                exit_with_error(e);
+            }
+            let snapshot = match divans::train_prior_snapshot(&[&sample[..]], opts) {
+                Ok(snapshot) => snapshot,
+                Err(e) => exit_with_error(e),
+            };
+            let written = if filenames[1] != "" {
+                match File::create(&Path::new(&filenames[1])) {
+                    Err(why) => panic!("couldn't open file for writing: {:}\n{:}", filenames[1], why),
+                    Ok(mut file) => file.write_all(&snapshot[..]),
+                }
+            } else {
+                io::stdout().write_all(&snapshot[..])
+            };
+            if let Err(e) = written {
+                exit_with_error(e);
+            }
+            return;
+        }
         if filenames[0] != "" {
             let mut input = match File::open(&Path::new(&filenames[0])) {
                 Err(why) => panic!("couldn't open {:}\n{:}", filenames[0], why),
@@ -1612,20 +1985,35 @@ fn main() {
                     Ok(file) => file,
                 };
                 for i in 0..num_benchmarks {
-                    if do_compress && !raw_compress {
+                    if do_recompress_brotli {
+                        match recompress_brotli_file(&mut input, &mut output, opts) {
+                            Ok(_) => {}
+                            Err(e) => exit_with_error(e),
+                        }
+                    } else if do_recompress_deflate {
+                        match recompress_deflate_file(&mut input, &mut output, opts) {
+                            Ok(_) => {}
+                            Err(e) => exit_with_error(e),
+                        }
+                    } else if do_transcode_to_brotli {
+                        match transcode_to_brotli_file(&mut input, &mut output) {
+                            Ok(_) => {}
+                            Err(e) => exit_with_error(e),
+                        }
+                    } else if do_compress && !raw_compress {
                         let mut buffered_input = BufReader::new(input);
                         match compress_ir(&mut buffered_input, &mut output, opts) {
                             Ok(_) => {}
-                            Err(e) => panic!("Error {:?}", e),
+                            Err(e) => exit_with_error(e),
                         }
                         input = buffered_input.into_inner();
                     } else if do_compress {
                         match compress_raw(&mut input,
                                            &mut output,
                                            opts,
-                                           buffer_size, use_brotli, force_compress, parallel) {
+                                           buffer_size, use_brotli, force_compress, parallel, &prior_snapshot[..]) {
                             Ok(_) => {}
-                            Err(e) => panic!("Error {:?}", e),
+                            Err(e) => exit_with_error(e),
                         }
                     } else if do_recode {
                         let mut buffered_input = BufReader::new(input);
@@ -1633,9 +2021,9 @@ fn main() {
                                &mut output).unwrap();
                         input = buffered_input.into_inner();
                     } else {
-                        match decompress(&mut input, &mut output, buffer_size, &mut [], skip_crc, parallel) {
+                        match decompress(&mut input, &mut output, buffer_size, &mut [], skip_crc, parallel, &prior_snapshot[..]) {
                             Ok(_) => {}
-                            Err(e) => panic!("Error {:?}", e),
+                            Err(e) => exit_with_error(e),
                         }
                     }
                     if i + 1 != num_benchmarks {
@@ -1646,49 +2034,79 @@ fn main() {
                 drop(output);
             } else {
                 assert_eq!(num_benchmarks, 1);
-                if do_compress && !raw_compress {
+                if do_recompress_brotli {
+                    match recompress_brotli_file(&mut input, &mut io::stdout(), opts) {
+                        Ok(_) => {}
+                        Err(e) => exit_with_error(e),
+                    }
+                } else if do_recompress_deflate {
+                    match recompress_deflate_file(&mut input, &mut io::stdout(), opts) {
+                        Ok(_) => {}
+                        Err(e) => exit_with_error(e),
+                    }
+                } else if do_transcode_to_brotli {
+                    match transcode_to_brotli_file(&mut input, &mut io::stdout()) {
+                        Ok(_) => {}
+                        Err(e) => exit_with_error(e),
+                    }
+                } else if do_compress && !raw_compress {
                     let mut buffered_input = BufReader::new(input);
                     match compress_ir (&mut buffered_input, &mut io::stdout(), opts) {
                         Ok(_) => {}
-                        Err(e) => panic!("Error {:?}", e),
+                        Err(e) => exit_with_error(e),
                     }
                 } else if do_compress {
                     match compress_raw(&mut input,
                                        &mut io::stdout(),
                                        opts,
                                        buffer_size,
-                                       use_brotli, force_compress, parallel) {
+                                       use_brotli, force_compress, parallel, &prior_snapshot[..]) {
                         Ok(_) => {}
-                        Err(e) => panic!("Error {:?}", e),
+                        Err(e) => exit_with_error(e),
                     }
                 } else if do_recode {
                     let mut buffered_input = BufReader::new(input);
                     recode(&mut buffered_input,
                            &mut io::stdout()).unwrap()
                 } else {
-                    match decompress(&mut input, &mut io::stdout(), buffer_size, &mut [], skip_crc, parallel) {
+                    match decompress(&mut input, &mut io::stdout(), buffer_size, &mut [], skip_crc, parallel, &prior_snapshot[..]) {
                         Ok(_) => {}
-                        Err(e) => panic!("Error {:?}", e),
+                        Err(e) => exit_with_error(e),
                     }
                 }
             }
         } else {
             assert_eq!(num_benchmarks, 1);
-            if do_compress && !raw_compress {
+            if do_recompress_brotli {
+                match recompress_brotli_file(&mut std::io::stdin(), &mut io::stdout(), opts) {
+                    Ok(_) => return,
+                    Err(e) => exit_with_error(e),
+                }
+            } else if do_recompress_deflate {
+                match recompress_deflate_file(&mut std::io::stdin(), &mut io::stdout(), opts) {
+                    Ok(_) => return,
+                    Err(e) => exit_with_error(e),
+                }
+            } else if do_transcode_to_brotli {
+                match transcode_to_brotli_file(&mut std::io::stdin(), &mut io::stdout()) {
+                    Ok(_) => return,
+                    Err(e) => exit_with_error(e),
+                }
+            } else if do_compress && !raw_compress {
                 let stdin = std::io::stdin();
                 let mut stdin = stdin.lock();
                 match compress_ir(&mut stdin, &mut io::stdout(), opts) {
                     Ok(_) => return,
-                    Err(e) => panic!("Error {:?}", e),
+                    Err(e) => exit_with_error(e),
                 }
             } else if do_compress {
                 match compress_raw(&mut std::io::stdin(),
                                    &mut io::stdout(),
                                    opts,
                                    buffer_size,
-                                   use_brotli, force_compress, parallel) {
+                                   use_brotli, force_compress, parallel, &prior_snapshot[..]) {
                     Ok(_) => return,
-                    Err(e) => panic!("Error {:?}", e),
+                    Err(e) => exit_with_error(e),
                 }
             } else if do_recode {
                 let stdin = std::io::stdin();
@@ -1696,9 +2114,9 @@ fn main() {
                 recode(&mut stdin,
                        &mut io::stdout()).unwrap()
             } else {
-                match decompress(&mut io::stdin(), &mut io::stdout(), buffer_size, &mut [], skip_crc, parallel) {
+                match decompress(&mut io::stdin(), &mut io::stdout(), buffer_size, &mut [], skip_crc, parallel, &prior_snapshot[..]) {
                     Ok(_) => return,
-                    Err(e) => panic!("Error {:?}", e),
+                    Err(e) => exit_with_error(e),
                 }
             }
         }
@@ -132,12 +132,22 @@ fn e2e_no_ir(buffer_size: usize, use_serialized_priors: bool, use_brotli: bool,
                             prior_bitmask_detection: 1,
                             stride_detection_quality: None,
                             divans_ir_optimizer:1,
+                            cdf_flavor:None,
+                            entropy_coder:None,
+                            content_trailer:false,
+                            num_threads:1,
+                            literal_mixer:false,
+                            literal_sse:false,
+                            literal_hash_size:None,
+                            framed:false,
+                            literal_match:false,
+                            sync_points:false,
                         },
                         buffer_size,
                         use_brotli,
                         true,
-                        true).unwrap();
-    super::decompress(&mut dv_buffer, &mut rt_buffer, buffer_size, &mut[], false, MULTI).unwrap();
+                        true, &[]).unwrap();
+    super::decompress(&mut dv_buffer, &mut rt_buffer, buffer_size, &mut[], false, MULTI, &[]).unwrap();
     assert_eq!(rt_buffer.data, in_buffer.data);
     if ratio != 0.0 {
         let actual_ratio =  dv_buffer.data.len() as f64 / in_buffer.data.len() as f64;
@@ -223,7 +233,7 @@ fn test_e2e_empty_just_flush() {
     }
     let mut rt_buffer = UnlimitedBuffer::new(&[]);
     let mut dv_buffer = UnlimitedBuffer::new(obuffer.split_at(olim).0);
-    super::decompress(&mut dv_buffer, &mut rt_buffer, 0, &mut[], false, MULTI).unwrap();
+    super::decompress(&mut dv_buffer, &mut rt_buffer, 0, &mut[], false, MULTI, &[]).unwrap();
     assert_eq!(rt_buffer.data, &[]);
     state.free();
 }
@@ -247,7 +257,7 @@ fn e2e_alice(buffer_size: usize, use_serialized_priors: bool) {
    opts.use_context_map = true;
     super::compress_ir(&mut buf_ir, &mut dv_buffer, opts).unwrap();
     
-   super::decompress(&mut dv_buffer, &mut rt_buffer, buffer_size, &mut[], false, MULTI).unwrap();
+   super::decompress(&mut dv_buffer, &mut rt_buffer, buffer_size, &mut[], false, MULTI, &[]).unwrap();
    println!("dv_buffer size: {}", dv_buffer.data.len());
    let a =  rt_buffer.data;
    let b = raw_text_buffer.data;
@@ -259,6 +269,107 @@ fn test_e2e_alice() {
     e2e_alice(65536, true);
 }
 
+fn e2e_internal_match_finder(data: &[u8], quality: u16, window_size: i32, ratio: f64) {
+    let mut dv_buffer = UnlimitedBuffer::new(&[]);
+    super::compress_raw(&mut UnlimitedBuffer::new(data),
+                        &mut dv_buffer,
+                        DivansCompressorOptions{
+                            use_brotli: BrotliCompressionSetting::UseInternalCommandSelection,
+                            quality: Some(quality),
+                            window_size: Some(window_size),
+                            ..DivansCompressorOptions::default()
+                        },
+                        4096,
+                        false,
+                        true,
+                        false, &[]).unwrap();
+    let mut rt_buffer = UnlimitedBuffer::new(&[]);
+    super::decompress(&mut dv_buffer, &mut rt_buffer, 4096, &mut[], false, MULTI, &[]).unwrap();
+    assert_eq!(&rt_buffer.data[..], data);
+    assert!(dv_buffer.data.len() as f64 <= data.len() as f64 * ratio);
+}
+
+#[test]
+fn test_e2e_internal_match_finder() {
+    let alice = include_bytes!("../../testdata/alice29");
+    e2e_internal_match_finder(&alice[..], 5, 22, 0.345);
+    e2e_internal_match_finder(&alice[..], 11, 22, 0.335);
+    // small windows wrap the ring buffer many times
+    let mixed = include_bytes!("../../testdata/random_then_unicode");
+    e2e_internal_match_finder(&mixed[..], 1, 10, 0.75);
+    e2e_internal_match_finder(&mixed[..], 11, 16, 0.5);
+}
+
+fn e2e_ir_optimizer(data: &[u8], level: u8) -> usize {
+    let mut dv_buffer = UnlimitedBuffer::new(&[]);
+    super::compress_raw(&mut UnlimitedBuffer::new(data),
+                        &mut dv_buffer,
+                        DivansCompressorOptions{
+                            divans_ir_optimizer: level,
+                            ..DivansCompressorOptions::default()
+                        },
+                        65536,
+                        true,
+                        true,
+                        false, &[]).unwrap();
+    let mut rt_buffer = UnlimitedBuffer::new(&[]);
+    super::decompress(&mut dv_buffer, &mut rt_buffer, 65536, &mut[], false, MULTI, &[]).unwrap();
+    assert_eq!(&rt_buffer.data[..], data);
+    dv_buffer.data.len()
+}
+
+#[test]
+fn test_e2e_content_trailer() {
+    let alice = include_bytes!("../../testdata/alice29");
+    for use_brotli in [false, true].iter() {
+        let mut dv_buffer = UnlimitedBuffer::new(&[]);
+        super::compress_raw(&mut UnlimitedBuffer::new(&alice[..]),
+                            &mut dv_buffer,
+                            DivansCompressorOptions{
+                                content_trailer: true,
+                                ..DivansCompressorOptions::default()
+                            },
+                            4096,
+                            *use_brotli,
+                            true,
+                            false, &[]).unwrap();
+        let summary = divans::ContentSummary::from_stream(&dv_buffer.data[..]).unwrap();
+        assert_eq!(summary.length, alice.len() as u64);
+        let mut rt_buffer = UnlimitedBuffer::new(&[]);
+        super::decompress(&mut dv_buffer, &mut rt_buffer, 4096, &mut[], false, MULTI, &[]).unwrap();
+        assert_eq!(&rt_buffer.data[..], &alice[..]);
+    }
+}
+
+#[test]
+fn test_e2e_threads() {
+    let alice = include_bytes!("../../testdata/alice29");
+    let mut dv_buffer = UnlimitedBuffer::new(&[]);
+    super::compress_raw(&mut UnlimitedBuffer::new(&alice[..]),
+                        &mut dv_buffer,
+                        DivansCompressorOptions{
+                            num_threads: 2,
+                            ..DivansCompressorOptions::default()
+                        },
+                        4096,
+                        true,
+                        true,
+                        false, &[]).unwrap();
+    let (seek_table, _) = divans::SeekTable::read_from(&mut io::Cursor::new(&dv_buffer.data[..])).unwrap();
+    assert_eq!(seek_table.uncompressed_len(), alice.len() as u64);
+    let mut rt_buffer = UnlimitedBuffer::new(&[]);
+    super::decompress(&mut dv_buffer, &mut rt_buffer, 4096, &mut[], false, MULTI, &[]).unwrap();
+    assert_eq!(&rt_buffer.data[..], &alice[..]);
+}
+
+#[test]
+fn test_e2e_optimal_parse() {
+    let alice = include_bytes!("../../testdata/alice29");
+    assert!(e2e_ir_optimizer(&alice[..], 2) <= e2e_ir_optimizer(&alice[..], 1));
+    let mixed = include_bytes!("../../testdata/random_then_unicode");
+    assert!(e2e_ir_optimizer(&mixed[..], 2) <= e2e_ir_optimizer(&mixed[..], 1));
+}
+
 #[test]
 fn test_e2e_smallbuf_without_priors() {
     e2e_alice(15, false);
@@ -282,7 +393,7 @@ fn test_e2e_32xx() {
    opts.literal_adaptation = None;
 
    super::compress_ir(&mut buf_ir, &mut dv_buffer, opts).unwrap();
-   super::decompress(&mut dv_buffer, &mut rt_buffer, 15, &mut [],  false, MULTI).unwrap();
+   super::decompress(&mut dv_buffer, &mut rt_buffer, 15, &mut [],  false, MULTI, &[]).unwrap();
    let a =  rt_buffer.data;
    let b = raw_text_buffer.data;
    assert_eq!(a, b);
@@ -302,11 +413,90 @@ fn test_e2e_262145_at() {
    opts.use_context_map = true;
    opts.dynamic_context_mixing = Some(2);
    super::compress_ir(&mut buf_ir, &mut dv_buffer, opts).unwrap();
-   super::decompress(&mut dv_buffer, &mut rt_buffer, 15, &mut[], false, MULTI).unwrap();
+   super::decompress(&mut dv_buffer, &mut rt_buffer, 15, &mut[], false, MULTI, &[]).unwrap();
    let a =  rt_buffer.data;
    let b = raw_text_buffer.data;
    assert_eq!(a, b);
 }
+
+#[test]
+fn test_e2e_recompress_brotli() {
+    let raw_text_slice = include_bytes!("../../testdata/asyoulik");
+    let mut br_buffer = UnlimitedBuffer::new(&[]);
+    let mut params = super::brotli::enc::BrotliEncoderInitParams();
+    params.quality = 9;
+    super::brotli::BrotliCompress(&mut &raw_text_slice[..], &mut br_buffer, &params).unwrap();
+    let mut dv_buffer = UnlimitedBuffer::new(&[]);
+    super::recompress_brotli_file(&mut UnlimitedBuffer::new(&br_buffer.data[..]), &mut dv_buffer,
+                                  DivansCompressorOptions::default()).unwrap();
+    assert!(dv_buffer.data.len() < br_buffer.data.len());
+    let mut rt_buffer = UnlimitedBuffer::new(&[]);
+    super::decompress(&mut dv_buffer, &mut rt_buffer, 4096, &mut[], false, MULTI, &[]).unwrap();
+    assert_eq!(rt_buffer.data, br_buffer.data);
+}
+#[test]
+fn test_e2e_recompress_deflate() {
+    let gz_slice = include_bytes!("../../testdata/asyoulik.gz");
+    let mut dv_buffer = UnlimitedBuffer::new(&[]);
+    super::recompress_deflate_file(&mut UnlimitedBuffer::new(&gz_slice[..]), &mut dv_buffer,
+                                   DivansCompressorOptions::default()).unwrap();
+    assert!(dv_buffer.data.len() < gz_slice.len());
+    let mut rt_buffer = UnlimitedBuffer::new(&[]);
+    super::decompress(&mut dv_buffer, &mut rt_buffer, 4096, &mut[], false, MULTI, &[]).unwrap();
+    assert_eq!(&rt_buffer.data[..], &gz_slice[..]);
+}
+#[test]
+fn test_verify() {
+    let gz_slice = include_bytes!("../../testdata/asyoulik.gz");
+    let mut dv_buffer = UnlimitedBuffer::new(&[]);
+    super::recompress_deflate_file(&mut UnlimitedBuffer::new(&gz_slice[..]), &mut dv_buffer,
+                                   DivansCompressorOptions::default()).unwrap();
+    assert_eq!(super::verify(&mut UnlimitedBuffer::new(&dv_buffer.data[..]), 4096, MULTI, &[]).unwrap().ok(),
+               Some(gz_slice.len() as u64));
+    let raw_text_slice = include_bytes!("../../testdata/alice29");
+    let mut dv_buffer = UnlimitedBuffer::new(&[]);
+    super::compress_raw(&mut UnlimitedBuffer::new(&raw_text_slice[..20000]),
+                        &mut dv_buffer,
+                        DivansCompressorOptions::default(),
+                        4096,
+                        true,
+                        true,
+                        false, &[]).unwrap();
+    assert_eq!(super::verify(&mut UnlimitedBuffer::new(&dv_buffer.data[..]), 4096, MULTI, &[]).unwrap().ok(),
+               Some(20000));
+    let mut corrupt = dv_buffer.data.clone();
+    let middle = corrupt.len() / 2;
+    corrupt[middle] ^= 4;
+    assert!(super::verify(&mut UnlimitedBuffer::new(&corrupt[..]), 4096, MULTI, &[]).unwrap().is_err());
+    let truncated = &dv_buffer.data[..dv_buffer.data.len() - 1];
+    let located = super::verify(&mut UnlimitedBuffer::new(truncated), 4096, MULTI, &[]).unwrap().unwrap_err()
+        .downcast::<divans::DivansError>().unwrap();
+    assert_eq!(located.msg, divans::ErrMsg::UnexpectedEof);
+    assert_eq!(located.compressed_offset, truncated.len() as u64);
+}
+#[test]
+fn test_e2e_transcode_to_brotli() {
+    let raw_text_slice = include_bytes!("../../testdata/asyoulik");
+    for &use_brotli in [BrotliCompressionSetting::UseInternalCommandSelection,
+                        BrotliCompressionSetting::UseBrotliCommandSelection].iter() {
+        let mut dv_buffer = UnlimitedBuffer::new(&[]);
+        super::compress_raw(&mut UnlimitedBuffer::new(&raw_text_slice[..]),
+                            &mut dv_buffer,
+                            DivansCompressorOptions{
+                                use_brotli: use_brotli,
+                                ..DivansCompressorOptions::default()
+                            },
+                            65536,
+                            true,
+                            true,
+                            false, &[]).unwrap();
+        let mut br_buffer = UnlimitedBuffer::new(&[]);
+        super::transcode_to_brotli_file(&mut dv_buffer, &mut br_buffer).unwrap();
+        let mut rt_buffer = UnlimitedBuffer::new(&[]);
+        super::brotli::BrotliDecompress(&mut UnlimitedBuffer::new(&br_buffer.data[..]), &mut rt_buffer).unwrap();
+        assert_eq!(&rt_buffer.data[..], &raw_text_slice[..]);
+    }
+}
 #[cfg(not(feature="external-literal-probability"))]
 const EXTERNAL_PROB_FEATURE:bool = false;
 #[cfg(feature="external-literal-probability")]
@@ -17,7 +17,7 @@ use super::probability::CDF16;
 use super::brotli;
 use super::mux::{Mux,DevNull};
 use codec::io::DemuxerAndRingBuffer;
-use codec::{CommandArray, CommandSliceArray};
+use codec::{CommandArray, CommandSliceArray, PriorSnapshotInfo};
 pub use super::alloc::{AllocatedStackMemory, Allocator, SliceWrapper, SliceWrapperMut, StackAllocator};
 pub use super::interface::{BlockSwitch, LiteralBlockSwitch, Command, Compressor, CopyCommand, Decompressor, DictCommand, LiteralCommand, Nop, NewWithAllocator, ArithmeticEncoderOrDecoder, LiteralPredictionModeNibble, PredictionModeContextMap, free_cmd, FeatureFlagSliceType,
     LITERAL_PREDICTION_MODE_SIGN,
@@ -31,9 +31,9 @@ pub use codec::{EncoderOrDecoderSpecialization, DivansCodec, StrideSelection};
 use super::resizable_buffer::ResizableByteBuffer;
 use super::interface;
 use super::interface::{DivansOutputResult, DivansResult, ErrMsg};
-use super::brotli::enc::encode::{BrotliEncoderStateStruct, BrotliEncoderCompressStream, BrotliEncoderOperation, BrotliEncoderIsFinished};
+use super::brotli::enc::encode::{BrotliEncoderStateStruct, BrotliEncoderCompressStream, BrotliEncoderOperation, BrotliEncoderIsFinished, BrotliEncoderHasMoreOutput};
 use super::brotli::enc::backward_references::BrotliEncoderMode;
-use super::divans_compressor::write_header;
+use super::divans_compressor::{write_header, write_trailer};
 pub struct BrotliDivansHybridCompressor<SelectedCDF:CDF16,
                             ChosenEncoder: ArithmeticEncoderOrDecoder + NewWithAllocator<AllocU8>,
                             AllocU8:Allocator<u8>,
@@ -74,7 +74,11 @@ pub struct BrotliDivansHybridCompressor<SelectedCDF:CDF16,
     brotli_data: ResizableByteBuffer<u8, AllocU8>,
     divans_data: ResizableByteBuffer<u8, AllocU8>,
     encoded_byte_offset: usize,
+    // the coders were synced but divans_data has not been copied out yet
+    sync_pending: bool,
+    content: interface::ContentDigest,
     opt: super::interface::DivansCompressorOptions,
+    features: interface::StreamFeatures,
 }
 
 
@@ -122,6 +126,25 @@ impl<SelectedCDF:CDF16,
     pub fn get_m8(&mut self) -> &mut AllocU8 {
        self.codec.get_m8().unwrap().get_base_alloc()
     }
+    // starts the stream from a prior snapshot, whose id and cdf flavor go in the header;
+    // must be called before anything is encoded
+    pub fn load_prior_snapshot(&mut self, prior_snapshot: &[u8]) -> Result<(), ErrMsg> {
+        if self.header_progress != 0 {
+            return Err(ErrMsg::WrongInternalEncoderState(2));
+        }
+        match self.codec.load_prior_snapshot(prior_snapshot) {
+            Ok(info) => {
+                self.features.cdf_flavor = info.cdf_flavor;
+                self.features.prior_snapshot_id = Some(info.id);
+                Ok(())
+            },
+            Err(e) => Err(e),
+        }
+    }
+    // serializes the priors the stream has adapted so far as a prior snapshot
+    pub fn write_prior_snapshot<Sink:FnMut(&[u8])>(&self, sink: &mut Sink) -> Result<PriorSnapshotInfo, ErrMsg> {
+        self.codec.write_prior_snapshot(sink)
+    }
     #[cfg(feature="no-stdlib")]
     fn do_panic(_m:ErrMsg) {
         panic!("Internal Error With Compression Stage")
@@ -140,7 +163,8 @@ impl<SelectedCDF:CDF16,
                                                                                   SelectedCDF,
                                                                                   AllocU8,
                                                                                   AllocCDF16>,
-                                                          window_size: u8) {
+                                                          window_size: u8,
+                                                          features: interface::StreamFeatures) {
         let mut cmd_offset = 0usize;
         loop {
             let ret: DivansResult;
@@ -149,7 +173,7 @@ impl<SelectedCDF:CDF16,
                 let output = data.checkout_next_buffer(codec.get_m8().as_mut().unwrap().get_base_alloc(),
                                                            Some(interface::HEADER_LENGTH + 256));
                 if *header_progress != interface::HEADER_LENGTH {
-                    match write_header(header_progress, window_size, output, &mut output_offset, codec.get_crc()) {
+                    match write_header(header_progress, window_size, features, output, &mut output_offset, codec.get_crc()) {
                         DivansOutputResult::Success => {},
                         _ => panic!("Unexpected failure writing header"),
                     }
@@ -187,6 +211,7 @@ impl<SelectedCDF:CDF16,
             let header_progress_ref = &mut self.header_progress;
             let window_size = self.window_size;
             let opt = self.opt;
+            let features = self.features;
             let mut cb = |pm:&mut brotli::interface::PredictionModeContextMap<brotli::InputReferenceMut>,
                           a:&mut [brotli::interface::Command<brotli::SliceOffset>],
                           mb:brotli::InputPair,
@@ -211,13 +236,15 @@ impl<SelectedCDF:CDF16,
                                                                header_progress_ref,
                                                                divans_data_ref,
                                                                divans_codec_ref,
-                                                               window_size);
+                                                               window_size,
+                                                               features);
                                   if final_cmd.len() != 0 {
                                       Self::divans_encode_commands(&ThawingSliceArray(final_cmd, mb),
                                                                    header_progress_ref,
                                                                    divans_data_ref,
                                                                    divans_codec_ref,
-                                                                   window_size);
+                                                                   window_size,
+                                                                   features);
                                   }
                               }
                               if expanded_buffer.len() != 0 {
@@ -284,11 +311,23 @@ impl<SelectedCDF:CDF16,
                 self.divans_data.commit_next_buffer(output_offset);
                 match ret {
                     DivansOutputResult::NeedsMoreOutput => {},
+                    DivansOutputResult::Success => break,
                     _ => return DivansResult::from(ret),
                 }
             }
+            if self.opt.content_trailer {
+                let mut trailer_progress = 0usize;
+                let mut output_offset = 0usize;
+                {
+                    let output = self.divans_data.checkout_next_buffer(self.codec.get_m8().as_mut().unwrap().get_base_alloc(),
+                                                                       Some(interface::TRAILER_LENGTH));
+                    write_trailer(&mut trailer_progress, self.content.summary(), output, &mut output_offset);
+                }
+                self.divans_data.commit_next_buffer(output_offset);
+            }
+            DivansResult::Success
         } else {
-            return DivansResult::NeedsMoreInput
+            DivansResult::NeedsMoreInput
         }
     }
     fn free_internal(&mut self) {
@@ -354,10 +393,15 @@ impl<SelectedCDF:CDF16,
               input_offset: &mut usize,
               _output: &mut [u8],
               _output_offset: &mut usize) -> DivansResult {
-        match self.internal_encode_stream(BrotliEncoderOperation::BROTLI_OPERATION_PROCESS,
-                                          input,
-                                          input_offset,
-                                          false) {
+        let old_input_offset = *input_offset;
+        let ret = self.internal_encode_stream(BrotliEncoderOperation::BROTLI_OPERATION_PROCESS,
+                                              input,
+                                              input_offset,
+                                              false);
+        if self.opt.content_trailer {
+            self.content.write(&input[old_input_offset..*input_offset]);
+        }
+        match ret {
             DivansResult::NeedsMoreOutput => DivansResult::Failure(ErrMsg::BrotliInternalEncodeStreamNeedsOutputWithoutFlush),
             DivansResult::Failure(m) => DivansResult::Failure(m),
             DivansResult::Success | DivansResult::NeedsMoreInput => DivansResult::NeedsMoreInput,
@@ -368,7 +412,7 @@ impl<SelectedCDF:CDF16,
              output_offset: &mut usize) -> DivansOutputResult {
         let mut zero = 0usize;
         if self.header_progress != interface::HEADER_LENGTH {
-            match write_header(&mut self.header_progress, self.window_size, output, output_offset, self.codec.get_crc()) {
+            match write_header(&mut self.header_progress, self.window_size, self.features, output, output_offset, self.codec.get_crc()) {
                 DivansOutputResult::Success => {},
                 need => return need,
             }
@@ -397,13 +441,70 @@ impl<SelectedCDF:CDF16,
         }
         DivansOutputResult::NeedsMoreOutput
     }
+    fn sync_flush(&mut self,
+                  output: &mut [u8],
+                  output_offset: &mut usize) -> DivansOutputResult {
+        if self.header_progress != interface::HEADER_LENGTH {
+            match write_header(&mut self.header_progress, self.window_size, self.features, output, output_offset, self.codec.get_crc()) {
+                DivansOutputResult::Success => {},
+                need => return need,
+            }
+        }
+        if !self.sync_pending {
+            let mut zero = 0usize;
+            loop { // push all buffered input through brotli so the commands reach the divans codec
+                if let DivansResult::Failure(m) = self.internal_encode_stream(BrotliEncoderOperation::BROTLI_OPERATION_FLUSH,
+                                                                              &[],
+                                                                              &mut zero,
+                                                                              false) {
+                    return DivansOutputResult::Failure(m);
+                }
+                if BrotliEncoderHasMoreOutput(&self.brotli_encoder) == 0 {
+                    break;
+                }
+            }
+            loop {
+                let ret;
+                let mut divans_offset = 0usize;
+                {
+                    let divans_output = self.divans_data.checkout_next_buffer(self.codec.get_m8().as_mut().unwrap().get_base_alloc(),
+                                                                                  Some(interface::HEADER_LENGTH + 256));
+                    ret = self.codec.sync_flush(divans_output, &mut divans_offset);
+                }
+                self.divans_data.commit_next_buffer(divans_offset);
+                match ret {
+                    DivansOutputResult::NeedsMoreOutput => {},
+                    DivansOutputResult::Success => break,
+                    DivansOutputResult::Failure(m) => return DivansOutputResult::Failure(m),
+                }
+            }
+            self.sync_pending = true;
+        }
+        let destination = output.split_at_mut(*output_offset).1;
+        let src = self.divans_data.slice().split_at(self.encoded_byte_offset).1;
+        let copy_len = min(src.len(), destination.len());
+        destination.split_at_mut(copy_len).0.clone_from_slice(src.split_at(copy_len).0);
+        *output_offset += copy_len;
+        self.encoded_byte_offset += copy_len;
+        if self.encoded_byte_offset != self.divans_data.len() {
+            return DivansOutputResult::NeedsMoreOutput;
+        }
+        // everything so far is out: the buffer can start over
+        self.divans_data.clear();
+        self.encoded_byte_offset = 0;
+        self.sync_pending = false;
+        DivansOutputResult::Success
+    }
     fn encode_commands<SliceType:SliceWrapper<u8>+Default>(&mut self,
                                                            input:&[Command<SliceType>],
                                                            input_offset : &mut usize,
                                                            output :&mut[u8],
                                                            output_offset: &mut usize) -> DivansOutputResult {
+        if self.opt.content_trailer {
+            return DivansOutputResult::Failure(ErrMsg::ContentTrailerNeedsRawInput);
+        }
         if self.header_progress != interface::HEADER_LENGTH {
-            match write_header(&mut self.header_progress, self.window_size, output, output_offset, self.codec.get_crc()) {
+            match write_header(&mut self.header_progress, self.window_size, self.features, output, output_offset, self.codec.get_crc()) {
                 DivansOutputResult::Success => {},
                 res => return res,
             }
@@ -511,8 +612,9 @@ impl<AllocU8:Allocator<u8>,
                opt: super::interface::DivansCompressorOptions,
                additional_args: Self::AdditionalArgs) -> Self::ConstructedCompressor {
         let window_size = min(24, max(10, opt.window_size.unwrap_or(22)));
-        let cmd_enc = Self::DefaultEncoder::new(&mut m8);
-        let lit_enc = Self::DefaultEncoder::new(&mut m8);
+        let features = opt.stream_features();
+        let cmd_enc = Self::DefaultEncoder::new_with_entropy_coder(&mut m8, features.entropy_coder);
+        let lit_enc = Self::DefaultEncoder::new_with_entropy_coder(&mut m8, features.entropy_coder);
         let mut ret = Self::ConstructedCompressor {
              m64: additional_args.4,
              mf64: additional_args.5,
@@ -529,6 +631,8 @@ impl<AllocU8:Allocator<u8>,
              brotli_data: ResizableByteBuffer::<u8, AllocU8>::new(),
              divans_data: ResizableByteBuffer::<u8, AllocU8>::new(),
              encoded_byte_offset:0, 
+             sync_pending: false,
+             content: interface::ContentDigest::default(),
              brotli_encoder: brotli::enc::encode::BrotliEncoderCreateInstance(additional_args.0,
                                                                               additional_args.1,
                                                                               additional_args.2,
@@ -550,9 +654,16 @@ impl<AllocU8:Allocator<u8>,
                 false,
             ),
             opt:opt,
+            features: features,
             header_progress: 0,
             window_size: window_size as u8,
         };
+        ret.codec.set_cdf_flavor(features.cdf_flavor);
+        ret.codec.set_literal_mixer(features.literal_mixer);
+        ret.codec.set_literal_match(features.literal_match);
+        ret.codec.set_literal_sse(features.literal_sse);
+        ret.codec.set_literal_hash_size(features.literal_hash_size);
+        ret.codec.set_sync_points(features.sync_points);
         if let Some(prediction_mode) = opt.force_literal_context_mode {
             brotli::enc::encode::BrotliEncoderSetParameter(
                 &mut ret.brotli_encoder,
@@ -0,0 +1,286 @@
+// Copyright 2017 Dropbox, Inc
+//
+//   Licensed under the Apache License, Version 2.0 (the "License");
+//   you may not use this file except in compliance with the License.
+//   You may obtain a copy of the License at
+//
+//       http://www.apache.org/licenses/LICENSE-2.0
+//
+//   Unless required by applicable law or agreed to in writing, software
+//   distributed under the License is distributed on an "AS IS" BASIS,
+//   WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//   See the License for the specific language governing permissions and
+//   limitations under the License.
+
+// Splits a brotli file into the divans IR of its commands and the side data holding every bit
+// that cannot be derived from the commands and the decoded output.
+use std::vec::Vec;
+use ::brotli;
+use ::brotli::dictionary::{kBrotliDictionary, kBrotliDictionaryOffsetsByLength, kBrotliDictionarySizeBitsByLength};
+use ::interface::{CopyCommand, DictCommand, ErrMsg, PredictionModeContextMap, LiteralPredictionModeNibble};
+use super::bit_io::{BitReader, BitSource, BitWriter, TeeReader};
+use super::bitstream::{BlockCategory, CompressedHeader, DistanceCache, MetaBlockKind, COPY_LENGTH_PREFIX,
+                       INSERT_LENGTH_PREFIX, NUM_TRANSFORMS, check_distance, decode_distance, distance_context,
+                       read_meta_block_header, read_window_bits, split_command_symbol};
+use super::{DistanceChoice, DistanceExceptionWriter, canonical_distance_choice, literal_context};
+
+pub enum IrCommand {
+    // offset and length in the decoded output
+    Literal(usize, usize),
+    Copy(CopyCommand),
+    Dict(DictCommand),
+    BlockSwitchCommand(u8),
+    BlockSwitchDistance(u8),
+    BlockSwitchLiteral(u8),
+    // index into the prediction modes
+    PredictionMode(usize),
+}
+
+pub struct Analysis {
+    pub window_bits: u32,
+    pub output: Vec<u8>,
+    pub commands: Vec<IrCommand>,
+    // literal context map and combined speed and distance context map of each prediction mode
+    pub prediction_modes: Vec<(Vec<u8>, Vec<u8>)>,
+    pub side_bits: Vec<u8>,
+    pub distance_exceptions: Vec<u8>,
+    pub tail: Vec<u8>,
+}
+
+// the block types divans was last told about
+#[derive(Default)]
+struct IrBlockTypes {
+    literal: usize,
+    command: usize,
+    distance: usize,
+}
+
+struct Analyzer<'a> {
+    reader: BitReader<'a>,
+    side: BitWriter,
+    max_window: usize,
+    output: Vec<u8>,
+    commands: Vec<IrCommand>,
+    prediction_modes: Vec<(Vec<u8>, Vec<u8>)>,
+    exceptions: DistanceExceptionWriter,
+    distance_cache: DistanceCache,
+    ir_block_types: IrBlockTypes,
+}
+
+// the literal context map and combined speed and distance context map of a divans prediction mode
+// that mixes in the stride prior the way the brotli encoder sets it up
+pub fn prediction_mode_tables(literal_context_map: &[u8], distance_context_map: &[u8], mode: u8) -> (Vec<u8>, Vec<u8>) {
+    let mut literal_context_map = literal_context_map.to_vec();
+    let mut combined = vec![0u8; PredictionModeContextMap::<brotli::InputReference>::size_of_combined_array(
+        distance_context_map.len())];
+    {
+        let offset = PredictionModeContextMap::<brotli::InputReference>::size_of_combined_array(0);
+        combined[offset..].clone_from_slice(distance_context_map);
+        let mut pm = PredictionModeContextMap::<brotli::InputReferenceMut> {
+            literal_context_map: brotli::InputReferenceMut{data: &mut literal_context_map[..], orig_offset: 0},
+            predmode_speed_and_distance_context_map: brotli::InputReferenceMut{data: &mut combined[..], orig_offset: 0},
+        };
+        for item in pm.get_mixing_values_mut().iter_mut() {
+            *item = brotli::enc::prior_eval::WhichPrior::STRIDE1 as u8;
+        }
+        pm.set_literal_prediction_mode(LiteralPredictionModeNibble(mode));
+    }
+    (literal_context_map, combined)
+}
+
+fn advance_block<'a>(reader: &mut BitReader<'a>, side: &mut BitWriter, category: &mut BlockCategory) -> Result<bool, ErrMsg> {
+    let mut tee = TeeReader{source: reader, sink: side, stream_is_sink: false};
+    category.advance(&mut tee)
+}
+
+impl<'a> Analyzer<'a> {
+    fn push_literals(&mut self, start: usize) {
+        if self.output.len() != start {
+            self.commands.push(IrCommand::Literal(start, self.output.len() - start));
+        }
+    }
+    fn push_prediction_mode(&mut self, header: &CompressedHeader) {
+        let tables = prediction_mode_tables(&header.literal_context_map[..], &header.distance_context_map[..],
+                                            header.context_modes[0]);
+        self.commands.push(IrCommand::PredictionMode(self.prediction_modes.len()));
+        self.prediction_modes.push(tables);
+        // brotli starts every meta-block with block type 0 in each category
+        if self.ir_block_types.literal != 0 {
+            self.commands.push(IrCommand::BlockSwitchLiteral(0));
+        }
+        if self.ir_block_types.command != 0 {
+            self.commands.push(IrCommand::BlockSwitchCommand(0));
+        }
+        if self.ir_block_types.distance != 0 {
+            self.commands.push(IrCommand::BlockSwitchDistance(0));
+        }
+        self.ir_block_types = IrBlockTypes::default();
+    }
+    fn analyze_compressed(&mut self, header: &mut CompressedHeader, len: usize) -> Result<(), ErrMsg> {
+        self.push_prediction_mode(header);
+        let mut remaining = len;
+        while remaining != 0 {
+            if try!(advance_block(&mut self.reader, &mut self.side, &mut header.command)) {
+                self.ir_block_types.command = header.command.block_type;
+                self.commands.push(IrCommand::BlockSwitchCommand(header.command.block_type as u8));
+            }
+            let command_code = &header.command_codes[header.command.block_type];
+            let symbol = try!(command_code.read_symbol(&mut self.reader));
+            let (insert_code, copy_code, implicit_distance) = split_command_symbol(symbol);
+            let insert_len = INSERT_LENGTH_PREFIX[insert_code].0
+                + try!(self.reader.read_bits(INSERT_LENGTH_PREFIX[insert_code].1));
+            let copy_extra_bits = COPY_LENGTH_PREFIX[copy_code].1;
+            let copy_extra = try!(self.reader.read_bits(copy_extra_bits));
+            let copy_len = COPY_LENGTH_PREFIX[copy_code].0 + copy_extra;
+            if insert_len as usize > remaining {
+                return Err(ErrMsg::BrotliBitstreamCorrupt(12));
+            }
+            if insert_len as usize == remaining {
+                // the copy of the last command of a meta-block is never executed, so nothing determines it
+                self.side.write_bits(10, u32::from(symbol));
+                self.side.write_bits(copy_extra_bits, copy_extra);
+            }
+            let mut literal_start = self.output.len();
+            for _ in 0..insert_len {
+                if try!(advance_block(&mut self.reader, &mut self.side, &mut header.literal)) {
+                    self.push_literals(literal_start);
+                    literal_start = self.output.len();
+                    self.ir_block_types.literal = header.literal.block_type;
+                    self.commands.push(IrCommand::BlockSwitchLiteral(header.literal.block_type as u8));
+                }
+                let block_type = header.literal.block_type;
+                let context = literal_context(&self.output[..], header.context_modes[block_type]);
+                let tree = header.literal_context_map[(block_type << 6) + context] as usize;
+                let literal = try!(header.literal_codes[tree].read_symbol(&mut self.reader));
+                self.output.push(literal as u8);
+            }
+            self.push_literals(literal_start);
+            remaining -= insert_len as usize;
+            if remaining == 0 {
+                break;
+            }
+            let (distance, choice) = if implicit_distance {
+                (self.distance_cache.short_code_distance(0), DistanceChoice::Implicit)
+            } else {
+                if try!(advance_block(&mut self.reader, &mut self.side, &mut header.distance)) {
+                    self.ir_block_types.distance = header.distance.block_type;
+                    self.commands.push(IrCommand::BlockSwitchDistance(header.distance.block_type as u8));
+                }
+                let tree = header.distance_context_map[(header.distance.block_type << 2)
+                                                       + distance_context(copy_len)] as usize;
+                let distance_symbol = u32::from(try!(header.distance_codes[tree].read_symbol(&mut self.reader)));
+                if distance_symbol < 16 {
+                    (self.distance_cache.short_code_distance(distance_symbol), DistanceChoice::Short(distance_symbol as u8))
+                } else {
+                    (try!(decode_distance(&mut self.reader, distance_symbol, header.npostfix, header.ndirect)),
+                     DistanceChoice::General)
+                }
+            };
+            try!(check_distance(distance));
+            let max_distance = core::cmp::min(self.output.len(), self.max_window) as i64;
+            let is_dictionary = distance > max_distance;
+            let canonical = canonical_distance_choice(&self.distance_cache, distance, max_distance, insert_code,
+                                                      copy_code, command_code);
+            self.exceptions.push(choice, canonical);
+            if is_dictionary {
+                let final_size = try!(self.push_dictionary_word(copy_len, (distance - max_distance - 1) as u32));
+                if final_size > remaining {
+                    return Err(ErrMsg::BrotliBitstreamCorrupt(13));
+                }
+                remaining -= final_size;
+            } else {
+                if copy_len as usize > remaining {
+                    return Err(ErrMsg::BrotliBitstreamCorrupt(14));
+                }
+                for _ in 0..copy_len {
+                    let byte = self.output[self.output.len() - distance as usize];
+                    self.output.push(byte);
+                }
+                self.commands.push(IrCommand::Copy(CopyCommand{distance: distance as u32, num_bytes: copy_len}));
+                remaining -= copy_len as usize;
+            }
+            self.distance_cache.update(choice.reuses_last_distance(), is_dictionary, distance);
+        }
+        Ok(())
+    }
+    fn push_dictionary_word(&mut self, copy_len: u32, word_index: u32) -> Result<usize, ErrMsg> {
+        if !(4..25).contains(&copy_len) {
+            return Err(ErrMsg::BrotliBitstreamCorrupt(15));
+        }
+        let size_bits = u32::from(kBrotliDictionarySizeBitsByLength[copy_len as usize]);
+        let word_id = word_index & ((1 << size_bits) - 1);
+        let transform = word_index >> size_bits;
+        if size_bits == 0 || transform >= NUM_TRANSFORMS {
+            return Err(ErrMsg::BrotliBitstreamCorrupt(16));
+        }
+        let offset = (kBrotliDictionaryOffsetsByLength[copy_len as usize] + word_id * copy_len) as usize;
+        let mut transformed_word = [0u8; 24 + 13];
+        let final_size = brotli::TransformDictionaryWord(&mut transformed_word[..],
+                                                         &kBrotliDictionary[offset..offset + copy_len as usize],
+                                                         copy_len as i32,
+                                                         transform as i32) as usize;
+        self.output.extend(&transformed_word[..final_size]);
+        self.commands.push(IrCommand::Dict(DictCommand{
+            word_size: copy_len as u8,
+            transform: transform as u8,
+            final_size: final_size as u8,
+            empty: 0,
+            word_id: word_id,
+        }));
+        Ok(final_size)
+    }
+}
+
+pub fn analyze(input: &[u8]) -> Result<Analysis, ErrMsg> {
+    let mut analyzer = Analyzer {
+        reader: BitReader::new(input),
+        side: BitWriter::new(),
+        max_window: 0,
+        output: Vec::<u8>::new(),
+        commands: Vec::<IrCommand>::new(),
+        prediction_modes: Vec::<(Vec<u8>, Vec<u8>)>::new(),
+        exceptions: DistanceExceptionWriter::default(),
+        distance_cache: DistanceCache::default(),
+        ir_block_types: IrBlockTypes::default(),
+    };
+    let window_bits = {
+        let mut tee = TeeReader{source: &mut analyzer.reader, sink: &mut analyzer.side, stream_is_sink: false};
+        try!(read_window_bits(&mut tee))
+    };
+    analyzer.max_window = (1 << window_bits) - 16;
+    loop {
+        let header = {
+            let mut tee = TeeReader{source: &mut analyzer.reader, sink: &mut analyzer.side, stream_is_sink: false};
+            try!(read_meta_block_header(&mut tee))
+        };
+        let mut header = match header {
+            Some(header) => header,
+            None => break,
+        };
+        match header.kind {
+            MetaBlockKind::Metadata => {},
+            MetaBlockKind::Uncompressed => {
+                let start = analyzer.output.len();
+                analyzer.output.extend(try!(analyzer.reader.read_bytes(header.len)));
+                analyzer.push_literals(start);
+            },
+            MetaBlockKind::Compressed(ref mut compressed) => try!(analyzer.analyze_compressed(compressed, header.len)),
+        }
+        if header.is_last {
+            break;
+        }
+    }
+    {
+        let mut tee = TeeReader{source: &mut analyzer.reader, sink: &mut analyzer.side, stream_is_sink: false};
+        try!(tee.read_to_byte_boundary());
+    }
+    Ok(Analysis {
+        window_bits: window_bits,
+        tail: analyzer.reader.remaining_bytes().to_vec(),
+        output: analyzer.output,
+        commands: analyzer.commands,
+        prediction_modes: analyzer.prediction_modes,
+        side_bits: analyzer.side.into_bytes(),
+        distance_exceptions: analyzer.exceptions.into_bytes(),
+    })
+}
@@ -0,0 +1,145 @@
+// Copyright 2017 Dropbox, Inc
+//
+//   Licensed under the Apache License, Version 2.0 (the "License");
+//   you may not use this file except in compliance with the License.
+//   You may obtain a copy of the License at
+//
+//       http://www.apache.org/licenses/LICENSE-2.0
+//
+//   Unless required by applicable law or agreed to in writing, software
+//   distributed under the License is distributed on an "AS IS" BASIS,
+//   WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//   See the License for the specific language governing permissions and
+//   limitations under the License.
+
+// LSB-first bit reading and writing, matching the bit packing order of both brotli and DEFLATE.
+use std::vec::Vec;
+use ::interface::ErrMsg;
+
+pub trait BitSource {
+    // reads up to 32 bits, least significant bit first
+    fn read_bits(&mut self, num_bits: u32) -> Result<u32, ErrMsg>;
+    // number of bits until the compressed stream (which is not always this source) is byte aligned
+    fn padding_bits(&self) -> u32;
+    fn read_to_byte_boundary(&mut self) -> Result<u32, ErrMsg> {
+        let pad = self.padding_bits();
+        self.read_bits(pad)
+    }
+}
+
+pub struct BitReader<'a> {
+    data: &'a [u8],
+    bit_offset: usize,
+}
+
+impl<'a> BitReader<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        BitReader {
+            data: data,
+            bit_offset: 0,
+        }
+    }
+    // the bytes after the current position, which must be byte aligned
+    pub fn remaining_bytes(&self) -> &'a [u8] {
+        debug_assert_eq!(self.bit_offset & 7, 0);
+        &self.data[(self.bit_offset + 7) >> 3..]
+    }
+    pub fn read_bytes(&mut self, len: usize) -> Result<&'a [u8], ErrMsg> {
+        debug_assert_eq!(self.bit_offset & 7, 0);
+        let start = self.bit_offset >> 3;
+        if self.data.len() - start < len {
+            return Err(ErrMsg::BrotliBitstreamTruncated);
+        }
+        self.bit_offset += len << 3;
+        Ok(&self.data[start..start + len])
+    }
+}
+
+impl<'a> BitSource for BitReader<'a> {
+    fn read_bits(&mut self, num_bits: u32) -> Result<u32, ErrMsg> {
+        debug_assert!(num_bits <= 32);
+        if self.bit_offset + num_bits as usize > self.data.len() << 3 {
+            return Err(ErrMsg::BrotliBitstreamTruncated);
+        }
+        let mut ret = 0u64;
+        let mut filled = 0u32;
+        while filled < num_bits {
+            let byte = u64::from(self.data[self.bit_offset >> 3]);
+            let bit_in_byte = (self.bit_offset & 7) as u32;
+            let take = ::core::cmp::min(8 - bit_in_byte, num_bits - filled);
+            ret |= ((byte >> bit_in_byte) & ((1 << take) - 1)) << filled;
+            filled += take;
+            self.bit_offset += take as usize;
+        }
+        Ok(ret as u32)
+    }
+    fn padding_bits(&self) -> u32 {
+        ((8 - (self.bit_offset & 7)) & 7) as u32
+    }
+}
+
+#[derive(Default)]
+pub struct BitWriter {
+    data: Vec<u8>,
+    num_bits: usize,
+}
+
+impl BitWriter {
+    pub fn new() -> Self {
+        BitWriter::default()
+    }
+    pub fn write_bits(&mut self, num_bits: u32, mut val: u32) {
+        debug_assert!(num_bits <= 32);
+        debug_assert!(num_bits == 32 || val >> num_bits == 0);
+        let mut remaining = num_bits;
+        while remaining != 0 {
+            let bit_in_byte = (self.num_bits & 7) as u32;
+            if bit_in_byte == 0 {
+                self.data.push(0);
+            }
+            let take = ::core::cmp::min(8 - bit_in_byte, remaining);
+            let last = self.data.len() - 1;
+            self.data[last] |= ((val & ((1 << take) - 1)) << bit_in_byte) as u8;
+            val >>= take;
+            remaining -= take;
+            self.num_bits += take as usize;
+        }
+    }
+    pub fn is_byte_aligned(&self) -> bool {
+        self.num_bits & 7 == 0
+    }
+    pub fn padding_bits(&self) -> u32 {
+        ((8 - (self.num_bits & 7)) & 7) as u32
+    }
+    pub fn write_bytes(&mut self, bytes: &[u8]) {
+        debug_assert!(self.is_byte_aligned());
+        self.data.extend(bytes);
+        self.num_bits += bytes.len() << 3;
+    }
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.data
+    }
+}
+
+// copies every bit it reads from the source into the sink
+// when reconstructing, the compressed stream is the sink, so byte alignment follows the sink
+pub struct TeeReader<'a, Source: BitSource + 'a> {
+    pub source: &'a mut Source,
+    pub sink: &'a mut BitWriter,
+    pub stream_is_sink: bool,
+}
+
+impl<'a, Source: BitSource + 'a> BitSource for TeeReader<'a, Source> {
+    fn read_bits(&mut self, num_bits: u32) -> Result<u32, ErrMsg> {
+        let ret = try!(self.source.read_bits(num_bits));
+        self.sink.write_bits(num_bits, ret);
+        Ok(ret)
+    }
+    fn padding_bits(&self) -> u32 {
+        if self.stream_is_sink {
+            self.sink.padding_bits()
+        } else {
+            self.source.padding_bits()
+        }
+    }
+}
@@ -0,0 +1,426 @@
+// Copyright 2017 Dropbox, Inc
+//
+//   Licensed under the Apache License, Version 2.0 (the "License");
+//   you may not use this file except in compliance with the License.
+//   You may obtain a copy of the License at
+//
+//       http://www.apache.org/licenses/LICENSE-2.0
+//
+//   Unless required by applicable law or agreed to in writing, software
+//   distributed under the License is distributed on an "AS IS" BASIS,
+//   WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//   See the License for the specific language governing permissions and
+//   limitations under the License.
+
+// The parts of the brotli format (RFC 7932) shared by the analyzer and the reconstructor:
+// stream and meta-block headers, block switching, commands and the distance cache.
+use std::boxed::Box;
+use std::vec::Vec;
+use ::interface::ErrMsg;
+use super::bit_io::{BitSource, BitWriter};
+use super::prefix::{PrefixCode, read_prefix_code, read_block_type_count, read_context_map};
+
+pub const NUM_LITERAL_SYMBOLS: usize = 256;
+pub const NUM_COMMAND_SYMBOLS: usize = 704;
+pub const NUM_BLOCK_COUNT_SYMBOLS: usize = 26;
+pub const LITERAL_CONTEXT_BITS: usize = 6;
+pub const DISTANCE_CONTEXT_BITS: usize = 2;
+pub const NUM_TRANSFORMS: u32 = 121;
+const MAX_ALLOWED_DISTANCE: i64 = 0x7fff_fffc;
+
+const BLOCK_COUNT_PREFIX: [(u32, u32); NUM_BLOCK_COUNT_SYMBOLS] = [
+    (1, 2), (5, 2), (9, 2), (13, 2), (17, 3), (25, 3), (33, 3), (41, 3),
+    (49, 4), (65, 4), (81, 4), (97, 4), (113, 5), (145, 5), (177, 5), (209, 5),
+    (241, 6), (305, 6), (369, 7), (497, 8), (753, 9), (1265, 10), (2289, 11), (4337, 12),
+    (8433, 13), (16625, 24)];
+
+pub const INSERT_LENGTH_PREFIX: [(u32, u32); 24] = [
+    (0, 0), (1, 0), (2, 0), (3, 0), (4, 0), (5, 0), (6, 1), (8, 1),
+    (10, 2), (14, 2), (18, 3), (26, 3), (34, 4), (50, 4), (66, 5), (98, 5),
+    (130, 6), (194, 7), (322, 8), (578, 9), (1090, 10), (2114, 12), (6210, 14), (22594, 24)];
+
+pub const COPY_LENGTH_PREFIX: [(u32, u32); 24] = [
+    (2, 0), (3, 0), (4, 0), (5, 0), (6, 0), (7, 0), (8, 0), (9, 0),
+    (10, 1), (12, 1), (14, 2), (18, 2), (22, 3), (30, 3), (38, 4), (54, 4),
+    (70, 5), (102, 5), (134, 6), (198, 7), (326, 8), (582, 9), (1094, 10), (2118, 24)];
+
+// insert and copy code offsets of each 64 symbol cell of the command alphabet
+const COMMAND_CELLS: [(u32, u32); 11] = [
+    (0, 0), (0, 8), (0, 0), (0, 8), (8, 0), (8, 8), (0, 16), (16, 0), (8, 16), (16, 8), (16, 16)];
+
+// the sliding window size described by the WBITS field
+pub fn read_window_bits<Source: BitSource>(source: &mut Source) -> Result<u32, ErrMsg> {
+    if try!(source.read_bits(1)) == 0 {
+        return Ok(16);
+    }
+    let n = try!(source.read_bits(3));
+    if n != 0 {
+        return Ok(17 + n);
+    }
+    let m = try!(source.read_bits(3));
+    match m {
+        0 => Ok(17),
+        1 => Err(ErrMsg::BrotliWindowUnsupported(m as u8)),
+        _ => Ok(8 + m),
+    }
+}
+
+pub fn write_window_bits(sink: &mut BitWriter, window_bits: u32) {
+    match window_bits {
+        16 => sink.write_bits(1, 0),
+        17 => sink.write_bits(7, 1),
+        _ if window_bits > 17 => sink.write_bits(4, 1 | ((window_bits - 17) << 1)),
+        _ => sink.write_bits(7, 1 | ((window_bits - 8) << 4)),
+    }
+}
+
+pub struct BlockCategory {
+    pub num_types: usize,
+    type_code: Option<PrefixCode>,
+    count_code: Option<PrefixCode>,
+    pub block_type: usize,
+    previous_type: usize,
+    pub remaining: u32,
+}
+
+fn read_block_count<Source: BitSource>(source: &mut Source, code: &PrefixCode) -> Result<u32, ErrMsg> {
+    let (offset, nbits) = BLOCK_COUNT_PREFIX[try!(code.read_symbol(source)) as usize];
+    Ok(offset + try!(source.read_bits(nbits)))
+}
+
+// returns the block count symbol along with the number and value of its extra bits
+pub fn encode_block_count(count: u32) -> (usize, u32, u32) {
+    let symbol = BLOCK_COUNT_PREFIX.iter().rposition(|prefix| prefix.0 <= count).unwrap_or(0);
+    let (offset, nbits) = BLOCK_COUNT_PREFIX[symbol];
+    (symbol, nbits, count - offset)
+}
+
+impl BlockCategory {
+    pub fn read<Source: BitSource>(source: &mut Source) -> Result<Self, ErrMsg> {
+        let num_types = try!(read_block_type_count(source));
+        let mut ret = BlockCategory {
+            num_types: num_types,
+            type_code: None,
+            count_code: None,
+            block_type: 0,
+            previous_type: 1,
+            remaining: 1 << 24,
+        };
+        if num_types >= 2 {
+            ret.type_code = Some(try!(read_prefix_code(source, num_types + 2)));
+            let count_code = try!(read_prefix_code(source, NUM_BLOCK_COUNT_SYMBOLS));
+            ret.remaining = try!(read_block_count(source, &count_code));
+            ret.count_code = Some(count_code);
+        }
+        Ok(ret)
+    }
+    // accounts for one more symbol of this category, reading a block switch if the current block is used up
+    // returns true if the block type changed
+    pub fn advance<Source: BitSource>(&mut self, source: &mut Source) -> Result<bool, ErrMsg> {
+        if self.num_types < 2 {
+            return Ok(false);
+        }
+        let mut switched = false;
+        if self.remaining == 0 {
+            let symbol = match self.type_code {
+                Some(ref code) => try!(code.read_symbol(source)) as usize,
+                None => unreachable!(),
+            };
+            let mut new_type = match symbol {
+                0 => self.previous_type,
+                1 => self.block_type + 1,
+                _ => symbol - 2,
+            };
+            if new_type >= self.num_types {
+                new_type -= self.num_types;
+            }
+            self.previous_type = self.block_type;
+            self.block_type = new_type;
+            self.remaining = match self.count_code {
+                Some(ref code) => try!(read_block_count(source, code)),
+                None => unreachable!(),
+            };
+            switched = true;
+        }
+        self.remaining -= 1;
+        Ok(switched)
+    }
+}
+
+pub struct CompressedHeader {
+    pub literal: BlockCategory,
+    pub command: BlockCategory,
+    pub distance: BlockCategory,
+    pub npostfix: u32,
+    pub ndirect: u32,
+    pub context_modes: Vec<u8>,
+    pub literal_context_map: Vec<u8>,
+    pub distance_context_map: Vec<u8>,
+    pub literal_codes: Vec<PrefixCode>,
+    pub command_codes: Vec<PrefixCode>,
+    pub distance_codes: Vec<PrefixCode>,
+}
+
+impl CompressedHeader {
+    pub fn num_distance_symbols(&self) -> usize {
+        16 + self.ndirect as usize + (48 << self.npostfix)
+    }
+}
+
+pub enum MetaBlockKind {
+    Compressed(Box<CompressedHeader>),
+    Uncompressed,
+    // the metadata itself is part of the header
+    Metadata,
+}
+
+pub struct MetaBlockHeader {
+    pub is_last: bool,
+    pub len: usize,
+    pub kind: MetaBlockKind,
+}
+
+fn read_compressed_header<Source: BitSource>(source: &mut Source) -> Result<CompressedHeader, ErrMsg> {
+    let literal = try!(BlockCategory::read(source));
+    let command = try!(BlockCategory::read(source));
+    let distance = try!(BlockCategory::read(source));
+    let npostfix = try!(source.read_bits(2));
+    let ndirect = try!(source.read_bits(4)) << npostfix;
+    let mut context_modes = vec![0u8; literal.num_types];
+    for mode in context_modes.iter_mut() {
+        *mode = try!(source.read_bits(2)) as u8;
+    }
+    let num_literal_trees = try!(read_block_type_count(source));
+    let literal_context_map = try!(read_context_map(source, literal.num_types << LITERAL_CONTEXT_BITS,
+                                                    num_literal_trees));
+    let num_distance_trees = try!(read_block_type_count(source));
+    let distance_context_map = try!(read_context_map(source, distance.num_types << DISTANCE_CONTEXT_BITS,
+                                                     num_distance_trees));
+    let mut ret = CompressedHeader {
+        literal: literal,
+        command: command,
+        distance: distance,
+        npostfix: npostfix,
+        ndirect: ndirect,
+        context_modes: context_modes,
+        literal_context_map: literal_context_map,
+        distance_context_map: distance_context_map,
+        literal_codes: Vec::<PrefixCode>::with_capacity(num_literal_trees),
+        command_codes: Vec::<PrefixCode>::new(),
+        distance_codes: Vec::<PrefixCode>::with_capacity(num_distance_trees),
+    };
+    for _ in 0..num_literal_trees {
+        ret.literal_codes.push(try!(read_prefix_code(source, NUM_LITERAL_SYMBOLS)));
+    }
+    for _ in 0..ret.command.num_types {
+        ret.command_codes.push(try!(read_prefix_code(source, NUM_COMMAND_SYMBOLS)));
+    }
+    let num_distance_symbols = ret.num_distance_symbols();
+    for _ in 0..num_distance_trees {
+        ret.distance_codes.push(try!(read_prefix_code(source, num_distance_symbols)));
+    }
+    Ok(ret)
+}
+
+// returns None once the empty final meta-block is read
+// everything up to the first data bit is read, including the padding of uncompressed meta-blocks
+// and the contents of metadata blocks
+pub fn read_meta_block_header<Source: BitSource>(source: &mut Source) -> Result<Option<MetaBlockHeader>, ErrMsg> {
+    let is_last = try!(source.read_bits(1)) != 0;
+    if is_last && try!(source.read_bits(1)) != 0 {
+        return Ok(None);
+    }
+    let nibbles = try!(source.read_bits(2));
+    if nibbles == 3 {
+        if try!(source.read_bits(1)) != 0 {
+            return Err(ErrMsg::BrotliBitstreamCorrupt(6));
+        }
+        let skip_bytes = try!(source.read_bits(2));
+        let mut len = 0usize;
+        for index in 0..skip_bytes {
+            let byte = try!(source.read_bits(8));
+            if index + 1 == skip_bytes && skip_bytes > 1 && byte == 0 {
+                return Err(ErrMsg::BrotliBitstreamCorrupt(7));
+            }
+            len |= (byte as usize) << (index * 8);
+        }
+        if skip_bytes != 0 {
+            len += 1;
+        }
+        if try!(source.read_to_byte_boundary()) != 0 {
+            return Err(ErrMsg::BrotliBitstreamCorrupt(8));
+        }
+        for _ in 0..len {
+            try!(source.read_bits(8));
+        }
+        return Ok(Some(MetaBlockHeader{is_last: is_last, len: 0, kind: MetaBlockKind::Metadata}));
+    }
+    let nibbles = nibbles + 4;
+    let mut len = 0usize;
+    for index in 0..nibbles {
+        let nibble = try!(source.read_bits(4));
+        if index + 1 == nibbles && nibbles > 4 && nibble == 0 {
+            return Err(ErrMsg::BrotliBitstreamCorrupt(9));
+        }
+        len |= (nibble as usize) << (index * 4);
+    }
+    len += 1;
+    if !is_last && try!(source.read_bits(1)) != 0 {
+        if try!(source.read_to_byte_boundary()) != 0 {
+            return Err(ErrMsg::BrotliBitstreamCorrupt(10));
+        }
+        return Ok(Some(MetaBlockHeader{is_last: is_last, len: len, kind: MetaBlockKind::Uncompressed}));
+    }
+    let header = try!(read_compressed_header(source));
+    Ok(Some(MetaBlockHeader{is_last: is_last, len: len, kind: MetaBlockKind::Compressed(Box::new(header))}))
+}
+
+pub fn insert_length_code(insert_len: u32) -> usize {
+    INSERT_LENGTH_PREFIX.iter().rposition(|prefix| prefix.0 <= insert_len).unwrap_or(0)
+}
+
+pub fn copy_length_code(copy_len: u32) -> usize {
+    COPY_LENGTH_PREFIX.iter().rposition(|prefix| prefix.0 <= copy_len).unwrap_or(0)
+}
+
+// splits a command symbol into its insert code, copy code and whether it reuses the last distance
+pub fn split_command_symbol(symbol: u16) -> (usize, usize, bool) {
+    let cell = COMMAND_CELLS[(symbol >> 6) as usize];
+    let insert_code = cell.0 as usize + ((symbol as usize >> 3) & 7);
+    let copy_code = cell.1 as usize + (symbol as usize & 7);
+    (insert_code, copy_code, symbol < 128)
+}
+
+pub fn combine_command_symbol(insert_code: usize, copy_code: usize, implicit_distance: bool) -> u16 {
+    let low_bits = (copy_code & 7) | ((insert_code & 7) << 3);
+    if implicit_distance {
+        debug_assert!(insert_code < 8 && copy_code < 16);
+        return (if copy_code < 8 { low_bits } else { low_bits | 64 }) as u16;
+    }
+    let offset = 2 * ((copy_code >> 3) + 3 * (insert_code >> 3));
+    let offset = (offset << 5) + 0x40 + ((0x0052_0d40 >> offset) & 0xc0);
+    (offset | low_bits) as u16
+}
+
+pub fn can_use_implicit_distance(insert_code: usize, copy_code: usize) -> bool {
+    insert_code < 8 && copy_code < 16
+}
+
+pub fn distance_context(copy_len: u32) -> usize {
+    if copy_len > 4 { 3 } else { copy_len as usize - 2 }
+}
+
+// the ring of the last four distances, updated the way the reference decoder does it
+pub struct DistanceCache {
+    ring: [i64; 4],
+    index: usize,
+}
+
+impl Default for DistanceCache {
+    fn default() -> Self {
+        DistanceCache {
+            ring: [16, 15, 11, 4],
+            index: 0,
+        }
+    }
+}
+
+impl DistanceCache {
+    fn recent(&self, age: usize) -> i64 {
+        self.ring[self.index.wrapping_sub(age + 1) & 3]
+    }
+    pub fn short_code_distance(&self, code: u32) -> i64 {
+        match code {
+            0 | 1 | 2 | 3 => self.recent(code as usize),
+            _ => {
+                const DELTAS: [i64; 6] = [-1, 1, -2, 2, -3, 3];
+                let (base, age) = if code < 10 { (code - 4, 0) } else { (code - 10, 1) };
+                let distance = self.recent(age) + DELTAS[base as usize];
+                if distance <= 0 { 0x7fff_ffff } else { distance }
+            },
+        }
+    }
+    // the reference decoder rewinds the ring for the last distance and pushes every distance
+    // that is not a dictionary reference, so only these two cases change the ring
+    pub fn update(&mut self, reuses_last_distance: bool, is_dictionary: bool, distance: i64) {
+        if is_dictionary {
+            if reuses_last_distance {
+                self.index = self.index.wrapping_sub(1);
+            }
+        } else if !reuses_last_distance {
+            self.ring[self.index & 3] = distance;
+            self.index = self.index.wrapping_add(1);
+        }
+    }
+    // the short code an encoder would pick for a distance within the window, following the
+    // reference encoder's preference order
+    pub fn preferred_short_code(&self, distance: i64) -> Option<u32> {
+        let last = self.recent(0);
+        let second = self.recent(1);
+        if distance == last {
+            return Some(0);
+        }
+        if distance == second {
+            return Some(1);
+        }
+        let offset0 = distance + 3 - last;
+        if (0..7).contains(&offset0) {
+            return Some(((0x0975_0468 >> (4 * offset0)) & 0xf) as u32);
+        }
+        let offset1 = distance + 3 - second;
+        if (0..7).contains(&offset1) {
+            return Some(((0x0fdb_1ace >> (4 * offset1)) & 0xf) as u32);
+        }
+        if distance == self.recent(2) {
+            return Some(2);
+        }
+        if distance == self.recent(3) {
+            return Some(3);
+        }
+        None
+    }
+}
+
+pub fn decode_distance<Source: BitSource>(source: &mut Source,
+                                          symbol: u32,
+                                          npostfix: u32,
+                                          ndirect: u32) -> Result<i64, ErrMsg> {
+    if symbol < 16 + ndirect {
+        return Ok(i64::from(symbol) - 15);
+    }
+    let code = symbol - ndirect - 16;
+    let ndistbits = 1 + (code >> (npostfix + 1));
+    let hcode = code >> npostfix;
+    let lcode = code & ((1 << npostfix) - 1);
+    let offset = ((2 + i64::from(hcode & 1)) << ndistbits) - 4;
+    let extra = i64::from(try!(source.read_bits(ndistbits)));
+    Ok(((offset + extra) << npostfix) + i64::from(lcode) + i64::from(ndirect) + 1)
+}
+
+// returns the distance symbol along with the number and value of its extra bits
+pub fn encode_distance(distance: i64, npostfix: u32, ndirect: u32) -> (u32, u32, u32) {
+    let distance_code = distance as u64 + 15;
+    if distance_code < u64::from(16 + ndirect) {
+        return (distance_code as u32, 0, 0);
+    }
+    let dist = (1u64 << (npostfix + 2)) + (distance_code - 16 - u64::from(ndirect));
+    let bucket = 63 - dist.leading_zeros() - 1;
+    let postfix = dist & ((1 << npostfix) - 1);
+    let prefix = (dist >> bucket) & 1;
+    let offset = (2 + prefix) << bucket;
+    let nbits = bucket - npostfix;
+    let symbol = 16 + u64::from(ndirect) + ((2 * (u64::from(nbits) - 1) + prefix) << npostfix) + postfix;
+    (symbol as u32, nbits, ((dist - offset) >> npostfix) as u32)
+}
+
+pub fn check_distance(distance: i64) -> Result<(), ErrMsg> {
+    if distance > MAX_ALLOWED_DISTANCE {
+        return Err(ErrMsg::BrotliBitstreamCorrupt(11));
+    }
+    Ok(())
+}
+
+pub fn write_length_extra(sink: &mut BitWriter, prefix: &[(u32, u32)], code: usize, len: u32) {
+    let (offset, nbits) = prefix[code];
+    sink.write_bits(nbits, len - offset);
+}
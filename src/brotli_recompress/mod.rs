@@ -0,0 +1,497 @@
+// Copyright 2017 Dropbox, Inc
+//
+//   Licensed under the Apache License, Version 2.0 (the "License");
+//   you may not use this file except in compliance with the License.
+//   You may obtain a copy of the License at
+//
+//       http://www.apache.org/licenses/LICENSE-2.0
+//
+//   Unless required by applicable law or agreed to in writing, software
+//   distributed under the License is distributed on an "AS IS" BASIS,
+//   WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//   See the License for the specific language governing permissions and
+//   limitations under the License.
+
+// Lossless recompression of brotli files: the commands of the brotli stream are encoded as divans IR
+// and every bit of the brotli stream that cannot be derived from those commands (headers, prefix codes,
+// block switches and the few distance codes an encoder chose unusually) is kept as side data,
+// so the original file can be rebuilt bit for bit.
+#![cfg(not(feature="no-stdlib"))]
+pub mod bit_io;
+pub mod prefix;
+mod bitstream;
+pub mod analyze;
+mod reconstruct;
+mod transcode;
+
+use std::vec::Vec;
+use alloc::HeapAlloc;
+use ::brotli;
+use ::byte_util::{push_varint, read_u32, read_varint, write_u32};
+use ::interface;
+use ::interface::{Command, Compressor, Decompressor, DivansCompressorFactory, DivansCompressorOptions,
+                  DivansResult, DivansOutputResult, ErrMsg, FeatureFlagSliceType, LiteralCommand,
+                  LiteralPredictionModeNibble, PredictionModeContextMap, BlockSwitch, LiteralBlockSwitch};
+use ::brotli::interface::Nop;
+use ::{DivansDecompressorFactory, DivansDecompressorFactoryStruct, StaticCommand};
+use self::analyze::IrCommand;
+use self::bitstream::{DistanceCache, can_use_implicit_distance, combine_command_symbol};
+use self::prefix::PrefixCode;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DistanceChoice {
+    Short(u8),
+    General,
+    // the distance reused by a command symbol below 128 without reading a distance code
+    Implicit,
+}
+
+const DISTANCE_CHOICE_GENERAL: u8 = 16;
+const DISTANCE_CHOICE_IMPLICIT: u8 = 17;
+
+impl DistanceChoice {
+    pub fn reuses_last_distance(&self) -> bool {
+        match *self {
+            DistanceChoice::Short(code) => code == 0,
+            DistanceChoice::General => false,
+            DistanceChoice::Implicit => true,
+        }
+    }
+    fn serialize(&self) -> u8 {
+        match *self {
+            DistanceChoice::Short(code) => code,
+            DistanceChoice::General => DISTANCE_CHOICE_GENERAL,
+            DistanceChoice::Implicit => DISTANCE_CHOICE_IMPLICIT,
+        }
+    }
+    fn deserialize(val: u8) -> Result<Self, ErrMsg> {
+        match val {
+            _ if val < 16 => Ok(DistanceChoice::Short(val)),
+            DISTANCE_CHOICE_GENERAL => Ok(DistanceChoice::General),
+            DISTANCE_CHOICE_IMPLICIT => Ok(DistanceChoice::Implicit),
+            _ => Err(ErrMsg::BrotliSideDataCorrupt(1)),
+        }
+    }
+}
+
+// how the reference encoder would express a distance; only deviations from this are stored
+pub fn canonical_distance_choice(cache: &DistanceCache,
+                                 distance: i64,
+                                 max_distance: i64,
+                                 insert_code: usize,
+                                 copy_code: usize,
+                                 command_code: &PrefixCode) -> DistanceChoice {
+    if distance > max_distance {
+        return DistanceChoice::General;
+    }
+    match cache.preferred_short_code(distance) {
+        Some(0) => {
+            if can_use_implicit_distance(insert_code, copy_code)
+                && command_code.has_symbol(combine_command_symbol(insert_code, copy_code, true)) {
+                DistanceChoice::Implicit
+            } else {
+                DistanceChoice::Short(0)
+            }
+        },
+        Some(code) => DistanceChoice::Short(code as u8),
+        None => DistanceChoice::General,
+    }
+}
+
+// each exception is the number of commands since the previous one followed by the actual choice
+#[derive(Default)]
+pub struct DistanceExceptionWriter {
+    data: Vec<u8>,
+    since_last: u64,
+}
+
+impl DistanceExceptionWriter {
+    pub fn push(&mut self, actual: DistanceChoice, canonical: DistanceChoice) {
+        if actual == canonical {
+            self.since_last += 1;
+            return;
+        }
+        push_varint(&mut self.data, self.since_last);
+        self.data.push(actual.serialize());
+        self.since_last = 0;
+    }
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.data
+    }
+}
+
+pub struct DistanceExceptionReader<'a> {
+    data: &'a [u8],
+    offset: usize,
+    until_next: Option<u64>,
+}
+
+impl<'a> DistanceExceptionReader<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        DistanceExceptionReader {
+            data: data,
+            offset: 0,
+            until_next: None,
+        }
+    }
+    pub fn next(&mut self, canonical: DistanceChoice) -> Result<DistanceChoice, ErrMsg> {
+        if self.until_next.is_none() && self.offset < self.data.len() {
+            self.until_next = Some(try!(read_varint(self.data, &mut self.offset).ok_or(ErrMsg::BrotliSideDataCorrupt(2))));
+        }
+        match self.until_next {
+            Some(0) => {
+                self.until_next = None;
+                match self.data.get(self.offset) {
+                    Some(val) => {
+                        self.offset += 1;
+                        DistanceChoice::deserialize(*val)
+                    },
+                    None => Err(ErrMsg::BrotliSideDataCorrupt(4)),
+                }
+            },
+            Some(count) => {
+                self.until_next = Some(count - 1);
+                Ok(canonical)
+            },
+            None => Ok(canonical),
+        }
+    }
+    pub fn is_done(&self) -> bool {
+        self.until_next.is_none() && self.offset == self.data.len()
+    }
+}
+
+// the literal context of the next byte given everything decoded so far
+pub fn literal_context(output: &[u8], mode: u8) -> usize {
+    let p1 = if output.is_empty() { 0 } else { output[output.len() - 1] };
+    let p2 = if output.len() < 2 { 0 } else { output[output.len() - 2] };
+    let context_type = LiteralPredictionModeNibble(mode).to_context_enum().unwrap();
+    usize::from(brotli::enc::histogram::Context(p1, p2, context_type))
+}
+
+pub fn read_section(data: &[u8], offset: &mut usize) -> Result<Vec<u8>, ErrMsg> {
+    if data.len() - *offset < 4 {
+        return Err(ErrMsg::BrotliSideDataCorrupt(6));
+    }
+    let len = read_u32(&data[*offset..]) as usize;
+    *offset += 4;
+    if data.len() - *offset < len {
+        return Err(ErrMsg::BrotliSideDataCorrupt(7));
+    }
+    *offset += len;
+    Ok(data[*offset - len..*offset].to_vec())
+}
+
+struct SideData {
+    bits: Vec<u8>,
+    distance_exceptions: Vec<u8>,
+    tail: Vec<u8>,
+}
+
+impl SideData {
+    fn serialize(&self) -> Vec<u8> {
+        let mut ret = Vec::<u8>::with_capacity(12 + self.bits.len() + self.distance_exceptions.len() + self.tail.len());
+        for item in [&self.bits, &self.distance_exceptions, &self.tail].iter() {
+            write_u32(&mut ret, item.len() as u32);
+            ret.extend(&item[..]);
+        }
+        ret
+    }
+    fn deserialize(data: &[u8]) -> Result<Self, ErrMsg> {
+        let mut offset = 0;
+        let ret = SideData {
+            bits: try!(read_section(data, &mut offset)),
+            distance_exceptions: try!(read_section(data, &mut offset)),
+            tail: try!(read_section(data, &mut offset)),
+        };
+        if offset != data.len() {
+            return Err(ErrMsg::BrotliSideDataCorrupt(5));
+        }
+        Ok(ret)
+    }
+}
+
+pub fn brotli_compress_side_data(data: &[u8]) -> Vec<u8> {
+    let mut ret = Vec::<u8>::new();
+    let mut params = brotli::enc::BrotliEncoderInitParams();
+    params.quality = 9;
+    params.lgwin = 22;
+    brotli::BrotliCompress(&mut &data[..], &mut ret, &params).unwrap();
+    ret
+}
+
+pub fn brotli_decompress_side_data(data: &[u8]) -> Result<Vec<u8>, ErrMsg> {
+    let mut ret = Vec::<u8>::new();
+    match brotli::BrotliDecompress(&mut &data[..], &mut ret) {
+        Ok(_) => Ok(ret),
+        Err(_) => Err(ErrMsg::BrotliSideDataCorrupt(8)),
+    }
+}
+
+type DivansCustomFactory = ::DivansCompressorFactoryStruct<HeapAlloc<u8>, HeapAlloc<::DefaultCDF16>>;
+
+fn convert_command<'a>(cmd: &IrCommand,
+                       output: &'a [u8],
+                       prediction_modes: &'a [(Vec<u8>, Vec<u8>)]) -> Command<brotli::InputReference<'a>> {
+    match *cmd {
+        IrCommand::Literal(offset, len) => Command::Literal(LiteralCommand{
+            data: brotli::InputReference{data: &output[offset..offset + len], orig_offset: offset},
+            prob: FeatureFlagSliceType::<brotli::InputReference>::default(),
+            high_entropy: false,
+        }),
+        IrCommand::Copy(copy) => Command::Copy(copy),
+        IrCommand::Dict(dict) => Command::Dict(dict),
+        IrCommand::BlockSwitchCommand(block_type) => Command::BlockSwitchCommand(BlockSwitch::new(block_type)),
+        IrCommand::BlockSwitchDistance(block_type) => Command::BlockSwitchDistance(BlockSwitch::new(block_type)),
+        IrCommand::BlockSwitchLiteral(block_type) => Command::BlockSwitchLiteral(LiteralBlockSwitch::new(block_type, 0)),
+        IrCommand::PredictionMode(index) => Command::PredictionMode(PredictionModeContextMap::<brotli::InputReference> {
+            literal_context_map: brotli::InputReference{data: &prediction_modes[index].0[..], orig_offset: 0},
+            predmode_speed_and_distance_context_map: brotli::InputReference{data: &prediction_modes[index].1[..], orig_offset: 0},
+        }),
+    }
+}
+
+pub fn encode_analysis(analysis: &analyze::Analysis, mut opts: DivansCompressorOptions, output: &mut Vec<u8>) -> Result<(), ErrMsg> {
+    opts.window_size = Some(analysis.window_bits as i32);
+    let mut compressor = DivansCustomFactory::new(HeapAlloc::<u8>::new(0),
+                                                  HeapAlloc::<u32>::new(0),
+                                                  HeapAlloc::<::DefaultCDF16>::new(::DefaultCDF16::default()),
+                                                  opts,
+                                                  ());
+    let mut output_scratch = vec![0u8; 65_536];
+    let mut o_processed_index = 0usize;
+    let mut ret = Ok(());
+    for chunk in analysis.commands.chunks(::CMD_BUFFER_SIZE) {
+        let cmd_buffer: Vec<Command<brotli::InputReference>> = chunk.iter().map(
+            |cmd| convert_command(cmd, &analysis.output[..], &analysis.prediction_modes[..])).collect();
+        let mut i_processed_index = 0usize;
+        while i_processed_index < cmd_buffer.len() {
+            match compressor.encode_commands(&cmd_buffer[..], &mut i_processed_index,
+                                             &mut output_scratch[..], &mut o_processed_index) {
+                DivansOutputResult::Success => {},
+                DivansOutputResult::NeedsMoreOutput => {
+                    output.extend(&output_scratch[..o_processed_index]);
+                    o_processed_index = 0;
+                },
+                DivansOutputResult::Failure(m) => {
+                    ret = Err(m);
+                    break;
+                },
+            }
+        }
+        if ret.is_err() {
+            break;
+        }
+    }
+    while ret.is_ok() {
+        match compressor.flush(&mut output_scratch[..], &mut o_processed_index) {
+            DivansOutputResult::Success => break,
+            DivansOutputResult::NeedsMoreOutput => {},
+            DivansOutputResult::Failure(m) => ret = Err(m),
+        }
+        output.extend(&output_scratch[..o_processed_index]);
+        o_processed_index = 0;
+    }
+    output.extend(&output_scratch[..o_processed_index]);
+    compressor.free();
+    ret
+}
+
+// decodes a divans stream, returning the output along with the commands that produced it
+pub fn decode_with_command_log(input: &[u8]) -> Result<(Vec<u8>, Vec<u8>), ErrMsg> {
+    let mut decompressor = DivansDecompressorFactoryStruct::<HeapAlloc<u8>,
+                                                             HeapAlloc<::DefaultCDF16>,
+                                                             HeapAlloc<StaticCommand>>::new(
+        HeapAlloc::<u8>::new(0),
+        HeapAlloc::<::DefaultCDF16>::new(::DefaultCDF16::default()),
+        HeapAlloc::<StaticCommand>::new(StaticCommand::nop()),
+        false,
+        false);
+    try!(decompressor.enable_command_log());
+    let mut output = Vec::<u8>::new();
+    let mut command_log = Vec::<u8>::new();
+    let mut output_scratch = vec![0u8; 65_536];
+    let mut input_offset = 0usize;
+    let ret;
+    loop {
+        let mut output_offset = 0usize;
+        let res = decompressor.decode(input, &mut input_offset, &mut output_scratch[..], &mut output_offset);
+        output.extend(&output_scratch[..output_offset]);
+        command_log.extend(decompressor.command_log());
+        decompressor.clear_command_log();
+        match res {
+            DivansResult::Success => {
+                ret = Ok(());
+                break;
+            },
+            DivansResult::NeedsMoreOutput => {},
+            DivansResult::NeedsMoreInput => {
+                ret = Err(ErrMsg::UnexpectedEof);
+                break;
+            },
+            DivansResult::Failure(m) => {
+                ret = Err(m);
+                break;
+            },
+        }
+    }
+    decompressor.free();
+    ret.map(|_| (output, command_log))
+}
+
+pub fn is_recompressed_brotli(data: &[u8]) -> bool {
+    data.len() >= interface::RECOMPRESSED_BROTLI_HEADER_LENGTH
+        && data[..4] == interface::RECOMPRESSED_BROTLI_MAGIC[..]
+}
+
+// converts a brotli file into a smaller divans based file that reconstruct_brotli turns back into
+// exactly the same bytes; the result is verified before it is returned
+pub fn recompress_brotli(brotli_file: &[u8], opts: DivansCompressorOptions) -> Result<Vec<u8>, ErrMsg> {
+    let analysis = try!(analyze::analyze(brotli_file));
+    let side_data = SideData {
+        bits: analysis.side_bits.clone(),
+        distance_exceptions: analysis.distance_exceptions.clone(),
+        tail: analysis.tail.clone(),
+    };
+    let compressed_side_data = brotli_compress_side_data(&side_data.serialize()[..]);
+    let mut ret = Vec::<u8>::with_capacity(brotli_file.len());
+    ret.extend(&interface::RECOMPRESSED_BROTLI_MAGIC[..]);
+    ret.extend(&[interface::RECOMPRESSED_BROTLI_VERSION, 0, 0, 0]);
+    write_u32(&mut ret, compressed_side_data.len() as u32);
+    ret.extend(&compressed_side_data[..]);
+    try!(encode_analysis(&analysis, opts, &mut ret));
+    match reconstruct_brotli(&ret[..]) {
+        Ok(ref reconstructed) if reconstructed[..] == brotli_file[..] => Ok(ret),
+        _ => Err(ErrMsg::BrotliRoundTripMismatch),
+    }
+}
+
+pub fn reconstruct_brotli(data: &[u8]) -> Result<Vec<u8>, ErrMsg> {
+    if !is_recompressed_brotli(data) {
+        return Err(ErrMsg::MagicNumberWrongA(data.first().cloned().unwrap_or(0), data.get(1).cloned().unwrap_or(0)));
+    }
+    if data[4] != interface::RECOMPRESSED_BROTLI_VERSION {
+        return Err(ErrMsg::UnsupportedFormatVersion(data[4]));
+    }
+    let side_len = read_u32(&data[8..]) as usize;
+    let body = &data[interface::RECOMPRESSED_BROTLI_HEADER_LENGTH..];
+    if body.len() < side_len {
+        return Err(ErrMsg::BrotliSideDataCorrupt(9));
+    }
+    let side_data = try!(SideData::deserialize(&try!(brotli_decompress_side_data(&body[..side_len]))[..]));
+    let (output, command_log) = try!(decode_with_command_log(&body[side_len..]));
+    let mut ret = try!(reconstruct::reconstruct(&side_data.bits[..],
+                                                &side_data.distance_exceptions[..],
+                                                &output[..],
+                                                &command_log[..]));
+    ret.extend(&side_data.tail[..]);
+    Ok(ret)
+}
+
+// converts a divans stream into a standard brotli stream that reuses the commands chosen when
+// the divans stream was compressed instead of searching for matches again
+pub fn transcode_to_brotli(divans_file: &[u8]) -> Result<Vec<u8>, ErrMsg> {
+    let (output, command_log) = try!(decode_with_command_log(divans_file));
+    transcode::transcode(&output[..], &command_log[..])
+}
+
+#[cfg(test)]
+mod test {
+    use std::vec::Vec;
+    use ::brotli;
+    use ::interface::{DivansCompressorOptions, ErrMsg};
+    fn brotli_compress_tst(data: &[u8], quality: i32, lgwin: i32) -> Vec<u8> {
+        let mut params = brotli::enc::BrotliEncoderInitParams();
+        params.quality = quality;
+        params.lgwin = lgwin;
+        let mut ret = Vec::<u8>::new();
+        brotli::BrotliCompress(&mut &data[..], &mut ret, &params).unwrap();
+        ret
+    }
+    fn recompress_tst(brotli_file: &[u8], expected_output: &[u8]) -> Vec<u8> {
+        let analysis = super::analyze::analyze(brotli_file).unwrap();
+        assert_eq!(&analysis.output[..], expected_output);
+        let recompressed = super::recompress_brotli(brotli_file, DivansCompressorOptions::default()).unwrap();
+        assert!(super::is_recompressed_brotli(&recompressed[..]));
+        assert_eq!(&super::reconstruct_brotli(&recompressed[..]).unwrap()[..], brotli_file);
+        recompressed
+    }
+    fn brotli_decompress_tst(brotli_file: &[u8]) -> Vec<u8> {
+        let mut ret = Vec::<u8>::new();
+        brotli::BrotliDecompress(&mut &brotli_file[..], &mut ret).unwrap();
+        ret
+    }
+    // a divans stream carrying the commands, block switches and prediction modes of a brotli stream
+    fn divans_from_brotli_tst(brotli_file: &[u8]) -> Vec<u8> {
+        let analysis = super::analyze::analyze(brotli_file).unwrap();
+        let mut ret = Vec::<u8>::new();
+        super::encode_analysis(&analysis, DivansCompressorOptions::default(), &mut ret).unwrap();
+        ret
+    }
+    #[test]
+    fn test_recompress_alice_qualities() {
+        let data = include_bytes!("../../testdata/alice29");
+        for &(quality, lgwin) in [(0, 16), (1, 10), (5, 22), (9, 18), (11, 22)].iter() {
+            let brotli_file = brotli_compress_tst(&data[..], quality, lgwin);
+            recompress_tst(&brotli_file[..], &data[..]);
+        }
+    }
+    #[test]
+    fn test_recompress_smaller_than_brotli() {
+        let data = include_bytes!("../../testdata/asyoulik");
+        let brotli_file = brotli_compress_tst(&data[..], 9, 22);
+        let recompressed = recompress_tst(&brotli_file[..], &data[..]);
+        assert!(recompressed.len() < brotli_file.len());
+    }
+    #[test]
+    fn test_recompress_mixed_content() {
+        let data = include_bytes!("../../testdata/random_then_unicode");
+        let brotli_file = brotli_compress_tst(&data[..], 11, 16);
+        recompress_tst(&brotli_file[..], &data[..]);
+    }
+    #[test]
+    fn test_recompress_empty_and_trailing_bytes() {
+        let brotli_file = brotli_compress_tst(&[], 5, 22);
+        recompress_tst(&brotli_file[..], &[]);
+        let data = include_bytes!("../../testdata/alice29");
+        let mut brotli_file = brotli_compress_tst(&data[..10000], 6, 20);
+        brotli_file.extend(b"trailing garbage");
+        recompress_tst(&brotli_file[..], &data[..10000]);
+    }
+    #[test]
+    fn test_recompress_rejects_invalid() {
+        let data = include_bytes!("../../testdata/alice29");
+        let brotli_file = brotli_compress_tst(&data[..], 9, 22);
+        match super::recompress_brotli(&brotli_file[..brotli_file.len() / 2], DivansCompressorOptions::default()) {
+            Err(ErrMsg::BrotliBitstreamTruncated) => {},
+            _ => panic!("truncated brotli stream accepted"),
+        }
+        let mut recompressed = super::recompress_brotli(&brotli_file[..], DivansCompressorOptions::default()).unwrap();
+        recompressed[4] = 0xff;
+        match super::reconstruct_brotli(&recompressed[..]) {
+            Err(ErrMsg::UnsupportedFormatVersion(0xff)) => {},
+            _ => panic!("unknown version accepted"),
+        }
+    }
+    #[test]
+    fn test_transcode_keeps_brotli_parse() {
+        let data = include_bytes!("../../testdata/alice29");
+        for &(quality, lgwin) in [(1, 10), (5, 18), (9, 22), (11, 22)].iter() {
+            let brotli_file = brotli_compress_tst(&data[..], quality, lgwin);
+            let transcoded = super::transcode_to_brotli(&divans_from_brotli_tst(&brotli_file[..])[..]).unwrap();
+            assert_eq!(&brotli_decompress_tst(&transcoded[..])[..], &data[..]);
+            // only the prefix codes differ, so the size stays close to the original
+            assert!(transcoded.len() < brotli_file.len() + brotli_file.len() / 20);
+        }
+    }
+    #[test]
+    fn test_transcode_mixed_content_and_empty() {
+        let data = include_bytes!("../../testdata/random_then_unicode");
+        let brotli_file = brotli_compress_tst(&data[..], 11, 16);
+        let transcoded = super::transcode_to_brotli(&divans_from_brotli_tst(&brotli_file[..])[..]).unwrap();
+        assert_eq!(&brotli_decompress_tst(&transcoded[..])[..], &data[..]);
+        let empty = super::transcode_to_brotli(&divans_from_brotli_tst(&brotli_compress_tst(&[], 5, 22)[..])[..])
+            .unwrap();
+        assert_eq!(brotli_decompress_tst(&empty[..]).len(), 0);
+    }
+}
@@ -0,0 +1,593 @@
+// Copyright 2017 Dropbox, Inc
+//
+//   Licensed under the Apache License, Version 2.0 (the "License");
+//   you may not use this file except in compliance with the License.
+//   You may obtain a copy of the License at
+//
+//       http://www.apache.org/licenses/LICENSE-2.0
+//
+//   Unless required by applicable law or agreed to in writing, software
+//   distributed under the License is distributed on an "AS IS" BASIS,
+//   WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//   See the License for the specific language governing permissions and
+//   limitations under the License.
+
+// Brotli prefix code descriptions (RFC 7932 section 3) and context maps (section 7.3).
+// Decoding and encoding are both done symbol by symbol against the canonical code.
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::vec::Vec;
+use ::interface::ErrMsg;
+use super::bit_io::{BitSource, BitWriter};
+
+const MAX_CODE_LENGTH: usize = 15;
+const CODE_LENGTH_CODES: usize = 18;
+const CODE_LENGTH_ORDER: [u8; CODE_LENGTH_CODES] = [1, 2, 3, 4, 0, 5, 17, 6, 16, 7, 8, 9, 10, 11, 12, 13, 14, 15];
+const MAX_CODE_LENGTH_CODE_LENGTH: usize = 5;
+const INITIAL_REPEATED_CODE_LENGTH: u8 = 8;
+const REPEAT_PREVIOUS_CODE_LENGTH: u8 = 16;
+const REPEAT_ZERO_CODE_LENGTH: u8 = 17;
+const MAX_CONTEXT_MAP_RUN_LENGTH_PREFIX: u32 = 6;
+
+pub struct PrefixCode {
+    // canonical code of each symbol, bit reversed so it can be written LSB first
+    codes: Vec<u16>,
+    lengths: Vec<u8>,
+    count_per_length: [u16; MAX_CODE_LENGTH + 1],
+    // symbols ordered by (length, symbol)
+    sorted: Vec<u16>,
+    // a code with a single symbol uses no bits at all
+    single_symbol: Option<u16>,
+}
+
+fn reverse_bits(mut code: u16, len: u8) -> u16 {
+    let mut ret = 0u16;
+    for _ in 0..len {
+        ret = (ret << 1) | (code & 1);
+        code >>= 1;
+    }
+    ret
+}
+
+impl PrefixCode {
+    pub fn from_lengths(lengths: Vec<u8>) -> Self {
+        let mut count_per_length = [0u16; MAX_CODE_LENGTH + 1];
+        for len in lengths.iter() {
+            count_per_length[*len as usize] += 1;
+        }
+        count_per_length[0] = 0;
+        let mut next_code = [0u16; MAX_CODE_LENGTH + 1];
+        let mut code = 0u16;
+        for bits in 1..(MAX_CODE_LENGTH + 1) {
+            code = (code + count_per_length[bits - 1]) << 1;
+            next_code[bits] = code;
+        }
+        let mut codes = vec![0u16; lengths.len()];
+        for (symbol, len) in lengths.iter().enumerate() {
+            if *len != 0 {
+                codes[symbol] = reverse_bits(next_code[*len as usize], *len);
+                next_code[*len as usize] += 1;
+            }
+        }
+        let mut sorted = Vec::<u16>::with_capacity(lengths.len());
+        for len in 1..(MAX_CODE_LENGTH + 1) {
+            for (symbol, sym_len) in lengths.iter().enumerate() {
+                if *sym_len as usize == len {
+                    sorted.push(symbol as u16);
+                }
+            }
+        }
+        PrefixCode {
+            codes: codes,
+            lengths: lengths,
+            count_per_length: count_per_length,
+            sorted: sorted,
+            single_symbol: None,
+        }
+    }
+    fn single(symbol: u16, alphabet_size: usize) -> Self {
+        let mut ret = PrefixCode::from_lengths(vec![0u8; alphabet_size]);
+        ret.single_symbol = Some(symbol);
+        ret
+    }
+    pub fn has_symbol(&self, symbol: u16) -> bool {
+        match self.single_symbol {
+            Some(single) => single == symbol,
+            None => self.lengths.get(symbol as usize).map(|len| *len != 0).unwrap_or(false),
+        }
+    }
+    pub fn read_symbol<Source: BitSource>(&self, source: &mut Source) -> Result<u16, ErrMsg> {
+        if let Some(symbol) = self.single_symbol {
+            return Ok(symbol);
+        }
+        let mut code = 0i32;
+        let mut first = 0i32;
+        let mut index = 0i32;
+        for len in 1..(MAX_CODE_LENGTH + 1) {
+            code |= try!(source.read_bits(1)) as i32;
+            let count = i32::from(self.count_per_length[len]);
+            if code - first < count {
+                return Ok(self.sorted[(index + code - first) as usize]);
+            }
+            index += count;
+            first = (first + count) << 1;
+            code <<= 1;
+        }
+        Err(ErrMsg::BrotliBitstreamCorrupt(0))
+    }
+    pub fn write_symbol(&self, symbol: u16, sink: &mut BitWriter) -> Result<(), ErrMsg> {
+        if !self.has_symbol(symbol) {
+            return Err(ErrMsg::BrotliSideDataCorrupt(0));
+        }
+        if self.single_symbol.is_none() {
+            sink.write_bits(u32::from(self.lengths[symbol as usize]), u32::from(self.codes[symbol as usize]));
+        }
+        Ok(())
+    }
+}
+
+fn alphabet_bits(alphabet_size: usize) -> u32 {
+    let mut bits = 0;
+    while (alphabet_size - 1) >> bits != 0 {
+        bits += 1;
+    }
+    bits
+}
+
+// the fixed code for each code length code length, as (number of bits, bits in the order they are written)
+const CODE_LENGTH_CODE_LENGTH_CODES: [(u32, u32); MAX_CODE_LENGTH_CODE_LENGTH + 1] = [
+    (2, 0), (4, 7), (3, 3), (2, 2), (2, 1), (4, 15)];
+
+fn read_code_length_code_length<Source: BitSource>(source: &mut Source) -> Result<u8, ErrMsg> {
+    // the fixed code from RFC 7932 section 3.5, in the order the bits arrive
+    if try!(source.read_bits(1)) == 0 {
+        return Ok(if try!(source.read_bits(1)) == 0 { 0 } else { 3 });
+    }
+    if try!(source.read_bits(1)) == 0 {
+        return Ok(4);
+    }
+    if try!(source.read_bits(1)) == 0 {
+        return Ok(2);
+    }
+    Ok(if try!(source.read_bits(1)) == 0 { 1 } else { 5 })
+}
+
+fn read_simple_prefix_code<Source: BitSource>(source: &mut Source, alphabet_size: usize) -> Result<PrefixCode, ErrMsg> {
+    let num_symbols = try!(source.read_bits(2)) as usize + 1;
+    let bits = alphabet_bits(alphabet_size);
+    let mut symbols = [0u16; 4];
+    for index in 0..num_symbols {
+        let symbol = try!(source.read_bits(bits)) as usize;
+        if symbol >= alphabet_size || symbols[..index].contains(&(symbol as u16)) {
+            return Err(ErrMsg::BrotliBitstreamCorrupt(1));
+        }
+        symbols[index] = symbol as u16;
+    }
+    if num_symbols == 1 {
+        return Ok(PrefixCode::single(symbols[0], alphabet_size));
+    }
+    let symbol_lengths: &[u8] = match num_symbols {
+        2 => &[1, 1],
+        3 => &[1, 2, 2],
+        _ => if try!(source.read_bits(1)) == 0 { &[2, 2, 2, 2] } else { &[1, 2, 3, 3] },
+    };
+    let mut lengths = vec![0u8; alphabet_size];
+    for (symbol, len) in symbols.iter().zip(symbol_lengths.iter()) {
+        lengths[*symbol as usize] = *len;
+    }
+    Ok(PrefixCode::from_lengths(lengths))
+}
+
+fn read_complex_prefix_code<Source: BitSource>(source: &mut Source,
+                                               alphabet_size: usize,
+                                               skip: usize) -> Result<PrefixCode, ErrMsg> {
+    let mut code_length_lengths = vec![0u8; CODE_LENGTH_CODES];
+    let mut space = 32i32;
+    let mut num_codes = 0;
+    let mut last_nonzero = 0usize;
+    for symbol in CODE_LENGTH_ORDER[skip..].iter() {
+        let len = try!(read_code_length_code_length(source));
+        let symbol = *symbol as usize;
+        code_length_lengths[symbol] = len;
+        if len != 0 {
+            space -= 32 >> len;
+            num_codes += 1;
+            last_nonzero = symbol;
+            if space <= 0 {
+                break;
+            }
+        }
+    }
+    if !(num_codes == 1 || space == 0) {
+        return Err(ErrMsg::BrotliBitstreamCorrupt(2));
+    }
+    let code_length_code = if num_codes == 1 {
+        PrefixCode::single(last_nonzero as u16, CODE_LENGTH_CODES)
+    } else {
+        PrefixCode::from_lengths(code_length_lengths)
+    };
+    let mut lengths = vec![0u8; alphabet_size];
+    let mut symbol = 0usize;
+    let mut prev_code_len = 8u8;
+    let mut repeat = 0usize;
+    let mut repeat_code_len = 0u8;
+    let mut space = 32768i32;
+    while symbol < alphabet_size && space > 0 {
+        let code_len = try!(code_length_code.read_symbol(source)) as u8;
+        if code_len < 16 {
+            repeat = 0;
+            lengths[symbol] = code_len;
+            if code_len != 0 {
+                prev_code_len = code_len;
+                space -= 32768 >> code_len;
+            }
+            symbol += 1;
+        } else {
+            let (extra_bits, new_len) = if code_len == 16 { (2, prev_code_len) } else { (3, 0) };
+            if repeat_code_len != new_len {
+                repeat = 0;
+                repeat_code_len = new_len;
+            }
+            let old_repeat = repeat;
+            if repeat > 0 {
+                repeat = (repeat - 2) << extra_bits;
+            }
+            repeat += try!(source.read_bits(extra_bits)) as usize + 3;
+            let repeat_delta = repeat - old_repeat;
+            if symbol + repeat_delta > alphabet_size {
+                return Err(ErrMsg::BrotliBitstreamCorrupt(3));
+            }
+            for len in lengths[symbol..symbol + repeat_delta].iter_mut() {
+                *len = repeat_code_len;
+            }
+            symbol += repeat_delta;
+            if repeat_code_len != 0 {
+                space -= (repeat_delta << (15 - repeat_code_len)) as i32;
+            }
+        }
+    }
+    if space != 0 {
+        return Err(ErrMsg::BrotliBitstreamCorrupt(4));
+    }
+    Ok(PrefixCode::from_lengths(lengths))
+}
+
+pub fn read_prefix_code<Source: BitSource>(source: &mut Source, alphabet_size: usize) -> Result<PrefixCode, ErrMsg> {
+    let skip = try!(source.read_bits(2)) as usize;
+    if skip == 1 {
+        read_simple_prefix_code(source, alphabet_size)
+    } else {
+        read_complex_prefix_code(source, alphabet_size, skip)
+    }
+}
+
+// the variable length code used for NBLTYPES and NTREES
+pub fn read_block_type_count<Source: BitSource>(source: &mut Source) -> Result<usize, ErrMsg> {
+    if try!(source.read_bits(1)) == 0 {
+        return Ok(1);
+    }
+    let num_bits = try!(source.read_bits(3));
+    if num_bits == 0 {
+        return Ok(2);
+    }
+    Ok((1usize << num_bits) + 1 + try!(source.read_bits(num_bits)) as usize)
+}
+
+pub fn read_context_map<Source: BitSource>(source: &mut Source,
+                                           size: usize,
+                                           num_trees: usize) -> Result<Vec<u8>, ErrMsg> {
+    let mut context_map = vec![0u8; size];
+    if num_trees < 2 {
+        return Ok(context_map);
+    }
+    let max_run_length_prefix = if try!(source.read_bits(1)) != 0 {
+        try!(source.read_bits(4)) as usize + 1
+    } else {
+        0
+    };
+    let code = try!(read_prefix_code(source, num_trees + max_run_length_prefix));
+    let mut index = 0usize;
+    while index < size {
+        let symbol = try!(code.read_symbol(source)) as usize;
+        if symbol == 0 {
+            index += 1;
+        } else if symbol <= max_run_length_prefix {
+            let run = (1usize << symbol) + try!(source.read_bits(symbol as u32)) as usize;
+            if index + run > size {
+                return Err(ErrMsg::BrotliBitstreamCorrupt(5));
+            }
+            index += run;
+        } else {
+            context_map[index] = (symbol - max_run_length_prefix) as u8;
+            index += 1;
+        }
+    }
+    if try!(source.read_bits(1)) != 0 {
+        // inverse move-to-front transform
+        let mut mtf = [0u8; 256];
+        for (index, item) in mtf.iter_mut().enumerate() {
+            *item = index as u8;
+        }
+        for item in context_map.iter_mut() {
+            let index = *item as usize;
+            let value = mtf[index];
+            *item = value;
+            for i in (0..index).rev() {
+                mtf[i + 1] = mtf[i];
+            }
+            mtf[0] = value;
+        }
+    }
+    Ok(context_map)
+}
+
+// Huffman code lengths no longer than max_length for the used symbols of the histogram
+// codes with fewer than two symbols get no lengths at all
+pub fn huffman_code_lengths(histogram: &[u32], max_length: usize) -> Vec<u8> {
+    let mut lengths = vec![0u8; histogram.len()];
+    let used: Vec<usize> = (0..histogram.len()).filter(|symbol| histogram[*symbol] != 0).collect();
+    if used.len() < 2 {
+        return lengths;
+    }
+    // like the reference encoder, flatten the histogram until the tree is shallow enough
+    let mut count_floor = 1u32;
+    loop {
+        let mut heap = BinaryHeap::<Reverse<(u64, usize)>>::with_capacity(used.len());
+        for (leaf, symbol) in used.iter().enumerate() {
+            heap.push(Reverse((u64::from(::core::cmp::max(histogram[*symbol], count_floor)), leaf)));
+        }
+        let mut parent = vec![0usize; 2 * used.len() - 1];
+        let mut next_node = used.len();
+        while heap.len() > 1 {
+            let Reverse((weight0, node0)) = heap.pop().unwrap();
+            let Reverse((weight1, node1)) = heap.pop().unwrap();
+            parent[node0] = next_node;
+            parent[node1] = next_node;
+            heap.push(Reverse((weight0 + weight1, next_node)));
+            next_node += 1;
+        }
+        // parents are always created after their children, so walking backwards from the root sees them first
+        let mut depth = vec![0usize; next_node];
+        for node in (0..next_node - 1).rev() {
+            depth[node] = depth[parent[node]] + 1;
+        }
+        if depth[..used.len()].iter().all(|len| *len <= max_length) {
+            for (leaf, symbol) in used.iter().enumerate() {
+                lengths[*symbol] = depth[leaf] as u8;
+            }
+            return lengths;
+        }
+        count_floor <<= 1;
+    }
+}
+
+fn write_repeated_code_lengths(previous: u8, value: u8, mut repetitions: usize, tree: &mut Vec<(u8, u8)>) {
+    if previous != value {
+        tree.push((value, 0));
+        repetitions -= 1;
+    }
+    if repetitions == 7 {
+        tree.push((value, 0));
+        repetitions -= 1;
+    }
+    if repetitions < 3 {
+        for _ in 0..repetitions {
+            tree.push((value, 0));
+        }
+        return;
+    }
+    // consecutive repeat codes multiply, so the extra bits are the digits of the count, most significant first
+    let start = tree.len();
+    repetitions -= 3;
+    loop {
+        tree.push((REPEAT_PREVIOUS_CODE_LENGTH, (repetitions & 3) as u8));
+        repetitions >>= 2;
+        if repetitions == 0 {
+            break;
+        }
+        repetitions -= 1;
+    }
+    tree[start..].reverse();
+}
+
+fn write_repeated_zeros(mut repetitions: usize, tree: &mut Vec<(u8, u8)>) {
+    if repetitions == 11 {
+        tree.push((0, 0));
+        repetitions -= 1;
+    }
+    if repetitions < 3 {
+        for _ in 0..repetitions {
+            tree.push((0, 0));
+        }
+        return;
+    }
+    let start = tree.len();
+    repetitions -= 3;
+    loop {
+        tree.push((REPEAT_ZERO_CODE_LENGTH, (repetitions & 7) as u8));
+        repetitions >>= 3;
+        if repetitions == 0 {
+            break;
+        }
+        repetitions -= 1;
+    }
+    tree[start..].reverse();
+}
+
+fn write_complex_prefix_code(sink: &mut BitWriter, lengths: &[u8]) -> Result<(), ErrMsg> {
+    // run length code the lengths up to the last used symbol
+    let used_len = lengths.iter().rposition(|len| *len != 0).map(|last| last + 1).unwrap_or(0);
+    let mut tree = Vec::<(u8, u8)>::new();
+    let mut previous = INITIAL_REPEATED_CODE_LENGTH;
+    let mut index = 0;
+    while index < used_len {
+        let value = lengths[index];
+        let repetitions = lengths[index..used_len].iter().take_while(|len| **len == value).count();
+        if value == 0 {
+            write_repeated_zeros(repetitions, &mut tree);
+        } else {
+            write_repeated_code_lengths(previous, value, repetitions, &mut tree);
+            previous = value;
+        }
+        index += repetitions;
+    }
+    let mut histogram = [0u32; CODE_LENGTH_CODES];
+    for item in tree.iter() {
+        histogram[item.0 as usize] += 1;
+    }
+    let mut code_length_lengths = huffman_code_lengths(&histogram[..], MAX_CODE_LENGTH_CODE_LENGTH);
+    let used: Vec<usize> = (0..CODE_LENGTH_CODES).filter(|symbol| histogram[*symbol] != 0).collect();
+    let code_length_code = if used.len() == 1 {
+        // a lone code length symbol takes no bits; any length marks it as used
+        code_length_lengths[used[0]] = 1;
+        PrefixCode::single(used[0] as u16, CODE_LENGTH_CODES)
+    } else {
+        PrefixCode::from_lengths(code_length_lengths.clone())
+    };
+    let skip = if code_length_lengths[1] != 0 || code_length_lengths[2] != 0 {
+        0
+    } else if code_length_lengths[3] != 0 {
+        2
+    } else {
+        3
+    };
+    // a complete code ends the list once its last length is read, a lone symbol needs the whole list
+    let num_written = if used.len() == 1 {
+        CODE_LENGTH_CODES
+    } else {
+        CODE_LENGTH_ORDER.iter().rposition(|symbol| code_length_lengths[*symbol as usize] != 0).unwrap() + 1
+    };
+    sink.write_bits(2, skip as u32);
+    for symbol in CODE_LENGTH_ORDER[skip..num_written].iter() {
+        let (nbits, bits) = CODE_LENGTH_CODE_LENGTH_CODES[code_length_lengths[*symbol as usize] as usize];
+        sink.write_bits(nbits, bits);
+    }
+    for item in tree.iter() {
+        try!(code_length_code.write_symbol(u16::from(item.0), sink));
+        match item.0 {
+            REPEAT_PREVIOUS_CODE_LENGTH => sink.write_bits(2, u32::from(item.1)),
+            REPEAT_ZERO_CODE_LENGTH => sink.write_bits(3, u32::from(item.1)),
+            _ => {},
+        }
+    }
+    Ok(())
+}
+
+// writes the description of a code fitted to the histogram, whose length is the alphabet size
+pub fn write_prefix_code(sink: &mut BitWriter, histogram: &[u32]) -> Result<PrefixCode, ErrMsg> {
+    let alphabet_size = histogram.len();
+    let lengths = huffman_code_lengths(histogram, MAX_CODE_LENGTH);
+    let mut symbols: Vec<u16> = (0..alphabet_size).filter(|symbol| histogram[*symbol] != 0)
+        .map(|symbol| symbol as u16).collect();
+    if symbols.len() > 4 {
+        try!(write_complex_prefix_code(sink, &lengths[..]));
+        return Ok(PrefixCode::from_lengths(lengths));
+    }
+    // simple codes list their symbols from the shortest length to the longest
+    symbols.sort_by_key(|symbol| lengths[*symbol as usize]);
+    if symbols.is_empty() {
+        symbols.push(0);
+    }
+    sink.write_bits(2, 1);
+    sink.write_bits(2, symbols.len() as u32 - 1);
+    let bits = alphabet_bits(alphabet_size);
+    for symbol in symbols.iter() {
+        sink.write_bits(bits, u32::from(*symbol));
+    }
+    if symbols.len() == 4 {
+        sink.write_bits(1, if lengths[symbols[0] as usize] == 1 { 1 } else { 0 });
+    }
+    if symbols.len() == 1 {
+        return Ok(PrefixCode::single(symbols[0], alphabet_size));
+    }
+    Ok(PrefixCode::from_lengths(lengths))
+}
+
+pub fn write_block_type_count(sink: &mut BitWriter, count: usize) {
+    if count == 1 {
+        sink.write_bits(1, 0);
+        return;
+    }
+    sink.write_bits(1, 1);
+    if count == 2 {
+        sink.write_bits(3, 0);
+        return;
+    }
+    let value = count as u32 - 1;
+    let num_bits = 31 - value.leading_zeros();
+    sink.write_bits(3, num_bits);
+    sink.write_bits(num_bits, value - (1 << num_bits));
+}
+
+// writes a context map using the move-to-front transform and run lengths of zeros
+pub fn write_context_map(sink: &mut BitWriter, context_map: &[u8], num_trees: usize) -> Result<(), ErrMsg> {
+    write_block_type_count(sink, num_trees);
+    if num_trees < 2 {
+        return Ok(());
+    }
+    let mut mtf = [0u8; 256];
+    for (index, item) in mtf.iter_mut().enumerate() {
+        *item = index as u8;
+    }
+    let mut transformed = Vec::<u32>::with_capacity(context_map.len());
+    for value in context_map.iter() {
+        let index = mtf.iter().position(|item| *item == *value).unwrap();
+        for i in (0..index).rev() {
+            mtf[i + 1] = mtf[i];
+        }
+        mtf[0] = *value;
+        transformed.push(index as u32);
+    }
+    let mut max_run = 0u32;
+    let mut run = 0u32;
+    for value in transformed.iter() {
+        run = if *value == 0 { run + 1 } else { 0 };
+        max_run = ::core::cmp::max(max_run, run);
+    }
+    let max_prefix = if max_run == 0 {
+        0
+    } else {
+        ::core::cmp::min(31 - max_run.leading_zeros(), MAX_CONTEXT_MAP_RUN_LENGTH_PREFIX)
+    };
+    // symbols along with the extra bits of run lengths
+    let mut symbols = Vec::<(u32, u32)>::with_capacity(transformed.len());
+    let mut index = 0;
+    while index < transformed.len() {
+        if transformed[index] != 0 {
+            symbols.push((transformed[index] + max_prefix, 0));
+            index += 1;
+            continue;
+        }
+        let mut zeros = transformed[index..].iter().take_while(|value| **value == 0).count() as u32;
+        index += zeros as usize;
+        while zeros != 0 {
+            if zeros < (2 << max_prefix) {
+                let prefix = 31 - zeros.leading_zeros();
+                symbols.push((prefix, zeros - (1 << prefix)));
+                break;
+            }
+            symbols.push((max_prefix, (1 << max_prefix) - 1));
+            zeros -= (2 << max_prefix) - 1;
+        }
+    }
+    if max_prefix != 0 {
+        sink.write_bits(1, 1);
+        sink.write_bits(4, max_prefix - 1);
+    } else {
+        sink.write_bits(1, 0);
+    }
+    let mut histogram = vec![0u32; num_trees + max_prefix as usize];
+    for symbol in symbols.iter() {
+        histogram[symbol.0 as usize] += 1;
+    }
+    let code = try!(write_prefix_code(sink, &histogram[..]));
+    for symbol in symbols.iter() {
+        try!(code.write_symbol(symbol.0 as u16, sink));
+        if symbol.0 != 0 && symbol.0 <= max_prefix {
+            sink.write_bits(symbol.0, symbol.1);
+        }
+    }
+    // the inverse move-to-front transform
+    sink.write_bits(1, 1);
+    Ok(())
+}
@@ -0,0 +1,259 @@
+// Copyright 2017 Dropbox, Inc
+//
+//   Licensed under the Apache License, Version 2.0 (the "License");
+//   you may not use this file except in compliance with the License.
+//   You may obtain a copy of the License at
+//
+//       http://www.apache.org/licenses/LICENSE-2.0
+//
+//   Unless required by applicable law or agreed to in writing, software
+//   distributed under the License is distributed on an "AS IS" BASIS,
+//   WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//   See the License for the specific language governing permissions and
+//   limitations under the License.
+
+// Rebuilds the brotli stream from the side data and the commands a divans decoder executed,
+// mirroring every read the analyzer made.
+use std::vec::Vec;
+use ::brotli::dictionary::kBrotliDictionarySizeBitsByLength;
+use ::command_log::{LoggedCommand, parse_logged_command};
+use ::interface::ErrMsg;
+use super::bit_io::{BitReader, BitSource, BitWriter, TeeReader};
+use super::bitstream::{BlockCategory, CompressedHeader, DistanceCache, MetaBlockKind, COPY_LENGTH_PREFIX,
+                       INSERT_LENGTH_PREFIX, combine_command_symbol, copy_length_code, distance_context,
+                       encode_distance, insert_length_code, read_meta_block_header, read_window_bits,
+                       split_command_symbol, write_length_extra};
+use super::{DistanceChoice, DistanceExceptionReader, canonical_distance_choice, literal_context};
+
+// the bytes a brotli command copies, as logged by the divans decoder
+#[derive(Clone, Copy)]
+enum Backreference {
+    Copy(u32, u32),
+    // word size, word index within the dictionary and transformed size
+    Dict(u32, u32, u32),
+}
+
+// hands out the literal runs and backreferences of the command log in brotli command sized pieces
+struct CommandPool<'a> {
+    log: &'a [u8],
+    literals: usize,
+    backreference: Option<Backreference>,
+}
+
+impl<'a> CommandPool<'a> {
+    // gathers literals until the next backreference or the end of the log
+    fn fill(&mut self) -> Result<(), ErrMsg> {
+        while self.backreference.is_none() {
+            let (cmd, used) = match parse_logged_command(self.log) {
+                Some(parsed) => parsed,
+                None => {
+                    if !self.log.is_empty() {
+                        return Err(ErrMsg::BrotliSideDataCorrupt(10));
+                    }
+                    return Ok(());
+                },
+            };
+            self.log = &self.log[used..];
+            match cmd {
+                LoggedCommand::Literal(len) => self.literals += len as usize,
+                LoggedCommand::Copy(copy) => self.backreference = Some(Backreference::Copy(copy.distance,
+                                                                                         copy.num_bytes)),
+                LoggedCommand::Dict(dict) => {
+                    let size_bits = u32::from(kBrotliDictionarySizeBitsByLength[dict.word_size as usize]);
+                    self.backreference = Some(Backreference::Dict(
+                        u32::from(dict.word_size),
+                        (u32::from(dict.transform) << size_bits) | dict.word_id,
+                        u32::from(dict.final_size)));
+                },
+                LoggedCommand::BlockSwitchCommand(_) | LoggedCommand::BlockSwitchDistance(_)
+                    | LoggedCommand::BlockSwitchLiteral(_) | LoggedCommand::PredictionMode(..) => {},
+            }
+        }
+        Ok(())
+    }
+    fn take_literals(&mut self, len: usize) -> Result<(), ErrMsg> {
+        try!(self.fill());
+        if self.literals < len {
+            return Err(ErrMsg::BrotliSideDataCorrupt(11));
+        }
+        self.literals -= len;
+        Ok(())
+    }
+    fn is_empty(&self) -> bool {
+        self.literals == 0 && self.backreference.is_none() && self.log.is_empty()
+    }
+}
+
+struct Reconstructor<'a> {
+    side: BitReader<'a>,
+    out: BitWriter,
+    exceptions: DistanceExceptionReader<'a>,
+    data: &'a [u8],
+    pos: usize,
+    pool: CommandPool<'a>,
+    max_window: usize,
+    distance_cache: DistanceCache,
+}
+
+fn advance_block<'a>(side: &mut BitReader<'a>, out: &mut BitWriter, category: &mut BlockCategory) -> Result<bool, ErrMsg> {
+    let mut tee = TeeReader{source: side, sink: out, stream_is_sink: true};
+    category.advance(&mut tee)
+}
+
+impl<'a> Reconstructor<'a> {
+    fn write_literals(&mut self, header: &mut CompressedHeader, len: usize) -> Result<(), ErrMsg> {
+        if self.data.len() - self.pos < len {
+            return Err(ErrMsg::BrotliSideDataCorrupt(12));
+        }
+        for _ in 0..len {
+            try!(advance_block(&mut self.side, &mut self.out, &mut header.literal));
+            let block_type = header.literal.block_type;
+            let context = literal_context(&self.data[..self.pos], header.context_modes[block_type]);
+            let tree = header.literal_context_map[(block_type << 6) + context] as usize;
+            try!(header.literal_codes[tree].write_symbol(u16::from(self.data[self.pos]), &mut self.out));
+            self.pos += 1;
+        }
+        Ok(())
+    }
+    fn reconstruct_compressed(&mut self, header: &mut CompressedHeader, len: usize) -> Result<(), ErrMsg> {
+        let mut remaining = len;
+        while remaining != 0 {
+            try!(advance_block(&mut self.side, &mut self.out, &mut header.command));
+            try!(self.pool.fill());
+            let backreference = match self.pool.backreference {
+                Some(backreference) if self.pool.literals < remaining => backreference,
+                _ => {
+                    // the last command of the meta-block only inserts; its unused copy comes from the side data
+                    try!(self.pool.take_literals(remaining));
+                    let symbol = try!(self.side.read_bits(10)) as u16;
+                    let (insert_code, copy_code, _) = split_command_symbol(symbol);
+                    let insert_len = remaining as u32;
+                    if insert_length_code(insert_len) != insert_code {
+                        return Err(ErrMsg::BrotliSideDataCorrupt(13));
+                    }
+                    try!(header.command_codes[header.command.block_type].write_symbol(symbol, &mut self.out));
+                    write_length_extra(&mut self.out, &INSERT_LENGTH_PREFIX[..], insert_code, insert_len);
+                    let copy_extra_bits = COPY_LENGTH_PREFIX[copy_code].1;
+                    let copy_extra = try!(self.side.read_bits(copy_extra_bits));
+                    self.out.write_bits(copy_extra_bits, copy_extra);
+                    try!(self.write_literals(header, remaining));
+                    return Ok(());
+                },
+            };
+            let insert_len = self.pool.literals;
+            self.pool.literals = 0;
+            self.pool.backreference = None;
+            let max_distance = ::core::cmp::min(self.pos + insert_len, self.max_window) as i64;
+            let (copy_len, distance, is_dictionary, final_size) = match backreference {
+                Backreference::Copy(distance, copy_len) => (copy_len, i64::from(distance), false, copy_len),
+                Backreference::Dict(word_size, word_index, final_size) =>
+                    (word_size, max_distance + 1 + i64::from(word_index), true, final_size),
+            };
+            if final_size as usize > remaining - insert_len || copy_len < 2 {
+                return Err(ErrMsg::BrotliSideDataCorrupt(14));
+            }
+            let insert_code = insert_length_code(insert_len as u32);
+            let copy_code = copy_length_code(copy_len);
+            let command_code = &header.command_codes[header.command.block_type];
+            let canonical = canonical_distance_choice(&self.distance_cache, distance, max_distance, insert_code,
+                                                      copy_code, command_code);
+            let choice = try!(self.exceptions.next(canonical));
+            let implicit_distance = choice == DistanceChoice::Implicit;
+            if implicit_distance && (insert_code >= 8 || copy_code >= 16) {
+                return Err(ErrMsg::BrotliSideDataCorrupt(15));
+            }
+            try!(command_code.write_symbol(combine_command_symbol(insert_code, copy_code, implicit_distance),
+                                           &mut self.out));
+            write_length_extra(&mut self.out, &INSERT_LENGTH_PREFIX[..], insert_code, insert_len as u32);
+            write_length_extra(&mut self.out, &COPY_LENGTH_PREFIX[..], copy_code, copy_len);
+            try!(self.write_literals(header, insert_len));
+            match choice {
+                DistanceChoice::Implicit => {
+                    if self.distance_cache.short_code_distance(0) != distance {
+                        return Err(ErrMsg::BrotliSideDataCorrupt(16));
+                    }
+                },
+                _ => {
+                    try!(advance_block(&mut self.side, &mut self.out, &mut header.distance));
+                    let tree = header.distance_context_map[(header.distance.block_type << 2)
+                                                           + distance_context(copy_len)] as usize;
+                    let distance_code = &header.distance_codes[tree];
+                    if let DistanceChoice::Short(code) = choice {
+                        if self.distance_cache.short_code_distance(u32::from(code)) != distance {
+                            return Err(ErrMsg::BrotliSideDataCorrupt(16));
+                        }
+                        try!(distance_code.write_symbol(u16::from(code), &mut self.out));
+                    } else {
+                        let (symbol, nbits, extra) = encode_distance(distance, header.npostfix, header.ndirect);
+                        try!(distance_code.write_symbol(symbol as u16, &mut self.out));
+                        self.out.write_bits(nbits, extra);
+                    }
+                },
+            }
+            self.distance_cache.update(choice.reuses_last_distance(), is_dictionary, distance);
+            self.pos += final_size as usize;
+            remaining -= insert_len + final_size as usize;
+        }
+        Ok(())
+    }
+}
+
+// returns the brotli stream without any bytes that followed it in the original file
+pub fn reconstruct(side_bits: &[u8],
+                   distance_exceptions: &[u8],
+                   data: &[u8],
+                   command_log: &[u8]) -> Result<Vec<u8>, ErrMsg> {
+    let mut rec = Reconstructor {
+        side: BitReader::new(side_bits),
+        out: BitWriter::new(),
+        exceptions: DistanceExceptionReader::new(distance_exceptions),
+        data: data,
+        pos: 0,
+        pool: CommandPool {
+            log: command_log,
+            literals: 0,
+            backreference: None,
+        },
+        max_window: 0,
+        distance_cache: DistanceCache::default(),
+    };
+    let window_bits = {
+        let mut tee = TeeReader{source: &mut rec.side, sink: &mut rec.out, stream_is_sink: true};
+        try!(read_window_bits(&mut tee))
+    };
+    rec.max_window = (1 << window_bits) - 16;
+    loop {
+        let header = {
+            let mut tee = TeeReader{source: &mut rec.side, sink: &mut rec.out, stream_is_sink: true};
+            try!(read_meta_block_header(&mut tee))
+        };
+        let mut header = match header {
+            Some(header) => header,
+            None => break,
+        };
+        match header.kind {
+            MetaBlockKind::Metadata => {},
+            MetaBlockKind::Uncompressed => {
+                try!(rec.pool.take_literals(header.len));
+                if rec.data.len() - rec.pos < header.len {
+                    return Err(ErrMsg::BrotliSideDataCorrupt(12));
+                }
+                rec.out.write_bytes(&rec.data[rec.pos..rec.pos + header.len]);
+                rec.pos += header.len;
+            },
+            MetaBlockKind::Compressed(ref mut compressed) => try!(rec.reconstruct_compressed(compressed, header.len)),
+        }
+        if header.is_last {
+            break;
+        }
+    }
+    {
+        let mut tee = TeeReader{source: &mut rec.side, sink: &mut rec.out, stream_is_sink: true};
+        try!(tee.read_to_byte_boundary());
+    }
+    try!(rec.pool.fill());
+    if !rec.pool.is_empty() || rec.pos != rec.data.len() || !rec.exceptions.is_done() {
+        return Err(ErrMsg::BrotliSideDataCorrupt(17));
+    }
+    Ok(rec.out.into_bytes())
+}
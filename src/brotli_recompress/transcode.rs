@@ -0,0 +1,586 @@
+// Copyright 2017 Dropbox, Inc
+//
+//   Licensed under the Apache License, Version 2.0 (the "License");
+//   you may not use this file except in compliance with the License.
+//   You may obtain a copy of the License at
+//
+//       http://www.apache.org/licenses/LICENSE-2.0
+//
+//   Unless required by applicable law or agreed to in writing, software
+//   distributed under the License is distributed on an "AS IS" BASIS,
+//   WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//   See the License for the specific language governing permissions and
+//   limitations under the License.
+
+// Writes a brotli stream for the commands a divans decoder executed. The LZ77 parse, block switches
+// and context maps of the divans stream are kept; only the prefix codes are fitted to the new stream.
+use std::vec::Vec;
+use ::brotli::dictionary::kBrotliDictionarySizeBitsByLength;
+use ::command_log::{LoggedCommand, parse_logged_command};
+use ::interface::ErrMsg;
+use super::bit_io::BitWriter;
+use super::bitstream::{DistanceCache, COPY_LENGTH_PREFIX, INSERT_LENGTH_PREFIX, LITERAL_CONTEXT_BITS,
+                       DISTANCE_CONTEXT_BITS, NUM_BLOCK_COUNT_SYMBOLS, NUM_COMMAND_SYMBOLS, NUM_LITERAL_SYMBOLS,
+                       NUM_TRANSFORMS, can_use_implicit_distance, combine_command_symbol, copy_length_code,
+                       distance_context, encode_block_count, encode_distance, insert_length_code,
+                       write_length_extra, write_window_bits};
+use super::prefix::{PrefixCode, write_block_type_count, write_context_map, write_prefix_code};
+use super::{DistanceChoice, literal_context};
+
+// small enough to keep the decoder's view of a meta-block cheap, large enough that the prefix codes pay off
+const MAX_META_BLOCK_LEN: usize = 1 << 22;
+const MIN_WINDOW_BITS: u32 = 10;
+const MAX_WINDOW_BITS: u32 = 24;
+// without postfix bits or direct distance codes
+const NUM_DISTANCE_SYMBOLS: usize = 64;
+
+#[derive(Clone, Copy)]
+enum Backreference {
+    Copy(u32, u32),
+    // word size, word index within the dictionary and transformed size
+    Dict(u32, u32, u32),
+}
+
+impl Backreference {
+    fn output_len(&self) -> usize {
+        match *self {
+            Backreference::Copy(_, len) => len as usize,
+            Backreference::Dict(_, _, final_size) => final_size as usize,
+        }
+    }
+}
+
+struct MetaBlockCommand {
+    insert_len: u32,
+    // the last command of a meta-block may only insert
+    backreference: Option<Backreference>,
+    command_type: u8,
+    distance_type: u8,
+}
+
+struct MetaBlock<'a> {
+    len: usize,
+    commands: Vec<MetaBlockCommand>,
+    literal_blocks: Vec<(u8, u32)>,
+    mode: u8,
+    literal_context_map: &'a [u8],
+    distance_context_map: &'a [u8],
+}
+
+impl<'a> MetaBlock<'a> {
+    fn new(mode: u8, literal_context_map: &'a [u8], distance_context_map: &'a [u8]) -> Self {
+        MetaBlock {
+            len: 0,
+            commands: Vec::new(),
+            literal_blocks: Vec::new(),
+            mode: mode,
+            literal_context_map: literal_context_map,
+            distance_context_map: distance_context_map,
+        }
+    }
+}
+
+fn push_block<T: PartialEq>(blocks: &mut Vec<(T, u32)>, block_type: T, count: u32) {
+    if let Some(last) = blocks.last_mut() {
+        if last.0 == block_type {
+            last.1 += count;
+            return;
+        }
+    }
+    blocks.push((block_type, count));
+}
+
+// cuts the command log into meta-blocks, starting a new one wherever the prediction mode changes
+struct MetaBlockSplitter<'a> {
+    meta_blocks: Vec<MetaBlock<'a>>,
+    current: MetaBlock<'a>,
+    pos: usize,
+    max_distance: usize,
+    insert_len: u32,
+    literal_type: u8,
+    command_type: u8,
+    distance_type: u8,
+}
+
+impl<'a> MetaBlockSplitter<'a> {
+    fn finish_meta_block(&mut self) {
+        if self.insert_len != 0 {
+            self.current.commands.push(MetaBlockCommand {
+                insert_len: self.insert_len,
+                backreference: None,
+                command_type: self.command_type,
+                distance_type: self.distance_type,
+            });
+            self.insert_len = 0;
+        }
+        if self.current.len != 0 {
+            let next = MetaBlock::new(self.current.mode, self.current.literal_context_map,
+                                      self.current.distance_context_map);
+            self.meta_blocks.push(::core::mem::replace(&mut self.current, next));
+        }
+    }
+    fn push_literals(&mut self, mut len: usize) {
+        while len != 0 {
+            let run = ::core::cmp::min(len, MAX_META_BLOCK_LEN - self.current.len);
+            push_block(&mut self.current.literal_blocks, self.literal_type, run as u32);
+            self.insert_len += run as u32;
+            self.current.len += run;
+            self.pos += run;
+            len -= run;
+            if self.current.len == MAX_META_BLOCK_LEN {
+                self.finish_meta_block();
+            }
+        }
+    }
+    fn push_backreference(&mut self, backreference: Backreference) {
+        let len = backreference.output_len();
+        if self.current.len + len > MAX_META_BLOCK_LEN {
+            self.finish_meta_block();
+        }
+        self.current.commands.push(MetaBlockCommand {
+            insert_len: self.insert_len,
+            backreference: Some(backreference),
+            command_type: self.command_type,
+            distance_type: self.distance_type,
+        });
+        self.insert_len = 0;
+        self.current.len += len;
+        self.pos += len;
+    }
+    fn push_copy(&mut self, distance: u32, mut len: u32) {
+        if distance == 0 || distance as usize > ::core::cmp::min(self.pos, self.max_distance) || len < 2 {
+            // brotli cannot express this copy, but its bytes are in the output all the same
+            return self.push_literals(len as usize);
+        }
+        while len as usize > MAX_META_BLOCK_LEN {
+            // a copy split in two at the same distance copies the same bytes
+            let piece = MAX_META_BLOCK_LEN as u32 - 2;
+            self.push_backreference(Backreference::Copy(distance, piece));
+            len -= piece;
+        }
+        self.push_backreference(Backreference::Copy(distance, len));
+    }
+    fn push_dict(&mut self, word_size: u8, transform: u8, word_id: u32, final_size: u8) {
+        let size_bits = kBrotliDictionarySizeBitsByLength.get(word_size as usize).cloned().unwrap_or(0);
+        if word_size < 4 || size_bits == 0 || u32::from(transform) >= NUM_TRANSFORMS || word_id >> size_bits != 0 {
+            return self.push_literals(final_size as usize);
+        }
+        self.push_backreference(Backreference::Dict(u32::from(word_size),
+                                                    (u32::from(transform) << size_bits) | word_id,
+                                                    u32::from(final_size)));
+    }
+}
+
+// the window needs to cover the farthest copy; longer distances are turned back into literals
+fn choose_window_bits(command_log: &[u8]) -> u32 {
+    let mut max_distance = 0u32;
+    let mut log = command_log;
+    while let Some((cmd, used)) = parse_logged_command(log) {
+        if let LoggedCommand::Copy(copy) = cmd {
+            max_distance = ::core::cmp::max(max_distance, copy.distance);
+        }
+        log = &log[used..];
+    }
+    let mut window_bits = MIN_WINDOW_BITS;
+    while window_bits < MAX_WINDOW_BITS && ((1u32 << window_bits) - 16) < max_distance {
+        window_bits += 1;
+    }
+    window_bits
+}
+
+fn split_meta_blocks<'a>(command_log: &'a [u8], max_distance: usize) -> Result<Vec<MetaBlock<'a>>, ErrMsg> {
+    let mut splitter = MetaBlockSplitter {
+        meta_blocks: Vec::new(),
+        current: MetaBlock::new(0, &[], &[]),
+        pos: 0,
+        max_distance: max_distance,
+        insert_len: 0,
+        literal_type: 0,
+        command_type: 0,
+        distance_type: 0,
+    };
+    let mut log = command_log;
+    while !log.is_empty() {
+        let (cmd, used) = match parse_logged_command(log) {
+            Some(parsed) => parsed,
+            None => return Err(ErrMsg::BrotliTranscodeFailed(0)),
+        };
+        log = &log[used..];
+        match cmd {
+            LoggedCommand::Literal(len) => splitter.push_literals(len as usize),
+            LoggedCommand::Copy(copy) => splitter.push_copy(copy.distance, copy.num_bytes),
+            LoggedCommand::Dict(dict) => splitter.push_dict(dict.word_size, dict.transform, dict.word_id,
+                                                            dict.final_size),
+            LoggedCommand::BlockSwitchCommand(bs) => splitter.command_type = bs.block_type(),
+            LoggedCommand::BlockSwitchDistance(bs) => splitter.distance_type = bs.block_type(),
+            LoggedCommand::BlockSwitchLiteral(bs) => splitter.literal_type = bs.block_type(),
+            LoggedCommand::PredictionMode(mode, literal_context_map, distance_context_map) => {
+                splitter.finish_meta_block();
+                let mode = mode.prediction_mode();
+                // divans has prediction modes beyond the four brotli context modes
+                splitter.current.mode = if mode < 4 { mode } else { 0 };
+                splitter.current.literal_context_map = literal_context_map;
+                splitter.current.distance_context_map = distance_context_map;
+            },
+        }
+    }
+    splitter.finish_meta_block();
+    Ok(splitter.meta_blocks)
+}
+
+// walks the blocks of one category symbol by symbol
+struct BlockCursor {
+    index: usize,
+    remaining: u32,
+}
+
+impl BlockCursor {
+    fn new(blocks: &[(usize, u32)]) -> Self {
+        BlockCursor {
+            index: 0,
+            remaining: blocks.first().map(|block| block.1).unwrap_or(0),
+        }
+    }
+    // returns the block type of the next symbol and whether that symbol starts a new block
+    fn next(&mut self, blocks: &[(usize, u32)]) -> (usize, bool) {
+        if blocks.is_empty() {
+            return (0, false);
+        }
+        let mut switched = false;
+        if self.remaining == 0 {
+            self.index += 1;
+            self.remaining = blocks[self.index].1;
+            switched = true;
+        }
+        self.remaining -= 1;
+        (blocks[self.index].0, switched)
+    }
+}
+
+// the blocks of one category with their types renumbered in order of appearance, since every
+// meta-block starts out at block type 0
+struct BlockSplit {
+    num_types: usize,
+    blocks: Vec<(usize, u32)>,
+    original_types: Vec<u8>,
+    type_symbols: Vec<u16>,
+    type_code: Option<PrefixCode>,
+    count_code: Option<PrefixCode>,
+    cursor: BlockCursor,
+}
+
+impl BlockSplit {
+    fn new(original_blocks: &[(u8, u32)]) -> Self {
+        let mut original_types = Vec::<u8>::new();
+        let mut blocks = Vec::<(usize, u32)>::with_capacity(original_blocks.len());
+        for block in original_blocks.iter() {
+            let block_type = match original_types.iter().position(|item| *item == block.0) {
+                Some(index) => index,
+                None => {
+                    original_types.push(block.0);
+                    original_types.len() - 1
+                },
+            };
+            push_block(&mut blocks, block_type, block.1);
+        }
+        if original_types.is_empty() {
+            original_types.push(0);
+        }
+        let num_types = original_types.len();
+        let mut type_symbols = Vec::<u16>::with_capacity(blocks.len());
+        let (mut last_type, mut second_last_type) = (0usize, 1usize);
+        for block in blocks.iter().skip(1) {
+            type_symbols.push(if block.0 == (last_type + 1) % num_types {
+                1
+            } else if block.0 == second_last_type {
+                0
+            } else {
+                block.0 as u16 + 2
+            });
+            second_last_type = last_type;
+            last_type = block.0;
+        }
+        let cursor = BlockCursor::new(&blocks[..]);
+        BlockSplit {
+            num_types: num_types,
+            blocks: blocks,
+            original_types: original_types,
+            type_symbols: type_symbols,
+            type_code: None,
+            count_code: None,
+            cursor: cursor,
+        }
+    }
+    fn write_header(&mut self, sink: &mut BitWriter) -> Result<(), ErrMsg> {
+        write_block_type_count(sink, self.num_types);
+        if self.num_types < 2 {
+            return Ok(());
+        }
+        let mut type_histogram = vec![0u32; self.num_types + 2];
+        for symbol in self.type_symbols.iter() {
+            type_histogram[*symbol as usize] += 1;
+        }
+        let mut count_histogram = [0u32; NUM_BLOCK_COUNT_SYMBOLS];
+        for block in self.blocks.iter() {
+            count_histogram[encode_block_count(block.1).0] += 1;
+        }
+        self.type_code = Some(try!(write_prefix_code(sink, &type_histogram[..])));
+        let count_code = try!(write_prefix_code(sink, &count_histogram[..]));
+        let (symbol, nbits, extra) = encode_block_count(self.blocks[0].1);
+        try!(count_code.write_symbol(symbol as u16, sink));
+        sink.write_bits(nbits, extra);
+        self.count_code = Some(count_code);
+        Ok(())
+    }
+    // accounts for one more symbol of this category, writing a block switch if it starts a new block
+    fn advance(&mut self, sink: &mut BitWriter) -> Result<usize, ErrMsg> {
+        let (block_type, switched) = self.cursor.next(&self.blocks[..]);
+        if switched {
+            let index = self.cursor.index;
+            if let (&Some(ref type_code), &Some(ref count_code)) = (&self.type_code, &self.count_code) {
+                try!(type_code.write_symbol(self.type_symbols[index - 1], sink));
+                let (symbol, nbits, extra) = encode_block_count(self.blocks[index].1);
+                try!(count_code.write_symbol(symbol as u16, sink));
+                sink.write_bits(nbits, extra);
+            }
+        }
+        Ok(block_type)
+    }
+}
+
+// numbers the trees of a context map in order of appearance so that no tree goes unused
+fn compact_context_map(context_map: &mut [u8]) -> usize {
+    let mut renumbered = [None; 256];
+    let mut num_trees = 0usize;
+    for item in context_map.iter_mut() {
+        let tree = match renumbered[*item as usize] {
+            Some(tree) => tree,
+            None => {
+                renumbered[*item as usize] = Some(num_trees as u8);
+                num_trees += 1;
+                (num_trees - 1) as u8
+            },
+        };
+        *item = tree;
+    }
+    num_trees
+}
+
+fn build_context_map(original_map: &[u8], original_types: &[u8], context_bits: usize) -> (Vec<u8>, usize) {
+    let num_contexts = 1 << context_bits;
+    let mut context_map = Vec::<u8>::with_capacity(original_types.len() * num_contexts);
+    for original_type in original_types.iter() {
+        for context in 0..num_contexts {
+            let index = (usize::from(*original_type) << context_bits) + context;
+            context_map.push(original_map.get(index).cloned().unwrap_or(0));
+        }
+    }
+    let num_trees = compact_context_map(&mut context_map[..]);
+    (context_map, num_trees)
+}
+
+struct PlannedCommand {
+    symbol: u16,
+    insert_len: u32,
+    insert_code: usize,
+    copy_len: u32,
+    copy_code: usize,
+    // distance symbol, extra bits and distance context of an explicit distance code
+    distance: Option<(u16, u32, u32, usize)>,
+    output_len: usize,
+    command_type: u8,
+    distance_type: u8,
+}
+
+struct Transcoder<'a> {
+    out: BitWriter,
+    data: &'a [u8],
+    pos: usize,
+    max_window: usize,
+    distance_cache: DistanceCache,
+}
+
+impl<'a> Transcoder<'a> {
+    // picks the cheapest way to refer to each distance, the way the reference encoder does
+    fn plan_commands(&mut self, meta_block: &MetaBlock) -> Vec<PlannedCommand> {
+        let mut planned = Vec::<PlannedCommand>::with_capacity(meta_block.commands.len());
+        let mut pos = self.pos;
+        for cmd in meta_block.commands.iter() {
+            let insert_code = insert_length_code(cmd.insert_len);
+            pos += cmd.insert_len as usize;
+            let backreference = match cmd.backreference {
+                Some(backreference) => backreference,
+                None => {
+                    // the copy of a final insert-only command is never executed
+                    planned.push(PlannedCommand {
+                        symbol: combine_command_symbol(insert_code, 0, can_use_implicit_distance(insert_code, 0)),
+                        insert_len: cmd.insert_len,
+                        insert_code: insert_code,
+                        copy_len: COPY_LENGTH_PREFIX[0].0,
+                        copy_code: 0,
+                        distance: None,
+                        output_len: cmd.insert_len as usize,
+                        command_type: cmd.command_type,
+                        distance_type: cmd.distance_type,
+                    });
+                    continue;
+                },
+            };
+            let max_distance = ::core::cmp::min(pos, self.max_window) as i64;
+            let (copy_len, distance, is_dictionary) = match backreference {
+                Backreference::Copy(distance, copy_len) => (copy_len, i64::from(distance), false),
+                Backreference::Dict(word_size, word_index, _) =>
+                    (word_size, max_distance + 1 + i64::from(word_index), true),
+            };
+            let copy_code = copy_length_code(copy_len);
+            let choice = match self.distance_cache.preferred_short_code(distance) {
+                Some(0) if can_use_implicit_distance(insert_code, copy_code) => DistanceChoice::Implicit,
+                Some(code) if self.distance_cache.short_code_distance(code) == distance => DistanceChoice::Short(code as u8),
+                _ => DistanceChoice::General,
+            };
+            let explicit_distance = match choice {
+                DistanceChoice::Implicit => None,
+                DistanceChoice::Short(code) => Some((u16::from(code), 0, 0, distance_context(copy_len))),
+                DistanceChoice::General => {
+                    let (symbol, nbits, extra) = encode_distance(distance, 0, 0);
+                    Some((symbol as u16, nbits, extra, distance_context(copy_len)))
+                },
+            };
+            self.distance_cache.update(choice.reuses_last_distance(), is_dictionary, distance);
+            pos += backreference.output_len();
+            planned.push(PlannedCommand {
+                symbol: combine_command_symbol(insert_code, copy_code, choice == DistanceChoice::Implicit),
+                insert_len: cmd.insert_len,
+                insert_code: insert_code,
+                copy_len: copy_len,
+                copy_code: copy_code,
+                distance: explicit_distance,
+                output_len: cmd.insert_len as usize + backreference.output_len(),
+                command_type: cmd.command_type,
+                distance_type: cmd.distance_type,
+            });
+        }
+        planned
+    }
+    fn write_meta_block(&mut self, meta_block: &MetaBlock) -> Result<(), ErrMsg> {
+        let commands = self.plan_commands(meta_block);
+        let mut command_blocks = Vec::<(u8, u32)>::new();
+        let mut distance_blocks = Vec::<(u8, u32)>::new();
+        for cmd in commands.iter() {
+            push_block(&mut command_blocks, cmd.command_type, 1);
+            if cmd.distance.is_some() {
+                push_block(&mut distance_blocks, cmd.distance_type, 1);
+            }
+        }
+        let mut literal_split = BlockSplit::new(&meta_block.literal_blocks[..]);
+        let mut command_split = BlockSplit::new(&command_blocks[..]);
+        let mut distance_split = BlockSplit::new(&distance_blocks[..]);
+        let (literal_context_map, num_literal_trees) = build_context_map(
+            meta_block.literal_context_map, &literal_split.original_types[..], LITERAL_CONTEXT_BITS);
+        let (distance_context_map, num_distance_trees) = build_context_map(
+            meta_block.distance_context_map, &distance_split.original_types[..], DISTANCE_CONTEXT_BITS);
+
+        // gather the histograms by walking the meta-block the way it will be written
+        let mut literal_histograms = vec![vec![0u32; NUM_LITERAL_SYMBOLS]; num_literal_trees];
+        let mut command_histograms = vec![vec![0u32; NUM_COMMAND_SYMBOLS]; command_split.num_types];
+        let mut distance_histograms = vec![vec![0u32; NUM_DISTANCE_SYMBOLS]; num_distance_trees];
+        {
+            let mut literal_cursor = BlockCursor::new(&literal_split.blocks[..]);
+            let mut command_cursor = BlockCursor::new(&command_split.blocks[..]);
+            let mut distance_cursor = BlockCursor::new(&distance_split.blocks[..]);
+            let mut pos = self.pos;
+            for cmd in commands.iter() {
+                let command_type = command_cursor.next(&command_split.blocks[..]).0;
+                command_histograms[command_type][cmd.symbol as usize] += 1;
+                for literal_pos in pos..pos + cmd.insert_len as usize {
+                    let literal_type = literal_cursor.next(&literal_split.blocks[..]).0;
+                    let context = literal_context(&self.data[..literal_pos], meta_block.mode);
+                    let tree = literal_context_map[(literal_type << LITERAL_CONTEXT_BITS) + context] as usize;
+                    literal_histograms[tree][self.data[literal_pos] as usize] += 1;
+                }
+                if let Some((symbol, _, _, context)) = cmd.distance {
+                    let distance_type = distance_cursor.next(&distance_split.blocks[..]).0;
+                    let tree = distance_context_map[(distance_type << DISTANCE_CONTEXT_BITS) + context] as usize;
+                    distance_histograms[tree][symbol as usize] += 1;
+                }
+                pos += cmd.output_len;
+            }
+        }
+
+        // ISLAST, MNIBBLES and MLEN - 1, then ISUNCOMPRESSED
+        let len = meta_block.len as u32 - 1;
+        let nibbles = if len < 1 << 16 { 4 } else if len < 1 << 20 { 5 } else { 6 };
+        self.out.write_bits(1, 0);
+        self.out.write_bits(2, nibbles - 4);
+        self.out.write_bits(nibbles * 4, len);
+        self.out.write_bits(1, 0);
+        try!(literal_split.write_header(&mut self.out));
+        try!(command_split.write_header(&mut self.out));
+        try!(distance_split.write_header(&mut self.out));
+        // NPOSTFIX and NDIRECT
+        self.out.write_bits(2, 0);
+        self.out.write_bits(4, 0);
+        for _ in 0..literal_split.num_types {
+            self.out.write_bits(2, u32::from(meta_block.mode));
+        }
+        try!(write_context_map(&mut self.out, &literal_context_map[..], num_literal_trees));
+        try!(write_context_map(&mut self.out, &distance_context_map[..], num_distance_trees));
+        let mut literal_codes = Vec::<PrefixCode>::with_capacity(num_literal_trees);
+        for histogram in literal_histograms.iter() {
+            literal_codes.push(try!(write_prefix_code(&mut self.out, &histogram[..])));
+        }
+        let mut command_codes = Vec::<PrefixCode>::with_capacity(command_split.num_types);
+        for histogram in command_histograms.iter() {
+            command_codes.push(try!(write_prefix_code(&mut self.out, &histogram[..])));
+        }
+        let mut distance_codes = Vec::<PrefixCode>::with_capacity(num_distance_trees);
+        for histogram in distance_histograms.iter() {
+            distance_codes.push(try!(write_prefix_code(&mut self.out, &histogram[..])));
+        }
+
+        for cmd in commands.iter() {
+            let command_type = try!(command_split.advance(&mut self.out));
+            try!(command_codes[command_type].write_symbol(cmd.symbol, &mut self.out));
+            write_length_extra(&mut self.out, &INSERT_LENGTH_PREFIX[..], cmd.insert_code, cmd.insert_len);
+            write_length_extra(&mut self.out, &COPY_LENGTH_PREFIX[..], cmd.copy_code, cmd.copy_len);
+            for literal_pos in self.pos..self.pos + cmd.insert_len as usize {
+                let literal_type = try!(literal_split.advance(&mut self.out));
+                let context = literal_context(&self.data[..literal_pos], meta_block.mode);
+                let tree = literal_context_map[(literal_type << LITERAL_CONTEXT_BITS) + context] as usize;
+                try!(literal_codes[tree].write_symbol(u16::from(self.data[literal_pos]), &mut self.out));
+            }
+            if let Some((symbol, nbits, extra, context)) = cmd.distance {
+                let distance_type = try!(distance_split.advance(&mut self.out));
+                let tree = distance_context_map[(distance_type << DISTANCE_CONTEXT_BITS) + context] as usize;
+                try!(distance_codes[tree].write_symbol(symbol, &mut self.out));
+                self.out.write_bits(nbits, extra);
+            }
+            self.pos += cmd.output_len;
+        }
+        Ok(())
+    }
+}
+
+// data is the output of the divans decoder, command_log the commands it logged while producing it
+pub fn transcode(data: &[u8], command_log: &[u8]) -> Result<Vec<u8>, ErrMsg> {
+    let window_bits = choose_window_bits(command_log);
+    let max_window = (1usize << window_bits) - 16;
+    let meta_blocks = try!(split_meta_blocks(command_log, max_window));
+    let mut transcoder = Transcoder {
+        out: BitWriter::new(),
+        data: data,
+        pos: 0,
+        max_window: max_window,
+        distance_cache: DistanceCache::default(),
+    };
+    write_window_bits(&mut transcoder.out, window_bits);
+    for meta_block in meta_blocks.iter() {
+        try!(transcoder.write_meta_block(meta_block));
+    }
+    if transcoder.pos != data.len() {
+        return Err(ErrMsg::BrotliTranscodeFailed(1));
+    }
+    // ISLAST and ISLASTEMPTY
+    transcoder.out.write_bits(2, 3);
+    Ok(transcoder.out.into_bytes())
+}
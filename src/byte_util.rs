@@ -0,0 +1,74 @@
+// Copyright 2017 Dropbox, Inc
+//
+//   Licensed under the Apache License, Version 2.0 (the "License");
+//   you may not use this file except in compliance with the License.
+//   You may obtain a copy of the License at
+//
+//       http://www.apache.org/licenses/LICENSE-2.0
+//
+//   Unless required by applicable law or agreed to in writing, software
+//   distributed under the License is distributed on an "AS IS" BASIS,
+//   WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//   See the License for the specific language governing permissions and
+//   limitations under the License.
+
+// little-endian integers and varints shared by the framing, side data and snapshot formats
+use core;
+#[cfg(not(feature="no-stdlib"))]
+use std::vec::Vec;
+
+pub fn read_u32(data: &[u8]) -> u32 {
+    u32::from(data[0]) | (u32::from(data[1]) << 8) | (u32::from(data[2]) << 16) | (u32::from(data[3]) << 24)
+}
+
+pub fn u32_bytes(val: u32) -> [u8; 4] {
+    [val as u8, (val >> 8) as u8, (val >> 16) as u8, (val >> 24) as u8]
+}
+
+pub fn read_u64(data: &[u8]) -> u64 {
+    u64::from(read_u32(data)) | (u64::from(read_u32(&data[4..])) << 32)
+}
+
+pub fn u64_bytes(val: u64) -> [u8; 8] {
+    let (low, high) = (u32_bytes(val as u32), u32_bytes((val >> 32) as u32));
+    [low[0], low[1], low[2], low[3], high[0], high[1], high[2], high[3]]
+}
+
+#[cfg(not(feature="no-stdlib"))]
+pub fn write_u32(data: &mut Vec<u8>, val: u32) {
+    data.extend(&u32_bytes(val));
+}
+
+// varints are LEB128: seven bits per byte, low bits first
+pub const MAX_VARINT_LENGTH: usize = 10;
+
+pub fn write_varint(output: &mut [u8], mut val: u64) -> usize {
+    let mut index = 0;
+    while val >= 0x80 {
+        output[index] = (val as u8 & 0x7f) | 0x80;
+        val >>= 7;
+        index += 1;
+    }
+    output[index] = val as u8;
+    index + 1
+}
+
+#[cfg(not(feature="no-stdlib"))]
+pub fn push_varint(data: &mut Vec<u8>, val: u64) {
+    let mut encoded = [0u8; MAX_VARINT_LENGTH];
+    let len = write_varint(&mut encoded, val);
+    data.extend(&encoded[..len]);
+}
+
+// advances offset past the varint, or returns None if it is truncated or too long
+pub fn read_varint(data: &[u8], offset: &mut usize) -> Option<u64> {
+    let mut val = 0u64;
+    for (index, byte) in data[core::cmp::min(*offset, data.len())..].iter().enumerate().take(MAX_VARINT_LENGTH) {
+        val |= u64::from(*byte & 0x7f) << (7 * index);
+        if *byte & 0x80 == 0 {
+            *offset += index + 1;
+            return Some(val);
+        }
+    }
+    None
+}
@@ -17,7 +17,7 @@ use core;
 pub use interface::{DivansResult, DivansOutputResult, ErrMsg};
 pub use alloc::{AllocatedStackMemory, Allocator, SliceWrapper, SliceWrapperMut, StackAllocator};
 use brotli::dictionary::{kBrotliMaxDictionaryWordLength, kBrotliDictionary,
-                                      kBrotliDictionaryOffsetsByLength};
+                                      kBrotliDictionaryOffsetsByLength, kBrotliDictionarySizeBitsByLength};
 use brotli::TransformDictionaryWord;
 pub use super::interface::{Command, Compressor, LiteralCommand, CopyCommand, DictCommand, FeatureFlagSliceType};
 mod test;
@@ -28,6 +28,8 @@ pub struct RingBufferIndexState {
     input_sub_offset: usize,
     ring_buffer_decode_index: u32,
     ring_buffer_output_index: u32,
+    // distance of the most recent copy, 0 until the first one
+    last_copy_distance: u32,
 }
 
 pub struct DivansRecodeState<RingBuffer: SliceWrapperMut<u8> + SliceWrapper<u8>>{
@@ -52,6 +54,7 @@ impl<RingBuffer: SliceWrapperMut<u8> + SliceWrapper<u8>> DivansRecodeState<RingB
                 ring_buffer_output_index: 0,
                 input_sub_offset: 0,
                 total_offset:0,
+                last_copy_distance: 0,
             },
         }
     }
@@ -65,6 +68,15 @@ impl<RingBuffer: SliceWrapperMut<u8> + SliceWrapper<u8>> DivansRecodeState<RingB
     pub fn num_bytes_encoded(&self) -> usize {
         self.state.total_offset
     }
+    // fills output with the bytes that follow the source of the last copy, the ones a literal would
+    // repeat if it continued that copy; returns how many it holds, which is at most the copy distance
+    pub fn match_history(&self, output: &mut [u8]) -> usize {
+        let count = core::cmp::min(output.len(), self.state.last_copy_distance as usize);
+        if count != 0 {
+            self.copy_decoded_from_ring_buffer(output.split_at_mut(count).0, self.state.last_copy_distance, count as u32);
+        }
+        count
+    }
     #[cold]
     fn fallback_last_8_literals(&self) -> [u8; 8] {
         let len = self.ring_buffer.slice().len();
@@ -243,6 +255,10 @@ impl<RingBuffer: SliceWrapperMut<u8> + SliceWrapper<u8>> DivansRecodeState<RingB
     }
     #[inline(always)]
     fn parse_copy(&mut self, copy:&CopyCommand) -> DivansOutputResult {
+        if copy.distance == 0 {
+            return DivansOutputResult::Failure(ErrMsg::Distance0NotAllowed);
+        }
+        self.state.last_copy_distance = copy.distance;
         let num_bytes_left_in_cmd = copy.num_bytes - self.state.input_sub_offset as u32;
         if copy.distance <= REPEAT_BUFFER_MAX_SIZE && num_bytes_left_in_cmd > copy.distance {
             let num_bytes_to_copy = core::cmp::min(num_bytes_left_in_cmd,
@@ -284,6 +300,17 @@ impl<RingBuffer: SliceWrapperMut<u8> + SliceWrapper<u8>> DivansRecodeState<RingB
     fn parse_dictionary(&mut self, dict_cmd:&DictCommand) -> DivansOutputResult {
         // dictionary words are bounded in size: make sure there's enough room for the whole word
         let copy_len = u32::from(dict_cmd.word_size);
+        // a corrupt stream may name a word the dictionary does not have
+        if copy_len > u32::from(kBrotliMaxDictionaryWordLength) {
+            return DivansOutputResult::Failure(ErrMsg::DictWordSizeTooLarge(dict_cmd.word_size));
+        }
+        let size_bits = u32::from(kBrotliDictionarySizeBitsByLength[copy_len as usize]);
+        if size_bits == 0 || dict_cmd.word_id >> size_bits != 0 {
+            return DivansOutputResult::Failure(ErrMsg::DictWordIdOutOfBounds(dict_cmd.word_size));
+        }
+        if dict_cmd.transform >= 121 {
+            return DivansOutputResult::Failure(ErrMsg::DictTransformIndexUndefined(dict_cmd.transform));
+        }
         let word_len_category_index = kBrotliDictionaryOffsetsByLength[copy_len as usize] as u32;
         let word_index = (dict_cmd.word_id * copy_len) + word_len_category_index;
         let dict = &kBrotliDictionary;
@@ -409,4 +436,9 @@ impl<RingBuffer:SliceWrapperMut<u8> + SliceWrapper<u8> + Default> Compressor for
              _output_offset:&mut usize)->DivansOutputResult{
         DivansOutputResult::Success
     }
+    fn sync_flush(&mut self,
+                  output:&mut[u8],
+                  output_offset:&mut usize)->DivansOutputResult{
+        self.flush(output, output_offset)
+    }
 }
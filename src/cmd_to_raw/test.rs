@@ -41,6 +41,7 @@ fn make_ring_buffer_state() -> super::DivansRecodeState<ExRingBuffer>{
             input_sub_offset: 0,
             ring_buffer_decode_index:0,
             ring_buffer_output_index:0,
+            last_copy_distance:0,
         },
         ring_buffer: ExRingBuffer::default(),
     }
@@ -174,6 +175,10 @@ fn help_copy_far(mut state: super::DivansRecodeState<ExRingBuffer>,
     let mut first_copy_data = [0u8;29];
     let mut first_readout = [0u8;29];
     first_copy_data.clone_from_slice(&buffer[(TEST_RING_SIZE - 112)..(TEST_RING_SIZE - 112 + 29)]);
+    // a literal after the copy would continue from where its source left off
+    let mut history = [0u8; 8];
+    assert_eq!(state.match_history(&mut history[..]), 8);
+    assert_eq!(history, buffer[(TEST_RING_SIZE - 112 + 29)..(TEST_RING_SIZE - 112 + 37)]);
 
     scratch_buffer[..(TEST_RING_SIZE - 29)].clone_from_slice(&buffer[29..]);
     scratch_buffer[(TEST_RING_SIZE - 29)..].clone_from_slice(&first_copy_data[..]);
@@ -388,3 +393,32 @@ fn test_insert_huge() {
     }
     help_test_insert(state, &values[..]);
 }
+#[test]
+fn test_dict_out_of_bounds() {
+    let mut state = make_ring_buffer_state();
+    for &(word_size, transform, word_id, expected) in [(24u8, 0u8, 32u32, super::ErrMsg::DictWordIdOutOfBounds(24)),
+                                                        (4, 0, 1024, super::ErrMsg::DictWordIdOutOfBounds(4)),
+                                                        (3, 0, 0, super::ErrMsg::DictWordIdOutOfBounds(3)),
+                                                        (25, 0, 0, super::ErrMsg::DictWordSizeTooLarge(25)),
+                                                        (4, 121, 0, super::ErrMsg::DictTransformIndexUndefined(121))].iter() {
+        let ret = state.parse_dictionary(&super::DictCommand{
+            word_size:word_size,
+            transform:transform,
+            final_size:0,
+            empty:0,
+            word_id:word_id
+        });
+        match ret {
+            DivansOutputResult::Failure(m) => assert_eq!(m, expected),
+            _ => panic!("dictionary word out of bounds accepted"),
+        }
+    }
+}
+#[test]
+fn test_copy_distance_zero() {
+    let mut state = help_ring_buffer_dict(make_ring_buffer_state());
+    match state.parse_copy(&super::CopyCommand{distance:0, num_bytes:4}) {
+        DivansOutputResult::Failure(m) => assert_eq!(m, super::ErrMsg::Distance0NotAllowed),
+        _ => panic!("copy from distance 0 accepted"),
+    }
+}
@@ -14,6 +14,7 @@ use ::interface::{
     LiteralPredictionModeNibble,
     PredictionModeContextMap,
     u8_to_speed,
+    speed_to_u8,
     MAX_LITERAL_CONTEXT_MAP_SIZE,
     MAX_PREDMODE_SPEED_AND_DISTANCE_CONTEXT_MAP_SIZE,
     NUM_MIXING_VALUES,
@@ -251,6 +252,14 @@ impl <AllocU8:Allocator<u8>> PredictionModeState<AllocU8> {
                        out_adapt_speed[speed_index].1 |= nibble;
                    }
                    if index as usize + 1 == 4 * out_adapt_speed.len(){
+                       // no encoder writes a speed faster than the probability model adapts
+                       for speed in out_adapt_speed.iter() {
+                           for code in [speed.0, speed.1].iter() {
+                               if *code > speed_to_u8(0x4000) {
+                                   return DivansResult::Failure(ErrMsg::AdaptationSpeedOutOfBounds(*code));
+                               }
+                           }
+                       }
                        self.pm.set_stride_context_speed([(u8_to_speed(out_adapt_speed[0].0),u8_to_speed(out_adapt_speed[0].1)),
                                                          (u8_to_speed(out_adapt_speed[1].0),u8_to_speed(out_adapt_speed[1].1))]);
                        self.pm.set_context_map_speed([(u8_to_speed(out_adapt_speed[2].0),u8_to_speed(out_adapt_speed[2].1)),
@@ -132,6 +132,9 @@ impl CopyState {
                     if superstate.specialization.adapt_cdf() {
                         nibble_prob.blend(last_nib, Speed::FAST);
                     }
+                    if last_nib + 19 > 32 {
+                        return DivansResult::Failure(ErrMsg::CopyLengthTooLarge(last_nib + 19));
+                    }
                     superstate.bk.last_clen = last_nib + 19;
                     self.state = CopySubstate::CountMantissaNibbles(0, round_up_mod_4(last_nib + 18), 1 << (last_nib + 18));
                 },
@@ -6,6 +6,8 @@ use mux::DevNull;
 use ::probability::{CDF16};
 use ::slice_util::{AllocatedMemoryPrefix, AllocatedMemoryRange};
 use ::alloc_util::UninitializedOnAlloc;
+use ::resizable_buffer::ResizableByteBuffer;
+use ::command_log::{log_command, logged_command_size};
 use ::divans_to_raw::DecoderSpecialization;
 use super::literal::{LiteralState, LiteralSubstate};
 use alloc::{SliceWrapper, Allocator, SliceWrapperMut};
@@ -58,8 +60,17 @@ pub struct DivansDecoderCodec<Cdf16:CDF16,
     pub outstanding_buffer_count: usize,
     pub cmd_buffer: AllocatedMemoryPrefix<StaticCommand, AllocCommand>,
     pub cmd_buffer_offset: usize,
+    // commands taken from the buffer so far, reported with decode errors
+    pub commands_decoded: u64,
     pub cmd_buffer_contains_eof: bool,
+    pub cmd_buffer_contains_sync: bool,
+    // the worker asked for input while commands were still buffered
+    pub deferred_need_input: bool,
+    // longest literal the memory limit leaves room for
+    pub max_literal_bytes: usize,
     pub pred_buffer: [PredictionModeContextMap<AllocatedMemoryPrefix<u8, AllocU8>>;2],
+    // when present, every executed command is appended here (see command_log)
+    pub command_log: Option<ResizableByteBuffer<u8, AllocU8>>,
 }
 
 impl<Cdf16:CDF16,
@@ -94,10 +105,15 @@ impl<Cdf16:CDF16,
             crc:crc,
             eof:false,
             cmd_buffer_offset:0,
+            commands_decoded: 0,
             cmd_buffer:AllocatedMemoryPrefix::realloc(mcommand.alloc_cell(NUM_SERIAL_COMMANDS_BUFFERED),0),
             cmd_buffer_contains_eof:false,
+            cmd_buffer_contains_sync:false,
+            deferred_need_input:false,
+            max_literal_bytes: usize::MAX,
             pred_buffer: [empty_prediction_mode_context_map::<AllocatedMemoryPrefix<u8, AllocU8>>(),
                           empty_prediction_mode_context_map::<AllocatedMemoryPrefix<u8, AllocU8>>()],
+            command_log: None,
         }
     }
     pub fn free(&mut self, mcommand: &mut AllocCommand) {
@@ -106,6 +122,9 @@ impl<Cdf16:CDF16,
         self.ctx.m8.get_base_alloc().free_cell(core::mem::replace(&mut self.state_lit.lc.data.0,
                                                                   AllocU8::AllocatedMemory::default()));
         self.demuxer.free_demux(self.ctx.m8.get_base_alloc());
+        if let Some(ref mut log) = self.command_log {
+            log.free(self.ctx.m8.get_base_alloc());
+        }
         for item in self.pred_buffer.iter_mut() {
             free_cmd(&mut Command::PredictionMode(core::mem::replace(item,
                                                                      empty_prediction_mode_context_map::<AllocatedMemoryPrefix<u8, AllocU8>>())),
@@ -132,6 +151,9 @@ impl<Cdf16:CDF16,
             }
             *input_offset += adjusted_input_bytes_offset;
         }
+        if self.demuxer.out_of_memory() {
+            return DivansInputResult::Failure(ErrMsg::MemoryExceedsLimit);
+        }
         if self.demuxer.encountered_eof() && usize::from(self.deserialized_crc_count) != self.deserialized_crc.len() {
             let crc_bytes_remaining = self.deserialized_crc.len() - usize::from(self.deserialized_crc_count);
             let amt_to_copy = core::cmp::min(input.len() - *input_offset, crc_bytes_remaining);
@@ -154,13 +176,20 @@ impl<Cdf16:CDF16,
             }
             DivansInputResult::Success
         } else {
-            if self.demuxer.encountered_eof() || self.outstanding_buffer_count > 0 {
+            if self.demuxer.encountered_eof() || self.outstanding_buffer_count > 0 || self.cmd_buffer_contains_sync {
                 DivansInputResult::Success
             } else {
                 DivansInputResult::NeedsMoreInput
             }
         }
     }
+    fn append_to_command_log(&mut self, cmd: &Command<AllocatedMemoryPrefix<u8, AllocU8>>) {
+        if let Some(ref mut log) = self.command_log {
+            let used = log_command(cmd, log.checkout_next_buffer(self.ctx.m8.get_base_alloc(),
+                                                                 Some(logged_command_size(cmd))));
+            log.commit_next_buffer(used);
+        }
+    }
     #[cfg_attr(not(feature="no-inline"), inline(always))]
     fn populate_ring_buffer(&mut self,
                             output: &mut [u8],
@@ -169,9 +198,15 @@ impl<Cdf16:CDF16,
             return DivansOutputResult::Success;
         }
         match self.ctx.recoder.encode_cmd(&mut self.state_populate_ring_buffer, output, output_offset) {
-            DivansOutputResult::Success => free_cmd(&mut self.state_populate_ring_buffer,
-                                                    &mut self.ctx.m8.use_cached_allocation::<
-                                                            UninitializedOnAlloc>()),
+            DivansOutputResult::Success => {
+                if self.command_log.is_some() {
+                    let cmd = core::mem::replace(&mut self.state_populate_ring_buffer, Command::nop());
+                    self.append_to_command_log(&cmd);
+                    self.state_populate_ring_buffer = cmd;
+                }
+                free_cmd(&mut self.state_populate_ring_buffer,
+                         &mut self.ctx.m8.use_cached_allocation::<UninitializedOnAlloc>())
+            },
             DivansOutputResult::Failure(f) => {
                 free_cmd(&mut self.state_populate_ring_buffer, &mut self.ctx.m8.use_cached_allocation::<
                         UninitializedOnAlloc>());
@@ -211,6 +246,19 @@ impl<Cdf16:CDF16,
         }
         return DivansResult::Success; // DONE decoding
     }
+    fn process_sync(&mut self, output: &mut [u8], output_offset: &mut usize) -> DivansOutputResult {
+        match self.ctx.recoder.flush(output, output_offset) {
+            DivansOutputResult::Success => {},
+            need_something => return need_something,
+        }
+        match self.ctx.lit_coder.sync() {
+            DivansResult::Success => {},
+            DivansResult::Failure(e) => return DivansOutputResult::Failure(e),
+            _ => return DivansOutputResult::Failure(ErrMsg::UnintendedCodecState(5)),
+        }
+        self.cmd_buffer_contains_sync = false;
+        DivansOutputResult::Success
+    }
     /*
     fn interpret_thread_literal(&mut self, lit: LiteralCommand<AllocatedMemoryPrefix<u8, AllocU8>>) {
         if let Command::Literal(lit) = cmd {
@@ -287,7 +335,13 @@ impl<Cdf16:CDF16,
             if self.eof {
                 return DecoderResult::Processed(self.process_eof(output, output_offset));
             }
-            if self.cmd_buffer_offset >= self.cmd_buffer.1 as usize && !self.cmd_buffer_contains_eof {
+            if self.cmd_buffer_offset >= self.cmd_buffer.1 as usize && !self.cmd_buffer_contains_eof && !self.cmd_buffer_contains_sync {
+                if self.deferred_need_input {
+                    self.deferred_need_input = false;
+                    if self.outstanding_buffer_count == 0 {
+                        return DecoderResult::Processed(DivansResult::NeedsMoreInput);
+                    }
+                }
                 self.cmd_buffer_offset = 0;
                 self.cmd_buffer.1 = 0; //reset the command buffer to zero
                 let mut consumed_data = [AllocatedMemoryRange::<u8, AllocU8>::default(),
@@ -337,14 +391,25 @@ impl<Cdf16:CDF16,
                     CommandResult::Ok => {},
                     CommandResult::Err(e) => return DecoderResult::Processed(DivansResult::Failure(e)),
                     CommandResult::Eof => self.cmd_buffer_contains_eof = true,
+                    CommandResult::Sync => self.cmd_buffer_contains_sync = true,
                 }
                 if need_input {
-                    return DecoderResult::Processed(DivansResult::NeedsMoreInput);
+                    if self.cmd_buffer.1 == 0 && !self.cmd_buffer_contains_sync {
+                        return DecoderResult::Processed(DivansResult::NeedsMoreInput);
+                    }
+                    // everything pulled so far has to reach the output before we ask for more
+                    self.deferred_need_input = true;
                 }
             }
             if self.cmd_buffer_offset >= self.cmd_buffer.1 as usize{
                 if self.cmd_buffer_contains_eof {
                     return DecoderResult::Processed(self.process_eof(output, output_offset));
+                } else if self.cmd_buffer_contains_sync {
+                    match self.process_sync(output, output_offset) {
+                        DivansOutputResult::Success => {},
+                        need_something => return DecoderResult::Processed(DivansResult::from(need_something)),
+                    }
+                    continue;
                 } else {
                     if Worker::COOPERATIVE_MAIN  {
                         return DecoderResult::Yield;
@@ -355,12 +420,20 @@ impl<Cdf16:CDF16,
             let offt = self.cmd_buffer_offset;
             let cur_cmd = &mut self.cmd_buffer.slice_mut()[offt];
             self.cmd_buffer_offset += 1;
+            self.commands_decoded += 1;
             if let &mut Command::Copy(cp) = cur_cmd {
                 self.is_populating_ring_buffer = true;
                 self.state_populate_ring_buffer=Command::Copy(cp);
             } else if let &mut Command::Literal(ref lit) = cur_cmd {
                 let num_bytes = lit.data.len();
+                if num_bytes > self.max_literal_bytes {
+                    return DecoderResult::Processed(DivansResult::Failure(ErrMsg::MemoryExceedsLimit));
+                }
                 self.state_lit.lc.data = self.ctx.m8.use_cached_allocation::<UninitializedOnAlloc>().alloc_cell(num_bytes);
+                // a metered allocator hands back an empty buffer once its budget is spent
+                if self.state_lit.lc.data.slice().len() != num_bytes {
+                    return DecoderResult::Processed(DivansResult::Failure(ErrMsg::MemoryExceedsLimit));
+                }
                 let last_8 = self.ctx.recoder.last_8_literals();
                 self.ctx.lbk.last_8_literals = //FIXME(threading) only should be run in the main thread
                     u64::from(last_8[0])
@@ -371,6 +444,7 @@ impl<Cdf16:CDF16,
                     | (u64::from(last_8[5])<<0x28)
                     | (u64::from(last_8[6])<<0x30)
                     | (u64::from(last_8[7])<<0x38);
+                self.ctx.start_literal_match();
                 let new_state = self.state_lit.get_nibble_code_state(0, &self.state_lit.lc, self.demuxer.read_buffer()[LIT_CODER].bytes_avail());
                 self.state_lit.state = new_state;
                 if Worker::COOPERATIVE_MAIN {
@@ -391,12 +465,21 @@ impl<Cdf16:CDF16,
                             _ => return DecoderResult::Processed(DivansResult::from(ret)),
                         }
                         self.codec_traits = construct_codec_trait_from_bookkeeping(&self.ctx.lbk);
+                        if self.command_log.is_some() {
+                            let cmd = Command::PredictionMode(pred_mode);
+                            self.append_to_command_log(&cmd);
+                            pred_mode = match cmd {
+                                Command::PredictionMode(logged) => logged,
+                                _ => unreachable!(),
+                            };
+                        }
                         match worker.push_context_map(pred_mode) {
                             Ok(_) => {},
                             Err(_) => panic!("thread unable to accept 2 concurrent context map"),
                         }
                     },
                     &mut Command::BlockSwitchLiteral(new_block_type) => {
+                        self.append_to_command_log(&Command::BlockSwitchLiteral(new_block_type));
                         self.ctx.lbk.obs_literal_block_switch(new_block_type.clone());
                         self.codec_traits = construct_codec_trait_from_bookkeeping(&self.ctx.lbk);
                     },
@@ -432,6 +515,34 @@ impl<Cdf16:CDF16,
             DecoderResult::Yield => unreachable!(),
         }
     }
+    // once decoding cannot continue, writes out what was decoded but not yet emitted: the ring buffer
+    // and the complete bytes of a partly decoded literal. The codec may not decode afterwards.
+    pub fn salvage_pending(&mut self, output: &mut [u8], output_offset: &mut usize) -> DivansOutputResult {
+        match self.populate_ring_buffer(output, output_offset) {
+            DivansOutputResult::Success => {},
+            need_something => return need_something,
+        }
+        let complete_bytes = match self.state_lit.state {
+            LiteralSubstate::LiteralNibbleIndex(nibble_index)
+            | LiteralSubstate::SafeLiteralNibbleIndex(nibble_index)
+            | LiteralSubstate::LiteralNibbleLowerHalf(nibble_index)
+            | LiteralSubstate::LiteralNibbleIndexWithECDF(nibble_index) => nibble_index >> 1,
+            _ => 0,
+        };
+        if complete_bytes != 0 {
+            self.state_lit.state = LiteralSubstate::FullyDecoded;
+            self.state_lit.lc.data.1 = complete_bytes;
+            self.is_populating_ring_buffer = true;
+            self.state_populate_ring_buffer = Command::Literal(
+                core::mem::replace(&mut self.state_lit.lc,
+                                   LiteralCommand::<AllocatedMemoryPrefix<u8, AllocU8>>::nop()));
+            match self.populate_ring_buffer(output, output_offset) {
+                DivansOutputResult::Success => {},
+                need_something => return need_something,
+            }
+        }
+        self.ctx.recoder.flush(output, output_offset)
+    }
 }
 
 #[derive(Clone, Copy, Debug, Eq, PartialEq)]
@@ -0,0 +1,139 @@
+// This file contains the literal half of a threaded encoder: the command thread codes everything
+// but literal contents and forwards the literals here in stream order
+use interface::{DivansResult, DivansOpResult, StreamMuxer};
+use mux::DevNull;
+use ::probability::{CDF16};
+use ::slice_util::{AllocatedMemoryPrefix, SliceReference};
+use ::alloc_util::UninitializedOnAlloc;
+use ::cmd_to_divans::EncoderSpecialization;
+use super::literal::{LiteralState, LiteralSubstate};
+use alloc::{SliceWrapper, Allocator};
+use super::interface::{
+    MainThreadContext,
+    LIT_CODER,
+    drain_or_fill_static_buffer,
+};
+use super::specializations::{
+    construct_codec_trait_from_bookkeeping,
+    CodecTraitSelector,
+};
+use ::interface::{
+    ArithmeticEncoderOrDecoder,
+    LiteralBlockSwitch,
+    LiteralCommand,
+    PredictionModeContextMap,
+    FeatureFlagSliceType,
+    Nop,
+};
+use threading::LiteralCoderOp;
+
+pub struct DivansLiteralEncoder<Cdf16:CDF16,
+                                AllocU8:Allocator<u8>,
+                                AllocCDF16:Allocator<Cdf16>,
+                                ArithmeticCoder:ArithmeticEncoderOrDecoder,
+                                LinearOutputBytes:StreamMuxer<AllocU8>+Default> {
+    pub ctx: MainThreadContext<Cdf16, AllocU8, AllocCDF16, ArithmeticCoder>,
+    pub devnull: DevNull<AllocU8>,
+    pub muxer: LinearOutputBytes,
+    pub codec_traits: CodecTraitSelector,
+    pub state_lit: LiteralState<AllocU8>,
+    pub specialization: EncoderSpecialization,
+}
+
+impl<Cdf16:CDF16,
+     AllocU8:Allocator<u8>,
+     AllocCDF16:Allocator<Cdf16>,
+     ArithmeticCoder:ArithmeticEncoderOrDecoder,
+     LinearOutputBytes:StreamMuxer<AllocU8>+Default> DivansLiteralEncoder<Cdf16, AllocU8, AllocCDF16, ArithmeticCoder, LinearOutputBytes> {
+    pub fn new(main_thread_context: MainThreadContext<Cdf16, AllocU8, AllocCDF16, ArithmeticCoder>,
+               muxer: LinearOutputBytes) -> Self {
+        let codec_trait = construct_codec_trait_from_bookkeeping(&main_thread_context.lbk);
+        DivansLiteralEncoder::<Cdf16, AllocU8, AllocCDF16, ArithmeticCoder, LinearOutputBytes> {
+            ctx: main_thread_context,
+            devnull: DevNull::default(),
+            muxer,
+            codec_traits: codec_trait,
+            state_lit: LiteralState {
+                lc:LiteralCommand::<AllocatedMemoryPrefix<u8, AllocU8>>::nop(),
+                state:LiteralSubstate::FullyDecoded,
+            },
+            specialization: EncoderSpecialization::new(),
+        }
+    }
+    // codes the contents of one literal; last_8_literals is the window tail and match_history the
+    // bytes after the last copy source (see DivansRecodeState::match_history) the command thread
+    // saw before this literal, which is all the context the literal priors need
+    pub fn encode_literal(&mut self,
+                          data: &[u8],
+                          last_8_literals: u64,
+                          match_history: &[u8],
+                          output_bytes: &mut [u8],
+                          output_offset: &mut usize) -> DivansResult {
+        let in_cmd = LiteralCommand::<SliceReference<u8>> {
+            data: SliceReference::new(data, 0, data.len()),
+            prob: FeatureFlagSliceType::<SliceReference<u8>>::default(),
+            high_entropy: false,
+        };
+        self.ctx.lbk.last_8_literals = last_8_literals;
+        if self.ctx.lbk.match_literals {
+            self.ctx.lbk.literal_match.start(match_history);
+        }
+        self.state_lit.lc.data = self.ctx.m8.use_cached_allocation::<UninitializedOnAlloc>().alloc_cell(data.len());
+        self.state_lit.state = self.state_lit.get_nibble_code_state(0, &in_cmd, 0);
+        let ret = match self.codec_traits {
+            CodecTraitSelector::MixingTrait(tr) => self.state_lit.encode_or_decode_content_bytes(
+                self.ctx.m8.get_base_alloc(),
+                &mut self.ctx.lit_coder,
+                &mut self.ctx.lbk,
+                &mut self.ctx.lit_high_priors,
+                &mut self.ctx.lit_low_priors,
+                &mut self.devnull,
+                &mut self.muxer,
+                &in_cmd,
+                output_bytes,
+                output_offset,
+                tr,
+                &self.specialization),
+            CodecTraitSelector::DefaultTrait(tr) => self.state_lit.encode_or_decode_content_bytes(
+                self.ctx.m8.get_base_alloc(),
+                &mut self.ctx.lit_coder,
+                &mut self.ctx.lbk,
+                &mut self.ctx.lit_high_priors,
+                &mut self.ctx.lit_low_priors,
+                &mut self.devnull,
+                &mut self.muxer,
+                &in_cmd,
+                output_bytes,
+                output_offset,
+                tr,
+                &self.specialization),
+        };
+        self.state_lit.free(&mut self.ctx.m8);
+        ret
+    }
+    pub fn obs_literal_block_switch(&mut self, btype: LiteralBlockSwitch) {
+        self.ctx.lbk.obs_literal_block_switch(btype);
+    }
+    pub fn obs_prediction_mode_context_map<ISlice:SliceWrapper<u8>>(&mut self,
+                                                                    pm: &PredictionModeContextMap<ISlice>) -> DivansOpResult {
+        let ret = self.ctx.lbk.obs_prediction_mode_context_map(pm, &mut self.ctx.mcdf16);
+        self.codec_traits = construct_codec_trait_from_bookkeeping(&self.ctx.lbk);
+        ret
+    }
+    pub fn literal_coder_op(&mut self,
+                            op: LiteralCoderOp,
+                            output_bytes: &mut [u8],
+                            output_offset: &mut usize) -> DivansResult {
+        match op {
+            LiteralCoderOp::Drain => drain_or_fill_static_buffer(LIT_CODER,
+                                                                 &mut self.ctx.lit_coder,
+                                                                 &mut self.devnull,
+                                                                 &mut self.muxer,
+                                                                 output_bytes,
+                                                                 output_offset,
+                                                                 &mut Some(self.ctx.m8.get_base_alloc())),
+            LiteralCoderOp::Sync => self.ctx.lit_coder.sync(),
+            LiteralCoderOp::Close => self.ctx.lit_coder.close(),
+        }
+    }
+}
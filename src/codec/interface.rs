@@ -3,7 +3,7 @@ use brotli;
 use brotli::interface::Nop;
 use interface::{DivansOpResult, ErrMsg, StreamMuxer, StreamDemuxer, DivansResult, WritableBytes};
 use ::cmd_to_raw::{DivansRecodeState, RingBufferSnapshot};
-use ::probability::{CDF16, Speed};
+use ::probability::{CDF16, CDFFlavor, Speed};
 use alloc::{SliceWrapper, Allocator, SliceWrapperMut};
 use ::slice_util::AllocatedMemoryPrefix;
 use ::alloc_util::RepurposingAlloc;
@@ -31,6 +31,8 @@ use super::priors::{
     LiteralNibblePriors,
     LiteralCommandPriors,
     LiteralCommandPriorsCM,
+    LiteralMixerPriors,
+    LiteralMatchPriors,
     CopyCommandPriors,
     DictCommandPriors,
     CrossCommandPriors,
@@ -38,6 +40,7 @@ use super::priors::{
     BlockTypePriors,
     NUM_BLOCK_TYPES,
 };
+use super::snapshot::{self, PriorSnapshotInfo, NUM_SNAPSHOT_TABLES};
 use ::priors::PriorCollection;
 const LOG_NUM_COPY_TYPE_PRIORS: usize = 4;
 
@@ -137,6 +140,19 @@ pub struct LiteralBookKeeping<Cdf16:CDF16,
     pub mixing_mask:[u8; 8192],
     pub model_weights: [super::weights::Weights;2],
     pub lit_cm_priors: LiteralCommandPriorsCM<Cdf16, AllocCDF16>,
+    pub cdf_flavor: CDFFlavor,
+    // code literals with literal_mixer blending every predictor instead of the stride/context map pair
+    pub mix_literals: bool,
+    pub literal_mixer: super::mixer::LiteralMixer,
+    pub lit_mixer_priors: LiteralMixerPriors<Cdf16, AllocCDF16>,
+    // add literal_match to the models the mixer blends
+    pub match_literals: bool,
+    pub literal_match: super::mixer::LiteralMatch,
+    pub lit_match_priors: LiteralMatchPriors<Cdf16, AllocCDF16>,
+    pub literal_sse: super::sse::LiteralSSE<Cdf16, AllocCDF16>,
+    // hashed order-2/3 contexts for the mixer: 1 << lit_hash_size entries, or none when 0
+    pub lit_hash_size: u8,
+    pub lit_hash_priors: AllocCDF16::AllocatedMemory,
 }
 
 pub struct CrossCommandBookKeeping<Cdf16:CDF16,
@@ -184,6 +200,12 @@ pub fn round_up_mod_4(val: u8) -> u8 {
 pub fn round_up_mod_4_u32(val: u32) -> u32 {
     ((val - 1)|3)+1
 }
+fn init_priors<Cdf16:CDF16>(priors: &mut [Cdf16], flavor: CDFFlavor) {
+    let init = Cdf16::flavored(flavor);
+    for item in priors.iter_mut() {
+        *item = init;
+    }
+}
 #[inline(always)]
 pub fn default_literal_speed() -> Speed {
     Speed::MUD
@@ -260,6 +282,20 @@ impl<
             lit_cm_priors: LiteralCommandPriorsCM {
                 priors: AllocCDF16::AllocatedMemory::default()
             },
+            cdf_flavor: CDFFlavor::default(),
+            mix_literals: false,
+            literal_mixer: super::mixer::LiteralMixer::default(),
+            lit_mixer_priors: LiteralMixerPriors {
+                priors: AllocCDF16::AllocatedMemory::default()
+            },
+            match_literals: false,
+            literal_match: super::mixer::LiteralMatch::default(),
+            lit_match_priors: LiteralMatchPriors {
+                priors: AllocCDF16::AllocatedMemory::default()
+            },
+            literal_sse: super::sse::LiteralSSE::default(),
+            lit_hash_size: 0,
+            lit_hash_priors: AllocCDF16::AllocatedMemory::default(),
         }
     }
     pub fn get_literal_block_type(&self) -> u8 {
@@ -281,6 +317,7 @@ impl<
         //self.num_literals_coded += 1;
         self.last_8_literals >>= 0x8;
         self.last_8_literals |= u64::from(b) << 0x38;
+        self.literal_match.push(b);
     }
     pub fn push_literal_nibble(&mut self, nibble: u8) {
         self.last_8_literals >>= 0x4;
@@ -323,6 +360,9 @@ impl<
         self.combine_literal_predictions = (context_mixing != 0) as bool;
         if context_mixing >= 2 && self.lit_cm_priors.priors.slice().len() == 0 {
             self.lit_cm_priors.priors = mcdf16.alloc_cell(LiteralCommandPriorsCM::<Cdf16, AllocCDF16>::NUM_ALL_PRIORS);
+            if self.cdf_flavor != CDFFlavor::default() {
+                init_priors(self.lit_cm_priors.priors.slice_mut(), self.cdf_flavor);
+            }
         }
         self.model_weights[0].set_mixing_param(context_mixing);
         self.model_weights[1].set_mixing_param(context_mixing);
@@ -556,6 +596,93 @@ pub enum ThreadContext<Cdf16:CDF16, AllocU8:Allocator<u8>, AllocCDF16:Allocator<
     Worker,
 }
 impl <Cdf16:CDF16, AllocU8:Allocator<u8>, AllocCDF16:Allocator<Cdf16>, ArithmeticCoder:ArithmeticEncoderOrDecoder> MainThreadContext<Cdf16, AllocU8, AllocCDF16, ArithmeticCoder> {
+    pub fn new(mut m8: AllocU8,
+               mut mcdf16: AllocCDF16,
+               lit_coder: ArithmeticCoder,
+               ring_buffer_size: usize) -> Self {
+        let ring_buffer = m8.alloc_cell(1 << ring_buffer_size);
+        let lit_low_priors = mcdf16.alloc_cell(LiteralNibblePriors::<Cdf16, AllocCDF16>::NUM_ALL_PRIORS);
+        let lit_high_priors = mcdf16.alloc_cell(LiteralNibblePriors::<Cdf16, AllocCDF16>::NUM_ALL_PRIORS);
+        let literal_context_map = m8.alloc_cell(MAX_LITERAL_CONTEXT_MAP_SIZE);
+        MainThreadContext::<Cdf16, AllocU8, AllocCDF16, ArithmeticCoder>{
+            recoder: DivansRecodeState::<AllocU8::AllocatedMemory>::new(
+                ring_buffer),
+            m8: RepurposingAlloc::<u8, AllocU8>::new(m8),
+            mcdf16:mcdf16,
+            lbk: LiteralBookKeeping::new(literal_context_map),
+            lit_high_priors: LiteralNibblePriors {
+                priors: lit_high_priors
+            },
+            lit_low_priors: LiteralNibblePriors {
+                priors: lit_low_priors
+            },
+            lit_coder: lit_coder,
+        }
+    }
+    pub fn set_cdf_flavor(&mut self, flavor: CDFFlavor) {
+        if self.lbk.cdf_flavor == flavor {
+            return;
+        }
+        self.lbk.cdf_flavor = flavor;
+        init_priors(self.lit_high_priors.priors.slice_mut(), flavor);
+        init_priors(self.lit_low_priors.priors.slice_mut(), flavor);
+        init_priors(self.lbk.lit_cm_priors.priors.slice_mut(), flavor);
+        init_priors(self.lbk.lit_mixer_priors.priors.slice_mut(), flavor);
+        init_priors(self.lbk.lit_match_priors.priors.slice_mut(), flavor);
+        init_priors(self.lbk.literal_sse.priors.priors.slice_mut(), flavor);
+        init_priors(self.lbk.lit_hash_priors.slice_mut(), flavor);
+    }
+    // switches literals to the N-way mixer, allocating the predictors it needs on first use
+    pub fn set_literal_mixer(&mut self, enable: bool) {
+        self.lbk.mix_literals = enable;
+        if !enable {
+            return;
+        }
+        if self.lbk.lit_cm_priors.priors.slice().is_empty() {
+            self.lbk.lit_cm_priors.priors = self.mcdf16.alloc_cell(LiteralCommandPriorsCM::<Cdf16, AllocCDF16>::NUM_ALL_PRIORS);
+            init_priors(self.lbk.lit_cm_priors.priors.slice_mut(), self.lbk.cdf_flavor);
+        }
+        if self.lbk.lit_mixer_priors.priors.slice().is_empty() {
+            self.lbk.lit_mixer_priors.priors = self.mcdf16.alloc_cell(LiteralMixerPriors::<Cdf16, AllocCDF16>::NUM_ALL_PRIORS);
+            init_priors(self.lbk.lit_mixer_priors.priors.slice_mut(), self.lbk.cdf_flavor);
+        }
+    }
+    // adds the match model to the mixer's predictors, allocating its priors on first use
+    pub fn set_literal_match(&mut self, enable: bool) {
+        self.lbk.match_literals = enable;
+        if enable && self.lbk.lit_match_priors.priors.slice().is_empty() {
+            self.lbk.lit_match_priors.priors = self.mcdf16.alloc_cell(LiteralMatchPriors::<Cdf16, AllocCDF16>::NUM_ALL_PRIORS);
+            init_priors(self.lbk.lit_match_priors.priors.slice_mut(), self.lbk.cdf_flavor);
+        }
+    }
+    // adds the secondary estimation stage to literals, allocating its tables on first use
+    pub fn set_literal_sse(&mut self, enable: bool) {
+        self.lbk.literal_sse.enabled = enable;
+        if enable && self.lbk.literal_sse.priors.priors.slice().is_empty() {
+            self.lbk.literal_sse.priors.priors = self.mcdf16.alloc_cell(super::priors::LiteralSSEPriors::<Cdf16, AllocCDF16>::NUM_ALL_PRIORS);
+            init_priors(self.lbk.literal_sse.priors.priors.slice_mut(), self.lbk.cdf_flavor);
+        }
+    }
+    // gives the mixer a hashed order-2/3 context table of 1 << log_size entries; 0 removes it
+    pub fn set_literal_hash_size(&mut self, log_size: u8) {
+        if self.lbk.lit_hash_size == log_size {
+            return;
+        }
+        self.mcdf16.free_cell(core::mem::take(&mut self.lbk.lit_hash_priors));
+        self.lbk.lit_hash_size = log_size;
+        if log_size != 0 {
+            self.lbk.lit_hash_priors = self.mcdf16.alloc_cell(1 << log_size);
+            init_priors(self.lbk.lit_hash_priors.slice_mut(), self.lbk.cdf_flavor);
+        }
+    }
+    // points the match model at the bytes after the source of the last copy in the window
+    pub fn start_literal_match(&mut self) {
+        if self.lbk.match_literals {
+            let mut history = [0u8; super::mixer::MAX_MATCH_BYTES];
+            let count = self.recoder.match_history(&mut history[..]);
+            self.lbk.literal_match.start(&history[..count]);
+        }
+    }
     pub fn dismantle(self) -> (
         RepurposingAlloc<u8, AllocU8>,
         AllocCDF16,
@@ -596,6 +723,10 @@ impl <Cdf16:CDF16, AllocU8:Allocator<u8>, AllocCDF16:Allocator<Cdf16>, Arithmeti
         self.mcdf16.free_cell(core::mem::replace(&mut self.lit_high_priors.priors, AllocCDF16::AllocatedMemory::default()));
         self.mcdf16.free_cell(core::mem::replace(&mut self.lit_low_priors.priors, AllocCDF16::AllocatedMemory::default()));
         self.mcdf16.free_cell(core::mem::replace(&mut self.lbk.lit_cm_priors.priors, AllocCDF16::AllocatedMemory::default()));
+        self.mcdf16.free_cell(core::mem::take(&mut self.lbk.lit_mixer_priors.priors));
+        self.mcdf16.free_cell(core::mem::take(&mut self.lbk.lit_match_priors.priors));
+        self.mcdf16.free_cell(core::mem::take(&mut self.lbk.literal_sse.priors.priors));
+        self.mcdf16.free_cell(core::mem::take(&mut self.lbk.lit_hash_priors));
     }
 }
 impl <Cdf16:CDF16, AllocU8:Allocator<u8>, AllocCDF16:Allocator<Cdf16>, ArithmeticCoder:ArithmeticEncoderOrDecoder> ThreadContext<Cdf16, AllocU8, AllocCDF16, ArithmeticCoder> {
@@ -696,6 +827,39 @@ impl<ArithmeticCoder:ArithmeticEncoderOrDecoder,
         }
     }
 }
+// bytes allocated by CrossCommandState::new plus the context mixing priors it may add later
+pub fn model_allocation_size<Cdf16:CDF16, AllocCDF16:Allocator<Cdf16>>(ring_buffer_size: usize) -> usize {
+    let num_priors = 2 * LiteralNibblePriors::<Cdf16, AllocCDF16>::NUM_ALL_PRIORS
+        + LiteralCommandPriors::<Cdf16, AllocCDF16>::NUM_ALL_PRIORS
+        + LiteralCommandPriorsCM::<Cdf16, AllocCDF16>::NUM_ALL_PRIORS
+        + CopyCommandPriors::<Cdf16, AllocCDF16>::NUM_ALL_PRIORS
+        + DictCommandPriors::<Cdf16, AllocCDF16>::NUM_ALL_PRIORS
+        + CrossCommandPriors::<Cdf16, AllocCDF16>::NUM_ALL_PRIORS
+        + PredictionModePriors::<Cdf16, AllocCDF16>::NUM_ALL_PRIORS
+        + BlockTypePriors::<Cdf16, AllocCDF16>::NUM_ALL_PRIORS;
+    (1 << ring_buffer_size) + MAX_LITERAL_CONTEXT_MAP_SIZE + 4 * NUM_BLOCK_TYPES
+        + num_priors * core::mem::size_of::<Cdf16>()
+}
+// what a frame using the literal mixer allocates beyond model_allocation_size
+pub fn literal_mixer_allocation_size<Cdf16:CDF16, AllocCDF16:Allocator<Cdf16>>() -> usize {
+    LiteralMixerPriors::<Cdf16, AllocCDF16>::NUM_ALL_PRIORS * core::mem::size_of::<Cdf16>()
+}
+// what a frame whose mixer includes the match model allocates for it
+pub fn literal_match_allocation_size<Cdf16:CDF16, AllocCDF16:Allocator<Cdf16>>() -> usize {
+    LiteralMatchPriors::<Cdf16, AllocCDF16>::NUM_ALL_PRIORS * core::mem::size_of::<Cdf16>()
+}
+// what a frame with a hashed literal context table of 1 << log_size entries allocates for it
+pub fn literal_hash_allocation_size<Cdf16:CDF16>(log_size: u8) -> usize {
+    if log_size == 0 {
+        0
+    } else {
+        (1usize << log_size) * core::mem::size_of::<Cdf16>()
+    }
+}
+// what a frame using the literal secondary estimation stage allocates beyond model_allocation_size
+pub fn literal_sse_allocation_size<Cdf16:CDF16, AllocCDF16:Allocator<Cdf16>>() -> usize {
+    super::priors::LiteralSSEPriors::<Cdf16, AllocCDF16>::NUM_ALL_PRIORS * core::mem::size_of::<Cdf16>()
+}
 impl <AllocU8:Allocator<u8>,
       LinearInputBytes:StreamDemuxer<AllocU8>,
       LinearOutputBytes:StreamMuxer<AllocU8>+Default,                                   
@@ -724,16 +888,12 @@ impl <AllocU8:Allocator<u8>,
                literal_adaptation_rate: Option<[Speed;4]>,
                do_context_map:bool,
                force_stride: StrideSelection) -> Self {
-        let ring_buffer = m8.alloc_cell(1 << ring_buffer_size);
-        let lit_low_priors = mcdf16.alloc_cell(LiteralNibblePriors::<Cdf16, AllocCDF16>::NUM_ALL_PRIORS);
-        let lit_high_priors = mcdf16.alloc_cell(LiteralNibblePriors::<Cdf16, AllocCDF16>::NUM_ALL_PRIORS);
         let lit_len_priors = mcdf16.alloc_cell(LiteralCommandPriors::<Cdf16, AllocCDF16>::NUM_ALL_PRIORS);
         let copy_priors = mcdf16.alloc_cell(CopyCommandPriors::<Cdf16, AllocCDF16>::NUM_ALL_PRIORS);
         let dict_priors = mcdf16.alloc_cell(DictCommandPriors::<Cdf16, AllocCDF16>::NUM_ALL_PRIORS);
         let cc_priors = mcdf16.alloc_cell(CrossCommandPriors::<Cdf16, AllocCDF16>::NUM_ALL_PRIORS);
         let pred_priors = mcdf16.alloc_cell(PredictionModePriors::<Cdf16, AllocCDF16>::NUM_ALL_PRIORS);
         let btype_priors = mcdf16.alloc_cell(BlockTypePriors::<Cdf16, AllocCDF16>::NUM_ALL_PRIORS);
-        let literal_context_map = m8.alloc_cell(MAX_LITERAL_CONTEXT_MAP_SIZE);
         let distance_context_map = m8.alloc_cell(4 * NUM_BLOCK_TYPES);
         CrossCommandState::<ArithmeticCoder,
                             Specialization,
@@ -746,20 +906,11 @@ impl <AllocU8:Allocator<u8>,
             coder: cmd_coder,
             //lit_coder: lit_coder,
             specialization: spc,
-            thread_ctx: ThreadContext::MainThread(MainThreadContext::<Cdf16, AllocU8, AllocCDF16, ArithmeticCoder>{
-                recoder: DivansRecodeState::<AllocU8::AllocatedMemory>::new(
-                ring_buffer),
-                m8: RepurposingAlloc::<u8, AllocU8>::new(m8),
-                mcdf16:mcdf16,
-                lbk: LiteralBookKeeping::new(literal_context_map),
-                lit_high_priors: LiteralNibblePriors {
-                    priors: lit_high_priors
-                },
-                lit_low_priors: LiteralNibblePriors {
-                    priors: lit_low_priors
-                },
-                lit_coder: lit_coder,
-            }),
+            thread_ctx: ThreadContext::MainThread(MainThreadContext::<Cdf16, AllocU8, AllocCDF16, ArithmeticCoder>::new(
+                m8,
+                mcdf16,
+                lit_coder,
+                ring_buffer_size)),
             demuxer:linear_input_bytes,
             muxer:LinearOutputBytes::default(),
             bk:CrossCommandBookKeeping::new(lit_len_priors, cc_priors, copy_priors,
@@ -773,6 +924,90 @@ impl <AllocU8:Allocator<u8>,
             ),
         }
     }
+    // reseeds every prior with the initial distribution of the flavor named in the stream header
+    pub fn set_cdf_flavor(&mut self, flavor: CDFFlavor) {
+        if let ThreadContext::MainThread(ref mut ctx) = self.thread_ctx {
+            if ctx.lbk.cdf_flavor == flavor {
+                return;
+            }
+            ctx.set_cdf_flavor(flavor);
+        }
+        init_priors(self.bk.lit_len_priors.priors.slice_mut(), flavor);
+        init_priors(self.bk.cc_priors.priors.slice_mut(), flavor);
+        init_priors(self.bk.copy_priors.priors.slice_mut(), flavor);
+        init_priors(self.bk.dict_priors.priors.slice_mut(), flavor);
+        init_priors(self.bk.prediction_priors.priors.slice_mut(), flavor);
+        init_priors(self.bk.btype_priors.priors.slice_mut(), flavor);
+    }
+    pub fn set_literal_mixer(&mut self, enable: bool) {
+        if let ThreadContext::MainThread(ref mut ctx) = self.thread_ctx {
+            ctx.set_literal_mixer(enable);
+        }
+    }
+    pub fn set_literal_match(&mut self, enable: bool) {
+        if let ThreadContext::MainThread(ref mut ctx) = self.thread_ctx {
+            ctx.set_literal_match(enable);
+        }
+    }
+    pub fn set_literal_sse(&mut self, enable: bool) {
+        if let ThreadContext::MainThread(ref mut ctx) = self.thread_ctx {
+            ctx.set_literal_sse(enable);
+        }
+    }
+    pub fn set_literal_hash_size(&mut self, log_size: u8) {
+        if let ThreadContext::MainThread(ref mut ctx) = self.thread_ctx {
+            ctx.set_literal_hash_size(log_size);
+        }
+    }
+    // serializes the priors adapted so far, so a later stream can start where this one stands
+    pub fn write_prior_snapshot<Sink:FnMut(&[u8])>(&self, sink: &mut Sink) -> Result<PriorSnapshotInfo, ErrMsg> {
+        match self.thread_ctx {
+            ThreadContext::MainThread(ref ctx) => {
+                let tables: [&[Cdf16]; NUM_SNAPSHOT_TABLES] = [
+                    ctx.lit_high_priors.priors.slice(),
+                    ctx.lit_low_priors.priors.slice(),
+                    self.bk.lit_len_priors.priors.slice(),
+                    self.bk.cc_priors.priors.slice(),
+                    self.bk.copy_priors.priors.slice(),
+                    self.bk.dict_priors.priors.slice(),
+                    self.bk.prediction_priors.priors.slice(),
+                    self.bk.btype_priors.priors.slice()];
+                Ok(snapshot::write_prior_snapshot(ctx.lbk.cdf_flavor,
+                                                  &ctx.lbk.literal_adaptation,
+                                                  &ctx.lbk.mixing_mask[..],
+                                                  &tables,
+                                                  sink))
+            },
+            ThreadContext::Worker => Err(ErrMsg::WrongInternalEncoderState(2)),
+        }
+    }
+    // starts from the priors of a snapshot, switching to its cdf flavor; the mixer, sse and hashed
+    // literal tables are not recorded and keep their initial distributions
+    pub fn load_prior_snapshot(&mut self, prior_snapshot: &[u8]) -> Result<PriorSnapshotInfo, ErrMsg> {
+        let info = match snapshot::parse_prior_snapshot::<Cdf16, AllocCDF16>(prior_snapshot) {
+            Ok(info) => info,
+            Err(e) => return Err(e),
+        };
+        self.set_cdf_flavor(info.cdf_flavor);
+        match self.thread_ctx {
+            ThreadContext::MainThread(ref mut ctx) => {
+                let mut tables: [&mut [Cdf16]; NUM_SNAPSHOT_TABLES] = [
+                    ctx.lit_high_priors.priors.slice_mut(),
+                    ctx.lit_low_priors.priors.slice_mut(),
+                    self.bk.lit_len_priors.priors.slice_mut(),
+                    self.bk.cc_priors.priors.slice_mut(),
+                    self.bk.copy_priors.priors.slice_mut(),
+                    self.bk.dict_priors.priors.slice_mut(),
+                    self.bk.prediction_priors.priors.slice_mut(),
+                    self.bk.btype_priors.priors.slice_mut()];
+                snapshot::load_prior_snapshot(prior_snapshot,
+                                              &mut ctx.lbk.literal_adaptation,
+                                              &mut ctx.lbk.mixing_mask[..],
+                                              &mut tables)
+            },
+            ThreadContext::Worker => Err(ErrMsg::WrongInternalDecoderState),
+        }
+    }
     pub fn snapshot_literal_or_copy_state(&self) -> CodecSnapshot {
         let ring_buffer;
         let last_8;
@@ -824,6 +1059,9 @@ impl <AllocU8:Allocator<u8>,
             ctx.lit_high_priors.summarize_speed_costs();
             ctx.lit_low_priors.summarize_speed_costs();
             ctx.lbk.lit_cm_priors.summarize_speed_costs();
+            ctx.lbk.lit_mixer_priors.summarize_speed_costs();
+            ctx.lbk.lit_match_priors.summarize_speed_costs();
+            ctx.lbk.literal_sse.priors.summarize_speed_costs();
         }
         let cdf16a = core::mem::replace(&mut self.bk.cc_priors.priors, AllocCDF16::AllocatedMemory::default());
         let cdf16b = core::mem::replace(&mut self.bk.copy_priors.priors, AllocCDF16::AllocatedMemory::default());
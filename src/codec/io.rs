@@ -72,6 +72,9 @@ impl<AllocU8:Allocator<u8>, LinearInputBytes:StreamDemuxer<AllocU8>> StreamDemux
     fn encountered_eof(&self) -> bool {
         self.input.encountered_eof()
     }
+    fn out_of_memory(&self) -> bool {
+        self.input.out_of_memory()
+    }
     #[inline(always)]
     fn free_demux(&mut self, m8: &mut AllocU8) {
         self.input.free_demux(m8);
@@ -103,6 +106,9 @@ impl<AllocU8:Allocator<u8>, LinearInputBytes:StreamDemuxer<AllocU8>> ThreadToMai
     fn push_eof(&mut self) -> DivansOutputResult {
         self.err
     }
+    fn push_sync(&mut self) -> DivansOutputResult {
+        self.err
+    }
     fn push_consumed_data(&mut self,
         data: &mut AllocatedMemoryRange<u8, AllocU8>,
         mut m8: Option<&mut RepurposingAlloc<u8, AllocU8>>,
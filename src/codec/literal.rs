@@ -1,7 +1,8 @@
 use core;
-use interface::{DivansResult, StreamMuxer, StreamDemuxer};
-use ::probability::{CDF16, Speed, ExternalProbCDF16};
-use super::priors::{LiteralNibblePriorType, LiteralCommandPriorType, LiteralCMPriorType};
+use interface::{DivansResult, ErrMsg, StreamMuxer, StreamDemuxer};
+use ::probability::{CDF16, Speed, ExternalProbCDF16, Prob};
+use super::priors::{LiteralNibblePriorType, LiteralCommandPriorType, LiteralCMPriorType, LiteralMixerPriorType, LiteralMatchPriorType};
+use super::mixer::{mixer_context, word_context, NO_MATCH_NIBBLE};
 
 use alloc_util::{RepurposingAlloc, AllocatedMemoryPrefix, UninitializedOnAlloc};
 use alloc::{SliceWrapper, Allocator, SliceWrapperMut};
@@ -72,7 +73,7 @@ impl NibbleArrayCallSite for NibbleArraySecond {
    const SECOND_HALF: bool = true;
 }
 
-trait HighTrait {
+pub trait HighTrait {
     const IS_HIGH: bool;
 }
 struct HighNibble{}
@@ -116,6 +117,103 @@ pub fn get_prev_word_context<Cdf16:CDF16,
     ByteContext{actual_context:actual_context, stride_bytes:lbk.last_8_literals, prev_byte: prev_byte}
 }
 
+// the hashed context of the order most recent bytes, with the order mixed in so that each order
+// lands on its own slots of the shared table
+#[inline(always)]
+fn order_hash(last_8_literals: u64, order: u32) -> u64 {
+    ((last_8_literals >> (64 - 8 * order)) | (u64::from(order) << 32)).wrapping_mul(0x9e37_79b9_7f4a_7c15)
+}
+
+// the slot of the hashed literal context table holding one nibble of a context: nibble_slot is 0
+// for the high nibble and 1 plus the high nibble for the low one
+#[inline(always)]
+fn hashed_context_index(context_hash: u64, nibble_slot: usize, log_size: u8) -> usize {
+    ((context_hash ^ nibble_slot as u64).wrapping_mul(0xff51_afd7_ed55_8ccd) >> (64 - u32::from(log_size))) as usize
+}
+
+// codes a nibble from the mix of the stride prior chosen by code_nibble with the context map,
+// order 1 and word position predictors, plus the match model and the order 2 and 3 predictors
+// when the stream has them, then trains the mixer and the predictors on the result
+#[inline(always)]
+fn code_mixed_nibble<ArithmeticCoder:ArithmeticEncoderOrDecoder,
+                     Specialization:EncoderOrDecoderSpecialization,
+                     Cdf16:CDF16,
+                     AllocU8:Allocator<u8>,
+                     AllocCDF16:Allocator<Cdf16>,
+                     HTraits:HighTrait>(mut cur_nibble: u8,
+                                        byte_context: ByteContext,
+                                        cur_byte_prior: u8,
+                                        stride_prob: &Cdf16,
+                                        local_coder: &mut ArithmeticCoder,
+                                        lbk: &mut LiteralBookKeeping<Cdf16, AllocU8, AllocCDF16>,
+                                        specialization: &Specialization) -> u8 {
+    // the low nibble predictors are indexed by the high nibble first, so each row holds 16 of them
+    let (nibble_prior, row_size) = if HTraits::IS_HIGH {(0, 1)} else {(usize::from(cur_byte_prior), 16)};
+    let cm_index = nibble_prior + row_size * usize::from(byte_context.actual_context);
+    let order1_index = nibble_prior + row_size * usize::from(byte_context.prev_byte);
+    let word_index = nibble_prior + row_size * word_context(byte_context.stride_bytes);
+    let match_nibble = if lbk.match_literals {
+        lbk.literal_match.expected_nibble(HTraits::IS_HIGH, cur_byte_prior)
+    } else {
+        NO_MATCH_NIBBLE
+    };
+    let match_index = match_nibble + (NO_MATCH_NIBBLE + 1) * lbk.literal_match.state();
+    let (cm_type, order1_type, word_type, match_type) = if HTraits::IS_HIGH {
+        (LiteralCMPriorType::FirstNibble, LiteralMixerPriorType::OrderOneHigh, LiteralMixerPriorType::WordHigh, LiteralMatchPriorType::MatchHigh)
+    } else {
+        (LiteralCMPriorType::SecondNibble, LiteralMixerPriorType::OrderOneLow, LiteralMixerPriorType::WordLow, LiteralMatchPriorType::MatchLow)
+    };
+    let mut models = [
+        *stride_prob,
+        *lbk.lit_cm_priors.get_with_raw_index(cm_type.clone(), cm_index),
+        *lbk.lit_mixer_priors.get_with_raw_index(order1_type.clone(), order1_index),
+        *lbk.lit_mixer_priors.get_with_raw_index(word_type.clone(), word_index),
+        Cdf16::default(),
+        Cdf16::default(),
+        Cdf16::default(),
+    ];
+    let mut num_models = 4;
+    if lbk.match_literals {
+        models[num_models] = *lbk.lit_match_priors.get_with_raw_index(match_type.clone(), match_index);
+        num_models += 1;
+    }
+    let mut hash_indices = [0usize; 2];
+    if lbk.lit_hash_size != 0 {
+        let nibble_slot = if HTraits::IS_HIGH {0} else {1 + usize::from(cur_byte_prior)};
+        for (hash_index, order) in hash_indices.iter_mut().zip([2, 3].iter()) {
+            *hash_index = hashed_context_index(order_hash(byte_context.stride_bytes, *order), nibble_slot, lbk.lit_hash_size);
+            models[num_models] = lbk.lit_hash_priors.slice()[*hash_index];
+            num_models += 1;
+        }
+    }
+    let models = &models[..num_models];
+    let mixer_ctx = mixer_context(HTraits::IS_HIGH, byte_context.prev_byte, match_nibble);
+    let prob = lbk.literal_mixer.mix(mixer_ctx, models);
+    let prev_nibble = if HTraits::IS_HIGH {byte_context.prev_byte} else {cur_byte_prior};
+    let weighted_prob = lbk.literal_sse.code_nibble::<ArithmeticCoder, Specialization, HTraits>(
+        &mut cur_nibble, &prob, prev_nibble, lbk.btype_last, lbk.literal_adaptation[HTraits::IS_HIGH as usize], local_coder, specialization);
+    let mut model_probs: [Prob; 7] = [0; 7];
+    for (model_prob, model) in model_probs.iter_mut().zip(models.iter()) {
+        *model_prob = model.sym_to_start_and_freq(cur_nibble).range.freq;
+    }
+    lbk.literal_mixer.update(mixer_ctx, &model_probs[..num_models], weighted_prob);
+    if specialization.adapt_cdf() {
+        let speed = lbk.literal_adaptation[2 | HTraits::IS_HIGH as usize];
+        lbk.lit_cm_priors.get_with_raw_index_mut(cm_type, cm_index).blend(cur_nibble, speed);
+        let stride_speed = lbk.literal_adaptation[HTraits::IS_HIGH as usize];
+        lbk.lit_mixer_priors.get_with_raw_index_mut(order1_type, order1_index).blend(cur_nibble, stride_speed);
+        lbk.lit_mixer_priors.get_with_raw_index_mut(word_type, word_index).blend(cur_nibble, stride_speed);
+        if lbk.match_literals {
+            lbk.lit_match_priors.get_with_raw_index_mut(match_type, match_index).blend(cur_nibble, stride_speed);
+        }
+        if lbk.lit_hash_size != 0 {
+            for hash_index in hash_indices.iter() {
+                lbk.lit_hash_priors.slice_mut()[*hash_index].blend(cur_nibble, stride_speed);
+            }
+        }
+    }
+    cur_nibble
+}
 
 impl<AllocU8:Allocator<u8>,
                          > LiteralState<AllocU8> {
@@ -216,7 +314,17 @@ impl<AllocU8:Allocator<u8>,
             } else {
                 coder_prior = nibble_prob;
             }
-            if CTraits::MIXING_PRIORS {
+            let prev_nibble = if HTraits::IS_HIGH {byte_context.prev_byte} else {cur_byte_prior};
+            if lbk.mix_literals {
+                cur_nibble = code_mixed_nibble::<ArithmeticCoder, Specialization, Cdf16, AllocU8, AllocCDF16, HTraits>(
+                    cur_nibble,
+                    byte_context,
+                    cur_byte_prior,
+                    nibble_prob,
+                    local_coder,
+                    lbk,
+                    specialization);
+            } else if CTraits::MIXING_PRIORS {
                 let cm_prob = if HTraits::IS_HIGH {
                     lbk.lit_cm_priors.get(LiteralCMPriorType::FirstNibble,
                                                     (0,//(byte_context.selected_context as i8 & -(bk.prior_depth as i8)) as usize,
@@ -228,28 +336,25 @@ impl<AllocU8:Allocator<u8>,
                                                      usize::from(byte_context.actual_context)))
                 };
                 let prob = cm_prob.average(nibble_prob, lbk.model_weights[HTraits::IS_HIGH as usize].norm_weight() as u16 as i32);
-                let weighted_prob_range = local_coder.get_or_put_nibble(
-                    &mut cur_nibble,
-                    &prob,
-                    BillingDesignation::LiteralCommand(LiteralSubstate::LiteralNibbleIndex(!HTraits::IS_HIGH as u32)));
+                let weighted_prob = lbk.literal_sse.code_nibble::<ArithmeticCoder, Specialization, HTraits>(
+                    &mut cur_nibble, &prob, prev_nibble, lbk.btype_last, lbk.literal_adaptation[HTraits::IS_HIGH as usize], local_coder, specialization);
                 assert_eq!(lbk.model_weights[HTraits::IS_HIGH as usize].should_mix(), true);
                 let model_probs = [
                     cm_prob.sym_to_start_and_freq(cur_nibble).range.freq,
                     nibble_prob.sym_to_start_and_freq(cur_nibble).range.freq,
                 ];
-                lbk.model_weights[HTraits::IS_HIGH as usize].update(model_probs, weighted_prob_range.freq);
+                lbk.model_weights[HTraits::IS_HIGH as usize].update(model_probs, weighted_prob);
                 if specialization.adapt_cdf() {
                     cm_prob.blend(cur_nibble, lbk.literal_adaptation[2 | HTraits::IS_HIGH as usize].clone());
                 }
             } else {
                 // actually code (or decode) the byte from the file
-                local_coder.get_or_put_nibble(&mut cur_nibble,
-                                              coder_prior,
-                                              BillingDesignation::LiteralCommand(LiteralSubstate::LiteralNibbleIndex(!HTraits::IS_HIGH as u32)));
+                lbk.literal_sse.code_nibble::<ArithmeticCoder, Specialization, HTraits>(
+                    &mut cur_nibble, coder_prior, prev_nibble, lbk.btype_last, lbk.literal_adaptation[HTraits::IS_HIGH as usize], local_coder, specialization);
             }
         }
         let blendable_prob: Option<&'a mut Cdf16>;
-        if mm_opts == 2 {
+        if mm_opts == 2 && !lbk.mix_literals {
             blendable_prob = None;
         } else {
             blendable_prob = Some(nibble_prob);
@@ -517,6 +622,11 @@ impl<AllocU8:Allocator<u8>,
         let serialized_large_literal_len  = literal_len.wrapping_sub(NUM_LITERAL_LENGTH_MNEMONIC + 1);
         let lllen: u8 = (core::mem::size_of_val(&serialized_large_literal_len) as u32 * 8 - serialized_large_literal_len.leading_zeros()) as u8;
         let _ltype = superstate.bk.get_literal_block_type();
+        if let LiteralSubstate::Begin = self.state {
+            if let ThreadContext::MainThread(ref mut ctx) = superstate.thread_ctx {
+                ctx.start_literal_match();
+            }
+        }
         let (mut lit_coder, mut m8, mut lbk, mut lit_high_priors, mut lit_low_priors) = if LinearInputBytes::ISOLATED {
             (None, None, None, None, None)
         } else {
@@ -660,6 +770,9 @@ impl<AllocU8:Allocator<u8>,
                     }
                 },
                 LiteralSubstate::LiteralNibbleIndexWithECDF(nibble_index) => {
+                    if LinearInputBytes::REMOTE_LITERAL_CODER {
+                        return DivansResult::Failure(ErrMsg::LiteralProbabilitiesNeedLocalCoder);
+                    }
                     superstate.bk.last_llen = self.lc.data.slice().len() as u32;
                     let byte_index = (nibble_index as usize) >> 1;
                     let high_nibble = (nibble_index & 1) == 0;
@@ -699,6 +812,14 @@ impl<AllocU8:Allocator<u8>,
                 LiteralSubstate::LiteralNibbleLowerHalf(_) |
                 LiteralSubstate::LiteralNibbleIndex(_) |
                 LiteralSubstate::SafeLiteralNibbleIndex(_) => {
+                    if LinearInputBytes::REMOTE_LITERAL_CODER {
+                        // the bytes travel to the literal coder with the command
+                        for (index, byte) in self.lc.data.slice_mut().iter_mut().enumerate() {
+                            *byte = superstate.specialization.get_literal_byte(in_cmd, index);
+                        }
+                        self.state = LiteralSubstate::FullyDecoded;
+                        return DivansResult::Success;
+                    }
                     match lit_coder {
                         None => { // we're on a worker thread
                             self.state = LiteralSubstate::FullyDecoded;
@@ -740,3 +861,42 @@ impl<AllocU8:Allocator<u8>,
         res
     }
 }
+
+#[cfg(test)]
+mod test {
+    use ::interface;
+    use super::{hashed_context_index, order_hash};
+
+    #[test]
+    fn test_literal_hash_size() {
+        let mut opts = interface::DivansCompressorOptions::default();
+        opts.literal_hash_size = Some(4);
+        let features = opts.stream_features();
+        assert_eq!(features.literal_hash_size, interface::MIN_LITERAL_HASH_SIZE);
+        assert!(features.literal_mixer);
+        assert_eq!(interface::StreamFeatures::from_flags(features.to_flags()), Ok(features));
+        let mut oversized = features;
+        oversized.literal_hash_size = interface::MAX_LITERAL_HASH_SIZE + 1;
+        assert_eq!(interface::StreamFeatures::from_flags(oversized.to_flags()),
+                   Err(::ErrMsg::UnsupportedLiteralHashSize(interface::MAX_LITERAL_HASH_SIZE + 1)));
+        let mut unmixed = features;
+        unmixed.literal_mixer = false;
+        assert_eq!(interface::StreamFeatures::from_flags(unmixed.to_flags()),
+                   Err(::ErrMsg::UnsupportedLiteralHashSize(interface::MIN_LITERAL_HASH_SIZE)));
+
+        // each order sees only its own bytes, and the two orders of the same bytes hash apart
+        let last_8_literals = u64::from_le_bytes(*b"12345xyz");
+        let other_4th_byte = u64::from_le_bytes(*b"1234?xyz");
+        assert_eq!(order_hash(last_8_literals, 2), order_hash(other_4th_byte, 2));
+        assert_eq!(order_hash(last_8_literals, 3), order_hash(other_4th_byte, 3));
+        assert!(order_hash(last_8_literals, 2) != order_hash(last_8_literals, 3));
+        assert!(order_hash(last_8_literals, 3) != order_hash(u64::from_le_bytes(*b"12345?yz"), 3));
+        for log_size in [interface::MIN_LITERAL_HASH_SIZE, interface::MAX_LITERAL_HASH_SIZE].iter() {
+            for nibble_slot in 0..17 {
+                for order in [2, 3].iter() {
+                    assert!(hashed_context_index(order_hash(last_8_literals, *order), nibble_slot, *log_size) < 1 << *log_size);
+                }
+            }
+        }
+    }
+}
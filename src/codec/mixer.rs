@@ -0,0 +1,371 @@
+use core;
+use ::probability::{CDF16, Prob, BLEND_FIXED_POINT_PRECISION};
+use super::weights::adjust_weight;
+
+// the most literal predictors a stream may mix for a single nibble
+pub const MAX_LITERAL_MODELS: usize = 8;
+// one weight set per nibble half, per class (top three bits) of the previous byte and
+// per whether the match model has a prediction
+pub const NUM_MIXER_CONTEXTS: usize = 32;
+pub const LOG_NUM_WORD_CONTEXTS: u32 = 10;
+pub const NUM_WORD_CONTEXTS: usize = 1 << LOG_NUM_WORD_CONTEXTS;
+// how far into a literal the match model follows the last copy
+pub const MAX_MATCH_BYTES: usize = 64;
+pub const NUM_MATCH_STATES: usize = 4;
+// the nibble index of the match priors used when the match model has no prediction
+pub const NO_MATCH_NIBBLE: usize = 16;
+
+// Blends any number of literal predictions with weights learned online, generalizing Weights
+// from two models to MAX_LITERAL_MODELS. The caller supplies the models in the same order every
+// time; a weight set is selected with mixer_context so that, for instance, the previous byte being
+// a space may favor different models than it being a letter.
+pub struct LiteralMixer {
+    weights: [[i32; MAX_LITERAL_MODELS]; NUM_MIXER_CONTEXTS],
+}
+
+impl Default for LiteralMixer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl LiteralMixer {
+    pub fn new() -> Self {
+        LiteralMixer {
+            weights: [[1; MAX_LITERAL_MODELS]; NUM_MIXER_CONTEXTS],
+        }
+    }
+    // the weighted average of the models: each is folded into the running mixture with the
+    // share of the weight it adds to the total
+    #[inline(always)]
+    pub fn mix<Cdf16:CDF16>(&self, context: usize, models: &[Cdf16]) -> Cdf16 {
+        debug_assert!(models.len() <= MAX_LITERAL_MODELS);
+        let weights = &self.weights[context];
+        let mut total = i64::from(weights[0]);
+        let mut mixed = models[0];
+        for (model, weight) in models.iter().zip(weights.iter()).skip(1) {
+            let new_total = total + i64::from(*weight);
+            mixed = mixed.average(model, ((total << BLEND_FIXED_POINT_PRECISION) / new_total) as i32);
+            total = new_total;
+        }
+        mixed
+    }
+    // model_probs holds the frequency each model gave the coded nibble, weighted_prob the one the mixture gave it
+    #[inline(always)]
+    pub fn update(&mut self, context: usize, model_probs: &[Prob], weighted_prob: Prob) {
+        let weights = &mut self.weights[context];
+        if weights.iter().any(|w| (w & 0x7f000000) != 0) {
+            fix_weights(weights);
+        }
+        for (weight, model_prob) in weights.iter_mut().zip(model_probs.iter()) {
+            *weight = adjust_weight(*model_prob, weighted_prob, *weight);
+        }
+    }
+}
+
+#[cold]
+fn fix_weights(weights: &mut [i32; MAX_LITERAL_MODELS]) {
+    let min_leading_zeros = weights.iter().fold(32, |acc, w| core::cmp::min(acc, w.leading_zeros()));
+    let ilog = 32 - min_leading_zeros;
+    let max_log = 24;
+    if ilog >= max_log {
+        for weight in weights.iter_mut() {
+            *weight = core::cmp::max(1, *weight >> (ilog - max_log));
+        }
+    }
+}
+
+#[inline(always)]
+pub fn mixer_context(is_high: bool, prev_byte: u8, match_nibble: usize) -> usize {
+    ((is_high as usize) << 4) | (((match_nibble != NO_MATCH_NIBBLE) as usize) << 3) | usize::from(prev_byte >> 5)
+}
+
+#[inline(always)]
+fn is_word_byte(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || b >= 0x80
+}
+
+// hashes the word the next byte continues, read back from the most recent literals, so the
+// predictor sees both the letters typed so far and their count; 0 right after a non-word byte
+#[inline(always)]
+pub fn word_context(last_8_literals: u64) -> usize {
+    let mut hash = 0u32;
+    let mut literals = last_8_literals;
+    for _ in 0..8 {
+        let b = (literals >> 0x38) as u8;
+        if !is_word_byte(b) {
+            break;
+        }
+        hash = (hash ^ u32::from(b)).wrapping_add(1).wrapping_mul(0x9e37_79b1);
+        literals <<= 8;
+    }
+    (hash >> (32 - LOG_NUM_WORD_CONTEXTS)) as usize
+}
+
+// Predicts that a literal continues the last copy: its bytes are expected to be the ones that
+// followed the copy source. A literal shorter than the copy distance reads them from the window;
+// a longer one starts repeating its own bytes, which push adds to the history as they are coded.
+pub struct LiteralMatch {
+    history: [u8; MAX_MATCH_BYTES],
+    // the copy distance, or the length of history when that is shorter; 0 for no prediction
+    period: usize,
+    // position of the next byte in the literal
+    index: usize,
+    // 0 after a wrong prediction, 1 at the start of a literal, then one more per correct prediction
+    run: u8,
+}
+
+impl Default for LiteralMatch {
+    fn default() -> Self {
+        LiteralMatch {
+            history: [0; MAX_MATCH_BYTES],
+            period: 0,
+            index: 0,
+            run: 0,
+        }
+    }
+}
+
+impl LiteralMatch {
+    // history holds the bytes after the copy source, as returned by DivansRecodeState::match_history
+    pub fn start(&mut self, history: &[u8]) {
+        self.history[..history.len()].clone_from_slice(history);
+        self.period = history.len();
+        self.index = 0;
+        self.run = 1;
+    }
+    #[inline(always)]
+    fn predicted_byte(&self) -> Option<u8> {
+        if self.period != 0 && self.index < MAX_MATCH_BYTES {
+            Some(self.history[self.index])
+        } else {
+            None
+        }
+    }
+    // the nibble the next byte is expected to have; the low nibble is only predicted when the
+    // high nibble coded just before (high_nibble) was the expected one
+    #[inline(always)]
+    pub fn expected_nibble(&self, is_high: bool, high_nibble: u8) -> usize {
+        match self.predicted_byte() {
+            Some(b) if is_high => usize::from(b >> 4),
+            Some(b) if b >> 4 == high_nibble => usize::from(b & 0xf),
+            _ => NO_MATCH_NIBBLE,
+        }
+    }
+    #[inline(always)]
+    pub fn state(&self) -> usize {
+        match self.run {
+            0 => 0,
+            1 => 1,
+            2..=4 => 2,
+            _ => 3,
+        }
+    }
+    #[inline(always)]
+    pub fn push(&mut self, b: u8) {
+        if let Some(predicted) = self.predicted_byte() {
+            self.run = if predicted == b { self.run.saturating_add(1) } else { 0 };
+            if self.index + self.period < MAX_MATCH_BYTES {
+                self.history[self.index + self.period] = b;
+            }
+        }
+        self.index = self.index.wrapping_add(1);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use ::interface;
+    use ::alloc::HeapAlloc;
+    use ::probability::{BaseCDF, CDF16, Prob, Speed};
+    use ::interface::{Command, CopyCommand, DivansOutputResult, FeatureFlagSliceType, LiteralCommand, NewWithAllocator};
+    use ::brotli::InputReference;
+    use ::runtime_coder::RuntimeDecoder;
+    use ::codec::MainThreadContext;
+    use alloc::SliceWrapper;
+    use core;
+    use super::{fix_weights, mixer_context, LiteralMatch, LiteralMixer, MAX_LITERAL_MODELS, NO_MATCH_NIBBLE, NUM_MIXER_CONTEXTS};
+
+    // a model that has seen nothing but nibble
+    fn trained_model(nibble: u8) -> ::DefaultCDF16 {
+        let mut model = ::DefaultCDF16::default();
+        for _ in 0..64 {
+            model.blend(nibble, Speed::MED);
+        }
+        model
+    }
+    fn freq(model: &::DefaultCDF16, nibble: u8) -> Prob {
+        model.sym_to_start_and_freq(nibble).range.freq
+    }
+    #[test]
+    fn test_literal_mixer_mix() {
+        let models = [trained_model(3), trained_model(9)];
+        let mut mixer = LiteralMixer::new();
+        // equal weights give each model half the mixture
+        let even = mixer.mix(5, &models[..]);
+        assert!(freq(&even, 3) < freq(&models[0], 3));
+        assert!(freq(&even, 3) > freq(&models[1], 3));
+        assert!(i32::from(freq(&even, 3)) - i32::from(freq(&even, 9)) <= 1);
+        mixer.weights[5][0] = 3;
+        let skewed = mixer.mix(5, &models[..]);
+        assert!(freq(&skewed, 3) > freq(&even, 3));
+        assert!(freq(&skewed, 9) < freq(&even, 9));
+        // a lone model passes through whatever its weight
+        assert_eq!(freq(&mixer.mix(5, &models[1..]), 9), freq(&models[1], 9));
+    }
+    #[test]
+    fn test_literal_mixer_update() {
+        let models = [trained_model(3), trained_model(9)];
+        let mut mixer = LiteralMixer::new();
+        let before = freq(&mixer.mix(5, &models[..]), 3);
+        for _ in 0..16 {
+            let weighted_prob = freq(&mixer.mix(5, &models[..]), 3);
+            mixer.update(5, &[freq(&models[0], 3), freq(&models[1], 3)], weighted_prob);
+        }
+        // the weight moves to the model that kept predicting the coded nibble
+        assert!(mixer.weights[5][0] > mixer.weights[5][1], "{:?}", mixer.weights[5]);
+        assert!(freq(&mixer.mix(5, &models[..]), 3) > before);
+        // and only in the context that was updated
+        for (context, weights) in mixer.weights.iter().enumerate() {
+            if context != 5 {
+                assert_eq!(*weights, [1; MAX_LITERAL_MODELS]);
+            }
+        }
+    }
+    #[test]
+    fn test_literal_mixer_fix_weights() {
+        let mut weights = [1i32; MAX_LITERAL_MODELS];
+        weights[0] = 0x7f00_0000;
+        weights[1] = 0x0100_0000;
+        weights[2] = 0x10;
+        fix_weights(&mut weights);
+        // shifted until the largest fits in 24 bits, which keeps the ratio of the large weights
+        assert_eq!(weights[0], 0x00fe_0000);
+        assert_eq!(weights[1], 0x0002_0000);
+        // while small ones stay at 1 rather than dropping out of the mixture
+        assert_eq!(weights[2], 1);
+        assert!(weights.iter().all(|w| *w >= 1 && *w < 1 << 24), "{:?}", weights);
+        // weights that already fit are left alone
+        let small = [0x00ff_ffff; MAX_LITERAL_MODELS];
+        let mut unchanged = small;
+        fix_weights(&mut unchanged);
+        assert_eq!(unchanged, small);
+        // update renormalizes a weight set that has grown too large before adjusting it
+        let mut mixer = LiteralMixer::new();
+        mixer.weights[2] = weights;
+        mixer.weights[2][0] = 0x7f00_0000;
+        let models = [trained_model(3), trained_model(9)];
+        let weighted_prob = freq(&mixer.mix(2, &models[..]), 3);
+        mixer.update(2, &[freq(&models[0], 3), freq(&models[1], 3)], weighted_prob);
+        assert!(mixer.weights[2].iter().all(|w| *w < 1 << 25), "{:?}", mixer.weights[2]);
+    }
+    #[test]
+    fn test_mixer_context() {
+        let mut seen = [false; NUM_MIXER_CONTEXTS];
+        for is_high in [false, true].iter() {
+            for match_nibble in [0, NO_MATCH_NIBBLE].iter() {
+                for prev_byte in (0..8u8).map(|class| class << 5) {
+                    let context = mixer_context(*is_high, prev_byte, *match_nibble);
+                    assert!(!seen[context]);
+                    seen[context] = true;
+                }
+            }
+        }
+        // every weight set is reachable
+        assert!(seen.iter().all(|x| *x));
+        // bytes in the same class of 32 share a weight set, whatever match nibble is expected
+        assert_eq!(mixer_context(true, b'a', 3), mixer_context(true, b'z', 12));
+        assert!(mixer_context(true, b'a', 3) != mixer_context(true, b' ', 3));
+        assert!(mixer_context(true, b'a', 3) != mixer_context(false, b'a', 3));
+        assert!(mixer_context(true, b'a', 3) != mixer_context(true, b'a', NO_MATCH_NIBBLE));
+    }
+    #[test]
+    fn test_literal_match() {
+        let mut literal_match = LiteralMatch::default();
+        assert_eq!(literal_match.expected_nibble(true, 0), NO_MATCH_NIBBLE);
+        literal_match.start(b"ab");
+        assert_eq!(literal_match.state(), 1);
+        assert_eq!(literal_match.expected_nibble(true, 0), usize::from(b'a' >> 4));
+        assert_eq!(literal_match.expected_nibble(false, b'a' >> 4), usize::from(b'a' & 0xf));
+        // the low nibble is only predicted after the expected high nibble
+        assert_eq!(literal_match.expected_nibble(false, (b'a' >> 4) + 1), NO_MATCH_NIBBLE);
+        literal_match.push(b'a');
+        literal_match.push(b'b');
+        assert_eq!(literal_match.state(), 2);
+        // past the copied bytes the literal is expected to repeat itself
+        assert_eq!(literal_match.expected_nibble(true, 0), usize::from(b'a' >> 4));
+        literal_match.push(b'z');
+        assert_eq!(literal_match.state(), 0);
+    }
+    // a main thread context with the literal mixer whose window holds "abcdefgh" then a copy of
+    // "abcd" from 8 bytes back, so the bytes after the copy source are "efghabcd"
+    fn codec_context_tst(match_literals: bool) -> MainThreadContext<::DefaultCDF16, HeapAlloc<u8>, HeapAlloc<::DefaultCDF16>, RuntimeDecoder> {
+        let mut m8 = HeapAlloc::<u8>::new(0);
+        let lit_coder = RuntimeDecoder::new(&mut m8);
+        let mut ctx = MainThreadContext::new(m8, HeapAlloc::<::DefaultCDF16>::new(::DefaultCDF16::default()), lit_coder, 16);
+        ctx.set_literal_mixer(true);
+        ctx.set_literal_match(match_literals);
+        let commands = [
+            Command::Literal(LiteralCommand {
+                data: InputReference { data: &b"abcdefgh"[..], orig_offset: 0 },
+                prob: FeatureFlagSliceType::default(),
+                high_entropy: false,
+            }),
+            Command::Copy(CopyCommand { distance: 8, num_bytes: 4 }),
+        ];
+        let mut output = [0u8; 16];
+        let mut output_offset = 0usize;
+        for cmd in commands.iter() {
+            match ctx.recoder.encode_cmd(cmd, &mut output[..], &mut output_offset) {
+                DivansOutputResult::Success => {},
+                _ => panic!("the window did not take the command"),
+            }
+        }
+        match ctx.recoder.flush(&mut output[..], &mut output_offset) {
+            DivansOutputResult::Success => {},
+            _ => panic!("the window did not flush"),
+        }
+        assert_eq!(&output[..output_offset], b"abcdefghabcd");
+        ctx
+    }
+    #[test]
+    fn test_literal_match_in_codec() {
+        // without its feature bit the mixer neither allocates nor starts the match model
+        let mut unmatched = codec_context_tst(false);
+        assert!(unmatched.lbk.mix_literals);
+        assert!(unmatched.lbk.lit_match_priors.priors.slice().is_empty());
+        unmatched.start_literal_match();
+        assert_eq!(unmatched.lbk.literal_match.expected_nibble(true, 0), NO_MATCH_NIBBLE);
+        assert_eq!(unmatched.lbk.literal_match.state(), 0);
+
+        let mut ctx = codec_context_tst(true);
+        assert_eq!(ctx.lbk.lit_match_priors.priors.slice().len(),
+                   ::codec::literal_match_allocation_size::<::DefaultCDF16, HeapAlloc<::DefaultCDF16>>() / core::mem::size_of::<::DefaultCDF16>());
+        ctx.start_literal_match();
+        assert_eq!(ctx.lbk.literal_match.state(), 1);
+        // the next literal is expected to go on with the bytes after the copy source
+        for (index, b) in b"efghabcd".iter().enumerate() {
+            assert_eq!(ctx.lbk.literal_match.expected_nibble(true, 0), usize::from(b >> 4));
+            assert_eq!(ctx.lbk.literal_match.expected_nibble(false, b >> 4), usize::from(b & 0xf));
+            ctx.lbk.push_literal_byte(*b);
+            // two to four correct predictions in a row, then five or more
+            assert_eq!(ctx.lbk.literal_match.state(), if index < 3 {2} else {3});
+        }
+        // then to repeat itself, until a byte differs
+        assert_eq!(ctx.lbk.literal_match.expected_nibble(true, 0), usize::from(b'e' >> 4));
+        ctx.lbk.push_literal_byte(b'z');
+        assert_eq!(ctx.lbk.literal_match.state(), 0);
+        assert_eq!(ctx.lbk.literal_match.expected_nibble(true, 0), usize::from(b'f' >> 4));
+
+        // the option turns on the mixer the match model feeds, and the header rejects it without one
+        let mut opts = interface::DivansCompressorOptions::default();
+        opts.literal_match = true;
+        let features = opts.stream_features();
+        assert!(features.literal_mixer && features.literal_match);
+        assert_eq!(interface::StreamFeatures::from_flags(features.to_flags()), Ok(features));
+        let mut unmixed = features;
+        unmixed.literal_mixer = false;
+        assert_eq!(interface::StreamFeatures::from_flags(unmixed.to_flags()),
+                   Err(::ErrMsg::LiteralMatchWithoutMixer));
+    }
+}
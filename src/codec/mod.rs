@@ -30,6 +30,10 @@ use super::interface::{
     Nop,
 };
 pub mod weights;
+pub mod mixer;
+pub mod sse;
+pub mod snapshot;
+pub use self::snapshot::{PriorSnapshotInfo, parse_prior_snapshot};
 pub mod specializations;
 pub mod crc32;
 pub mod crc32_table;
@@ -39,7 +43,7 @@ use self::specializations::{
     CodecTraits,
 };
 mod interface;
-use threading::{ThreadToMain, StaticCommand};
+use threading::{ThreadToMain, StaticCommand, LiteralCoderOp};
 use ::slice_util::AllocatedMemoryPrefix;
 pub use self::interface::{
     ThreadContext,
@@ -49,11 +53,18 @@ pub use self::interface::{
     CrossCommandBookKeeping,
     NUM_ARITHMETIC_CODERS,
     CMD_CODER,
+    LIT_CODER,
     CommandArray,
     EmptyCommandArray,
     CommandSliceArray,
     MainThreadContext,
     get_distance_from_mnemonic_code,
+    CodecSnapshot,
+    model_allocation_size,
+    literal_mixer_allocation_size,
+    literal_match_allocation_size,
+    literal_sse_allocation_size,
+    literal_hash_allocation_size,
 };
 use super::interface::{
     ArithmeticEncoderOrDecoder,
@@ -71,6 +82,7 @@ pub mod context_map;
 pub mod block_type;
 pub mod priors;
 pub mod decoder;
+pub mod encoder;
 pub use self::decoder::{
     DivansDecoderCodec,
     SubDigest,
@@ -87,7 +99,7 @@ macro_rules! println_stderr(
     } }
 );
 */
-use super::probability::{CDF16, Speed};
+use super::probability::{CDF16, CDFFlavor, Speed};
 
 //#[cfg(feature="billing")]
 //use std::io::Write;
@@ -110,8 +122,8 @@ use super::probability::{CDF16, Speed};
 
 
 
-#[derive(Clone,Copy,Debug)]
-enum EncodeOrDecodeState {
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub enum EncodeOrDecodeState {
     Begin,
     Literal,
     Dict,
@@ -127,6 +139,44 @@ enum EncodeOrDecodeState {
     CoderBufferDrain,
     MuxDrain,
     WriteChecksum(u8),
+    SyncCoders, // coded the sync node: every coder ends its chunk but keeps its model
+    SyncOutput,
+    SyncCoderDrain,
+    SyncMuxDrain,
+}
+
+impl core::fmt::Display for EncodeOrDecodeState {
+    fn fmt(&self, f:&mut core::fmt::Formatter) -> core::result::Result<(), core::fmt::Error> {
+        match *self {
+            EncodeOrDecodeState::Begin => write!(f, "reading the type of the next command"),
+            EncodeOrDecodeState::Literal => write!(f, "decoding a literal command"),
+            EncodeOrDecodeState::Dict => write!(f, "decoding a dictionary command"),
+            EncodeOrDecodeState::Copy => write!(f, "decoding a copy command"),
+            EncodeOrDecodeState::BlockSwitchLiteral => write!(f, "decoding a literal block switch"),
+            EncodeOrDecodeState::BlockSwitchCommand => write!(f, "decoding a command block switch"),
+            EncodeOrDecodeState::BlockSwitchDistance => write!(f, "decoding a distance block switch"),
+            EncodeOrDecodeState::PredictionMode => write!(f, "decoding a prediction mode and context map"),
+            EncodeOrDecodeState::PopulateRingBuffer => write!(f, "copying a command into the output window"),
+            EncodeOrDecodeState::DivansSuccess => write!(f, "finished with the stream"),
+            EncodeOrDecodeState::EncodedShutdownNode => write!(f, "closing the stream after its end marker"),
+            EncodeOrDecodeState::ShutdownCoder(index) => write!(f, "closing entropy coder {}", index),
+            EncodeOrDecodeState::CoderBufferDrain => write!(f, "draining the entropy coder buffers"),
+            EncodeOrDecodeState::MuxDrain => write!(f, "draining the stream multiplexer"),
+            EncodeOrDecodeState::WriteChecksum(count) => write!(f, "at byte {} of the checksum", count),
+            EncodeOrDecodeState::SyncCoders => write!(f, "ending the coder chunks at a sync point"),
+            EncodeOrDecodeState::SyncOutput => write!(f, "emitting the data before a sync point"),
+            EncodeOrDecodeState::SyncCoderDrain => write!(f, "draining the entropy coders at a sync point"),
+            EncodeOrDecodeState::SyncMuxDrain => write!(f, "draining the multiplexer at a sync point"),
+        }
+    }
+}
+
+// what the command nibble at the current position stands for
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub enum CommandNode {
+    Command,
+    SyncPoint,
+    End,
 }
 
 const CHECKSUM_LENGTH: usize = 8;
@@ -141,10 +191,12 @@ impl Default for EncodeOrDecodeState {
 
 
 pub fn command_type_to_nibble<SliceType:SliceWrapper<u8>>(cmd:&Command<SliceType>,
-                                                          is_end: bool) -> u8 {
+                                                          node: CommandNode) -> u8 {
 
-    if is_end {
-        return 0xf;
+    match node {
+        CommandNode::End => return 0xf,
+        CommandNode::SyncPoint => return 0x8,
+        CommandNode::Command => {},
     }
     match *cmd {
         Command::Copy(_) => 0x1,
@@ -183,6 +235,8 @@ pub struct DivansCodec<ArithmeticCoder:ArithmeticEncoderOrDecoder,
     crc: SubDigest,
     frozen_checksum: Option<u64>,
     skip_checksum: bool,
+    // the header announced sync points, so the 0x8 command nibble is allowed
+    sync_points: bool,
 }
 
 pub enum OneCommandReturn {
@@ -255,6 +309,7 @@ impl<AllocU8: Allocator<u8>,
             crc: default_crc(),
             frozen_checksum: None,
             skip_checksum:skip_checksum,
+            sync_points: false,
         };
         match ret.cross_command_state.thread_ctx.lbk() {
             Some(ref book_keeping) => ret.codec_traits = construct_codec_trait_from_bookkeeping(book_keeping),
@@ -313,6 +368,9 @@ impl<AllocU8: Allocator<u8>,
                              ArithmeticCoder,
                              Mux<AllocU8>>::new(main_thread_context, mcommand, self.crc.clone(), skip_checksum)
     }
+    pub fn state(&self) -> EncodeOrDecodeState {
+        self.state
+    }
     pub fn demuxer(&mut self) -> &mut LinearInputBytes{
         &mut self.cross_command_state.demuxer
     }
@@ -330,7 +388,7 @@ impl<AllocU8: Allocator<u8>,
         self.cross_command_state.free_ref()
     }
     #[inline(always)]
-    fn update_command_state_from_nibble(&mut self, command_type_code:u8, is_end: bool) -> DivansResult{
+    fn update_command_state_from_nibble(&mut self, command_type_code:u8, node: CommandNode) -> DivansResult{
         match command_type_code {
             1 => {
                 self.state_copy = copy::CopyState::begin();
@@ -365,7 +423,13 @@ impl<AllocU8: Allocator<u8>,
                 self.state_prediction_mode.state = context_map::PredictionModeSubstate::Begin;                
                 self.state = EncodeOrDecodeState::PredictionMode;
             },
-            0xf => if is_end {
+            8 => {
+                if !self.sync_points {
+                    return DivansResult::Failure(ErrMsg::CommandCodeOutOfBounds(command_type_code));
+                }
+                self.state = EncodeOrDecodeState::SyncCoders;
+            },
+            0xf => if node == CommandNode::End {
                 self.state = EncodeOrDecodeState::DivansSuccess; // encoder flows through this path
             } else {
                 self.state = EncodeOrDecodeState::WriteChecksum(0)
@@ -402,6 +466,37 @@ impl<AllocU8: Allocator<u8>,
     pub fn specialization(&mut self) -> &mut Specialization{
         &mut self.cross_command_state.specialization
     }
+    // must be called before any command is coded
+    pub fn set_cdf_flavor(&mut self, flavor: CDFFlavor) {
+        self.cross_command_state.set_cdf_flavor(flavor);
+    }
+    // must be called before any command is coded
+    pub fn set_literal_mixer(&mut self, enable: bool) {
+        self.cross_command_state.set_literal_mixer(enable);
+    }
+    // must be called before any command is coded
+    pub fn set_literal_match(&mut self, enable: bool) {
+        self.cross_command_state.set_literal_match(enable);
+    }
+    // must be called before any command is coded
+    pub fn set_literal_sse(&mut self, enable: bool) {
+        self.cross_command_state.set_literal_sse(enable);
+    }
+    // must be called before any command is coded
+    pub fn set_literal_hash_size(&mut self, log_size: u8) {
+        self.cross_command_state.set_literal_hash_size(log_size);
+    }
+    // must be called before any command is coded
+    pub fn set_sync_points(&mut self, enable: bool) {
+        self.sync_points = enable;
+    }
+    // must be called before any command is coded, after the features from the header are set
+    pub fn load_prior_snapshot(&mut self, prior_snapshot: &[u8]) -> Result<PriorSnapshotInfo, ErrMsg> {
+        self.cross_command_state.load_prior_snapshot(prior_snapshot)
+    }
+    pub fn write_prior_snapshot<Sink:FnMut(&[u8])>(&self, sink: &mut Sink) -> Result<PriorSnapshotInfo, ErrMsg> {
+        self.cross_command_state.write_prior_snapshot(sink)
+    }
     #[inline(always)]
     pub fn get_crc(&mut self) -> &mut SubDigest {
         &mut self.crc
@@ -421,6 +516,137 @@ impl<AllocU8: Allocator<u8>,
         }
         ret
     }
+    // Ends every chunk so the receiver can decode all commands coded so far, but unlike flush
+    // the stream stays open and the models keep their state.
+    pub fn sync_flush(&mut self,
+                      output_bytes: &mut [u8],
+                      output_bytes_offset: &mut usize) -> DivansOutputResult{
+        if !self.sync_points {
+            return DivansOutputResult::Failure(ErrMsg::SyncPointsNotEnabled);
+        }
+        let adjusted_output_bytes = output_bytes.split_at_mut(*output_bytes_offset).1;
+        let mut adjusted_output_bytes_offset = 0usize;
+        let ret = self.internal_sync_flush(adjusted_output_bytes, &mut adjusted_output_bytes_offset);
+        *output_bytes_offset += adjusted_output_bytes_offset;
+        if self.frozen_checksum.is_none() && !Specialization::IS_DECODING_FILE {
+            self.crc.write(adjusted_output_bytes.split_at(adjusted_output_bytes_offset).0);
+        }
+        ret
+    }
+    // waits for a literal coder on another thread and writes out everything it has coded so far
+    pub fn drain_remote_coders(&mut self,
+                               output_bytes: &mut [u8],
+                               output_bytes_offset: &mut usize) -> DivansOutputResult {
+        let adjusted_output_bytes = output_bytes.split_at_mut(*output_bytes_offset).1;
+        let mut adjusted_output_bytes_offset = 0usize;
+        let ret = self.cross_command_state.muxer.drain_remote_coders(adjusted_output_bytes,
+                                                                     &mut adjusted_output_bytes_offset);
+        *output_bytes_offset += adjusted_output_bytes_offset;
+        if self.frozen_checksum.is_none() && !Specialization::IS_DECODING_FILE {
+            self.crc.write(adjusted_output_bytes.split_at(adjusted_output_bytes_offset).0);
+        }
+        ret
+    }
+    fn drain_lit_coder(&mut self,
+                       output_bytes: &mut [u8],
+                       output_bytes_offset: &mut usize) -> DivansResult {
+        if LinearInputBytes::REMOTE_LITERAL_CODER {
+            match self.cross_command_state.demuxer.push_literal_coder_op(LiteralCoderOp::Drain) {
+                DivansOutputResult::Success => {},
+                res => return DivansResult::from(res),
+            }
+            return DivansResult::from(self.cross_command_state.muxer.drain_remote_coders(output_bytes, output_bytes_offset));
+        }
+        self.cross_command_state.drain_or_fill_internal_buffer_lit(output_bytes, output_bytes_offset)
+    }
+    fn drain_coders(&mut self,
+                    output_bytes: &mut [u8],
+                    output_bytes_offset: &mut usize) -> DivansOutputResult {
+        for index in 0..NUM_ARITHMETIC_CODERS {
+            let ret = if index == CMD_CODER {
+                self.cross_command_state.drain_or_fill_internal_buffer_cmd(output_bytes, output_bytes_offset)
+            } else {
+                self.drain_lit_coder(output_bytes, output_bytes_offset)
+            };
+            match ret {
+                DivansResult::Success => {},
+                DivansResult::NeedsMoreInput => return DivansOutputResult::Failure(ErrMsg::DrainOrFillNeedsInput(2)),
+                DivansResult::NeedsMoreOutput => return DivansOutputResult::NeedsMoreOutput,
+                DivansResult::Failure(m) => return DivansOutputResult::Failure(m),
+            }
+        }
+        DivansOutputResult::Success
+    }
+    fn internal_sync_flush(&mut self,
+                           output_bytes: &mut [u8],
+                           output_bytes_offset: &mut usize) -> DivansOutputResult{
+        let nop = Command::<AllocU8::AllocatedMemory>::nop();
+        loop {
+            match self.state {
+                EncodeOrDecodeState::Begin => {
+                    let mut unused = 0usize;
+                    let mut unused = ReadableBytes{data:&[], read_offset: &mut unused};
+                    match self.encode_or_decode_one_command(&mut unused,
+                                                            output_bytes,
+                                                            output_bytes_offset,
+                                                            &nop,
+                                                            &specializations::DEFAULT_TRAIT,
+                                                            CommandNode::SyncPoint) {
+                        CodecTraitResult::Res(OneCommandReturn::BufferExhausted(res)) => match res {
+                            DivansResult::Success => {},
+                            DivansResult::NeedsMoreInput => return DivansOutputResult::Failure(ErrMsg::EncodeOneCommandNeedsInput),
+                            DivansResult::NeedsMoreOutput => return DivansOutputResult::NeedsMoreOutput,
+                            DivansResult::Failure(m) => return DivansOutputResult::Failure(m),
+                        },
+                        _ => return DivansOutputResult::Failure(ErrMsg::UnintendedCodecState(5)),
+                    }
+                },
+                EncodeOrDecodeState::SyncCoders => {
+                    match self.drain_coders(output_bytes, output_bytes_offset) {
+                        DivansOutputResult::Success => {},
+                        need_something => return need_something,
+                    }
+                    for index in 0..NUM_ARITHMETIC_CODERS {
+                        match if index == LIT_CODER && LinearInputBytes::REMOTE_LITERAL_CODER {
+                            DivansResult::from(self.cross_command_state.demuxer.push_literal_coder_op(LiteralCoderOp::Sync))
+                        } else {
+                            self.coder_mut(index as StreamID).sync()
+                        } {
+                            DivansResult::Success => {},
+                            DivansResult::Failure(m) => return DivansOutputResult::Failure(m),
+                            _ => return DivansOutputResult::Failure(ErrMsg::UnintendedCodecState(6)),
+                        }
+                    }
+                    self.state = EncodeOrDecodeState::SyncCoderDrain;
+                },
+                EncodeOrDecodeState::SyncCoderDrain => {
+                    match self.drain_coders(output_bytes, output_bytes_offset) {
+                        DivansOutputResult::Success => {},
+                        need_something => return need_something,
+                    }
+                    self.state = EncodeOrDecodeState::SyncMuxDrain;
+                },
+                EncodeOrDecodeState::SyncMuxDrain => {
+                    while !self.cross_command_state.muxer.wrote_all_data() {
+                        let output_loc = output_bytes.split_at_mut(*output_bytes_offset).1;
+                        if output_loc.is_empty() {
+                            return DivansOutputResult::NeedsMoreOutput;
+                        }
+                        *output_bytes_offset += self.cross_command_state.muxer.sync_flush(output_loc);
+                    }
+                    // a remote literal coder that failed is only reported here
+                    match self.cross_command_state.muxer.drain_remote_coders(output_bytes, output_bytes_offset) {
+                        DivansOutputResult::Success => {},
+                        need_something => return need_something,
+                    }
+                    self.state = EncodeOrDecodeState::Begin;
+                    return DivansOutputResult::Success;
+                },
+                // not allowed to flush if previous command was partially processed
+                _ => return DivansOutputResult::Failure(ErrMsg::NotAllowedToFlushIfPreviousCommandPartial),
+            }
+        }
+    }
     fn internal_flush(&mut self,
                  output_bytes: &mut [u8],
                  output_bytes_offset: &mut usize) -> DivansOutputResult{
@@ -435,7 +661,7 @@ impl<AllocU8: Allocator<u8>,
                                                             output_bytes_offset,
                                                             &nop,
                                                             &specializations::DEFAULT_TRAIT,
-                                                            true) {
+                                                            CommandNode::End) {
                         CodecTraitResult::Res(one_command_return) => match one_command_return {
                             OneCommandReturn::BufferExhausted(res) => {
                                 match res {
@@ -459,7 +685,7 @@ impl<AllocU8: Allocator<u8>,
                         let ret = if index == CMD_CODER {
                             self.cross_command_state.drain_or_fill_internal_buffer_cmd(output_bytes, output_bytes_offset)
                         } else {
-                            self.cross_command_state.drain_or_fill_internal_buffer_lit(output_bytes, output_bytes_offset)
+                            self.drain_lit_coder(output_bytes, output_bytes_offset)
                         };
                         match ret {
                             DivansResult::Success => if index + 1 == NUM_ARITHMETIC_CODERS {
@@ -472,7 +698,11 @@ impl<AllocU8: Allocator<u8>,
                     }
                 },
                 EncodeOrDecodeState::ShutdownCoder(index) => {
-                    match self.coder_mut(index as StreamID).close() {
+                    match if index as usize == LIT_CODER && LinearInputBytes::REMOTE_LITERAL_CODER {
+                        DivansResult::from(self.cross_command_state.demuxer.push_literal_coder_op(LiteralCoderOp::Close))
+                    } else {
+                        self.coder_mut(index as StreamID).close()
+                    } {
                         DivansResult::Success => if index + 1 == NUM_ARITHMETIC_CODERS as u8 {
                             self.state = EncodeOrDecodeState::CoderBufferDrain;
                         } else {
@@ -488,7 +718,7 @@ impl<AllocU8: Allocator<u8>,
                         let ret = if index == CMD_CODER {
                             self.cross_command_state.drain_or_fill_internal_buffer_cmd(output_bytes, output_bytes_offset)
                         } else {
-                            self.cross_command_state.drain_or_fill_internal_buffer_lit(output_bytes, output_bytes_offset)
+                            self.drain_lit_coder(output_bytes, output_bytes_offset)
                         };
                         
                         match ret {
@@ -513,6 +743,10 @@ impl<AllocU8: Allocator<u8>,
                             break;
                         }
                     }
+                    match self.cross_command_state.muxer.drain_remote_coders(output_bytes, output_bytes_offset) {
+                        DivansOutputResult::Success => {},
+                        need_something => return need_something,
+                    }
                     self.state = EncodeOrDecodeState::WriteChecksum(0);
                 },
                 EncodeOrDecodeState::WriteChecksum(count) => {                    
@@ -568,6 +802,19 @@ impl<AllocU8: Allocator<u8>,
                                                           input_command_offset: &mut usize) -> DivansResult {
         let adjusted_output_bytes = output_bytes.split_at_mut(*output_bytes_offset).1;
         let mut adjusted_output_bytes_offset = 0usize;
+        if LinearInputBytes::REMOTE_LITERAL_CODER && *input_command_offset == input_commands.len() {
+            // an earlier call coded every command but ran out of room for the literals
+            let ret = self.cross_command_state.muxer.drain_remote_coders(adjusted_output_bytes,
+                                                                         &mut adjusted_output_bytes_offset);
+            *output_bytes_offset += adjusted_output_bytes_offset;
+            if self.frozen_checksum.is_none() {
+                self.crc.write(adjusted_output_bytes.split_at(adjusted_output_bytes_offset).0);
+            }
+            return match ret {
+                DivansOutputResult::Success => DivansResult::NeedsMoreInput,
+                res => DivansResult::from(res),
+            };
+        }
         if !LinearInputBytes::ISOLATED {
             if let Some(ref mut m8) = self.cross_command_state.thread_ctx.m8() {
                 let adjusted_input_bytes = input_bytes.split_at(*input_bytes_offset).1;
@@ -600,7 +847,18 @@ impl<AllocU8: Allocator<u8>,
             if let Some(update) = res.1 {
                 self.codec_traits = update;
             }
-            if let Some(result) = res.0 {
+            if let Some(mut result) = res.0 {
+                if LinearInputBytes::REMOTE_LITERAL_CODER {
+                    if let DivansResult::Success | DivansResult::NeedsMoreInput = result {
+                        // the literals of later batches may still be coding; anything left is
+                        // written before the next output or by drain_remote_coders
+                        match self.cross_command_state.muxer.drain_finished_remote_coders(adjusted_output_bytes,
+                                                                                          &mut adjusted_output_bytes_offset) {
+                            DivansOutputResult::Success => {},
+                            res => result = DivansResult::from(res),
+                        }
+                    }
+                }
                 *output_bytes_offset += adjusted_output_bytes_offset;
                 match self.frozen_checksum {
                     Some(_) => {},
@@ -627,7 +885,7 @@ impl<AllocU8: Allocator<u8>,
                                                     output_bytes_offset,
                                                     &in_cmd,
                                                     ctraits,
-                                                    false /* not end*/) {
+                                                    CommandNode::Command) {
                 CodecTraitResult::Res(one_command_return) => match one_command_return {
                     OneCommandReturn::Advance => {
                         *input_command_offset += 1;
@@ -656,7 +914,7 @@ impl<AllocU8: Allocator<u8>,
                                                          output_bytes_offset: &mut usize,
                                                          input_cmd: &Command<ISl>,
                                                          ctraits: &'static CTraits,
-                                                         is_end: bool) -> CodecTraitResult {
+                                                         node: CommandNode) -> CodecTraitResult {
         loop {
             match self.state {
                 EncodeOrDecodeState::Begin => {
@@ -664,7 +922,7 @@ impl<AllocU8: Allocator<u8>,
                         DivansResult::Success => {},
                         need_something => return CodecTraitResult::Res(OneCommandReturn::BufferExhausted(need_something)),
                     }
-                    let mut command_type_code = command_type_to_nibble(input_cmd, is_end);
+                    let mut command_type_code = command_type_to_nibble(input_cmd, node);
                     {
                         let command_type_prob = self.cross_command_state.bk.get_command_type_prob();
                         self.cross_command_state.coder.get_or_put_nibble(
@@ -675,7 +933,7 @@ impl<AllocU8: Allocator<u8>,
                             command_type_prob.blend(command_type_code, Speed::ROCKET);
                         }
                     }
-                    match self.update_command_state_from_nibble(command_type_code, is_end) {
+                    match self.update_command_state_from_nibble(command_type_code, node) {
                         DivansResult::Success => {},
                         need_something => return CodecTraitResult::Res(OneCommandReturn::BufferExhausted(need_something)),
                     }
@@ -840,6 +1098,25 @@ impl<AllocU8: Allocator<u8>,
                                  let ret = ctx.lbk.obs_prediction_mode_context_map(
                                      &self.state_prediction_mode.pm,
                                      &mut ctx.mcdf16);
+                                 if LinearInputBytes::REMOTE_LITERAL_CODER {
+                                     let mut pm = Command::PredictionMode(core::mem::replace(
+                                         &mut self.state_prediction_mode.pm,
+                                         empty_prediction_mode_context_map()));
+                                     loop {
+                                         // the encoder recoder writes to scratch space, so retrying always progresses
+                                         match self.cross_command_state.demuxer.push_cmd(&mut pm,
+                                                                                         Some(&mut ctx.m8),
+                                                                                         Some(&mut ctx.recoder),
+                                                                                         &mut self.cross_command_state.specialization,
+                                                                                         output_bytes,
+                                                                                         output_bytes_offset) {
+                                             DivansOutputResult::Success => break,
+                                             DivansOutputResult::NeedsMoreOutput => {},
+                                             DivansOutputResult::Failure(m) => return CodecTraitResult::Res(
+                                                 OneCommandReturn::BufferExhausted(DivansResult::Failure(m))),
+                                         }
+                                     }
+                                 }
                                  self.state_prediction_mode.reset(&mut ctx.m8);
                                  if let DivansOpResult::Failure(_) = ret {
                                      return CodecTraitResult::Res(OneCommandReturn::BufferExhausted(DivansResult::from(ret)));
@@ -873,7 +1150,11 @@ impl<AllocU8: Allocator<u8>,
                                     DivansResult::Failure(ErrMsg::UnintendedCodecState(0)))),
                             };
                             self.cross_command_state.bk.obs_btypel(new_block_type);
-                            match self.cross_command_state.thread_ctx.lbk() {
+                            match if LinearInputBytes::REMOTE_LITERAL_CODER {
+                                None
+                            } else {
+                                self.cross_command_state.thread_ctx.lbk()
+                            } {
                                 Some(book_keeping) => {
                                     book_keeping.obs_literal_block_switch(new_block_type);
                                     self.state = EncodeOrDecodeState::Begin;
@@ -946,6 +1227,41 @@ impl<AllocU8: Allocator<u8>,
                     // not allowed to encode additional commands after flush is invoked
                     return CodecTraitResult::Res(OneCommandReturn::BufferExhausted(DivansResult::Failure(ErrMsg::NotAllowedToEncodeAfterFlush)));
                 },
+                EncodeOrDecodeState::SyncCoders => {
+                    if !Specialization::IS_DECODING_FILE {
+                        // the encoder has to drain its coders first: sync_flush takes it from here
+                        return CodecTraitResult::Res(OneCommandReturn::BufferExhausted(DivansResult::Success));
+                    }
+                    match self.cross_command_state.coder.sync() {
+                        DivansResult::Success => {},
+                        need_something => return CodecTraitResult::Res(OneCommandReturn::BufferExhausted(need_something)),
+                    }
+                    if let ThreadContext::MainThread(ref mut ctx) = self.cross_command_state.thread_ctx {
+                        match ctx.lit_coder.sync() {
+                            DivansResult::Success => {},
+                            need_something => return CodecTraitResult::Res(OneCommandReturn::BufferExhausted(need_something)),
+                        }
+                    }
+                    self.state = EncodeOrDecodeState::SyncOutput;
+                },
+                EncodeOrDecodeState::SyncOutput => {
+                    // decoder only: hand over every byte up to the sync point without waiting for more input
+                    let ret = match self.cross_command_state.thread_ctx {
+                        ThreadContext::MainThread(ref mut main_thread_ctx) => main_thread_ctx.recoder.flush(output_bytes, output_bytes_offset),
+                        ThreadContext::Worker => self.cross_command_state.demuxer.push_sync(),
+                    };
+                    match ret {
+                        DivansOutputResult::Success => {
+                            self.state = EncodeOrDecodeState::Begin;
+                            return CodecTraitResult::Res(OneCommandReturn::Advance);
+                        },
+                        need_something => return CodecTraitResult::Res(OneCommandReturn::BufferExhausted(DivansResult::from(need_something))),
+                    }
+                },
+                EncodeOrDecodeState::SyncCoderDrain
+                    | EncodeOrDecodeState::SyncMuxDrain => {
+                    return CodecTraitResult::Res(OneCommandReturn::BufferExhausted(DivansResult::Failure(ErrMsg::NotAllowedToEncodeDuringSyncFlush)));
+                },
                 EncodeOrDecodeState::WriteChecksum(count) => {
                     assert!(Specialization::IS_DECODING_FILE);
                     match self.cross_command_state.thread_ctx {
@@ -9,6 +9,8 @@ pub const NUM_BLOCK_TYPES:usize = 256;
 pub const NUM_STRIDES:usize = 8;
 use alloc::{SliceWrapper, Allocator, SliceWrapperMut};
 use probability::{BaseCDF};
+use super::mixer::{NUM_WORD_CONTEXTS, NUM_MATCH_STATES};
+use super::sse::{NUM_SSE_BUCKETS, NUM_SSE_BLOCK_TYPES};
 define_prior_struct!(CrossCommandPriors, CrossCommandBilling,
                      (CrossCommandBilling::FullSelection, 16, 1),
                      (CrossCommandBilling::EndIndicator, 1, NUM_BLOCK_TYPES));
@@ -46,6 +48,39 @@ define_prior_struct!(LiteralCommandPriorsCM, LiteralCMPriorType,
                      (LiteralCMPriorType::FirstNibble, 1, NUM_BLOCK_TYPES),
                      (LiteralCMPriorType::SecondNibble, 1, 16, NUM_BLOCK_TYPES));
 
+#[derive(PartialEq, Debug, Clone)]
+pub enum LiteralMixerPriorType {
+    OrderOneHigh,
+    OrderOneLow,
+    WordHigh,
+    WordLow,
+}
+define_prior_struct!(LiteralMixerPriors, LiteralMixerPriorType,
+                     (LiteralMixerPriorType::OrderOneHigh, 256),
+                     (LiteralMixerPriorType::OrderOneLow, 16, 256),
+                     (LiteralMixerPriorType::WordHigh, NUM_WORD_CONTEXTS),
+                     (LiteralMixerPriorType::WordLow, 16, NUM_WORD_CONTEXTS));
+
+#[derive(PartialEq, Debug, Clone)]
+pub enum LiteralMatchPriorType {
+    MatchHigh,
+    MatchLow,
+}
+// indexed by the nibble the match model expects (16 when it has no prediction) and its match state
+define_prior_struct!(LiteralMatchPriors, LiteralMatchPriorType,
+                     (LiteralMatchPriorType::MatchHigh, 17, NUM_MATCH_STATES),
+                     (LiteralMatchPriorType::MatchLow, 17, NUM_MATCH_STATES));
+
+#[derive(PartialEq, Debug, Clone)]
+pub enum LiteralSSEPriorType {
+    SSEHigh,
+    SSELow,
+}
+// indexed by the confidence bucket and likely nibble from sse_quantize, the previous nibble and the block type
+define_prior_struct!(LiteralSSEPriors, LiteralSSEPriorType,
+                     (LiteralSSEPriorType::SSEHigh, NUM_SSE_BUCKETS, 16, 16, NUM_SSE_BLOCK_TYPES),
+                     (LiteralSSEPriorType::SSELow, NUM_SSE_BUCKETS, 16, 16, NUM_SSE_BLOCK_TYPES));
+
 #[derive(PartialEq, Debug, Clone)]
 pub enum RandLiteralNibblePriorType {
     CountSmall,
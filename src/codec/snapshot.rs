@@ -0,0 +1,343 @@
+// Copyright 2017 Dropbox, Inc
+//
+//   Licensed under the Apache License, Version 2.0 (the "License");
+//   you may not use this file except in compliance with the License.
+//   You may obtain a copy of the License at
+//
+//       http://www.apache.org/licenses/LICENSE-2.0
+//
+//   Unless required by applicable law or agreed to in writing, software
+//   distributed under the License is distributed on an "AS IS" BASIS,
+//   WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//   See the License for the specific language governing permissions and
+//   limitations under the License.
+
+// Prior snapshots: the priors a codec has adapted over a training corpus, serialized so a
+// compressor and a decompressor can both start a stream from them instead of the initial CDFs.
+// The layout is described next to PRIOR_SNAPSHOT_MAGIC.
+use core::hash::Hasher;
+use alloc::Allocator;
+use interface::{
+    ErrMsg,
+    PRIOR_SNAPSHOT_MAGIC,
+    PRIOR_SNAPSHOT_VERSION,
+    PRIOR_SNAPSHOT_HEADER_LENGTH,
+};
+use ::probability::{CDF16, CDFFlavor, Prob, Speed};
+use ::xxhash::XxHash64;
+use ::byte_util::{read_u32, u32_bytes};
+use ::priors::PriorCollection;
+use super::priors::{
+    LiteralNibblePriors,
+    LiteralCommandPriors,
+    CopyCommandPriors,
+    DictCommandPriors,
+    CrossCommandPriors,
+    PredictionModePriors,
+    BlockTypePriors,
+};
+
+// lit_high, lit_low, lit_len, cc, copy, dict, prediction and btype priors, in that order
+pub const NUM_SNAPSHOT_TABLES: usize = 8;
+pub const SNAPSHOT_MIXING_MASK_LENGTH: usize = 8192;
+const SPEEDS_LENGTH: usize = 8;
+const ENTRY_LENGTH: usize = 4 + 2 * 16;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PriorSnapshotInfo {
+    // recorded in the header of every stream that starts from the snapshot
+    pub id: u32,
+    pub cdf_flavor: CDFFlavor,
+}
+
+// the number of priors in each table a snapshot records
+pub fn snapshot_table_sizes<Cdf16:CDF16, AllocCDF16:Allocator<Cdf16>>() -> [usize; NUM_SNAPSHOT_TABLES] {
+    [LiteralNibblePriors::<Cdf16, AllocCDF16>::NUM_ALL_PRIORS,
+     LiteralNibblePriors::<Cdf16, AllocCDF16>::NUM_ALL_PRIORS,
+     LiteralCommandPriors::<Cdf16, AllocCDF16>::NUM_ALL_PRIORS,
+     CrossCommandPriors::<Cdf16, AllocCDF16>::NUM_ALL_PRIORS,
+     CopyCommandPriors::<Cdf16, AllocCDF16>::NUM_ALL_PRIORS,
+     DictCommandPriors::<Cdf16, AllocCDF16>::NUM_ALL_PRIORS,
+     PredictionModePriors::<Cdf16, AllocCDF16>::NUM_ALL_PRIORS,
+     BlockTypePriors::<Cdf16, AllocCDF16>::NUM_ALL_PRIORS]
+}
+
+// only the priors that have moved away from the initial distribution are recorded
+fn emit_body<Cdf16:CDF16, Sink:FnMut(&[u8])>(flavor: CDFFlavor,
+                                             literal_adaptation: &[Speed; 4],
+                                             mixing_mask: &[u8],
+                                             tables: &[&[Cdf16]; NUM_SNAPSHOT_TABLES],
+                                             sink: &mut Sink) {
+    let mut speeds = [0u8; SPEEDS_LENGTH];
+    for (out_item, speed) in speeds.chunks_mut(2).zip(literal_adaptation.iter()) {
+        let (inc, lim) = speed.to_f8_tuple();
+        out_item[0] = inc;
+        out_item[1] = lim;
+    }
+    sink(&speeds[..]);
+    sink(mixing_mask);
+    let init = Cdf16::flavored(flavor).to_words();
+    for table in tables.iter() {
+        let count = table.iter().filter(|cdf| cdf.to_words() != init).count();
+        sink(&u32_bytes(count as u32)[..]);
+        for (index, cdf) in table.iter().enumerate() {
+            let words = cdf.to_words();
+            if words == init {
+                continue;
+            }
+            let mut entry = [0u8; ENTRY_LENGTH];
+            entry[..4].clone_from_slice(&u32_bytes(index as u32)[..]);
+            for (out_item, word) in entry[4..].chunks_mut(2).zip(words.iter()) {
+                out_item[0] = *word as u16 as u8;
+                out_item[1] = (*word as u16 >> 8) as u8;
+            }
+            sink(&entry[..]);
+        }
+    }
+}
+
+// serializes the given state as a prior snapshot, handing the bytes to sink in order
+pub fn write_prior_snapshot<Cdf16:CDF16, Sink:FnMut(&[u8])>(flavor: CDFFlavor,
+                                                            literal_adaptation: &[Speed; 4],
+                                                            mixing_mask: &[u8],
+                                                            tables: &[&[Cdf16]; NUM_SNAPSHOT_TABLES],
+                                                            sink: &mut Sink) -> PriorSnapshotInfo {
+    let mut hasher = XxHash64::default();
+    hasher.write(&[flavor as u8]);
+    emit_body(flavor, literal_adaptation, mixing_mask, tables, &mut |data: &[u8]| hasher.write(data));
+    let id = hasher.finish() as u32;
+    let mut header = [0u8; PRIOR_SNAPSHOT_HEADER_LENGTH];
+    header[..4].clone_from_slice(&PRIOR_SNAPSHOT_MAGIC[..]);
+    header[4] = PRIOR_SNAPSHOT_VERSION;
+    header[5] = flavor as u8;
+    header[8..].clone_from_slice(&u32_bytes(id)[..]);
+    sink(&header[..]);
+    emit_body(flavor, literal_adaptation, mixing_mask, tables, sink);
+    PriorSnapshotInfo {
+        id: id,
+        cdf_flavor: flavor,
+    }
+}
+
+// walks the recorded priors of a snapshot whose header has been checked
+fn for_each_entry<Cdf16:CDF16, Visit:FnMut(usize, usize, Cdf16)>(flavor: CDFFlavor,
+                                                                 mut tables_data: &[u8],
+                                                                 table_sizes: &[usize; NUM_SNAPSHOT_TABLES],
+                                                                 visit: &mut Visit) -> Result<(), ErrMsg> {
+    for (table, size) in table_sizes.iter().enumerate() {
+        if tables_data.len() < 4 {
+            return Err(ErrMsg::PriorSnapshotCorrupt(4));
+        }
+        let count = read_u32(tables_data) as usize;
+        tables_data = &tables_data[4..];
+        if count > *size || tables_data.len() < count * ENTRY_LENGTH {
+            return Err(ErrMsg::PriorSnapshotCorrupt(4));
+        }
+        let mut next_index = 0usize;
+        for entry in tables_data[..count * ENTRY_LENGTH].chunks(ENTRY_LENGTH) {
+            let index = read_u32(entry) as usize;
+            if index < next_index || index >= *size {
+                return Err(ErrMsg::PriorSnapshotCorrupt(6));
+            }
+            next_index = index + 1;
+            let mut words = [0 as Prob; 16];
+            for (word, data) in words.iter_mut().zip(entry[4..].chunks(2)) {
+                *word = (u16::from(data[0]) | (u16::from(data[1]) << 8)) as Prob;
+            }
+            match Cdf16::from_words(flavor, &words) {
+                Some(cdf) => visit(table, index, cdf),
+                None => return Err(ErrMsg::PriorSnapshotCorrupt(7)),
+            }
+        }
+        tables_data = &tables_data[count * ENTRY_LENGTH..];
+    }
+    if !tables_data.is_empty() {
+        return Err(ErrMsg::PriorSnapshotCorrupt(4));
+    }
+    Ok(())
+}
+
+fn check_prior_snapshot<Cdf16:CDF16>(snapshot: &[u8],
+                                     table_sizes: &[usize; NUM_SNAPSHOT_TABLES]) -> Result<PriorSnapshotInfo, ErrMsg> {
+    if snapshot.len() < PRIOR_SNAPSHOT_HEADER_LENGTH || snapshot[..4] != PRIOR_SNAPSHOT_MAGIC[..] {
+        return Err(ErrMsg::PriorSnapshotCorrupt(0));
+    }
+    if snapshot[4] != PRIOR_SNAPSHOT_VERSION {
+        return Err(ErrMsg::PriorSnapshotCorrupt(1));
+    }
+    let flavor = match snapshot[5] {
+        0 => CDFFlavor::Frequentist,
+        1 => CDFFlavor::Blend,
+        _ => return Err(ErrMsg::PriorSnapshotCorrupt(2)),
+    };
+    if snapshot[6] != 0 || snapshot[7] != 0 {
+        return Err(ErrMsg::PriorSnapshotCorrupt(3));
+    }
+    let id = read_u32(&snapshot[8..]);
+    let body = &snapshot[PRIOR_SNAPSHOT_HEADER_LENGTH..];
+    if body.len() < SPEEDS_LENGTH + SNAPSHOT_MIXING_MASK_LENGTH {
+        return Err(ErrMsg::PriorSnapshotCorrupt(4));
+    }
+    let mut hasher = XxHash64::default();
+    hasher.write(&snapshot[5..6]);
+    hasher.write(body);
+    if hasher.finish() as u32 != id {
+        return Err(ErrMsg::PriorSnapshotCorrupt(5));
+    }
+    match for_each_entry::<Cdf16, _>(flavor,
+                                     &body[SPEEDS_LENGTH + SNAPSHOT_MIXING_MASK_LENGTH..],
+                                     table_sizes,
+                                     &mut |_table, _index, _cdf| {}) {
+        Ok(()) => {},
+        Err(e) => return Err(e),
+    }
+    Ok(PriorSnapshotInfo {
+        id: id,
+        cdf_flavor: flavor,
+    })
+}
+
+// checks that a snapshot is intact and fits a codec with these priors, without loading it
+pub fn parse_prior_snapshot<Cdf16:CDF16, AllocCDF16:Allocator<Cdf16>>(snapshot: &[u8]) -> Result<PriorSnapshotInfo, ErrMsg> {
+    check_prior_snapshot::<Cdf16>(snapshot, &snapshot_table_sizes::<Cdf16, AllocCDF16>())
+}
+
+// replaces the given state with that of the snapshot; nothing is written unless the whole snapshot
+// is valid. The tables must already hold priors of the snapshot's flavor.
+pub fn load_prior_snapshot<Cdf16:CDF16>(snapshot: &[u8],
+                                        literal_adaptation: &mut [Speed; 4],
+                                        mixing_mask: &mut [u8],
+                                        tables: &mut [&mut [Cdf16]; NUM_SNAPSHOT_TABLES]) -> Result<PriorSnapshotInfo, ErrMsg> {
+    let mut table_sizes = [0usize; NUM_SNAPSHOT_TABLES];
+    for (size, table) in table_sizes.iter_mut().zip(tables.iter()) {
+        *size = table.len();
+    }
+    let info = match check_prior_snapshot::<Cdf16>(snapshot, &table_sizes) {
+        Ok(info) => info,
+        Err(e) => return Err(e),
+    };
+    let body = &snapshot[PRIOR_SNAPSHOT_HEADER_LENGTH..];
+    for (speed, data) in literal_adaptation.iter_mut().zip(body[..SPEEDS_LENGTH].chunks(2)) {
+        *speed = Speed::from_f8_tuple((data[0], data[1]));
+    }
+    mixing_mask.clone_from_slice(&body[SPEEDS_LENGTH..SPEEDS_LENGTH + SNAPSHOT_MIXING_MASK_LENGTH]);
+    let init = Cdf16::flavored(info.cdf_flavor);
+    for table in tables.iter_mut() {
+        for item in table.iter_mut() {
+            *item = init;
+        }
+    }
+    match for_each_entry(info.cdf_flavor,
+                         &body[SPEEDS_LENGTH + SNAPSHOT_MIXING_MASK_LENGTH..],
+                         &table_sizes,
+                         &mut |table, index, cdf| tables[table][index] = cdf) {
+        Ok(()) => Ok(info),
+        Err(e) => Err(e),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Read;
+    use std::vec::Vec;
+    use ::interface;
+    use ::alloc::HeapAlloc;
+    use ::byte_util::read_u32;
+    use ::probability::Speed;
+    use super::{load_prior_snapshot, parse_prior_snapshot, snapshot_table_sizes, write_prior_snapshot,
+                PriorSnapshotInfo, NUM_SNAPSHOT_TABLES, SNAPSHOT_MIXING_MASK_LENGTH};
+
+    // loads snapshot into empty tables and serializes them again
+    fn reload_tst(snapshot: &[u8]) -> (PriorSnapshotInfo, Vec<u8>) {
+        let mut storage: Vec<Vec<::DefaultCDF16>> = snapshot_table_sizes::<::DefaultCDF16, HeapAlloc<::DefaultCDF16>>()
+            .iter().map(|size| vec![::DefaultCDF16::default(); *size]).collect();
+        let mut literal_adaptation = [Speed::MED; 4];
+        let mut mixing_mask = [0u8; SNAPSHOT_MIXING_MASK_LENGTH];
+        let info = {
+            let mut storage_iter = storage.iter_mut();
+            let mut tables: [&mut [::DefaultCDF16]; NUM_SNAPSHOT_TABLES] = [
+                &mut storage_iter.next().unwrap()[..], &mut storage_iter.next().unwrap()[..],
+                &mut storage_iter.next().unwrap()[..], &mut storage_iter.next().unwrap()[..],
+                &mut storage_iter.next().unwrap()[..], &mut storage_iter.next().unwrap()[..],
+                &mut storage_iter.next().unwrap()[..], &mut storage_iter.next().unwrap()[..]];
+            load_prior_snapshot(snapshot, &mut literal_adaptation, &mut mixing_mask[..], &mut tables).unwrap()
+        };
+        let tables: [&[::DefaultCDF16]; NUM_SNAPSHOT_TABLES] = [
+            &storage[0][..], &storage[1][..], &storage[2][..], &storage[3][..],
+            &storage[4][..], &storage[5][..], &storage[6][..], &storage[7][..]];
+        let mut rewritten = Vec::<u8>::new();
+        assert_eq!(write_prior_snapshot(info.cdf_flavor, &literal_adaptation, &mixing_mask[..], &tables,
+                                        &mut |data: &[u8]| rewritten.extend_from_slice(data)), info);
+        (info, rewritten)
+    }
+    fn compress_tst(data: &[u8], opts: interface::DivansCompressorOptions, prior_snapshot: Option<&[u8]>) -> Vec<u8> {
+        let mut compress = ::DivansBrotliHybridCompressorReader::<&[u8]>::new(data, opts, 4096);
+        if let Some(prior_snapshot) = prior_snapshot {
+            assert_eq!(compress.load_prior_snapshot(prior_snapshot), Ok(()));
+        }
+        let mut compressed = Vec::<u8>::new();
+        compress.read_to_end(&mut compressed).unwrap();
+        compressed
+    }
+    fn decompress_tst(compressed: &[u8], prior_snapshot: Option<&[u8]>, multithread: bool) -> Result<Vec<u8>, ::ErrMsg> {
+        let mut decompress = ::DivansDecompressorReader::new(compressed, 4096, false, multithread);
+        if let Some(prior_snapshot) = prior_snapshot {
+            assert_eq!(decompress.load_prior_snapshot(prior_snapshot), Ok(()));
+        }
+        let mut decompressed = Vec::<u8>::new();
+        match decompress.read_to_end(&mut decompressed) {
+            Ok(_) => Ok(decompressed),
+            Err(e) => Err(e.into_inner().unwrap().downcast::<::DivansError>().unwrap().msg),
+        }
+    }
+
+    #[test]
+    fn test_prior_snapshot() {
+        let data = include_bytes!("../../testdata/alice29");
+        let opts = interface::DivansCompressorOptions::level(6).unwrap();
+        let corpus: Vec<&[u8]> = data[..65536].chunks(16384).collect();
+        let snapshot = ::train_prior_snapshot(&corpus[..], opts).unwrap();
+        assert_eq!(::train_prior_snapshot(&corpus[..], opts).unwrap(), snapshot);
+        let info = parse_prior_snapshot::<::DefaultCDF16, HeapAlloc<::DefaultCDF16>>(&snapshot[..]).unwrap();
+        assert_eq!(read_u32(&snapshot[8..]), info.id);
+        // training moved priors away from their initial distribution, and they survive a reload
+        assert!(snapshot.len() > interface::PRIOR_SNAPSHOT_HEADER_LENGTH + 8 + SNAPSHOT_MIXING_MASK_LENGTH + 4 * NUM_SNAPSHOT_TABLES);
+        assert_eq!(reload_tst(&snapshot[..]), (info, snapshot.clone()));
+
+        // a stream started from the snapshot names it in header bytes 10..14
+        let sample = &data[98304..98304 + 4096];
+        let warm = compress_tst(sample, opts, Some(&snapshot[..]));
+        let cold = compress_tst(sample, opts, None);
+        assert!(warm.len() < cold.len());
+        assert_eq!(read_u32(&cold[10..14]), 0);
+        assert_eq!(interface::HEADER_PRIOR_SNAPSHOT_ID_INDEX, 10);
+        assert_eq!(read_u32(&warm[10..14]), info.id);
+        assert!(interface::StreamFeatures::from_bytes(&warm[interface::HEADER_FEATURE_FLAGS_INDEX..]).unwrap().prior_snapshot_id.is_some());
+
+        let other = ::train_prior_snapshot(&corpus[..1], opts).unwrap();
+        assert!(other != snapshot);
+        let limits = interface::DivansDecompressorLimits::default();
+        for multithread in [false, true].iter() {
+            assert_eq!(&decompress_tst(&warm, Some(&snapshot[..]), *multithread).unwrap()[..], sample);
+            // streams that name no snapshot still decode with one loaded
+            assert_eq!(&decompress_tst(&cold, Some(&snapshot[..]), *multithread).unwrap()[..], sample);
+            // without the snapshot it names, or with another one, the stream is rejected
+            for prior_snapshot in [None, Some(&other[..])].iter() {
+                match decompress_tst(&warm, *prior_snapshot, *multithread) {
+                    Err(::ErrMsg::PriorSnapshotMismatch(id)) => assert_eq!(id, info.id),
+                    other => panic!("unexpected {:?}", other.map(|x| x.len())),
+                }
+            }
+            assert_eq!(::verify_stream(&warm[..], 4096, *multithread, limits, &snapshot[..]).unwrap(), Ok(sample.len() as u64));
+            assert_eq!(::verify_stream(&warm[..], 4096, *multithread, limits, &[]).unwrap().map_err(|e| e.msg),
+                       Err(::ErrMsg::PriorSnapshotMismatch(info.id)));
+        }
+        let mut corrupt = snapshot.clone();
+        let last = corrupt.len() - 1;
+        corrupt[last] ^= 1;
+        assert_eq!(parse_prior_snapshot::<::DefaultCDF16, HeapAlloc<::DefaultCDF16>>(&corrupt[..]),
+                   Err(::ErrMsg::PriorSnapshotCorrupt(5)));
+        assert_eq!(parse_prior_snapshot::<::DefaultCDF16, HeapAlloc<::DefaultCDF16>>(&corrupt[..4]),
+                   Err(::ErrMsg::PriorSnapshotCorrupt(0)));
+    }
+}
@@ -0,0 +1,192 @@
+use core;
+use alloc::Allocator;
+use interface::{ArithmeticEncoderOrDecoder, BillingDesignation};
+use ::probability::{CDF16, Prob, Speed};
+use super::interface::EncoderOrDecoderSpecialization;
+use super::literal::{LiteralSubstate, HighTrait};
+use super::priors::{LiteralSSEPriors, LiteralSSEPriorType};
+use ::priors::PriorCollection;
+use super::weights::Weights;
+
+// how finely the secondary estimation stage tells apart the confidence of a prediction
+pub const NUM_SSE_BUCKETS: usize = 8;
+// literal block types past the first few share their refinement tables
+pub const NUM_SSE_BLOCK_TYPES: usize = 4;
+
+// Quantizes a nibble distribution for secondary symbol estimation: returns the nibble it favors
+// and how sure it is of it, bucketed by the log of the chance that nibble is wrong, so the
+// LiteralSSEPriors selected by the pair learn how often such predictions actually hold.
+#[inline(always)]
+pub fn sse_quantize<Cdf16:CDF16>(prob: &Cdf16) -> (usize, usize) {
+    let mut likely_nibble = 0u8;
+    let mut likely_pdf = prob.pdf(0);
+    for nibble in 1..16u8 {
+        let pdf = prob.pdf(nibble);
+        if pdf > likely_pdf {
+            likely_nibble = nibble;
+            likely_pdf = pdf;
+        }
+    }
+    // below 1 << 15, so its log is at most 14: a miss chance of at least a half lands in bucket 0
+    let miss = prob.div_by_max((i32::from(prob.max()) - i32::from(likely_pdf)) << 15) as u32;
+    let log_miss = 31 - (miss | 1).leading_zeros();
+    (usize::from(likely_nibble), core::cmp::min(NUM_SSE_BUCKETS - 1, 14 - log_miss as usize))
+}
+
+// Secondary symbol estimation: the distribution a literal model predicts is looked up, by
+// sse_quantize and a small context, in a table of distributions that learn what actually followed
+// such predictions, and the two are mixed with weights that learn how much to trust the table.
+pub struct LiteralSSE<Cdf16:CDF16, AllocCDF16:Allocator<Cdf16>> {
+    pub enabled: bool,
+    pub weights: [Weights; 2],
+    pub priors: LiteralSSEPriors<Cdf16, AllocCDF16>,
+}
+
+impl<Cdf16:CDF16, AllocCDF16:Allocator<Cdf16>> Default for LiteralSSE<Cdf16, AllocCDF16> {
+    fn default() -> Self {
+        LiteralSSE {
+            enabled: false,
+            weights: [Weights::default(), Weights::default()],
+            priors: LiteralSSEPriors {
+                priors: AllocCDF16::AllocatedMemory::default()
+            },
+        }
+    }
+}
+
+impl<Cdf16:CDF16, AllocCDF16:Allocator<Cdf16>> LiteralSSE<Cdf16, AllocCDF16> {
+    // codes nibble with prob, refined first when the stage is enabled; returns the frequency prob
+    // itself gave the nibble, so whatever mixed prob keeps learning from its own prediction
+    #[inline(always)]
+    pub fn code_nibble<ArithmeticCoder:ArithmeticEncoderOrDecoder,
+                       Specialization:EncoderOrDecoderSpecialization,
+                       HTraits:HighTrait>(&mut self,
+                                          nibble: &mut u8,
+                                          prob: &Cdf16,
+                                          prev_nibble: u8,
+                                          block_type: u8,
+                                          speed: Speed,
+                                          local_coder: &mut ArithmeticCoder,
+                                          specialization: &Specialization) -> Prob {
+        let nibble_index = !HTraits::IS_HIGH as u32;
+        if !self.enabled {
+            return local_coder.get_or_put_nibble(
+                nibble,
+                prob,
+                BillingDesignation::LiteralCommand(LiteralSubstate::LiteralNibbleIndex(nibble_index))).freq;
+        }
+        let (likely_nibble, bucket) = sse_quantize(prob);
+        let sse_type = if HTraits::IS_HIGH {LiteralSSEPriorType::SSEHigh} else {LiteralSSEPriorType::SSELow};
+        let sse_prob = self.priors.get(sse_type, (bucket,
+                                                  likely_nibble,
+                                                  usize::from(prev_nibble & 0xf),
+                                                  core::cmp::min(usize::from(block_type), NUM_SSE_BLOCK_TYPES - 1)));
+        let weights = &mut self.weights[HTraits::IS_HIGH as usize];
+        let refined = sse_prob.average(prob, weights.norm_weight() as u16 as i32);
+        let refined_range = local_coder.get_or_put_nibble(
+            nibble,
+            &refined,
+            BillingDesignation::LiteralCommand(LiteralSubstate::LiteralNibbleIndex(nibble_index)));
+        // billing reports what the nibble would have cost without this stage next to what it did
+        local_coder.estimate_nibble(*nibble, prob, BillingDesignation::LiteralCommand(LiteralSubstate::LiteralNibbleIndex(nibble_index)));
+        let prob_freq = prob.sym_to_start_and_freq(*nibble).range.freq;
+        weights.update([sse_prob.sym_to_start_and_freq(*nibble).range.freq, prob_freq], refined_range.freq);
+        if specialization.adapt_cdf() {
+            sse_prob.blend(*nibble, speed);
+        }
+        prob_freq
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use ::interface::{ArithmeticEncoderOrDecoder, DivansResult, ReadableBytes, WritableBytes};
+    use ::alloc::{Allocator, HeapAlloc};
+    use ::probability::{BaseCDF, CDF16, Prob, ProbRange, Speed};
+    use ::cmd_to_divans::EncoderSpecialization;
+    use ::priors::PriorCollection;
+    use super::{sse_quantize, HighTrait, LiteralSSE, LiteralSSEPriors, NUM_SSE_BUCKETS};
+
+    // codes nothing, but keeps the frequency of the last nibble it was asked to code
+    #[derive(Default)]
+    struct LastNibbleCoder {
+        freq: Prob,
+    }
+    impl ArithmeticEncoderOrDecoder for LastNibbleCoder {
+        fn mov(&mut self) -> Self {
+            LastNibbleCoder { freq: self.freq }
+        }
+        fn has_data_to_drain_or_fill(&self) -> bool {
+            false
+        }
+        fn drain_or_fill_internal_buffer_unchecked(&mut self,
+                                                   _input: &mut ReadableBytes,
+                                                   _output: &mut WritableBytes) -> DivansResult {
+            DivansResult::Success
+        }
+        fn get_or_put_bit_without_billing(&mut self, _bit: &mut bool, _prob_of_false: u8) {
+        }
+        fn get_or_put_nibble_without_billing<C: CDF16>(&mut self, nibble: &mut u8, prob: &C) -> ProbRange {
+            let range = prob.sym_to_start_and_freq(*nibble).range;
+            self.freq = range.freq;
+            range
+        }
+        fn close(&mut self) -> DivansResult {
+            DivansResult::Success
+        }
+        fn sync(&mut self) -> DivansResult {
+            DivansResult::Success
+        }
+    }
+    struct High {}
+    impl HighTrait for High {
+        const IS_HIGH: bool = true;
+    }
+    // a model that has seen nibble count times
+    fn trained_model(nibble: u8, count: usize) -> ::DefaultCDF16 {
+        let mut model = ::DefaultCDF16::default();
+        for _ in 0..count {
+            model.blend(nibble, Speed::MED);
+        }
+        model
+    }
+
+    #[test]
+    fn test_sse_quantize() {
+        // no nibble is favored by a uniform distribution, which misses more than half the time
+        assert_eq!(sse_quantize(&::DefaultCDF16::default()), (0, 0));
+        // confidence only grows as the favored nibble keeps showing up
+        let mut last_bucket = 0;
+        for count in [1, 4, 16, 64].iter() {
+            let (nibble, bucket) = sse_quantize(&trained_model(9, *count));
+            assert_eq!(nibble, 9);
+            assert!(bucket >= last_bucket, "{} after {} at {} repeats", bucket, last_bucket, count);
+            last_bucket = bucket;
+        }
+        // a nearly certain nibble lands in the top bucket
+        assert_eq!(sse_quantize(&trained_model(9, 4096)), (9, NUM_SSE_BUCKETS - 1));
+    }
+    #[test]
+    fn test_literal_sse_code_nibble() {
+        let model = trained_model(3, 64);
+        let unrefined_freq = model.sym_to_start_and_freq(5).range.freq;
+        let specialization = EncoderSpecialization::new();
+        let mut coder = LastNibbleCoder::default();
+        let mut nibble = 5u8;
+        let mut disabled = LiteralSSE::<::DefaultCDF16, HeapAlloc<::DefaultCDF16>>::default();
+        assert_eq!(disabled.code_nibble::<LastNibbleCoder, EncoderSpecialization, High>(
+            &mut nibble, &model, 0, 0, Speed::MED, &mut coder, &specialization), unrefined_freq);
+        assert_eq!(coder.freq, unrefined_freq);
+
+        let mut sse = LiteralSSE::<::DefaultCDF16, HeapAlloc<::DefaultCDF16>>::default();
+        sse.enabled = true;
+        sse.priors.priors = HeapAlloc::<::DefaultCDF16>::new(::DefaultCDF16::default())
+            .alloc_cell(LiteralSSEPriors::<::DefaultCDF16, HeapAlloc<::DefaultCDF16>>::NUM_ALL_PRIORS);
+        // the refined distribution is what gets coded, but the caller learns what the model gave the nibble
+        assert_eq!(sse.code_nibble::<LastNibbleCoder, EncoderSpecialization, High>(
+            &mut nibble, &model, 0, 0, Speed::MED, &mut coder, &specialization), unrefined_freq);
+        assert_eq!(nibble, 5);
+        // an untrained table is uniform, so mixing it in makes the unexpected nibble cheaper
+        assert!(coder.freq > unrefined_freq, "{} <= {}", coder.freq, unrefined_freq);
+    }
+}
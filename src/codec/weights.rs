@@ -113,13 +113,22 @@ fn compute_new_weight(probs: [Prob; 2],
                       index_equal_1: bool,
                       _speed: u8) -> i32{ // speed ranges from 1 to 14 inclusive
     let index = index_equal_1 as usize;
+    adjust_weight(probs[index], weighted_prob, weights[index])
+}
+
+// moves the weight of one model toward the share of the coded symbol it predicted (n1i) and away
+// from what the mixture predicted (full_model_sum_p1); the weight never drops below 1
+#[inline(always)]
+pub fn adjust_weight(model_prob: Prob,
+                     weighted_prob: Prob,
+                     weight: i32) -> i32 {
     let full_model_sum_p1 = i64::from(weighted_prob);
     let full_model_total = 1i64 << LOG2_SCALE;
     let full_model_sum_p0 = full_model_total.wrapping_sub(i64::from(weighted_prob));
-    let n1i = i64::from(probs[index]);
+    let n1i = i64::from(model_prob);
     let ni = 1i64 << LOG2_SCALE;
     let error = full_model_total.wrapping_sub(full_model_sum_p1);
-    let wi = i64::from(weights[index]);
+    let wi = i64::from(weight);
     let efficacy = full_model_total.wrapping_mul(n1i) - full_model_sum_p1.wrapping_mul(ni);
     //let geometric_probabilities = full_model_sum_p1 * full_model_sum_p0;
     let log_geometric_probabilities = 64 - (full_model_sum_p1.wrapping_mul(full_model_sum_p0)).leading_zeros();
@@ -128,6 +137,5 @@ fn compute_new_weight(probs: [Prob; 2],
     //let new_weight_adj = (error * efficacy)/(full_model_sum_p1 * full_model_sum_p0);
     let new_weight_adj = (error.wrapping_mul(efficacy)) >> log_geometric_probabilities;
 //    assert!(wi + new_weight_adj < (1i64 << 31));
-    //print!("{} -> {} due to {:?} vs {}\n", wi as f64 / (weights[0] + weights[1]) as f64, (wi + new_weight_adj) as f64 /(weights[0] as i64 + new_weight_adj as i64 + weights[1] as i64) as f64, probs[index], weighted_prob);
     core::cmp::max(1,wi.wrapping_add(new_weight_adj) as i32)
 }
@@ -0,0 +1,185 @@
+// Copyright 2017 Dropbox, Inc
+//
+//   Licensed under the Apache License, Version 2.0 (the "License");
+//   you may not use this file except in compliance with the License.
+//   You may obtain a copy of the License at
+//
+//       http://www.apache.org/licenses/LICENSE-2.0
+//
+//   Unless required by applicable law or agreed to in writing, software
+//   distributed under the License is distributed on an "AS IS" BASIS,
+//   WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//   See the License for the specific language governing permissions and
+//   limitations under the License.
+
+// A compact record of the commands a decoder executed. Literal bytes are not logged since
+// they are already present in the decoded output; only the length of each literal run is kept.
+use alloc::SliceWrapper;
+use byte_util::{read_varint, write_varint};
+use interface::{Command, CopyCommand, DictCommand, BlockSwitch, LiteralBlockSwitch, LiteralPredictionModeNibble,
+                PredictionModeContextMap};
+
+pub const MAX_LOGGED_COMMAND_SIZE: usize = 16;
+
+const TAG_LITERAL: u8 = 0;
+const TAG_COPY: u8 = 1;
+const TAG_DICT: u8 = 2;
+const TAG_BLOCK_SWITCH_COMMAND: u8 = 3;
+const TAG_BLOCK_SWITCH_DISTANCE: u8 = 4;
+const TAG_BLOCK_SWITCH_LITERAL: u8 = 5;
+const TAG_PREDICTION_MODE: u8 = 6;
+
+#[derive(Clone, Copy, Debug)]
+pub enum LoggedCommand<'a> {
+    Literal(u32),
+    Copy(CopyCommand),
+    Dict(DictCommand),
+    BlockSwitchCommand(BlockSwitch),
+    BlockSwitchDistance(BlockSwitch),
+    BlockSwitchLiteral(LiteralBlockSwitch),
+    // literal prediction mode, literal context map and distance context map
+    PredictionMode(LiteralPredictionModeNibble, &'a [u8], &'a [u8]),
+}
+
+// the logged fields are all 32 bits, so anything wider is corrupt
+fn read_u32_varint(input: &[u8]) -> Option<(u32, usize)> {
+    let mut used = 0;
+    match read_varint(input, &mut used) {
+        Some(val) if val >> 32 == 0 => Some((val as u32, used)),
+        _ => None,
+    }
+}
+
+fn distance_context_map<SliceType:SliceWrapper<u8>>(pm: &PredictionModeContextMap<SliceType>) -> &[u8] {
+    if pm.has_context_speeds() {
+        pm.distance_context_map()
+    } else {
+        &[]
+    }
+}
+
+// the number of bytes log_command may need for cmd
+pub fn logged_command_size<SliceType:SliceWrapper<u8>>(cmd: &Command<SliceType>) -> usize {
+    match *cmd {
+        Command::PredictionMode(ref pm) => MAX_LOGGED_COMMAND_SIZE + pm.literal_context_map.slice().len()
+            + distance_context_map(pm).len(),
+        _ => MAX_LOGGED_COMMAND_SIZE,
+    }
+}
+
+// serializes cmd into output, which must hold at least logged_command_size(cmd) bytes
+// returns the number of bytes used; the mixing and speed settings of prediction modes are not logged
+pub fn log_command<SliceType:SliceWrapper<u8>>(cmd: &Command<SliceType>, output: &mut [u8]) -> usize {
+    match *cmd {
+        Command::Literal(ref lit) => {
+            output[0] = TAG_LITERAL;
+            1 + write_varint(&mut output[1..], lit.data.slice().len() as u64)
+        },
+        Command::Copy(ref copy) => {
+            output[0] = TAG_COPY;
+            let offset = 1 + write_varint(&mut output[1..], u64::from(copy.distance));
+            offset + write_varint(&mut output[offset..], u64::from(copy.num_bytes))
+        },
+        Command::Dict(ref dict) => {
+            output[0] = TAG_DICT;
+            output[1] = dict.word_size;
+            output[2] = dict.transform;
+            output[3] = dict.final_size;
+            4 + write_varint(&mut output[4..], u64::from(dict.word_id))
+        },
+        Command::BlockSwitchCommand(ref bs) => {
+            output[0] = TAG_BLOCK_SWITCH_COMMAND;
+            output[1] = bs.block_type();
+            2
+        },
+        Command::BlockSwitchDistance(ref bs) => {
+            output[0] = TAG_BLOCK_SWITCH_DISTANCE;
+            output[1] = bs.block_type();
+            2
+        },
+        Command::BlockSwitchLiteral(ref bs) => {
+            output[0] = TAG_BLOCK_SWITCH_LITERAL;
+            output[1] = bs.block_type();
+            output[2] = bs.stride();
+            3
+        },
+        Command::PredictionMode(ref pm) => {
+            output[0] = TAG_PREDICTION_MODE;
+            output[1] = pm.literal_prediction_mode().prediction_mode();
+            let mut offset = 2;
+            for map in [pm.literal_context_map.slice(), distance_context_map(pm)].iter() {
+                offset += write_varint(&mut output[offset..], map.len() as u64);
+                output[offset..offset + map.len()].clone_from_slice(map);
+                offset += map.len();
+            }
+            offset
+        },
+    }
+}
+
+// returns the first command in the log and the number of bytes it occupied
+pub fn parse_logged_command<'a>(log: &'a [u8]) -> Option<(LoggedCommand<'a>, usize)> {
+    let tag = match log.first() {
+        Some(tag) => *tag,
+        None => return None,
+    };
+    let rest = &log[1..];
+    match tag {
+        TAG_LITERAL => read_u32_varint(rest).map(|(len, used)| (LoggedCommand::Literal(len), 1 + used)),
+        TAG_COPY => {
+            let (distance, used_distance) = match read_u32_varint(rest) {
+                Some(val) => val,
+                None => return None,
+            };
+            read_u32_varint(&rest[used_distance..]).map(|(num_bytes, used_len)| (
+                LoggedCommand::Copy(CopyCommand{distance:distance, num_bytes:num_bytes}),
+                1 + used_distance + used_len))
+        },
+        TAG_DICT => {
+            if rest.len() < 3 {
+                return None;
+            }
+            read_u32_varint(&rest[3..]).map(|(word_id, used)| (
+                LoggedCommand::Dict(DictCommand{
+                    word_size: rest[0],
+                    transform: rest[1],
+                    final_size: rest[2],
+                    empty: 0,
+                    word_id: word_id,
+                }),
+                4 + used))
+        },
+        TAG_BLOCK_SWITCH_COMMAND => rest.first().map(
+            |block_type| (LoggedCommand::BlockSwitchCommand(BlockSwitch::new(*block_type)), 2)),
+        TAG_BLOCK_SWITCH_DISTANCE => rest.first().map(
+            |block_type| (LoggedCommand::BlockSwitchDistance(BlockSwitch::new(*block_type)), 2)),
+        TAG_BLOCK_SWITCH_LITERAL => {
+            if rest.len() < 2 {
+                return None;
+            }
+            Some((LoggedCommand::BlockSwitchLiteral(LiteralBlockSwitch::new(rest[0], rest[1])), 3))
+        },
+        TAG_PREDICTION_MODE => {
+            let mode = match rest.first() {
+                Some(mode) => LiteralPredictionModeNibble(*mode),
+                None => return None,
+            };
+            let mut offset = 1;
+            let mut maps = [&rest[..0], &rest[..0]];
+            for map in maps.iter_mut() {
+                let (len, used) = match read_u32_varint(&rest[offset..]) {
+                    Some(val) => val,
+                    None => return None,
+                };
+                offset += used;
+                if rest.len() - offset < len as usize {
+                    return None;
+                }
+                *map = &rest[offset..offset + len as usize];
+                offset += len as usize;
+            }
+            Some((LoggedCommand::PredictionMode(mode, maps[0], maps[1]), 1 + offset))
+        },
+        _ => None,
+    }
+}
@@ -73,6 +73,8 @@ impl EntropyDecoder for DebugDecoder {
     fn flush(&mut self) -> DivansResult {
         DivansResult::Success
     }
+    fn resync(&mut self) {
+    }
 }
 
 impl DebugEncoder {
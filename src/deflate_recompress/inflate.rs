@@ -0,0 +1,251 @@
+// Copyright 2017 Dropbox, Inc
+//
+//   Licensed under the Apache License, Version 2.0 (the "License");
+//   you may not use this file except in compliance with the License.
+//   You may obtain a copy of the License at
+//
+//       http://www.apache.org/licenses/LICENSE-2.0
+//
+//   Unless required by applicable law or agreed to in writing, software
+//   distributed under the License is distributed on an "AS IS" BASIS,
+//   WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//   See the License for the specific language governing permissions and
+//   limitations under the License.
+
+// Splits a DEFLATE stream (RFC 1951) into divans IR literals and copies. Block headers and prefix
+// code descriptions go to the side data; the symbols are implied by the commands.
+use std::vec::Vec;
+use ::interface::{CopyCommand, ErrMsg};
+use ::brotli_recompress::analyze::IrCommand;
+use ::brotli_recompress::bit_io::{BitReader, BitSource, BitWriter, TeeReader};
+use ::brotli_recompress::prefix::PrefixCode;
+use ::byte_util::push_varint;
+
+pub const END_OF_BLOCK: u16 = 256;
+pub const FIRST_LENGTH_SYMBOL: u16 = 257;
+pub const MAX_DISTANCE: u32 = 32_768;
+pub const BLOCK_STORED: u32 = 0;
+pub const BLOCK_FIXED: u32 = 1;
+pub const BLOCK_DYNAMIC: u32 = 2;
+const NUM_FIXED_LITERAL_LENGTH_SYMBOLS: usize = 288;
+const NUM_FIXED_DISTANCE_SYMBOLS: usize = 32;
+const MAX_LITERAL_LENGTH_SYMBOLS: usize = 286;
+const MAX_DISTANCE_SYMBOLS: usize = 30;
+const CODE_LENGTH_CODES: usize = 19;
+const CODE_LENGTH_ORDER: [usize; CODE_LENGTH_CODES] = [16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15];
+
+// base and number of extra bits of each length and distance symbol
+pub const LENGTH_PREFIX: [(u32, u32); 29] = [
+    (3, 0), (4, 0), (5, 0), (6, 0), (7, 0), (8, 0), (9, 0), (10, 0),
+    (11, 1), (13, 1), (15, 1), (17, 1), (19, 2), (23, 2), (27, 2), (31, 2),
+    (35, 3), (43, 3), (51, 3), (59, 3), (67, 4), (83, 4), (99, 4), (115, 4),
+    (131, 5), (163, 5), (195, 5), (227, 5), (258, 0)];
+
+pub const DISTANCE_PREFIX: [(u32, u32); 30] = [
+    (1, 0), (2, 0), (3, 0), (4, 0), (5, 1), (7, 1), (9, 2), (13, 2),
+    (17, 3), (25, 3), (33, 4), (49, 4), (65, 5), (97, 5), (129, 6), (193, 6),
+    (257, 7), (385, 7), (513, 8), (769, 8), (1025, 9), (1537, 9), (2049, 10), (3073, 10),
+    (4097, 11), (6145, 11), (8193, 12), (12289, 12), (16385, 13), (24577, 13)];
+
+pub struct BlockCodes {
+    pub literal_length: PrefixCode,
+    pub distance: PrefixCode,
+}
+
+// rejects the codes zlib rejects: oversubscribed ones and incomplete ones other than a lone one bit code
+fn check_code_lengths(lengths: &[u8], allow_single_code: bool) -> Result<(), ErrMsg> {
+    let mut space = 1i32 << 15;
+    let mut num_codes = 0;
+    for len in lengths.iter() {
+        if *len != 0 {
+            space -= (1 << 15) >> *len;
+            num_codes += 1;
+        }
+    }
+    if space < 0 {
+        return Err(ErrMsg::DeflateBitstreamCorrupt(0));
+    }
+    if space > 0 && num_codes != 0 && !(allow_single_code && num_codes == 1 && space == 1 << 14) {
+        return Err(ErrMsg::DeflateBitstreamCorrupt(1));
+    }
+    Ok(())
+}
+
+pub fn fixed_codes() -> BlockCodes {
+    let mut literal_length = vec![8u8; NUM_FIXED_LITERAL_LENGTH_SYMBOLS];
+    for len in literal_length[144..256].iter_mut() {
+        *len = 9;
+    }
+    for len in literal_length[256..280].iter_mut() {
+        *len = 7;
+    }
+    BlockCodes {
+        literal_length: PrefixCode::from_lengths(literal_length),
+        distance: PrefixCode::from_lengths(vec![5u8; NUM_FIXED_DISTANCE_SYMBOLS]),
+    }
+}
+
+// reads HLIT, HDIST, HCLEN and the code lengths of a dynamic block
+pub fn read_dynamic_codes<Source: BitSource>(source: &mut Source) -> Result<BlockCodes, ErrMsg> {
+    let num_literal_length = try!(source.read_bits(5)) as usize + 257;
+    let num_distance = try!(source.read_bits(5)) as usize + 1;
+    let num_code_length = try!(source.read_bits(4)) as usize + 4;
+    if num_literal_length > MAX_LITERAL_LENGTH_SYMBOLS || num_distance > MAX_DISTANCE_SYMBOLS {
+        return Err(ErrMsg::DeflateBitstreamCorrupt(2));
+    }
+    let mut code_length_lengths = vec![0u8; CODE_LENGTH_CODES];
+    for symbol in CODE_LENGTH_ORDER[..num_code_length].iter() {
+        code_length_lengths[*symbol] = try!(source.read_bits(3)) as u8;
+    }
+    try!(check_code_lengths(&code_length_lengths[..], false));
+    let code_length_code = PrefixCode::from_lengths(code_length_lengths);
+    let total = num_literal_length + num_distance;
+    let mut lengths = vec![0u8; total];
+    let mut index = 0usize;
+    while index < total {
+        let symbol = try!(code_length_code.read_symbol(source));
+        let (len, repeat) = match symbol {
+            _ if symbol < 16 => (symbol as u8, 1),
+            16 => {
+                if index == 0 {
+                    return Err(ErrMsg::DeflateBitstreamCorrupt(3));
+                }
+                (lengths[index - 1], 3 + try!(source.read_bits(2)) as usize)
+            },
+            17 => (0, 3 + try!(source.read_bits(3)) as usize),
+            _ => (0, 11 + try!(source.read_bits(7)) as usize),
+        };
+        if index + repeat > total {
+            return Err(ErrMsg::DeflateBitstreamCorrupt(4));
+        }
+        for item in lengths[index..index + repeat].iter_mut() {
+            *item = len;
+        }
+        index += repeat;
+    }
+    if lengths[END_OF_BLOCK as usize] == 0 {
+        return Err(ErrMsg::DeflateBitstreamCorrupt(5));
+    }
+    let distance_lengths = lengths.split_off(num_literal_length);
+    try!(check_code_lengths(&lengths[..], true));
+    try!(check_code_lengths(&distance_lengths[..], true));
+    Ok(BlockCodes {
+        literal_length: PrefixCode::from_lengths(lengths),
+        distance: PrefixCode::from_lengths(distance_lengths),
+    })
+}
+
+pub fn length_symbol(len: u32) -> usize {
+    LENGTH_PREFIX.iter().rposition(|prefix| prefix.0 <= len).unwrap_or(0)
+}
+
+pub fn distance_symbol(distance: u32) -> usize {
+    DISTANCE_PREFIX.iter().rposition(|prefix| prefix.0 <= distance).unwrap_or(0)
+}
+
+pub struct Inflater {
+    pub side: BitWriter,
+    pub output: Vec<u8>,
+    pub commands: Vec<IrCommand>,
+    // the number of bytes each block decodes to, as varints
+    pub block_lengths: Vec<u8>,
+    literal_start: usize,
+}
+
+impl Inflater {
+    pub fn new(first_command: IrCommand) -> Self {
+        Inflater {
+            side: BitWriter::new(),
+            output: Vec::new(),
+            commands: vec![first_command],
+            block_lengths: Vec::new(),
+            literal_start: 0,
+        }
+    }
+    fn flush_literals(&mut self) {
+        if self.output.len() != self.literal_start {
+            self.commands.push(IrCommand::Literal(self.literal_start, self.output.len() - self.literal_start));
+            self.literal_start = self.output.len();
+        }
+    }
+    fn inflate_symbols(&mut self, reader: &mut BitReader, codes: &BlockCodes) -> Result<(), ErrMsg> {
+        loop {
+            let symbol = try!(codes.literal_length.read_symbol(reader));
+            if symbol < END_OF_BLOCK {
+                self.output.push(symbol as u8);
+                continue;
+            }
+            if symbol == END_OF_BLOCK {
+                return Ok(());
+            }
+            let (len_base, len_bits) = match LENGTH_PREFIX.get((symbol - FIRST_LENGTH_SYMBOL) as usize) {
+                Some(prefix) => *prefix,
+                None => return Err(ErrMsg::DeflateBitstreamCorrupt(6)),
+            };
+            let len = len_base + try!(reader.read_bits(len_bits));
+            if length_symbol(len) != (symbol - FIRST_LENGTH_SYMBOL) as usize {
+                // 258 spelled with symbol 284, which only some decoders accept
+                return Err(ErrMsg::DeflateUnsupported(0));
+            }
+            let (distance_base, distance_bits) = match DISTANCE_PREFIX.get(
+                try!(codes.distance.read_symbol(reader)) as usize) {
+                Some(prefix) => *prefix,
+                None => return Err(ErrMsg::DeflateBitstreamCorrupt(7)),
+            };
+            let distance = distance_base + try!(reader.read_bits(distance_bits));
+            if distance as usize > self.output.len() {
+                return Err(ErrMsg::DeflateBitstreamCorrupt(8));
+            }
+            self.flush_literals();
+            self.commands.push(IrCommand::Copy(CopyCommand{distance: distance, num_bytes: len}));
+            for _ in 0..len {
+                let byte = self.output[self.output.len() - distance as usize];
+                self.output.push(byte);
+            }
+            self.literal_start = self.output.len();
+        }
+    }
+    // decodes one DEFLATE stream, leaving the reader at the byte after it
+    pub fn inflate(&mut self, reader: &mut BitReader) -> Result<(), ErrMsg> {
+        loop {
+            let (is_last, block_type) = {
+                let mut tee = TeeReader{source: reader, sink: &mut self.side, stream_is_sink: false};
+                (try!(tee.read_bits(1)) != 0, try!(tee.read_bits(2)))
+            };
+            let start = self.output.len();
+            match block_type {
+                BLOCK_STORED => {
+                    let len = {
+                        let mut tee = TeeReader{source: reader, sink: &mut self.side, stream_is_sink: false};
+                        try!(tee.read_to_byte_boundary());
+                        let len = try!(tee.read_bits(16));
+                        if try!(tee.read_bits(16)) != !len & 0xffff {
+                            return Err(ErrMsg::DeflateBitstreamCorrupt(9));
+                        }
+                        len
+                    };
+                    self.output.extend(try!(reader.read_bytes(len as usize)));
+                },
+                BLOCK_FIXED => try!(self.inflate_symbols(reader, &fixed_codes())),
+                BLOCK_DYNAMIC => {
+                    let codes = {
+                        let mut tee = TeeReader{source: reader, sink: &mut self.side, stream_is_sink: false};
+                        try!(read_dynamic_codes(&mut tee))
+                    };
+                    try!(self.inflate_symbols(reader, &codes));
+                },
+                _ => return Err(ErrMsg::DeflateBitstreamCorrupt(10)),
+            }
+            push_varint(&mut self.block_lengths, (self.output.len() - start) as u64);
+            if is_last {
+                break;
+            }
+        }
+        let mut tee = TeeReader{source: reader, sink: &mut self.side, stream_is_sink: false};
+        try!(tee.read_to_byte_boundary());
+        Ok(())
+    }
+    pub fn finish(&mut self) {
+        self.flush_literals();
+    }
+}
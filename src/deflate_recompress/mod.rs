@@ -0,0 +1,397 @@
+// Copyright 2017 Dropbox, Inc
+//
+//   Licensed under the Apache License, Version 2.0 (the "License");
+//   you may not use this file except in compliance with the License.
+//   You may obtain a copy of the License at
+//
+//       http://www.apache.org/licenses/LICENSE-2.0
+//
+//   Unless required by applicable law or agreed to in writing, software
+//   distributed under the License is distributed on an "AS IS" BASIS,
+//   WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//   See the License for the specific language governing permissions and
+//   limitations under the License.
+
+// Lossless recompression of DEFLATE, zlib and gzip files: the LZ77 commands of the stream are
+// compressed by divans and everything else needed to rebuild the original bytes is kept as side data.
+#![cfg(not(feature="no-stdlib"))]
+mod inflate;
+mod reconstruct;
+
+use std::vec::Vec;
+use ::interface;
+use ::interface::{DivansCompressorOptions, ErrMsg};
+use ::brotli_recompress::analyze::{Analysis, IrCommand, prediction_mode_tables};
+use ::brotli_recompress::bit_io::BitReader;
+use ::brotli_recompress::{brotli_compress_side_data, brotli_decompress_side_data, decode_with_command_log,
+                          encode_analysis, read_section};
+use ::byte_util::{push_varint, read_u32, read_varint, write_u32};
+
+const GZIP_MAGIC: [u8; 3] = [0x1f, 0x8b, 8];
+const GZIP_TRAILER_LENGTH: usize = 8;
+const ZLIB_TRAILER_LENGTH: usize = 4;
+// DEFLATE distances fit in 15 bits
+const DEFLATE_WINDOW_BITS: u32 = 16;
+const UTF8_PREDICTION_MODE: u8 = 2;
+
+#[derive(Clone, Copy, PartialEq)]
+enum Wrapper {
+    Gzip,
+    Zlib,
+    Raw,
+}
+
+fn detect_wrapper(data: &[u8]) -> Wrapper {
+    if data.len() >= GZIP_MAGIC.len() && data[..GZIP_MAGIC.len()] == GZIP_MAGIC[..] {
+        return Wrapper::Gzip;
+    }
+    if data.len() >= 2 && data[0] & 0xf == 8 && data[0] >> 4 <= 7
+        && ((u32::from(data[0]) << 8) | u32::from(data[1])) % 31 == 0 {
+        return Wrapper::Zlib;
+    }
+    Wrapper::Raw
+}
+
+fn skip_zero_terminated(data: &[u8], offset: usize) -> Result<usize, ErrMsg> {
+    match data[offset..].iter().position(|byte| *byte == 0) {
+        Some(len) => Ok(offset + len + 1),
+        None => Err(ErrMsg::DeflateBitstreamCorrupt(11)),
+    }
+}
+
+fn gzip_header_length(data: &[u8]) -> Result<usize, ErrMsg> {
+    const FHCRC: u8 = 2;
+    const FEXTRA: u8 = 4;
+    const FNAME: u8 = 8;
+    const FCOMMENT: u8 = 16;
+    if data.len() < 10 || data[..GZIP_MAGIC.len()] != GZIP_MAGIC[..] {
+        return Err(ErrMsg::DeflateBitstreamCorrupt(12));
+    }
+    let flags = data[3];
+    if flags & 0xe0 != 0 {
+        return Err(ErrMsg::DeflateUnsupported(1));
+    }
+    let mut offset = 10;
+    if flags & FEXTRA != 0 {
+        if data.len() < offset + 2 {
+            return Err(ErrMsg::DeflateBitstreamCorrupt(13));
+        }
+        offset += 2 + (usize::from(data[offset]) | (usize::from(data[offset + 1]) << 8));
+    }
+    if flags & FNAME != 0 && offset < data.len() {
+        offset = try!(skip_zero_terminated(data, offset));
+    }
+    if flags & FCOMMENT != 0 && offset < data.len() {
+        offset = try!(skip_zero_terminated(data, offset));
+    }
+    if flags & FHCRC != 0 {
+        offset += 2;
+    }
+    if offset > data.len() {
+        return Err(ErrMsg::DeflateBitstreamCorrupt(13));
+    }
+    Ok(offset)
+}
+
+fn header_length(data: &[u8], wrapper: Wrapper) -> Result<usize, ErrMsg> {
+    match wrapper {
+        Wrapper::Gzip => gzip_header_length(data),
+        Wrapper::Zlib => {
+            if data.len() < 2 {
+                return Err(ErrMsg::DeflateBitstreamCorrupt(14));
+            }
+            if data[1] & 0x20 != 0 {
+                // preset dictionaries are not part of the file
+                return Err(ErrMsg::DeflateUnsupported(2));
+            }
+            Ok(2)
+        },
+        Wrapper::Raw => Ok(0),
+    }
+}
+
+fn trailer_length(wrapper: Wrapper) -> usize {
+    match wrapper {
+        Wrapper::Gzip => GZIP_TRAILER_LENGTH,
+        Wrapper::Zlib => ZLIB_TRAILER_LENGTH,
+        Wrapper::Raw => 0,
+    }
+}
+
+// the wrapper bytes around one DEFLATE stream, kept verbatim
+struct Member {
+    header: Vec<u8>,
+    trailer: Vec<u8>,
+}
+
+struct Parsed {
+    inflater: inflate::Inflater,
+    members: Vec<Member>,
+    tail: Vec<u8>,
+}
+
+fn parse(data: &[u8], wrapper: Wrapper) -> Result<Parsed, ErrMsg> {
+    let mut inflater = inflate::Inflater::new(IrCommand::PredictionMode(0));
+    let mut members = Vec::<Member>::new();
+    let mut offset = 0usize;
+    loop {
+        let header_end = offset + try!(header_length(&data[offset..], wrapper));
+        let mut reader = BitReader::new(&data[header_end..]);
+        try!(inflater.inflate(&mut reader));
+        let trailer_start = data.len() - reader.remaining_bytes().len();
+        let trailer_end = trailer_start + trailer_length(wrapper);
+        if trailer_end > data.len() {
+            return Err(ErrMsg::DeflateBitstreamCorrupt(15));
+        }
+        members.push(Member {
+            header: data[offset..header_end].to_vec(),
+            trailer: data[trailer_start..trailer_end].to_vec(),
+        });
+        offset = trailer_end;
+        // concatenated gzip members decompress to the concatenation of their contents
+        if wrapper != Wrapper::Gzip || data.len() - offset < GZIP_MAGIC.len()
+            || data[offset..offset + GZIP_MAGIC.len()] != GZIP_MAGIC[..] {
+            break;
+        }
+    }
+    inflater.finish();
+    Ok(Parsed {
+        inflater: inflater,
+        members: members,
+        tail: data[offset..].to_vec(),
+    })
+}
+
+struct SideData {
+    bits: Vec<u8>,
+    block_lengths: Vec<u8>,
+    framing: Vec<u8>,
+    tail: Vec<u8>,
+}
+
+impl SideData {
+    fn serialize(&self) -> Vec<u8> {
+        let mut ret = Vec::<u8>::with_capacity(16 + self.bits.len() + self.block_lengths.len()
+                                               + self.framing.len() + self.tail.len());
+        for item in [&self.bits, &self.block_lengths, &self.framing, &self.tail].iter() {
+            write_u32(&mut ret, item.len() as u32);
+            ret.extend(&item[..]);
+        }
+        ret
+    }
+    fn deserialize(data: &[u8]) -> Result<Self, ErrMsg> {
+        let mut offset = 0;
+        let ret = SideData {
+            bits: try!(read_section(data, &mut offset)),
+            block_lengths: try!(read_section(data, &mut offset)),
+            framing: try!(read_section(data, &mut offset)),
+            tail: try!(read_section(data, &mut offset)),
+        };
+        if offset != data.len() {
+            return Err(ErrMsg::DeflateSideDataCorrupt(8));
+        }
+        Ok(ret)
+    }
+}
+
+fn serialize_members(members: &[Member]) -> Vec<u8> {
+    let mut ret = Vec::<u8>::new();
+    push_varint(&mut ret, members.len() as u64);
+    for member in members.iter() {
+        push_varint(&mut ret, member.header.len() as u64);
+        ret.extend(&member.header[..]);
+        push_varint(&mut ret, member.trailer.len() as u64);
+        ret.extend(&member.trailer[..]);
+    }
+    ret
+}
+
+fn read_framing_bytes(framing: &[u8], offset: &mut usize) -> Result<Vec<u8>, ErrMsg> {
+    let len = match read_varint(framing, offset) {
+        Some(len) => len as usize,
+        None => return Err(ErrMsg::DeflateSideDataCorrupt(9)),
+    };
+    if framing.len() - *offset < len {
+        return Err(ErrMsg::DeflateSideDataCorrupt(10));
+    }
+    *offset += len;
+    Ok(framing[*offset - len..*offset].to_vec())
+}
+
+fn deserialize_members(framing: &[u8]) -> Result<Vec<Member>, ErrMsg> {
+    let mut offset = 0usize;
+    let count = match read_varint(framing, &mut offset) {
+        Some(count) => count as usize,
+        None => return Err(ErrMsg::DeflateSideDataCorrupt(9)),
+    };
+    let mut ret = Vec::<Member>::new();
+    for _ in 0..count {
+        let header = try!(read_framing_bytes(framing, &mut offset));
+        let trailer = try!(read_framing_bytes(framing, &mut offset));
+        ret.push(Member{header: header, trailer: trailer});
+    }
+    if offset != framing.len() {
+        return Err(ErrMsg::DeflateSideDataCorrupt(11));
+    }
+    Ok(ret)
+}
+
+pub fn is_recompressed_deflate(data: &[u8]) -> bool {
+    data.len() >= interface::RECOMPRESSED_DEFLATE_HEADER_LENGTH
+        && data[..4] == interface::RECOMPRESSED_DEFLATE_MAGIC[..]
+}
+
+// converts a raw DEFLATE, zlib or gzip file into a divans based file that reconstruct_deflate turns
+// back into exactly the same bytes; the result is verified before it is returned
+pub fn recompress_deflate(deflate_file: &[u8], opts: DivansCompressorOptions) -> Result<Vec<u8>, ErrMsg> {
+    let wrapper = detect_wrapper(deflate_file);
+    let parsed = match parse(deflate_file, wrapper) {
+        Ok(parsed) => parsed,
+        Err(e) => {
+            if wrapper == Wrapper::Raw {
+                return Err(e);
+            }
+            // a raw stream whose first bytes happen to look like a wrapper
+            match parse(deflate_file, Wrapper::Raw) {
+                Ok(parsed) => parsed,
+                Err(_) => return Err(e),
+            }
+        },
+    };
+    let side_data = SideData {
+        bits: parsed.inflater.side.into_bytes(),
+        block_lengths: parsed.inflater.block_lengths,
+        framing: serialize_members(&parsed.members[..]),
+        tail: parsed.tail,
+    };
+    let analysis = Analysis {
+        window_bits: DEFLATE_WINDOW_BITS,
+        output: parsed.inflater.output,
+        commands: parsed.inflater.commands,
+        prediction_modes: vec![prediction_mode_tables(&[0u8; 64], &[0u8; 4], UTF8_PREDICTION_MODE)],
+        side_bits: Vec::new(),
+        distance_exceptions: Vec::new(),
+        tail: Vec::new(),
+    };
+    let compressed_side_data = brotli_compress_side_data(&side_data.serialize()[..]);
+    let mut ret = Vec::<u8>::with_capacity(deflate_file.len());
+    ret.extend(&interface::RECOMPRESSED_DEFLATE_MAGIC[..]);
+    ret.extend(&[interface::RECOMPRESSED_DEFLATE_VERSION, 0, 0, 0]);
+    write_u32(&mut ret, compressed_side_data.len() as u32);
+    ret.extend(&compressed_side_data[..]);
+    try!(encode_analysis(&analysis, opts, &mut ret));
+    match reconstruct_deflate(&ret[..]) {
+        Ok(ref reconstructed) if reconstructed[..] == deflate_file[..] => Ok(ret),
+        _ => Err(ErrMsg::DeflateRoundTripMismatch),
+    }
+}
+
+pub fn reconstruct_deflate(data: &[u8]) -> Result<Vec<u8>, ErrMsg> {
+    if !is_recompressed_deflate(data) {
+        return Err(ErrMsg::MagicNumberWrongA(data.first().cloned().unwrap_or(0), data.get(1).cloned().unwrap_or(0)));
+    }
+    if data[4] != interface::RECOMPRESSED_DEFLATE_VERSION {
+        return Err(ErrMsg::UnsupportedFormatVersion(data[4]));
+    }
+    let side_len = read_u32(&data[8..]) as usize;
+    let body = &data[interface::RECOMPRESSED_DEFLATE_HEADER_LENGTH..];
+    if body.len() < side_len {
+        return Err(ErrMsg::DeflateSideDataCorrupt(12));
+    }
+    let side_data = try!(SideData::deserialize(&try!(brotli_decompress_side_data(&body[..side_len]))[..]));
+    let members = try!(deserialize_members(&side_data.framing[..]));
+    let (output, command_log) = try!(decode_with_command_log(&body[side_len..]));
+    let mut rebuilder = reconstruct::Rebuilder::new(&side_data.bits[..],
+                                                    &side_data.block_lengths[..],
+                                                    &output[..],
+                                                    &command_log[..]);
+    for member in members.iter() {
+        rebuilder.out.write_bytes(&member.header[..]);
+        try!(rebuilder.rebuild_stream());
+        rebuilder.out.write_bytes(&member.trailer[..]);
+    }
+    if !try!(rebuilder.is_done()) {
+        return Err(ErrMsg::DeflateSideDataCorrupt(13));
+    }
+    let mut ret = rebuilder.out.into_bytes();
+    ret.extend(&side_data.tail[..]);
+    Ok(ret)
+}
+
+#[cfg(test)]
+mod test {
+    use std::vec::Vec;
+    use ::interface::{DivansCompressorOptions, ErrMsg};
+    // zlib output for a short string, which uses a fixed code block
+    const FIXED_ZLIB: [u8; 27] = [0x78, 0x9c, 0xcb, 0x48, 0xcd, 0xc9, 0xc9, 0x57, 0xc8, 0x40, 0x22, 0xcb, 0xf3,
+                                  0x8b, 0x72, 0x52, 0x00, 0x68, 0x7d, 0x08, 0xc5, 0, 0, 0, 0, 0, 0];
+    fn round_trip_tst(deflate_file: &[u8], expected_output: &[u8]) -> Vec<u8> {
+        let recompressed = super::recompress_deflate(deflate_file, DivansCompressorOptions::default()).unwrap();
+        assert!(super::is_recompressed_deflate(&recompressed[..]));
+        assert_eq!(&super::reconstruct_deflate(&recompressed[..]).unwrap()[..], deflate_file);
+        let (output, _) = super::decode_with_command_log(
+            &recompressed[::interface::RECOMPRESSED_DEFLATE_HEADER_LENGTH
+                          + super::read_u32(&recompressed[8..]) as usize..]).unwrap();
+        assert_eq!(&output[..], expected_output);
+        recompressed
+    }
+    #[test]
+    fn test_recompress_gzip() {
+        let gz = include_bytes!("../../testdata/asyoulik.gz");
+        let data = include_bytes!("../../testdata/asyoulik");
+        let recompressed = round_trip_tst(&gz[..], &data[..40000]);
+        assert!(recompressed.len() < gz.len());
+    }
+    #[test]
+    fn test_recompress_zlib_and_fixed_codes() {
+        let zlib = include_bytes!("../../testdata/alice29.zlib");
+        let data = include_bytes!("../../testdata/alice29");
+        round_trip_tst(&zlib[..], &data[..50000]);
+        // the last six bytes are trailing garbage
+        round_trip_tst(&FIXED_ZLIB[..], b"hello hello hello world");
+    }
+    #[test]
+    fn test_recompress_raw_with_stored_blocks() {
+        let raw = include_bytes!("../../testdata/random_then_unicode.deflate");
+        let data = include_bytes!("../../testdata/random_then_unicode");
+        let mut expected = data[..20000].to_vec();
+        expected.extend(&data[data.len() - 30000..]);
+        round_trip_tst(&raw[..], &expected[..]);
+    }
+    #[test]
+    fn test_recompress_multi_member_gzip() {
+        let gz = include_bytes!("../../testdata/asyoulik.gz");
+        let data = include_bytes!("../../testdata/asyoulik");
+        let mut two_members = gz.to_vec();
+        two_members.extend(&gz[..]);
+        two_members.extend(b"tail");
+        let mut expected = data[..40000].to_vec();
+        expected.extend(&data[..40000]);
+        round_trip_tst(&two_members[..], &expected[..]);
+    }
+    #[test]
+    fn test_recompress_rejects_invalid() {
+        let gz = include_bytes!("../../testdata/asyoulik.gz");
+        match super::recompress_deflate(&gz[..gz.len() / 2], DivansCompressorOptions::default()) {
+            Err(_) => {},
+            Ok(_) => panic!("truncated gzip stream accepted"),
+        }
+        let mut recompressed = super::recompress_deflate(&gz[..], DivansCompressorOptions::default()).unwrap();
+        recompressed[4] = 0xff;
+        match super::reconstruct_deflate(&recompressed[..]) {
+            Err(ErrMsg::UnsupportedFormatVersion(0xff)) => {},
+            _ => panic!("unknown version accepted"),
+        }
+    }
+    #[test]
+    fn test_reconstruct_rejects_corrupt_container() {
+        let gz = include_bytes!("../../testdata/asyoulik.gz");
+        let recompressed = super::recompress_deflate(&gz[..], DivansCompressorOptions::default()).unwrap();
+        // flipped bits reach the side data, the command codes, the dictionary and the adaptation speeds
+        for index in (0..64).map(|part| 8 + (recompressed.len() - 8) * part / 64) {
+            let mut corrupt = recompressed.clone();
+            corrupt[index] ^= 0x10;
+            assert!(super::reconstruct_deflate(&corrupt[..]).is_err());
+        }
+    }
+}
@@ -0,0 +1,168 @@
+// Copyright 2017 Dropbox, Inc
+//
+//   Licensed under the Apache License, Version 2.0 (the "License");
+//   you may not use this file except in compliance with the License.
+//   You may obtain a copy of the License at
+//
+//       http://www.apache.org/licenses/LICENSE-2.0
+//
+//   Unless required by applicable law or agreed to in writing, software
+//   distributed under the License is distributed on an "AS IS" BASIS,
+//   WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//   See the License for the specific language governing permissions and
+//   limitations under the License.
+
+// Rebuilds DEFLATE streams from the side data and the commands a divans decoder executed,
+// mirroring every read the inflater made.
+use ::command_log::{LoggedCommand, parse_logged_command};
+use ::interface::{CopyCommand, ErrMsg};
+use ::brotli_recompress::bit_io::{BitReader, BitSource, BitWriter, TeeReader};
+use ::byte_util::read_varint;
+use super::inflate::{BlockCodes, BLOCK_DYNAMIC, BLOCK_FIXED, BLOCK_STORED, DISTANCE_PREFIX, END_OF_BLOCK,
+                     FIRST_LENGTH_SYMBOL, LENGTH_PREFIX, MAX_DISTANCE, distance_symbol, fixed_codes,
+                     length_symbol, read_dynamic_codes};
+
+pub struct Rebuilder<'a> {
+    side: BitReader<'a>,
+    pub out: BitWriter,
+    block_lengths: &'a [u8],
+    block_lengths_offset: usize,
+    data: &'a [u8],
+    pos: usize,
+    log: &'a [u8],
+    literals: usize,
+    copy: Option<CopyCommand>,
+}
+
+impl<'a> Rebuilder<'a> {
+    pub fn new(side_bits: &'a [u8], block_lengths: &'a [u8], data: &'a [u8], command_log: &'a [u8]) -> Self {
+        Rebuilder {
+            side: BitReader::new(side_bits),
+            out: BitWriter::new(),
+            block_lengths: block_lengths,
+            block_lengths_offset: 0,
+            data: data,
+            pos: 0,
+            log: command_log,
+            literals: 0,
+            copy: None,
+        }
+    }
+    // gathers literals until the next copy or the end of the log
+    fn fill(&mut self) -> Result<(), ErrMsg> {
+        while self.copy.is_none() {
+            let (cmd, used) = match parse_logged_command(self.log) {
+                Some(parsed) => parsed,
+                None => {
+                    if !self.log.is_empty() {
+                        return Err(ErrMsg::DeflateSideDataCorrupt(0));
+                    }
+                    return Ok(());
+                },
+            };
+            self.log = &self.log[used..];
+            match cmd {
+                LoggedCommand::Literal(len) => self.literals += len as usize,
+                LoggedCommand::Copy(copy) => self.copy = Some(copy),
+                LoggedCommand::Dict(_) => return Err(ErrMsg::DeflateSideDataCorrupt(1)),
+                LoggedCommand::BlockSwitchCommand(_) | LoggedCommand::BlockSwitchDistance(_)
+                    | LoggedCommand::BlockSwitchLiteral(_) | LoggedCommand::PredictionMode(..) => {},
+            }
+        }
+        Ok(())
+    }
+    fn take_literals(&mut self, len: usize) -> Result<(), ErrMsg> {
+        try!(self.fill());
+        if self.literals < len || self.data.len() - self.pos < len {
+            return Err(ErrMsg::DeflateSideDataCorrupt(2));
+        }
+        self.literals -= len;
+        Ok(())
+    }
+    fn rebuild_symbols(&mut self, codes: &BlockCodes, len: usize) -> Result<(), ErrMsg> {
+        let mut remaining = len;
+        while remaining != 0 {
+            try!(self.fill());
+            if self.literals != 0 {
+                let run = ::core::cmp::min(self.literals, remaining);
+                try!(self.take_literals(run));
+                for byte in self.data[self.pos..self.pos + run].iter() {
+                    try!(codes.literal_length.write_symbol(u16::from(*byte), &mut self.out));
+                }
+                self.pos += run;
+                remaining -= run;
+                continue;
+            }
+            let copy = match self.copy.take() {
+                Some(copy) => copy,
+                None => return Err(ErrMsg::DeflateSideDataCorrupt(3)),
+            };
+            let copy_len = copy.num_bytes as usize;
+            if copy_len > remaining || copy.num_bytes < LENGTH_PREFIX[0].0
+                || copy.num_bytes > LENGTH_PREFIX[LENGTH_PREFIX.len() - 1].0
+                || copy.distance == 0 || copy.distance > MAX_DISTANCE {
+                return Err(ErrMsg::DeflateSideDataCorrupt(4));
+            }
+            let len_symbol = length_symbol(copy.num_bytes);
+            try!(codes.literal_length.write_symbol(FIRST_LENGTH_SYMBOL + len_symbol as u16, &mut self.out));
+            self.out.write_bits(LENGTH_PREFIX[len_symbol].1, copy.num_bytes - LENGTH_PREFIX[len_symbol].0);
+            let dist_symbol = distance_symbol(copy.distance);
+            try!(codes.distance.write_symbol(dist_symbol as u16, &mut self.out));
+            self.out.write_bits(DISTANCE_PREFIX[dist_symbol].1, copy.distance - DISTANCE_PREFIX[dist_symbol].0);
+            self.pos += copy_len;
+            remaining -= copy_len;
+        }
+        codes.literal_length.write_symbol(END_OF_BLOCK, &mut self.out)
+    }
+    // writes one DEFLATE stream, ending on a byte boundary
+    pub fn rebuild_stream(&mut self) -> Result<(), ErrMsg> {
+        loop {
+            let (is_last, block_type) = {
+                let mut tee = TeeReader{source: &mut self.side, sink: &mut self.out, stream_is_sink: true};
+                (try!(tee.read_bits(1)) != 0, try!(tee.read_bits(2)))
+            };
+            let len = match read_varint(self.block_lengths, &mut self.block_lengths_offset) {
+                Some(len) => len as usize,
+                None => return Err(ErrMsg::DeflateSideDataCorrupt(5)),
+            };
+            match block_type {
+                BLOCK_STORED => {
+                    let stored_len = {
+                        let mut tee = TeeReader{source: &mut self.side, sink: &mut self.out, stream_is_sink: true};
+                        try!(tee.read_to_byte_boundary());
+                        let stored_len = try!(tee.read_bits(16));
+                        try!(tee.read_bits(16));
+                        stored_len as usize
+                    };
+                    if stored_len != len {
+                        return Err(ErrMsg::DeflateSideDataCorrupt(6));
+                    }
+                    try!(self.take_literals(len));
+                    self.out.write_bytes(&self.data[self.pos..self.pos + len]);
+                    self.pos += len;
+                },
+                BLOCK_FIXED => try!(self.rebuild_symbols(&fixed_codes(), len)),
+                BLOCK_DYNAMIC => {
+                    let codes = {
+                        let mut tee = TeeReader{source: &mut self.side, sink: &mut self.out, stream_is_sink: true};
+                        try!(read_dynamic_codes(&mut tee))
+                    };
+                    try!(self.rebuild_symbols(&codes, len));
+                },
+                _ => return Err(ErrMsg::DeflateSideDataCorrupt(7)),
+            }
+            if is_last {
+                break;
+            }
+        }
+        let mut tee = TeeReader{source: &mut self.side, sink: &mut self.out, stream_is_sink: true};
+        try!(tee.read_to_byte_boundary());
+        Ok(())
+    }
+    // every command and every byte of side data must have been used
+    pub fn is_done(&mut self) -> Result<bool, ErrMsg> {
+        try!(self.fill());
+        Ok(self.literals == 0 && self.copy.is_none() && self.log.is_empty() && self.pos == self.data.len()
+           && self.block_lengths_offset == self.block_lengths.len())
+    }
+}
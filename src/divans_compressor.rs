@@ -23,6 +23,7 @@ use super::mux::{Mux,DevNull};
 use super::raw_to_cmd;
 use super::slice_util;
 use super::alloc_util::RepurposingAlloc;
+use super::byte_util::u32_bytes;
 pub use super::alloc::{AllocatedStackMemory, Allocator, SliceWrapper, SliceWrapperMut, StackAllocator};
 use codec::io::DemuxerAndRingBuffer;
 use brotli;
@@ -47,18 +48,24 @@ pub use super::interface::{
     };
 
 pub use super::cmd_to_divans::EncoderSpecialization;
-pub use codec::{EncoderOrDecoderSpecialization, DivansCodec, StrideSelection, default_crc, CommandArray, CommandSliceArray};
+pub use codec::{EncoderOrDecoderSpecialization, DivansCodec, StrideSelection, default_crc, CommandArray, CommandSliceArray, PriorSnapshotInfo};
 use super::interface;
-use super::interface::{DivansOutputResult, DivansResult, ErrMsg};
+use super::interface::{DivansOutputResult, DivansResult, ErrMsg, StreamDemuxer, StreamMuxer};
+use threading::ThreadToMain;
 const COMPRESSOR_CMD_BUFFER_SIZE : usize = 16;
 pub struct DivansCompressor<DefaultEncoder: ArithmeticEncoderOrDecoder + NewWithAllocator<AllocU8>,
                             AllocU8:Allocator<u8>,
                             AllocU32:Allocator<u32>,
-                            AllocCDF16:Allocator<interface::DefaultCDF16>> {
+                            AllocCDF16:Allocator<interface::DefaultCDF16>,
+                            LinearInputBytes:StreamDemuxer<AllocU8>+ThreadToMain<AllocU8>=DemuxerAndRingBuffer<AllocU8, DevNull<AllocU8>>,
+                            LinearOutputBytes:StreamMuxer<AllocU8>+Default=Mux<AllocU8>> {
     m32: AllocU32,
-    codec: DivansCodec<DefaultEncoder, EncoderSpecialization, DemuxerAndRingBuffer<AllocU8, DevNull<AllocU8>>, Mux<AllocU8>, interface::DefaultCDF16, AllocU8, AllocCDF16>,
+    codec: DivansCodec<DefaultEncoder, EncoderSpecialization, LinearInputBytes, LinearOutputBytes, interface::DefaultCDF16, AllocU8, AllocCDF16>,
     header_progress: usize,
     window_size: u8,
+    features: interface::StreamFeatures,
+    content: interface::ContentDigest,
+    trailer_progress: usize,
     literal_context_map_backing: AllocU8::AllocatedMemory,
     prediction_mode_backing: AllocU8::AllocatedMemory,
     cmd_assembler: raw_to_cmd::RawToCmdState<AllocU8::AllocatedMemory, AllocU32>,
@@ -84,49 +91,27 @@ impl<AllocU8:Allocator<u8>,
      type DefaultEncoder = DefaultEncoderType!();
      type ConstructedCompressor = DivansCompressor<Self::DefaultEncoder, AllocU8, AllocU32, AllocCDF16>;
      type AdditionalArgs = ();
-     fn new(mut m8: AllocU8, mut m32: AllocU32, mcdf16:AllocCDF16,
+     fn new(m8: AllocU8, m32: AllocU32, mcdf16:AllocCDF16,
             opts: super::interface::DivansCompressorOptions,
             _additional_args: ()) -> DivansCompressor<Self::DefaultEncoder, AllocU8, AllocU32, AllocCDF16> {
-         let window_size = core::cmp::min(24, core::cmp::max(10, opts.window_size.unwrap_or(22)));
-         let ring_buffer = m8.alloc_cell(1<<window_size);
-         let prediction_mode_backing = m8.alloc_cell(interface::MAX_PREDMODE_SPEED_AND_DISTANCE_CONTEXT_MAP_SIZE);
-         let literal_context_map = m8.alloc_cell(interface::MAX_LITERAL_CONTEXT_MAP_SIZE);
-         let cmd_enc = Self::DefaultEncoder::new(&mut m8);
-         let lit_enc = Self::DefaultEncoder::new(&mut m8);
-         let assembler = raw_to_cmd::RawToCmdState::new(&mut m32, ring_buffer);
-         DivansCompressor::<Self::DefaultEncoder, AllocU8, AllocU32, AllocCDF16> {
-            m32 :m32,
-            codec:DivansCodec::<Self::DefaultEncoder, EncoderSpecialization, DemuxerAndRingBuffer<AllocU8, DevNull<AllocU8>>, Mux<AllocU8>, interface::DefaultCDF16, AllocU8, AllocCDF16>::new(
-                m8,
-                mcdf16,
-                cmd_enc,
-                lit_enc,
-                EncoderSpecialization::new(),
-                DemuxerAndRingBuffer::<AllocU8, DevNull<AllocU8>>::default(),
-                window_size as usize,
-                opts.dynamic_context_mixing.unwrap_or(0),
-                opts.prior_depth,
-                opts.literal_adaptation,
-                opts.use_context_map,
-                opts.force_stride_value,
-                false,
-            ),
-            literal_context_map_backing: literal_context_map,
-            prediction_mode_backing: prediction_mode_backing,
-            freeze_dried_cmd_array:[interface::Command::<slice_util::SliceReference<'static, u8>>::default(); COMPRESSOR_CMD_BUFFER_SIZE],
-            freeze_dried_cmd_start:0,
-            freeze_dried_cmd_end:0,
-            cmd_assembler:assembler,
-            header_progress: 0,
-            window_size: window_size as u8,
-        }
+         DivansCompressor::new(m8, m32, mcdf16, opts,
+                               DemuxerAndRingBuffer::<AllocU8, DevNull<AllocU8>>::default(),
+                               Mux::<AllocU8>::default())
      }
 }
 
-pub fn make_header(window_size: u8) -> [u8; interface::HEADER_LENGTH] {
+pub fn make_header(window_size: u8, features: interface::StreamFeatures) -> [u8; interface::HEADER_LENGTH] {
     let mut retval = [0u8; interface::HEADER_LENGTH];
     retval[0..interface::MAGIC_NUMBER.len()].clone_from_slice(&interface::MAGIC_NUMBER[..]);
-    retval[5] = window_size;
+    retval[interface::HEADER_VERSION_INDEX] = interface::FORMAT_VERSION;
+    retval[interface::HEADER_WINDOW_SIZE_INDEX] = window_size;
+    retval[interface::HEADER_FEATURE_FLAGS_INDEX..(interface::HEADER_FEATURE_FLAGS_INDEX + interface::HEADER_FEATURE_FLAGS_LENGTH)]
+        .clone_from_slice(&features.to_bytes()[..]);
+    if let Some(id) = features.prior_snapshot_id {
+        retval[interface::HEADER_PRIOR_SNAPSHOT_ID_INDEX..
+               (interface::HEADER_PRIOR_SNAPSHOT_ID_INDEX + interface::HEADER_PRIOR_SNAPSHOT_ID_LENGTH)]
+            .clone_from_slice(&u32_bytes(id)[..]);
+    }
     retval
 }
 fn thaw_commands<'a>(input: &[Command<slice_util::SliceReference<'static, u8>>], ring_buffer: &'a[u8], start_index:  usize, end_index: usize) -> [Command<InputReference<'a>>; COMPRESSOR_CMD_BUFFER_SIZE] {
@@ -149,12 +134,13 @@ fn freeze<'a>(item: &FeatureFlagSliceType<InputReference<'a>>) -> FeatureFlagSli
 
 pub fn write_header<CRC:Hasher>(header_progress: &mut usize,
                                 window_size: u8,
+                                features: interface::StreamFeatures,
                                 output: &mut[u8],
                                 output_offset:&mut usize,
                                 crc: &mut CRC) -> DivansOutputResult {
     let bytes_avail = output.len() - *output_offset;
     if bytes_avail + *header_progress < interface::HEADER_LENGTH {
-        let to_write = &make_header(window_size)[*header_progress..
+        let to_write = &make_header(window_size, features)[*header_progress..
                                                  (*header_progress + bytes_avail)];
         crc.write(to_write);
         output.split_at_mut(*output_offset).1.clone_from_slice(
@@ -163,7 +149,7 @@ pub fn write_header<CRC:Hasher>(header_progress: &mut usize,
         *header_progress += bytes_avail;
         return DivansOutputResult::NeedsMoreOutput;
     }
-    let to_write = &make_header(window_size)[*header_progress..];
+    let to_write = &make_header(window_size, features)[*header_progress..];
     output[*output_offset..(*output_offset + interface::HEADER_LENGTH - *header_progress)].clone_from_slice(
         to_write);
     crc.write(to_write);
@@ -173,6 +159,22 @@ pub fn write_header<CRC:Hasher>(header_progress: &mut usize,
 
 }
 
+pub fn write_trailer(trailer_progress: &mut usize,
+                     summary: interface::ContentSummary,
+                     output: &mut[u8],
+                     output_offset:&mut usize) -> DivansOutputResult {
+    let trailer = summary.to_trailer();
+    let to_write = core::cmp::min(interface::TRAILER_LENGTH - *trailer_progress, output.len() - *output_offset);
+    output[*output_offset..(*output_offset + to_write)].clone_from_slice(
+        &trailer[*trailer_progress..(*trailer_progress + to_write)]);
+    *output_offset += to_write;
+    *trailer_progress += to_write;
+    if *trailer_progress != interface::TRAILER_LENGTH {
+        return DivansOutputResult::NeedsMoreOutput;
+    }
+    DivansOutputResult::Success
+}
+
 struct InputReferenceCommandArray<'a>(&'a [Command<InputReference<'a>>]);
 
 impl<'a> CommandArray for InputReferenceCommandArray<'a> {
@@ -184,8 +186,130 @@ impl<'a> CommandArray for InputReferenceCommandArray<'a> {
     }
 }
 
-impl<DefaultEncoder: ArithmeticEncoderOrDecoder + NewWithAllocator<AllocU8>, AllocU8:Allocator<u8>, AllocU32:Allocator<u32>, AllocCDF16:Allocator<interface::DefaultCDF16>> 
-    DivansCompressor<DefaultEncoder, AllocU8, AllocU32, AllocCDF16> {
+impl<DefaultEncoder: ArithmeticEncoderOrDecoder + NewWithAllocator<AllocU8>,
+     AllocU8:Allocator<u8>,
+     AllocU32:Allocator<u32>,
+     AllocCDF16:Allocator<interface::DefaultCDF16>,
+     LinearInputBytes:StreamDemuxer<AllocU8>+ThreadToMain<AllocU8>,
+     LinearOutputBytes:StreamMuxer<AllocU8>+Default>
+    DivansCompressor<DefaultEncoder, AllocU8, AllocU32, AllocCDF16, LinearInputBytes, LinearOutputBytes> {
+    // the codec reads commands through demuxer and writes the stream through muxer
+    pub fn new(mut m8: AllocU8, m32: AllocU32, mcdf16:AllocCDF16,
+               opts: super::interface::DivansCompressorOptions,
+               demuxer: LinearInputBytes,
+               muxer: LinearOutputBytes) -> Self {
+        let window_size = core::cmp::min(24, core::cmp::max(10, opts.window_size.unwrap_or(22)));
+        // the command assembler keeps a window of history in front of the data it is parsing
+        let ring_buffer = m8.alloc_cell(1<<(window_size + 1));
+        let prediction_mode_backing = m8.alloc_cell(interface::MAX_PREDMODE_SPEED_AND_DISTANCE_CONTEXT_MAP_SIZE);
+        let literal_context_map = m8.alloc_cell(interface::MAX_LITERAL_CONTEXT_MAP_SIZE);
+        let features = opts.stream_features();
+        let cmd_enc = DefaultEncoder::new_with_entropy_coder(&mut m8, features.entropy_coder);
+        let lit_enc = DefaultEncoder::new_with_entropy_coder(&mut m8, features.entropy_coder);
+        let assembler = raw_to_cmd::RawToCmdState::new(ring_buffer, opts.quality.unwrap_or(11));
+        let mut ret = DivansCompressor::<DefaultEncoder, AllocU8, AllocU32, AllocCDF16, LinearInputBytes, LinearOutputBytes> {
+            m32 :m32,
+            codec:DivansCodec::<DefaultEncoder, EncoderSpecialization, LinearInputBytes, LinearOutputBytes, interface::DefaultCDF16, AllocU8, AllocCDF16>::new(
+                m8,
+                mcdf16,
+                cmd_enc,
+                lit_enc,
+                EncoderSpecialization::new(),
+                demuxer,
+                window_size as usize,
+                opts.dynamic_context_mixing.unwrap_or(0),
+                opts.prior_depth,
+                opts.literal_adaptation,
+                opts.use_context_map,
+                opts.force_stride_value,
+                false,
+            ),
+            literal_context_map_backing: literal_context_map,
+            prediction_mode_backing: prediction_mode_backing,
+            freeze_dried_cmd_array:[interface::Command::<slice_util::SliceReference<'static, u8>>::default(); COMPRESSOR_CMD_BUFFER_SIZE],
+            freeze_dried_cmd_start:0,
+            freeze_dried_cmd_end:0,
+            cmd_assembler:assembler,
+            header_progress: 0,
+            window_size: window_size as u8,
+            features: features,
+            content: interface::ContentDigest::default(),
+            trailer_progress: 0,
+        };
+        ret.codec.cross_command_state.muxer = muxer;
+        ret.codec.set_cdf_flavor(features.cdf_flavor);
+        ret.codec.set_literal_mixer(features.literal_mixer);
+        ret.codec.set_literal_match(features.literal_match);
+        ret.codec.set_literal_sse(features.literal_sse);
+        ret.codec.set_literal_hash_size(features.literal_hash_size);
+        ret.codec.set_sync_points(features.sync_points);
+        ret
+    }
+    // encodes the header and every command the assembler can produce from the input so far
+    fn flush_pending_commands(&mut self,
+                              output: &mut [u8],
+                              output_offset: &mut usize) -> DivansOutputResult {
+        if self.header_progress != interface::HEADER_LENGTH {
+            match write_header(&mut self.header_progress, self.window_size, self.features, output, output_offset,
+                               self.codec.get_crc()) {
+                DivansOutputResult::Success => {},
+                res => return res,
+            }
+        }
+        match self.flush_freeze_dried_cmds(output, output_offset) {
+               DivansOutputResult::Success => {},
+               res => return res,
+        }
+        loop {
+            let literal_context_map_backing = self.literal_context_map_backing.slice_mut();
+            let prediction_mode_backing = self.prediction_mode_backing.slice_mut();
+            let mut temp_bs: [interface::Command<InputReference>;COMPRESSOR_CMD_BUFFER_SIZE] =
+                [interface::Command::<InputReference>::default();COMPRESSOR_CMD_BUFFER_SIZE];
+            let mut temp_cmd_offset = 0;
+            let command_flush_ret = self.cmd_assembler.flush(&mut self.m32, &mut temp_bs[..], &mut temp_cmd_offset, literal_context_map_backing, prediction_mode_backing);
+            match command_flush_ret {
+                DivansOutputResult::Success => {
+                    if temp_cmd_offset == 0 {
+                        break; // no output from the cmd_assembler, just plain flush the codec
+                    }
+                },
+                DivansOutputResult::Failure(m) => {
+                    return DivansOutputResult::Failure(m); // we are never done
+                },
+                DivansOutputResult::NeedsMoreOutput => {},
+            }
+            let mut out_cmd_offset = 0;
+            let mut zero: usize = 0;
+            let codec_ret = self.codec.encode_or_decode(&[],
+                                                        &mut zero,
+                                                        output,
+                                                        output_offset,
+                                                        &InputReferenceCommandArray(temp_bs.split_at(temp_cmd_offset).0),
+                                                        &mut out_cmd_offset);
+            match codec_ret {
+                DivansResult::Success | DivansResult::NeedsMoreInput => {
+                    assert_eq!(temp_cmd_offset, out_cmd_offset); // must have consumed all commands
+                    if let DivansOutputResult::Success = command_flush_ret {
+                         break; // we've exhausted all commands and all input
+                    }
+                },
+                DivansResult::NeedsMoreOutput | DivansResult::Failure(_) => {
+                    Self::freeze_dry(
+                        &mut self.freeze_dried_cmd_array,
+                        &mut self.freeze_dried_cmd_start,
+                        &mut self.freeze_dried_cmd_end,
+                        &temp_bs[out_cmd_offset..temp_cmd_offset]);
+                    match codec_ret {
+                        DivansResult::Success | DivansResult::NeedsMoreInput => return DivansOutputResult::Failure(
+                            ErrMsg::WrongInternalEncoderState(0)),
+                        DivansResult::NeedsMoreOutput => return DivansOutputResult::NeedsMoreOutput,
+                        DivansResult::Failure(m) => return DivansOutputResult::Failure(m),
+                    }
+                }
+            }
+        }
+        DivansOutputResult::Success
+    }
     fn flush_freeze_dried_cmds(&mut self, output: &mut [u8], output_offset: &mut usize) -> interface::DivansOutputResult {
         if self.freeze_dried_cmd_start != self.freeze_dried_cmd_end { // we have some freeze dried items
             let thawed_buffer = thaw_commands(&self.freeze_dried_cmd_array[..], self.cmd_assembler.ring_buffer.slice(),
@@ -247,6 +371,29 @@ impl<DefaultEncoder: ArithmeticEncoderOrDecoder + NewWithAllocator<AllocU8>, All
     pub fn get_m8(&mut self) -> Option<&mut RepurposingAlloc<u8, AllocU8>> {
        self.codec.get_m8()
     }
+    // starts the stream from a prior snapshot, whose id and cdf flavor go in the header;
+    // must be called before anything is encoded
+    pub fn load_prior_snapshot(&mut self, prior_snapshot: &[u8]) -> Result<(), ErrMsg> {
+        if self.header_progress != 0 {
+            return Err(ErrMsg::WrongInternalEncoderState(2));
+        }
+        match self.codec.load_prior_snapshot(prior_snapshot) {
+            Ok(info) => {
+                self.features.cdf_flavor = info.cdf_flavor;
+                self.features.prior_snapshot_id = Some(info.id);
+                Ok(())
+            },
+            Err(e) => Err(e),
+        }
+    }
+    // serializes the priors the stream has adapted so far as a prior snapshot
+    pub fn write_prior_snapshot<Sink:FnMut(&[u8])>(&self, sink: &mut Sink) -> Result<PriorSnapshotInfo, ErrMsg> {
+        self.codec.write_prior_snapshot(sink)
+    }
+    // writes out the literals a coder on another thread still owes the stream
+    pub fn drain_remote_coders(&mut self, output: &mut [u8], output_offset: &mut usize) -> DivansOutputResult {
+        self.codec.drain_remote_coders(output, output_offset)
+    }
     pub fn free_ref(&mut self) {
         self.cmd_assembler.free(&mut self.m32);
         self.codec.get_m8().as_mut().unwrap().get_base_alloc().free_cell(core::mem::replace(&mut self.cmd_assembler.ring_buffer, AllocU8::AllocatedMemory::default()));
@@ -269,17 +416,21 @@ impl<DefaultEncoder: ArithmeticEncoderOrDecoder + NewWithAllocator<AllocU8>, All
 impl<DefaultEncoder: ArithmeticEncoderOrDecoder + NewWithAllocator<AllocU8>,
      AllocU8:Allocator<u8>,
      AllocU32:Allocator<u32>,
-     AllocCDF16:Allocator<interface::DefaultCDF16>> Compressor for DivansCompressor<DefaultEncoder,
-                                                                                    AllocU8,
-                                                                                    AllocU32,
-                                                                                    AllocCDF16> {
+     AllocCDF16:Allocator<interface::DefaultCDF16>,
+     LinearInputBytes:StreamDemuxer<AllocU8>+ThreadToMain<AllocU8>,
+     LinearOutputBytes:StreamMuxer<AllocU8>+Default> Compressor for DivansCompressor<DefaultEncoder,
+                                                                                     AllocU8,
+                                                                                     AllocU32,
+                                                                                     AllocCDF16,
+                                                                                     LinearInputBytes,
+                                                                                     LinearOutputBytes> {
     fn encode(&mut self,
               input: &[u8],
               input_offset: &mut usize,
               output: &mut [u8],
               output_offset: &mut usize) -> DivansResult {
         if self.header_progress != interface::HEADER_LENGTH {
-            match write_header(&mut self.header_progress, self.window_size, output, output_offset,
+            match write_header(&mut self.header_progress, self.window_size, self.features, output, output_offset,
                                self.codec.get_crc()) {
                 DivansOutputResult::Success => {},
                 res => return DivansResult::from(res),
@@ -295,9 +446,13 @@ impl<DefaultEncoder: ArithmeticEncoderOrDecoder + NewWithAllocator<AllocU8>,
             let mut temp_bs: [interface::Command<InputReference>;COMPRESSOR_CMD_BUFFER_SIZE] =
                 [interface::Command::<InputReference>::default();COMPRESSOR_CMD_BUFFER_SIZE];
             let mut temp_cmd_offset = 0;
-            let command_decode_ret = self.cmd_assembler.stream(input, input_offset,
+            let old_input_offset = *input_offset;
+            let command_decode_ret = self.cmd_assembler.stream(&mut self.m32, input, input_offset,
                                                                &mut temp_bs[..], &mut temp_cmd_offset,
                                                                literal_context_map, prediction_mode_backing);
+            if self.features.trailer_metadata {
+                self.content.write(&input[old_input_offset..*input_offset]);
+            }
             match command_decode_ret {
                 DivansResult::NeedsMoreInput => {
                     if temp_cmd_offset == 0 {
@@ -340,9 +495,13 @@ impl<DefaultEncoder: ArithmeticEncoderOrDecoder + NewWithAllocator<AllocU8>,
                                           input_offset : &mut usize,
                                           output :&mut[u8],
                                           output_offset: &mut usize) -> DivansOutputResult{
+        if self.features.trailer_metadata {
+            // the hash covers the raw input, which commands alone do not provide
+            return DivansOutputResult::Failure(ErrMsg::ContentTrailerNeedsRawInput);
+        }
         self.cmd_assembler.raw_input_ir_mode();
         if self.header_progress != interface::HEADER_LENGTH {
-            match write_header(&mut self.header_progress, self.window_size, output, output_offset,
+            match write_header(&mut self.header_progress, self.window_size, self.features, output, output_offset,
                                self.codec.get_crc()) {
                 DivansOutputResult::Success => {},
                 res => return res,
@@ -363,66 +522,27 @@ impl<DefaultEncoder: ArithmeticEncoderOrDecoder + NewWithAllocator<AllocU8>,
     fn flush(&mut self,
              output: &mut [u8],
              output_offset: &mut usize) -> DivansOutputResult {
-        if self.header_progress != interface::HEADER_LENGTH {
-            match write_header(&mut self.header_progress, self.window_size, output, output_offset,
-                               self.codec.get_crc()) {
-                DivansOutputResult::Success => {},
-                res => return res,
-            }
+        match self.flush_pending_commands(output, output_offset) {
+            DivansOutputResult::Success => {},
+            res => return res,
         }
-        match self.flush_freeze_dried_cmds(output, output_offset) {
-               DivansOutputResult::Success => {},
-               res => return res,
+        match self.codec.flush(output, output_offset) {
+            DivansOutputResult::Success => {},
+            res => return res,
         }
-        loop {
-            let literal_context_map_backing = self.literal_context_map_backing.slice_mut();
-            let prediction_mode_backing = self.prediction_mode_backing.slice_mut();
-            let mut temp_bs: [interface::Command<InputReference>;COMPRESSOR_CMD_BUFFER_SIZE] =
-                [interface::Command::<InputReference>::default();COMPRESSOR_CMD_BUFFER_SIZE];
-            let mut temp_cmd_offset = 0;
-            let command_flush_ret = self.cmd_assembler.flush(&mut temp_bs[..], &mut temp_cmd_offset, literal_context_map_backing, prediction_mode_backing);
-            match command_flush_ret {
-                DivansOutputResult::Success => {
-                    if temp_cmd_offset == 0 {
-                        break; // no output from the cmd_assembler, just plain flush the codec
-                    }
-                },
-                DivansOutputResult::Failure(m) => {
-                    return DivansOutputResult::Failure(m); // we are never done
-                },
-                DivansOutputResult::NeedsMoreOutput => {},
-            }
-            let mut out_cmd_offset = 0;
-            let mut zero: usize = 0;
-            let codec_ret = self.codec.encode_or_decode(&[],
-                                                        &mut zero,
-                                                        output,
-                                                        output_offset,
-                                                        &InputReferenceCommandArray(temp_bs.split_at(temp_cmd_offset).0),
-                                                        &mut out_cmd_offset);
-            match codec_ret {
-                DivansResult::Success | DivansResult::NeedsMoreInput => {
-                    assert_eq!(temp_cmd_offset, out_cmd_offset); // must have consumed all commands
-                    if let DivansOutputResult::Success = command_flush_ret {
-                         break; // we've exhausted all commands and all input
-                    }
-                },
-                DivansResult::NeedsMoreOutput | DivansResult::Failure(_) => {
-                    Self::freeze_dry(
-                        &mut self.freeze_dried_cmd_array,
-                        &mut self.freeze_dried_cmd_start,
-                        &mut self.freeze_dried_cmd_end,
-                        &temp_bs[out_cmd_offset..temp_cmd_offset]);
-                    match codec_ret {
-                        DivansResult::Success | DivansResult::NeedsMoreInput => return DivansOutputResult::Failure(
-                            ErrMsg::WrongInternalEncoderState(0)),
-                        DivansResult::NeedsMoreOutput => return DivansOutputResult::NeedsMoreOutput,
-                        DivansResult::Failure(m) => return DivansOutputResult::Failure(m),
-                    }
-                }
-            }
+        if !self.features.trailer_metadata {
+            return DivansOutputResult::Success;
+        }
+        write_trailer(&mut self.trailer_progress, self.content.summary(), output, output_offset)
+    }
+    fn sync_flush(&mut self,
+                  output: &mut [u8],
+                  output_offset: &mut usize) -> DivansOutputResult {
+        match self.flush_pending_commands(output, output_offset) {
+            DivansOutputResult::Success => {},
+            res => return res,
         }
-        self.codec.flush(output, output_offset)
+        self.codec.sync_flush(output, output_offset)
     }
 }
 
@@ -7,22 +7,24 @@ use ::DecoderSpecialization;
 use ::codec;
 use super::mux::{Mux,DevNull};
 use codec::decoder::{DecoderResult, DivansDecoderCodec};
-use threading::{ThreadToMainDemuxer, SerialWorker};
+use threading::{ThreadToMainDemuxer, SerialWorker, WorkerSpawner, DefaultSpawner, NUM_SERIAL_COMMANDS_BUFFERED};
+#[cfg(not(feature="no-stdlib"))]
+use threading::StdThreadSpawner;
+use ::resizable_buffer::ResizableByteBuffer;
+use ::byte_util::read_u32;
 
 
-use ::interface::{DivansResult, DivansOpResult, DivansInputResult, ErrMsg};
+use ::interface::{DivansResult, DivansOpResult, DivansInputResult, DivansOutputResult, ErrMsg};
 use ::ArithmeticEncoderOrDecoder;
-use ::alloc::{Allocator};
+use ::alloc::{Allocator, SliceWrapper, SliceWrapperMut};
 pub use threading::StaticCommand;
 
-#[cfg(not(feature="no-stdlib"))]
-use parallel_decompressor::{ParallelDivansProcess};
-#[cfg(feature="no-stdlib")]
-use stub_parallel_decompressor::{ParallelDivansProcess};
+use parallel_decompressor::{ParallelDivansProcess, WorkerCodec};
 
 pub struct HeaderParser<AllocU8:Allocator<u8>,
                         AllocCDF16:Allocator<interface::DefaultCDF16>,
-                        AllocCommand:Allocator<StaticCommand>> {
+                        AllocCommand:Allocator<StaticCommand>,
+                        Spawner:WorkerSpawner=DefaultSpawner> {
     pub header:[u8;interface::HEADER_LENGTH],
     pub read_offset: usize,
     pub m8: Option<AllocU8>,
@@ -30,11 +32,148 @@ pub struct HeaderParser<AllocU8:Allocator<u8>,
     pub mcommand: Option<AllocCommand>,
     pub skip_crc: bool,
     pub multithread: bool,
+    // starts the command thread of each multithreaded frame; without one every frame is decoded serially
+    pub spawner: Option<Spawner>,
+    // number of complete frames that preceded this header in a seekable stream
+    pub frames_decoded: u32,
+    pub seek_table_remaining: usize,
+    // the previous frame set the framed flag, so another frame or the seek table follows it
+    pub more_frames: bool,
+    pub log_commands: bool,
+    // commands logged by earlier frames, handed to the codec of the next frame
+    pub command_log: Option<ResizableByteBuffer<u8, AllocU8>>,
+    // the prior snapshot frames naming prior_snapshot_info in their header start from, carried like command_log
+    pub prior_snapshot: AllocU8::AllocatedMemory,
+    pub prior_snapshot_info: Option<codec::PriorSnapshotInfo>,
+    // how the priors of this frame adapt and which entropy coder it uses, as named by its header
+    pub features: interface::StreamFeatures,
+    pub limits: interface::DivansDecompressorLimits,
+    // bytes produced by the stream so far, checked against limits.max_output_size
+    pub bytes_decoded: u64,
+    // compressed bytes taken from the input so far
+    pub bytes_consumed: u64,
+    // stream position of input offset zero in the current call to decode
+    pub input_base: u64,
+    // stream position where the frame being decoded begins, including any trailer of the frame before it
+    pub frame_start: u64,
+    // commands executed by the frames before this header
+    pub commands_decoded: u64,
+    // where the most recent failure happened, once decode has reported one
+    pub last_error: Option<interface::DivansError>,
+    // content of the previous frame, to be checked against the trailer that precedes this header
+    pub content: Option<interface::ContentDigest>,
+    // the verified trailer of the most recent frame that carried one
+    pub content_summary: Option<interface::ContentSummary>,
 }
 
 impl<AllocU8:Allocator<u8>,
      AllocCDF16:Allocator<interface::DefaultCDF16>,
-     AllocCommand:Allocator<StaticCommand>>HeaderParser<AllocU8, AllocCDF16, AllocCommand> {
+     AllocCommand:Allocator<StaticCommand>,
+     Spawner:WorkerSpawner>HeaderParser<AllocU8, AllocCDF16, AllocCommand, Spawner> {
+    pub fn without_allocators(&self, frames_decoded: u32) -> Self {
+        HeaderParser{header:[0u8;interface::HEADER_LENGTH], read_offset:0,
+                     m8:None, mcdf16:None, mcommand:None,
+                     skip_crc:self.skip_crc,
+                     multithread:self.multithread,
+                     spawner:self.spawner.clone(),
+                     frames_decoded:frames_decoded,
+                     seek_table_remaining:0,
+                     more_frames:self.more_frames,
+                     log_commands:self.log_commands,
+                     command_log:None,
+                     prior_snapshot:AllocU8::AllocatedMemory::default(),
+                     prior_snapshot_info:self.prior_snapshot_info,
+                     features:interface::StreamFeatures::compiled(),
+                     limits:self.limits,
+                     bytes_decoded:self.bytes_decoded,
+                     bytes_consumed:self.bytes_consumed,
+                     input_base:self.input_base,
+                     frame_start:self.frame_start,
+                     commands_decoded:self.commands_decoded,
+                     last_error:self.last_error,
+                     content:self.content,
+                     content_summary:self.content_summary,
+        }
+    }
+    // the parser for the header after the frame this one just parsed
+    pub fn following_frame_header(&self) -> Self {
+        let mut ret = self.without_allocators(self.frames_decoded + 1);
+        ret.more_frames = self.features.framed;
+        if self.features.trailer_metadata {
+            ret.content = Some(interface::ContentDigest::default());
+        }
+        ret
+    }
+    pub fn record_output(&mut self, output: &[u8]) {
+        if let Some(ref mut content) = self.content {
+            content.write(output);
+        }
+    }
+    // what the decoder allocates up front for a frame with this window, whatever the frame contains
+    pub fn fixed_memory(window_size: usize) -> usize {
+        codec::model_allocation_size::<interface::DefaultCDF16, AllocCDF16>(window_size)
+            + interface::MAX_LITERAL_CONTEXT_MAP_SIZE + interface::MAX_PREDMODE_SPEED_AND_DISTANCE_CONTEXT_MAP_SIZE
+            + 2 * NUM_SERIAL_COMMANDS_BUFFERED * core::mem::size_of::<StaticCommand>()
+    }
+    // what the decoder allocates up front for the frame whose header was just parsed
+    pub fn frame_memory(&self, window_size: usize) -> usize {
+        let mixer_memory = if self.features.literal_mixer {
+            codec::literal_mixer_allocation_size::<interface::DefaultCDF16, AllocCDF16>()
+        } else {
+            0
+        };
+        let match_memory = if self.features.literal_match {
+            codec::literal_match_allocation_size::<interface::DefaultCDF16, AllocCDF16>()
+        } else {
+            0
+        };
+        let sse_memory = if self.features.literal_sse {
+            codec::literal_sse_allocation_size::<interface::DefaultCDF16, AllocCDF16>()
+        } else {
+            0
+        };
+        Self::fixed_memory(window_size) + mixer_memory + match_memory + sse_memory
+            + codec::literal_hash_allocation_size::<interface::DefaultCDF16>(self.features.literal_hash_size)
+            + self.prior_snapshot.slice().len()
+    }
+    // the longest literal a frame with this window may allocate without exceeding max_memory_estimate
+    pub fn max_literal_bytes(&self, window_size: usize) -> usize {
+        match self.limits.max_memory_estimate {
+            Some(max_memory) => max_memory.saturating_sub(self.frame_memory(window_size)),
+            None => usize::MAX,
+        }
+    }
+    // true when a complete frame (and any seek table) has been consumed and no new header is started
+    pub fn at_frame_boundary(&self) -> bool {
+        self.frames_decoded != 0 && self.read_offset == 0 && self.seek_table_remaining == 0 && self.content.is_none()
+    }
+    // reads the trailer of the previous frame and compares it with the content that was decoded
+    fn check_trailer(&mut self, input:&[u8], input_offset:&mut usize) -> DivansInputResult {
+        let content = match self.content {
+            Some(content) => content,
+            None => return DivansInputResult::Success,
+        };
+        let to_copy = core::cmp::min(interface::TRAILER_LENGTH - self.read_offset, input.len() - *input_offset);
+        self.header[self.read_offset..(self.read_offset + to_copy)].clone_from_slice(
+            &input[*input_offset..(*input_offset + to_copy)]);
+        *input_offset += to_copy;
+        self.read_offset += to_copy;
+        if self.read_offset != interface::TRAILER_LENGTH {
+            return DivansInputResult::NeedsMoreInput;
+        }
+        self.read_offset = 0;
+        self.content = None;
+        let expected = interface::ContentSummary::from_trailer(&self.header[..interface::TRAILER_LENGTH]);
+        let actual = content.summary();
+        if expected.length != actual.length {
+            return DivansInputResult::Failure(ErrMsg::ContentLengthMismatch);
+        }
+        if expected.hash != actual.hash {
+            return DivansInputResult::Failure(ErrMsg::ContentHashMismatch);
+        }
+        self.content_summary = Some(actual);
+        DivansInputResult::Success
+    }
     pub fn parse_header(&mut self)->Result<usize, DivansOpResult>{
         if self.header[0] != interface::MAGIC_NUMBER[0] ||
             self.header[1] != interface::MAGIC_NUMBER[1] {
@@ -44,10 +183,56 @@ impl<AllocU8:Allocator<u8>,
             self.header[3] != interface::MAGIC_NUMBER[3] {
                 return Err(DivansOpResult::Failure(ErrMsg::MagicNumberWrongB(self.header[2], self.header[3])));
         }
-        let window_size = self.header[5] as usize;
+        let window_size = self.header[interface::HEADER_WINDOW_SIZE_INDEX] as usize;
         if window_size < 10 || window_size >= 25 {
             return Err(DivansOpResult::Failure(ErrMsg::BadWindowSize(window_size as u8)));
         }
+        if let Some(max_window_size) = self.limits.max_window_size {
+            if window_size > usize::from(max_window_size) {
+                return Err(DivansOpResult::Failure(ErrMsg::WindowSizeExceedsLimit(window_size as u8)));
+            }
+        }
+        let flags_start = interface::HEADER_FEATURE_FLAGS_INDEX;
+        let flags_end = flags_start + interface::HEADER_FEATURE_FLAGS_LENGTH;
+        match self.header[interface::HEADER_VERSION_INDEX] {
+            interface::FORMAT_VERSION => {
+                match interface::StreamFeatures::from_bytes(&self.header[flags_start..flags_end]) {
+                    Ok(features) => self.features = features,
+                    Err(e) => return Err(DivansOpResult::Failure(e)),
+                }
+                if self.features.prior_snapshot_id.is_some() {
+                    let id = read_u32(&self.header[interface::HEADER_PRIOR_SNAPSHOT_ID_INDEX..]);
+                    self.features.prior_snapshot_id = Some(id);
+                    match self.prior_snapshot_info {
+                        Some(info) if info.id == id && info.cdf_flavor == self.features.cdf_flavor => {},
+                        _ => return Err(DivansOpResult::Failure(ErrMsg::PriorSnapshotMismatch(id))),
+                    }
+                }
+            },
+            interface::FORMAT_VERSION_UNVERSIONED => {
+                self.features = interface::StreamFeatures::compiled();
+                // legacy streams never set the flags, so anything nonzero is not one of ours
+                if let Some(index) = self.header[flags_start..flags_end].iter().position(|x| *x != 0) {
+                    return Err(DivansOpResult::Failure(ErrMsg::UnknownFeatureFlags(
+                        (flags_start + index) as u8, self.header[flags_start + index])));
+                }
+            },
+            version => return Err(DivansOpResult::Failure(ErrMsg::UnsupportedFormatVersion(version))),
+        }
+        let reserved_start = if self.features.prior_snapshot_id.is_some() {
+            interface::HEADER_PRIOR_SNAPSHOT_ID_INDEX + interface::HEADER_PRIOR_SNAPSHOT_ID_LENGTH
+        } else {
+            flags_end
+        };
+        if let Some(index) = self.header[reserved_start..].iter().position(|x| *x != 0) {
+            return Err(DivansOpResult::Failure(ErrMsg::UnknownFeatureFlags(
+                (reserved_start + index) as u8, self.header[reserved_start + index])));
+        }
+        if let Some(max_memory) = self.limits.max_memory_estimate {
+            if self.frame_memory(window_size) > max_memory {
+                return Err(DivansOpResult::Failure(ErrMsg::MemoryExceedsLimit));
+            }
+        }
         Ok(window_size)
     }
     pub fn decode(&mut self,
@@ -56,16 +241,48 @@ impl<AllocU8:Allocator<u8>,
         let header_parser = self;
         let window_size: usize;
         let is_multi: bool;
+        match header_parser.check_trailer(input, input_offset) {
+            DivansInputResult::Success => {},
+            res => return (0, false, res),
+        }
+        if header_parser.seek_table_remaining != 0 {
+            let to_skip = core::cmp::min(header_parser.seek_table_remaining, input.len() - *input_offset);
+            *input_offset += to_skip;
+            header_parser.seek_table_remaining -= to_skip;
+            if header_parser.seek_table_remaining != 0 {
+                return (0, false, DivansInputResult::NeedsMoreInput);
+            }
+        }
+        if header_parser.at_frame_boundary() && !header_parser.more_frames {
+            // the frame did not announce another after it, so whatever follows belongs to the caller
+            return (0, false, DivansInputResult::NeedsMoreInput);
+        }
         let remaining = input.len() - *input_offset;
+        if remaining != 0 && header_parser.at_frame_boundary()
+            && input[*input_offset] != interface::MAGIC_NUMBER[0]
+            && input[*input_offset] != interface::SEEK_TABLE_MAGIC[0] {
+            // data that cannot start another frame trails the stream: leave it unconsumed and stop
+            return (0, false, DivansInputResult::Failure(ErrMsg::TrailingInput(core::cmp::min(remaining, 255) as u8)));
+        }
         let header_left = header_parser.header.len() - header_parser.read_offset;
         if remaining >= header_left {
             header_parser.header[header_parser.read_offset..].clone_from_slice(
                 input.split_at(*input_offset).1.split_at(header_left).0);
             *input_offset += header_left;
+            if header_parser.header[..interface::SEEK_TABLE_MAGIC.len()] == interface::SEEK_TABLE_MAGIC[..] {
+                if header_parser.frames_decoded == 0 {
+                    return (0, false, DivansInputResult::Failure(ErrMsg::UnexpectedSeekTable));
+                }
+                let num_frames = read_u32(&header_parser.header[interface::SEEK_TABLE_MAGIC.len()..]);
+                // the first entry and the footer together fill out the bytes already read
+                header_parser.seek_table_remaining = num_frames as usize * interface::SEEK_TABLE_ENTRY_LENGTH;
+                header_parser.read_offset = 0;
+                return header_parser.decode(input, input_offset);
+            }
             match header_parser.parse_header() {
                 Ok(wsize) => {
                     window_size = wsize;
-                    is_multi = header_parser.multithread;
+                    is_multi = header_parser.multithread && header_parser.spawner.is_some();
                 },
                 Err(result) => return (0, false, DivansInputResult::from(result)),
             }
@@ -84,7 +301,8 @@ impl<AllocU8:Allocator<u8>,
 pub struct DivansProcess<DefaultDecoder: ArithmeticEncoderOrDecoder + NewWithAllocator<AllocU8>,
                      AllocU8:Allocator<u8>,
                          AllocCDF16:Allocator<interface::DefaultCDF16>,
-                         AllocCommand:Allocator<StaticCommand>> {
+                         AllocCommand:Allocator<StaticCommand>,
+                         Spawner:WorkerSpawner=DefaultSpawner> {
     codec: Option<codec::DivansCodec<DefaultDecoder,
                               DecoderSpecialization,
                               ThreadToMainDemuxer<AllocU8, SerialWorker<AllocU8, AllocCommand>>,
@@ -100,6 +318,7 @@ pub struct DivansProcess<DefaultDecoder: ArithmeticEncoderOrDecoder + NewWithAll
                                                Mux<AllocU8>>>,
     bytes_encoded: usize,
     mcommand: AllocCommand,
+    next_header: HeaderParser<AllocU8, AllocCDF16, AllocCommand, Spawner>,
 }
 
 
@@ -107,7 +326,8 @@ pub struct DivansProcess<DefaultDecoder: ArithmeticEncoderOrDecoder + NewWithAll
 impl<DefaultDecoder: ArithmeticEncoderOrDecoder + NewWithAllocator<AllocU8> + interface::BillingCapability,
      AllocU8:Allocator<u8>,
      AllocCDF16:Allocator<interface::DefaultCDF16>,
-     AllocCommand:Allocator<StaticCommand>> DivansProcess<DefaultDecoder, AllocU8, AllocCDF16, AllocCommand> {
+     AllocCommand:Allocator<StaticCommand>,
+     Spawner:WorkerSpawner> DivansProcess<DefaultDecoder, AllocU8, AllocCDF16, AllocCommand, Spawner> {
     fn decode(&mut self,
               input:&[u8],
               input_offset:&mut usize,
@@ -135,7 +355,8 @@ impl<DefaultDecoder: ArithmeticEncoderOrDecoder + NewWithAllocator<AllocU8> + in
                 DivansResult::Success => {},
                 DivansResult::Failure(e) => return DivansResult::Failure(e),
                 DivansResult::NeedsMoreInput => {
-                    if process.literal_decoder.as_mut().unwrap().outstanding_buffer_count == 0 {
+                    let literal_decoder = process.literal_decoder.as_mut().unwrap();
+                    if literal_decoder.outstanding_buffer_count == 0 && !literal_decoder.cmd_buffer_contains_sync {
                         return DivansResult::NeedsMoreInput;
                     } else {
                         // we can fall through here because if outstanding_buffer_count != 0 then
@@ -159,6 +380,43 @@ impl<DefaultDecoder: ArithmeticEncoderOrDecoder + NewWithAllocator<AllocU8> + in
             }
         }
     }
+    pub fn next_frame_header(&self) -> HeaderParser<AllocU8, AllocCDF16, AllocCommand, Spawner> {
+        self.next_header.without_allocators(self.next_header.frames_decoded)
+    }
+    pub fn commands_decoded(&self) -> u64 {
+        self.literal_decoder.as_ref().map_or(0, |ld| ld.commands_decoded)
+    }
+    pub fn codec_state(&self) -> Option<codec::EncodeOrDecodeState> {
+        self.codec.as_ref().map(|codec| codec.state())
+    }
+    pub fn salvage_pending(&mut self, output: &mut [u8], output_offset: &mut usize) -> DivansOutputResult {
+        match self.literal_decoder {
+            Some(ref mut ld) => ld.salvage_pending(output, output_offset),
+            None => DivansOutputResult::Success,
+        }
+    }
+    pub fn command_log(&self) -> &[u8] {
+        match self.literal_decoder {
+            Some(ref ld) => match ld.command_log {
+                Some(ref log) => log.slice(),
+                None => &[],
+            },
+            None => &[],
+        }
+    }
+    pub fn clear_command_log(&mut self) {
+        if let Some(ref mut ld) = self.literal_decoder {
+            if let Some(ref mut log) = ld.command_log {
+                log.clear();
+            }
+        }
+    }
+    pub fn take_command_log(&mut self) -> Option<ResizableByteBuffer<u8, AllocU8>> {
+        match self.literal_decoder {
+            Some(ref mut ld) => ld.command_log.take(),
+            None => None,
+        }
+    }
     pub fn free(mut self) -> (AllocU8, AllocCDF16, AllocCommand) {
         use codec::NUM_ARITHMETIC_CODERS;
         if let Some(mut codec) = core::mem::replace(&mut self.codec, None) {
@@ -191,23 +449,27 @@ impl<DefaultDecoder: ArithmeticEncoderOrDecoder + NewWithAllocator<AllocU8> + in
 pub enum DivansDecompressor<DefaultDecoder: ArithmeticEncoderOrDecoder + NewWithAllocator<AllocU8>,
                             AllocU8:Allocator<u8>,
                             AllocCDF16:Allocator<interface::DefaultCDF16>,
-                            AllocCommand:Allocator<StaticCommand>> {
-    Header(HeaderParser<AllocU8, AllocCDF16, AllocCommand>),
+                            AllocCommand:Allocator<StaticCommand>,
+                            Spawner:WorkerSpawner=DefaultSpawner> {
+    Header(HeaderParser<AllocU8, AllocCDF16, AllocCommand, Spawner>),
     Decode(DivansProcess<DefaultDecoder,
            AllocU8,
            AllocCDF16,
-           AllocCommand>),
+           AllocCommand,
+           Spawner>),
     MultiDecode(ParallelDivansProcess<DefaultDecoder,
                                       AllocU8,
                                       AllocCDF16,
-                                      AllocCommand>),
+                                      AllocCommand,
+                                      Spawner>),
 }
 
 impl<DefaultDecoder: ArithmeticEncoderOrDecoder + NewWithAllocator<AllocU8>,
      AllocU8:Allocator<u8>,
      AllocCDF16:Allocator<interface::DefaultCDF16>,
-     AllocCommand:Allocator<StaticCommand>>
-    DivansDecompressor<DefaultDecoder, AllocU8, AllocCDF16, AllocCommand> {
+     AllocCommand:Allocator<StaticCommand>,
+     Spawner:WorkerSpawner>
+    DivansDecompressor<DefaultDecoder, AllocU8, AllocCDF16, AllocCommand, Spawner> {
 
     fn finish_parsing_header_serial(&mut self, window_size: usize) -> DivansResult {
         if window_size < 10 {
@@ -220,6 +482,11 @@ impl<DefaultDecoder: ArithmeticEncoderOrDecoder + NewWithAllocator<AllocU8>,
         let mcdf16:AllocCDF16;
         let raw_header:[u8; interface::HEADER_LENGTH];
         let skip_crc:bool;
+        let features:interface::StreamFeatures;
+        let max_literal_bytes:usize;
+        let command_log:Option<ResizableByteBuffer<u8, AllocU8>>;
+        let prior_snapshot:AllocU8::AllocatedMemory;
+        let mut next_header: HeaderParser<AllocU8, AllocCDF16, AllocCommand, Spawner>;
         let mut mcommand:AllocCommand;
         match *self {
             DivansDecompressor::Header(ref mut header) => {
@@ -229,6 +496,15 @@ impl<DefaultDecoder: ArithmeticEncoderOrDecoder + NewWithAllocator<AllocU8>,
                 };
                 raw_header = header.header;
                 skip_crc = header.skip_crc;
+                features = header.features;
+                max_literal_bytes = header.max_literal_bytes(window_size);
+                command_log = if header.log_commands {
+                    Some(header.command_log.take().unwrap_or_else(ResizableByteBuffer::new))
+                } else {
+                    None
+                };
+                prior_snapshot = core::mem::take(&mut header.prior_snapshot);
+                next_header = header.following_frame_header();
             },
             _ => return DivansResult::Failure(ErrMsg::WrongInternalDecoderState),
         }
@@ -251,8 +527,8 @@ impl<DefaultDecoder: ArithmeticEncoderOrDecoder + NewWithAllocator<AllocU8>,
             _ => return DivansResult::Failure(ErrMsg::WrongInternalDecoderState),
         }
         //update this if you change the SelectedArithmeticDecoder macro
-        let cmd_decoder = DefaultDecoder::new(&mut m8);
-        let lit_decoder = DefaultDecoder::new(&mut m8);
+        let cmd_decoder = DefaultDecoder::new_with_entropy_coder(&mut m8, features.entropy_coder);
+        let lit_decoder = DefaultDecoder::new_with_entropy_coder(&mut m8, features.entropy_coder);
         let linear_input_bytes = ThreadToMainDemuxer::<AllocU8,SerialWorker<AllocU8, AllocCommand>>::new(
             SerialWorker::<AllocU8, AllocCommand>::new(&mut mcommand));
         let mut codec = codec::DivansCodec::<DefaultDecoder,
@@ -274,18 +550,33 @@ impl<DefaultDecoder: ArithmeticEncoderOrDecoder + NewWithAllocator<AllocU8>,
                                                           true,
                                                               codec::StrideSelection::UseBrotliRec,
                                                               skip_crc);
+        codec.set_cdf_flavor(features.cdf_flavor);
+        codec.set_literal_mixer(features.literal_mixer);
+        codec.set_literal_match(features.literal_match);
+        codec.set_literal_sse(features.literal_sse);
+        codec.set_literal_hash_size(features.literal_hash_size);
+        codec.set_sync_points(features.sync_points);
+        if features.prior_snapshot_id.is_some() {
+            if let Err(e) = codec.load_prior_snapshot(prior_snapshot.slice()) {
+                return DivansResult::Failure(e);
+            }
+        }
+        next_header.prior_snapshot = prior_snapshot;
         if !skip_crc {
             codec.get_crc().write(&raw_header[..]);
         }
-        let main_thread_codec = codec.fork(&mut mcommand);
+        let mut main_thread_codec = codec.fork(&mut mcommand);
         assert_eq!(*codec.get_crc(), main_thread_codec.crc);
+        main_thread_codec.command_log = command_log;
+        main_thread_codec.max_literal_bytes = max_literal_bytes;
         core::mem::replace(self,
                            DivansDecompressor::Decode(
-                               DivansProcess::<DefaultDecoder, AllocU8, AllocCDF16, AllocCommand> {
+                               DivansProcess::<DefaultDecoder, AllocU8, AllocCDF16, AllocCommand, Spawner> {
                                    codec:Some(codec),
                                    literal_decoder:Some(main_thread_codec),
                                    bytes_encoded:0,
                                    mcommand:mcommand,
+                                   next_header:next_header,
                                }));
         DivansResult::Success
     }
@@ -294,6 +585,100 @@ impl<DefaultDecoder: ArithmeticEncoderOrDecoder + NewWithAllocator<AllocU8>,
 
 macro_rules! free_body {
     () => {
+    // records the commands executed by the decoder so they can be inspected with command_log
+    // must be called before the header is parsed; logging forces single threaded decode
+    pub fn enable_command_log(&mut self) -> Result<(), ErrMsg> {
+        match *self {
+            DivansDecompressor::Header(ref mut header) => {
+                header.log_commands = true;
+                header.multithread = false;
+                Ok(())
+            },
+            _ => Err(ErrMsg::WrongInternalDecoderState),
+        }
+    }
+    // frames whose header names this prior snapshot start from it; others are rejected with
+    // PriorSnapshotMismatch. Must be called before the header is parsed
+    pub fn load_prior_snapshot(&mut self, prior_snapshot: &[u8]) -> Result<(), ErrMsg> {
+        let info = match codec::parse_prior_snapshot::<interface::DefaultCDF16, AllocCDF16>(prior_snapshot) {
+            Ok(info) => info,
+            Err(e) => return Err(e),
+        };
+        match *self {
+            DivansDecompressor::Header(ref mut header) => {
+                let m8 = match header.m8 {
+                    Some(ref mut m8) => m8,
+                    None => return Err(ErrMsg::MissingAllocator(8)),
+                };
+                m8.free_cell(core::mem::take(&mut header.prior_snapshot));
+                header.prior_snapshot = m8.alloc_cell(prior_snapshot.len());
+                if header.prior_snapshot.slice().len() != prior_snapshot.len() {
+                    return Err(ErrMsg::MemoryExceedsLimit);
+                }
+                header.prior_snapshot.slice_mut().clone_from_slice(prior_snapshot);
+                header.prior_snapshot_info = Some(info);
+                Ok(())
+            },
+            _ => Err(ErrMsg::WrongInternalDecoderState),
+        }
+    }
+    pub fn command_log(&self) -> &[u8] {
+        match *self {
+            DivansDecompressor::Header(ref header) => match header.command_log {
+                Some(ref log) => log.slice(),
+                None => &[],
+            },
+            DivansDecompressor::Decode(ref process) => process.command_log(),
+            DivansDecompressor::MultiDecode(_) => &[],
+        }
+    }
+    pub fn clear_command_log(&mut self) {
+        match *self {
+            DivansDecompressor::Header(ref mut header) => if let Some(ref mut log) = header.command_log {
+                log.clear();
+            },
+            DivansDecompressor::Decode(ref mut process) => process.clear_command_log(),
+            DivansDecompressor::MultiDecode(_) => {},
+        }
+    }
+    // the length and hash of the content of the last frame with a trailer, once the trailer is verified
+    pub fn content_summary(&self) -> Option<interface::ContentSummary> {
+        self.tracker().content_summary
+    }
+    // after decode fails or runs out of input, writes the decoded output that is still held back
+    pub fn salvage_pending(&mut self, output: &mut [u8], output_offset: &mut usize) -> DivansOutputResult {
+        let old_output_offset = *output_offset;
+        let ret = match *self {
+            DivansDecompressor::Header(_) => DivansOutputResult::Success,
+            DivansDecompressor::Decode(ref mut process) => process.salvage_pending(output, output_offset),
+            DivansDecompressor::MultiDecode(ref mut process) => process.salvage_pending(output, output_offset),
+        };
+        self.limit_tracker().bytes_decoded += (*output_offset - old_output_offset) as u64;
+        ret
+    }
+    pub fn frame_start(&self) -> u64 {
+        self.tracker().frame_start
+    }
+    // the failure most recently returned by decode, with the position in the stream where it happened
+    pub fn last_error(&self) -> Option<interface::DivansError> {
+        self.tracker().last_error
+    }
+    // describes how far the decoder got before msg, for failures noticed outside of decode
+    pub fn locate_error(&self, msg: ErrMsg) -> interface::DivansError {
+        let (commands, state) = match *self {
+            DivansDecompressor::Header(_) => (0, None),
+            DivansDecompressor::Decode(ref process) => (process.commands_decoded(), process.codec_state()),
+            DivansDecompressor::MultiDecode(ref process) => (process.commands_decoded(), process.codec_state()),
+        };
+        let tracker = self.tracker();
+        interface::DivansError {
+            msg,
+            compressed_offset: tracker.bytes_consumed,
+            decoded_offset: tracker.bytes_decoded,
+            command_index: tracker.commands_decoded + commands,
+            state,
+        }
+    }
     pub fn free_ref(&mut self) {
         match self {
             DivansDecompressor::Header(_parser) => {},
@@ -305,9 +690,128 @@ macro_rules! free_body {
             }
         }
     }
-    pub fn free(self) -> (AllocU8, AllocCDF16, AllocCommand) {
-        match self {
-            DivansDecompressor::Header(parser) => {
+    // frees a completed frame and returns to parsing a header, keeping the allocators for the next frame
+    fn start_next_frame(&mut self) {
+        let (mut next_header, command_log, commands) = match *self {
+            DivansDecompressor::Header(_) => return,
+            DivansDecompressor::MultiDecode(ref process) => (process.next_frame_header(), None, process.commands_decoded()),
+            DivansDecompressor::Decode(ref mut process) => (process.next_frame_header(), process.take_command_log(),
+                                                            process.commands_decoded()),
+        };
+        next_header.commands_decoded += commands;
+        next_header.prior_snapshot = core::mem::take(&mut self.limit_tracker().prior_snapshot);
+        let (m8, mcdf16, mcommand) = core::mem::replace(self, DivansDecompressor::Header(next_header)).free();
+        if let DivansDecompressor::Header(ref mut header) = *self {
+            header.command_log = command_log;
+            header.m8 = Some(m8);
+            header.mcdf16 = Some(mcdf16);
+            header.mcommand = Some(mcommand);
+        }
+    }
+    // decodes with no regard for limits.max_output_size
+    fn decode_frames(&mut self,
+              input:&[u8],
+              input_offset:&mut usize,
+              output:&mut [u8],
+              output_offset: &mut usize) -> DivansResult {
+        let parsed_header = match *self  {
+            DivansDecompressor::Header(ref mut header_parser) => {
+                let (ws, mul, ret) = header_parser.decode(input, input_offset);
+                match ret {
+                    DivansInputResult::Success => Some((ws, mul)),
+                    DivansInputResult::NeedsMoreInput => if header_parser.at_frame_boundary() {
+                        return DivansResult::Success;
+                    } else {
+                        return DivansResult::NeedsMoreInput;
+                    },
+                    _ => return DivansResult::from(ret),
+                }
+            },
+            DivansDecompressor::MultiDecode(ref mut process) => {
+                let old_output_offset = *output_offset;
+                let retval = process.decode(input, input_offset, output, output_offset);
+                let next_header: &mut HeaderParser<AllocU8, AllocCDF16, AllocCommand, Spawner> = process.next_header_mut();
+                next_header.record_output(&output[old_output_offset..*output_offset]);
+                match retval {
+                    DivansResult::Success => None,
+                    retval => return retval,
+                }
+            },
+            DivansDecompressor::Decode(ref mut process) => {
+                let old_output_offset = *output_offset;
+                let retval = process.decode(input, input_offset, output, output_offset);
+                process.next_header.record_output(&output[old_output_offset..*output_offset]);
+                match retval {
+                    DivansResult::Success => None,
+                    retval => return retval,
+                }
+            },
+        };
+        let (window_size, is_multi) = match parsed_header {
+            Some(header_info) => header_info,
+            None => {
+                // a frame of a seekable stream may be followed by another frame or the seek table
+                self.start_next_frame();
+                {
+                    let tracker = self.limit_tracker();
+                    tracker.frame_start = tracker.input_base.wrapping_add(*input_offset as u64);
+                }
+                if *input_offset < input.len() || self.limit_tracker().content.is_some() {
+                    return self.decode_frames(input, input_offset, output, output_offset);
+                }
+                return DivansResult::Success;
+            },
+        };
+        if is_multi {
+            let par_proc;
+            {
+                if let DivansDecompressor::Header(ref mut header) = *self {
+                    let spawner = match header.spawner.clone() {
+                        Some(spawner) => spawner,
+                        None => return DivansResult::Failure(ErrMsg::WrongInternalDecoderState),
+                    };
+                    par_proc = match ParallelDivansProcess::<DefaultDecoder, AllocU8, AllocCDF16, AllocCommand, Spawner>::new(header, spawner, window_size) {
+                        Ok(par_proc) => par_proc,
+                        Err(e) => return DivansResult::Failure(e),
+                    };
+                } else {
+                    return DivansResult::Failure(ErrMsg::WrongInternalDecoderState);
+                }
+            }
+            *self = DivansDecompressor::MultiDecode(par_proc);
+        } else {
+            match self.finish_parsing_header_serial(window_size) {
+                DivansResult::Success => {},
+                failure => return failure,
+            }
+        }
+        if *input_offset < input.len() {
+            return self.decode_frames(input, input_offset, output, output_offset);
+        }
+        DivansResult::NeedsMoreInput
+    }
+    fn tracker(&self) -> &HeaderParser<AllocU8, AllocCDF16, AllocCommand, Spawner> {
+        match *self {
+            DivansDecompressor::Header(ref header) => header,
+            DivansDecompressor::Decode(ref process) => &process.next_header,
+            DivansDecompressor::MultiDecode(ref process) => process.next_header(),
+        }
+    }
+    // the header parser that carries the limits and the output count from frame to frame
+    fn limit_tracker(&mut self) -> &mut HeaderParser<AllocU8, AllocCDF16, AllocCommand, Spawner> {
+        match *self {
+            DivansDecompressor::Header(ref mut header) => header,
+            DivansDecompressor::Decode(ref mut process) => &mut process.next_header,
+            DivansDecompressor::MultiDecode(ref mut process) => process.next_header_mut(),
+        }
+    }
+    pub fn free(mut self) -> (AllocU8, AllocCDF16, AllocCommand) {
+        let prior_snapshot = core::mem::take(&mut self.limit_tracker().prior_snapshot);
+        let (mut m8, mcdf16, mcommand) = match self {
+            DivansDecompressor::Header(mut parser) => {
+                if let Some(mut log) = parser.command_log.take() {
+                    log.free(parser.m8.as_mut().unwrap());
+                }
                 (parser.m8.unwrap(),
                  parser.mcdf16.unwrap(),
                  parser.mcommand.unwrap(),
@@ -319,36 +823,30 @@ macro_rules! free_body {
             DivansDecompressor::Decode(process) => {
                 process.free()
             }
-        }
+        };
+        m8.free_cell(prior_snapshot);
+        (m8, mcdf16, mcommand)
     }
     }
 }
-#[cfg(not(feature="no-stdlib"))]
 impl<DefaultDecoder: ArithmeticEncoderOrDecoder + NewWithAllocator<AllocU8> + interface::BillingCapability,
      AllocU8:Allocator<u8>,
      AllocCDF16:Allocator<interface::DefaultCDF16>,
-     AllocCommand:Allocator<StaticCommand>>
-    DivansDecompressor<DefaultDecoder, AllocU8, AllocCDF16, AllocCommand>
+     AllocCommand:Allocator<StaticCommand>,
+     Spawner:WorkerSpawner>
+    DivansDecompressor<DefaultDecoder, AllocU8, AllocCDF16, AllocCommand, Spawner>
     where
-        DefaultDecoder: Send + 'static,  // fixme: only demand send if not no-stdlib
-        AllocCommand : Send + 'static,
-        AllocCDF16 : Send + 'static,
-        AllocU8 : Send + 'static,
-        AllocCommand::AllocatedMemory : Send + 'static,
-        AllocCDF16::AllocatedMemory : Send + 'static,
-        AllocU8::AllocatedMemory : Send + 'static,
+        DefaultDecoder: 'static,
+        AllocCommand : 'static,
+        AllocCDF16 : 'static,
+        AllocU8 : 'static,
+        Spawner : 'static,
+        // the codec of a multithreaded frame moves to the thread the spawner starts
+        Spawner::Lock<Option<WorkerCodec<DefaultDecoder, AllocU8, AllocCDF16, AllocCommand, Spawner>>> : Send,
         {
     free_body!();
 }
 
-#[cfg(feature="no-stdlib")]
-impl<DefaultDecoder: ArithmeticEncoderOrDecoder + NewWithAllocator<AllocU8> + interface::BillingCapability,
-     AllocU8:Allocator<u8>,
-     AllocCDF16:Allocator<interface::DefaultCDF16>,
-     AllocCommand:Allocator<StaticCommand>>
-    DivansDecompressor<DefaultDecoder, AllocU8, AllocCDF16, AllocCommand> {
-        free_body!();
-}
 
 
 macro_rules! decode_body {
@@ -358,78 +856,62 @@ macro_rules! decode_body {
               input_offset:&mut usize,
               output:&mut [u8],
               output_offset: &mut usize) -> DivansResult {
-        let window_size: usize;
-        let is_multi: bool;
-        match *self  {
-            DivansDecompressor::Header(ref mut header_parser) => {
-                let (ws, mul, ret) = header_parser.decode(input, input_offset);
-                if let DivansInputResult::Success = ret {
-                    window_size = ws;
-                    is_multi = mul;
-                } else {
-                    return DivansResult::from(ret);
-                }
-            },
-            DivansDecompressor::MultiDecode(ref mut process) => {
-                return process.decode(input, input_offset, output, output_offset);
-            },
-            DivansDecompressor::Decode(ref mut process) => {
-                return process.decode(input, input_offset, output, output_offset);
-            },
-        }
-        if is_multi {
-            let par_proc;
-            {
-                if let DivansDecompressor::Header(ref mut header) = *self {
-                    par_proc = ParallelDivansProcess::<DefaultDecoder, AllocU8, AllocCDF16, AllocCommand>::new(header, window_size);
-                } else {
-                    return DivansResult::Failure(ErrMsg::WrongInternalDecoderState);
-                }
-            }
-            *self = DivansDecompressor::MultiDecode(par_proc);
+        let budget = match self.limit_tracker().limits.max_output_size {
+            Some(max_output_size) => max_output_size.saturating_sub(self.limit_tracker().bytes_decoded),
+            None => u64::MAX,
+        };
+        let output_end = if budget < (output.len() - *output_offset) as u64 {
+            *output_offset + budget as usize
         } else {
-            self.finish_parsing_header_serial(window_size);
+            output.len()
+        };
+        let old_input_offset = *input_offset;
+        let old_output_offset = *output_offset;
+        {
+            let tracker = self.limit_tracker();
+            tracker.input_base = tracker.bytes_consumed.wrapping_sub(old_input_offset as u64);
         }
-        if *input_offset < input.len() {
-            return self.decode(input, input_offset, output, output_offset);
+        let ret = self.decode_frames(input, input_offset, output.split_at_mut(output_end).0, output_offset);
+        {
+            let tracker = self.limit_tracker();
+            tracker.bytes_consumed += (*input_offset - old_input_offset) as u64;
+            tracker.bytes_decoded += (*output_offset - old_output_offset) as u64;
         }
-        DivansResult::NeedsMoreInput
+        let ret = match ret {
+            DivansResult::NeedsMoreOutput if output_end != output.len() => DivansResult::Failure(ErrMsg::OutputSizeExceedsLimit),
+            _ => ret,
+        };
+        if let DivansResult::Failure(msg) = ret {
+            let located = self.locate_error(msg);
+            self.limit_tracker().last_error = Some(located);
+        }
+        ret
     }
         
     }
 }
 
-#[cfg(not(feature="no-stdlib"))]
 impl<DefaultDecoder: ArithmeticEncoderOrDecoder + NewWithAllocator<AllocU8> + interface::BillingCapability,
      AllocU8:Allocator<u8>,
      AllocCDF16:Allocator<interface::DefaultCDF16>,
-     AllocCommand:Allocator<StaticCommand>> Decompressor for DivansDecompressor<DefaultDecoder,
-                                                                                AllocU8,
-                                                                                AllocCDF16,
-                                                                                AllocCommand>
+     AllocCommand:Allocator<StaticCommand>,
+     Spawner:WorkerSpawner> Decompressor for DivansDecompressor<DefaultDecoder,
+                                                                AllocU8,
+                                                                AllocCDF16,
+                                                                AllocCommand,
+                                                                Spawner>
     where
-        DefaultDecoder: Send + 'static,  // fixme: only demand send if not no-stdlib
-        AllocCommand : Send + 'static,
-        AllocCDF16 : Send + 'static,
-        AllocU8 : Send + 'static,
-        AllocCommand::AllocatedMemory : Send + 'static,
-        AllocCDF16::AllocatedMemory : Send + 'static,
-        AllocU8::AllocatedMemory : Send + 'static,
+        DefaultDecoder: 'static,
+        AllocCommand : 'static,
+        AllocCDF16 : 'static,
+        AllocU8 : 'static,
+        Spawner : 'static,
+        // the codec of a multithreaded frame moves to the thread the spawner starts
+        Spawner::Lock<Option<WorkerCodec<DefaultDecoder, AllocU8, AllocCDF16, AllocCommand, Spawner>>> : Send,
 {
     decode_body!();
 }
 
-#[cfg(feature="no-stdlib")]
-impl<DefaultDecoder: ArithmeticEncoderOrDecoder + NewWithAllocator<AllocU8> + interface::BillingCapability,
-     AllocU8:Allocator<u8>,
-     AllocCDF16:Allocator<interface::DefaultCDF16>,
-     AllocCommand:Allocator<StaticCommand>> Decompressor for DivansDecompressor<DefaultDecoder,
-                                                                                AllocU8,
-                                                                                AllocCDF16,
-                                                                                AllocCommand> {
-    decode_body!();
-}
-
 pub trait DivansDecompressorFactory<
      AllocU8:Allocator<u8>,
     AllocCDF16:Allocator<interface::DefaultCDF16>,
@@ -440,12 +922,73 @@ pub trait DivansDecompressorFactory<
            mc: AllocCommand,
            skip_crc:bool,
            multithread:bool) -> DivansDecompressor<Self::DefaultDecoder, AllocU8, AllocCDF16, AllocCommand> {
-        DivansDecompressor::Header(HeaderParser{header:[0u8;interface::HEADER_LENGTH], read_offset:0,
-                                                m8:Some(m8), mcdf16:Some(mcdf16), mcommand:Some(mc),
-                                                skip_crc:skip_crc,
-                                                multithread:multithread,
-        })
+        Self::new_with_limits(m8, mcdf16, mc, skip_crc, multithread, interface::DivansDecompressorLimits::default())
     }
+    fn new_with_limits(m8: AllocU8,
+                       mcdf16:AllocCDF16,
+                       mc: AllocCommand,
+                       skip_crc:bool,
+                       multithread:bool,
+                       limits: interface::DivansDecompressorLimits) -> DivansDecompressor<Self::DefaultDecoder, AllocU8, AllocCDF16, AllocCommand> {
+        new_header_parser(m8, mcdf16, mc, skip_crc, multithread, limits, default_spawner())
+    }
+    // like new_with_limits, but multithreaded frames get their command thread from spawner
+    fn new_with_spawner<Spawner:WorkerSpawner>(m8: AllocU8,
+                                               mcdf16:AllocCDF16,
+                                               mc: AllocCommand,
+                                               skip_crc:bool,
+                                               multithread:bool,
+                                               limits: interface::DivansDecompressorLimits,
+                                               spawner: Spawner) -> DivansDecompressor<Self::DefaultDecoder, AllocU8, AllocCDF16, AllocCommand, Spawner> {
+        new_header_parser(m8, mcdf16, mc, skip_crc, multithread, limits, Some(spawner))
+    }
+}
+
+#[cfg(not(feature="no-stdlib"))]
+fn default_spawner() -> Option<DefaultSpawner> {
+    Some(StdThreadSpawner::default())
+}
+
+// a build without std has no thread to start unless the caller passes a spawner to new_with_spawner
+#[cfg(feature="no-stdlib")]
+fn default_spawner() -> Option<DefaultSpawner> {
+    None
+}
+
+fn new_header_parser<DefaultDecoder: ArithmeticEncoderOrDecoder + NewWithAllocator<AllocU8>,
+                     AllocU8:Allocator<u8>,
+                     AllocCDF16:Allocator<interface::DefaultCDF16>,
+                     AllocCommand:Allocator<StaticCommand>,
+                     Spawner:WorkerSpawner>(m8: AllocU8,
+                                            mcdf16:AllocCDF16,
+                                            mc: AllocCommand,
+                                            skip_crc:bool,
+                                            multithread:bool,
+                                            limits: interface::DivansDecompressorLimits,
+                                            spawner: Option<Spawner>) -> DivansDecompressor<DefaultDecoder, AllocU8, AllocCDF16, AllocCommand, Spawner> {
+    DivansDecompressor::Header(HeaderParser{header:[0u8;interface::HEADER_LENGTH], read_offset:0,
+                                            m8:Some(m8), mcdf16:Some(mcdf16), mcommand:Some(mc),
+                                            skip_crc:skip_crc,
+                                            multithread:multithread,
+                                            spawner,
+                                            frames_decoded:0,
+                                            seek_table_remaining:0,
+                                            more_frames:false,
+                                            log_commands:false,
+                                            command_log:None,
+                                            prior_snapshot:AllocU8::AllocatedMemory::default(),
+                                            prior_snapshot_info:None,
+                                            features:interface::StreamFeatures::compiled(),
+                                            limits,
+                                            bytes_decoded:0,
+                                            bytes_consumed:0,
+                                            input_base:0,
+                                            frame_start:0,
+                                            commands_decoded:0,
+                                            last_error:None,
+                                            content:None,
+                                            content_summary:None,
+    })
 }
 
 #[derive(Default)]
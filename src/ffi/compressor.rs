@@ -2,7 +2,7 @@ use ::brotli;
 use ::interface::{DivansResult, DivansOutputResult};
 use ::brotli::enc::interface::LiteralPredictionModeNibble;
 use core;
-use ::interface::{DivansCompressorOptions, BrotliCompressionSetting, StrideSelection, DivansCompressorFactory, Compressor};
+use ::interface::{DivansCompressorOptions, BrotliCompressionSetting, StrideSelection, DivansCompressorFactory, Compressor, CDFFlavor, EntropyCoder};
 use ::probability::Speed;
 use super::alloc_util::SubclassableAllocator;
 use super::interface::*;
@@ -58,6 +58,8 @@ pub enum CompressorState {
                        SubclassableAllocator<u8>,
                        SubclassableAllocator<u32>,
                        SubclassableAllocator<::DefaultCDF16>>),
+    #[cfg(not(feature="no-stdlib"))]
+    ParallelCompressor(::DivansParallelCompressor),
 }
 
 impl Default for CompressorState {
@@ -164,6 +166,45 @@ impl CompressorState {
                 DIVANS_OPTION_IR_OPTIMIZER => {
                     opts.divans_ir_optimizer = value as u8;
                 },
+                DIVANS_OPTION_CDF_FLAVOR => {opts.cdf_flavor = match value {
+                    0 => Some(CDFFlavor::Frequentist),
+                    1 => Some(CDFFlavor::Blend),
+                    _ => return DIVANS_FAILURE,
+                }},
+                DIVANS_OPTION_ENTROPY_CODER => {opts.entropy_coder = match value {
+                    0 => Some(EntropyCoder::ANS),
+                    1 => Some(EntropyCoder::Range),
+                    _ => return DIVANS_FAILURE,
+                }},
+                DIVANS_OPTION_CONTENT_TRAILER => {opts.content_trailer = match value {
+                    1 => true,
+                    0 => false,
+                    _ => return DIVANS_FAILURE,
+                };},
+                DIVANS_OPTION_NUM_THREADS => {opts.num_threads = value;},
+                DIVANS_OPTION_LEVEL => if value > 255 || opts.apply_level(value as u8).is_err() {
+                    return DIVANS_FAILURE;
+                },
+                DIVANS_OPTION_LITERAL_MIXER => {opts.literal_mixer = match value {
+                    1 => true,
+                    0 => false,
+                    _ => return DIVANS_FAILURE,
+                };},
+                DIVANS_OPTION_LITERAL_SSE => {opts.literal_sse = match value {
+                    1 => true,
+                    0 => false,
+                    _ => return DIVANS_FAILURE,
+                };},
+                DIVANS_OPTION_LITERAL_HASH_SIZE => {opts.literal_hash_size = match value {
+                    0 => None,
+                    size if size <= u32::from(::interface::MAX_LITERAL_HASH_SIZE) => Some(size as u8),
+                    _ => return DIVANS_FAILURE,
+                };},
+                DIVANS_OPTION_LITERAL_MATCH => {opts.literal_match = match value {
+                    1 => true,
+                    0 => false,
+                    _ => return DIVANS_FAILURE,
+                };},
                 _ => return DIVANS_FAILURE,
             }
             return DIVANS_SUCCESS;
@@ -171,6 +212,13 @@ impl CompressorState {
         DIVANS_FAILURE
     }
     fn start(&mut self, allocators: &CAllocator, opts:DivansCompressorOptions) {
+        #[cfg(not(feature="no-stdlib"))]
+        {
+            if opts.num_threads > 1 {
+                *self = CompressorState::ParallelCompressor(::DivansParallelCompressor::new(opts, ::DEFAULT_FRAME_SIZE));
+                return;
+            }
+        }
         match opts.use_brotli {
             BrotliCompressionSetting::UseInternalCommandSelection => {
                 core::mem::replace(self,
@@ -230,6 +278,10 @@ impl CompressorState {
             CompressorState::InternalCompressor(ref mut compressor) => {
                 compressor.encode(input_buf, input_offset, output_buf, output_offset)
             },
+            #[cfg(not(feature="no-stdlib"))]
+            CompressorState::ParallelCompressor(ref mut compressor) => {
+                compressor.encode(input_buf, input_offset, output_buf, output_offset)
+            },
         };
         match res {
             DivansResult::Success => DIVANS_SUCCESS,
@@ -253,6 +305,10 @@ impl CompressorState {
             CompressorState::InternalCompressor(ref mut compressor) => {
                 compressor.flush(output_buf, output_offset)
             },
+            #[cfg(not(feature="no-stdlib"))]
+            CompressorState::ParallelCompressor(ref mut compressor) => {
+                compressor.flush(output_buf, output_offset)
+            },
         };
         match res {
             DivansOutputResult::Success => DIVANS_SUCCESS,
@@ -280,7 +336,9 @@ impl Drop for DivansCompressorState {
             },
             CompressorState::InternalCompressor(ref mut compressor) => {
                 compressor.free_ref();
-            }
+            },
+            #[cfg(not(feature="no-stdlib"))]
+            CompressorState::ParallelCompressor(_) => {},
         }
     }
 }
@@ -1,3 +1,5 @@
+use ::EncodeOrDecodeState;
+
 #[allow(non_camel_case_types)]
 #[repr(u8)]
 pub enum c_void{
@@ -35,6 +37,65 @@ pub const DIVANS_OPTION_PRIOR_BITMASK_DETECTION:DivansOptionSelect = 17;
 pub const DIVANS_OPTION_Q9_5:DivansOptionSelect = 18;
 pub const DIVANS_OPTION_FORCE_LITERAL_CONTEXT_MODE:DivansOptionSelect = 19;
 pub const DIVANS_OPTION_IR_OPTIMIZER:DivansOptionSelect = 20;
+pub const DIVANS_OPTION_CDF_FLAVOR:DivansOptionSelect = 21;
+pub const DIVANS_OPTION_ENTROPY_CODER:DivansOptionSelect = 22;
+pub const DIVANS_OPTION_CONTENT_TRAILER:DivansOptionSelect = 23;
+pub const DIVANS_OPTION_NUM_THREADS:DivansOptionSelect = 24;
+pub const DIVANS_OPTION_LEVEL:DivansOptionSelect = 25;
+pub const DIVANS_OPTION_LITERAL_MIXER:DivansOptionSelect = 26;
+pub const DIVANS_OPTION_LITERAL_SSE:DivansOptionSelect = 27;
+pub const DIVANS_OPTION_LITERAL_HASH_SIZE:DivansOptionSelect = 28;
+pub const DIVANS_OPTION_LITERAL_MATCH:DivansOptionSelect = 29;
+
+// what the command decoder was doing when a failure happened; see DivansError::state
+pub type DivansCodecState = u8;
+
+pub const DIVANS_CODEC_STATE_UNKNOWN:DivansCodecState = 0;
+pub const DIVANS_CODEC_STATE_BEGIN:DivansCodecState = 1;
+pub const DIVANS_CODEC_STATE_LITERAL:DivansCodecState = 2;
+pub const DIVANS_CODEC_STATE_DICT:DivansCodecState = 3;
+pub const DIVANS_CODEC_STATE_COPY:DivansCodecState = 4;
+pub const DIVANS_CODEC_STATE_BLOCK_SWITCH_LITERAL:DivansCodecState = 5;
+pub const DIVANS_CODEC_STATE_BLOCK_SWITCH_COMMAND:DivansCodecState = 6;
+pub const DIVANS_CODEC_STATE_BLOCK_SWITCH_DISTANCE:DivansCodecState = 7;
+pub const DIVANS_CODEC_STATE_PREDICTION_MODE:DivansCodecState = 8;
+pub const DIVANS_CODEC_STATE_POPULATE_RING_BUFFER:DivansCodecState = 9;
+pub const DIVANS_CODEC_STATE_SUCCESS:DivansCodecState = 10;
+pub const DIVANS_CODEC_STATE_ENCODED_SHUTDOWN_NODE:DivansCodecState = 11;
+pub const DIVANS_CODEC_STATE_SHUTDOWN_CODER:DivansCodecState = 12;
+pub const DIVANS_CODEC_STATE_CODER_BUFFER_DRAIN:DivansCodecState = 13;
+pub const DIVANS_CODEC_STATE_MUX_DRAIN:DivansCodecState = 14;
+pub const DIVANS_CODEC_STATE_WRITE_CHECKSUM:DivansCodecState = 15;
+pub const DIVANS_CODEC_STATE_SYNC_CODERS:DivansCodecState = 16;
+pub const DIVANS_CODEC_STATE_SYNC_OUTPUT:DivansCodecState = 17;
+pub const DIVANS_CODEC_STATE_SYNC_CODER_DRAIN:DivansCodecState = 18;
+pub const DIVANS_CODEC_STATE_SYNC_MUX_DRAIN:DivansCodecState = 19;
+
+// the coder index of ShutdownCoder and the byte of WriteChecksum are left to the message
+pub fn codec_state(state: Option<EncodeOrDecodeState>) -> DivansCodecState {
+    match state {
+        None => DIVANS_CODEC_STATE_UNKNOWN,
+        Some(EncodeOrDecodeState::Begin) => DIVANS_CODEC_STATE_BEGIN,
+        Some(EncodeOrDecodeState::Literal) => DIVANS_CODEC_STATE_LITERAL,
+        Some(EncodeOrDecodeState::Dict) => DIVANS_CODEC_STATE_DICT,
+        Some(EncodeOrDecodeState::Copy) => DIVANS_CODEC_STATE_COPY,
+        Some(EncodeOrDecodeState::BlockSwitchLiteral) => DIVANS_CODEC_STATE_BLOCK_SWITCH_LITERAL,
+        Some(EncodeOrDecodeState::BlockSwitchCommand) => DIVANS_CODEC_STATE_BLOCK_SWITCH_COMMAND,
+        Some(EncodeOrDecodeState::BlockSwitchDistance) => DIVANS_CODEC_STATE_BLOCK_SWITCH_DISTANCE,
+        Some(EncodeOrDecodeState::PredictionMode) => DIVANS_CODEC_STATE_PREDICTION_MODE,
+        Some(EncodeOrDecodeState::PopulateRingBuffer) => DIVANS_CODEC_STATE_POPULATE_RING_BUFFER,
+        Some(EncodeOrDecodeState::DivansSuccess) => DIVANS_CODEC_STATE_SUCCESS,
+        Some(EncodeOrDecodeState::EncodedShutdownNode) => DIVANS_CODEC_STATE_ENCODED_SHUTDOWN_NODE,
+        Some(EncodeOrDecodeState::ShutdownCoder(_)) => DIVANS_CODEC_STATE_SHUTDOWN_CODER,
+        Some(EncodeOrDecodeState::CoderBufferDrain) => DIVANS_CODEC_STATE_CODER_BUFFER_DRAIN,
+        Some(EncodeOrDecodeState::MuxDrain) => DIVANS_CODEC_STATE_MUX_DRAIN,
+        Some(EncodeOrDecodeState::WriteChecksum(_)) => DIVANS_CODEC_STATE_WRITE_CHECKSUM,
+        Some(EncodeOrDecodeState::SyncCoders) => DIVANS_CODEC_STATE_SYNC_CODERS,
+        Some(EncodeOrDecodeState::SyncOutput) => DIVANS_CODEC_STATE_SYNC_OUTPUT,
+        Some(EncodeOrDecodeState::SyncCoderDrain) => DIVANS_CODEC_STATE_SYNC_CODER_DRAIN,
+        Some(EncodeOrDecodeState::SyncMuxDrain) => DIVANS_CODEC_STATE_SYNC_MUX_DRAIN,
+    }
+}
 
 
 #[repr(C)]
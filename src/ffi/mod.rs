@@ -13,7 +13,7 @@ mod compressor;
 mod decompressor;
 use self::compressor::DivansCompressorState;
 use self::decompressor::DivansDecompressorState;
-use self::interface::{CAllocator, c_void, DivansOptionSelect, DivansReturnCode, DIVANS_FAILURE, DIVANS_SUCCESS, DIVANS_NEEDS_MORE_INPUT, DIVANS_NEEDS_MORE_OUTPUT};
+use self::interface::{CAllocator, c_void, DivansCodecState, DivansOptionSelect, DivansReturnCode, DIVANS_FAILURE, DIVANS_SUCCESS, DIVANS_NEEDS_MORE_INPUT, DIVANS_NEEDS_MORE_OUTPUT};
 #[no_mangle]
 pub extern fn divans_new_compressor() -> *mut compressor::DivansCompressorState{
     unsafe {
@@ -211,14 +211,29 @@ fn divans_new_decompressor_without_custom_alloc(to_box: DivansDecompressorState)
 
 #[no_mangle]
 pub unsafe extern fn divans_new_decompressor_with_custom_alloc(allocators:CAllocator, skip_crc:u8, multithread: u8) -> *mut DivansDecompressorState{
+    divans_new_decompressor_with_limits(allocators, skip_crc, multithread, 0, 0, 0)
+}
+
+
+// a zero max_window_size, max_output_size or max_memory_estimate leaves that resource unbounded
+#[no_mangle]
+pub unsafe extern fn divans_new_decompressor_with_limits(allocators:CAllocator, skip_crc:u8, multithread: u8,
+                                                         max_window_size: u8, max_output_size: u64,
+                                                         max_memory_estimate: usize) -> *mut DivansDecompressorState{
+    let limits = super::interface::DivansDecompressorLimits{
+        max_window_size: if max_window_size == 0 { None } else { Some(max_window_size) },
+        max_output_size: if max_output_size == 0 { None } else { Some(max_output_size) },
+        max_memory_estimate: if max_memory_estimate == 0 { None } else { Some(max_memory_estimate) },
+    };
     let to_box = DivansDecompressorState{
         custom_allocator:allocators.clone(),
-        decompressor:decompressor::DecompressorFactory::new(
+        decompressor:decompressor::DecompressorFactory::new_with_limits(
             SubclassableAllocator::<u8>::new(allocators.clone()),
             SubclassableAllocator::<super::DefaultCDF16>::new(allocators.clone()),
             SubclassableAllocator::<StaticCommand>::new(allocators.clone()),
             skip_crc != 0,
             multithread != 0,
+            limits,
         ),
     };
     if let Some(alloc_fn) = allocators.alloc_func {
@@ -261,6 +276,73 @@ pub unsafe extern fn divans_decode(state_ptr: *mut DivansDecompressorState,
     }
 }
 
+#[no_mangle]
+pub unsafe extern fn divans_decompressed_content_summary(state_ptr: *mut DivansDecompressorState,
+                                                         length_ptr: *mut u64,
+                                                         hash_ptr: *mut u64) -> DivansReturnCode {
+    match (state_ptr.as_ref(), length_ptr.as_mut(), hash_ptr.as_mut()) {
+        (Some(state_ref), Some(length), Some(hash)) => match state_ref.decompressor.content_summary() {
+            Some(summary) => {
+                *length = summary.length;
+                *hash = summary.hash;
+                DIVANS_SUCCESS
+            },
+            None => DIVANS_FAILURE,
+        },
+        _ => DIVANS_FAILURE,
+    }
+}
+
+// formats into a fixed buffer, dropping whatever does not fit while leaving room for a NUL
+struct TruncatingWriter<'a> {
+    buffer: &'a mut [u8],
+    len: usize,
+}
+
+impl<'a> core::fmt::Write for TruncatingWriter<'a> {
+    fn write_str(&mut self, data: &str) -> core::fmt::Result {
+        let to_copy = core::cmp::min(data.len(), self.buffer.len() - 1 - self.len);
+        self.buffer[self.len..self.len + to_copy].clone_from_slice(&data.as_bytes()[..to_copy]);
+        self.len += to_copy;
+        Ok(())
+    }
+}
+
+#[no_mangle]
+pub unsafe extern fn divans_decompressor_last_error(state_ptr: *const DivansDecompressorState,
+                                                    compressed_offset_ptr: *mut u64,
+                                                    decoded_offset_ptr: *mut u64,
+                                                    command_index_ptr: *mut u64,
+                                                    codec_state_ptr: *mut DivansCodecState,
+                                                    message_ptr: *mut u8,
+                                                    message_size: usize) -> DivansReturnCode {
+    let located = match state_ptr.as_ref() {
+        Some(state_ref) => match state_ref.decompressor.last_error() {
+            Some(located) => located,
+            None => return DIVANS_FAILURE,
+        },
+        None => return DIVANS_FAILURE,
+    };
+    if let Some(compressed_offset) = compressed_offset_ptr.as_mut() {
+        *compressed_offset = located.compressed_offset;
+    }
+    if let Some(decoded_offset) = decoded_offset_ptr.as_mut() {
+        *decoded_offset = located.decoded_offset;
+    }
+    if let Some(command_index) = command_index_ptr.as_mut() {
+        *command_index = located.command_index;
+    }
+    if let Some(codec_state) = codec_state_ptr.as_mut() {
+        *codec_state = interface::codec_state(located.state);
+    }
+    if !message_ptr.is_null() && message_size != 0 {
+        let mut message = TruncatingWriter{buffer: slice::from_raw_parts_mut(message_ptr, message_size), len: 0};
+        let _ = core::fmt::Write::write_fmt(&mut message, format_args!("{}", located));
+        message.buffer[message.len] = 0;
+    }
+    DIVANS_SUCCESS
+}
+
 #[cfg(not(feature="no-stdlib"))]
 unsafe fn free_decompressor_no_custom_alloc(state_ptr: *mut DivansDecompressorState) {
     let _state = alloc_util::Box::from_raw(state_ptr);
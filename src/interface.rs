@@ -22,15 +22,20 @@ use super::codec::literal::LiteralSubstate;
 use super::codec::context_map::PredictionModeSubstate;
 use super::codec::block_type::BlockTypeState;
 pub use super::codec::StrideSelection;
+pub use super::codec::EncodeOrDecodeState;
+use super::xxhash::XxHash64;
+use super::byte_util::{read_u32, read_u64, u32_bytes, u64_bytes};
+use core::hash::Hasher;
 pub use brotli::enc::interface::*;
 
-#[derive(Copy,Clone,Debug)]
+#[derive(Copy,Clone,Debug,PartialEq)]
 pub enum ErrMsg {
     PredictionModeFail(()),
     ShutdownCoderNeedsInput,
     EncodeOneCommandNeedsInput,
     NotAllowedToFlushIfPreviousCommandPartial,
     NotAllowedToEncodeAfterFlush,
+    NotAllowedToEncodeDuringSyncFlush,
     Distance0NotAllowed,
     DrainOrFillNeedsInput(u8),
     BrotliIrGenFlushStreamNeedsInput,
@@ -44,16 +49,28 @@ pub enum ErrMsg {
     InputOffsetOutOfBounds,
     CommandCodeOutOfBounds(u8),
     CopyDistanceMnemonicCodeBad(u8, u8),
+    CopyLengthTooLarge(u8),
     BadChecksum(u8, u8),
     IndexBeyondContextMapSize(u8, u8),
     PredictionModeOutOfBounds(u8),
     DictWordSizeTooLarge(u8),
+    DictWordIdOutOfBounds(u8),
     DictTransformIndexUndefined(u8),
+    AdaptationSpeedOutOfBounds(u8),
     BrotliCompressStreamFail(u8, u8),
     BrotliInternalEncodeStreamNeedsOutputWithoutFlush,
     MagicNumberWrongA(u8, u8),
     MagicNumberWrongB(u8, u8),
     BadWindowSize(u8),
+    WindowSizeExceedsLimit(u8),
+    OutputSizeExceedsLimit,
+    ContentLengthMismatch,
+    ContentHashMismatch,
+    ContentTrailerNeedsRawInput,
+    ParallelCompressionNeedsRawInput,
+    LiteralProbabilitiesNeedLocalCoder,
+    LiteralWorkerExited,
+    MemoryExceedsLimit,
     MissingAllocator(u8),
     WrongInternalDecoderState,
     WrongInternalEncoderState(u8),
@@ -61,6 +78,139 @@ pub enum ErrMsg {
     MainFunctionCalledFromThread(u8),
     DecodingDecoderAlreadyFreed,
     AdvContextMapNotBoolean(u8),
+    UnsupportedFormatVersion(u8),
+    UnsupportedCDFFlavor(u8),
+    UnsupportedEntropyCoder(u8),
+    UnsupportedChecksumKind(u8),
+    UnknownFeatureFlags(u8, u8),
+    UnsupportedLiteralHashSize(u8),
+    UnexpectedSeekTable,
+    SeekTableMissing,
+    SeekTableCorrupt(u8),
+    SeekTableFrameTooLarge(u64),
+    BrotliBitstreamCorrupt(u8),
+    BrotliBitstreamTruncated,
+    BrotliWindowUnsupported(u8),
+    BrotliSideDataCorrupt(u8),
+    BrotliRoundTripMismatch,
+    BrotliTranscodeFailed(u8),
+    DeflateBitstreamCorrupt(u8),
+    DeflateUnsupported(u8),
+    DeflateSideDataCorrupt(u8),
+    DeflateRoundTripMismatch,
+    PriorSnapshotCorrupt(u8),
+    PriorSnapshotMismatch(u32),
+    BadCompressionLevel(u8),
+    LiteralMatchWithoutMixer,
+    SyncPointsNotEnabled,
+}
+
+impl core::fmt::Display for ErrMsg {
+    fn fmt(&self, f:&mut core::fmt::Formatter) -> core::result::Result<(), core::fmt::Error> {
+        match *self {
+            ErrMsg::PredictionModeFail(()) => write!(f, "the prediction mode could not be applied"),
+            ErrMsg::ShutdownCoderNeedsInput => write!(f, "an entropy coder asked for input while shutting down"),
+            ErrMsg::EncodeOneCommandNeedsInput => write!(f, "the end of stream marker could not be coded without more input"),
+            ErrMsg::NotAllowedToFlushIfPreviousCommandPartial => write!(f, "flush was called while a command was only partly coded"),
+            ErrMsg::NotAllowedToEncodeAfterFlush => write!(f, "data was passed to the compressor after it was flushed"),
+            ErrMsg::NotAllowedToEncodeDuringSyncFlush => write!(f, "data was passed to the compressor during a sync flush"),
+            ErrMsg::Distance0NotAllowed => write!(f, "a copy command has a distance of zero"),
+            ErrMsg::DrainOrFillNeedsInput(code) => write!(f, "an entropy coder ran out of input while draining (code {})", code),
+            ErrMsg::BrotliIrGenFlushStreamNeedsInput => write!(f, "the brotli command generator asked for input during flush"),
+            ErrMsg::AssemblerStreamReportsDone => write!(f, "the command assembler finished before the input did"),
+            ErrMsg::UnexpectedEof => write!(f, "the stream ended before it was complete"),
+            ErrMsg::TrailingInput(count) => write!(f, "{} or more bytes follow the end of the stream", count),
+            ErrMsg::InputChangedAfterContinuation => write!(f, "the input changed between calls that continued one command"),
+            ErrMsg::DistanceGreaterRingBuffer => write!(f, "a copy reaches further back than the window"),
+            ErrMsg::DictTransformDiffersFromExpectedSize => write!(f, "a dictionary transform produced a word of the wrong size"),
+            ErrMsg::MinLogicError => write!(f, "an internal size computation went negative"),
+            ErrMsg::InputOffsetOutOfBounds => write!(f, "an input offset lies beyond the end of the input"),
+            ErrMsg::CommandCodeOutOfBounds(code) => write!(f, "command type {} is not defined", code),
+            ErrMsg::CopyDistanceMnemonicCodeBad(low, high) => write!(
+                f, "copy distance code {} is not defined", u16::from(low) | (u16::from(high) << 8)),
+            ErrMsg::CopyLengthTooLarge(bits) => write!(f, "a copy length of {} bits is too large", bits),
+            ErrMsg::BadChecksum(computed, stored) => write!(
+                f, "the checksum does not match the decoded data (computed byte {:#04x}, stored {:#04x})", computed, stored),
+            ErrMsg::IndexBeyondContextMapSize(low, high) => write!(
+                f, "context map index {} is beyond the end of the map", u16::from(low) | (u16::from(high) << 8)),
+            ErrMsg::PredictionModeOutOfBounds(mode) => write!(f, "literal prediction mode {} is not defined", mode),
+            ErrMsg::DictWordSizeTooLarge(size) => write!(f, "dictionary word size {} is too large", size),
+            ErrMsg::DictWordIdOutOfBounds(size) => write!(f, "the dictionary has no such word of size {}", size),
+            ErrMsg::DictTransformIndexUndefined(index) => write!(f, "dictionary transform {} is not defined", index),
+            ErrMsg::AdaptationSpeedOutOfBounds(speed) => write!(f, "adaptation speed code {} is out of range", speed),
+            ErrMsg::BrotliCompressStreamFail(_, _) => write!(f, "the brotli encoder failed"),
+            ErrMsg::BrotliInternalEncodeStreamNeedsOutputWithoutFlush => write!(f, "the brotli encoder asked for output outside of flush"),
+            ErrMsg::MagicNumberWrongA(first, second) | ErrMsg::MagicNumberWrongB(first, second) => write!(
+                f, "the stream does not start with the divans magic number (found {:#04x} {:#04x})", first, second),
+            ErrMsg::BadWindowSize(size) => write!(f, "window size {} is outside of the range 10 to 24", size),
+            ErrMsg::WindowSizeExceedsLimit(size) => write!(f, "window size {} exceeds the configured limit", size),
+            ErrMsg::OutputSizeExceedsLimit => write!(f, "the decoded output exceeds the configured size limit"),
+            ErrMsg::ContentLengthMismatch => write!(f, "the decoded length differs from the length stored in the trailer"),
+            ErrMsg::ContentHashMismatch => write!(f, "the hash of the decoded data differs from the hash stored in the trailer"),
+            ErrMsg::ContentTrailerNeedsRawInput => write!(f, "a content trailer requires raw input rather than commands"),
+            ErrMsg::ParallelCompressionNeedsRawInput => write!(f, "parallel compression requires raw input rather than commands"),
+            ErrMsg::LiteralProbabilitiesNeedLocalCoder => write!(f, "literals with external probabilities cannot be coded on a literal thread"),
+            ErrMsg::LiteralWorkerExited => write!(f, "the literal coding thread stopped before the stream was complete"),
+            ErrMsg::MemoryExceedsLimit => write!(f, "decoding would need more memory than the configured estimate allows"),
+            ErrMsg::MissingAllocator(kind) => write!(f, "the allocator for {} bit items is missing", kind),
+            ErrMsg::WrongInternalDecoderState => write!(f, "the decoder is in the wrong state for this call"),
+            ErrMsg::WrongInternalEncoderState(code) => write!(f, "the encoder is in the wrong state for this call (code {})", code),
+            ErrMsg::UnintendedCodecState(code) => write!(f, "the codec reached a state it should not (code {})", code),
+            ErrMsg::MainFunctionCalledFromThread(code) => write!(f, "a main thread function was called from a worker (code {})", code),
+            ErrMsg::DecodingDecoderAlreadyFreed => write!(f, "the decoder was used after it was freed"),
+            ErrMsg::AdvContextMapNotBoolean(value) => write!(f, "the context map flag {} is neither 0 nor 1", value),
+            ErrMsg::UnsupportedFormatVersion(version) => write!(f, "format version {} is not supported by this build", version),
+            ErrMsg::UnsupportedCDFFlavor(flavor) => write!(f, "CDF flavor {} is not supported by this build", flavor),
+            ErrMsg::UnsupportedEntropyCoder(coder) => write!(f, "entropy coder {} is not supported by this build", coder),
+            ErrMsg::UnsupportedChecksumKind(kind) => write!(f, "checksum kind {} is not supported by this build", kind),
+            ErrMsg::UnknownFeatureFlags(index, value) => write!(f, "header byte {} has unknown feature bits {:#04x}", index, value),
+            ErrMsg::UnsupportedLiteralHashSize(size) => write!(f, "a literal hash table of 2^{} entries is not supported", size),
+            ErrMsg::UnexpectedSeekTable => write!(f, "a seek table appears before any frame"),
+            ErrMsg::SeekTableMissing => write!(f, "the stream does not end with a seek table"),
+            ErrMsg::SeekTableCorrupt(code) => write!(f, "the seek table is corrupt (code {})", code),
+            ErrMsg::SeekTableFrameTooLarge(size) => write!(f, "a frame of {} bytes is too large for the seek table", size),
+            ErrMsg::BrotliBitstreamCorrupt(code) => write!(f, "the brotli stream is corrupt (code {})", code),
+            ErrMsg::BrotliBitstreamTruncated => write!(f, "the brotli stream is truncated"),
+            ErrMsg::BrotliWindowUnsupported(size) => write!(f, "brotli window size {} is not supported", size),
+            ErrMsg::BrotliSideDataCorrupt(code) => write!(f, "the side data of a recompressed brotli file is corrupt (code {})", code),
+            ErrMsg::BrotliRoundTripMismatch => write!(f, "the brotli file cannot be reproduced bit for bit"),
+            ErrMsg::BrotliTranscodeFailed(code) => write!(f, "conversion to brotli failed (code {})", code),
+            ErrMsg::DeflateBitstreamCorrupt(code) => write!(f, "the DEFLATE stream is corrupt (code {})", code),
+            ErrMsg::DeflateUnsupported(code) => write!(f, "the DEFLATE stream uses an unsupported feature (code {})", code),
+            ErrMsg::DeflateSideDataCorrupt(code) => write!(f, "the side data of a recompressed DEFLATE file is corrupt (code {})", code),
+            ErrMsg::DeflateRoundTripMismatch => write!(f, "the DEFLATE file cannot be reproduced bit for bit"),
+            ErrMsg::PriorSnapshotCorrupt(code) => write!(f, "the prior snapshot is corrupt (code {})", code),
+            ErrMsg::PriorSnapshotMismatch(id) => write!(f, "the stream starts from prior snapshot {:08x}, which is not loaded", id),
+            ErrMsg::BadCompressionLevel(level) => write!(f, "compression level {} is outside of the range 1 to 12", level),
+            ErrMsg::LiteralMatchWithoutMixer => write!(f, "the literal match model was enabled without the literal mixer"),
+            ErrMsg::SyncPointsNotEnabled => write!(f, "a sync flush needs the sync_points option, which announces sync points in the header"),
+        }
+    }
+}
+
+// a failure together with where in the stream it happened
+#[derive(Copy,Clone,Debug,PartialEq)]
+pub struct DivansError {
+    pub msg: ErrMsg,
+    // bytes of compressed input consumed when the failure was reported
+    pub compressed_offset: u64,
+    // bytes of output produced before the failure
+    pub decoded_offset: u64,
+    // commands executed before the failure
+    pub command_index: u64,
+    // what the command decoder was doing, when it could be inspected
+    pub state: Option<EncodeOrDecodeState>,
+}
+
+impl core::fmt::Display for DivansError {
+    fn fmt(&self, f:&mut core::fmt::Formatter) -> core::result::Result<(), core::fmt::Error> {
+        try!(write!(f, "{} at compressed byte {}, after {} decoded bytes and {} commands",
+                    self.msg, self.compressed_offset, self.decoded_offset, self.command_index));
+        if let Some(state) = self.state {
+            try!(write!(f, ", while {}", state));
+        }
+        Ok(())
+    }
 }
 
 
@@ -142,15 +292,15 @@ impl From<DivansOutputResult> for DivansResult {
     }
 }
 
-// The choice of CDF16 struct is controlled by feature flags.
-#[cfg(feature="blend")]
-pub type DefaultInternalCDF16 = probability::BlendCDF16;
-#[cfg(all(not(any(feature="blend")), feature="uncached_frequentist"))]
-pub type DefaultInternalCDF16 = probability::FrequentistCDF16;
-#[cfg(all(not(any(feature="blend", feature="uncached_frequentist")), feature="simd"))]
-pub type DefaultInternalCDF16 = probability::SIMDFrequentistCDF16;
-#[cfg(all(not(any(feature="blend", feature="uncached_frequentist", feature="simd"))))]
-pub type DefaultInternalCDF16 = probability::OptFrequentistCDF16;
+// The frequentist CDF implementation is controlled by feature flags; whether the codec blends
+// or counts is read from the stream header, so one build decodes every flavor.
+#[cfg(feature="uncached_frequentist")]
+pub type DefaultFrequentistCDF16 = probability::FrequentistCDF16;
+#[cfg(all(not(feature="uncached_frequentist"), feature="simd"))]
+pub type DefaultFrequentistCDF16 = probability::SIMDFrequentistCDF16;
+#[cfg(not(any(feature="uncached_frequentist", feature="simd")))]
+pub type DefaultFrequentistCDF16 = probability::OptFrequentistCDF16;
+pub type DefaultInternalCDF16 = probability::RuntimeCDF16<DefaultFrequentistCDF16>;
 
 #[cfg(feature="debug_entropy")]
 #[cfg(not(feature="findspeed"))]
@@ -162,6 +312,255 @@ pub type DefaultCDF16 = probability::VariantSpeedCDF<DefaultInternalCDF16>;
 
 pub const HEADER_LENGTH: usize = 16;
 pub const MAGIC_NUMBER:[u8;4] = [0xff, 0xe5,0x8c, 0x9f];
+pub const HEADER_VERSION_INDEX: usize = 4;
+pub const HEADER_WINDOW_SIZE_INDEX: usize = 5;
+// little endian u32 holding the StreamFeatures bitmask; bytes after it are reserved and must be zero
+pub const HEADER_FEATURE_FLAGS_INDEX: usize = 6;
+pub const HEADER_FEATURE_FLAGS_LENGTH: usize = 4;
+// little endian u32 id of the prior snapshot the stream starts from, present when the flags name one
+pub const HEADER_PRIOR_SNAPSHOT_ID_INDEX: usize = 10;
+pub const HEADER_PRIOR_SNAPSHOT_ID_LENGTH: usize = 4;
+// streams from before the header was versioned have zeros in every byte but the magic and window
+pub const FORMAT_VERSION_UNVERSIONED: u8 = 0;
+pub const FORMAT_VERSION: u8 = 1;
+
+// A seekable stream is a series of complete divans streams (frames) followed by a seek table:
+// each frame sets the framed flag so that the decoder reads on past its end.
+// SEEK_TABLE_MAGIC, u32 frame count, one entry per frame, u32 frame count, SEEK_TABLE_MAGIC.
+// Each entry is the little endian u32 compressed size followed by the u32 uncompressed size.
+pub const SEEK_TABLE_MAGIC:[u8;4] = [0xfe, 0xe5, 0x8c, 0x9f];
+pub const SEEK_TABLE_ENTRY_LENGTH: usize = 8;
+pub const SEEK_TABLE_FOOTER_LENGTH: usize = 8;
+
+// A recompressed brotli file: RECOMPRESSED_BROTLI_MAGIC, a version byte, three reserved bytes,
+// the little endian u32 length of the brotli compressed side data, the side data and then a divans
+// stream holding the commands of the original file.
+pub const RECOMPRESSED_BROTLI_MAGIC:[u8;4] = [0xfd, 0xe5, 0x8c, 0x9f];
+pub const RECOMPRESSED_BROTLI_VERSION: u8 = 1;
+pub const RECOMPRESSED_BROTLI_HEADER_LENGTH: usize = 12;
+
+// A recompressed DEFLATE, zlib or gzip file, laid out like a recompressed brotli file but starting
+// with RECOMPRESSED_DEFLATE_MAGIC.
+pub const RECOMPRESSED_DEFLATE_MAGIC:[u8;4] = [0xfd, 0xe5, 0x8c, 0x9e];
+pub const RECOMPRESSED_DEFLATE_VERSION: u8 = 1;
+pub const RECOMPRESSED_DEFLATE_HEADER_LENGTH: usize = 12;
+
+// A prior snapshot: PRIOR_SNAPSHOT_MAGIC, a version byte, the CDFFlavor byte, two reserved bytes and
+// the little endian u32 id, followed by the body. The body holds the four literal adaptation speeds
+// as f8 pairs, the literal mixing mask and then, for each prior table, a little endian u32 count of
+// entries, each a u32 index and the sixteen u16 words of a CDF that differs from the initial one.
+// The id is the low half of the XXH64 of the flavor byte and the body.
+pub const PRIOR_SNAPSHOT_MAGIC:[u8;4] = [0xfc, 0xe5, 0x8c, 0x9f];
+pub const PRIOR_SNAPSHOT_VERSION: u8 = 1;
+pub const PRIOR_SNAPSHOT_HEADER_LENGTH: usize = 12;
+
+// A frame whose header sets the trailer metadata flag is followed by TRAILER_LENGTH bytes:
+// the little endian u64 length of the content of the frame and its little endian u64 XXH64.
+pub const TRAILER_LENGTH: usize = 16;
+
+const FEATURE_CDF_FLAVOR_SHIFT: u32 = 0;
+const FEATURE_ENTROPY_CODER_SHIFT: u32 = 2;
+const FEATURE_CHECKSUM_KIND_SHIFT: u32 = 4;
+const FEATURE_FIELD_MASK: u32 = 0x3;
+const FEATURE_TRAILER_METADATA: u32 = 1 << 6;
+const FEATURE_LITERAL_MIXER: u32 = 1 << 7;
+const FEATURE_LITERAL_SSE: u32 = 1 << 8;
+const FEATURE_LITERAL_HASH_SIZE_SHIFT: u32 = 9;
+const FEATURE_LITERAL_HASH_SIZE_MASK: u32 = 0x1f;
+const FEATURE_PRIOR_SNAPSHOT: u32 = 1 << 14;
+const FEATURE_FRAMED: u32 = 1 << 15;
+const FEATURE_LITERAL_MATCH: u32 = 1 << 16;
+const FEATURE_SYNC_POINTS: u32 = 1 << 17;
+const FEATURE_KNOWN_BITS: u32 = (FEATURE_FIELD_MASK << FEATURE_CDF_FLAVOR_SHIFT)
+    | (FEATURE_FIELD_MASK << FEATURE_ENTROPY_CODER_SHIFT)
+    | (FEATURE_FIELD_MASK << FEATURE_CHECKSUM_KIND_SHIFT)
+    | FEATURE_TRAILER_METADATA
+    | FEATURE_LITERAL_MIXER
+    | FEATURE_LITERAL_SSE
+    | (FEATURE_LITERAL_HASH_SIZE_MASK << FEATURE_LITERAL_HASH_SIZE_SHIFT)
+    | FEATURE_PRIOR_SNAPSHOT
+    | FEATURE_FRAMED
+    | FEATURE_LITERAL_MATCH
+    | FEATURE_SYNC_POINTS;
+
+// the range of literal_hash_size, the log2 of the entries in the hashed literal context table
+pub const MIN_LITERAL_HASH_SIZE: u8 = 10;
+pub const MAX_LITERAL_HASH_SIZE: u8 = 22;
+
+pub use probability::CDFFlavor;
+
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, PartialEq, Default)]
+pub enum EntropyCoder {
+    #[default]
+    ANS = 0,
+    Range = 1,
+}
+
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ChecksumKind {
+    Crc32c = 0,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct StreamFeatures {
+    pub cdf_flavor: CDFFlavor,
+    pub entropy_coder: EntropyCoder,
+    pub checksum_kind: ChecksumKind,
+    pub trailer_metadata: bool,
+    // literals are coded from a learned mix of every literal predictor
+    pub literal_mixer: bool,
+    // literal nibble distributions are refined by secondary symbol estimation before coding
+    pub literal_sse: bool,
+    // log2 of the entries in the hashed order-2/3 literal context table the mixer reads, 0 for none
+    pub literal_hash_size: u8,
+    // id of the prior snapshot both sides load before the first command; None for the default priors
+    pub prior_snapshot_id: Option<u32>,
+    // another frame or the seek table follows this frame; otherwise decoding stops where it ends
+    pub framed: bool,
+    // the mixer also blends a prediction that literals continue the bytes after the last copy source
+    pub literal_match: bool,
+    // the command stream may hold sync points, where every coder ends its chunk mid-stream
+    pub sync_points: bool,
+}
+
+impl StreamFeatures {
+    // the features this build of the library writes unless told otherwise
+    pub fn compiled() -> Self {
+        StreamFeatures {
+            cdf_flavor: CDFFlavor::default(),
+            entropy_coder: EntropyCoder::default(),
+            checksum_kind: ChecksumKind::Crc32c,
+            trailer_metadata: false,
+            literal_mixer: false,
+            literal_sse: false,
+            literal_hash_size: 0,
+            prior_snapshot_id: None,
+            framed: false,
+            literal_match: false,
+            sync_points: false,
+        }
+    }
+    pub fn to_flags(&self) -> u32 {
+        ((self.cdf_flavor as u32) << FEATURE_CDF_FLAVOR_SHIFT)
+            | ((self.entropy_coder as u32) << FEATURE_ENTROPY_CODER_SHIFT)
+            | ((self.checksum_kind as u32) << FEATURE_CHECKSUM_KIND_SHIFT)
+            | if self.trailer_metadata {FEATURE_TRAILER_METADATA} else {0}
+            | if self.literal_mixer {FEATURE_LITERAL_MIXER} else {0}
+            | if self.literal_sse {FEATURE_LITERAL_SSE} else {0}
+            | (u32::from(self.literal_hash_size) << FEATURE_LITERAL_HASH_SIZE_SHIFT)
+            | if self.prior_snapshot_id.is_some() {FEATURE_PRIOR_SNAPSHOT} else {0}
+            | if self.framed {FEATURE_FRAMED} else {0}
+            | if self.literal_match {FEATURE_LITERAL_MATCH} else {0}
+            | if self.sync_points {FEATURE_SYNC_POINTS} else {0}
+    }
+    // the flag word as it is laid out in the header
+    pub fn to_bytes(&self) -> [u8; HEADER_FEATURE_FLAGS_LENGTH] {
+        u32_bytes(self.to_flags())
+    }
+    // decodes the flag word at the start of flag_bytes, as from_flags does
+    pub fn from_bytes(flag_bytes: &[u8]) -> Result<Self, ErrMsg> {
+        Self::from_flags(read_u32(flag_bytes))
+    }
+    // decodes a flag word and checks that every feature it names is supported by this build;
+    // the prior snapshot id is not part of the flags, so it comes back as Some(0) when one is named
+    pub fn from_flags(flags: u32) -> Result<Self, ErrMsg> {
+        let unknown = flags & !FEATURE_KNOWN_BITS;
+        if unknown != 0 {
+            let bit = unknown.trailing_zeros();
+            return Err(ErrMsg::UnknownFeatureFlags((HEADER_FEATURE_FLAGS_INDEX as u32 + bit / 8) as u8,
+                                                   (flags >> (bit & !7)) as u8));
+        }
+        let mut compiled = Self::compiled();
+        compiled.cdf_flavor = match ((flags >> FEATURE_CDF_FLAVOR_SHIFT) & FEATURE_FIELD_MASK) as u8 {
+            0 => CDFFlavor::Frequentist,
+            1 => CDFFlavor::Blend,
+            cdf_flavor => return Err(ErrMsg::UnsupportedCDFFlavor(cdf_flavor)),
+        };
+        compiled.entropy_coder = match ((flags >> FEATURE_ENTROPY_CODER_SHIFT) & FEATURE_FIELD_MASK) as u8 {
+            0 => EntropyCoder::ANS,
+            1 => EntropyCoder::Range,
+            entropy_coder => return Err(ErrMsg::UnsupportedEntropyCoder(entropy_coder)),
+        };
+        let checksum_kind = ((flags >> FEATURE_CHECKSUM_KIND_SHIFT) & FEATURE_FIELD_MASK) as u8;
+        if checksum_kind != compiled.checksum_kind as u8 {
+            return Err(ErrMsg::UnsupportedChecksumKind(checksum_kind));
+        }
+        compiled.trailer_metadata = (flags & FEATURE_TRAILER_METADATA) != 0;
+        compiled.literal_mixer = (flags & FEATURE_LITERAL_MIXER) != 0;
+        compiled.literal_sse = (flags & FEATURE_LITERAL_SSE) != 0;
+        compiled.literal_hash_size = ((flags >> FEATURE_LITERAL_HASH_SIZE_SHIFT) & FEATURE_LITERAL_HASH_SIZE_MASK) as u8;
+        // the hashed table only feeds the literal mixer, so a size without the mixer is malformed
+        if compiled.literal_hash_size != 0
+            && (!compiled.literal_mixer
+                || !(MIN_LITERAL_HASH_SIZE..=MAX_LITERAL_HASH_SIZE).contains(&compiled.literal_hash_size)) {
+            return Err(ErrMsg::UnsupportedLiteralHashSize(compiled.literal_hash_size));
+        }
+        if (flags & FEATURE_PRIOR_SNAPSHOT) != 0 {
+            compiled.prior_snapshot_id = Some(0);
+        }
+        compiled.framed = (flags & FEATURE_FRAMED) != 0;
+        // like the hashed table, the match prediction is only read by the literal mixer
+        compiled.literal_match = (flags & FEATURE_LITERAL_MATCH) != 0;
+        if compiled.literal_match && !compiled.literal_mixer {
+            return Err(ErrMsg::LiteralMatchWithoutMixer);
+        }
+        compiled.sync_points = (flags & FEATURE_SYNC_POINTS) != 0;
+        Ok(compiled)
+    }
+}
+
+// the uncompressed length and content hash recorded in a frame trailer
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ContentSummary {
+    pub length: u64,
+    pub hash: u64,
+}
+
+impl ContentSummary {
+    pub fn to_trailer(&self) -> [u8; TRAILER_LENGTH] {
+        let mut retval = [0u8; TRAILER_LENGTH];
+        retval[..8].clone_from_slice(&u64_bytes(self.length)[..]);
+        retval[8..].clone_from_slice(&u64_bytes(self.hash)[..]);
+        retval
+    }
+    pub fn from_trailer(trailer: &[u8]) -> Self {
+        ContentSummary {
+            length: read_u64(trailer),
+            hash: read_u64(&trailer[8..]),
+        }
+    }
+    // reads the trailer of a complete single frame stream so its output can be sized before decoding
+    pub fn from_stream(stream: &[u8]) -> Option<Self> {
+        if stream.len() < HEADER_LENGTH + TRAILER_LENGTH || stream[..MAGIC_NUMBER.len()] != MAGIC_NUMBER[..]
+            || stream[HEADER_VERSION_INDEX] != FORMAT_VERSION
+            || (u32::from(stream[HEADER_FEATURE_FLAGS_INDEX]) & FEATURE_TRAILER_METADATA) == 0 {
+            return None;
+        }
+        Some(Self::from_trailer(&stream[stream.len() - TRAILER_LENGTH..]))
+    }
+}
+
+// tracks the content of a frame as it is compressed or decompressed
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ContentDigest {
+    length: u64,
+    hasher: XxHash64,
+}
+
+impl ContentDigest {
+    pub fn write(&mut self, data: &[u8]) {
+        self.length += data.len() as u64;
+        self.hasher.write(data);
+    }
+    pub fn summary(&self) -> ContentSummary {
+        ContentSummary {
+            length: self.length,
+            hash: self.hasher.finish(),
+        }
+    }
+}
 
 // Commands that can instantiate as a no-op should implement this.
 /*
@@ -265,6 +664,17 @@ pub trait StreamMuxer<AllocU8: Allocator<u8> > {
     fn flush(&mut self, output:&mut[u8]) -> usize;
     #[inline(always)]
     fn wrote_eof(&self) -> bool;
+    // serializes everything buffered so far without closing the stream
+    fn sync_flush(&mut self, output:&mut[u8]) -> usize;
+    fn wrote_all_data(&self) -> bool;
+    // writes out whatever a literal coder on another thread has produced so far
+    fn drain_remote_coders(&mut self, _output:&mut[u8], _output_offset:&mut usize) -> DivansOutputResult {
+        DivansOutputResult::Success
+    }
+    // like drain_remote_coders, but only writes what is already finished instead of waiting for it
+    fn drain_finished_remote_coders(&mut self, _output:&mut[u8], _output_offset:&mut usize) -> DivansOutputResult {
+        DivansOutputResult::Success
+    }
     #[inline(always)]
     fn free_mux(&mut self, m8: &mut AllocU8);
 }
@@ -285,6 +695,10 @@ pub trait StreamDemuxer<AllocU8: Allocator<u8> > {
     fn consumed_all_streams_until_eof(&self) -> bool;
     #[inline(always)]
     fn encountered_eof(&self) -> bool;
+    // true once input was dropped because the allocator refused a buffer to hold it
+    fn out_of_memory(&self) -> bool {
+        false
+    }
     #[inline(always)]
     fn free_demux(&mut self, m8: &mut AllocU8);
 }
@@ -303,6 +717,11 @@ pub trait Compressor {
     fn flush(&mut self,
                                           output :&mut[u8],
                                           output_offset: &mut usize) -> DivansOutputResult;
+    // makes all input so far decodable from the output without ending the stream; fails with
+    // SyncPointsNotEnabled unless the options set sync_points
+    fn sync_flush(&mut self,
+                  output :&mut[u8],
+                  output_offset: &mut usize) -> DivansOutputResult;
 }
 
 pub trait Decompressor {
@@ -347,6 +766,10 @@ pub enum CrossCommandBilling {
 
 pub trait NewWithAllocator<AllocU8: Allocator<u8>> {
     fn new(m8: &mut AllocU8) -> Self;
+    // entropy coders that can switch backends at runtime override this
+    fn new_with_entropy_coder(m8: &mut AllocU8, _entropy_coder: EntropyCoder) -> Self where Self: Sized {
+        Self::new(m8)
+    }
     fn free(&mut self, m8: &mut AllocU8);
 }
 
@@ -413,8 +836,18 @@ pub trait ArithmeticEncoderOrDecoder : Sized {
                                    _billing: BillingDesignation) -> ProbRange {
         self.get_or_put_nibble_without_billing(nibble, prob)
     }
+    // accounts for what coding nibble with prob would have cost, without coding anything, so that
+    // billing can report a model next to the one actually used
+    #[inline(always)]
+    fn estimate_nibble<C: CDF16>(&mut self,
+                                 _nibble: u8,
+                                 _prob: &C,
+                                 _billing: BillingDesignation) {
+    }
 
     fn close(&mut self) -> DivansResult;
+    // ends the current chunk so that everything coded so far can be decoded, but keeps going
+    fn sync(&mut self) -> DivansResult;
 }
 pub trait DivansCompressorFactory<
      AllocU8:Allocator<u8>,
@@ -459,6 +892,31 @@ pub struct DivansCompressorOptions{
     pub prior_bitmask_detection: u8,
     pub brotli_literal_byte_score: Option<u32>,
     pub divans_ir_optimizer: u8,
+    pub cdf_flavor: Option<CDFFlavor>,
+    pub entropy_coder: Option<EntropyCoder>,
+    // append the length and hash of the input after the compressed data
+    pub content_trailer: bool,
+    // worker threads compressing independent frames; 0 and 1 compress serially
+    pub num_threads: u32,
+    // code literals with the N-way literal mixer (announced in the stream header)
+    pub literal_mixer: bool,
+    // refine literal probabilities with secondary symbol estimation (announced in the stream header)
+    pub literal_sse: bool,
+    // log2 of the entries in a hashed order-2/3 literal context table; implies literal_mixer, which
+    // reads it. Clamped to MIN_LITERAL_HASH_SIZE..=MAX_LITERAL_HASH_SIZE. It pays off on text with
+    // multibyte characters (about 1% on the UTF-8 tail of testdata/random_then_unicode at 2^18
+    // entries with speed detection on); on English text such as testdata/alice29 the gain stays
+    // under 0.2% and at low qualities it can cost a few bytes
+    pub literal_hash_size: Option<u8>,
+    // mark the stream as one frame of a framed stream, so the decoder goes on to the next frame or
+    // the seek table after it; set by the framed and parallel compressors for each frame they write
+    pub framed: bool,
+    // add the match model, which predicts that literals continue the last copy, to the literal
+    // mixer; implies literal_mixer (announced in the stream header)
+    pub literal_match: bool,
+    // allow sync_flush on the compressor; announced in the stream header, since a decoder rejects
+    // sync points in streams that do not announce them
+    pub sync_points: bool,
 }
 impl Default for DivansCompressorOptions{
     fn default() ->Self {
@@ -479,6 +937,84 @@ impl Default for DivansCompressorOptions{
             prior_bitmask_detection: 1,
             brotli_literal_byte_score: None,
             divans_ir_optimizer: 0,
+            cdf_flavor: None,
+            entropy_coder: None,
+            content_trailer: false,
+            num_threads: 1,
+            literal_mixer: false,
+            literal_sse: false,
+            literal_hash_size: None,
+            framed: false,
+            literal_match: false,
+            sync_points: false,
         }
     }
 }
+
+// caps on the resources a decoder may spend on untrusted input; None leaves a resource unbounded
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct DivansDecompressorLimits {
+    pub max_window_size: Option<u8>,
+    // total bytes produced across every frame of the stream
+    pub max_output_size: Option<u64>,
+    // each frame header is rejected when the window, model and command buffers the decoder computes
+    // it needs exceed this many bytes, and so is each literal whose buffer would not fit in what is
+    // left. DivansDecompressorReader also meters its allocators against it with alloc_util::LimitedAlloc,
+    // so an allocation past the budget fails the stream with MemoryExceedsLimit; decoders built on
+    // other allocators get the same metering by wrapping them in a LimitedAlloc sharing one MemoryBudget
+    pub max_memory_estimate: Option<usize>,
+}
+
+impl DivansCompressorOptions {
+    // the features announced in the header of a stream compressed with these options
+    pub fn stream_features(&self) -> StreamFeatures {
+        StreamFeatures {
+            cdf_flavor: self.cdf_flavor.unwrap_or_default(),
+            entropy_coder: self.entropy_coder.unwrap_or_default(),
+            trailer_metadata: self.content_trailer,
+            literal_mixer: self.literal_mixer || self.literal_hash_size.is_some() || self.literal_match,
+            literal_sse: self.literal_sse,
+            literal_hash_size: self.literal_hash_size.map_or(0, |size| core::cmp::max(MIN_LITERAL_HASH_SIZE, core::cmp::min(size, MAX_LITERAL_HASH_SIZE))),
+            framed: self.framed,
+            literal_match: self.literal_match,
+            sync_points: self.sync_points,
+            ..StreamFeatures::compiled()
+        }
+    }
+    // options tuned for compression level 1 (fastest) through 12 (smallest)
+    pub fn level(level: u8) -> Result<Self, ErrMsg> {
+        let mut ret = DivansCompressorOptions::default();
+        try!(ret.apply_level(level));
+        Ok(ret)
+    }
+    // sets only the knobs that trade speed for size, so window, threads and stream features are kept.
+    // Each level is the fastest setting found that compresses the concatenation of testdata/alice29,
+    // asyoulik and random_then_unicode (569217 bytes) smaller than the level below it:
+    //   level           1      2      3      4      5      6      7      8      9     10     11     12
+    //   bytes      248866 245145 233532 231812 230984 230080 229676 227984 214897 214168 213233 211016
+    //   seconds      0.13   0.12   0.15   0.20   0.20   0.25   0.26   0.52   0.88   1.02   1.37   2.75
+    // Quality 11 never made the cut: quality 10 with the same settings was both smaller and faster.
+    pub fn apply_level(&mut self, level: u8) -> Result<(), ErrMsg> {
+        let (quality, ir_optimizer, prior_bitmask_detection, dynamic_context_mixing) = match level {
+            1 => (2, 0, 0, 1),
+            2 => (4, 0, 0, 1),
+            3 => (6, 0, 0, 1),
+            4 => (7, 0, 0, 1),
+            5 => (8, 0, 0, 1),
+            6 => (8, 0, 0, 2),
+            7 => (9, 0, 0, 2),
+            8 => (9, 1, 0, 2),
+            9 => (10, 0, 0, 2),
+            10 => (10, 0, 1, 2),
+            11 => (10, 1, 1, 2),
+            12 => (10, 2, 1, 2),
+            _ => return Err(ErrMsg::BadCompressionLevel(level)),
+        };
+        self.quality = Some(quality);
+        self.q9_5 = false;
+        self.divans_ir_optimizer = ir_optimizer;
+        self.prior_bitmask_detection = prior_bitmask_detection;
+        self.dynamic_context_mixing = Some(dynamic_context_mixing);
+        Ok(())
+    }
+}
@@ -7,12 +7,68 @@ use codec::io::DemuxerAndRingBuffer;
 pub use super::cmd_to_divans::EncoderSpecialization;
 use brotli::interface::{Command, LiteralCommand, CopyCommand, Nop, PredictionModeContextMap, StaticCommand};
 use alloc_util;
-use alloc::{SliceWrapper, Allocator};
+use alloc::{SliceWrapper, SliceWrapperMut, Allocator};
 pub use super::interface::{ArithmeticEncoderOrDecoder, NewWithAllocator, DivansResult, ErrMsg};
 mod statistics_tracking_codec;
 mod cache;
+mod optimal_parse;
 use self::statistics_tracking_codec::{TallyingArithmeticEncoder, OneCommandThawingArray, TwoCommandThawingArray, ToggleProbabilityBlend,
-                                      take_billing_snapshot, billing_snapshot_delta,reset_billing_snapshot};
+                                      take_billing_snapshot, billing_snapshot_delta,reset_billing_snapshot, total_billing_cost};
+
+pub type Actuary<SelectedCDF, AllocU8, AllocCDF16> = codec::DivansCodec<TallyingArithmeticEncoder,
+                                                                        ToggleProbabilityBlend,
+                                                                        DemuxerAndRingBuffer<AllocU8,
+                                                                                             DevNull<AllocU8>>,
+                                                                        DevNull<AllocU8>,
+                                                                        SelectedCDF,
+                                                                        AllocU8,
+                                                                        AllocCDF16>;
+
+// an actuary prices commands without emitting anything; it starts from fresh priors with pm already applied
+fn new_actuary<SelectedCDF:CDF16,
+               AllocU8:Allocator<u8>,
+               AllocCDF16:Allocator<SelectedCDF>>(m8: AllocU8,
+                                                  mcdf16: AllocCDF16,
+                                                  pm:&brotli::interface::PredictionModeContextMap<brotli::InputReferenceMut>,
+                                                  window_size: u8,
+                                                  opt: &super::interface::DivansCompressorOptions) -> Result<Actuary<SelectedCDF, AllocU8, AllocCDF16>, ErrMsg> {
+    let mut unused = 0usize;
+    let mut unused2 = 0usize;
+    let mut actuary = Actuary::<SelectedCDF, AllocU8, AllocCDF16>::new(m8,
+                                                                       mcdf16,
+                                                                       TallyingArithmeticEncoder::default(),
+                                                                       TallyingArithmeticEncoder::default(),
+                                                                       ToggleProbabilityBlend::default(),
+                                                                       DemuxerAndRingBuffer::<AllocU8, DevNull<AllocU8>>::default(),
+                                                                       usize::from(window_size),
+                                                                       opt.dynamic_context_mixing.unwrap_or(0),
+                                                                       opt.prior_depth,
+                                                                       opt.literal_adaptation,
+                                                                       opt.use_context_map,
+                                                                       opt.force_stride_value,
+                                                                       false);
+    actuary.set_cdf_flavor(opt.cdf_flavor.unwrap_or_default());
+    let immutable_pm = Command::PredictionMode(PredictionModeContextMap::<brotli::InputReference>{
+        literal_context_map:brotli::InputReference::from(&pm.literal_context_map),
+        predmode_speed_and_distance_context_map:brotli::InputReference::from(&pm.predmode_speed_and_distance_context_map),
+    });
+    let mut cmd_offset = 0usize;
+    match actuary.encode_or_decode(&[], &mut unused, &mut[], &mut unused2,
+                                   &codec::CommandSliceArray(&[immutable_pm]),&mut cmd_offset) {
+        DivansResult::NeedsMoreOutput => {
+            return Err(ErrMsg::DrainOrFillNeedsInput(2));
+        },
+        DivansResult::Failure(e) => {
+            return Err(e);
+        }
+        DivansResult::NeedsMoreInput | DivansResult::Success => {
+            if cmd_offset != 1 {
+                return Err(ErrMsg::DrainOrFillNeedsInput(3));
+            }
+        }
+    }
+    Ok(actuary)
+}
 pub fn should_merge<SelectedCDF:CDF16,
                     AllocU8:Allocator<u8>,
                     AllocCDF16:Allocator<SelectedCDF>>(lit: &LiteralCommand<brotli::SliceOffset>,
@@ -109,78 +165,23 @@ pub fn should_merge<SelectedCDF:CDF16,
               copy_index, full_cost, copy.distance, copy.num_bytes, code, future_hit, cur_cost, combined_cost);*/
     Ok(combined_cost < cur_cost)
 }
-pub fn ir_optimize<'a, SelectedCDF:CDF16,
-                   ChosenEncoder: ArithmeticEncoderOrDecoder + NewWithAllocator<AllocU8>,
-                   AllocU8:Allocator<u8>,
-                   AllocCDF16:Allocator<SelectedCDF>,
-                   AllocCommand: Allocator<StaticCommand>
-                   >(pm:&mut brotli::interface::PredictionModeContextMap<brotli::InputReferenceMut>,
-                     orig_buf:&'a mut [brotli::interface::Command<brotli::SliceOffset>],
-                     mb:brotli::InputPair,
-                     codec:&mut codec::DivansCodec<ChosenEncoder,
-                                                   EncoderSpecialization,
-                                                   DemuxerAndRingBuffer<AllocU8,
-                                                                        DevNull<AllocU8>>,
-                                                   Mux<AllocU8>,
-                                                   SelectedCDF,
-                                                   AllocU8,
-                                                   AllocCDF16>,
-                     window_size: u8,
-                     opt: super::interface::DivansCompressorOptions,
-                     _mc: &'a mut AllocCommand,
-                     _buf: &'a mut AllocCommand::AllocatedMemory,
-) -> Result<&'a [brotli::interface::Command<brotli::SliceOffset>], ErrMsg> {
+// merges literal/copy pairs that the actuary prices cheaper as a single literal;
+// returns the surviving command count and what the actuary paid for them
+fn merge_pass<SelectedCDF:CDF16,
+              AllocU8:Allocator<u8>,
+              AllocCDF16:Allocator<SelectedCDF>>(m8: AllocU8,
+                                                 mcdf16: AllocCDF16,
+                                                 pm:&brotli::interface::PredictionModeContextMap<brotli::InputReferenceMut>,
+                                                 orig_buf:&mut [brotli::interface::Command<brotli::SliceOffset>],
+                                                 mb:brotli::InputPair,
+                                                 distance_lru: &[u32;4],
+                                                 window_size: u8,
+                                                 opt: &super::interface::DivansCompressorOptions) -> Result<(usize, f32, AllocU8, AllocCDF16), ErrMsg> {
     let mut unused = 0usize;
     let mut unused2 = 0usize;
-    if orig_buf.len() == 0 {
-        return Ok(orig_buf);
-    }
-    let (re_m8, mcdf16, remainder) = match core::mem::replace(&mut codec.cross_command_state.thread_ctx, codec::ThreadContext::Worker) {
-        codec::ThreadContext::MainThread(main) => main.dismantle(),
-        codec::ThreadContext::Worker => panic!("Main Thread was none during encode"),
-    };
-    let (mut m8, reallocation_item) = re_m8.disassemble();
-    let mut distance_cache = cache::Cache::<AllocU8>::new(&codec.cross_command_state.bk.distance_lru, orig_buf.len(), &mut m8);
-    let mut actuary = codec::DivansCodec::<TallyingArithmeticEncoder,
-                                           ToggleProbabilityBlend,
-                                           DemuxerAndRingBuffer<AllocU8, DevNull<AllocU8>>,
-                                           DevNull<AllocU8>,
-                                           SelectedCDF,
-                                           AllocU8,
-                                           AllocCDF16>::new(m8,
-                                                            mcdf16,
-                                                            TallyingArithmeticEncoder::default(),
-                                                            TallyingArithmeticEncoder::default(),
-                                                            ToggleProbabilityBlend::default(),
-                                                            DemuxerAndRingBuffer::<AllocU8, DevNull<AllocU8>>::default(),
-                                                            usize::from(window_size),
-                                                            opt.dynamic_context_mixing.unwrap_or(0),
-                                                            opt.prior_depth,
-                                                            opt.literal_adaptation,
-                                                            opt.use_context_map,
-                                                            opt.force_stride_value,
-                                                            false);
-    {
-        let immutable_pm = Command::PredictionMode(PredictionModeContextMap::<brotli::InputReference>{
-            literal_context_map:brotli::InputReference::from(&pm.literal_context_map),
-            predmode_speed_and_distance_context_map:brotli::InputReference::from(&pm.predmode_speed_and_distance_context_map),
-        });
-        let mut cmd_offset = 0usize;
-        match actuary.encode_or_decode(&[], &mut unused, &mut[], &mut unused2,
-                                            &codec::CommandSliceArray(&[immutable_pm]),&mut cmd_offset) {
-            DivansResult::NeedsMoreOutput => {
-                return Err(ErrMsg::DrainOrFillNeedsInput(2));
-            },
-            DivansResult::Failure(e) => {
-                return Err(e);
-            }
-            DivansResult::NeedsMoreInput | DivansResult::Success => {
-                if cmd_offset != 1 {
-                    return Err(ErrMsg::DrainOrFillNeedsInput(3));                    
-                }
-            }
-        }
-    }
+    let mut m8 = m8;
+    let mut distance_cache = cache::Cache::<AllocU8>::new(distance_lru, orig_buf.len(), &mut m8);
+    let mut actuary = try!(new_actuary::<SelectedCDF, AllocU8, AllocCDF16>(m8, mcdf16, pm, window_size, opt));
     for (index, cmd) in orig_buf.iter().enumerate() {
         if let Command::Copy(ref copy) = *cmd {
             distance_cache.populate(copy.distance, copy.num_bytes, index);
@@ -272,15 +273,76 @@ pub fn ir_optimize<'a, SelectedCDF:CDF16,
         orig_buf[eligible_index] = cmd;
         eligible_index += 1;
     }
-    //eprintln!("Actuary estimate: total cost {} bits; {} bytes\n", total_billing_cost(&actuary), total_billing_cost(&actuary)/ 8.0);
+    let cost = total_billing_cost(&actuary);
     let (mut retrieved_m8, retrieved_mcdf16) = actuary.free();
     distance_cache.free(&mut retrieved_m8);
+    Ok((eligible_index, cost, retrieved_m8, retrieved_mcdf16))
+}
+pub fn ir_optimize<'a, SelectedCDF:CDF16,
+                   ChosenEncoder: ArithmeticEncoderOrDecoder + NewWithAllocator<AllocU8>,
+                   AllocU8:Allocator<u8>,
+                   AllocCDF16:Allocator<SelectedCDF>,
+                   AllocCommand: Allocator<StaticCommand>
+                   >(pm:&mut brotli::interface::PredictionModeContextMap<brotli::InputReferenceMut>,
+                     orig_buf:&'a mut [brotli::interface::Command<brotli::SliceOffset>],
+                     mb:brotli::InputPair,
+                     codec:&mut codec::DivansCodec<ChosenEncoder,
+                                                   EncoderSpecialization,
+                                                   DemuxerAndRingBuffer<AllocU8,
+                                                                        DevNull<AllocU8>>,
+                                                   Mux<AllocU8>,
+                                                   SelectedCDF,
+                                                   AllocU8,
+                                                   AllocCDF16>,
+                     window_size: u8,
+                     opt: super::interface::DivansCompressorOptions,
+                     mc: &'a mut AllocCommand,
+                     buf: &'a mut AllocCommand::AllocatedMemory,
+) -> Result<&'a [brotli::interface::Command<brotli::SliceOffset>], ErrMsg> {
+    if orig_buf.len() == 0 {
+        return Ok(orig_buf);
+    }
+    let (re_m8, mut mcdf16, remainder) = match core::mem::replace(&mut codec.cross_command_state.thread_ctx, codec::ThreadContext::Worker) {
+        codec::ThreadContext::MainThread(main) => main.dismantle(),
+        codec::ThreadContext::Worker => panic!("Main Thread was none during encode"),
+    };
+    let (mut m8, reallocation_item) = re_m8.disassemble();
+    let mut orig_buf = orig_buf;
+    let mut num_commands = 0usize;
+    if opt.divans_ir_optimizer > 1 {
+        let mut planner = try!(new_actuary::<SelectedCDF, AllocU8, AllocCDF16>(m8, mcdf16, pm, window_size, &opt));
+        num_commands = try!(optimal_parse::optimal_parse(&mut planner,
+                                                              orig_buf,
+                                                              mb,
+                                                              codec.cross_command_state.bk.distance_lru,
+                                                              window_size,
+                                                              mc,
+                                                              buf));
+        let (retrieved_m8, retrieved_mcdf16) = planner.free();
+        m8 = retrieved_m8;
+        mcdf16 = retrieved_mcdf16;
+    }
+    let distance_lru = codec.cross_command_state.bk.distance_lru;
+    let (mut eligible_index, cost, retrieved_m8, retrieved_mcdf16) = try!(merge_pass(m8, mcdf16, pm, orig_buf, mb, &distance_lru, window_size, &opt));
+    m8 = retrieved_m8;
+    mcdf16 = retrieved_mcdf16;
+    if num_commands != 0 {
+        // the reparse only wins if it is still cheaper once both sides are merged under adapting priors
+        let reparsed = &mut buf.slice_mut()[..num_commands];
+        let (reparsed_len, reparsed_cost, retrieved_m8, retrieved_mcdf16) = try!(merge_pass(m8, mcdf16, pm, reparsed, mb, &distance_lru, window_size, &opt));
+        m8 = retrieved_m8;
+        mcdf16 = retrieved_mcdf16;
+        if reparsed_cost < cost {
+            orig_buf = reparsed;
+            eligible_index = reparsed_len;
+        }
+    }
     codec.cross_command_state.thread_ctx = codec::ThreadContext::MainThread(
         codec::MainThreadContext::<SelectedCDF,
                                    AllocU8,
                                    AllocCDF16,
-                                   ChosenEncoder>::reassemble((alloc_util::RepurposingAlloc::reassemble((retrieved_m8, reallocation_item)),
-                                                               retrieved_mcdf16,
+                                   ChosenEncoder>::reassemble((alloc_util::RepurposingAlloc::reassemble((m8, reallocation_item)),
+                                                               mcdf16,
                                                                remainder)));
     Ok(&orig_buf[..eligible_index])
 }
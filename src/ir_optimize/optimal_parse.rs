@@ -0,0 +1,878 @@
+use core;
+use brotli;
+use codec;
+use codec::get_distance_from_mnemonic_code;
+use alloc::{SliceWrapper, SliceWrapperMut, Allocator};
+use brotli::interface::{Command, CopyCommand, LiteralCommand, FeatureFlagSliceType, StaticCommand};
+use probability::CDF16;
+use super::{Actuary, DivansResult, ErrMsg};
+use raw_to_cmd::hash_match::{HashMatch, Match, PositionTable, MIN_MATCH_LENGTH, match_length};
+use super::statistics_tracking_codec::{OneCommandThawingArray, take_billing_snapshot, billing_snapshot_delta,
+                                       literal_billing_snapshot_delta, reset_billing_snapshot};
+
+// The optimal parser trains a scratch actuary on the brotli parse of the metablock, then prices
+// every literal byte and a table of copy shapes under those priors (blending disabled) and finds
+// the cheapest path through the metablock with a forward shortest-path pass. Candidate copies come
+// from the brotli commands, the distance LRU (including the +/- mnemonics) and the raw compressor's
+// hash chain over the metablock. Each node keeps the distance LRU of its best path so cache hits
+// are priced exactly.
+
+const HASH_CHAIN_DEPTH: u32 = 32;
+const MIN_CACHE_MATCH: usize = 2;
+// copies longer than this are only considered at full length and suspend the match search
+const LONG_COPY_LENGTH: usize = 325;
+const MAX_CANDIDATES: usize = 32;
+const PARSE_ITERATIONS: usize = 2;
+// metablocks where brotli copies less than 1/64 of the bytes (incompressible data) are not reparsed
+const MIN_COPIED_FRACTION: usize = 64;
+
+// lengths 1..16 are priced exactly, longer ones by power of two
+const LEN_BUCKETS: usize = 37;
+const DIST_BUCKETS: usize = 25;
+const MAX_PRICED_LENGTH_BUCKET: usize = 24;
+const EXTRAPOLATED_BITS_PER_DOUBLING: f32 = 2.0;
+const HIT_PRICING_DISTANCE: u32 = 64;
+const MISS_PRICING_LRU: [u32; 4] = [0x4000_0001, 0x5000_0001, 0x6000_0001, 0x7000_0001];
+const MISS_CODE: u8 = 15;
+
+const NODE_FIELDS: usize = 7;
+const NODE_COST: usize = 0;
+const NODE_LEN: usize = 1;
+const NODE_DIST: usize = 2; // 0 for a literal run of NODE_LEN bytes
+const NODE_LRU: usize = 3;
+const FIXED_ARRIVAL: u32 = 0xffff_ffff;
+
+struct PackedU32<AllocU8:Allocator<u8>>(AllocU8::AllocatedMemory);
+
+impl<AllocU8:Allocator<u8>> PackedU32<AllocU8> {
+    fn new(m8: &mut AllocU8, len: usize) -> Self {
+        PackedU32::<AllocU8>(m8.alloc_cell(len << 2))
+    }
+    #[inline(always)]
+    fn get(&self, index: usize) -> u32 {
+        let base = index << 2;
+        let s = self.0.slice();
+        u32::from(s[base]) | (u32::from(s[base + 1]) << 8) | (u32::from(s[base + 2]) << 16) | (u32::from(s[base + 3]) << 24)
+    }
+    #[inline(always)]
+    fn set(&mut self, index: usize, val: u32) {
+        let base = index << 2;
+        let s = self.0.slice_mut();
+        s[base] = val as u8;
+        s[base + 1] = (val >> 8) as u8;
+        s[base + 2] = (val >> 16) as u8;
+        s[base + 3] = (val >> 24) as u8;
+    }
+    fn free(&mut self, m8: &mut AllocU8) {
+        m8.free_cell(core::mem::replace(&mut self.0, AllocU8::AllocatedMemory::default()));
+    }
+}
+
+impl<AllocU8:Allocator<u8>> Default for PackedU32<AllocU8> {
+    fn default() -> Self {
+        PackedU32::<AllocU8>(AllocU8::AllocatedMemory::default())
+    }
+}
+
+impl<AllocU8:Allocator<u8>> PositionTable for PackedU32<AllocU8> {
+    fn len(&self) -> usize {
+        self.0.slice().len() >> 2
+    }
+    #[inline(always)]
+    fn get(&self, index: usize) -> u32 {
+        PackedU32::get(self, index)
+    }
+    #[inline(always)]
+    fn set(&mut self, index: usize, val: u32) {
+        PackedU32::set(self, index, val)
+    }
+}
+
+struct Nodes<AllocU8:Allocator<u8>>(PackedU32<AllocU8>);
+
+impl<AllocU8:Allocator<u8>> Nodes<AllocU8> {
+    fn new(m8: &mut AllocU8, len: usize) -> Self {
+        let mut ret = Nodes::<AllocU8>(PackedU32::<AllocU8>::new(m8, len * NODE_FIELDS));
+        for i in 0..len {
+            ret.0.set(i * NODE_FIELDS + NODE_COST, f32::INFINITY.to_bits());
+        }
+        ret
+    }
+    #[inline(always)]
+    fn cost(&self, i: usize) -> f32 {
+        f32::from_bits(self.0.get(i * NODE_FIELDS + NODE_COST))
+    }
+    #[inline(always)]
+    fn len(&self, i: usize) -> u32 {
+        self.0.get(i * NODE_FIELDS + NODE_LEN)
+    }
+    #[inline(always)]
+    fn dist(&self, i: usize) -> u32 {
+        self.0.get(i * NODE_FIELDS + NODE_DIST)
+    }
+    fn lru(&self, i: usize) -> [u32; 4] {
+        let base = i * NODE_FIELDS + NODE_LRU;
+        [self.0.get(base), self.0.get(base + 1), self.0.get(base + 2), self.0.get(base + 3)]
+    }
+    #[inline(always)]
+    fn relax(&mut self, i: usize, cost: f32, len: u32, dist: u32, lru: &[u32; 4]) {
+        if cost < self.cost(i) {
+            let base = i * NODE_FIELDS;
+            self.0.set(base + NODE_COST, cost.to_bits());
+            self.0.set(base + NODE_LEN, len);
+            self.0.set(base + NODE_DIST, dist);
+            for (index, item) in lru.iter().enumerate() {
+                self.0.set(base + NODE_LRU + index, *item);
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+struct CostModel {
+    literal_overhead: [f32; LEN_BUCKETS],
+    miss: [[f32; DIST_BUCKETS]; LEN_BUCKETS],
+    hit: [[f32; LEN_BUCKETS]; MISS_CODE as usize],
+}
+
+impl CostModel {
+    fn literal_run(&self, run: usize) -> f32 {
+        if run == 0 {
+            return 0.0;
+        }
+        self.literal_overhead[length_bucket(run)]
+    }
+    #[inline(always)]
+    fn copy(&self, code: u8, distance: u32, len: usize) -> f32 {
+        if code == MISS_CODE {
+            self.miss[length_bucket(len)][distance_bucket(distance)]
+        } else {
+            self.hit[usize::from(code)][length_bucket(len)]
+        }
+    }
+}
+
+struct Checkpoint {
+    state: codec::CodecSnapshot,
+    btype_lru: [[u8; 2]; 3],
+    btype_max_seen: [u8; 3],
+}
+
+fn checkpoint<SelectedCDF:CDF16,
+              AllocU8:Allocator<u8>,
+              AllocCDF16:Allocator<SelectedCDF>>(actuary: &Actuary<SelectedCDF, AllocU8, AllocCDF16>) -> Checkpoint {
+    Checkpoint {
+        state: actuary.cross_command_state.snapshot_literal_or_copy_state(),
+        btype_lru: actuary.cross_command_state.bk.btype_lru,
+        btype_max_seen: actuary.cross_command_state.bk.btype_max_seen,
+    }
+}
+
+fn rewind<SelectedCDF:CDF16,
+          AllocU8:Allocator<u8>,
+          AllocCDF16:Allocator<SelectedCDF>>(actuary: &mut Actuary<SelectedCDF, AllocU8, AllocCDF16>, cp: &Checkpoint) {
+    actuary.cross_command_state.restore_literal_or_copy_snapshot(cp.state.clone());
+    actuary.cross_command_state.bk.btype_lru = cp.btype_lru;
+    actuary.cross_command_state.bk.btype_max_seen = cp.btype_max_seen;
+}
+
+// scratch space for the parser comes from the allocator underneath the actuary
+fn base_alloc<SelectedCDF:CDF16,
+              AllocU8:Allocator<u8>,
+              AllocCDF16:Allocator<SelectedCDF>>(actuary: &mut Actuary<SelectedCDF, AllocU8, AllocCDF16>) -> &mut AllocU8 {
+    actuary.cross_command_state.thread_ctx.m8().unwrap().get_base_alloc()
+}
+
+fn encode_command<SelectedCDF:CDF16,
+                  AllocU8:Allocator<u8>,
+                  AllocCDF16:Allocator<SelectedCDF>>(actuary: &mut Actuary<SelectedCDF, AllocU8, AllocCDF16>,
+                                                     cmd: &StaticCommand,
+                                                     mb: &brotli::InputPair) -> Result<(), ErrMsg> {
+    let mut unused = 0usize;
+    let mut unused2 = 0usize;
+    let mut cmd_offset = 0usize;
+    match actuary.encode_or_decode(&[], &mut unused, &mut[], &mut unused2,
+                                   &OneCommandThawingArray(cmd, mb), &mut cmd_offset) {
+        DivansResult::NeedsMoreOutput => Err(ErrMsg::DrainOrFillNeedsInput(8)),
+        DivansResult::Failure(e) => Err(e),
+        DivansResult::NeedsMoreInput | DivansResult::Success => {
+            if cmd_offset != 1 {
+                return Err(ErrMsg::DrainOrFillNeedsInput(9));
+            }
+            Ok(())
+        }
+    }
+}
+
+// returns the (total, literal) cost of cmd and leaves the actuary as it found it
+fn price_command<SelectedCDF:CDF16,
+                 AllocU8:Allocator<u8>,
+                 AllocCDF16:Allocator<SelectedCDF>>(actuary: &mut Actuary<SelectedCDF, AllocU8, AllocCDF16>,
+                                                    cmd: &StaticCommand,
+                                                    mb: &brotli::InputPair) -> Result<(f32, f32), ErrMsg> {
+    let cp = checkpoint(actuary);
+    take_billing_snapshot(actuary);
+    try!(encode_command(actuary, cmd, mb));
+    let ret = (billing_snapshot_delta(actuary), literal_billing_snapshot_delta(actuary));
+    reset_billing_snapshot(actuary);
+    rewind(actuary, &cp);
+    Ok(ret)
+}
+
+
+fn command_len(cmd: &StaticCommand) -> usize {
+    match *cmd {
+        Command::Literal(ref lit) => lit.data.len(),
+        Command::Copy(ref copy) => copy.num_bytes as usize,
+        Command::Dict(ref dict) => usize::from(dict.final_size),
+        _ => 0,
+    }
+}
+
+// commands the parser passes through untouched: dictionary words, incompressible literals and block switches
+fn is_fixed(cmd: &StaticCommand) -> bool {
+    match *cmd {
+        Command::Literal(ref lit) => lit.high_entropy,
+        Command::Copy(_) => false,
+        _ => true,
+    }
+}
+
+#[inline(always)]
+fn floor_log2(val: usize) -> usize {
+    63 - (val as u64).leading_zeros() as usize
+}
+
+#[inline(always)]
+fn length_bucket(len: usize) -> usize {
+    if len <= 16 {
+        return len - 1;
+    }
+    core::cmp::min(floor_log2(len) + 12, LEN_BUCKETS - 1)
+}
+
+#[inline(always)]
+fn distance_bucket(distance: u32) -> usize {
+    core::cmp::min(floor_log2(distance as usize), DIST_BUCKETS - 1)
+}
+
+fn bucket_length(bucket: usize) -> usize {
+    if bucket < 16 {
+        return bucket + 1;
+    }
+    let log = bucket - 12;
+    (1 << log) + (1 << (log - 1))
+}
+
+fn mnemonic_code(lru: &[u32; 4], distance: u32) -> u8 {
+    for code in 0..MISS_CODE {
+        let (item, ok, _cache_index) = get_distance_from_mnemonic_code(lru, code, 0);
+        if item == distance && ok {
+            return code;
+        }
+    }
+    MISS_CODE
+}
+
+// mirrors CrossCommandBookKeeping::obs_distance
+fn observe_distance(lru: &[u32; 4], distance: u32) -> [u32; 4] {
+    if distance == lru[1] {
+        [distance, lru[0], lru[2], lru[3]]
+    } else if distance == lru[2] {
+        [distance, lru[0], lru[1], lru[3]]
+    } else if distance != lru[0] {
+        [distance, lru[0], lru[1], lru[2]]
+    } else {
+        *lru
+    }
+}
+
+// an LRU for which mnemonic code resolves to target and no earlier code does
+fn lru_for_code(code: u8, target: u32) -> [u32; 4] {
+    let mut lru = MISS_PRICING_LRU;
+    if code < 4 {
+        lru[usize::from(code)] = target;
+    } else {
+        let unsigned_summand = i32::from(code >> 2);
+        let signed_summand = if code & 1 != 0 { -unsigned_summand } else { unsigned_summand };
+        lru[usize::from((code & 2) >> 1)] = (target as i32 - signed_summand) as u32;
+    }
+    lru
+}
+
+fn price_cost_model<SelectedCDF:CDF16,
+                    AllocU8:Allocator<u8>,
+                    AllocCDF16:Allocator<SelectedCDF>>(actuary: &mut Actuary<SelectedCDF, AllocU8, AllocCDF16>,
+                                                       mb: &brotli::InputPair,
+                                                       max_distance: usize) -> Result<CostModel, ErrMsg> {
+    let mut model = CostModel {
+        literal_overhead: [0.0; LEN_BUCKETS],
+        miss: [[0.0; DIST_BUCKETS]; LEN_BUCKETS],
+        hit: [[0.0; LEN_BUCKETS]; MISS_CODE as usize],
+    };
+    let n0 = mb.0.len();
+    let n1 = mb.1.len();
+    for bucket in 0..LEN_BUCKETS {
+        let len = bucket_length(bucket);
+        let sample = if bucket > MAX_PRICED_LENGTH_BUCKET {
+            None
+        } else if len <= n0 {
+            Some(brotli::SliceOffset(0, len as u32))
+        } else if len <= n1 {
+            Some(brotli::SliceOffset(n0, len as u32))
+        } else {
+            None
+        };
+        model.literal_overhead[bucket] = match sample {
+            Some(data) => {
+                let (total, literal) = try!(price_command(actuary, &Command::Literal(LiteralCommand{
+                    data: data,
+                    prob: FeatureFlagSliceType::default(),
+                    high_entropy: false,
+                }), mb));
+                total - literal
+            },
+            None if bucket == 0 => 0.0,
+            None => model.literal_overhead[bucket - 1] + if bucket > 16 { EXTRAPOLATED_BITS_PER_DOUBLING } else { 0.0 },
+        };
+    }
+    let cp = checkpoint(actuary);
+    actuary.cross_command_state.bk.distance_lru = MISS_PRICING_LRU;
+    for bucket in 0..LEN_BUCKETS {
+        let len = bucket_length(bucket) as u32;
+        for dist_bucket in 0..DIST_BUCKETS {
+            model.miss[bucket][dist_bucket] = if bucket > MAX_PRICED_LENGTH_BUCKET {
+                model.miss[bucket - 1][dist_bucket] + EXTRAPOLATED_BITS_PER_DOUBLING
+            } else if dist_bucket != 0 && (1usize << dist_bucket) > max_distance {
+                model.miss[bucket][dist_bucket - 1]
+            } else {
+                let distance = core::cmp::min((1usize << dist_bucket) + (1usize << dist_bucket >> 1), max_distance) as u32;
+                try!(price_command(actuary, &Command::Copy(CopyCommand{distance: distance, num_bytes: len}), mb)).0
+            };
+        }
+    }
+    for code in 0..MISS_CODE {
+        actuary.cross_command_state.bk.distance_lru = lru_for_code(code, HIT_PRICING_DISTANCE);
+        for bucket in 0..LEN_BUCKETS {
+            model.hit[usize::from(code)][bucket] = if bucket > MAX_PRICED_LENGTH_BUCKET {
+                model.hit[usize::from(code)][bucket - 1] + EXTRAPOLATED_BITS_PER_DOUBLING
+            } else {
+                try!(price_command(actuary, &Command::Copy(CopyCommand{distance: HIT_PRICING_DISTANCE,
+                                                                       num_bytes: bucket_length(bucket) as u32}), mb)).0
+            };
+        }
+    }
+    rewind(actuary, &cp);
+    Ok(model)
+}
+
+// The table above is priced from a single codec state; the costs the brotli parse actually pays along
+// the way (with the copy priors in their usual contexts) are folded in per cell, and cells it never
+// visits are scaled by the overall ratio between observed and table prices.
+fn calibrate_cost_model<SelectedCDF:CDF16,
+                        AllocU8:Allocator<u8>,
+                        AllocCDF16:Allocator<SelectedCDF>>(actuary: &mut Actuary<SelectedCDF, AllocU8, AllocCDF16>,
+                                                           orig: &[StaticCommand],
+                                                           mb: &brotli::InputPair,
+                                                           model: &mut CostModel) -> Result<(), ErrMsg> {
+    let mut literal_sum = [0.0f32; LEN_BUCKETS];
+    let mut literal_count = [0u32; LEN_BUCKETS];
+    let mut miss_sum = [[0.0f32; DIST_BUCKETS]; LEN_BUCKETS];
+    let mut miss_count = [[0u32; DIST_BUCKETS]; LEN_BUCKETS];
+    let mut hit_sum = [[0.0f32; LEN_BUCKETS]; MISS_CODE as usize];
+    let mut hit_count = [[0u32; LEN_BUCKETS]; MISS_CODE as usize];
+    let start = checkpoint(actuary);
+    for cmd in orig.iter() {
+        let code = match *cmd {
+            Command::Copy(ref copy) => actuary.cross_command_state.bk.distance_mnemonic_code(copy.distance, copy.num_bytes),
+            _ => MISS_CODE,
+        };
+        take_billing_snapshot(actuary);
+        try!(encode_command(actuary, cmd, mb));
+        let cost = billing_snapshot_delta(actuary);
+        match *cmd {
+            Command::Literal(ref lit) if !is_fixed(cmd) && lit.data.len() != 0 => {
+                let bucket = length_bucket(lit.data.len());
+                literal_sum[bucket] += cost - literal_billing_snapshot_delta(actuary);
+                literal_count[bucket] += 1;
+            },
+            Command::Copy(ref copy) if copy.num_bytes != 0 => {
+                let bucket = length_bucket(copy.num_bytes as usize);
+                if code == MISS_CODE {
+                    miss_sum[bucket][distance_bucket(copy.distance)] += cost;
+                    miss_count[bucket][distance_bucket(copy.distance)] += 1;
+                } else {
+                    hit_sum[usize::from(code)][bucket] += cost;
+                    hit_count[usize::from(code)][bucket] += 1;
+                }
+            },
+            _ => {},
+        }
+    }
+    rewind(actuary, &start);
+    let mut observed = 0.0f32;
+    let mut priced = 0.0f32;
+    for bucket in 0..LEN_BUCKETS {
+        if literal_count[bucket] != 0 {
+            observed += literal_sum[bucket];
+            priced += model.literal_overhead[bucket] * literal_count[bucket] as f32;
+        }
+    }
+    let ratio = if priced > 0.0 { observed / priced } else { 1.0 };
+    for bucket in 0..LEN_BUCKETS {
+        model.literal_overhead[bucket] = if literal_count[bucket] != 0 {
+            literal_sum[bucket] / literal_count[bucket] as f32
+        } else {
+            model.literal_overhead[bucket] * ratio
+        };
+    }
+    observed = 0.0;
+    priced = 0.0;
+    for bucket in 0..LEN_BUCKETS {
+        for dist_bucket in 0..DIST_BUCKETS {
+            if miss_count[bucket][dist_bucket] != 0 {
+                observed += miss_sum[bucket][dist_bucket];
+                priced += model.miss[bucket][dist_bucket] * miss_count[bucket][dist_bucket] as f32;
+            }
+        }
+        for code in 0..usize::from(MISS_CODE) {
+            if hit_count[code][bucket] != 0 {
+                observed += hit_sum[code][bucket];
+                priced += model.hit[code][bucket] * hit_count[code][bucket] as f32;
+            }
+        }
+    }
+    let ratio = if priced > 0.0 { observed / priced } else { 1.0 };
+    for bucket in 0..LEN_BUCKETS {
+        for dist_bucket in 0..DIST_BUCKETS {
+            model.miss[bucket][dist_bucket] = if miss_count[bucket][dist_bucket] != 0 {
+                miss_sum[bucket][dist_bucket] / miss_count[bucket][dist_bucket] as f32
+            } else {
+                model.miss[bucket][dist_bucket] * ratio
+            };
+        }
+        for code in 0..usize::from(MISS_CODE) {
+            model.hit[code][bucket] = if hit_count[code][bucket] != 0 {
+                hit_sum[code][bucket] / hit_count[code][bucket] as f32
+            } else {
+                model.hit[code][bucket] * ratio
+            };
+        }
+    }
+    Ok(())
+}
+
+struct Parser<AllocU8:Allocator<u8>> {
+    data: AllocU8::AllocatedMemory, // the metablock in one piece for the match finder
+    lit_cost: PackedU32<AllocU8>,
+    fixed: PackedU32<AllocU8>, // (position, command index) of each fixed command
+    copies: PackedU32<AllocU8>, // (position, command index) of each brotli copy
+    num_fixed: usize,
+    num_copies: usize,
+    n0: usize,
+    n: usize,
+    max_distance: usize,
+}
+
+impl<AllocU8:Allocator<u8>> Parser<AllocU8> {
+    // returns None if the commands do not tile the metablock
+    fn new(m8: &mut AllocU8, orig: &[StaticCommand], mb: &brotli::InputPair, max_distance: usize) -> Option<Self> {
+        let n0 = mb.0.len();
+        let n = n0 + mb.1.len();
+        let mut num_fixed = 0usize;
+        let mut num_copies = 0usize;
+        let mut pos = 0usize;
+        for cmd in orig.iter() {
+            if let Command::Literal(ref lit) = *cmd {
+                if lit.data.offset() != pos {
+                    return None;
+                }
+            }
+            if is_fixed(cmd) {
+                num_fixed += 1;
+            } else if let Command::Copy(_) = *cmd {
+                num_copies += 1;
+            }
+            pos += command_len(cmd);
+        }
+        if pos != n || n == 0 {
+            return None;
+        }
+        let mut data = m8.alloc_cell(n);
+        data.slice_mut()[..n0].clone_from_slice(mb.0.slice());
+        data.slice_mut()[n0..].clone_from_slice(mb.1.slice());
+        let mut ret = Parser::<AllocU8> {
+            data: data,
+            lit_cost: PackedU32::<AllocU8>::new(m8, n),
+            fixed: PackedU32::<AllocU8>::new(m8, 2 * num_fixed),
+            copies: PackedU32::<AllocU8>::new(m8, 2 * num_copies),
+            num_fixed: num_fixed,
+            num_copies: num_copies,
+            n0: n0,
+            n: n,
+            max_distance: max_distance,
+        };
+        num_fixed = 0;
+        num_copies = 0;
+        pos = 0;
+        for (index, cmd) in orig.iter().enumerate() {
+            if is_fixed(cmd) {
+                ret.fixed.set(2 * num_fixed, pos as u32);
+                ret.fixed.set(2 * num_fixed + 1, index as u32);
+                num_fixed += 1;
+            } else if let Command::Copy(_) = *cmd {
+                ret.copies.set(2 * num_copies, pos as u32);
+                ret.copies.set(2 * num_copies + 1, index as u32);
+                num_copies += 1;
+            }
+            pos += command_len(cmd);
+        }
+        Some(ret)
+    }
+    fn free(&mut self, m8: &mut AllocU8) {
+        m8.free_cell(core::mem::replace(&mut self.data, AllocU8::AllocatedMemory::default()));
+        self.lit_cost.free(m8);
+        self.fixed.free(m8);
+        self.copies.free(m8);
+    }
+    // prices every byte as a single literal in the context the brotli parse leaves it in
+    fn price_literals<SelectedCDF:CDF16,
+                      AllocCDF16:Allocator<SelectedCDF>>(&mut self,
+                                                         actuary: &mut Actuary<SelectedCDF, AllocU8, AllocCDF16>,
+                                                         orig: &[StaticCommand],
+                                                         mb: &brotli::InputPair) -> Result<(), ErrMsg> {
+        let start = checkpoint(actuary);
+        let mut pos = 0usize;
+        for cmd in orig.iter() {
+            let len = command_len(cmd);
+            if is_fixed(cmd) {
+                try!(encode_command(actuary, cmd, mb));
+            } else {
+                for offset in pos..pos + len {
+                    take_billing_snapshot(actuary);
+                    try!(encode_command(actuary, &Command::Literal(LiteralCommand{
+                        data: brotli::SliceOffset(offset, 1),
+                        prob: FeatureFlagSliceType::default(),
+                        high_entropy: false,
+                    }), mb));
+                    self.lit_cost.set(offset, literal_billing_snapshot_delta(actuary).to_bits());
+                }
+            }
+            pos += len;
+        }
+        rewind(actuary, &start);
+        Ok(())
+    }
+    fn shortest_path(&self,
+                     m8: &mut AllocU8,
+                     model: &CostModel,
+                     orig: &[StaticCommand],
+                     distance_lru: &[u32; 4]) -> Option<Nodes<AllocU8>> {
+        let n = self.n;
+        let data = self.data.slice();
+        // the metablock never wraps, and sizing the window to it keeps every position in the tables
+        // while leaving max_distance at least n
+        let window_bits = floor_log2(n + 16) as u8 + 1;
+        let mut matcher = HashMatch::<PackedU32<AllocU8>>::new_hash_chain(window_bits, HASH_CHAIN_DEPTH);
+        matcher.ensure_allocated(|len| {
+            let mut table = PackedU32::<AllocU8>::new(m8, len);
+            for index in 0..len {
+                table.set(index, 0);
+            }
+            table
+        });
+        let mut nodes = Nodes::<AllocU8>::new(m8, n + 1);
+        let mut matches = [Match::default(); MAX_CANDIDATES];
+        nodes.relax(0, 0.0, 0, 0, distance_lru);
+        let mut next_fixed = 0usize;
+        let mut next_copy = 0usize;
+        let mut skip_until = 0usize;
+        let mut candidates = [(0u32, 0usize, 0usize); MAX_CANDIDATES];
+        for pos in 0..n {
+            while next_fixed < self.num_fixed && (self.fixed.get(2 * next_fixed) as usize) < pos {
+                next_fixed += 1;
+            }
+            let mut barrier_here = false;
+            let mut region_len = 0usize;
+            let mut fixed_end = next_fixed;
+            while fixed_end < self.num_fixed && self.fixed.get(2 * fixed_end) as usize == pos {
+                let len = command_len(&orig[self.fixed.get(2 * fixed_end + 1) as usize]);
+                if len == 0 {
+                    barrier_here = true;
+                } else {
+                    region_len = len;
+                }
+                fixed_end += 1;
+            }
+            // nothing may straddle a fixed command
+            let limit = if fixed_end < self.num_fixed { self.fixed.get(2 * fixed_end) as usize } else { n };
+            let cost = nodes.cost(pos);
+            let mut searched = false;
+            if cost != f32::INFINITY {
+                let lru = nodes.lru(pos);
+                if region_len != 0 {
+                    nodes.relax(pos + region_len, cost, region_len as u32, FIXED_ARRIVAL, &lru);
+                } else {
+                    let run = if nodes.dist(pos) == 0 && !barrier_here && pos != self.n0 { nodes.len(pos) as usize } else { 0 };
+                    let literal = cost + f32::from_bits(self.lit_cost.get(pos)) + model.literal_run(run + 1) - model.literal_run(run);
+                    nodes.relax(pos + 1, literal, (run + 1) as u32, 0, &lru);
+
+                    let mut num_candidates = 0usize;
+                    while next_copy < self.num_copies && (self.copies.get(2 * next_copy) as usize) < pos {
+                        next_copy += 1;
+                    }
+                    if next_copy < self.num_copies && self.copies.get(2 * next_copy) as usize == pos {
+                        if let Command::Copy(ref copy) = orig[self.copies.get(2 * next_copy + 1) as usize] {
+                            let len = core::cmp::min(copy.num_bytes as usize, limit - pos);
+                            if len != 0 {
+                                candidates[num_candidates] = (copy.distance, len, core::cmp::min(MIN_CACHE_MATCH, len));
+                                num_candidates += 1;
+                            }
+                        }
+                    }
+                    if pos >= skip_until {
+                        for code in 0..MISS_CODE {
+                            let (distance, ok, _cache_index) = get_distance_from_mnemonic_code(&lru, code, 0);
+                            if !ok || distance as usize > pos || distance as usize > self.max_distance
+                                || candidates[..num_candidates].iter().any(|c| c.0 == distance) {
+                                continue;
+                            }
+                            let len = match_length(data, !0, pos - distance as usize, pos, limit - pos);
+                            if len >= MIN_CACHE_MATCH {
+                                candidates[num_candidates] = (distance, len, MIN_CACHE_MATCH);
+                                num_candidates += 1;
+                            }
+                        }
+                        if pos + MIN_MATCH_LENGTH <= limit {
+                            searched = true;
+                            let num_matches = matcher.find_matches(data, !0, pos, limit - pos,
+                                                                   &mut matches[..MAX_CANDIDATES - num_candidates]);
+                            let mut min_len = MIN_MATCH_LENGTH;
+                            for m in matches[..num_matches].iter() {
+                                if m.distance as usize <= self.max_distance {
+                                    candidates[num_candidates] = (m.distance, m.length as usize, min_len);
+                                    num_candidates += 1;
+                                }
+                                min_len = m.length as usize + 1;
+                            }
+                        }
+                    }
+                    for &(distance, len, min_len) in candidates[..num_candidates].iter() {
+                        let code = mnemonic_code(&lru, distance);
+                        let next_lru = observe_distance(&lru, distance);
+                        if len > LONG_COPY_LENGTH {
+                            nodes.relax(pos + len, cost + model.copy(code, distance, len), len as u32, distance, &next_lru);
+                            skip_until = core::cmp::max(skip_until, pos + len);
+                        } else {
+                            for sub_len in min_len..(len + 1) {
+                                nodes.relax(pos + sub_len, cost + model.copy(code, distance, sub_len), sub_len as u32, distance, &next_lru);
+                            }
+                        }
+                    }
+                }
+            }
+            if !searched {
+                matcher.insert_range(data, !0, pos, pos + 1, n);
+            }
+        }
+        matcher.free(|mut table| table.free(m8));
+        if nodes.cost(n) == f32::INFINITY {
+            nodes.0.free(m8);
+            return None;
+        }
+        Some(nodes)
+    }
+    // walks the chosen path backwards, writing commands from the end of out if it is large enough; returns the command count
+    fn trace_path(&self, nodes: &Nodes<AllocU8>, orig: &[StaticCommand], out: &mut [StaticCommand]) -> usize {
+        let mut count = 0usize;
+        let mut emit = |cmd: StaticCommand, count: &mut usize| {
+            *count += 1;
+            if out.len() >= *count {
+                let index = out.len() - *count;
+                out[index] = cmd;
+            }
+        };
+        let mut pos = self.n;
+        let mut fixed_index = self.num_fixed;
+        loop {
+            while fixed_index != 0 && self.fixed.get(2 * (fixed_index - 1)) as usize == pos
+                && command_len(&orig[self.fixed.get(2 * fixed_index - 1) as usize]) == 0 {
+                fixed_index -= 1;
+                emit(orig[self.fixed.get(2 * fixed_index + 1) as usize], &mut count);
+            }
+            if pos == 0 {
+                break;
+            }
+            let len = nodes.len(pos) as usize;
+            let dist = nodes.dist(pos);
+            if dist == 0 {
+                emit(Command::Literal(LiteralCommand{
+                    data: brotli::SliceOffset(pos - len, len as u32),
+                    prob: FeatureFlagSliceType::default(),
+                    high_entropy: false,
+                }), &mut count);
+            } else if dist == FIXED_ARRIVAL {
+                fixed_index -= 1;
+                emit(orig[self.fixed.get(2 * fixed_index + 1) as usize], &mut count);
+            } else {
+                emit(Command::Copy(CopyCommand{distance: dist, num_bytes: len as u32}), &mut count);
+            }
+            pos -= len;
+        }
+        count
+    }
+}
+
+fn reparse<SelectedCDF:CDF16,
+           AllocU8:Allocator<u8>,
+           AllocCDF16:Allocator<SelectedCDF>,
+           AllocCommand:Allocator<StaticCommand>>(actuary: &mut Actuary<SelectedCDF, AllocU8, AllocCDF16>,
+                                                  parser: &mut Parser<AllocU8>,
+                                                  orig: &[StaticCommand],
+                                                  mb: &brotli::InputPair,
+                                                  distance_lru: &[u32; 4],
+                                                  mc: &mut AllocCommand,
+                                                  buf: &mut AllocCommand::AllocatedMemory) -> Result<(), ErrMsg> {
+    try!(parser.price_literals(actuary, orig, mb));
+    let table = try!(price_cost_model(actuary, mb, parser.max_distance));
+    for iteration in 0..PARSE_ITERATIONS {
+        // later iterations calibrate against the previous path, which is where the parse is headed
+        let mut model = table;
+        if iteration == 0 {
+            try!(calibrate_cost_model(actuary, orig, mb, &mut model));
+        } else {
+            try!(calibrate_cost_model(actuary, buf.slice(), mb, &mut model));
+        }
+        let mut nodes = match parser.shortest_path(base_alloc(actuary), &model, orig, distance_lru) {
+            Some(nodes) => nodes,
+            None => break,
+        };
+        let count = parser.trace_path(&nodes, orig, &mut []);
+        let mut path = mc.alloc_cell(count);
+        parser.trace_path(&nodes, orig, path.slice_mut());
+        nodes.0.free(base_alloc(actuary));
+        let prev = core::mem::replace(buf, path);
+        if !prev.slice().is_empty() {
+            mc.free_cell(prev);
+        }
+    }
+    Ok(())
+}
+
+// Reparses the metablock; returns the number of commands placed in buf, or 0 if the brotli parse should be kept.
+// The result is cheapest under priors frozen after seeing the brotli parse, so the caller still has to compare it against the original.
+pub fn optimal_parse<SelectedCDF:CDF16,
+                     AllocU8:Allocator<u8>,
+                     AllocCDF16:Allocator<SelectedCDF>,
+                     AllocCommand:Allocator<StaticCommand>>(actuary: &mut Actuary<SelectedCDF, AllocU8, AllocCDF16>,
+                                                            orig: &[StaticCommand],
+                                                            mb: brotli::InputPair,
+                                                            distance_lru: [u32; 4],
+                                                            window_size: u8,
+                                                            mc: &mut AllocCommand,
+                                                            buf: &mut AllocCommand::AllocatedMemory) -> Result<usize, ErrMsg> {
+    let max_distance = (1usize << window_size) - 16;
+    let copied = orig.iter().fold(0usize, |sum, cmd| match *cmd {
+        Command::Copy(ref copy) => sum + copy.num_bytes as usize,
+        _ => sum,
+    });
+    if copied * MIN_COPIED_FRACTION < mb.0.len() + mb.1.len() {
+        return Ok(0);
+    }
+    let mut parser = match Parser::<AllocU8>::new(base_alloc(actuary), orig, &mb, max_distance) {
+        Some(parser) => parser,
+        None => return Ok(0),
+    };
+    actuary.cross_command_state.bk.distance_lru = distance_lru;
+    let start = checkpoint(actuary);
+    // learn the priors of this metablock from the brotli parse, then freeze them for pricing
+    let mut ret = Ok(());
+    for cmd in orig.iter() {
+        ret = encode_command(actuary, cmd, &mb);
+        if ret.is_err() {
+            break;
+        }
+    }
+    rewind(actuary, &start);
+    actuary.cross_command_state.specialization.will_it_blend = false;
+    if ret.is_ok() {
+        ret = reparse(actuary, &mut parser, orig, &mb, &distance_lru, mc, buf);
+    }
+    parser.free(base_alloc(actuary));
+    try!(ret);
+    Ok(buf.slice().len())
+}
+
+#[cfg(test)]
+mod test {
+    use std::vec::Vec;
+    use alloc::HeapAlloc;
+    use brotli;
+    use brotli::interface::{Command, CopyCommand, LiteralCommand, FeatureFlagSliceType, StaticCommand};
+    use super::{length_bucket, bucket_length, lru_for_code, mnemonic_code, observe_distance, CostModel, Parser,
+                LEN_BUCKETS, DIST_BUCKETS, MISS_CODE};
+    #[test]
+    fn test_length_buckets() {
+        for len in 1..100000usize {
+            let bucket = length_bucket(len);
+            assert!(bucket < LEN_BUCKETS);
+            if len <= 16 {
+                assert_eq!(bucket_length(bucket), len);
+            } else {
+                assert_eq!(length_bucket(bucket_length(bucket)), bucket);
+            }
+        }
+    }
+    #[test]
+    fn test_lru_for_code() {
+        for code in 0..MISS_CODE {
+            assert_eq!(mnemonic_code(&lru_for_code(code, 64), 64), code);
+        }
+        assert_eq!(mnemonic_code(&lru_for_code(0, 64), 1000), MISS_CODE);
+        assert_eq!(observe_distance(&[1, 2, 3, 4], 3), [3, 1, 2, 4]);
+        assert_eq!(observe_distance(&[1, 2, 3, 4], 4), [4, 1, 2, 3]);
+        assert_eq!(observe_distance(&[1, 2, 3, 4], 1), [1, 2, 3, 4]);
+    }
+    fn summarize(cmds: &[StaticCommand]) -> Vec<(char, usize, u32)> {
+        cmds.iter().map(|cmd| match *cmd {
+            Command::Literal(ref lit) => ('l', lit.data.0, lit.data.1),
+            Command::Copy(ref copy) => ('c', copy.distance as usize, copy.num_bytes),
+            _ => ('?', 0, 0),
+        }).collect()
+    }
+    #[test]
+    fn test_shortest_path_on_crafted_stream() {
+        // four repeats of an 8 byte phrase split across the two halves of the metablock; brotli copied
+        // it in two pieces but one literal run followed by a single copy of the remaining 24 bytes is cheapest
+        let data = b"divans!?divans!?divans!?divans!?";
+        let mb = brotli::InputPair(brotli::InputReference{data: &data[..20], orig_offset: 0},
+                                   brotli::InputReference{data: &data[20..], orig_offset: 20});
+        let literal = |offset: usize, len: u32| Command::Literal(LiteralCommand{
+            data: brotli::SliceOffset(offset, len),
+            prob: FeatureFlagSliceType::default(),
+            high_entropy: false,
+        });
+        let orig = [literal(0, 8),
+                    Command::Copy(CopyCommand{distance: 8, num_bytes: 4}),
+                    literal(12, 4),
+                    Command::Copy(CopyCommand{distance: 8, num_bytes: 16})];
+        let model = CostModel {
+            literal_overhead: [4.0; LEN_BUCKETS],
+            miss: [[12.0; DIST_BUCKETS]; LEN_BUCKETS],
+            hit: [[6.0; LEN_BUCKETS]; MISS_CODE as usize],
+        };
+        let mut m8 = HeapAlloc::<u8>::new(0);
+        let mut parser = Parser::<HeapAlloc<u8>>::new(&mut m8, &orig, &mb, 1 << 20).unwrap();
+        for pos in 0..data.len() {
+            parser.lit_cost.set(pos, 8.0f32.to_bits());
+        }
+        let mut nodes = parser.shortest_path(&mut m8, &model, &orig, &[100, 200, 300, 400]).unwrap();
+        assert_eq!(nodes.cost(data.len()), 4.0 + 8.0 * 8.0 + 12.0);
+        let count = parser.trace_path(&nodes, &orig, &mut []);
+        let mut path = vec![Command::default(); count];
+        assert_eq!(parser.trace_path(&nodes, &orig, &mut path), count);
+        assert_eq!(summarize(&path), [('l', 0, 8), ('c', 8, 24)]);
+        nodes.0.free(&mut m8);
+        parser.free(&mut m8);
+    }
+}
@@ -63,6 +63,9 @@ impl ArithmeticEncoderOrDecoder for TallyingArithmeticEncoder {
     fn close(&mut self) -> DivansResult {
         DivansResult::Success
     }
+    fn sync(&mut self) -> DivansResult {
+        DivansResult::Success
+    }
         
     fn get_or_put_bit_without_billing(&mut self,
                                       bit: &mut bool,
@@ -148,7 +151,23 @@ pub fn billing_snapshot_delta<SelectedCDF:CDF16,
     }
 }
 
-#[allow(dead_code)]
+pub fn literal_billing_snapshot_delta<SelectedCDF:CDF16,
+                                      AllocU8:Allocator<u8>,
+                                      AllocCDF16:Allocator<SelectedCDF>,
+                                      Spc: EncoderOrDecoderSpecialization
+                                      >(codec:&codec::DivansCodec<TallyingArithmeticEncoder,
+                                                                  Spc,
+                                                                  DemuxerAndRingBuffer<AllocU8, DevNull<AllocU8>>,
+                                                                  DevNull<AllocU8>,
+                                                                  SelectedCDF,
+                                                                  AllocU8,
+                                                                  AllocCDF16>) -> floatY {
+    match codec.cross_command_state.thread_ctx {
+        codec::ThreadContext::Worker => 0.0,
+        codec::ThreadContext::MainThread(ref ctx) => ctx.lit_coder.snapshot_delta(),
+    }
+}
+
 pub fn total_billing_cost<SelectedCDF:CDF16,
                           AllocU8:Allocator<u8>,
                           AllocCDF16:Allocator<SelectedCDF>,
@@ -47,6 +47,8 @@ pub mod resizable_buffer;
 pub mod interface;
 pub mod slice_util;
 pub mod alloc_util;
+mod byte_util;
+mod xxhash;
 mod probability;
 #[macro_use]
 mod priors;
@@ -54,6 +56,7 @@ mod priors;
 mod arithmetic_coder;
 mod debug_encoder;
 mod cmd_to_raw;
+mod command_log;
 mod raw_to_cmd;
 mod codec;
 mod cmd_to_divans;
@@ -64,28 +67,32 @@ pub mod test_helper;
 mod test_ans;
 mod test_mux;
 mod ans;
+mod range_coder;
+mod runtime_coder;
 mod brotli_ir_gen;
 mod divans_compressor;
 mod divans_decompressor;
 mod parallel_decompressor;
-mod stub_parallel_decompressor;
 pub mod ir_optimize;
 pub mod mux;
 pub mod constants;
 pub mod threading;
 pub mod multithreading;
-pub use self::interface::{DivansInputResult,DivansOpResult,DivansOutputResult, DivansResult, ErrMsg, MAGIC_NUMBER};
+pub use self::interface::{DivansInputResult,DivansOpResult,DivansOutputResult, DivansResult, ErrMsg, MAGIC_NUMBER, StreamFeatures, CDFFlavor, EntropyCoder};
 pub use alloc::{AllocatedStackMemory, Allocator, SliceWrapper, SliceWrapperMut, StackAllocator};
 pub use interface::{DivansCompressorFactory, BlockSwitch, LiteralBlockSwitch, Command, Compressor, CopyCommand, Decompressor, DictCommand, LiteralCommand, Nop, NewWithAllocator, ArithmeticEncoderOrDecoder, LiteralPredictionModeNibble, PredictionModeContextMap, free_cmd, FeatureFlagSliceType,
                     DefaultCDF16};
 
 pub use brotli_ir_gen::{BrotliDivansHybridCompressor,BrotliDivansHybridCompressorFactory};
 pub use cmd_to_raw::DivansRecodeState;
+pub use command_log::{LoggedCommand, parse_logged_command};
 pub use codec::CMD_BUFFER_SIZE;
 pub use divans_to_raw::DecoderSpecialization;
 pub use cmd_to_divans::EncoderSpecialization;
-pub use codec::{EncoderOrDecoderSpecialization, DivansCodec, StrideSelection};
+pub use codec::{EncoderOrDecoderSpecialization, DivansCodec, StrideSelection, EncodeOrDecodeState, PriorSnapshotInfo};
 pub use divans_compressor::{DivansCompressor, DivansCompressorFactoryStruct};
+pub use xxhash::XxHash64;
+pub use interface::{ContentSummary, DivansError, TRAILER_LENGTH};
 
 #[cfg(not(feature="safe"))]
 mod ffi;
@@ -93,12 +100,33 @@ mod ffi;
 pub use ffi::*;
 mod reader;
 mod writer;
+mod seekable;
+mod parallel_compressor;
+mod literal_thread_compressor;
+mod brotli_recompress;
+mod deflate_recompress;
 #[cfg(not(feature="no-stdlib"))]
 pub use reader::DivansBrotliHybridCompressorReader;
 #[cfg(not(feature="no-stdlib"))]
 pub use reader::DivansExperimentalCompressorReader;
 #[cfg(not(feature="no-stdlib"))]
 pub use reader::DivansDecompressorReader;
+#[cfg(not(feature="no-stdlib"))]
+pub use reader::{verify_stream, salvage_stream, train_prior_snapshot, SalvageReport, DamagedRange};
+#[cfg(not(feature="no-stdlib"))]
+pub use reader::DivansFramedCompressorReader;
+#[cfg(not(feature="no-stdlib"))]
+pub use reader::DivansParallelCompressorReader;
+#[cfg(not(feature="no-stdlib"))]
+pub use seekable::{DivansFramedCompressor, DivansSeekableDecompressor, SeekTable, FrameLocation, DEFAULT_FRAME_SIZE};
+#[cfg(not(feature="no-stdlib"))]
+pub use parallel_compressor::DivansParallelCompressor;
+#[cfg(not(feature="no-stdlib"))]
+pub use literal_thread_compressor::DivansLiteralThreadCompressor;
+#[cfg(not(feature="no-stdlib"))]
+pub use brotli_recompress::{is_recompressed_brotli, recompress_brotli, reconstruct_brotli, transcode_to_brotli};
+#[cfg(not(feature="no-stdlib"))]
+pub use deflate_recompress::{is_recompressed_deflate, recompress_deflate, reconstruct_deflate};
 
 #[cfg(not(feature="no-stdlib"))]
 pub use writer::DivansBrotliHybridCompressorWriter;
@@ -106,6 +134,10 @@ pub use writer::DivansBrotliHybridCompressorWriter;
 pub use writer::DivansExperimentalCompressorWriter;
 #[cfg(not(feature="no-stdlib"))]
 pub use writer::DivansDecompressorWriter;
+#[cfg(not(feature="no-stdlib"))]
+pub use writer::DivansFramedCompressorWriter;
+#[cfg(not(feature="no-stdlib"))]
+pub use writer::DivansParallelCompressorWriter;
 
 
 pub use probability::Speed;
@@ -117,7 +149,13 @@ pub use probability::BaseCDF;
 
 pub use interface::BrotliCompressionSetting;
 pub use interface::DivansCompressorOptions;
+pub use interface::DivansDecompressorLimits;
 pub use divans_decompressor::{DivansDecompressor,
                               DivansDecompressorFactory,
                               DivansDecompressorFactoryStruct,
                               StaticCommand};
+pub use threading::{WorkerSpawner, WorkerLock, DefaultSpawner};
+#[cfg(not(feature="no-stdlib"))]
+pub use threading::{StdThreadSpawner, StdWorkerLock};
+#[cfg(feature="no-stdlib")]
+pub use threading::{NoSpawner, NoLock};
@@ -0,0 +1,877 @@
+// Copyright 2017 Dropbox, Inc
+//
+//   Licensed under the Apache License, Version 2.0 (the "License");
+//   you may not use this file except in compliance with the License.
+//   You may obtain a copy of the License at
+//
+//       http://www.apache.org/licenses/LICENSE-2.0
+//
+//   Unless required by applicable law or agreed to in writing, software
+//   distributed under the License is distributed on an "AS IS" BASIS,
+//   WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//   See the License for the specific language governing permissions and
+//   limitations under the License.
+
+// Two-thread compression of a single stream, mirroring the threaded decoder: the command thread
+// runs the ordinary encoder but forwards every literal to a worker that owns the literal coder.
+// The worker records what each literal drain would have handed to the multiplexer and the
+// command thread replays those drains in stream order, so the output is byte for byte the
+// output of DivansCompressor.
+#![cfg(not(feature="no-stdlib"))]
+use core;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex, Condvar};
+use std::thread;
+use std::vec::Vec;
+use alloc::{Allocator, HeapAlloc, SliceWrapper};
+use alloc_util::RepurposingAlloc;
+use cmd_to_raw::DivansRecodeState;
+use codec::{MainThreadContext, LIT_CODER};
+use codec::encoder::DivansLiteralEncoder;
+use codec::mixer::MAX_MATCH_BYTES;
+use codec::io::DemuxerAndRingBuffer;
+use divans_compressor::DivansCompressor;
+use interface::{
+    ArithmeticEncoderOrDecoder,
+    BillingDesignation,
+    Command,
+    Compressor,
+    DivansCompressorOptions,
+    DivansOutputResult,
+    DivansResult,
+    EncoderOrDecoderRecoderSpecialization,
+    ErrMsg,
+    LiteralBlockSwitch,
+    NewWithAllocator,
+    PredictionModeContextMap,
+    ReadableBytes,
+    StreamDemuxer,
+    StreamID,
+    StreamMuxer,
+    WritableBytes,
+    NUM_STREAMS,
+};
+use mux::{DevNull, Mux};
+use probability::{CDF16, ProbRange};
+use slice_util::{AllocatedMemoryPrefix, AllocatedMemoryRange, SliceReference};
+use threading::{LiteralCoderOp, ThreadData, ThreadToMain};
+
+type AllocU8 = HeapAlloc<u8>;
+type LiteralCoder = DefaultEncoderType!();
+
+// literal bytes the command thread may run ahead of the worker
+const MAX_QUEUED_LITERAL_BYTES: usize = 1 << 20;
+
+enum LiteralJob {
+    Literal(Vec<u8>, u64, Vec<u8>),
+    BlockSwitch(LiteralBlockSwitch),
+    PredictionMode(Vec<u8>, Vec<u8>),
+    CoderOp(LiteralCoderOp),
+}
+
+impl LiteralJob {
+    fn size(&self) -> usize {
+        match *self {
+            LiteralJob::Literal(ref data, _, _) => data.len(),
+            _ => 0,
+        }
+    }
+}
+
+// the bytes one literal drain moved from the coder into the multiplexer
+struct ReplayItem {
+    bytes: Vec<u8>,
+    // the serial encoder stops this drain when the output fills and leaves the rest in the coder
+    abandonable: bool,
+}
+
+#[derive(Default)]
+struct LiteralQueue {
+    jobs: VecDeque<LiteralJob>,
+    queued_bytes: usize,
+    busy: bool,
+    results: VecDeque<ReplayItem>,
+    err: Option<ErrMsg>,
+    exited: bool,
+    shutdown: bool,
+}
+
+type LiteralChannel = Arc<(Mutex<LiteralQueue>, Condvar)>;
+
+fn submit_job(channel: &LiteralChannel, job: LiteralJob) -> DivansOutputResult {
+    let (ref lock, ref cvar) = **channel;
+    let mut queue = lock.lock().unwrap();
+    while queue.queued_bytes > MAX_QUEUED_LITERAL_BYTES && queue.err.is_none() && !queue.exited {
+        queue = cvar.wait(queue).unwrap();
+    }
+    if let Some(m) = queue.err {
+        return DivansOutputResult::Failure(m);
+    }
+    if queue.exited {
+        return DivansOutputResult::Failure(ErrMsg::LiteralWorkerExited);
+    }
+    queue.queued_bytes += job.size();
+    queue.jobs.push_back(job);
+    cvar.notify_all();
+    DivansOutputResult::Success
+}
+
+// takes what the worker has recorded; with wait set, first lets it finish every job submitted so far
+fn collect_results(channel: &LiteralChannel, items: &mut VecDeque<ReplayItem>, wait: bool) -> Result<(), ErrMsg> {
+    let (ref lock, ref cvar) = **channel;
+    let mut queue = lock.lock().unwrap();
+    while wait && (queue.busy || !queue.jobs.is_empty()) && queue.err.is_none() && !queue.exited {
+        queue = cvar.wait(queue).unwrap();
+    }
+    if let Some(m) = queue.err {
+        return Err(m);
+    }
+    if wait && (queue.busy || !queue.jobs.is_empty()) {
+        return Err(ErrMsg::LiteralWorkerExited);
+    }
+    items.extend(queue.results.drain(..));
+    Ok(())
+}
+
+fn has_pending_jobs(channel: &LiteralChannel) -> bool {
+    let queue = channel.0.lock().unwrap();
+    queue.busy || !queue.jobs.is_empty() || !queue.results.is_empty()
+}
+
+fn shutdown_worker(channel: &LiteralChannel) {
+    let (ref lock, ref cvar) = **channel;
+    lock.lock().unwrap().shutdown = true;
+    cvar.notify_all();
+}
+
+// marks the worker gone even if it panics, so the command thread never waits on it forever
+struct WorkerExitGuard(LiteralChannel);
+
+impl Drop for WorkerExitGuard {
+    fn drop(&mut self) {
+        let (ref lock, ref cvar) = *self.0;
+        if let Ok(mut queue) = lock.lock() {
+            queue.exited = true;
+        }
+        cvar.notify_all();
+    }
+}
+
+// The literal coder on the worker. It remembers whether anything was coded since its buffer was
+// last drained, which tells whether the serial encoder could have given up on the last drain.
+struct DrainTrackingCoder {
+    coder: LiteralCoder,
+    coded_since_drain: bool,
+}
+
+impl ArithmeticEncoderOrDecoder for DrainTrackingCoder {
+    fn mov(&mut self) -> Self {
+        DrainTrackingCoder {
+            coder: self.coder.mov(),
+            coded_since_drain: self.coded_since_drain,
+        }
+    }
+    fn drain_or_fill_internal_buffer_unchecked(&mut self,
+                                               input:&mut ReadableBytes,
+                                               output:&mut WritableBytes) -> DivansResult {
+        self.coded_since_drain = false;
+        self.coder.drain_or_fill_internal_buffer_unchecked(input, output)
+    }
+    fn has_data_to_drain_or_fill(&self) -> bool {
+        self.coder.has_data_to_drain_or_fill()
+    }
+    fn get_or_put_bit_without_billing(&mut self,
+                                      bit: &mut bool,
+                                      prob_of_false: u8) {
+        self.coded_since_drain = true;
+        self.coder.get_or_put_bit_without_billing(bit, prob_of_false)
+    }
+    fn get_or_put_bit(&mut self,
+                      bit: &mut bool,
+                      prob_of_false: u8,
+                      billing: BillingDesignation) {
+        self.coded_since_drain = true;
+        self.coder.get_or_put_bit(bit, prob_of_false, billing)
+    }
+    fn get_or_put_nibble_without_billing<C: CDF16>(&mut self,
+                                                   nibble: &mut u8,
+                                                   prob: &C) -> ProbRange {
+        self.coded_since_drain = true;
+        self.coder.get_or_put_nibble_without_billing(nibble, prob)
+    }
+    fn get_or_put_nibble<C: CDF16>(&mut self,
+                                   nibble: &mut u8,
+                                   prob: &C,
+                                   billing: BillingDesignation) -> ProbRange {
+        self.coded_since_drain = true;
+        self.coder.get_or_put_nibble(nibble, prob, billing)
+    }
+    fn close(&mut self) -> DivansResult {
+        self.coder.close()
+    }
+    fn sync(&mut self) -> DivansResult {
+        self.coder.sync()
+    }
+}
+
+// Multiplexer for the worker: every drain of the literal coder fits in one write, and each one
+// is kept as a separate chunk for the command thread to replay.
+#[derive(Default)]
+struct LiteralRecorder {
+    buffer: Vec<u8>,
+    cursor: usize,
+    unused_cursor: usize,
+    chunks: Vec<Vec<u8>>,
+}
+
+impl LiteralRecorder {
+    fn close_chunk(&mut self) {
+        if self.cursor != 0 {
+            self.chunks.push(self.buffer[..self.cursor].to_vec());
+            self.cursor = 0;
+        }
+    }
+}
+
+impl StreamMuxer<AllocU8> for LiteralRecorder {
+    fn write(&mut self, stream_id: StreamID, data: &[u8], _m8: &mut AllocU8) -> usize {
+        if usize::from(stream_id) != LIT_CODER {
+            return 0;
+        }
+        self.buffer.truncate(self.cursor);
+        self.buffer.extend(data);
+        self.cursor += data.len();
+        data.len()
+    }
+    fn write_buffer(&mut self, _m8: &mut AllocU8) -> [WritableBytes<'_>; NUM_STREAMS] {
+        if self.buffer.len() < self.cursor + ::ans::MAX_BUFFER_SIZE {
+            self.buffer.resize(self.cursor + ::ans::MAX_BUFFER_SIZE, 0);
+        }
+        [
+            WritableBytes{
+                data:&mut [],
+                write_offset:&mut self.unused_cursor,
+            },
+            WritableBytes{
+                data:&mut self.buffer[..],
+                write_offset:&mut self.cursor,
+            },
+        ]
+    }
+    fn linearize(&mut self, _output: &mut [u8]) -> usize {
+        self.close_chunk();
+        0
+    }
+    fn flush(&mut self, _output: &mut [u8]) -> usize {
+        self.close_chunk();
+        0
+    }
+    fn wrote_eof(&self) -> bool {
+        self.cursor == 0
+    }
+    fn sync_flush(&mut self, _output: &mut [u8]) -> usize {
+        self.close_chunk();
+        0
+    }
+    fn wrote_all_data(&self) -> bool {
+        self.cursor == 0
+    }
+    fn free_mux(&mut self, _m8: &mut AllocU8) {
+    }
+}
+
+type LiteralWorkerEncoder = DivansLiteralEncoder<::DefaultCDF16,
+                                                 AllocU8,
+                                                 HeapAlloc<::DefaultCDF16>,
+                                                 DrainTrackingCoder,
+                                                 LiteralRecorder>;
+
+fn run_literal_job(encoder: &mut LiteralWorkerEncoder, job: LiteralJob) -> Result<Vec<ReplayItem>, ErrMsg> {
+    let mut items = Vec::<ReplayItem>::new();
+    let mut unused_offset = 0usize;
+    let ret = match job {
+        LiteralJob::Literal(data, last_8_literals, match_history) => {
+            let ret = encoder.encode_literal(&data[..], last_8_literals, &match_history[..], &mut [], &mut unused_offset);
+            encoder.muxer.close_chunk();
+            // the drain ahead of the first nibble writes out whatever an earlier literal left behind
+            items.push(ReplayItem{bytes: Vec::<u8>::new(), abandonable: false});
+            for chunk in encoder.muxer.chunks.drain(..) {
+                items.push(ReplayItem{bytes: chunk, abandonable: false});
+            }
+            if items.len() > 1 && !encoder.ctx.lit_coder.coded_since_drain {
+                items.last_mut().unwrap().abandonable = true;
+            }
+            ret
+        },
+        LiteralJob::BlockSwitch(btype) => {
+            encoder.obs_literal_block_switch(btype);
+            DivansResult::Success
+        },
+        LiteralJob::PredictionMode(literal_context_map, predmode_speed_and_distance_context_map) => {
+            let pm = PredictionModeContextMap::<SliceReference<u8>> {
+                literal_context_map: SliceReference::new(&literal_context_map[..], 0, literal_context_map.len()),
+                predmode_speed_and_distance_context_map: SliceReference::new(
+                    &predmode_speed_and_distance_context_map[..], 0, predmode_speed_and_distance_context_map.len()),
+            };
+            DivansResult::from(encoder.obs_prediction_mode_context_map(&pm))
+        },
+        LiteralJob::CoderOp(op) => {
+            let ret = encoder.literal_coder_op(op, &mut [], &mut unused_offset);
+            if let LiteralCoderOp::Drain = op {
+                encoder.muxer.close_chunk();
+                let mut bytes = Vec::<u8>::new();
+                for chunk in encoder.muxer.chunks.drain(..) {
+                    bytes.extend(chunk);
+                }
+                items.push(ReplayItem{bytes: bytes, abandonable: false});
+            }
+            ret
+        },
+    };
+    match ret {
+        DivansResult::Success => Ok(items),
+        DivansResult::Failure(m) => Err(m),
+        // the recorder always has room, so the coder never has to stop partway
+        DivansResult::NeedsMoreInput | DivansResult::NeedsMoreOutput => Err(ErrMsg::WrongInternalEncoderState(1)),
+    }
+}
+
+fn run_literal_worker(mut encoder: LiteralWorkerEncoder, channel: LiteralChannel) {
+    let _exit_guard = WorkerExitGuard(channel.clone());
+    let (ref lock, ref cvar) = *channel;
+    loop {
+        let job = {
+            let mut queue = lock.lock().unwrap();
+            while queue.jobs.is_empty() && !queue.shutdown {
+                queue = cvar.wait(queue).unwrap();
+            }
+            if queue.shutdown {
+                return;
+            }
+            queue.busy = true;
+            queue.jobs.pop_front().unwrap()
+        };
+        let size = job.size();
+        let result = run_literal_job(&mut encoder, job);
+        let mut queue = lock.lock().unwrap();
+        queue.busy = false;
+        queue.queued_bytes -= size;
+        cvar.notify_all();
+        match result {
+            Ok(items) => queue.results.extend(items),
+            Err(m) => {
+                queue.err = Some(m);
+                return;
+            },
+        }
+    }
+}
+
+fn pack_last_8_literals(last_8: [u8; 8]) -> u64 {
+    u64::from(last_8[0])
+        | (u64::from(last_8[1])<<0x8)
+        | (u64::from(last_8[2])<<0x10)
+        | (u64::from(last_8[3])<<0x18)
+        | (u64::from(last_8[4])<<0x20)
+        | (u64::from(last_8[5])<<0x28)
+        | (u64::from(last_8[6])<<0x30)
+        | (u64::from(last_8[7])<<0x38)
+}
+
+// Sits where the encoder expects its ring buffer: commands are still recoded locally, but the
+// ones that feed the literal model are also queued for the worker in stream order.
+pub struct LiteralForwarder {
+    ring_buffer: DemuxerAndRingBuffer<AllocU8, DevNull<AllocU8>>,
+    channel: LiteralChannel,
+    last_8_literals: u64,
+    match_history: [u8; MAX_MATCH_BYTES],
+    match_history_len: usize,
+    // the current command already went to the worker before a retry
+    forwarded: bool,
+}
+
+impl StreamDemuxer<AllocU8> for LiteralForwarder {
+    fn write_linear(&mut self, data:&[u8], m8: &mut AllocU8) -> usize {
+        self.ring_buffer.write_linear(data, m8)
+    }
+    fn read_buffer(&mut self) -> [ReadableBytes<'_>; NUM_STREAMS] {
+        self.ring_buffer.read_buffer()
+    }
+    fn data_ready(&self, stream_id:StreamID) -> usize {
+        self.ring_buffer.data_ready(stream_id)
+    }
+    fn peek(&self, stream_id: StreamID) -> &[u8] {
+        self.ring_buffer.peek(stream_id)
+    }
+    fn edit(&mut self, stream_id: StreamID) -> &mut AllocatedMemoryRange<u8, AllocU8> {
+        self.ring_buffer.edit(stream_id)
+    }
+    fn consume(&mut self, stream_id: StreamID, count: usize) {
+        self.ring_buffer.consume(stream_id, count)
+    }
+    fn consumed_all_streams_until_eof(&self) -> bool {
+        self.ring_buffer.consumed_all_streams_until_eof()
+    }
+    fn encountered_eof(&self) -> bool {
+        self.ring_buffer.encountered_eof()
+    }
+    fn free_demux(&mut self, m8: &mut AllocU8) {
+        self.ring_buffer.free_demux(m8)
+    }
+}
+
+impl ThreadToMain<AllocU8> for LiteralForwarder {
+    const COOPERATIVE: bool = false;
+    const ISOLATED: bool = false;
+    const REMOTE_LITERAL_CODER: bool = true;
+    fn pull_data(&mut self) -> ThreadData<AllocU8> {
+        self.ring_buffer.pull_data()
+    }
+    fn pull_context_map(&mut self, m8: Option<&mut RepurposingAlloc<u8, AllocU8>>) -> Result<PredictionModeContextMap<AllocatedMemoryPrefix<u8, AllocU8>>, ()> {
+        self.ring_buffer.pull_context_map(m8)
+    }
+    fn push_cmd<Specialization:EncoderOrDecoderRecoderSpecialization>(
+        &mut self,
+        cmd:&mut Command<AllocatedMemoryPrefix<u8, AllocU8>>,
+        m8: Option<&mut RepurposingAlloc<u8, AllocU8>>,
+        recoder: Option<&mut DivansRecodeState<<AllocU8 as Allocator<u8>>::AllocatedMemory>>,
+        specialization: &mut Specialization,
+        output:&mut [u8],
+        output_offset: &mut usize,
+    ) -> DivansOutputResult {
+        if !self.forwarded {
+            let job = match *cmd {
+                Command::Literal(ref lit) => Some(LiteralJob::Literal(lit.data.slice().to_vec(),
+                                                                           self.last_8_literals,
+                                                                           self.match_history[..self.match_history_len].to_vec())),
+                Command::BlockSwitchLiteral(btype) => Some(LiteralJob::BlockSwitch(btype)),
+                Command::PredictionMode(ref pm) => Some(LiteralJob::PredictionMode(
+                    pm.literal_context_map.slice().to_vec(),
+                    pm.predmode_speed_and_distance_context_map.slice().to_vec())),
+                _ => None,
+            };
+            if let Some(job) = job {
+                match submit_job(&self.channel, job) {
+                    DivansOutputResult::Success => {},
+                    res => return res,
+                }
+            }
+            self.forwarded = true;
+        }
+        let ret = match recoder {
+            Some(recoder) => {
+                let ret = self.ring_buffer.push_cmd(cmd, m8, Some(&mut *recoder), specialization, output, output_offset);
+                if let DivansOutputResult::Success = ret {
+                    self.last_8_literals = pack_last_8_literals(recoder.last_8_literals());
+                    self.match_history_len = recoder.match_history(&mut self.match_history[..]);
+                }
+                ret
+            },
+            None => self.ring_buffer.push_cmd(cmd, m8, None, specialization, output, output_offset),
+        };
+        if let DivansOutputResult::NeedsMoreOutput = ret {} else {
+            self.forwarded = false;
+        }
+        ret
+    }
+    fn push_consumed_data(&mut self,
+                          data:&mut AllocatedMemoryRange<u8, AllocU8>,
+                          m8: Option<&mut RepurposingAlloc<u8, AllocU8>>) -> DivansOutputResult {
+        self.ring_buffer.push_consumed_data(data, m8)
+    }
+    fn push_eof(&mut self) -> DivansOutputResult {
+        self.ring_buffer.push_eof()
+    }
+    fn push_sync(&mut self) -> DivansOutputResult {
+        self.ring_buffer.push_sync()
+    }
+    fn push_literal_coder_op(&mut self, op: LiteralCoderOp) -> DivansOutputResult {
+        submit_job(&self.channel, LiteralJob::CoderOp(op))
+    }
+    fn broadcast_err(&mut self, err:ErrMsg) {
+        self.ring_buffer.broadcast_err(err)
+    }
+}
+
+// The multiplexer of the command thread. Before the command coder touches the stream, it
+// replays the literal drains recorded by the worker, including where the serial encoder would
+// have stopped for lack of output space.
+pub struct LiteralReplayMux {
+    mux: Mux<AllocU8>,
+    m8: AllocU8,
+    channel: LiteralChannel,
+    items: VecDeque<ReplayItem>,
+    current: ReplayItem,
+    progress: usize,
+    // bytes the serial encoder would have left in its coder after an abandoned drain
+    leftover: Vec<u8>,
+    err: Option<ErrMsg>,
+    unused_cursor: [usize; NUM_STREAMS],
+}
+
+impl Default for LiteralReplayMux {
+    fn default() -> Self {
+        LiteralReplayMux::new(Arc::new((Mutex::new(LiteralQueue::default()), Condvar::new())))
+    }
+}
+
+impl LiteralReplayMux {
+    fn new(channel: LiteralChannel) -> Self {
+        LiteralReplayMux {
+            mux: Mux::<AllocU8>::default(),
+            m8: HeapAlloc::<u8>::new(0),
+            channel,
+            items: VecDeque::<ReplayItem>::new(),
+            current: ReplayItem{bytes: Vec::<u8>::new(), abandonable: false},
+            progress: 0,
+            leftover: Vec::<u8>::new(),
+            err: None,
+            unused_cursor: [0; NUM_STREAMS],
+        }
+    }
+    fn suspended(&self) -> bool {
+        self.progress != self.current.bytes.len()
+    }
+    fn has_pending_data(&self) -> bool {
+        self.suspended() || !self.items.is_empty() || !self.leftover.is_empty() || has_pending_jobs(&self.channel)
+    }
+    // writes the recorded drains to the multiplexer the way drain_or_fill_static_buffer would have.
+    // Without wait only the drains the worker already finished are written; the rest stay queued
+    // until the next call, which is exact as long as nothing else reaches the output in between.
+    fn replay(&mut self, output: &mut [u8], wait: bool) -> Result<usize, ErrMsg> {
+        if let Some(m) = self.err {
+            return Err(m);
+        }
+        if self.suspended() && output.is_empty() {
+            // the serial encoder returned at this drain and only retries once given more room
+            return Ok(0);
+        }
+        if let Err(m) = collect_results(&self.channel, &mut self.items, wait) {
+            self.err = Some(m);
+            return Err(m);
+        }
+        let mut output_offset = 0usize;
+        loop {
+            if !self.suspended() {
+                match self.items.pop_front() {
+                    None => return Ok(output_offset),
+                    Some(mut item) => {
+                        if !self.leftover.is_empty() {
+                            self.leftover.extend(item.bytes.iter());
+                            core::mem::swap(&mut self.leftover, &mut item.bytes);
+                            self.leftover.clear();
+                        }
+                        self.current = item;
+                        self.progress = 0;
+                        continue;
+                    },
+                }
+            }
+            output_offset += self.mux.linearize(output.split_at_mut(output_offset).1);
+            let copied = {
+                let mut buffers = self.mux.write_buffer(&mut self.m8);
+                let lit = &mut buffers[LIT_CODER];
+                let to_copy = core::cmp::min(lit.data.len() - *lit.write_offset,
+                                             self.current.bytes.len() - self.progress);
+                lit.data[*lit.write_offset..*lit.write_offset + to_copy].clone_from_slice(
+                    &self.current.bytes[self.progress..self.progress + to_copy]);
+                *lit.write_offset += to_copy;
+                to_copy
+            };
+            self.progress += copied;
+            if self.suspended() && output_offset == output.len() {
+                if !self.current.abandonable {
+                    return Ok(output_offset);
+                }
+                self.leftover = self.current.bytes.split_off(self.progress);
+            }
+        }
+    }
+    fn drain_replay(&mut self, output:&mut[u8], output_offset:&mut usize, wait: bool) -> DivansOutputResult {
+        match self.replay(output.split_at_mut(*output_offset).1, wait) {
+            Ok(replayed) => *output_offset += replayed,
+            Err(m) => return DivansOutputResult::Failure(m),
+        }
+        if self.suspended() {
+            DivansOutputResult::NeedsMoreOutput
+        } else {
+            DivansOutputResult::Success
+        }
+    }
+}
+
+impl StreamMuxer<AllocU8> for LiteralReplayMux {
+    fn write(&mut self, stream_id: StreamID, data:&[u8], m8: &mut AllocU8) -> usize {
+        self.mux.write(stream_id, data, m8)
+    }
+    fn write_buffer(&mut self, m8: &mut AllocU8) -> [WritableBytes<'_>; NUM_STREAMS] {
+        if self.suspended() {
+            // the serial encoder would still be stuck on the literal drain
+            let (first, second) = self.unused_cursor.split_at_mut(1);
+            return [
+                WritableBytes{
+                    data:&mut [],
+                    write_offset:&mut first[0],
+                },
+                WritableBytes{
+                    data:&mut [],
+                    write_offset:&mut second[0],
+                },
+            ];
+        }
+        self.mux.write_buffer(m8)
+    }
+    fn linearize(&mut self, output:&mut[u8]) -> usize {
+        // a failed worker is remembered in self.err and reported by drain_remote_coders
+        let replayed = self.replay(output, true).unwrap_or(0);
+        replayed + self.mux.linearize(output.split_at_mut(replayed).1)
+    }
+    fn flush(&mut self, output:&mut[u8]) -> usize {
+        let replayed = self.replay(output, true).unwrap_or(0);
+        replayed + self.mux.flush(output.split_at_mut(replayed).1)
+    }
+    fn wrote_eof(&self) -> bool {
+        (self.err.is_some() || !self.has_pending_data()) && self.mux.wrote_eof()
+    }
+    fn sync_flush(&mut self, output:&mut[u8]) -> usize {
+        let replayed = self.replay(output, true).unwrap_or(0);
+        replayed + self.mux.sync_flush(output.split_at_mut(replayed).1)
+    }
+    fn wrote_all_data(&self) -> bool {
+        (self.err.is_some() || !self.has_pending_data()) && self.mux.wrote_all_data()
+    }
+    fn drain_remote_coders(&mut self, output:&mut[u8], output_offset:&mut usize) -> DivansOutputResult {
+        self.drain_replay(output, output_offset, true)
+    }
+    fn drain_finished_remote_coders(&mut self, output:&mut[u8], output_offset:&mut usize) -> DivansOutputResult {
+        self.drain_replay(output, output_offset, false)
+    }
+    fn free_mux(&mut self, m8: &mut AllocU8) {
+        self.mux.free_mux(m8)
+    }
+}
+
+type CommandThreadCompressor = DivansCompressor<LiteralCoder,
+                                                AllocU8,
+                                                HeapAlloc<u32>,
+                                                HeapAlloc<::DefaultCDF16>,
+                                                LiteralForwarder,
+                                                LiteralReplayMux>;
+
+// Compresses on two threads, one coding commands and one coding literal contents. The stream is
+// identical to the one DivansCompressor produces with the same options; literals that carry
+// their own probabilities are not supported.
+pub struct DivansLiteralThreadCompressor {
+    compressor: CommandThreadCompressor,
+    channel: LiteralChannel,
+    worker: Option<thread::JoinHandle<()>>,
+}
+
+impl DivansLiteralThreadCompressor {
+    pub fn new(opts: DivansCompressorOptions) -> Self {
+        let channel: LiteralChannel = Arc::new((Mutex::new(LiteralQueue::default()), Condvar::new()));
+        let features = opts.stream_features();
+        let mut m8 = HeapAlloc::<u8>::new(0);
+        let lit_coder = DrainTrackingCoder {
+            coder: LiteralCoder::new_with_entropy_coder(&mut m8, features.entropy_coder),
+            coded_since_drain: false,
+        };
+        // the worker keeps no window of its own: the command thread sends the context with each literal
+        let mut ctx = MainThreadContext::<::DefaultCDF16, AllocU8, HeapAlloc<::DefaultCDF16>, DrainTrackingCoder>::new(
+            m8,
+            HeapAlloc::<::DefaultCDF16>::new(::DefaultCDF16::default()),
+            lit_coder,
+            0);
+        ctx.set_cdf_flavor(features.cdf_flavor);
+        ctx.set_literal_mixer(features.literal_mixer);
+        ctx.set_literal_match(features.literal_match);
+        ctx.set_literal_sse(features.literal_sse);
+        ctx.set_literal_hash_size(features.literal_hash_size);
+        let encoder = LiteralWorkerEncoder::new(ctx, LiteralRecorder::default());
+        let worker_channel = channel.clone();
+        let worker = thread::spawn(move || run_literal_worker(encoder, worker_channel));
+        let forwarder = LiteralForwarder {
+            ring_buffer: DemuxerAndRingBuffer::<AllocU8, DevNull<AllocU8>>::default(),
+            channel: channel.clone(),
+            last_8_literals: 0,
+            match_history: [0; MAX_MATCH_BYTES],
+            match_history_len: 0,
+            forwarded: false,
+        };
+        DivansLiteralThreadCompressor {
+            compressor: CommandThreadCompressor::new(HeapAlloc::<u8>::new(0),
+                                                     HeapAlloc::<u32>::new(0),
+                                                     HeapAlloc::<::DefaultCDF16>::new(::DefaultCDF16::default()),
+                                                     opts,
+                                                     forwarder,
+                                                     LiteralReplayMux::new(channel.clone())),
+            channel,
+            worker: Some(worker),
+        }
+    }
+}
+
+impl Drop for DivansLiteralThreadCompressor {
+    fn drop(&mut self) {
+        shutdown_worker(&self.channel);
+        if let Some(worker) = self.worker.take() {
+            let _ = worker.join();
+        }
+    }
+}
+
+impl Compressor for DivansLiteralThreadCompressor {
+    fn encode(&mut self,
+              input: &[u8],
+              input_offset: &mut usize,
+              output: &mut [u8],
+              output_offset: &mut usize) -> DivansResult {
+        let ret = self.compressor.encode(input, input_offset, output, output_offset);
+        if let DivansResult::Failure(_) = ret {
+            return ret;
+        }
+        // the caller may hand in a different buffer next time, so the literals coded for this one
+        // have to be in it before returning
+        match self.compressor.drain_remote_coders(output, output_offset) {
+            DivansOutputResult::Success => ret,
+            res => DivansResult::from(res),
+        }
+    }
+    fn encode_commands<SliceType:SliceWrapper<u8>+Default>(&mut self,
+                                                           input:&[Command<SliceType>],
+                                                           input_offset : &mut usize,
+                                                           output :&mut[u8],
+                                                           output_offset: &mut usize) -> DivansOutputResult {
+        self.compressor.encode_commands(input, input_offset, output, output_offset)
+    }
+    fn flush(&mut self,
+             output: &mut [u8],
+             output_offset: &mut usize) -> DivansOutputResult {
+        self.compressor.flush(output, output_offset)
+    }
+    fn sync_flush(&mut self,
+                  output: &mut [u8],
+                  output_offset: &mut usize) -> DivansOutputResult {
+        self.compressor.sync_flush(output, output_offset)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use core;
+    use std::io::Read;
+    use std::vec::Vec;
+    use alloc::HeapAlloc;
+    use ::interface::{Compressor, DivansCompressorFactory, DivansCompressorOptions,
+                      DivansOutputResult, DivansResult, EntropyCoder, ErrMsg};
+    use super::DivansLiteralThreadCompressor;
+
+    fn drain_tst<F:FnMut(&mut [u8], &mut usize) -> DivansOutputResult>(mut flush: F, output: &mut [u8], compressed: &mut Vec<u8>) {
+        loop {
+            let mut output_offset = 0usize;
+            let ret = flush(output, &mut output_offset);
+            compressed.extend(&output[..output_offset]);
+            match ret {
+                DivansOutputResult::Success => return,
+                DivansOutputResult::NeedsMoreOutput => {},
+                DivansOutputResult::Failure(m) => panic!("flush failed {:?}", m),
+            }
+        }
+    }
+    // hands the input over in chunks of input_size, optionally with a sync flush after each chunk
+    fn compress_tst<C:Compressor>(compressor: &mut C, data: &[u8], input_size: usize, output_size: usize, sync: bool) -> Vec<u8> {
+        let mut output = vec![0u8; output_size];
+        let mut compressed = Vec::<u8>::new();
+        let mut input_offset = 0usize;
+        while input_offset < data.len() {
+            let input_end = core::cmp::min(data.len(), input_offset + input_size);
+            loop {
+                let mut output_offset = 0usize;
+                let ret = compressor.encode(&data[..input_end], &mut input_offset, &mut output[..], &mut output_offset);
+                compressed.extend(&output[..output_offset]);
+                match ret {
+                    DivansResult::NeedsMoreInput => break,
+                    DivansResult::NeedsMoreOutput => {},
+                    DivansResult::Success | DivansResult::Failure(_) => panic!("encode returned {:?}", ret),
+                }
+            }
+            if sync {
+                drain_tst(|out, off| compressor.sync_flush(out, off), &mut output[..], &mut compressed);
+            }
+        }
+        drain_tst(|out, off| compressor.flush(out, off), &mut output[..], &mut compressed);
+        compressed
+    }
+    fn serial_compress_tst(opts: DivansCompressorOptions, data: &[u8], input_size: usize, output_size: usize, sync: bool) -> Vec<u8> {
+        let mut compressor = ::DivansCompressorFactoryStruct::<HeapAlloc<u8>, HeapAlloc<::DefaultCDF16>>::new(
+            HeapAlloc::<u8>::new(0),
+            HeapAlloc::<u32>::new(0),
+            HeapAlloc::<::DefaultCDF16>::new(::DefaultCDF16::default()),
+            opts,
+            ());
+        let ret = compress_tst(&mut compressor, data, input_size, output_size, sync);
+        compressor.free();
+        ret
+    }
+    fn threaded_compress_tst(opts: DivansCompressorOptions, data: &[u8], input_size: usize, output_size: usize, sync: bool) -> Vec<u8> {
+        let mut compressor = DivansLiteralThreadCompressor::new(opts);
+        compress_tst(&mut compressor, data, input_size, output_size, sync)
+    }
+    #[test]
+    fn test_literal_thread_matches_serial() {
+        let data = include_bytes!("../testdata/alice29");
+        for entropy_coder in [None, Some(EntropyCoder::Range)].iter() {
+            let mut opts = DivansCompressorOptions::default();
+            opts.entropy_coder = *entropy_coder;
+            for output_size in [7usize, 65536].iter() {
+                assert_eq!(threaded_compress_tst(opts, &data[..], 65536, *output_size, false),
+                           serial_compress_tst(opts, &data[..], 65536, *output_size, false));
+            }
+        }
+    }
+    #[test]
+    fn test_literal_thread_matches_serial_tiny_output() {
+        let data = include_bytes!("../testdata/alice29");
+        let opts = DivansCompressorOptions::default();
+        let input = &data[..40000];
+        assert_eq!(threaded_compress_tst(opts, input, 4096, 1, false),
+                   serial_compress_tst(opts, input, 4096, 1, false));
+    }
+    #[test]
+    fn test_literal_thread_matches_serial_sync_flush() {
+        let data = include_bytes!("../testdata/alice29");
+        let mut opts = DivansCompressorOptions::default();
+        opts.sync_points = true;
+        for output_size in [7usize, 65536].iter() {
+            assert_eq!(threaded_compress_tst(opts, &data[..], 16384, *output_size, true),
+                       serial_compress_tst(opts, &data[..], 16384, *output_size, true));
+        }
+    }
+    #[test]
+    fn test_literal_thread_reports_worker_failure() {
+        let data = include_bytes!("../testdata/alice29");
+        let mut compressor = DivansLiteralThreadCompressor::new(DivansCompressorOptions::default());
+        let mut output = vec![0u8; 65536];
+        let mut input_offset = 0usize;
+        let mut output_offset = 0usize;
+        match compressor.encode(&data[..16384], &mut input_offset, &mut output[..], &mut output_offset) {
+            DivansResult::NeedsMoreInput => {},
+            res => panic!("encode returned {:?}", res),
+        }
+        compressor.channel.0.lock().unwrap().err = Some(ErrMsg::LiteralWorkerExited);
+        output_offset = 0;
+        match compressor.flush(&mut output[..], &mut output_offset) {
+            DivansOutputResult::Failure(ErrMsg::LiteralWorkerExited) => {},
+            res => panic!("flush returned {:?}", res),
+        }
+    }
+    #[test]
+    fn test_literal_thread_roundtrip() {
+        let data = include_bytes!("../testdata/alice29");
+        let compressed = threaded_compress_tst(DivansCompressorOptions::default(), &data[..], 4096, 4096, false);
+        let mut decompressor = ::DivansDecompressorReader::new(&compressed[..], 4096, false, false);
+        let mut decompressed = Vec::<u8>::new();
+        decompressor.read_to_end(&mut decompressed).unwrap();
+        assert_eq!(&decompressed[..], &data[..]);
+    }
+}
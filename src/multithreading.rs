@@ -1,14 +1,14 @@
-#![cfg(not(feature="no-stdlib"))]
 use core;
 
-use std::sync::{Arc, Mutex, Condvar};
-use threading::{SerialWorker, MainToThread, ThreadToMain, CommandResult, ThreadData, NUM_SERIAL_COMMANDS_BUFFERED, NUM_DATA_BUFFERED,};
+use threading::{WorkerLock, WorkerSpawner, SerialWorker, MainToThread, ThreadToMain, CommandResult, ThreadData, NUM_SERIAL_COMMANDS_BUFFERED, NUM_DATA_BUFFERED,};
 use slice_util::{AllocatedMemoryRange, AllocatedMemoryPrefix};
 use alloc::{Allocator, SliceWrapper, SliceWrapperMut};
 use alloc_util::RepurposingAlloc;
 use cmd_to_raw::DivansRecodeState;
 use interface::{PredictionModeContextMap, EncoderOrDecoderRecoderSpecialization, Command, DivansOpResult, DivansOutputResult, ErrMsg};
-use std::time::{SystemTime, Duration};
+use core::time::Duration;
+#[cfg(feature="threadlog")]
+use std::time::SystemTime;
 use threading::{StaticCommand, PullAllocatedCommand, downcast_command};
 #[cfg(feature="threadlog")]
 const MAX_LOG_SIZE: usize = 8192;
@@ -16,9 +16,10 @@ const MAX_LOG_SIZE: usize = 8192;
 const MAX_LOG_SIZE: usize = 0;
 
 
-pub struct MultiWorker<AllocU8:Allocator<u8>, AllocCommand:Allocator<StaticCommand>> {
+pub struct MultiWorker<AllocU8:Allocator<u8>, AllocCommand:Allocator<StaticCommand>, Spawner:WorkerSpawner> {
+    #[cfg(feature="threadlog")]
     start: SystemTime,
-    queue: Arc<(Mutex<SerialWorker<AllocU8, AllocCommand>>, Condvar)>,
+    queue: Spawner::Lock<SerialWorker<AllocU8, AllocCommand>>,
     log: [ThreadEvent; MAX_LOG_SIZE],
     log_offset: u32,
 }
@@ -46,6 +47,8 @@ enum ThreadEventType {
     W_WAIT_PUSH_CONSUMED_DATA,
     W_PUSH_EOF,
     W_WAIT_PUSH_EOF,
+    W_PUSH_SYNC,
+    W_WAIT_PUSH_SYNC,
     W_BROADCAST_ERR,
 }
 #[derive(Debug, Clone, Copy)]
@@ -80,11 +83,12 @@ macro_rules! thread_debug {
     };
 }
 
-impl<AllocU8:Allocator<u8>, AllocCommand:Allocator<StaticCommand>> Clone for MultiWorker<AllocU8, AllocCommand> {
+impl<AllocU8:Allocator<u8>, AllocCommand:Allocator<StaticCommand>, Spawner:WorkerSpawner> Clone for MultiWorker<AllocU8, AllocCommand, Spawner> {
     fn clone(&self) -> Self {
         Self {
             log:self.log.clone(),
             log_offset:self.log_offset.clone(),
+            #[cfg(feature="threadlog")]
             start:self.start,
             queue:self.queue.clone(),
         }
@@ -92,7 +96,7 @@ impl<AllocU8:Allocator<u8>, AllocCommand:Allocator<StaticCommand>> Clone for Mul
 }
 
 #[cfg(feature="threadlog")]
-impl<AllocU8:Allocator<u8>, AllocCommand:Allocator<StaticCommand>> Drop for MultiWorker<AllocU8, AllocCommand> {
+impl<AllocU8:Allocator<u8>, AllocCommand:Allocator<StaticCommand>, Spawner:WorkerSpawner> Drop for MultiWorker<AllocU8, AllocCommand, Spawner> {
     fn drop(&mut self) {
         let epoch_d = self.start.duration_since(std::time::UNIX_EPOCH).unwrap_or(Duration::new(0,0));
         let epoch = (epoch_d.as_secs()%100) * 100 + u64::from(epoch_d.subsec_nanos()) / 10000000;
@@ -112,38 +116,41 @@ impl<AllocU8:Allocator<u8>, AllocCommand:Allocator<StaticCommand>> Drop for Mult
 
 
 
-impl<AllocU8:Allocator<u8>, AllocCommand:Allocator<StaticCommand>> MultiWorker<AllocU8, AllocCommand>
+impl<AllocU8:Allocator<u8>, AllocCommand:Allocator<StaticCommand>, Spawner:WorkerSpawner> MultiWorker<AllocU8, AllocCommand, Spawner>
 {
-    pub fn new(mcommand: &mut AllocCommand) -> Self {
-        MultiWorker::<AllocU8, AllocCommand> {
+    pub fn new(mcommand: &mut AllocCommand, spawner: &Spawner) -> Self {
+        MultiWorker::<AllocU8, AllocCommand, Spawner> {
             log:[ThreadEvent(ThreadEventType::M_PUSH_EMPTY_DATA, 0, Duration::new(0,0)); MAX_LOG_SIZE],
             log_offset:0,
+            #[cfg(feature="threadlog")]
             start: SystemTime::now(),
-            queue: Arc::new((Mutex::new(SerialWorker::<AllocU8, AllocCommand>::new(mcommand)), Condvar::new())),
+            queue: spawner.new_lock(SerialWorker::<AllocU8, AllocCommand>::new(mcommand)),
         }
     }
     fn broadcast_err_internal(&mut self, err: ErrMsg, _thread_event_type: ThreadEventType) {
         let _elapsed = unguarded_debug_time!(self);
-        let &(ref lock, ref cvar) = &*self.queue;
-        let mut worker = lock.lock().unwrap();
+        let mut worker = self.queue.lock();
         if worker.waiters != 0 {
-            cvar.notify_one();
+            self.queue.notify_one();
         }
         let ret = worker.broadcast_err_internal(err);
         thread_debug!(_thread_event_type, output.len(), self, _elapsed);
         return ret;        
     }
+    // the error broadcast by either thread, if any
+    pub fn err(&self) -> Option<ErrMsg> {
+        self.queue.lock().err
+    }
     pub fn free(&mut self, m8: &mut RepurposingAlloc<u8, AllocU8>, mcommand: &mut AllocCommand) {
-        let &(ref lock, ref cvar) = &*self.queue;
-        let mut worker = lock.lock().unwrap();
+        let mut worker = self.queue.lock();
         if worker.waiters != 0 {
             worker.broadcast_err_internal(ErrMsg::UnexpectedEof);
-            cvar.notify_one();
+            self.queue.notify_one();
         }
         worker.free(m8, mcommand);
     }
 }
-impl<AllocU8:Allocator<u8>, AllocCommand: Allocator<StaticCommand>> PullAllocatedCommand<AllocU8, AllocCommand> for MultiWorker<AllocU8, AllocCommand> {
+impl<AllocU8:Allocator<u8>, AllocCommand: Allocator<StaticCommand>, Spawner:WorkerSpawner> PullAllocatedCommand<AllocU8, AllocCommand> for MultiWorker<AllocU8, AllocCommand, Spawner> {
     fn pull_command_buf(&mut self,
                         output:&mut AllocatedMemoryPrefix<StaticCommand, AllocCommand>,
                         consumed_data:&mut [AllocatedMemoryRange<u8, AllocU8>;NUM_DATA_BUFFERED],
@@ -152,7 +159,7 @@ impl<AllocU8:Allocator<u8>, AllocCommand: Allocator<StaticCommand>> PullAllocate
     }
 }
 
-impl<AllocU8:Allocator<u8>, AllocCommand:Allocator<StaticCommand>> MainToThread<AllocU8> for MultiWorker<AllocU8, AllocCommand> {
+impl<AllocU8:Allocator<u8>, AllocCommand:Allocator<StaticCommand>, Spawner:WorkerSpawner> MainToThread<AllocU8> for MultiWorker<AllocU8, AllocCommand, Spawner> {
     const COOPERATIVE_MAIN:bool = false;
     type CommandOutputType= <SerialWorker<AllocU8, AllocCommand> as MainToThread<AllocU8>>::CommandOutputType;
     #[inline(always)]
@@ -160,19 +167,20 @@ impl<AllocU8:Allocator<u8>, AllocCommand:Allocator<StaticCommand>> MainToThread<
         
         loop { // FIXME: should this loop forever? We should never run out of context map room
             let _elapsed = unguarded_debug_time!(self);
-            let &(ref lock, ref cvar) = &*self.queue;
-            let mut worker = lock.lock().unwrap();
+            let mut worker = self.queue.lock();
             if worker.cm_space_ready() {
                 thread_debug!(ThreadEventType::M_PUSH_CONTEXT_MAP, 1, self, _elapsed);
                 if worker.waiters != 0 {
-                    cvar.notify_one();
+                    self.queue.notify_one();
                 }
                 return worker.push_context_map(cm);
             } else {
+                if worker.err.is_some() {
+                    return Err(());
+                }
                 thread_debug!(ThreadEventType::M_WAIT_PUSH_CONTEXT_MAP, 0, self, _elapsed);
                 worker.waiters += 1;
-                let _ign = cvar.wait(worker); // always safe to loop around again
-                _ign.unwrap().waiters -= 1;
+                self.queue.wait(worker).waiters -= 1; // always safe to loop around again
             }
         }
     }
@@ -180,13 +188,12 @@ impl<AllocU8:Allocator<u8>, AllocCommand:Allocator<StaticCommand>> MainToThread<
     fn push(&mut self, data: &mut AllocatedMemoryRange<u8, AllocU8>) -> Result<(),()> {
         let _elapsed = unguarded_debug_time!(self);
         let _len = data.len();
-        let &(ref lock, ref cvar) = &*self.queue;
-        let mut worker = lock.lock().unwrap();
+        let mut worker = self.queue.lock();
         match worker.push(data) {
             Ok(()) => {
                 thread_debug!(ThreadEventType::M_PUSH_DATA, _len, self, _elapsed);
                 if worker.waiters != 0 {
-                    cvar.notify_one();
+                    self.queue.notify_one();
                 }
                 return Ok(());
             },
@@ -208,11 +215,10 @@ impl<AllocU8:Allocator<u8>, AllocCommand:Allocator<StaticCommand>> MainToThread<
             pm:&mut [PredictionModeContextMap<AllocatedMemoryPrefix<u8, AllocU8>>; 2]) -> CommandResult {
         loop {
             let _elapsed = unguarded_debug_time!(self);
-            let &(ref lock, ref cvar) = &*self.queue;
-            let mut worker = lock.lock().unwrap();
+            let mut worker = self.queue.lock();
             if worker.result_ready() {
                 if worker.waiters != 0 {
-                    cvar.notify_one(); // FIXME: do we want to signal here?
+                    self.queue.notify_one(); // FIXME: do we want to signal here?
                 }
                 let ret = worker.pull(output, consumed_data, pm);
                 thread_debug!(ThreadEventType::M_PULL_COMMAND_RESULT, output.len(), self, _elapsed);
@@ -220,8 +226,7 @@ impl<AllocU8:Allocator<u8>, AllocCommand:Allocator<StaticCommand>> MainToThread<
             } else if worker.err.is_none() {
                 thread_debug!(ThreadEventType::M_WAIT_PULL_COMMAND_RESULT, 0, self, _elapsed);
                 worker.waiters += 1;
-                let _ign = cvar.wait(worker);
-                _ign.unwrap().waiters -= 1;
+                self.queue.wait(worker).waiters -= 1;
                 //return CommandResult::ProcessedData(AllocatedMemoryRange::<u8, AllocU8>::default()); // FIXME: busy wait
             } else {
                 return CommandResult::Err(worker.err.unwrap());
@@ -234,24 +239,26 @@ impl<AllocU8:Allocator<u8>, AllocCommand:Allocator<StaticCommand>> MainToThread<
     }
 }
 
-impl<AllocU8:Allocator<u8>, AllocCommand:Allocator<StaticCommand>> ThreadToMain<AllocU8> for MultiWorker<AllocU8, AllocCommand> {
+impl<AllocU8:Allocator<u8>, AllocCommand:Allocator<StaticCommand>, Spawner:WorkerSpawner> ThreadToMain<AllocU8> for MultiWorker<AllocU8, AllocCommand, Spawner> {
     const COOPERATIVE:bool = false;
     const ISOLATED:bool = true;
     #[inline(always)]
     fn pull_data(&mut self) -> ThreadData<AllocU8> {
         loop {
             let _elapsed = unguarded_debug_time!(self);
-            let &(ref lock, ref cvar) = &*self.queue;
-            let mut worker = lock.lock().unwrap();
+            let mut worker = self.queue.lock();
             if worker.data_ready() {
                 let ret = worker.pull_data();
                 thread_debug!(ThreadEventType::W_PULL_DATA, match ret {ThreadData::Data(ref d) => d.len(), ThreadData::Yield => 0, ThreadData::Eof=> 99999999,}, self, _elapsed);
                 return ret;
             } else {
+                if let Some(err) = worker.err {
+                    // main gave up on the stream, so no more data will arrive
+                    return ThreadData::Err(err);
+                }
                 thread_debug!(ThreadEventType::W_WAIT_PULL_DATA, 0, self, _elapsed);
                 worker.waiters += 1;
-                let _ign = cvar.wait(worker);
-                _ign.unwrap().waiters -= 1;
+                self.queue.wait(worker).waiters -= 1;
             }
         }
     }
@@ -260,19 +267,20 @@ impl<AllocU8:Allocator<u8>, AllocCommand:Allocator<StaticCommand>> ThreadToMain<
                         m8: Option<&mut RepurposingAlloc<u8, AllocU8>>) -> Result<PredictionModeContextMap<AllocatedMemoryPrefix<u8, AllocU8>>, ()> {
         loop {
             let _elapsed = unguarded_debug_time!(self);
-            let &(ref lock, ref cvar) = &*self.queue;
-            let mut worker = lock.lock().unwrap();
+            let mut worker = self.queue.lock();
             if worker.cm_ready() {
                 if worker.waiters != 0 {
-                    cvar.notify_one();
+                    self.queue.notify_one();
                 }
                 thread_debug!(ThreadEventType::W_PULL_CONTEXT_MAP, 1, self, _elapsed);
                 return worker.pull_context_map(m8);
             } else {
+                if worker.err.is_some() {
+                    return Err(());
+                }
                 thread_debug!(ThreadEventType::W_WAIT_PULL_CONTEXT_MAP, 0, self, _elapsed);
                 worker.waiters += 1;
-                let _ign = cvar.wait(worker);
-                _ign.unwrap().waiters -= 1;
+                self.queue.wait(worker).waiters -= 1;
             }
         }
     }
@@ -288,19 +296,20 @@ impl<AllocU8:Allocator<u8>, AllocCommand:Allocator<StaticCommand>> ThreadToMain<
     ) -> DivansOutputResult {
         loop {
             let _elapsed = unguarded_debug_time!(self);
-            let &(ref lock, ref cvar) = &*self.queue;
-            let mut worker = lock.lock().unwrap();
+            let mut worker = self.queue.lock();
             if worker.result_space_ready() {
                 thread_debug!(ThreadEventType::W_PUSH_CMD, 1, self, _elapsed);
                 if worker.waiters != 0 {
-                    cvar.notify_one();
+                    self.queue.notify_one();
                 }
                 return worker.push_cmd(cmd, m8, recoder, specialization, output, output_offset);
             } else {
+                if let Some(err) = worker.err {
+                    return DivansOutputResult::Failure(err);
+                }
                 thread_debug!(ThreadEventType::W_WAIT_PUSH_CMD, 0, self, _elapsed);
                 worker.waiters += 1;
-                let _ign = cvar.wait(worker);
-                _ign.unwrap().waiters -= 1;
+                self.queue.wait(worker).waiters -= 1;
             }
         }
     }
@@ -312,19 +321,20 @@ impl<AllocU8:Allocator<u8>, AllocCommand:Allocator<StaticCommand>> ThreadToMain<
         let _len = data.len();
         loop {
             let _elapsed = unguarded_debug_time!(self);
-            let &(ref lock, ref cvar) = &*self.queue;
-            let mut worker = lock.lock().unwrap();
+            let mut worker = self.queue.lock();
             if worker.result_space_ready() {
                 if worker.waiters != 0 {
-                    cvar.notify_one();
+                    self.queue.notify_one();
                 }
                 thread_debug!(ThreadEventType::W_PUSH_CONSUMED_DATA, _len, self, _elapsed);
                 return worker.push_consumed_data(data, m8);
             } else {
+                if let Some(err) = worker.err {
+                    return DivansOutputResult::Failure(err);
+                }
                 thread_debug!(ThreadEventType::W_WAIT_PUSH_CONSUMED_DATA, 0, self, _elapsed);
                 worker.waiters += 1;
-                let _ign = cvar.wait(worker);
-                _ign.unwrap().waiters -= 1;
+                self.queue.wait(worker).waiters -= 1;
             }
         }
     }
@@ -333,19 +343,41 @@ impl<AllocU8:Allocator<u8>, AllocCommand:Allocator<StaticCommand>> ThreadToMain<
     ) -> DivansOutputResult {
         loop {
             let _elapsed = unguarded_debug_time!(self);
-            let &(ref lock, ref cvar) = &*self.queue;
-            let mut worker = lock.lock().unwrap();
+            let mut worker = self.queue.lock();
             if worker.result_space_ready() {
                 if worker.waiters != 0 {
-                    cvar.notify_one();
+                    self.queue.notify_one();
                 }
                 thread_debug!(ThreadEventType::W_PUSH_EOF, 1, self, _elapsed);
                 return worker.push_eof();
             } else {
+                if let Some(err) = worker.err {
+                    return DivansOutputResult::Failure(err);
+                }
                 thread_debug!(ThreadEventType::W_WAIT_PUSH_EOF, 1, self, _elapsed);
                 worker.waiters += 1;
-                let _ign = cvar.wait(worker);
-                _ign.unwrap().waiters -=1;
+                self.queue.wait(worker).waiters -= 1;
+            }
+        }
+    }
+   #[inline(always)]
+    fn push_sync(&mut self,
+    ) -> DivansOutputResult {
+        loop {
+            let _elapsed = unguarded_debug_time!(self);
+            let mut worker = self.queue.lock();
+            if worker.result_space_ready() {
+                if worker.waiters != 0 {
+                    self.queue.notify_one();
+                }
+                thread_debug!(ThreadEventType::W_PUSH_SYNC, 1, self, _elapsed);
+                return worker.push_sync();
+            } else if worker.err.is_none() {
+                thread_debug!(ThreadEventType::W_WAIT_PUSH_SYNC, 1, self, _elapsed);
+                worker.waiters += 1;
+                self.queue.wait(worker).waiters -= 1;
+            } else {
+                return worker.push_sync();
             }
         }
     }
@@ -355,8 +387,8 @@ impl<AllocU8:Allocator<u8>, AllocCommand:Allocator<StaticCommand>> ThreadToMain<
     }
 }
 
-pub struct BufferedMultiWorker<AllocU8:Allocator<u8>, AllocCommand:Allocator<StaticCommand>> {
-    pub worker: MultiWorker<AllocU8, AllocCommand>,
+pub struct BufferedMultiWorker<AllocU8:Allocator<u8>, AllocCommand:Allocator<StaticCommand>, Spawner:WorkerSpawner> {
+    pub worker: MultiWorker<AllocU8, AllocCommand, Spawner>,
     buffer: AllocatedMemoryPrefix<StaticCommand, AllocCommand>,
     min_buffer_push_len: usize,
 }
@@ -370,23 +402,22 @@ impl<AllocU8:Allocator<u8>, AllocCommand: Allocator<StaticCommand>> PullAllocate
 }*/
 
 
-impl<AllocU8:Allocator<u8>, AllocCommand:Allocator<StaticCommand>> BufferedMultiWorker<AllocU8, AllocCommand> {
-    pub fn new(mc: &mut AllocCommand)->Self{
-        let worker = MultiWorker::<AllocU8, AllocCommand>::new(mc);
+impl<AllocU8:Allocator<u8>, AllocCommand:Allocator<StaticCommand>, Spawner:WorkerSpawner> BufferedMultiWorker<AllocU8, AllocCommand, Spawner> {
+    pub fn new(mc: &mut AllocCommand, spawner: &Spawner)->Self{
+        let worker = MultiWorker::<AllocU8, AllocCommand, Spawner>::new(mc, spawner);
         Self {
             min_buffer_push_len: 2,
             worker:worker,
             buffer: AllocatedMemoryPrefix::realloc(mc.alloc_cell(NUM_SERIAL_COMMANDS_BUFFERED), 0),
         }
     }
-    fn force_push(&mut self, eof_inside: bool, data: &mut AllocatedMemoryRange<u8, AllocU8>, pm: Option<&mut PredictionModeContextMap<AllocatedMemoryPrefix<u8, AllocU8>>>) -> DivansOpResult {
+    fn force_push(&mut self, hint: CommandResult, data: &mut AllocatedMemoryRange<u8, AllocU8>, pm: Option<&mut PredictionModeContextMap<AllocatedMemoryPrefix<u8, AllocU8>>>) -> DivansOpResult {
         if self.min_buffer_push_len * 2 < self.buffer.max_len(){
             self.min_buffer_push_len <<= 2;
         }
         loop {
             let _elapsed = unguarded_debug_time!(self.worker);
-            let &(ref lock, ref cvar) = &*self.worker.queue;
-            let mut worker = lock.lock().unwrap();
+            let mut worker = self.worker.queue.lock();
             let mut did_notify = false;
             if data.0.len() != 0 { // before we get to sending commands, lets make sure data is taken care of
                 match worker.push_consumed_data(data, None) {
@@ -394,10 +425,12 @@ impl<AllocU8:Allocator<u8>, AllocCommand:Allocator<StaticCommand>> BufferedMulti
                         thread_debug!(ThreadEventType::W_PUSH_CONSUMED_DATA, data.0.len() as u32, self.worker, _elapsed);
                     },
                     DivansOutputResult::NeedsMoreOutput => {
+                        if let Some(err) = worker.err {
+                            return DivansOpResult::Failure(err);
+                        }
                         thread_debug!(ThreadEventType::W_WAIT_PUSH_CONSUMED_DATA, data.0.len(), self.worker, _elapsed);
                         worker.waiters += 1;
-                        let _ign = cvar.wait(worker);
-                        _ign.unwrap().waiters -= 1;
+                        self.worker.queue.wait(worker).waiters -= 1;
                         continue;
                     }
                     DivansOutputResult::Failure(e) => {
@@ -405,17 +438,19 @@ impl<AllocU8:Allocator<u8>, AllocCommand:Allocator<StaticCommand>> BufferedMulti
                     }
                 }
                 if worker.waiters != 0 && !did_notify{
-                    cvar.notify_one();
+                    self.worker.queue.notify_one();
                     did_notify = true;
                 }
             }
             if worker.result_multi_space_ready(self.buffer.1 as usize) {
                 thread_debug!(ThreadEventType::W_PUSH_CMD, self.buffer.1, self.worker, _elapsed);
-                if eof_inside {
-                    worker.set_eof_hint(); // so other side gets more aggressive about pulling
+                match hint {
+                    CommandResult::Eof => worker.set_eof_hint(), // so other side gets more aggressive about pulling
+                    CommandResult::Sync => worker.set_sync_hint(),
+                    _ => {},
                 }
                 if worker.waiters != 0 && !did_notify{
-                    cvar.notify_one();
+                    self.worker.queue.notify_one();
                 }
                 let extant_space = worker.insert_results(&mut self.buffer, pm);
                 if extant_space <= 16 {
@@ -426,8 +461,7 @@ impl<AllocU8:Allocator<u8>, AllocCommand:Allocator<StaticCommand>> BufferedMulti
             } else if worker.err.is_none() {
                 thread_debug!(ThreadEventType::W_WAIT_PUSH_CMD, self.buffer.1, self.worker, _elapsed);
                 worker.waiters += 1;
-                let _ign = cvar.wait(worker);
-                _ign.unwrap().waiters -= 1;
+                self.worker.queue.wait(worker).waiters -= 1;
             } else {
                 return DivansOpResult::Failure(worker.err.unwrap());
             }
@@ -439,7 +473,7 @@ impl<AllocU8:Allocator<u8>, AllocCommand:Allocator<StaticCommand>> BufferedMulti
         self.worker.free(m8, mc);
     }
 }
-impl<AllocU8:Allocator<u8>, AllocCommand:Allocator<StaticCommand>> ThreadToMain<AllocU8> for BufferedMultiWorker<AllocU8, AllocCommand> {
+impl<AllocU8:Allocator<u8>, AllocCommand:Allocator<StaticCommand>, Spawner:WorkerSpawner> ThreadToMain<AllocU8> for BufferedMultiWorker<AllocU8, AllocCommand, Spawner> {
     const COOPERATIVE:bool = false;
     const ISOLATED:bool = true;
     #[inline(always)]
@@ -465,9 +499,9 @@ impl<AllocU8:Allocator<u8>, AllocCommand:Allocator<StaticCommand>> ThreadToMain<
         self.buffer.0.slice_mut()[self.buffer.1 as usize] = static_command;
         self.buffer.1 += 1;
         if pm.is_some() {
-            DivansOutputResult::from(self.force_push(false, &mut AllocatedMemoryRange::<u8, AllocU8>::default(), pm))
+            DivansOutputResult::from(self.force_push(CommandResult::Ok, &mut AllocatedMemoryRange::<u8, AllocU8>::default(), pm))
         } else if self.buffer.1 as usize == self.buffer.0.len() || self.buffer.1 as usize == self.min_buffer_push_len {
-            DivansOutputResult::from(self.force_push(false, &mut AllocatedMemoryRange::<u8, AllocU8>::default(), None))
+            DivansOutputResult::from(self.force_push(CommandResult::Ok, &mut AllocatedMemoryRange::<u8, AllocU8>::default(), None))
         } else {
             //FIXME: why does this case not do anything
             DivansOutputResult::Success
@@ -478,12 +512,17 @@ impl<AllocU8:Allocator<u8>, AllocCommand:Allocator<StaticCommand>> ThreadToMain<
                     data:&mut AllocatedMemoryRange<u8, AllocU8>,
                     _m8: Option<&mut RepurposingAlloc<u8, AllocU8>>,
     ) -> DivansOutputResult {
-        DivansOutputResult::from(self.force_push(false, data, None))
+        DivansOutputResult::from(self.force_push(CommandResult::Ok, data, None))
     }
    #[inline(always)]
     fn push_eof(&mut self,
     ) -> DivansOutputResult {
-        DivansOutputResult::from(self.force_push(true, &mut AllocatedMemoryRange::<u8, AllocU8>::default(), None))
+        DivansOutputResult::from(self.force_push(CommandResult::Eof, &mut AllocatedMemoryRange::<u8, AllocU8>::default(), None))
+    }
+   #[inline(always)]
+    fn push_sync(&mut self,
+    ) -> DivansOutputResult {
+        DivansOutputResult::from(self.force_push(CommandResult::Sync, &mut AllocatedMemoryRange::<u8, AllocU8>::default(), None))
     }
    #[inline(always)]
     fn broadcast_err(&mut self, err: ErrMsg) {
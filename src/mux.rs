@@ -32,6 +32,8 @@ pub struct Mux<AllocU8:Allocator<u8> > {
    bytes_flushed: usize,
    bytes_to_deserialize:BytesToDeserialize,
    eof: StreamState,
+   // a stream buffer could not grow, so deserialized data was dropped
+   out_of_memory: bool,
 }
 
 fn chunk_size(last_flushed:usize, lagging_stream: bool) -> usize {
@@ -91,6 +93,7 @@ impl<AllocU8:Allocator<u8> > Default for Mux<AllocU8> {
             cur_stream: 0,
             cur_stream_bytes_avail: 0,
             eof:StreamState::Running,
+            out_of_memory: false,
             buf:[
                 AllocatedMemoryRange::<u8, AllocU8>::default(),
                 AllocatedMemoryRange::<u8, AllocU8>::default(),
@@ -155,6 +158,9 @@ impl<AllocU8: Allocator<u8> > StreamDemuxer<AllocU8> for Mux<AllocU8>{
             _ => false,
         }
     }
+    fn out_of_memory(&self) -> bool {
+        self.out_of_memory
+    }
     fn free_demux(&mut self, m8: &mut AllocU8) {
         self.free(m8)
     }
@@ -194,6 +200,12 @@ impl<AllocU8:Allocator<u8> > StreamMuxer<AllocU8> for Mux<AllocU8> {
     fn wrote_eof(&self) -> bool {
         self.is_eof()
     }
+    fn sync_flush(&mut self, output:&mut[u8]) -> usize {
+        self.flush_internal(output)
+    }
+    fn wrote_all_data(&self) -> bool {
+        self.is_flushed()
+    }
     fn free_mux(&mut self, m8: &mut AllocU8) {
         self.free(m8);
     }
@@ -211,8 +223,8 @@ impl<AllocU8:Allocator<u8>> Mux<AllocU8> {
       self.buf[usize::from(stream_id)].1.start += count;
    }
     pub fn is_eof(&self) -> bool {
-        for index in 0..NUM_STREAMS as usize {
-            if self.read_cursor(index)  != self.write_cursor(index) {
+        for index in 0..NUM_STREAMS {
+            if self.read_cursor(index) != self.write_cursor(index) {
                 return false;
             }
         }
@@ -221,6 +233,14 @@ impl<AllocU8:Allocator<u8>> Mux<AllocU8> {
             _ => false,
         }
    }
+    pub fn is_flushed(&self) -> bool {
+        for index in 0..NUM_STREAMS {
+            if self.read_cursor(index) != self.write_cursor(index) {
+                return false;
+            }
+        }
+        true
+    }
    fn unchecked_push(buf: &mut[u8], write_cursor: &mut usize, data: &[u8]) {
        buf.split_at_mut(*write_cursor).1.split_at_mut(data.len()).0.clone_from_slice(data);
        *write_cursor += data.len();
@@ -239,7 +259,9 @@ impl<AllocU8:Allocator<u8>> Mux<AllocU8> {
    }
     pub fn push_data(&mut self, stream_id: StreamID, data: &[u8], m8: &mut AllocU8) {
        let (buf, offset) = self.prep_push_for_n_bytes(stream_id, data.len(), m8);
-       Self::unchecked_push(buf.slice_mut(), offset, data)
+       if buf.slice().len() - *offset >= data.len() {
+           Self::unchecked_push(buf.slice_mut(), offset, data)
+       }
    }
    // this pushes data from a source into the stream buffers. This data may later be serialized through serialize()
    // or else consumed through data_avail/consume
@@ -274,7 +296,12 @@ impl<AllocU8:Allocator<u8>> Mux<AllocU8> {
       let log_desired_size = (64 - desired_size.leading_zeros()) + 1;
       // allocate the new data and then copy in the current data
       let mut new_buf = m8.alloc_cell(1 << core::cmp::max(log_desired_size, 9));
-      debug_assert!(new_buf.slice().len() >= *write_cursor - *read_cursor + data_len);
+      if new_buf.slice().len() < MAX_HEADER_SIZE + *write_cursor - *read_cursor + data_len {
+          // the allocator is out of budget: keep the old buffer and let push_data drop the data
+          m8.free_cell(new_buf);
+          self.out_of_memory = true;
+          return (buf, write_cursor)
+      }
       new_buf.slice_mut().split_at_mut(MAX_HEADER_SIZE).1.split_at_mut(*write_cursor - *read_cursor).0.clone_from_slice(
           buf.slice().split_at(*read_cursor).1.split_at(*write_cursor - *read_cursor).0);
       *write_cursor = MAX_HEADER_SIZE + *write_cursor - *read_cursor;
@@ -643,6 +670,12 @@ impl<AllocU8:Allocator<u8> > StreamMuxer<AllocU8> for DevNull<AllocU8> {
     fn wrote_eof(&self) -> bool {
         true
     }
+    fn sync_flush(&mut self, _output:&mut[u8]) -> usize {
+        0
+    }
+    fn wrote_all_data(&self) -> bool {
+        true
+    }
     fn free_mux(&mut self, _m8: &mut AllocU8) {
     }
     fn can_linearize() -> bool {false}
@@ -0,0 +1,258 @@
+// Copyright 2017 Dropbox, Inc
+//
+//   Licensed under the Apache License, Version 2.0 (the "License");
+//   you may not use this file except in compliance with the License.
+//   You may obtain a copy of the License at
+//
+//       http://www.apache.org/licenses/LICENSE-2.0
+//
+//   Unless required by applicable law or agreed to in writing, software
+//   distributed under the License is distributed on an "AS IS" BASIS,
+//   WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//   See the License for the specific language governing permissions and
+//   limitations under the License.
+
+// Multi-threaded compression: the input is cut into frames that are compressed independently
+// on worker threads and written out in order as a seekable stream, so the result decodes with
+// the ordinary decompressor. There is no shared prefix context: each frame starts with an empty
+// window rather than the end of the frame before it, because a frame that copied from its
+// predecessor could no longer be decoded alone through the seek table. A prior snapshot loaded
+// with load_prior_snapshot sets the starting probabilities of every frame but puts no data in
+// the window.
+#![cfg(not(feature="no-stdlib"))]
+use core;
+use std::collections::VecDeque;
+use std::panic;
+use std::sync::Arc;
+use std::thread;
+use std::vec::Vec;
+use alloc::{HeapAlloc, SliceWrapper};
+use ::codec::parse_prior_snapshot;
+use ::interface::{Command, Compressor, DivansCompressorOptions, DivansResult, DivansOutputResult, ErrMsg};
+use ::seekable::{FrameCompressor, SeekTable, MAX_FRAME_SIZE};
+
+const FRAME_OUTPUT_BUFFER_SIZE: usize = 65536;
+
+fn compress_frame(opts: DivansCompressorOptions, prior_snapshot: &[u8], data: Vec<u8>) -> Result<Vec<u8>, ErrMsg> {
+    let mut compressor = FrameCompressor::new(opts);
+    if !prior_snapshot.is_empty() {
+        if let Err(e) = compressor.load_prior_snapshot(prior_snapshot) {
+            compressor.free();
+            return Err(e);
+        }
+    }
+    let mut buffer = vec![0u8; FRAME_OUTPUT_BUFFER_SIZE];
+    let mut compressed = Vec::<u8>::with_capacity(data.len() / 2);
+    let mut input_offset = 0usize;
+    let mut result = Ok(());
+    while input_offset < data.len() {
+        let mut output_offset = 0usize;
+        let ret = compressor.encode(&data[..], &mut input_offset, &mut buffer[..], &mut output_offset);
+        compressed.extend(&buffer[..output_offset]);
+        if let DivansResult::Failure(m) = ret {
+            result = Err(m);
+            break;
+        }
+    }
+    while result.is_ok() {
+        let mut output_offset = 0usize;
+        let ret = compressor.flush(&mut buffer[..], &mut output_offset);
+        compressed.extend(&buffer[..output_offset]);
+        match ret {
+            DivansOutputResult::Success => break,
+            DivansOutputResult::NeedsMoreOutput => {},
+            DivansOutputResult::Failure(m) => result = Err(m),
+        }
+    }
+    compressor.free();
+    result.map(|_| compressed)
+}
+
+struct FrameJob {
+    worker: thread::JoinHandle<Result<Vec<u8>, ErrMsg>>,
+    uncompressed_size: u64,
+}
+
+pub struct DivansParallelCompressor {
+    opts: DivansCompressorOptions,
+    frame_size: usize,
+    num_threads: usize,
+    // loaded into the compressor of every frame; empty when frames start from the default priors
+    prior_snapshot: Arc<Vec<u8>>,
+    chunk: Vec<u8>,
+    jobs: VecDeque<FrameJob>,
+    ready: Vec<u8>,
+    ready_progress: usize,
+    seek_table: SeekTable,
+    serialized_table: Vec<u8>,
+    table_progress: usize,
+}
+
+impl DivansParallelCompressor {
+    // compresses frames of frame_size bytes on opts.num_threads worker threads
+    pub fn new(opts: DivansCompressorOptions, frame_size: usize) -> Self {
+        DivansParallelCompressor {
+            opts,
+            frame_size: core::cmp::max(1, core::cmp::min(frame_size, MAX_FRAME_SIZE)),
+            num_threads: core::cmp::max(1, opts.num_threads as usize),
+            prior_snapshot: Arc::new(Vec::<u8>::new()),
+            chunk: Vec::<u8>::new(),
+            jobs: VecDeque::<FrameJob>::new(),
+            ready: Vec::<u8>::new(),
+            ready_progress: 0,
+            seek_table: SeekTable::default(),
+            serialized_table: Vec::<u8>::new(),
+            table_progress: 0,
+        }
+    }
+    pub fn seek_table(&self) -> &SeekTable {
+        &self.seek_table
+    }
+    // starts every frame from a prior snapshot made by train_prior_snapshot; must be called before
+    // anything is encoded
+    pub fn load_prior_snapshot(&mut self, prior_snapshot: &[u8]) -> Result<(), ErrMsg> {
+        if !self.chunk.is_empty() || !self.jobs.is_empty() || !self.seek_table.frames().is_empty() {
+            return Err(ErrMsg::WrongInternalEncoderState(2));
+        }
+        // checked here so that a bad snapshot is not reported by every frame
+        try!(parse_prior_snapshot::<::DefaultCDF16, HeapAlloc<::DefaultCDF16>>(prior_snapshot));
+        self.prior_snapshot = Arc::new(prior_snapshot.to_vec());
+        Ok(())
+    }
+    fn spawn_frame(&mut self) {
+        let data = core::mem::replace(&mut self.chunk, Vec::<u8>::with_capacity(self.frame_size));
+        let opts = self.opts;
+        let prior_snapshot = self.prior_snapshot.clone();
+        let uncompressed_size = data.len() as u64;
+        self.jobs.push_back(FrameJob{
+            worker: thread::spawn(move || compress_frame(opts, &prior_snapshot[..], data)),
+            uncompressed_size,
+        });
+    }
+    // moves the oldest frame to the ready buffer once the previous one has been written out
+    fn join_frame(&mut self) -> Result<(), ErrMsg> {
+        let job = self.jobs.pop_front().unwrap();
+        let compressed = match job.worker.join() {
+            Ok(compressed) => try!(compressed),
+            Err(panic_payload) => panic::resume_unwind(panic_payload),
+        };
+        self.seek_table.push(compressed.len() as u64, job.uncompressed_size);
+        self.ready = compressed;
+        self.ready_progress = 0;
+        Ok(())
+    }
+    fn drain_ready(&mut self, output: &mut [u8], output_offset: &mut usize) -> DivansOutputResult {
+        let to_copy = core::cmp::min(self.ready.len() - self.ready_progress,
+                                     output.len() - *output_offset);
+        output[*output_offset..*output_offset + to_copy].clone_from_slice(
+            &self.ready[self.ready_progress..self.ready_progress + to_copy]);
+        *output_offset += to_copy;
+        self.ready_progress += to_copy;
+        if self.ready_progress == self.ready.len() {
+            DivansOutputResult::Success
+        } else {
+            DivansOutputResult::NeedsMoreOutput
+        }
+    }
+    // writes out frames until no more than max_jobs remain in flight
+    fn drain_jobs(&mut self, max_jobs: usize, output: &mut [u8], output_offset: &mut usize) -> DivansOutputResult {
+        loop {
+            match self.drain_ready(output, output_offset) {
+                DivansOutputResult::Success => {},
+                need_something => return need_something,
+            }
+            if self.jobs.len() <= max_jobs {
+                return DivansOutputResult::Success;
+            }
+            if let Err(m) = self.join_frame() {
+                return DivansOutputResult::Failure(m);
+            }
+        }
+    }
+}
+
+impl Compressor for DivansParallelCompressor {
+    fn encode(&mut self,
+              input: &[u8],
+              input_offset: &mut usize,
+              output: &mut [u8],
+              output_offset: &mut usize) -> DivansResult {
+        if !self.serialized_table.is_empty() {
+            return DivansResult::Failure(ErrMsg::NotAllowedToEncodeAfterFlush);
+        }
+        loop {
+            // keep one frame per thread in flight, handing back finished ones as output space allows
+            let max_jobs = self.num_threads - 1;
+            match self.drain_jobs(max_jobs, output, output_offset) {
+                DivansOutputResult::Success => {},
+                need_something => return DivansResult::from(need_something),
+            }
+            if *input_offset == input.len() {
+                return DivansResult::NeedsMoreInput;
+            }
+            let to_copy = core::cmp::min(input.len() - *input_offset, self.frame_size - self.chunk.len());
+            self.chunk.extend(&input[*input_offset..*input_offset + to_copy]);
+            *input_offset += to_copy;
+            if self.chunk.len() == self.frame_size {
+                self.spawn_frame();
+            }
+        }
+    }
+    fn encode_commands<SliceType:SliceWrapper<u8>+Default>(&mut self,
+                                                           _input:&[Command<SliceType>],
+                                                           _input_offset : &mut usize,
+                                                           _output :&mut[u8],
+                                                           _output_offset: &mut usize) -> DivansOutputResult {
+        // frames are cut from raw bytes, which a command stream does not provide
+        DivansOutputResult::Failure(ErrMsg::ParallelCompressionNeedsRawInput)
+    }
+    fn sync_flush(&mut self,
+                  output: &mut [u8],
+                  output_offset: &mut usize) -> DivansOutputResult {
+        if !self.serialized_table.is_empty() {
+            return DivansOutputResult::Failure(ErrMsg::NotAllowedToEncodeAfterFlush);
+        }
+        if !self.chunk.is_empty() {
+            self.spawn_frame();
+        }
+        self.drain_jobs(0, output, output_offset)
+    }
+    fn flush(&mut self,
+             output: &mut [u8],
+             output_offset: &mut usize) -> DivansOutputResult {
+        if self.serialized_table.is_empty() {
+            // an empty input still produces a single empty frame so the stream is decodable
+            if !self.chunk.is_empty() || (self.jobs.is_empty() && self.seek_table.frames().is_empty()) {
+                self.spawn_frame();
+            }
+            match self.drain_jobs(0, output, output_offset) {
+                DivansOutputResult::Success => {},
+                need_something => return need_something,
+            }
+            self.serialized_table = match self.seek_table.serialize() {
+                Ok(table) => table,
+                Err(e) => return DivansOutputResult::Failure(e),
+            };
+        }
+        let to_copy = core::cmp::min(self.serialized_table.len() - self.table_progress,
+                                     output.len() - *output_offset);
+        output[*output_offset..*output_offset + to_copy].clone_from_slice(
+            &self.serialized_table[self.table_progress..self.table_progress + to_copy]);
+        *output_offset += to_copy;
+        self.table_progress += to_copy;
+        if self.table_progress == self.serialized_table.len() {
+            DivansOutputResult::Success
+        } else {
+            DivansOutputResult::NeedsMoreOutput
+        }
+    }
+}
+
+impl Drop for DivansParallelCompressor {
+    fn drop(&mut self) {
+        // let abandoned workers finish rather than detaching them
+        for job in self.jobs.drain(..) {
+            let _ = job.worker.join();
+        }
+    }
+}
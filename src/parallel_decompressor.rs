@@ -1,36 +1,37 @@
-#![cfg(not(feature="no-stdlib"))]
 use core;
 use core::hash::Hasher;
 use ::interface;
 use ::interface::{NewWithAllocator};
 use ::DecoderSpecialization;
 use ::codec;
-use std::sync::{Arc, Mutex};
 use divans_decompressor::HeaderParser;
 use super::mux::{Mux,DevNull};
 use codec::decoder::{DecoderResult, DivansDecoderCodec};
-use threading::{ThreadToMainDemuxer, ThreadToMain};
+use threading::{ThreadToMainDemuxer, ThreadToMain, WorkerLock, WorkerSpawner, DefaultSpawner};
 use multithreading::{BufferedMultiWorker, MultiWorker};
 
-use ::interface::{DivansResult, DivansInputResult, ErrMsg};
+use ::interface::{DivansResult, DivansInputResult, DivansOutputResult, ErrMsg};
 use ::ArithmeticEncoderOrDecoder;
-use ::alloc::{Allocator};
-use std::thread;
+use ::alloc::{Allocator, SliceWrapper};
 use super::divans_decompressor::StaticCommand;
 
+// the codec that decodes the commands of a frame on the spawned thread
+pub type WorkerCodec<DefaultDecoder, AllocU8, AllocCDF16, AllocCommand, Spawner> =
+    codec::DivansCodec<DefaultDecoder,
+                       DecoderSpecialization,
+                       ThreadToMainDemuxer<AllocU8, BufferedMultiWorker<AllocU8, AllocCommand, Spawner>>,
+                       DevNull<AllocU8>,
+                       interface::DefaultCDF16,
+                       AllocU8,
+                       AllocCDF16>;
+
 pub struct ParallelDivansProcess<DefaultDecoder: ArithmeticEncoderOrDecoder + NewWithAllocator<AllocU8>,
                                  AllocU8:Allocator<u8>,
                                  AllocCDF16:Allocator<interface::DefaultCDF16>,
-                                 AllocCommand:Allocator<StaticCommand>> {
-    codec: Arc<Mutex<Option<codec::DivansCodec<DefaultDecoder,
-                                         DecoderSpecialization,
-                                         ThreadToMainDemuxer<AllocU8, BufferedMultiWorker<AllocU8, AllocCommand>>,
-                                         DevNull<AllocU8>,
-                                         interface::DefaultCDF16,
-                                         AllocU8,
-                                         AllocCDF16>>>,
-               >,
-    worker: MultiWorker<AllocU8, AllocCommand>,
+                                 AllocCommand:Allocator<StaticCommand>,
+                                 Spawner:WorkerSpawner=DefaultSpawner> {
+    codec: Spawner::Lock<Option<WorkerCodec<DefaultDecoder, AllocU8, AllocCDF16, AllocCommand, Spawner>>>,
+    worker: MultiWorker<AllocU8, AllocCommand, Spawner>,
     literal_decoder: Option<DivansDecoderCodec<interface::DefaultCDF16,
                                                AllocU8,
                                                AllocCDF16,
@@ -39,20 +40,20 @@ pub struct ParallelDivansProcess<DefaultDecoder: ArithmeticEncoderOrDecoder + Ne
                                                Mux<AllocU8>>>,
     bytes_encoded: usize,
     mcommand: AllocCommand,
+    next_header: HeaderParser<AllocU8, AllocCDF16, AllocCommand, Spawner>,
 }
 
 
-impl<DefaultDecoder: ArithmeticEncoderOrDecoder + NewWithAllocator<AllocU8> + interface::BillingCapability + Send + 'static,
-     AllocU8:Allocator<u8> + Send + 'static,
-     AllocCDF16:Allocator<interface::DefaultCDF16> + Send + 'static,
-     AllocCommand:Allocator<StaticCommand> + Send + 'static>
-    ParallelDivansProcess<DefaultDecoder, AllocU8, AllocCDF16, AllocCommand>
-    where <AllocU8 as Allocator<u8>>::AllocatedMemory: core::marker::Send,
-          <AllocCDF16 as Allocator<interface::DefaultCDF16>>::AllocatedMemory: core::marker::Send,
-          <AllocCommand as Allocator<StaticCommand>>::AllocatedMemory: core::marker::Send,
+impl<DefaultDecoder: ArithmeticEncoderOrDecoder + NewWithAllocator<AllocU8> + interface::BillingCapability + 'static,
+     AllocU8:Allocator<u8> + 'static,
+     AllocCDF16:Allocator<interface::DefaultCDF16> + 'static,
+     AllocCommand:Allocator<StaticCommand> + 'static,
+     Spawner:WorkerSpawner + 'static>
+    ParallelDivansProcess<DefaultDecoder, AllocU8, AllocCDF16, AllocCommand, Spawner>
+    where Spawner::Lock<Option<WorkerCodec<DefaultDecoder, AllocU8, AllocCDF16, AllocCommand, Spawner>>>: Send,
 {
 
-    pub fn new(header: &mut HeaderParser<AllocU8, AllocCDF16, AllocCommand>, mut window_size: usize) -> Self {
+    pub fn new(header: &mut HeaderParser<AllocU8, AllocCDF16, AllocCommand, Spawner>, spawner: Spawner, mut window_size: usize) -> Result<Self, ErrMsg> {
         if window_size < 10 {
             window_size = 10;
         }
@@ -67,20 +68,16 @@ impl<DefaultDecoder: ArithmeticEncoderOrDecoder + NewWithAllocator<AllocU8> + in
         m8 = header.m8.take().unwrap();
         raw_header = header.header;
         skip_crc = header.skip_crc;
+        let mut next_header = header.following_frame_header();
+        let prior_snapshot = core::mem::take(&mut header.prior_snapshot);
         mcdf16 = header.mcdf16.take().unwrap();
         mc = header.mcommand.take().unwrap();
         //update this if you change the SelectedArithmeticDecoder macro
-        let cmd_decoder = DefaultDecoder::new(&mut m8);
-        let lit_decoder = DefaultDecoder::new(&mut m8);
-        let linear_input_bytes = ThreadToMainDemuxer::<AllocU8,BufferedMultiWorker<AllocU8, AllocCommand>>::new(
-            BufferedMultiWorker::<AllocU8, AllocCommand>::new(&mut mc));
-        let mut codec = codec::DivansCodec::<DefaultDecoder,
-                                             DecoderSpecialization,
-                                             ThreadToMainDemuxer<AllocU8, BufferedMultiWorker<AllocU8, AllocCommand>>,
-                                             DevNull<AllocU8>,
-                                             interface::DefaultCDF16,
-                                             AllocU8,
-                                             AllocCDF16>::new(m8,
+        let cmd_decoder = DefaultDecoder::new_with_entropy_coder(&mut m8, header.features.entropy_coder);
+        let lit_decoder = DefaultDecoder::new_with_entropy_coder(&mut m8, header.features.entropy_coder);
+        let linear_input_bytes = ThreadToMainDemuxer::<AllocU8,BufferedMultiWorker<AllocU8, AllocCommand, Spawner>>::new(
+            BufferedMultiWorker::<AllocU8, AllocCommand, Spawner>::new(&mut mc, &spawner));
+        let mut codec = WorkerCodec::<DefaultDecoder, AllocU8, AllocCDF16, AllocCommand, Spawner>::new(m8,
                                                               mcdf16,
                                                               cmd_decoder,
                                                               lit_decoder,
@@ -93,16 +90,30 @@ impl<DefaultDecoder: ArithmeticEncoderOrDecoder + NewWithAllocator<AllocU8> + in
                                                           true,
                                                               codec::StrideSelection::UseBrotliRec,
                                                               skip_crc);
+        codec.set_cdf_flavor(header.features.cdf_flavor);
+        codec.set_literal_mixer(header.features.literal_mixer);
+        codec.set_literal_match(header.features.literal_match);
+        codec.set_literal_sse(header.features.literal_sse);
+        codec.set_literal_hash_size(header.features.literal_hash_size);
+        codec.set_sync_points(header.features.sync_points);
+        if header.features.prior_snapshot_id.is_some() {
+            match codec.load_prior_snapshot(prior_snapshot.slice()) {
+                Ok(_) => {},
+                Err(e) => return Err(e),
+            }
+        }
+        next_header.prior_snapshot = prior_snapshot;
         if !skip_crc {
             codec.get_crc().write(&raw_header[..]);
         }
-        let main_thread_codec = codec.fork(&mut mc);
+        let mut main_thread_codec = codec.fork(&mut mc);
         assert_eq!(*codec.get_crc(), main_thread_codec.crc);
+        main_thread_codec.max_literal_bytes = header.max_literal_bytes(window_size);
         let multi_worker = (codec.demuxer().worker).worker.clone();
-        let thread_codec = Arc::new(Mutex::new(Some(codec)));
+        let thread_codec = spawner.new_lock(Some(codec));
         let worker_codec = thread_codec.clone();
-        thread::spawn(move || {
-            let mut guard = thread_codec.lock().unwrap();
+        spawner.spawn(move || {
+            let mut guard = thread_codec.lock();
             if let Some(ref mut process_codec) = *guard {
                 let mut unused_out = 0usize;
                 let mut unused_in = 0usize;
@@ -126,22 +137,51 @@ impl<DefaultDecoder: ArithmeticEncoderOrDecoder + NewWithAllocator<AllocU8> + in
                         },
                         DivansResult::NeedsMoreOutput => {}, // lets make room for more output
                     }
+                    if process_codec.demuxer().worker.worker.err().is_some() {
+                        return; // the main thread failed and will not consume anything further
+                    }
                 }
             } else {
                 panic!("Thread started with None-process_codec")
             }
         });
-        ParallelDivansProcess::<DefaultDecoder, AllocU8, AllocCDF16, AllocCommand> {
+        Ok(ParallelDivansProcess::<DefaultDecoder, AllocU8, AllocCDF16, AllocCommand, Spawner> {
             mcommand:mc,
             codec:worker_codec,
             literal_decoder:Some(main_thread_codec),
             bytes_encoded:0,
             worker: multi_worker,
+            next_header:next_header,
+        })
+    }
+    pub fn next_frame_header(&self) -> HeaderParser<AllocU8, AllocCDF16, AllocCommand, Spawner> {
+        self.next_header.without_allocators(self.next_header.frames_decoded)
+    }
+    pub fn next_header(&self) -> &HeaderParser<AllocU8, AllocCDF16, AllocCommand, Spawner> {
+        &self.next_header
+    }
+    pub fn next_header_mut(&mut self) -> &mut HeaderParser<AllocU8, AllocCDF16, AllocCommand, Spawner> {
+        &mut self.next_header
+    }
+    pub fn commands_decoded(&self) -> u64 {
+        self.literal_decoder.as_ref().map_or(0, |ld| ld.commands_decoded)
+    }
+    pub fn salvage_pending(&mut self, output: &mut [u8], output_offset: &mut usize) -> DivansOutputResult {
+        match self.literal_decoder {
+            Some(ref mut ld) => ld.salvage_pending(output, output_offset),
+            None => DivansOutputResult::Success,
+        }
+    }
+    // None while the worker thread holds the codec
+    pub fn codec_state(&self) -> Option<codec::EncodeOrDecodeState> {
+        match self.codec.try_lock() {
+            Some(guard) => guard.as_ref().map(|codec| codec.state()),
+            None => None,
         }
     }
     pub fn free_ref(&mut self) {
         self.worker.broadcast_err(ErrMsg::UnexpectedEof); // in case we still have a worker holding the lock, not done
-        if let Some(ref mut codec) = *self.codec.lock().unwrap() {
+        if let Some(ref mut codec) = *self.codec.lock() {
             let lit_decoder = core::mem::replace(&mut self.literal_decoder, None);
             if let Some(ld) = lit_decoder {
                 codec.join(ld, &mut self.mcommand);
@@ -154,7 +194,7 @@ impl<DefaultDecoder: ArithmeticEncoderOrDecoder + NewWithAllocator<AllocU8> + in
     pub fn free(mut self) -> (AllocU8, AllocCDF16, AllocCommand) {
         use codec::NUM_ARITHMETIC_CODERS;
         self.worker.broadcast_err(ErrMsg::UnexpectedEof); // in case we still have a worker holding the lock, not done
-        if let Some(mut codec) = core::mem::replace(&mut *self.codec.lock().unwrap(), None) {
+        if let Some(mut codec) = core::mem::replace(&mut *self.codec.lock(), None) {
             let lit_decoder = core::mem::replace(&mut self.literal_decoder, None);
             if let Some(ld) = lit_decoder {
                 codec.join(ld, &mut self.mcommand);
@@ -1,4 +1,4 @@
-use super::interface::{Prob, BaseCDF, Speed, CDF_MAX, CDF16, BLEND_FIXED_POINT_PRECISION};
+use super::interface::{Prob, BaseCDF, Speed, CDF_MAX, CDF16, CDFFlavor, BLEND_FIXED_POINT_PRECISION};
 
 
 #[allow(unused)]
@@ -178,6 +178,23 @@ impl BaseCDF for BlendCDF16 {
 }
 
 impl CDF16 for BlendCDF16 {
+    fn to_words(&self) -> [Prob; 16] {
+        self.cdf
+    }
+    // the mix rate is not recorded, so a loaded distribution adapts as quickly as a fresh one
+    fn from_words(flavor: CDFFlavor, words: &[Prob; 16]) -> Option<Self> {
+        if flavor != CDFFlavor::Blend || words[15] > CDF_MAX - 16 {
+            return None;
+        }
+        let mut prev = 0;
+        for item in words.iter() {
+            if *item < prev {
+                return None;
+            }
+            prev = *item;
+        }
+        Some(BlendCDF16{cdf: *words, ..BlendCDF16::default()})
+    }
     fn average(&self, other: &Self, mix_rate: i32) ->Self {
         let mut retval = *self;
         retval.blend_internal(other.cdf, mix_rate);
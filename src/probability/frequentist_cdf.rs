@@ -1,5 +1,5 @@
 use core;
-use super::interface::{Prob, BaseCDF, Speed, CDF16, BLEND_FIXED_POINT_PRECISION};
+use super::interface::{Prob, BaseCDF, Speed, CDF16, CDFFlavor, BLEND_FIXED_POINT_PRECISION};
 fn to_bit_i32(val: i32, shift_val: u8) -> u32 {
     if val != 0 {
         1 << shift_val
@@ -54,6 +54,23 @@ impl BaseCDF for FrequentistCDF16 {
 }
 
 impl CDF16 for FrequentistCDF16 {
+    fn to_words(&self) -> [Prob; 16] {
+        self.cdf
+    }
+    // counts must be strictly increasing and stay below the largest speed limit so blend cannot overflow
+    fn from_words(flavor: CDFFlavor, words: &[Prob; 16]) -> Option<Self> {
+        if flavor != CDFFlavor::Frequentist || words[15] >= 0x4000 {
+            return None;
+        }
+        let mut prev = 0;
+        for item in words.iter() {
+            if *item <= prev {
+                return None;
+            }
+            prev = *item;
+        }
+        Some(FrequentistCDF16{cdf: *words})
+    }
     #[inline(always)]
     fn average(&self, other:&Self, mix_rate:i32) -> Self {
         let mut retval = *self;
@@ -418,9 +418,45 @@ impl core::str::FromStr for Speed {
     }
 }
 
+// How a CDF adapts to the symbols it sees; recorded in the stream header.
+// The frequentist, opt-frequentist and simd-frequentist CDFs adapt identically, so they share a flavor.
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CDFFlavor {
+    Frequentist = 0,
+    Blend = 1,
+}
+
+impl Default for CDFFlavor {
+    #[cfg(feature="blend")]
+    fn default() -> Self {
+        CDFFlavor::Blend
+    }
+    #[cfg(not(feature="blend"))]
+    fn default() -> Self {
+        CDFFlavor::Frequentist
+    }
+}
+
 pub trait CDF16: Sized + Default + Copy + BaseCDF {
     fn blend(&mut self, symbol: u8, dyn:Speed);
     fn average(&self, other: &Self, mix_rate: i32) ->Self;
+    // the initial distribution for a stream of the given flavor; CDFs with a fixed flavor ignore it
+    fn flavored(_flavor: CDFFlavor) -> Self {
+        Self::default()
+    }
+    // the words a prior snapshot records for this distribution
+    fn to_words(&self) -> [Prob; 16] {
+        let mut words = [0 as Prob; 16];
+        for (index, word) in words.iter_mut().enumerate() {
+            *word = self.cdf(index as u8);
+        }
+        words
+    }
+    // rebuilds a distribution of the given flavor from to_words; None if the words are out of range
+    fn from_words(_flavor: CDFFlavor, _words: &[Prob; 16]) -> Option<Self> {
+        None
+    }
 }
 
 pub const BLEND_FIXED_POINT_PRECISION : i8 = 15;
@@ -454,6 +490,15 @@ pub struct DebugWrapperCDF16<Cdf16: CDF16> {
 
 #[cfg(feature="debug_entropy")]
 impl<Cdf16> CDF16 for DebugWrapperCDF16<Cdf16> where Cdf16: CDF16 {
+    fn flavored(flavor: CDFFlavor) -> Self {
+        Self::new(Cdf16::flavored(flavor))
+    }
+    fn to_words(&self) -> [Prob; 16] {
+        self.cdf.to_words()
+    }
+    fn from_words(flavor: CDFFlavor, words: &[Prob; 16]) -> Option<Self> {
+        Cdf16::from_words(flavor, words).map(Self::new)
+    }
     fn blend(&mut self, symbol: u8, speed: Speed) {
         self.counts[symbol as usize] += 1;
         let p = self.cdf.pdf(symbol) as f64 / self.cdf.max() as f64;
@@ -28,8 +28,9 @@ mod variant_speed_cdf;
 #[cfg(feature="simd")]
 pub mod simd_frequentist_cdf;
 pub mod opt_frequentist_cdf;
+pub mod runtime_cdf;
 
-pub use self::interface::{BaseCDF, CDF16, CDF2, Speed, SpeedPalette, Prob, LOG2_SCALE, BLEND_FIXED_POINT_PRECISION, ProbRange, SPEED_PALETTE_SIZE};
+pub use self::interface::{BaseCDF, CDF16, CDF2, CDFFlavor, Speed, SpeedPalette, Prob, LOG2_SCALE, BLEND_FIXED_POINT_PRECISION, ProbRange, SPEED_PALETTE_SIZE};
 #[cfg(feature="debug_entropy")]
 pub use self::interface::DebugWrapperCDF16;
 pub use self::blend_cdf::{BlendCDF16};
@@ -39,3 +40,4 @@ pub use self::external_cdf::ExternalProbCDF16;
 pub use self::simd_frequentist_cdf::SIMDFrequentistCDF16;
 pub use self::opt_frequentist_cdf::OptFrequentistCDF16;
 pub use self::variant_speed_cdf::VariantSpeedCDF;
+pub use self::runtime_cdf::RuntimeCDF16;
@@ -1,5 +1,5 @@
 use core;
-use super::interface::{Prob, BaseCDF, Speed, CDF16, BLEND_FIXED_POINT_PRECISION, LOG2_SCALE, CDF_BITS};
+use super::interface::{Prob, BaseCDF, Speed, CDF16, CDFFlavor, BLEND_FIXED_POINT_PRECISION, LOG2_SCALE, CDF_BITS};
 use super::frequentist_cdf::FrequentistCDF16;
 use super::numeric;
 fn to_bit_i32(val: i32, shift_val: u8) -> u32 {
@@ -116,6 +116,12 @@ impl CDF16 for OptFrequentistCDF16 {
         self.cdf.blend(symbol, speed);
         self.inv_max_and_bitlen = numeric::lookup_divisor(self.max());
     }
+    fn to_words(&self) -> [Prob; 16] {
+        self.cdf.to_words()
+    }
+    fn from_words(flavor: CDFFlavor, words: &[Prob; 16]) -> Option<Self> {
+        FrequentistCDF16::from_words(flavor, words).map(Self::new)
+    }
 }
 
 #[cfg(test)]
@@ -0,0 +1,150 @@
+use super::interface::{Prob, BaseCDF, Speed, CDF16, CDFFlavor, SymStartFreq};
+use super::blend_cdf::BlendCDF16;
+
+// A CDF whose adaptation rule is picked when the stream header is read rather than when the
+// library is built. Every prior of a codec holds the same variant, so averaging never mixes them.
+#[derive(Clone,Copy)]
+pub enum RuntimeCDF16<FrequentistImpl:CDF16> {
+    Frequentist(FrequentistImpl),
+    Blend(BlendCDF16),
+}
+
+impl<FrequentistImpl:CDF16> Default for RuntimeCDF16<FrequentistImpl> {
+    fn default() -> Self {
+        Self::flavored(CDFFlavor::default())
+    }
+}
+
+impl<FrequentistImpl:CDF16> BaseCDF for RuntimeCDF16<FrequentistImpl> {
+    fn num_symbols() -> u8 { 16 }
+    #[inline(always)]
+    fn cdf(&self, symbol: u8) -> Prob {
+        match *self {
+            RuntimeCDF16::Frequentist(ref cdf) => cdf.cdf(symbol),
+            RuntimeCDF16::Blend(ref cdf) => cdf.cdf(symbol),
+        }
+    }
+    #[inline(always)]
+    fn pdf(&self, symbol: u8) -> Prob {
+        match *self {
+            RuntimeCDF16::Frequentist(ref cdf) => cdf.pdf(symbol),
+            RuntimeCDF16::Blend(ref cdf) => cdf.pdf(symbol),
+        }
+    }
+    #[inline(always)]
+    fn div_by_max(&self, val: i32) -> i32 {
+        match *self {
+            RuntimeCDF16::Frequentist(ref cdf) => cdf.div_by_max(val),
+            RuntimeCDF16::Blend(ref cdf) => cdf.div_by_max(val),
+        }
+    }
+    #[inline(always)]
+    fn max(&self) -> Prob {
+        match *self {
+            RuntimeCDF16::Frequentist(ref cdf) => cdf.max(),
+            RuntimeCDF16::Blend(ref cdf) => cdf.max(),
+        }
+    }
+    fn log_max(&self) -> Option<i8> {
+        match *self {
+            RuntimeCDF16::Frequentist(ref cdf) => cdf.log_max(),
+            RuntimeCDF16::Blend(ref cdf) => cdf.log_max(),
+        }
+    }
+    fn used(&self) -> bool {
+        match *self {
+            RuntimeCDF16::Frequentist(ref cdf) => cdf.used(),
+            RuntimeCDF16::Blend(ref cdf) => cdf.used(),
+        }
+    }
+    fn valid(&self) -> bool {
+        match *self {
+            RuntimeCDF16::Frequentist(ref cdf) => cdf.valid(),
+            RuntimeCDF16::Blend(ref cdf) => cdf.valid(),
+        }
+    }
+    fn entropy(&self) -> f64 {
+        match *self {
+            RuntimeCDF16::Frequentist(ref cdf) => cdf.entropy(),
+            RuntimeCDF16::Blend(ref cdf) => cdf.entropy(),
+        }
+    }
+    #[inline(always)]
+    fn sym_to_start_and_freq(&self, sym: u8) -> SymStartFreq {
+        match *self {
+            RuntimeCDF16::Frequentist(ref cdf) => cdf.sym_to_start_and_freq(sym),
+            RuntimeCDF16::Blend(ref cdf) => cdf.sym_to_start_and_freq(sym),
+        }
+    }
+    #[inline(always)]
+    fn cdf_offset_to_sym_start_and_freq(&self, cdf_offset_p: Prob) -> SymStartFreq {
+        match *self {
+            RuntimeCDF16::Frequentist(ref cdf) => cdf.cdf_offset_to_sym_start_and_freq(cdf_offset_p),
+            RuntimeCDF16::Blend(ref cdf) => cdf.cdf_offset_to_sym_start_and_freq(cdf_offset_p),
+        }
+    }
+}
+
+impl<FrequentistImpl:CDF16> CDF16 for RuntimeCDF16<FrequentistImpl> {
+    #[inline(always)]
+    fn blend(&mut self, symbol: u8, speed: Speed) {
+        match *self {
+            RuntimeCDF16::Frequentist(ref mut cdf) => cdf.blend(symbol, speed),
+            RuntimeCDF16::Blend(ref mut cdf) => cdf.blend(symbol, speed),
+        }
+    }
+    #[inline(always)]
+    fn average(&self, other: &Self, mix_rate: i32) -> Self {
+        match (*self, *other) {
+            (RuntimeCDF16::Frequentist(cdf), RuntimeCDF16::Frequentist(other_cdf)) =>
+                RuntimeCDF16::Frequentist(cdf.average(&other_cdf, mix_rate)),
+            (RuntimeCDF16::Blend(cdf), RuntimeCDF16::Blend(other_cdf)) =>
+                RuntimeCDF16::Blend(cdf.average(&other_cdf, mix_rate)),
+            _ => panic!("Internal Error: averaged CDFs of different flavors"),
+        }
+    }
+    fn flavored(flavor: CDFFlavor) -> Self {
+        match flavor {
+            CDFFlavor::Frequentist => RuntimeCDF16::Frequentist(FrequentistImpl::default()),
+            CDFFlavor::Blend => RuntimeCDF16::Blend(BlendCDF16::default()),
+        }
+    }
+    fn to_words(&self) -> [Prob; 16] {
+        match *self {
+            RuntimeCDF16::Frequentist(ref cdf) => cdf.to_words(),
+            RuntimeCDF16::Blend(ref cdf) => cdf.to_words(),
+        }
+    }
+    fn from_words(flavor: CDFFlavor, words: &[Prob; 16]) -> Option<Self> {
+        match flavor {
+            CDFFlavor::Frequentist => FrequentistImpl::from_words(flavor, words).map(RuntimeCDF16::Frequentist),
+            CDFFlavor::Blend => BlendCDF16::from_words(flavor, words).map(RuntimeCDF16::Blend),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::RuntimeCDF16;
+    use super::super::{BlendCDF16, CDF16, CDFFlavor, FrequentistCDF16, OptFrequentistCDF16, Speed};
+    type RuntimeOptFrequentistCDF16 = RuntimeCDF16<OptFrequentistCDF16>;
+    declare_common_tests!(RuntimeOptFrequentistCDF16);
+
+    #[test]
+    fn test_runtime_matches_flavor() {
+        use super::super::common_tests;
+        common_tests::operation_test_helper(&mut FrequentistCDF16::default(),
+                                            &mut FrequentistCDF16::default(),
+                                            &mut RuntimeCDF16::<FrequentistCDF16>::flavored(CDFFlavor::Frequentist),
+                                            &mut RuntimeCDF16::<FrequentistCDF16>::flavored(CDFFlavor::Frequentist));
+        let mut reference = BlendCDF16::default();
+        let mut runtime = RuntimeCDF16::<FrequentistCDF16>::flavored(CDFFlavor::Blend);
+        for i in 0..100 {
+            reference.blend((i * 7 & 0xf) as u8, Speed::MED);
+            runtime.blend((i * 7 & 0xf) as u8, Speed::MED);
+            common_tests::assert_cdf_eq(&reference, &runtime);
+        }
+        common_tests::assert_cdf_eq(&reference.average(&BlendCDF16::default(), 1 << 13),
+                                    &runtime.average(&RuntimeCDF16::flavored(CDFFlavor::Blend), 1 << 13));
+    }
+}
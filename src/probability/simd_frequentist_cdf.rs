@@ -1,7 +1,8 @@
 use core;
 use core::simd::FromBits;
-use super::interface::{Prob, BaseCDF, Speed, CDF16, BLEND_FIXED_POINT_PRECISION, SymStartFreq, LOG2_SCALE};
+use super::interface::{Prob, BaseCDF, Speed, CDF16, CDFFlavor, BLEND_FIXED_POINT_PRECISION, SymStartFreq, LOG2_SCALE};
 use super::numeric;
+use super::frequentist_cdf::FrequentistCDF16;
 use core::simd;
 use core::simd::{i32x2, f64x2, i16x16, i64x4, i16x8, i8x32, i8x16, u32x8, u8x16, i64x2, i32x8};
 //use stdsimd::vendor::__m256i;
@@ -195,6 +196,9 @@ fn i32x8_tuple_to_i16x16(input0: i32x8, input1: i32x8) -> i16x16 {
 
 
 impl CDF16 for SIMDFrequentistCDF16 {
+    fn from_words(flavor: CDFFlavor, words: &[Prob; 16]) -> Option<Self> {
+        FrequentistCDF16::from_words(flavor, words).map(|cdf| SIMDFrequentistCDF16::new(i16x16::load_unaligned(&cdf.cdf)))
+    }
     #[inline(always)]
     fn average(&self, other:&Self, mix_rate:i32) -> Self {
 
@@ -1,5 +1,5 @@
 use core;
-use super::interface::{Prob, BaseCDF, Speed, CDF16, BLEND_FIXED_POINT_PRECISION, SPEED_PALETTE_SIZE, SymStartFreq};
+use super::interface::{Prob, BaseCDF, Speed, CDF16, CDFFlavor, BLEND_FIXED_POINT_PRECISION, SPEED_PALETTE_SIZE, SymStartFreq};
 use brotli::enc::util::FastLog2u16;
 
 #[derive(Clone,Copy)]
@@ -18,6 +18,21 @@ impl<ChildCDF:BaseCDF+CDF16+Sized+Default> Default for VariantSpeedCDF<ChildCDF>
 }
 
 impl<ChildCDF:BaseCDF+CDF16+Sized+Default> CDF16 for VariantSpeedCDF<ChildCDF> {
+    fn flavored(flavor: CDFFlavor) -> Self {
+        VariantSpeedCDF {
+            cdf:[ChildCDF::flavored(flavor);SPEED_PALETTE_SIZE + 1],
+            cost:[0.0;SPEED_PALETTE_SIZE+1],
+        }
+    }
+    fn to_words(&self) -> [Prob; 16] {
+        self.cdf[0].to_words()
+    }
+    fn from_words(flavor: CDFFlavor, words: &[Prob; 16]) -> Option<Self> {
+        ChildCDF::from_words(flavor, words).map(|cdf| VariantSpeedCDF {
+            cdf:[cdf;SPEED_PALETTE_SIZE + 1],
+            cost:[0.0;SPEED_PALETTE_SIZE+1],
+        })
+    }
     fn blend(&mut self, symbol: u8, dyn:Speed) {
         for (index, (cdf, cost)) in self.cdf.iter_mut().zip(self.cost.iter_mut()).enumerate() {
             let pdf = cdf.pdf(symbol);
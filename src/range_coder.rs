@@ -0,0 +1,375 @@
+// Copyright 2017 Dropbox, Inc
+//
+//   Licensed under the Apache License, Version 2.0 (the "License");
+//   you may not use this file except in compliance with the License.
+//   You may obtain a copy of the License at
+//
+//       http://www.apache.org/licenses/LICENSE-2.0
+//
+//   Unless required by applicable law or agreed to in writing, software
+//   distributed under the License is distributed on an "AS IS" BASIS,
+//   WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//   See the License for the specific language governing permissions and
+//   limitations under the License.
+
+use core;
+use core::cmp;
+use alloc::Allocator;
+use probability::{CDF16, Prob, LOG2_SCALE, ProbRange};
+use super::interface::{
+    ArithmeticEncoderOrDecoder,
+    NewWithAllocator,
+    BillingCapability,
+};
+use super::DivansResult;
+use super::arithmetic_coder::{
+    EntropyEncoder,
+    EntropyDecoder,
+    ByteQueue,
+};
+
+// A carry-less range coder (after Subbotin) over a 64 bit interval.
+// Unlike ANS, every symbol is emitted as soon as its leading bytes settle,
+// so nothing has to be buffered and reversed before it can be written out.
+type RangeState = u64;
+const RANGE_TOP: RangeState = 1 << 56;
+const RANGE_BOTTOM: RangeState = 1 << 48;
+const RANGE_STATE_BYTES: u8 = 8;
+// a single symbol settles at most 8 bytes, so this holds a symbol plus the final flush
+const RANGE_QUEUE_SIZE: usize = 16;
+
+#[derive(Debug, Clone, Copy)]
+struct RangeInterval {
+    low: RangeState,
+    range: RangeState,
+}
+
+impl Default for RangeInterval {
+    fn default() -> Self {
+        RangeInterval {
+            low: 0,
+            range: RangeState::MAX,
+        }
+    }
+}
+
+impl RangeInterval {
+    #[inline(always)]
+    fn narrow(&mut self, start: Prob, freq: Prob) {
+        debug_assert!(start >= 0);
+        debug_assert!(freq > 0);
+        let r = self.range >> LOG2_SCALE;
+        self.low += r * start as RangeState;
+        self.range = r * freq as RangeState;
+    }
+    // Shifts out the top byte of the interval once every value in it shares that byte.
+    // If the interval straddles a byte boundary while too narrow to keep its precision,
+    // it is cut down to the part below the boundary so that the byte settles anyway.
+    #[inline(always)]
+    fn shift_settled_byte(&mut self) -> Option<u8> {
+        if (self.low ^ self.low.wrapping_add(self.range)) >= RANGE_TOP {
+            if self.range >= RANGE_BOTTOM {
+                return None;
+            }
+            self.range = self.low.wrapping_neg() & (RANGE_BOTTOM - 1);
+        }
+        let byte = (self.low >> 56) as u8;
+        self.low <<= 8;
+        self.range <<= 8;
+        Some(byte)
+    }
+}
+
+#[derive(Clone)]
+pub struct RangeByteQueue {
+    data: [u8; RANGE_QUEUE_SIZE],
+    start: u8,
+    end: u8,
+}
+
+impl Default for RangeByteQueue {
+    fn default() -> Self {
+        RangeByteQueue {
+            data: [0; RANGE_QUEUE_SIZE],
+            start: 0,
+            end: 0,
+        }
+    }
+}
+
+impl RangeByteQueue {
+    #[inline(always)]
+    fn push_byte(&mut self, b: u8) {
+        assert!((self.end as usize) < RANGE_QUEUE_SIZE);
+        self.data[self.end as usize] = b;
+        self.end += 1;
+    }
+}
+
+impl ByteQueue for RangeByteQueue {
+    #[inline(always)]
+    fn num_push_bytes_avail(&self) -> usize {
+        RANGE_QUEUE_SIZE - self.end as usize
+    }
+    #[inline(always)]
+    fn num_pop_bytes_avail(&self) -> usize {
+        (self.end - self.start) as usize
+    }
+    fn push_data(&mut self, data:&[u8]) -> usize {
+        let n = cmp::min(data.len(), self.num_push_bytes_avail());
+        for b in data[..n].iter() {
+            self.push_byte(*b);
+        }
+        n
+    }
+    #[inline(always)]
+    fn pop_data(&mut self, data:&mut [u8]) -> usize {
+        let n = cmp::min(data.len(), self.num_pop_bytes_avail());
+        data[..n].clone_from_slice(&self.data[self.start as usize..self.start as usize + n]);
+        self.start += n as u8;
+        if self.start == self.end {
+            self.start = 0;
+            self.end = 0;
+        }
+        n
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct RangeEncoder {
+    interval: RangeInterval,
+    q: RangeByteQueue,
+}
+
+impl<A: Allocator<u8>> NewWithAllocator<A> for RangeEncoder {
+    fn new(_m8: &mut A) -> Self {
+        Self::default()
+    }
+    fn free(&mut self, _m8: &mut A) {
+    }
+}
+
+impl RangeEncoder {
+    fn mov_internal(&mut self) -> Self {
+        core::mem::take(self)
+    }
+    #[inline(always)]
+    fn put_start_freq(&mut self, start: Prob, freq: Prob) {
+        self.interval.narrow(start, freq);
+        while let Some(byte) = self.interval.shift_settled_byte() {
+            self.q.push_byte(byte);
+        }
+    }
+}
+
+impl EntropyEncoder for RangeEncoder {
+    type Queue = RangeByteQueue;
+    #[inline(always)]
+    fn get_internal_buffer_mut(&mut self) -> &mut Self::Queue {
+        &mut self.q
+    }
+    #[inline(always)]
+    fn get_internal_buffer(&self) -> &Self::Queue {
+        &self.q
+    }
+    #[inline(always)]
+    fn put_bit(&mut self, bit: bool, mut prob_of_false: u8) {
+        if prob_of_false == 0 {
+            prob_of_false = 1;
+        }
+        self.put_start_freq(if bit {Prob::from(prob_of_false) << (LOG2_SCALE - 8) } else {0},
+                            if bit {256 - Prob::from(prob_of_false)} else {Prob::from(prob_of_false)} << (LOG2_SCALE - 8))
+    }
+    #[inline(always)]
+    fn put_nibble<CDF:CDF16>(&mut self, symbol: u8, cdf:&CDF) -> ProbRange {
+        let start_freq = cdf.sym_to_start_and_freq(symbol).range;
+        self.put_start_freq(start_freq.start, start_freq.freq);
+        start_freq
+    }
+    fn flush(&mut self) {
+        // the low end of the interval identifies it, so writing it out in full ends the stream
+        let low = self.interval.low;
+        for index in 0..RANGE_STATE_BYTES {
+            self.q.push_byte((low >> (56 - 8 * index)) as u8);
+        }
+        self.interval = RangeInterval::default();
+    }
+}
+
+impl ArithmeticEncoderOrDecoder for RangeEncoder {
+    arithmetic_encoder_or_decoder_methods!();
+}
+
+#[derive(Debug, Clone)]
+pub struct RangeDecoder {
+    interval: RangeInterval,
+    code: RangeState,
+    bytes_required: u8,
+}
+
+impl Default for RangeDecoder {
+    fn default() -> Self {
+        RangeDecoder {
+            interval: RangeInterval::default(),
+            code: 0,
+            bytes_required: RANGE_STATE_BYTES,
+        }
+    }
+}
+
+impl<A: Allocator<u8>> NewWithAllocator<A> for RangeDecoder {
+    fn new(_m8: &mut A) -> Self {
+        Self::default()
+    }
+    fn free(&mut self, _m8: &mut A) {
+    }
+}
+
+impl RangeDecoder {
+    #[inline(always)]
+    fn helper_get_cdf_value_of_sym(&self) -> Prob {
+        debug_assert!(self.bytes_required == 0);
+        let r = self.interval.range >> LOG2_SCALE;
+        // only a corrupt stream can point past the end of the scale
+        cmp::min(self.code.wrapping_sub(self.interval.low) / r,
+                 (1 << LOG2_SCALE) - 1) as Prob
+    }
+    #[inline(always)]
+    fn helper_advance_sym(&mut self, start: Prob, freq: Prob) {
+        self.interval.narrow(start, freq);
+        // the bytes shifted out here are filled into the low end of code as they arrive
+        while self.interval.shift_settled_byte().is_some() {
+            self.code <<= 8;
+            self.bytes_required += 1;
+        }
+    }
+}
+
+impl ByteQueue for RangeDecoder {
+    #[inline(always)]
+    fn num_push_bytes_avail(&self) -> usize {
+        self.bytes_required as usize
+    }
+    #[inline(always)]
+    fn num_pop_bytes_avail(&self) -> usize {
+        0
+    }
+    #[inline(always)]
+    fn push_data(&mut self, data:&[u8]) -> usize {
+        let n = cmp::min(data.len(), self.bytes_required as usize);
+        for b in data[..n].iter() {
+            self.bytes_required -= 1;
+            if self.bytes_required < RANGE_STATE_BYTES {
+                self.code |= RangeState::from(*b) << (8 * self.bytes_required);
+            }
+        }
+        n
+    }
+    fn pop_data(&mut self, _data:&mut [u8]) -> usize {
+        unreachable!("the range decoder only consumes bytes")
+    }
+}
+
+impl EntropyDecoder for RangeDecoder {
+    type Queue = Self;
+    #[inline(always)]
+    fn get_internal_buffer_mut(&mut self) -> &mut Self::Queue {
+        self
+    }
+    #[inline(always)]
+    fn get_internal_buffer(&self) -> &Self::Queue {
+        self
+    }
+    #[inline(always)]
+    fn get_nibble<CDF:CDF16>(&mut self, cdf:&CDF) -> (u8, ProbRange) {
+        let cdf_offset = self.helper_get_cdf_value_of_sym();
+        let sym_start_freq = cdf.cdf_offset_to_sym_start_and_freq(cdf_offset);
+        self.helper_advance_sym(sym_start_freq.range.start,
+                                sym_start_freq.range.freq);
+        (sym_start_freq.sym, sym_start_freq.range)
+    }
+    #[inline(always)]
+    fn get_bit(&mut self, mut prob_of_false: u8) -> bool {
+        if prob_of_false == 0 {
+            prob_of_false = 1;
+        }
+        let cdf_offset = self.helper_get_cdf_value_of_sym();
+        let rescaled_prob_of_false = Prob::from(prob_of_false) << (LOG2_SCALE - 8);
+        let inv_rescaled_prob_of_false = (256 - Prob::from(prob_of_false)) << (LOG2_SCALE - 8);
+        let bit = cdf_offset >= rescaled_prob_of_false;
+        self.helper_advance_sym(if bit {rescaled_prob_of_false} else {0},
+                                if bit {inv_rescaled_prob_of_false} else {rescaled_prob_of_false});
+        bit
+    }
+    fn flush(&mut self) -> DivansResult {
+        DivansResult::Success
+    }
+    fn resync(&mut self) {
+        // bytes the interval already shifted out are still in the stream ahead of the encoder's
+        // flushed low end, so they are skipped before the next interval is loaded
+        let pending = self.bytes_required;
+        *self = RangeDecoder::default();
+        self.bytes_required += pending;
+    }
+}
+
+impl BillingCapability for RangeDecoder {
+}
+
+#[cfg(test)]
+mod test {
+    use super::{RangeEncoder, RangeDecoder};
+    use super::super::arithmetic_coder::{ByteQueue, EntropyEncoder, EntropyDecoder};
+    use probability::{CDF16, FrequentistCDF16, Speed};
+
+    fn drain(e: &mut RangeEncoder, out: &mut [u8], out_offset: &mut usize) {
+        *out_offset += e.get_internal_buffer_mut().pop_data(&mut out[*out_offset..]);
+        assert_eq!(e.get_internal_buffer().num_pop_bytes_avail(), 0);
+    }
+    fn fill(d: &mut RangeDecoder, input: &[u8], in_offset: &mut usize) {
+        // feed a byte at a time to exercise partial refills
+        while d.get_internal_buffer().num_push_bytes_avail() != 0 {
+            *in_offset += d.get_internal_buffer_mut().push_data(&input[*in_offset..*in_offset + 1]);
+        }
+    }
+
+    #[test]
+    fn test_range_coder_roundtrip() {
+        let mut out = [0u8; 8192];
+        let mut out_offset = 0usize;
+        let mut e = RangeEncoder::default();
+        let mut cdf = FrequentistCDF16::default();
+        let mut state = 0x12345u32;
+        for i in 0..4096 {
+            state = state.wrapping_mul(1103515245).wrapping_add(12345);
+            let prob = (state >> 24) as u8;
+            // mostly predictable bits with an occasional surprise
+            let bit = (i % 17 == 0) != (prob > 128);
+            e.put_bit(bit, prob);
+            drain(&mut e, &mut out, &mut out_offset);
+            let nibble = ((state >> 8) as u8 & 0x3) | ((i % 31 == 0) as u8) << 3;
+            e.put_nibble(nibble, &cdf);
+            cdf.blend(nibble, Speed::MED);
+            drain(&mut e, &mut out, &mut out_offset);
+        }
+        e.flush();
+        drain(&mut e, &mut out, &mut out_offset);
+
+        let mut in_offset = 0usize;
+        let mut d = RangeDecoder::default();
+        let mut cdf = FrequentistCDF16::default();
+        let mut state = 0x12345u32;
+        for i in 0..4096 {
+            state = state.wrapping_mul(1103515245).wrapping_add(12345);
+            let prob = (state >> 24) as u8;
+            fill(&mut d, &out[..out_offset], &mut in_offset);
+            assert_eq!(d.get_bit(prob), (i % 17 == 0) != (prob > 128));
+            fill(&mut d, &out[..out_offset], &mut in_offset);
+            let nibble = ((state >> 8) as u8 & 0x3) | ((i % 31 == 0) as u8) << 3;
+            assert_eq!(d.get_nibble(&cdf).0, nibble);
+            cdf.blend(nibble, Speed::MED);
+        }
+        fill(&mut d, &out[..out_offset], &mut in_offset);
+        assert_eq!(in_offset, out_offset);
+    }
+}
@@ -12,21 +12,449 @@
 //   See the License for the specific language governing permissions and
 //   limitations under the License.
 
+// LZ77 match finder over the ring buffer of the command assembler.
+// Positions are absolute stream offsets; the tables keep their low 32 bits and every candidate
+// is checked against the window and compared byte by byte, so stale entries are harmless.
 use core;
-pub use alloc::{AllocatedStackMemory, Allocator, SliceWrapper, SliceWrapperMut, StackAllocator};
+pub use alloc::{AllocatedStackMemory, SliceWrapper, SliceWrapperMut, StackAllocator};
 
+pub const MIN_MATCH_LENGTH: usize = 4;
+const HASH_MUL: u32 = 0x1e35_a7bd;
+const MAX_HASH_BITS: u32 = 17;
+// the binary tree only sorts suffixes by this many bytes
+const MAX_TREE_COMPARE_LENGTH: usize = 128;
+// brotli leaves the last 16 bytes of the window unused
+const WINDOW_GAP: usize = 16;
+// scores in the style of brotli's backward reference scores: 135 per byte, 30 per distance bit
+const LITERAL_BYTE_SCORE: u64 = 135;
+const DISTANCE_BIT_PENALTY: u64 = 30;
+const SCORE_BASE: u64 = DISTANCE_BIT_PENALTY * 8 * 8;
+const MIN_SCORE: u64 = SCORE_BASE + 300;
+const LAST_DISTANCE_BONUS: u64 = 15;
+pub const LAZY_MATCH_COST: u64 = 175;
 
-pub struct HashMatch<AllocU32:Allocator<u32> > {
-    ht: AllocU32::AllocatedMemory,
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MatchFinderMode {
+    HashChain,
+    BinaryTree,
 }
-impl<AllocU32:Allocator<u32> > HashMatch<AllocU32> {
-    pub fn new(m32: &mut AllocU32) -> Self {
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Match {
+    pub length: u32,
+    pub distance: u32,
+    pub score: u64,
+}
+
+impl Match {
+    pub fn is_match(&self) -> bool {
+        self.length as usize >= MIN_MATCH_LENGTH
+    }
+}
+
+pub fn backward_reference_score(length: usize, distance: usize) -> u64 {
+    let distance_bits = u64::from(31 - (distance as u32).leading_zeros());
+    (SCORE_BASE + LITERAL_BYTE_SCORE * length as u64).saturating_sub(DISTANCE_BIT_PENALTY * distance_bits)
+}
+
+fn last_distance_score(length: usize) -> u64 {
+    SCORE_BASE + LITERAL_BYTE_SCORE * length as u64 + LAST_DISTANCE_BONUS
+}
+
+#[inline(always)]
+pub fn match_length(ring: &[u8], ring_mask: usize, prev: usize, cur: usize, max_len: usize) -> usize {
+    let mut len = 0;
+    while len < max_len && ring[(prev + len) & ring_mask] == ring[(cur + len) & ring_mask] {
+        len += 1;
+    }
+    len
+}
+
+// The tables only need indexed u32s, so a caller with nothing but a byte allocator can pack them.
+pub trait PositionTable: Default {
+    fn len(&self) -> usize;
+    fn get(&self, index: usize) -> u32;
+    fn set(&mut self, index: usize, val: u32);
+}
+
+impl<T: SliceWrapperMut<u32> + Default> PositionTable for T {
+    #[inline(always)]
+    fn len(&self) -> usize {
+        self.slice().len()
+    }
+    #[inline(always)]
+    fn get(&self, index: usize) -> u32 {
+        self.slice()[index]
+    }
+    #[inline(always)]
+    fn set(&mut self, index: usize, val: u32) {
+        self.slice_mut()[index] = val;
+    }
+}
+
+pub struct HashMatch<Table:PositionTable> {
+    head: Table,
+    // one previous position per window slot for hash chains, two children per slot for the tree
+    links: Table,
+    hash_bits: u32,
+    window_mask: usize,
+    max_distance: usize,
+    mode: MatchFinderMode,
+    max_depth: u32,
+    pub lazy: bool,
+    // candidates visited plus bytes compared by every search so far
+    #[cfg(test)]
+    probes: usize,
+}
+
+impl<Table:PositionTable> HashMatch<Table> {
+    pub fn new(window_size: u8, quality: u16) -> Self {
+        let (mode, max_depth, lazy) = match quality {
+            0 | 1 => (MatchFinderMode::HashChain, 1, false),
+            _ if quality <= 4 => (MatchFinderMode::HashChain, 1 << quality, false),
+            _ if quality <= 9 => (MatchFinderMode::HashChain, 16 << (quality - 5), true),
+            10 => (MatchFinderMode::BinaryTree, 64, true),
+            _ => (MatchFinderMode::BinaryTree, 128, true),
+        };
         HashMatch {
-          ht:m32.alloc_cell(128),
+            head: Table::default(),
+            links: Table::default(),
+            hash_bits: core::cmp::min(MAX_HASH_BITS, u32::from(window_size)),
+            window_mask: (1 << window_size) - 1,
+            max_distance: (1 << window_size) - WINDOW_GAP,
+            mode: mode,
+            max_depth: max_depth,
+            lazy: lazy,
+            #[cfg(test)]
+            probes: 0,
+        }
+    }
+    // a hash chain searched max_depth deep, for callers that want every match rather than the best one
+    pub fn new_hash_chain(window_size: u8, max_depth: u32) -> Self {
+        let mut ret = Self::new(window_size, 0);
+        ret.max_depth = max_depth;
+        ret
+    }
+    // the tables are only allocated once raw input arrives, since IR input never searches
+    pub fn ensure_allocated<Alloc:FnMut(usize) -> Table>(&mut self, mut alloc: Alloc) {
+        if self.head.len() == 0 {
+            let links_per_slot = match self.mode {
+                MatchFinderMode::HashChain => 1,
+                MatchFinderMode::BinaryTree => 2,
+            };
+            self.head = alloc(1 << self.hash_bits);
+            self.links = alloc((self.window_mask + 1) * links_per_slot);
+        }
+    }
+    #[inline(always)]
+    fn hash(&self, ring: &[u8], ring_mask: usize, pos: usize) -> usize {
+        let val = u32::from(ring[pos & ring_mask]) | (u32::from(ring[(pos + 1) & ring_mask]) << 8)
+            | (u32::from(ring[(pos + 2) & ring_mask]) << 16) | (u32::from(ring[(pos + 3) & ring_mask]) << 24);
+        (val.wrapping_mul(HASH_MUL) >> (32 - self.hash_bits)) as usize
+    }
+    #[inline(always)]
+    fn backward(&self, cur: usize, candidate: u32) -> Option<usize> {
+        let backward = (cur as u32).wrapping_sub(candidate) as usize;
+        if backward == 0 || backward > self.max_distance || backward > cur {
+            None
+        } else {
+            Some(backward)
+        }
+    }
+    #[cfg(test)]
+    fn count_probe(&mut self, bytes_compared: usize) {
+        self.probes += 1 + bytes_compared;
+    }
+    #[cfg(not(test))]
+    #[inline(always)]
+    fn count_probe(&mut self, _bytes_compared: usize) {}
+    // a child that no later position accepts as a candidate
+    fn null_link(&self, cur: usize) -> u32 {
+        (cur as u32).wrapping_sub(self.max_distance as u32 + 1)
+    }
+    fn search_hash_chain(&mut self, ring: &[u8], ring_mask: usize, cur: usize, max_len: usize,
+                         best: &mut Match) {
+        let key = self.hash(ring, ring_mask, cur);
+        let mut candidate = self.head.get(key);
+        self.head.set(key, cur as u32);
+        self.links.set(cur & self.window_mask, candidate);
+        let mut last_backward = 0;
+        for _ in 0..self.max_depth {
+            let backward = match self.backward(cur, candidate) {
+                Some(backward) if backward > last_backward => backward,
+                _ => break,
+            };
+            let prev = cur - backward;
+            let best_len = best.length as usize;
+            if best_len < max_len && ring[(prev + best_len) & ring_mask] == ring[(cur + best_len) & ring_mask] {
+                let len = match_length(ring, ring_mask, prev, cur, max_len);
+                self.count_probe(len);
+                let score = backward_reference_score(len, backward);
+                if len >= MIN_MATCH_LENGTH && score > best.score {
+                    *best = Match{length: len as u32, distance: backward as u32, score: score};
+                    if len == max_len {
+                        break;
+                    }
+                }
+            } else {
+                self.count_probe(0);
+            }
+            last_backward = backward;
+            candidate = self.links.get(prev & self.window_mask);
         }
     }
-    pub fn free(&mut self, m32: &mut AllocU32) {
-       m32.free_cell(core::mem::replace(&mut self.ht, AllocU32::AllocatedMemory::default()));
+    // inserts cur into the tree of its hash bucket, re-rooting it at cur, and reports the
+    // best match seen on the way down
+    fn search_binary_tree(&mut self, ring: &[u8], ring_mask: usize, cur: usize, max_len: usize,
+                          best: &mut Match) {
+        let max_compare = core::cmp::min(max_len, MAX_TREE_COMPARE_LENGTH);
+        let key = self.hash(ring, ring_mask, cur);
+        let mut candidate = self.head.get(key);
+        self.head.set(key, cur as u32);
+        let null_link = self.null_link(cur);
+        let mut left_slot = 2 * (cur & self.window_mask);
+        let mut right_slot = left_slot + 1;
+        let mut best_len_left = 0;
+        let mut best_len_right = 0;
+        let mut depth = self.max_depth;
+        let mut tree_match = Match::default();
+        loop {
+            let backward = match self.backward(cur, candidate) {
+                Some(backward) if depth != 0 => backward,
+                _ => {
+                    self.links.set(left_slot, null_link);
+                    self.links.set(right_slot, null_link);
+                    break;
+                },
+            };
+            let prev = cur - backward;
+            let prev_slot = 2 * (prev & self.window_mask);
+            let known = core::cmp::min(best_len_left, best_len_right);
+            let len = known + match_length(ring, ring_mask, prev + known, cur + known, max_compare - known);
+            self.count_probe(len - known);
+            let score = backward_reference_score(len, backward);
+            if len >= MIN_MATCH_LENGTH && score > tree_match.score {
+                tree_match = Match{length: len as u32, distance: backward as u32, score: score};
+            }
+            if len == max_compare {
+                // cur replaces prev in the tree
+                let prev_left = self.links.get(prev_slot);
+                let prev_right = self.links.get(prev_slot + 1);
+                self.links.set(left_slot, prev_left);
+                self.links.set(right_slot, prev_right);
+                break;
+            }
+            if ring[(prev + len) & ring_mask] < ring[(cur + len) & ring_mask] {
+                self.links.set(left_slot, candidate);
+                best_len_left = len;
+                left_slot = prev_slot + 1;
+                candidate = self.links.get(left_slot);
+            } else {
+                self.links.set(right_slot, candidate);
+                best_len_right = len;
+                right_slot = prev_slot;
+                candidate = self.links.get(right_slot);
+            }
+            depth -= 1;
+        }
+        if tree_match.is_match() {
+            let len = tree_match.length as usize;
+            if len == max_compare && max_len > max_compare {
+                let prev = cur - tree_match.distance as usize;
+                let extended = len + match_length(ring, ring_mask, prev + len, cur + len, max_len - len);
+                self.count_probe(extended - len);
+                tree_match.length = extended as u32;
+                tree_match.score = backward_reference_score(extended, tree_match.distance as usize);
+            }
+            if tree_match.score > best.score {
+                *best = tree_match;
+            }
+        }
+    }
+    // finds the best match at cur, which may run for at most max_len bytes, and adds cur to the tables
+    pub fn find_match(&mut self, ring: &[u8], ring_mask: usize, cur: usize, max_len: usize,
+                      last_distance: usize) -> Match {
+        let mut best = Match{length: 0, distance: 0, score: MIN_SCORE};
+        if max_len < MIN_MATCH_LENGTH {
+            return Match::default();
+        }
+        if last_distance != 0 && last_distance <= cur && last_distance <= self.max_distance {
+            let len = match_length(ring, ring_mask, cur - last_distance, cur, max_len);
+            self.count_probe(len);
+            if len >= MIN_MATCH_LENGTH {
+                best = Match{length: len as u32, distance: last_distance as u32, score: last_distance_score(len)};
+            }
+        }
+        match self.mode {
+            MatchFinderMode::HashChain => self.search_hash_chain(ring, ring_mask, cur, max_len, &mut best),
+            MatchFinderMode::BinaryTree => self.search_binary_tree(ring, ring_mask, cur, max_len, &mut best),
+        }
+        if best.is_match() {
+            best
+        } else {
+            Match::default()
+        }
+    }
+    // reports each match at cur that is longer than every match nearer to it, at most matches.len()
+    // of them, and adds cur to the tables; only hash chains keep the candidates in distance order
+    pub fn find_matches(&mut self, ring: &[u8], ring_mask: usize, cur: usize, max_len: usize,
+                        matches: &mut [Match]) -> usize {
+        assert_eq!(self.mode, MatchFinderMode::HashChain);
+        if max_len < MIN_MATCH_LENGTH {
+            return 0;
+        }
+        let key = self.hash(ring, ring_mask, cur);
+        let mut candidate = self.head.get(key);
+        self.head.set(key, cur as u32);
+        self.links.set(cur & self.window_mask, candidate);
+        let mut num_matches = 0;
+        let mut best_len = MIN_MATCH_LENGTH - 1;
+        let mut last_backward = 0;
+        for _ in 0..self.max_depth {
+            if num_matches == matches.len() {
+                break;
+            }
+            let backward = match self.backward(cur, candidate) {
+                Some(backward) if backward > last_backward => backward,
+                _ => break,
+            };
+            let prev = cur - backward;
+            if ring[(prev + best_len) & ring_mask] == ring[(cur + best_len) & ring_mask] {
+                let len = match_length(ring, ring_mask, prev, cur, max_len);
+                self.count_probe(len);
+                if len > best_len {
+                    matches[num_matches] = Match{length: len as u32, distance: backward as u32,
+                                                 score: backward_reference_score(len, backward)};
+                    num_matches += 1;
+                    best_len = len;
+                    if len == max_len {
+                        break;
+                    }
+                }
+            } else {
+                self.count_probe(0);
+            }
+            last_backward = backward;
+            candidate = self.links.get(prev & self.window_mask);
+        }
+        num_matches
+    }
+    // adds positions covered by a copy without looking for matches there
+    pub fn insert_range(&mut self, ring: &[u8], ring_mask: usize, start: usize, end: usize, lookahead_end: usize) {
+        let mut start = start;
+        if self.mode == MatchFinderMode::BinaryTree && end > start + 2 * MAX_TREE_COMPARE_LENGTH {
+            // re-rooting the tree at every position of a long copy is slow and rarely pays off
+            start = end - MAX_TREE_COMPARE_LENGTH;
+        }
+        for pos in start..end {
+            let max_len = lookahead_end - pos;
+            if max_len < MIN_MATCH_LENGTH {
+                break;
+            }
+            match self.mode {
+                MatchFinderMode::HashChain => {
+                    let key = self.hash(ring, ring_mask, pos);
+                    let candidate = self.head.get(key);
+                    self.head.set(key, pos as u32);
+                    self.links.set(pos & self.window_mask, candidate);
+                },
+                MatchFinderMode::BinaryTree => {
+                    // the match is discarded, so it need not be extended past what the tree compares
+                    let mut unused = Match::default();
+                    self.search_binary_tree(ring, ring_mask, pos, core::cmp::min(max_len, MAX_TREE_COMPARE_LENGTH),
+                                            &mut unused);
+                },
+            }
+        }
+    }
+    pub fn free<Free:FnMut(Table)>(&mut self, mut free: Free) {
+        free(core::mem::replace(&mut self.head, Table::default()));
+        free(core::mem::replace(&mut self.links, Table::default()));
     }
 }
 
+#[cfg(test)]
+mod test {
+    use alloc::{Allocator, HeapAlloc};
+    use super::{HashMatch, Match, MatchFinderMode, MIN_MATCH_LENGTH};
+    fn find_all_tst(quality: u16, data: &[u8]) -> usize {
+        find_all_window_tst(10, quality, data, data.len()).0
+    }
+    // like find_all_tst, with copies no longer than max_copy as the command generator limits them;
+    // also returns the probes the match finder made
+    fn find_all_window_tst(window_size: u8, quality: u16, data: &[u8], max_copy: usize) -> (usize, usize) {
+        let mut m32 = HeapAlloc::<u32>::new(0);
+        let mut matcher = HashMatch::<<HeapAlloc<u32> as Allocator<u32>>::AllocatedMemory>::new(window_size, quality);
+        matcher.ensure_allocated(|len| m32.alloc_cell(len));
+        let mut ring = vec![0u8; 2 << window_size];
+        ring[..data.len()].clone_from_slice(data);
+        let mut pos = 0;
+        let mut matched = 0;
+        while pos < data.len() {
+            let m = matcher.find_match(&ring[..], ring.len() - 1, pos, core::cmp::min(max_copy, data.len() - pos), 0);
+            if m.is_match() {
+                assert!(m.distance as usize <= pos);
+                for i in 0..m.length as usize {
+                    assert_eq!(data[pos + i], data[pos + i - m.distance as usize]);
+                }
+                matcher.insert_range(&ring[..], ring.len() - 1, pos + 1, pos + m.length as usize, data.len());
+                pos += m.length as usize;
+                matched += m.length as usize;
+            } else {
+                pos += 1;
+            }
+        }
+        let probes = matcher.probes;
+        matcher.free(|table| m32.free_cell(table));
+        (matched, probes)
+    }
+    #[test]
+    fn test_hash_chain_and_binary_tree_find_matches() {
+        let data = include_bytes!("../../testdata/alice29");
+        for &(quality, mode) in [(1, MatchFinderMode::HashChain), (9, MatchFinderMode::HashChain),
+                                 (11, MatchFinderMode::BinaryTree)].iter() {
+            assert_eq!(HashMatch::<<HeapAlloc<u32> as Allocator<u32>>::AllocatedMemory>::new(10, quality).mode, mode);
+            let matched = find_all_tst(quality, &data[..1024]);
+            assert!(matched > 256);
+        }
+    }
+    #[test]
+    fn test_find_matches_grow_with_distance() {
+        let data = b"abcdefXabcdeYabcdZabcdefg";
+        let mut m32 = HeapAlloc::<u32>::new(0);
+        let mut matcher = HashMatch::<<HeapAlloc<u32> as Allocator<u32>>::AllocatedMemory>::new_hash_chain(10, 32);
+        matcher.ensure_allocated(|len| m32.alloc_cell(len));
+        let cur = data.len() - 7;
+        matcher.insert_range(&data[..], !0, 0, cur, data.len());
+        let mut matches = [Match::default(); 4];
+        let num_matches = matcher.find_matches(&data[..], !0, cur, 7, &mut matches[..]);
+        assert_eq!(num_matches, 3);
+        for (m, &(length, distance)) in matches.iter().zip([(4, 5), (5, 11), (6, 18)].iter()) {
+            assert_eq!((m.length, m.distance), (length, distance));
+        }
+        matcher.free(|table| m32.free_cell(table));
+    }
+    #[test]
+    fn test_runs_and_short_inputs() {
+        let run = [7u8; 1000];
+        for &quality in [2, 11].iter() {
+            assert_eq!(find_all_tst(quality, &run[..]), run.len() - 1);
+            assert_eq!(find_all_tst(quality, &run[..MIN_MATCH_LENGTH]), 0);
+        }
+    }
+    #[test]
+    fn test_long_run_is_linear() {
+        // each position a copy inserts into the tree compares a bounded number of bytes, however
+        // far the run continues past the copy, so the probes grow with the length of the run
+        let run = vec![0u8; 1 << 20];
+        for quality in [9u16, 11].iter() {
+            let (matched, short_probes) = find_all_window_tst(20, *quality, &run[..1 << 16], 4096);
+            assert_eq!(matched, (1 << 16) - 1);
+            let (matched, long_probes) = find_all_window_tst(20, *quality, &run[..], 4096);
+            assert_eq!(matched, run.len() - 1);
+            // sixteen times the run takes at most 5% more than sixteen times the probes
+            assert!(long_probes * 20 <= short_probes * 16 * 21, "{} {}", short_probes, long_probes);
+            assert!(long_probes <= 8 * run.len(), "{}", long_probes);
+        }
+    }
+}
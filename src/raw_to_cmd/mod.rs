@@ -13,46 +13,70 @@
 //   limitations under the License.
 
 use core;
-mod hash_match;
-use self::hash_match::HashMatch;
+pub mod hash_match;
+use self::hash_match::{HashMatch, Match, LAZY_MATCH_COST};
 use brotli::InputReference;
 pub use alloc::{AllocatedStackMemory, Allocator, SliceWrapper, SliceWrapperMut, StackAllocator};
 pub use super::slice_util::SliceReference;
 pub use interface::{DivansResult, DivansOutputResult};
 pub use super::interface::{PredictionModeContextMap, Command, Compressor, LiteralCommand, CopyCommand, DictCommand, FeatureFlagSliceType};
+
+// the parser stays this far behind the input so that copies are not cut short
+const MAX_COPY_LENGTH: usize = 4096;
+
+// the ring buffer holds a window of history followed by up to a window of unparsed input
 pub struct RawToCmdState<RingBuffer: SliceWrapperMut<u8> + SliceWrapper<u8>,
     AllocU32:Allocator<u32>>{
     pub ring_buffer: RingBuffer,
-    ring_buffer_decode_index: u32,
-    ring_buffer_output_index: u32,
-    hash_match: HashMatch<AllocU32>,
+    // absolute stream offsets
+    input_pos: usize,
+    parse_pos: usize,
+    literal_start: usize,
+    // the match at parse_pos when the lazy matcher already searched there
+    pending_match: Option<Match>,
+    last_distance: usize,
+    hash_match: HashMatch<AllocU32::AllocatedMemory>,
     pub has_produced_header: bool,
 }
 
+fn literal_command<'a>(ring: &'a [u8], start: usize, end: usize) -> Command<InputReference<'a>> {
+    let index = start & (ring.len() - 1);
+    Command::Literal(LiteralCommand::<InputReference<'a>>{
+        data: InputReference{
+            data: &ring[index..index + end - start],
+            orig_offset: index,
+        },
+        prob: FeatureFlagSliceType::<InputReference>::default(),
+        high_entropy: false,
+    })
+}
+
 impl<RingBuffer: SliceWrapperMut<u8> + SliceWrapper<u8>, AllocU32:Allocator<u32>> RawToCmdState<RingBuffer, AllocU32> {
-    pub fn new(m32:&mut AllocU32, rb:RingBuffer) -> Self {
+    // rb must be twice the window size
+    pub fn new(rb:RingBuffer, quality: u16) -> Self {
+        let window_size = (rb.slice().len() >> 1).trailing_zeros() as u8;
         RawToCmdState {
             ring_buffer: rb,
-            ring_buffer_decode_index: 0,
-            ring_buffer_output_index: 0,
-            hash_match:HashMatch::<AllocU32>::new(m32),
+            input_pos: 0,
+            parse_pos: 0,
+            literal_start: 0,
+            pending_match: None,
+            last_distance: 0,
+            hash_match:HashMatch::<AllocU32::AllocatedMemory>::new(window_size, quality),
             has_produced_header: false, // only produce header if no ir_translation
         }
     }
     pub fn raw_input_ir_mode(&mut self) {
         self.has_produced_header = true; // do not wish an additional prediction mode command at the end
     }
-    /*
-    fn freeze_dry<SliceType:SliceWrapper<u8>+Default>(&mut self, input:&[Command<SliceType>]) {
-        
+    fn window(&self) -> usize {
+        self.ring_buffer.slice().len() >> 1
     }
-    fn thaw<SliceType:SliceWrapper<u8>+Default>(&mut self, input:&[Command<SliceType>]) {
-        
-    }*/
-    pub fn ring_buffer_full(&self) -> bool {
-        self.ring_buffer_decode_index as usize == self.ring_buffer.slice().len() || self.ring_buffer_decode_index + 1 == self.ring_buffer_output_index
+    fn max_copy_length(window: usize) -> usize {
+        core::cmp::min(MAX_COPY_LENGTH, window >> 2)
     }
     pub fn stream<'a>(&'a mut self,
+                      m32: &mut AllocU32,
                       input:&[u8],
                       input_offset:&mut usize,
                       output: &mut [Command<InputReference<'a>>],
@@ -60,55 +84,48 @@ impl<RingBuffer: SliceWrapperMut<u8> + SliceWrapper<u8>, AllocU32:Allocator<u32>
                       literal_context_map: &'a mut[u8],
                       prediction_mode_backing:&'a mut[u8],
     ) -> DivansResult {
-        if self.ring_buffer_decode_index >= self.ring_buffer_output_index {
-            let max_copy = core::cmp::min(self.ring_buffer.slice().len() - self.ring_buffer_decode_index as usize,
-                                          input.len() - *input_offset);
-            self.ring_buffer.slice_mut()[(self.ring_buffer_decode_index as usize)..(self.ring_buffer_decode_index as usize + max_copy)].clone_from_slice(&input[*input_offset..(*input_offset + max_copy)]);
-            *input_offset += max_copy;
-            self.ring_buffer_decode_index += max_copy as u32;
-            if self.ring_buffer_output_index != 0 {
-               self.ring_buffer_decode_index = 0;
-            }
+        let window = self.window();
+        let ring_len = self.ring_buffer.slice().len();
+        while *input_offset < input.len() && self.input_pos - self.literal_start < window {
+            let start = self.input_pos & (ring_len - 1);
+            let count = core::cmp::min(core::cmp::min(ring_len - start, window - (self.input_pos - self.literal_start)),
+                                       input.len() - *input_offset);
+            self.ring_buffer.slice_mut()[start..start + count].clone_from_slice(&input[*input_offset..*input_offset + count]);
+            *input_offset += count;
+            self.input_pos += count;
         }
-        if self.ring_buffer_decode_index < self.ring_buffer_output_index {
-           let max_copy = core::cmp::min(self.ring_buffer_output_index as usize - 1 - self.ring_buffer_decode_index as usize,
-                                         input.len() - *input_offset);
-           debug_assert!(self.ring_buffer_output_index <= self.ring_buffer.slice().len() as u32);
-           debug_assert!(self.ring_buffer_decode_index + max_copy  as u32 <= self.ring_buffer.slice().len() as u32);
-           debug_assert!(*input_offset as u32 + max_copy as u32 <= input.len() as u32);
-           self.ring_buffer.slice_mut()[(self.ring_buffer_decode_index as usize)..(self.ring_buffer_decode_index as usize + max_copy)].clone_from_slice(&input[*input_offset..(*input_offset + max_copy)]);
-            *input_offset += max_copy;
-            self.ring_buffer_decode_index += max_copy as u32;
+        if *output_offset == output.len() {
+            return DivansResult::NeedsMoreOutput;
+        }
+        if self.input_pos - self.literal_start < window {
+            // wait for a full window of lookahead before parsing
+            assert_eq!(*input_offset, input.len());
+            return DivansResult::NeedsMoreInput;
         }
-        if *output_offset < output.len() && self.ring_buffer_full() {
-            match self.flush(output, output_offset, literal_context_map, prediction_mode_backing) {
-                DivansOutputResult::NeedsMoreOutput => {
-                  return DivansResult::NeedsMoreOutput;
+        self.hash_match.ensure_allocated(|len| m32.alloc_cell(len));
+        let parse_end = self.input_pos - Self::max_copy_length(window);
+        match self.parse(parse_end, false, output, output_offset, literal_context_map, prediction_mode_backing) {
+            DivansOutputResult::NeedsMoreOutput => DivansResult::NeedsMoreOutput,
+            DivansOutputResult::Failure(m) => DivansResult::Failure(m),
+            DivansOutputResult::Success => {
+                if *input_offset != input.len() {
+                    // not really true: we may be able to consume more input, but our
+                    // ring buffer is borrowed
+                    DivansResult::NeedsMoreOutput
+                } else {
+                    DivansResult::NeedsMoreInput
                 }
-                DivansOutputResult::Failure(m) => {
-                    return DivansResult::Failure(m);
-                },
-                _ => {
-                    if *input_offset != input.len() {
-                        // not really true: we may be able to consume more input, but ourr
-                        // ring buffer is borrowed
-                        return DivansResult::NeedsMoreOutput;
-                    }
-                },
-            }
-        } else if *output_offset == output.len() {
-            return DivansResult::NeedsMoreOutput;
+            },
         }
-        assert_eq!(*input_offset, input.len());
-        DivansResult::NeedsMoreInput
     }
-    pub fn flush<'a>(
-              &'a mut self,
-              output: &mut [Command<InputReference<'a>>],
-              output_offset:&mut usize,
-              literal_context_map: &'a mut[u8],
-              prediction_mode_backing:&'a mut[u8]) -> DivansOutputResult {
-
+    // produces commands for the input up to parse_end, which copies may run past
+    fn parse<'a>(&'a mut self,
+                 parse_end: usize,
+                 is_last: bool,
+                 output: &mut [Command<InputReference<'a>>],
+                 output_offset:&mut usize,
+                 literal_context_map: &'a mut[u8],
+                 prediction_mode_backing:&'a mut[u8]) -> DivansOutputResult {
         if *output_offset == output.len() {
            return DivansOutputResult::NeedsMoreOutput;
         }
@@ -135,51 +152,83 @@ impl<RingBuffer: SliceWrapperMut<u8> + SliceWrapper<u8>, AllocU32:Allocator<u32>
                     },
                     });
             *output_offset += 1;
-            if *output_offset == output.len() {
+        }
+        let window = self.window();
+        let max_copy_length = Self::max_copy_length(window);
+        let max_literal_length = window >> 2;
+        let ring = self.ring_buffer.slice();
+        let ring_mask = ring.len() - 1;
+        while self.parse_pos < parse_end {
+            // room for a literal and a copy
+            if output.len() - *output_offset < 2 {
                 return DivansOutputResult::NeedsMoreOutput;
             }
+            let cur = self.parse_pos;
+            let mut found = match self.pending_match.take() {
+                Some(found) => found,
+                None => self.hash_match.find_match(ring, ring_mask, cur, core::cmp::min(max_copy_length, self.input_pos - cur),
+                                                   self.last_distance),
+            };
+            let mut inserted_end = cur + 1;
+            if found.is_match() && self.hash_match.lazy && cur + 1 < parse_end {
+                let next = self.hash_match.find_match(ring, ring_mask, cur + 1,
+                                                      core::cmp::min(max_copy_length, self.input_pos - cur - 1),
+                                                      self.last_distance);
+                inserted_end = cur + 2;
+                if next.score > found.score + LAZY_MATCH_COST {
+                    self.pending_match = Some(next);
+                    found = Match::default();
+                }
+            }
+            if !found.is_match() {
+                self.parse_pos += 1;
+                // literals never wrap around the ring buffer
+                if self.parse_pos - self.literal_start == max_literal_length || self.parse_pos & ring_mask == 0 {
+                    output[*output_offset] = literal_command(ring, self.literal_start, self.parse_pos);
+                    *output_offset += 1;
+                    self.literal_start = self.parse_pos;
+                }
+                continue;
+            }
+            let copy_end = cur + found.length as usize;
+            self.hash_match.insert_range(ring, ring_mask, inserted_end, copy_end, self.input_pos);
+            if self.literal_start != cur {
+                output[*output_offset] = literal_command(ring, self.literal_start, cur);
+                *output_offset += 1;
+            }
+            output[*output_offset] = Command::Copy(CopyCommand{
+                distance: found.distance,
+                num_bytes: found.length,
+            });
+            *output_offset += 1;
+            self.last_distance = found.distance as usize;
+            self.parse_pos = copy_end;
+            self.literal_start = copy_end;
         }
-        if self.ring_buffer_decode_index < self.ring_buffer_output_index {
-           let max_copy = self.ring_buffer.slice().len() - self.ring_buffer_output_index as usize;
-           if max_copy != 0 {
-               output[*output_offset] = Command::Literal(
-                   LiteralCommand::<InputReference<'a> >{
-                       data: InputReference{
-                           data:self.ring_buffer.slice().split_at(self.ring_buffer_output_index as usize).1.split_at(max_copy).0,
-                           orig_offset:self.ring_buffer_output_index as usize,
-                       },
-                       prob: FeatureFlagSliceType::<InputReference>::default(),
-                       high_entropy: false,
-                   });
-               *output_offset += 1;
-           }
-           if self.ring_buffer_decode_index as usize == self.ring_buffer.slice().len() {
-               self.ring_buffer_decode_index = 0;
-           }
-           self.ring_buffer_output_index = 0
-        }
-        if self.ring_buffer_decode_index != self.ring_buffer_output_index {
-           if *output_offset == output.len() {
-               return DivansOutputResult::NeedsMoreOutput;
-           }
-           let max_copy = self.ring_buffer_decode_index as usize - self.ring_buffer_output_index as usize;
-           output[*output_offset] = Command::Literal(
-               LiteralCommand::<InputReference<'a>>{
-                   data: InputReference{
-                       data:self.ring_buffer.slice().split_at(self.ring_buffer_output_index as usize).1.split_at(max_copy).0,
-                       orig_offset:self.ring_buffer_output_index as usize,
-                   },
-                   prob: FeatureFlagSliceType::<InputReference>::default(),
-                   high_entropy: false,
-               });
-           *output_offset += 1;
-           assert!(self.ring_buffer_output_index <= self.ring_buffer.slice().len() as u32);
-           self.ring_buffer_output_index = self.ring_buffer_decode_index;
-           assert!(self.ring_buffer_output_index <= self.ring_buffer.slice().len() as u32);
+        if is_last && self.literal_start != self.parse_pos {
+            if *output_offset == output.len() {
+                return DivansOutputResult::NeedsMoreOutput;
+            }
+            output[*output_offset] = literal_command(ring, self.literal_start, self.parse_pos);
+            *output_offset += 1;
+            self.literal_start = self.parse_pos;
         }
         DivansOutputResult::Success
     }
+    pub fn flush<'a>(
+              &'a mut self,
+              m32: &mut AllocU32,
+              output: &mut [Command<InputReference<'a>>],
+              output_offset:&mut usize,
+              literal_context_map: &'a mut[u8],
+              prediction_mode_backing:&'a mut[u8]) -> DivansOutputResult {
+        if self.input_pos != self.literal_start {
+            self.hash_match.ensure_allocated(|len| m32.alloc_cell(len));
+        }
+        let parse_end = self.input_pos;
+        self.parse(parse_end, true, output, output_offset, literal_context_map, prediction_mode_backing)
+    }
     pub fn free(&mut self, m32: &mut AllocU32) {
-        self.hash_match.free(m32);
+        self.hash_match.free(|table| m32.free_cell(table));
     }
 }
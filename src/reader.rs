@@ -4,28 +4,34 @@ pub use alloc::HeapAlloc;
 use core;
 use std::error;
 use std::io;
-use std::io::{Read};
-use super::interface::{DivansResult, DivansOutputResult, ErrMsg};
+use std::io::{Read, Seek, SeekFrom};
+use std::vec::Vec;
+use super::interface::{DivansResult, DivansOutputResult, DivansError, ErrMsg};
 use ::interface::{Compressor, DivansCompressorFactory, Decompressor};
 use ::DivansDecompressorFactory;
 use ::brotli;
 use ::interface;
 use ::StaticCommand;
 use ::brotli::interface::Nop;
-impl core::fmt::Display for ErrMsg {
-    fn fmt(&self, f:&mut core::fmt::Formatter) -> core::result::Result<(), core::fmt::Error> {
-        <Self as core::fmt::Debug>::fmt(self, f)
-    }
-}
+use ::seekable::SeekTable;
 impl error::Error for ErrMsg {
     fn description(&self) -> &str {
         "Divans error"
     }
     fn cause(&self) -> Option<&error::Error> {None}
 }
+impl error::Error for DivansError {
+    fn description(&self) -> &str {
+        "Divans error"
+    }
+    fn cause(&self) -> Option<&error::Error> {Some(&self.msg)}
+}
 trait Processor {
    fn process(&mut self, input:&[u8], input_offset:&mut usize, output:&mut [u8], output_offset:&mut usize) -> DivansResult;
    fn close(&mut self, output:&mut [u8], output_offset:&mut usize) -> DivansOutputResult;
+   fn error(&self, msg: ErrMsg) -> io::Error {
+       io::Error::new(io::ErrorKind::InvalidData, msg)
+   }
 }
 
 struct GenReader<R: Read,
@@ -94,12 +100,16 @@ impl<R:Read, P:Processor, BufferType:SliceWrapperMut<u8>> Read for GenReader<R,P
            }
            match ret {
                DivansResult::Failure(m) => {
-                   return Err(io::Error::new(io::ErrorKind::InvalidData, m));
+                   return Err(self.compressor.error(m));
                },
              DivansResult::Success => {
                if self.input_eof && avail_in == 0 && self.has_flushed {
                  break;
                }
+               // a finished stream takes no more input; anything after it is left unread
+               if self.input_offset == old_input_offset && output_offset == old_output_offset {
+                 break;
+               }
              },
              DivansResult::NeedsMoreInput | DivansResult::NeedsMoreOutput => {},
            }
@@ -211,6 +221,45 @@ impl<R:Read> DivansBrotliHybridCompressorReader<R> {
                           true,
                        ))
     }
+    // starts the stream from a prior snapshot made by train_prior_snapshot; call before the first read
+    pub fn load_prior_snapshot(&mut self, prior_snapshot: &[u8]) -> Result<(), ErrMsg> {
+        self.0.compressor.load_prior_snapshot(prior_snapshot)
+    }
+    // the priors adapted over everything compressed so far, serialized as a prior snapshot
+    pub fn prior_snapshot(&self) -> Result<Vec<u8>, ErrMsg> {
+        let mut snapshot = Vec::<u8>::new();
+        match self.0.compressor.write_prior_snapshot(&mut |data: &[u8]| snapshot.extend_from_slice(data)) {
+            Ok(_) => Ok(snapshot),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+// Compresses each sample of a training corpus in turn, starting every sample from the priors the
+// samples before it left behind, and returns the priors after the last one as a prior snapshot.
+// Streams compressed and decompressed with the snapshot loaded skip the cost of learning them again.
+pub fn train_prior_snapshot(corpus: &[&[u8]], opts: interface::DivansCompressorOptions) -> io::Result<Vec<u8>> {
+    let no_samples: [&[u8]; 1] = [&[]];
+    let samples = if corpus.is_empty() {
+        &no_samples[..]
+    } else {
+        corpus
+    };
+    let mut snapshot = Vec::<u8>::new();
+    for sample in samples.iter() {
+        let mut compressor = DivansBrotliHybridCompressorReader::new(*sample, opts, 0);
+        if !snapshot.is_empty() {
+            if let Err(e) = compressor.load_prior_snapshot(&snapshot[..]) {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, e));
+            }
+        }
+        try!(io::copy(&mut compressor, &mut io::sink()));
+        snapshot = match compressor.prior_snapshot() {
+            Ok(snapshot) => snapshot,
+            Err(e) => return Err(io::Error::new(io::ErrorKind::Other, e)),
+        };
+    }
+    Ok(snapshot)
 }
 
 
@@ -250,19 +299,85 @@ impl<R:Read> DivansExperimentalCompressorReader<R> {
                           true,
                        ))
     }
+    // starts the stream from a prior snapshot made by train_prior_snapshot; call before the first read
+    pub fn load_prior_snapshot(&mut self, prior_snapshot: &[u8]) -> Result<(), ErrMsg> {
+        self.0.compressor.load_prior_snapshot(prior_snapshot)
+    }
+}
+
+pub struct DivansFramedCompressorReader<R:Read>(GenReader<R,
+                                                          ::DivansFramedCompressor,
+                                                          <HeapAlloc<u8> as Allocator<u8>>::AllocatedMemory,
+                                                          >);
+impl<R:Read> Read for DivansFramedCompressorReader<R> {
+	fn read(&mut self, buf: &mut [u8]) -> Result<usize, io::Error> {
+        self.0.read(buf)
+    }
+}
+impl<R:Read> DivansFramedCompressorReader<R> {
+    pub fn new(reader: R, opts: interface::DivansCompressorOptions, frame_size: usize, mut buffer_size: usize) -> Self {
+       if buffer_size == 0 {
+          buffer_size = 4096;
+       }
+       let mut m8 = HeapAlloc::<u8>::new(0);
+       let buffer = m8.alloc_cell(buffer_size);
+       DivansFramedCompressorReader::<R>(
+           GenReader::<R,
+                       ::DivansFramedCompressor,
+                       <HeapAlloc<u8> as Allocator<u8>>::AllocatedMemory>::new(
+                          reader,
+                          ::DivansFramedCompressor::new(opts, frame_size),
+                          buffer,
+                          true,
+                       ))
+    }
+}
+
+pub struct DivansParallelCompressorReader<R:Read>(GenReader<R,
+                                                          ::DivansParallelCompressor,
+                                                          <HeapAlloc<u8> as Allocator<u8>>::AllocatedMemory,
+                                                          >);
+impl<R:Read> Read for DivansParallelCompressorReader<R> {
+	fn read(&mut self, buf: &mut [u8]) -> Result<usize, io::Error> {
+        self.0.read(buf)
+    }
+}
+impl<R:Read> DivansParallelCompressorReader<R> {
+    pub fn new(reader: R, opts: interface::DivansCompressorOptions, frame_size: usize, mut buffer_size: usize) -> Self {
+       if buffer_size == 0 {
+          buffer_size = 4096;
+       }
+       let mut m8 = HeapAlloc::<u8>::new(0);
+       let buffer = m8.alloc_cell(buffer_size);
+       DivansParallelCompressorReader::<R>(
+           GenReader::<R,
+                       ::DivansParallelCompressor,
+                       <HeapAlloc<u8> as Allocator<u8>>::AllocatedMemory>::new(
+                          reader,
+                          ::DivansParallelCompressor::new(opts, frame_size),
+                          buffer,
+                          true,
+                       ))
+    }
+    // starts every frame from a prior snapshot made by train_prior_snapshot; call before the first read
+    pub fn load_prior_snapshot(&mut self, prior_snapshot: &[u8]) -> Result<(), ErrMsg> {
+        self.0.compressor.load_prior_snapshot(prior_snapshot)
+    }
 }
 
 
-type StandardDivansDecompressorFactory = ::DivansDecompressorFactoryStruct<HeapAlloc<u8>,
-                                                                           HeapAlloc<::DefaultCDF16>,
-                                                                           HeapAlloc<StaticCommand>>;
-type DivansConstructedDecompressor = ::DivansDecompressor<<StandardDivansDecompressorFactory as ::DivansDecompressorFactory<HeapAlloc<u8>,
-                                                                                                                            HeapAlloc<::DefaultCDF16>,
-                                                                                                                            HeapAlloc<StaticCommand>>
+// the decoder allocators of a reader draw on one budget of max_memory_estimate bytes
+type LimitedHeapAlloc<T> = ::alloc_util::LimitedAlloc<T, HeapAlloc<T>>;
+type StandardDivansDecompressorFactory = ::DivansDecompressorFactoryStruct<LimitedHeapAlloc<u8>,
+                                                                           LimitedHeapAlloc<::DefaultCDF16>,
+                                                                           LimitedHeapAlloc<StaticCommand>>;
+type DivansConstructedDecompressor = ::DivansDecompressor<<StandardDivansDecompressorFactory as ::DivansDecompressorFactory<LimitedHeapAlloc<u8>,
+                                                                                                                            LimitedHeapAlloc<::DefaultCDF16>,
+                                                                                                                            LimitedHeapAlloc<StaticCommand>>
                                                            >::DefaultDecoder,
-                                                          HeapAlloc<u8>,
-                                                          HeapAlloc<::DefaultCDF16>,
-                                                          HeapAlloc<StaticCommand>>;
+                                                          LimitedHeapAlloc<u8>,
+                                                          LimitedHeapAlloc<::DefaultCDF16>,
+                                                          LimitedHeapAlloc<StaticCommand>>;
 impl Processor for DivansConstructedDecompressor {
    fn process(&mut self, input:&[u8], input_offset:&mut usize, output:&mut [u8], output_offset:&mut usize) -> DivansResult {
        self.decode(input, input_offset, output, output_offset)
@@ -278,39 +393,326 @@ impl Processor for DivansConstructedDecompressor {
        DivansResult::Success => DivansOutputResult::Success,
        }
    }
-
+   fn error(&self, msg: ErrMsg) -> io::Error {
+       io::Error::new(io::ErrorKind::InvalidData, self.locate_error(msg))
+   }
+}
+pub struct DivansDecompressorReader<R:Read> {
+    reader: GenReader<R,
+                      DivansConstructedDecompressor,
+                      <HeapAlloc<u8> as Allocator<u8>>::AllocatedMemory,
+                      >,
+    skip_crc: bool,
+    multithread: bool,
+    limits: interface::DivansDecompressorLimits,
+    // uncompressed position and the bytes still to be discarded to reach it after a seek
+    position: u64,
+    discard: u64,
+    seek_table: Option<(SeekTable, u64)>,
+    // loaded again into the decompressor that replaces the current one on a seek
+    prior_snapshot: Option<Vec<u8>>,
 }
-pub struct DivansDecompressorReader<R:Read>(GenReader<R,
-                                                      DivansConstructedDecompressor,
-                                                      <HeapAlloc<u8> as Allocator<u8>>::AllocatedMemory,
-                                                      >);
 impl<R:Read> Read for DivansDecompressorReader<R> {
 	fn read(&mut self, buf: &mut [u8]) -> Result<usize, io::Error> {
-        self.0.read(buf)
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        if let Some((ref seek_table, _)) = self.seek_table {
+            if self.position >= seek_table.uncompressed_len() {
+                return Ok(0);
+            }
+        }
+        while self.discard != 0 {
+            let to_read = core::cmp::min(self.discard, buf.len() as u64) as usize;
+            let size = try!(self.reader.read(&mut buf[..to_read]));
+            if size == 0 {
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof,
+                                          self.reader.compressor.locate_error(ErrMsg::UnexpectedEof)));
+            }
+            self.discard -= size as u64;
+        }
+        let size = try!(self.reader.read(buf));
+        self.position += size as u64;
+        Ok(size)
     }
 }
+fn new_decompressor(skip_crc: bool, multithread: bool, limits: interface::DivansDecompressorLimits) -> DivansConstructedDecompressor {
+    let budget = ::alloc_util::MemoryBudget::new(limits.max_memory_estimate.unwrap_or(usize::MAX));
+    StandardDivansDecompressorFactory::new_with_limits(
+        LimitedHeapAlloc::<u8>::new(HeapAlloc::<u8>::new(0), budget.clone()),
+        LimitedHeapAlloc::<::DefaultCDF16>::new(HeapAlloc::<::DefaultCDF16>::new(::DefaultCDF16::default()), budget.clone()),
+        LimitedHeapAlloc::<StaticCommand>::new(HeapAlloc::<StaticCommand>::new(::StaticCommand::nop()), budget),
+        skip_crc,
+        multithread,
+        limits,
+    )
+}
 impl<R:Read> DivansDecompressorReader<R> {
-    pub fn new(reader: R, mut buffer_size: usize, skip_crc:bool, multithread:bool) -> Self {
+    pub fn new(reader: R, buffer_size: usize, skip_crc:bool, multithread:bool) -> Self {
+        Self::new_with_limits(reader, buffer_size, skip_crc, multithread, interface::DivansDecompressorLimits::default())
+    }
+    // like new, but the stream is rejected once decoding it would exceed limits
+    pub fn new_with_limits(reader: R, mut buffer_size: usize, skip_crc:bool, multithread:bool,
+                           limits: interface::DivansDecompressorLimits) -> Self {
        if buffer_size == 0 {
           buffer_size = 4096;
        }
        let mut m8 = HeapAlloc::<u8>::new(0);
        let buffer = m8.alloc_cell(buffer_size);
-       DivansDecompressorReader::<R>(
-           GenReader::<R,
-                       DivansConstructedDecompressor,
-                       <HeapAlloc<u8> as Allocator<u8>>::AllocatedMemory>::new(
-                          reader,
-                          StandardDivansDecompressorFactory::new(
-                              m8,
-                              HeapAlloc::<::DefaultCDF16>::new(::DefaultCDF16::default()),
-                              HeapAlloc::<StaticCommand>::new(::StaticCommand::nop()),
-                              skip_crc,
-                              multithread,
-                          ),
-                          buffer,
-                          false,
-                       ))
+       DivansDecompressorReader::<R>{
+           reader:GenReader::<R,
+                              DivansConstructedDecompressor,
+                              <HeapAlloc<u8> as Allocator<u8>>::AllocatedMemory>::new(
+                                  reader,
+                                  new_decompressor(skip_crc, multithread, limits),
+                                  buffer,
+                                  false,
+                              ),
+           skip_crc:skip_crc,
+           multithread:multithread,
+           limits,
+           position:0,
+           discard:0,
+           seek_table:None,
+           prior_snapshot:None,
+       }
+    }
+    // lets the reader decode streams compressed from this prior snapshot; call before the first read
+    pub fn load_prior_snapshot(&mut self, prior_snapshot: &[u8]) -> Result<(), ErrMsg> {
+        match self.reader.compressor.load_prior_snapshot(prior_snapshot) {
+            Ok(()) => {
+                self.prior_snapshot = Some(prior_snapshot.to_vec());
+                Ok(())
+            },
+            Err(e) => Err(e),
+        }
+    }
+}
+fn read_retrying<R:Read>(reader: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    loop {
+        match reader.read(buf) {
+            Err(e) => if e.kind() != io::ErrorKind::Interrupted {
+                return Err(e);
+            },
+            ret => return ret,
+        }
+    }
+}
+// decodes a whole stream, checksum included, discarding the output as it is produced.
+// Returns the decoded length, or where a corrupt or truncated stream went wrong; only
+// failures of the underlying reader are reported as io errors. A non-empty prior_snapshot
+// is loaded first, as load_prior_snapshot does for DivansDecompressorReader.
+pub fn verify_stream<R:Read>(mut reader: R, mut buffer_size: usize, multithread: bool,
+                             limits: interface::DivansDecompressorLimits,
+                             prior_snapshot: &[u8]) -> io::Result<Result<u64, DivansError>> {
+    if buffer_size == 0 {
+        buffer_size = 4096;
+    }
+    let mut state = new_decompressor(false, multithread, limits);
+    if !prior_snapshot.is_empty() {
+        if let Err(m) = state.load_prior_snapshot(prior_snapshot) {
+            let located = state.locate_error(m);
+            state.free_ref();
+            return Ok(Err(located));
+        }
+    }
+    let mut input = vec![0u8; buffer_size];
+    let mut scratch = vec![0u8; buffer_size];
+    let mut input_offset = 0usize;
+    let mut input_end = 0usize;
+    let mut decoded = 0u64;
+    let mut needs_input = true;
+    let ret = loop {
+        if needs_input {
+            if input_offset != 0 {
+                for index in input_offset..input_end {
+                    input[index - input_offset] = input[index];
+                }
+                input_end -= input_offset;
+                input_offset = 0;
+            }
+            match read_retrying(&mut reader, &mut input[input_end..]) {
+                // a stream may only end where the decoder reported Success
+                Ok(0) => break Ok(Err(state.locate_error(ErrMsg::UnexpectedEof))),
+                Ok(size) => input_end += size,
+                Err(e) => break Err(e),
+            }
+        }
+        let mut output_offset = 0usize;
+        let res = state.decode(&input[..input_end], &mut input_offset, &mut scratch[..], &mut output_offset);
+        decoded += output_offset as u64;
+        needs_input = false;
+        match res {
+            DivansResult::Failure(m) => break Ok(Err(state.locate_error(m))),
+            DivansResult::NeedsMoreOutput => {},
+            DivansResult::NeedsMoreInput => needs_input = true,
+            DivansResult::Success => {
+                if input_offset != input_end {
+                    // the decoder takes no more input once a stream has ended
+                    let trailing = core::cmp::min(input_end - input_offset, 255) as u8;
+                    break Ok(Err(state.locate_error(ErrMsg::TrailingInput(trailing))));
+                }
+                // further frames may follow the one that just ended
+                input_offset = 0;
+                match read_retrying(&mut reader, &mut input[..]) {
+                    Ok(0) => break Ok(Ok(decoded)),
+                    Ok(size) => input_end = size,
+                    Err(e) => break Err(e),
+                }
+            },
+        }
+    };
+    state.free_ref();
+    ret
+}
+// a region of a salvaged stream that could not be decoded
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct DamagedRange {
+    // compressed bytes from the start of the frame that failed up to where decoding resumed
+    pub compressed_start: u64,
+    pub compressed_end: u64,
+    // output written before the damage; the rest of the damaged frames is missing from here on
+    pub decoded_offset: u64,
+    // the first failure in the range, located within the whole stream
+    pub error: DivansError,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SalvageReport {
+    pub decoded: u64,
+    pub damaged: Vec<DamagedRange>,
+}
+
+fn find_frame_header(compressed: &[u8], from: usize) -> usize {
+    if from < compressed.len() {
+        if let Some(pos) = compressed[from..].windows(interface::MAGIC_NUMBER.len()).position(
+            |candidate| candidate == &interface::MAGIC_NUMBER[..]) {
+            return from + pos;
+        }
+    }
+    compressed.len()
+}
+
+// decodes as much of a truncated or corrupt stream as possible, writing every byte that decodes
+// before a failure. Decoding then resumes at the next frame header, so the frames of a seekable
+// stream that follow the damage are recovered, and each skipped region is listed in the report.
+// Decoding is single threaded so that the output stops where the damage was noticed.
+pub fn salvage_stream<W:io::Write>(compressed: &[u8], mut output: W, mut buffer_size: usize,
+                                   limits: interface::DivansDecompressorLimits) -> io::Result<SalvageReport> {
+    if buffer_size == 0 {
+        buffer_size = 4096;
+    }
+    let mut scratch = vec![0u8; buffer_size];
+    let mut report = SalvageReport::default();
+    let mut start = 0usize;
+    while start < compressed.len() {
+        let mut state = new_decompressor(false, false, limits);
+        let mut input_offset = start;
+        let failure = loop {
+            let mut output_offset = 0usize;
+            let res = state.decode(compressed, &mut input_offset, &mut scratch[..], &mut output_offset);
+            if let Err(e) = output.write_all(&scratch[..output_offset]) {
+                break Err(e);
+            }
+            report.decoded += output_offset as u64;
+            match res {
+                // bytes that follow the last frame without starting another are damage like any other
+                DivansResult::Success => if input_offset == compressed.len() {
+                    break Ok(None);
+                } else {
+                    let trailing = core::cmp::min(compressed.len() - input_offset, 255) as u8;
+                    break Ok(Some(state.locate_error(ErrMsg::TrailingInput(trailing))));
+                },
+                DivansResult::NeedsMoreOutput => {},
+                DivansResult::NeedsMoreInput => break Ok(Some(state.locate_error(ErrMsg::UnexpectedEof))),
+                DivansResult::Failure(m) => break Ok(Some(state.locate_error(m))),
+            }
+        };
+        // the output held back when the failure was noticed was decoded before it
+        let failure = match failure {
+            Ok(Some(error)) => loop {
+                let mut output_offset = 0usize;
+                let res = state.salvage_pending(&mut scratch[..], &mut output_offset);
+                if let Err(e) = output.write_all(&scratch[..output_offset]) {
+                    break Err(e);
+                }
+                report.decoded += output_offset as u64;
+                match res {
+                    DivansOutputResult::NeedsMoreOutput => {},
+                    _ => break Ok(Some(error)),
+                }
+            },
+            other => other,
+        };
+        let frame_start = start + state.frame_start() as usize;
+        state.free_ref();
+        let mut error = match try!(failure) {
+            Some(error) => error,
+            None => break,
+        };
+        error.compressed_offset += start as u64;
+        error.decoded_offset = report.decoded;
+        let resume = find_frame_header(compressed, frame_start + 1);
+        // a false match of the magic number fails straight away and only extends the damage
+        match report.damaged.last_mut() {
+            Some(ref mut last) if last.compressed_end == frame_start as u64 && last.decoded_offset == report.decoded => {
+                last.compressed_end = resume as u64;
+            },
+            _ => report.damaged.push(DamagedRange {
+                compressed_start: frame_start as u64,
+                compressed_end: resume as u64,
+                decoded_offset: report.decoded,
+                error,
+            }),
+        }
+        start = resume;
+    }
+    Ok(report)
+}
+// seeking relies on the seek table at the end of streams written by DivansFramedCompressor
+impl<R:Read+Seek> Seek for DivansDecompressorReader<R> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        if self.seek_table.is_none() {
+            self.seek_table = Some(try!(SeekTable::read_from(&mut self.reader.input)));
+        }
+        let (ref seek_table, base) = *self.seek_table.as_ref().unwrap();
+        let target = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(delta) => if delta < 0 {
+                seek_table.uncompressed_len().checked_sub(delta.wrapping_neg() as u64)
+            } else {
+                seek_table.uncompressed_len().checked_add(delta as u64)
+            },
+            SeekFrom::Current(delta) => if delta < 0 {
+                self.position.checked_sub(delta.wrapping_neg() as u64)
+            } else {
+                self.position.checked_add(delta as u64)
+            },
+        };
+        let target = match target {
+            Some(t) => t,
+            None => return Err(io::Error::new(io::ErrorKind::InvalidInput, "seek to a negative or overflowing position")),
+        };
+        self.reader.compressor.free_ref();
+        self.reader.compressor = new_decompressor(self.skip_crc, self.multithread, self.limits);
+        if let Some(ref prior_snapshot) = self.prior_snapshot {
+            if let Err(e) = self.reader.compressor.load_prior_snapshot(&prior_snapshot[..]) {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, e));
+            }
+        }
+        self.reader.input_offset = 0;
+        self.reader.input_len = 0;
+        self.reader.input_eof = false;
+        self.reader.read_error = None;
+        self.position = target;
+        self.discard = 0;
+        // past the end nothing is decoded: read reports EOF until the next seek
+        if let Some(index) = seek_table.frame_index(target) {
+            let frame = seek_table.frames()[index];
+            try!(self.reader.input.seek(SeekFrom::Start(base + frame.compressed_offset)));
+            self.discard = target - frame.uncompressed_offset;
+        }
+        Ok(target)
     }
 }
 #[cfg(test)]
@@ -443,6 +845,752 @@ mod test {
         assert!(ub.data.len() < data.len());
         print!("Compressed {} to {}...\n", ub.data.len(), data.len());
     }
+    fn compress_for_header_tst(data:&[u8]) -> Vec<u8> {
+        let mut compress = ::DivansExperimentalCompressorReader::<UnlimitedBuffer>::new(
+            UnlimitedBuffer::new(data), interface::DivansCompressorOptions::default(), 4096);
+        let mut compressed = Vec::<u8>::new();
+        compress.read_to_end(&mut compressed).unwrap();
+        compressed
+    }
+    fn decompress_header_tst(compressed:&[u8]) -> Result<Vec<u8>, ::ErrMsg> {
+        let mut decompress = super::DivansDecompressorReader::new(UnlimitedBuffer::new(compressed), 4096, false, false);
+        let mut decompressed = Vec::<u8>::new();
+        match decompress.read_to_end(&mut decompressed) {
+            Ok(_) => Ok(decompressed),
+            Err(e) => Err(e.into_inner().unwrap().downcast::<::DivansError>().unwrap().msg),
+        }
+    }
+    #[test]
+    fn test_header_carries_version_and_features() {
+        let data = include_bytes!("../testdata/random_then_unicode");
+        let compressed = compress_for_header_tst(&data[..4096]);
+        assert_eq!(compressed[interface::HEADER_VERSION_INDEX], interface::FORMAT_VERSION);
+        assert_eq!(interface::StreamFeatures::from_bytes(&compressed[interface::HEADER_FEATURE_FLAGS_INDEX..]).unwrap(), interface::StreamFeatures::compiled());
+        assert_eq!(&decompress_header_tst(&compressed).unwrap()[..], &data[..4096]);
+    }
+    #[test]
+    fn test_header_rejects_unsupported_features() {
+        let data = include_bytes!("../testdata/random_then_unicode");
+        let compressed = compress_for_header_tst(&data[..4096]);
+        let mut future_version = compressed.clone();
+        future_version[interface::HEADER_VERSION_INDEX] = interface::FORMAT_VERSION + 1;
+        match decompress_header_tst(&future_version) {
+            Err(::ErrMsg::UnsupportedFormatVersion(v)) => assert_eq!(v, interface::FORMAT_VERSION + 1),
+            other => panic!("unexpected {:?}", other.map(|x| x.len())),
+        }
+        let mut other_cdf = compressed.clone();
+        other_cdf[interface::HEADER_FEATURE_FLAGS_INDEX] |= 2;
+        match decompress_header_tst(&other_cdf) {
+            Err(::ErrMsg::UnsupportedCDFFlavor(flavor)) => assert!(flavor >= 2),
+            other => panic!("unexpected {:?}", other.map(|x| x.len())),
+        }
+        let mut other_coder = compressed.clone();
+        other_coder[interface::HEADER_FEATURE_FLAGS_INDEX] |= 2 << 2;
+        match decompress_header_tst(&other_coder) {
+            Err(::ErrMsg::UnsupportedEntropyCoder(coder)) => assert!(coder >= 2),
+            other => panic!("unexpected {:?}", other.map(|x| x.len())),
+        }
+        let mut trailer = compressed.clone();
+        trailer[interface::HEADER_FEATURE_FLAGS_INDEX] |= 0x40;
+        assert!(decompress_header_tst(&trailer).is_err());
+        let mut reserved = compressed.clone();
+        reserved[interface::HEADER_LENGTH - 1] = 7;
+        match decompress_header_tst(&reserved) {
+            Err(::ErrMsg::UnknownFeatureFlags(index, value)) => {
+                assert_eq!(index as usize, interface::HEADER_LENGTH - 1);
+                assert_eq!(value, 7);
+            },
+            other => panic!("unexpected {:?}", other.map(|x| x.len())),
+        }
+    }
+    #[test]
+    fn test_every_cdf_flavor_decodes() {
+        let data = include_bytes!("../testdata/random_then_unicode");
+        for flavor in [interface::CDFFlavor::Frequentist, interface::CDFFlavor::Blend].iter() {
+            let mut opts = interface::DivansCompressorOptions::default();
+            opts.cdf_flavor = Some(*flavor);
+            let mut compressed = Vec::<u8>::new();
+            ::DivansExperimentalCompressorReader::<UnlimitedBuffer>::new(
+                UnlimitedBuffer::new(&data[..65536]), opts, 4096).read_to_end(&mut compressed).unwrap();
+            assert_eq!(interface::StreamFeatures::from_bytes(&compressed[interface::HEADER_FEATURE_FLAGS_INDEX..]).unwrap().cdf_flavor, *flavor);
+            for multithread in [false, true].iter() {
+                let mut decompressed = Vec::<u8>::new();
+                super::DivansDecompressorReader::new(UnlimitedBuffer::new(&compressed), 4096, false, *multithread)
+                    .read_to_end(&mut decompressed).unwrap();
+                assert_eq!(&decompressed[..], &data[..65536]);
+            }
+            let mut hybrid = Vec::<u8>::new();
+            ::DivansBrotliHybridCompressorReader::<UnlimitedBuffer>::new(
+                UnlimitedBuffer::new(&data[..65536]), opts, 4096).read_to_end(&mut hybrid).unwrap();
+            assert_eq!(&decompress_header_tst(&hybrid).unwrap()[..], &data[..65536]);
+        }
+    }
+    #[test]
+    fn test_every_entropy_coder_decodes() {
+        let data = include_bytes!("../testdata/random_then_unicode");
+        for coder in [interface::EntropyCoder::ANS, interface::EntropyCoder::Range].iter() {
+            let mut opts = interface::DivansCompressorOptions::default();
+            opts.entropy_coder = Some(*coder);
+            let mut compressed = Vec::<u8>::new();
+            ::DivansExperimentalCompressorReader::<UnlimitedBuffer>::new(
+                UnlimitedBuffer::new(&data[..65536]), opts, 4096).read_to_end(&mut compressed).unwrap();
+            assert_eq!(interface::StreamFeatures::from_bytes(&compressed[interface::HEADER_FEATURE_FLAGS_INDEX..]).unwrap().entropy_coder, *coder);
+            for multithread in [false, true].iter() {
+                // an odd buffer size splits the coder refills across reads
+                let mut decompressed = Vec::<u8>::new();
+                super::DivansDecompressorReader::new(UnlimitedBuffer::new(&compressed), 7, false, *multithread)
+                    .read_to_end(&mut decompressed).unwrap();
+                assert_eq!(&decompressed[..], &data[..65536]);
+            }
+            let mut hybrid = Vec::<u8>::new();
+            ::DivansBrotliHybridCompressorReader::<UnlimitedBuffer>::new(
+                UnlimitedBuffer::new(&data[..65536]), opts, 4096).read_to_end(&mut hybrid).unwrap();
+            assert_eq!(&decompress_header_tst(&hybrid).unwrap()[..], &data[..65536]);
+        }
+    }
+    #[test]
+    fn test_header_rejects_unknown_feature_bits() {
+        match interface::StreamFeatures::from_flags(interface::StreamFeatures::compiled().to_flags() | (1 << 18)) {
+            Err(::ErrMsg::UnknownFeatureFlags(index, value)) => {
+                assert_eq!(index as usize, interface::HEADER_FEATURE_FLAGS_INDEX + 2);
+                assert_eq!(value, 4);
+            },
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+    fn decompress_limits_tst(compressed:&[u8], limits: interface::DivansDecompressorLimits, multithread: bool) -> Result<Vec<u8>, ::ErrMsg> {
+        let mut decompress = super::DivansDecompressorReader::new_with_limits(UnlimitedBuffer::new(compressed), 4096, false, multithread, limits);
+        let mut decompressed = Vec::<u8>::new();
+        match decompress.read_to_end(&mut decompressed) {
+            Ok(_) => Ok(decompressed),
+            Err(e) => Err(e.into_inner().unwrap().downcast::<::DivansError>().unwrap().msg),
+        }
+    }
+    #[test]
+    fn test_decompressor_limits() {
+        type Parser = ::divans_decompressor::HeaderParser<super::HeapAlloc<u8>, super::HeapAlloc<::DefaultCDF16>, super::HeapAlloc<::StaticCommand>>;
+        let data = include_bytes!("../testdata/random_then_unicode");
+        let compressed = compress_for_header_tst(&data[..65536]);
+        let window_size = compressed[interface::HEADER_WINDOW_SIZE_INDEX];
+        for multithread in [false, true].iter() {
+            let mut limits = interface::DivansDecompressorLimits::default();
+            assert_eq!(&decompress_limits_tst(&compressed, limits, *multithread).unwrap()[..], &data[..65536]);
+            limits.max_window_size = Some(window_size);
+            limits.max_output_size = Some(65536);
+            limits.max_memory_estimate = Some(Parser::fixed_memory(usize::from(window_size)) + 65536);
+            assert_eq!(&decompress_limits_tst(&compressed, limits, *multithread).unwrap()[..], &data[..65536]);
+
+            let mut limits = interface::DivansDecompressorLimits::default();
+            limits.max_window_size = Some(window_size - 1);
+            match decompress_limits_tst(&compressed, limits, *multithread) {
+                Err(::ErrMsg::WindowSizeExceedsLimit(ws)) => assert_eq!(ws, window_size),
+                other => panic!("unexpected {:?}", other.map(|x| x.len())),
+            }
+            let mut limits = interface::DivansDecompressorLimits::default();
+            limits.max_output_size = Some(65535);
+            match decompress_limits_tst(&compressed, limits, *multithread) {
+                Err(::ErrMsg::OutputSizeExceedsLimit) => {},
+                other => panic!("unexpected {:?}", other.map(|x| x.len())),
+            }
+            let mut limits = interface::DivansDecompressorLimits::default();
+            limits.max_memory_estimate = Some(1 << window_size);
+            match decompress_limits_tst(&compressed, limits, *multithread) {
+                Err(::ErrMsg::MemoryExceedsLimit) => {},
+                other => panic!("unexpected {:?}", other.map(|x| x.len())),
+            }
+            // leaves room for the model but not for the literals of the random prefix
+            limits.max_memory_estimate = Some(Parser::fixed_memory(usize::from(window_size)) + 16);
+            match decompress_limits_tst(&compressed, limits, *multithread) {
+                Err(::ErrMsg::MemoryExceedsLimit) => {},
+                other => panic!("unexpected {:?}", other.map(|x| x.len())),
+            }
+        }
+    }
+    // the priors a header names are charged against the memory limit before any of the frame is decoded
+    // compresses data with opts, checks that both decoders give it back and returns the stream
+    // with the features its header announces
+    fn literal_model_round_trip_tst(data:&[u8], opts: interface::DivansCompressorOptions) -> (Vec<u8>, interface::StreamFeatures) {
+        let mut compress = ::DivansBrotliHybridCompressorReader::<UnlimitedBuffer>::new(UnlimitedBuffer::new(data), opts, 4096);
+        let mut compressed = Vec::<u8>::new();
+        compress.read_to_end(&mut compressed).unwrap();
+        for multithread in [false, true].iter() {
+            assert_eq!(&decompress_limits_tst(&compressed, interface::DivansDecompressorLimits::default(), *multithread).unwrap()[..], data);
+        }
+        let features = interface::StreamFeatures::from_bytes(&compressed[interface::HEADER_FEATURE_FLAGS_INDEX..]).unwrap();
+        (compressed, features)
+    }
+    #[test]
+    fn test_literal_match_flag() {
+        let data = &include_bytes!("../testdata/alice29")[..16384];
+        let mut opts = interface::DivansCompressorOptions::default();
+        opts.literal_match = true;
+        let (_, features) = literal_model_round_trip_tst(data, opts);
+        assert!(features.literal_mixer && features.literal_match);
+        // the mixer on its own leaves the match model out
+        opts.literal_match = false;
+        opts.literal_mixer = true;
+        let (_, features) = literal_model_round_trip_tst(data, opts);
+        assert!(features.literal_mixer && !features.literal_match);
+    }
+    #[test]
+    fn test_literal_sse() {
+        let data = &include_bytes!("../testdata/alice29")[..65536];
+        let mut sizes = [0usize; 2];
+        for (size, literal_sse) in sizes.iter_mut().zip([false, true].iter()) {
+            let mut opts = interface::DivansCompressorOptions::level(5).unwrap();
+            opts.literal_sse = *literal_sse;
+            let (compressed, features) = literal_model_round_trip_tst(data, opts);
+            assert_eq!(features.literal_sse, *literal_sse);
+            assert!(!features.literal_mixer);
+            *size = compressed.len();
+        }
+        assert!(sizes[1] < sizes[0], "{:?}", sizes);
+    }
+    #[test]
+    fn test_literal_hash_size_gain() {
+        // the hashed contexts pay off on multibyte text, such as the UTF-8 tail of this file
+        let data = &include_bytes!("../testdata/random_then_unicode")[196608..];
+        let mut sizes = [0usize; 2];
+        for (size, literal_hash_size) in sizes.iter_mut().zip([None, Some(18)].iter()) {
+            let mut opts = interface::DivansCompressorOptions::default();
+            opts.literal_mixer = true;
+            opts.speed_detection_quality = Some(1);
+            opts.literal_hash_size = *literal_hash_size;
+            let (compressed, features) = literal_model_round_trip_tst(data, opts);
+            assert_eq!(features.literal_hash_size, literal_hash_size.unwrap_or(0));
+            *size = compressed.len();
+        }
+        // at least half a percent smaller
+        assert!(sizes[1] * 200 < sizes[0] * 199, "{:?}", sizes);
+    }
+    #[test]
+    fn test_feature_memory_limits() {
+        type Parser = ::divans_decompressor::HeaderParser<super::HeapAlloc<u8>, super::HeapAlloc<::DefaultCDF16>, super::HeapAlloc<::StaticCommand>>;
+        let data = include_bytes!("../testdata/alice29");
+        let compressed = compress_for_header_tst(&data[..4096]);
+        let window_size = usize::from(compressed[interface::HEADER_WINDOW_SIZE_INDEX]);
+        let mut limits = interface::DivansDecompressorLimits::default();
+        limits.max_memory_estimate = Some(Parser::fixed_memory(window_size) + 65536);
+        let mut mixer = interface::StreamFeatures::compiled();
+        mixer.literal_mixer = true;
+        let mut sse = interface::StreamFeatures::compiled();
+        sse.literal_sse = true;
+        let mut hash = mixer;
+        hash.literal_hash_size = interface::MIN_LITERAL_HASH_SIZE;
+        for features in [mixer, sse, hash].iter() {
+            let mut costly = compressed.clone();
+            costly[interface::HEADER_FEATURE_FLAGS_INDEX..interface::HEADER_FEATURE_FLAGS_INDEX + interface::HEADER_FEATURE_FLAGS_LENGTH]
+                .clone_from_slice(&features.to_bytes()[..]);
+            for multithread in [false, true].iter() {
+                assert_eq!(&decompress_limits_tst(&compressed, limits, *multithread).unwrap()[..], &data[..4096]);
+                match decompress_limits_tst(&costly, limits, *multithread) {
+                    Err(::ErrMsg::MemoryExceedsLimit) => {},
+                    other => panic!("unexpected {:?} for {:?}", other.map(|x| x.len()), features),
+                }
+            }
+        }
+        // the match priors are counted apart from the mixer they feed
+        let mut opts = interface::DivansCompressorOptions::default();
+        opts.literal_mixer = true;
+        let (mixed, _) = literal_model_round_trip_tst(&data[..4096], opts);
+        let mut matched = mixed.clone();
+        let mut features = interface::StreamFeatures::from_bytes(&mixed[interface::HEADER_FEATURE_FLAGS_INDEX..]).unwrap();
+        features.literal_match = true;
+        matched[interface::HEADER_FEATURE_FLAGS_INDEX..interface::HEADER_FEATURE_FLAGS_INDEX + interface::HEADER_FEATURE_FLAGS_LENGTH]
+            .clone_from_slice(&features.to_bytes()[..]);
+        let mixed_window_size = usize::from(mixed[interface::HEADER_WINDOW_SIZE_INDEX]);
+        let match_memory = ::codec::literal_match_allocation_size::<::DefaultCDF16, super::HeapAlloc<::DefaultCDF16>>();
+        match super::new_decompressor(false, false, limits) {
+            ::DivansDecompressor::Header(mut header) => {
+                header.features = features;
+                let frame_memory = header.frame_memory(mixed_window_size);
+                header.features.literal_match = false;
+                assert_eq!(frame_memory, header.frame_memory(mixed_window_size) + match_memory);
+            },
+            _ => panic!("a new decompressor starts with the header"),
+        }
+        limits.max_memory_estimate = Some(Parser::fixed_memory(mixed_window_size)
+                                          + ::codec::literal_mixer_allocation_size::<::DefaultCDF16, super::HeapAlloc<::DefaultCDF16>>()
+                                          + 65536);
+        for multithread in [false, true].iter() {
+            assert_eq!(&decompress_limits_tst(&mixed, limits, *multithread).unwrap()[..], &data[..4096]);
+        }
+        limits.max_memory_estimate = Some(Parser::fixed_memory(mixed_window_size)
+                                          + ::codec::literal_mixer_allocation_size::<::DefaultCDF16, super::HeapAlloc<::DefaultCDF16>>()
+                                          + match_memory - 1);
+        for multithread in [false, true].iter() {
+            match decompress_limits_tst(&matched, limits, *multithread) {
+                Err(::ErrMsg::MemoryExceedsLimit) => {},
+                other => panic!("unexpected {:?} for {:?}", other.map(|x| x.len()), features),
+            }
+        }
+    }
+    #[test]
+    fn test_prior_snapshot_memory_limit() {
+        type Parser = ::divans_decompressor::HeaderParser<super::HeapAlloc<u8>, super::HeapAlloc<::DefaultCDF16>, super::HeapAlloc<::StaticCommand>>;
+        let data = include_bytes!("../testdata/alice29");
+        let opts = interface::DivansCompressorOptions::default();
+        let snapshot = ::train_prior_snapshot(&[&data[..16384]], opts).unwrap();
+        let mut compress = ::DivansBrotliHybridCompressorReader::<UnlimitedBuffer>::new(UnlimitedBuffer::new(&data[16384..20480]), opts, 4096);
+        assert_eq!(compress.load_prior_snapshot(&snapshot[..]), Ok(()));
+        let mut warm = Vec::<u8>::new();
+        compress.read_to_end(&mut warm).unwrap();
+        let fixed_memory = Parser::fixed_memory(usize::from(warm[interface::HEADER_WINDOW_SIZE_INDEX]));
+        let decompress_warm_tst = |max_memory_estimate: usize, multithread: bool| -> Result<Vec<u8>, ::ErrMsg> {
+            let mut limits = interface::DivansDecompressorLimits::default();
+            limits.max_memory_estimate = Some(max_memory_estimate);
+            let mut decompress = super::DivansDecompressorReader::new_with_limits(UnlimitedBuffer::new(&warm[..]), 4096, false, multithread, limits);
+            decompress.load_prior_snapshot(&snapshot[..])?;
+            let mut decompressed = Vec::<u8>::new();
+            match decompress.read_to_end(&mut decompressed) {
+                Ok(_) => Ok(decompressed),
+                Err(e) => Err(e.into_inner().unwrap().downcast::<::DivansError>().unwrap().msg),
+            }
+        };
+        for multithread in [false, true].iter() {
+            // the allocator refuses a copy of the snapshot larger than the whole budget
+            assert_eq!(decompress_warm_tst(snapshot.len() - 1, *multithread), Err(::ErrMsg::MemoryExceedsLimit));
+            // and the copy it keeps is charged to every frame that starts from it
+            assert_eq!(decompress_warm_tst(fixed_memory + snapshot.len() - 1, *multithread), Err(::ErrMsg::MemoryExceedsLimit));
+            assert_eq!(&decompress_warm_tst(fixed_memory + snapshot.len() + 65536, *multithread).unwrap()[..], &data[16384..20480]);
+        }
+    }
+    #[test]
+    fn test_decompressor_output_limit_spans_frames() {
+        let data = include_bytes!("../testdata/alice29");
+        let compressed = framed_compress_tst(&data[..100000], 30000);
+        for multithread in [false, true].iter() {
+            let mut limits = interface::DivansDecompressorLimits::default();
+            limits.max_output_size = Some(100000);
+            assert_eq!(&decompress_limits_tst(&compressed, limits, *multithread).unwrap()[..], &data[..100000]);
+            limits.max_output_size = Some(70000);
+            match decompress_limits_tst(&compressed, limits, *multithread) {
+                Err(::ErrMsg::OutputSizeExceedsLimit) => {},
+                other => panic!("unexpected {:?}", other.map(|x| x.len())),
+            }
+        }
+    }
+    #[test]
+    fn test_verify_stream() {
+        let data = include_bytes!("../testdata/alice29");
+        let compressed = compress_for_header_tst(&data[..65536]);
+        let framed = framed_compress_tst(&data[..], 40000);
+        let limits = interface::DivansDecompressorLimits::default();
+        for multithread in [false, true].iter() {
+            for buffer_size in [7usize, 4096].iter() {
+                assert_eq!(::verify_stream(&compressed[..], *buffer_size, *multithread, limits, &[]).unwrap(), Ok(65536));
+                assert_eq!(::verify_stream(&framed[..], *buffer_size, *multithread, limits, &[]).unwrap(), Ok(data.len() as u64));
+            }
+            assert_eq!(::verify_stream(&compressed[..compressed.len() - 1], 4096, *multithread, limits, &[]).unwrap().map_err(|e| e.msg),
+                       Err(::ErrMsg::UnexpectedEof));
+            // a byte after a complete stream is reported where it starts rather than waited on
+            let mut trailing = compressed.clone();
+            trailing.push(0);
+            let located = ::verify_stream(&trailing[..], 4096, *multithread, limits, &[]).unwrap().unwrap_err();
+            assert_eq!(located.msg, ::ErrMsg::TrailingInput(1));
+            assert_eq!(located.compressed_offset, compressed.len() as u64);
+            assert_eq!(located.decoded_offset, 65536);
+            // corruption may surface on either thread; neither may be left waiting on the other
+            for index in (1..32).map(|part| compressed.len() * part / 32).chain(Some(compressed.len() - 1)) {
+                let mut corrupt = compressed.clone();
+                corrupt[index] ^= 0x10;
+                assert!(::verify_stream(&corrupt[..], 4096, *multithread, limits, &[]).unwrap().is_err());
+            }
+        }
+    }
+    #[test]
+    fn test_error_location() {
+        let data = include_bytes!("../testdata/alice29");
+        let compressed = compress_for_header_tst(&data[..65536]);
+        let limits = interface::DivansDecompressorLimits::default();
+        for multithread in [false, true].iter() {
+            let truncated = ::verify_stream(&compressed[..compressed.len() - 1], 4096, *multithread, limits, &[]).unwrap().unwrap_err();
+            assert_eq!(truncated.compressed_offset, compressed.len() as u64 - 1);
+            assert!(truncated.decoded_offset <= 65536);
+            assert!(truncated.command_index != 0);
+            let described = format!("{}", truncated);
+            assert!(described.starts_with("the stream ended before it was complete at compressed byte "), "{}", described);
+            for index in (1..8).map(|part| compressed.len() * part / 8) {
+                let mut corrupt = compressed.clone();
+                corrupt[index] ^= 0x10;
+                let mut decompress = super::DivansDecompressorReader::new_with_limits(UnlimitedBuffer::new(&corrupt[..]), 4096, false, *multithread, limits);
+                let mut decompressed = Vec::<u8>::new();
+                let located = decompress.read_to_end(&mut decompressed).unwrap_err().into_inner().unwrap().downcast::<::DivansError>().unwrap();
+                // the failure cannot be noticed before the corrupt byte is read
+                assert!(located.compressed_offset > index as u64);
+                assert!(located.compressed_offset <= compressed.len() as u64);
+                assert!(located.decoded_offset >= decompressed.len() as u64);
+            }
+        }
+    }
+    fn trailer_decode_tst(compressed:&[u8], multithread: bool) -> Result<(Vec<u8>, Option<interface::ContentSummary>), ::ErrMsg> {
+        use ::interface::{Decompressor, DivansResult};
+        let mut state = super::new_decompressor(false, multithread, interface::DivansDecompressorLimits::default());
+        let mut output = [0u8; 4096];
+        let mut decompressed = Vec::<u8>::new();
+        let mut input_offset = 0usize;
+        let ret = loop {
+            let mut output_offset = 0usize;
+            let res = state.decode(compressed, &mut input_offset, &mut output[..], &mut output_offset);
+            decompressed.extend(&output[..output_offset]);
+            match res {
+                DivansResult::NeedsMoreOutput => {},
+                DivansResult::Success => break Ok((decompressed, state.content_summary())),
+                DivansResult::NeedsMoreInput => break Err(::ErrMsg::UnexpectedEof),
+                DivansResult::Failure(m) => break Err(m),
+            }
+        };
+        state.free_ref();
+        ret
+    }
+    #[test]
+    fn test_content_trailer() {
+        use core::hash::Hasher;
+        let data = include_bytes!("../testdata/random_then_unicode");
+        let mut hasher = ::XxHash64::default();
+        hasher.write(&data[..65536]);
+        let expected = interface::ContentSummary{length: 65536, hash: hasher.finish()};
+        let mut opts = interface::DivansCompressorOptions::default();
+        opts.content_trailer = true;
+        let mut plain = Vec::<u8>::new();
+        ::DivansExperimentalCompressorReader::<UnlimitedBuffer>::new(
+            UnlimitedBuffer::new(&data[..65536]), opts, 4096).read_to_end(&mut plain).unwrap();
+        let mut hybrid = Vec::<u8>::new();
+        ::DivansBrotliHybridCompressorReader::<UnlimitedBuffer>::new(
+            UnlimitedBuffer::new(&data[..65536]), opts, 4096).read_to_end(&mut hybrid).unwrap();
+        assert_eq!(plain.len(), compress_for_header_tst(&data[..65536]).len() + interface::TRAILER_LENGTH);
+        for compressed in [&plain, &hybrid].iter() {
+            assert_eq!(interface::ContentSummary::from_stream(&compressed[..]), Some(expected));
+            for multithread in [false, true].iter() {
+                let (decompressed, summary) = trailer_decode_tst(&compressed[..], *multithread).unwrap();
+                assert_eq!(&decompressed[..], &data[..65536]);
+                assert_eq!(summary, Some(expected));
+                let mut bad_length = compressed.to_vec();
+                let length_index = bad_length.len() - interface::TRAILER_LENGTH;
+                bad_length[length_index] ^= 1;
+                assert_eq!(trailer_decode_tst(&bad_length[..], *multithread).err(), Some(::ErrMsg::ContentLengthMismatch));
+                let mut bad_hash = compressed.to_vec();
+                let hash_index = bad_hash.len() - 1;
+                bad_hash[hash_index] ^= 1;
+                assert_eq!(trailer_decode_tst(&bad_hash[..], *multithread).err(), Some(::ErrMsg::ContentHashMismatch));
+                assert_eq!(trailer_decode_tst(&compressed[..compressed.len() - 5], *multithread).err(),
+                           Some(::ErrMsg::UnexpectedEof));
+            }
+        }
+        assert_eq!(trailer_decode_tst(&compress_for_header_tst(&data[..65536]), false).unwrap().1, None);
+        // every frame of a seekable stream carries its own trailer
+        let mut framed = Vec::<u8>::new();
+        ::DivansFramedCompressorReader::<UnlimitedBuffer>::new(
+            UnlimitedBuffer::new(&data[..]), opts, 30000, 4096).read_to_end(&mut framed).unwrap();
+        let limits = interface::DivansDecompressorLimits::default();
+        for multithread in [false, true].iter() {
+            assert_eq!(::verify_stream(&framed[..], 7, *multithread, limits, &[]).unwrap(), Ok(data.len() as u64));
+            let (decompressed, summary) = trailer_decode_tst(&framed[..], *multithread).unwrap();
+            assert_eq!(&decompressed[..], &data[..]);
+            assert_eq!(summary.unwrap().length, ((data.len() - 1) % 30000 + 1) as u64);
+        }
+    }
+    fn framed_compress_tst(data:&[u8], frame_size: usize) -> Vec<u8> {
+        let mut compress = ::DivansFramedCompressorReader::<UnlimitedBuffer>::new(
+            UnlimitedBuffer::new(data), interface::DivansCompressorOptions::default(), frame_size, 4096);
+        let mut compressed = Vec::<u8>::new();
+        compress.read_to_end(&mut compressed).unwrap();
+        compressed
+    }
+    #[test]
+    fn test_framed_stream_decodes_sequentially() {
+        let data = include_bytes!("../testdata/alice29");
+        let compressed = framed_compress_tst(&data[..], 20000);
+        let (seek_table, base) = ::SeekTable::read_from(&mut io::Cursor::new(&compressed[..])).unwrap();
+        assert_eq!(base, 0);
+        assert_eq!(seek_table.frames().len(), (data.len() + 19999) / 20000);
+        assert_eq!(seek_table.uncompressed_len(), data.len() as u64);
+        assert!(interface::StreamFeatures::from_bytes(&compressed[interface::HEADER_FEATURE_FLAGS_INDEX..]).unwrap().framed);
+        for multithread in [false, true].iter() {
+            let mut decompress = super::DivansDecompressorReader::new(UnlimitedBuffer::new(&compressed[..]), 4095, false, *multithread);
+            let mut decompressed = Vec::<u8>::new();
+            decompress.read_to_end(&mut decompressed).unwrap();
+            assert_eq!(&decompressed[..], &data[..]);
+        }
+    }
+    #[test]
+    fn test_parallel_compressor_matches_framed() {
+        let data = include_bytes!("../testdata/alice29");
+        let mut opts = interface::DivansCompressorOptions::default();
+        opts.num_threads = 3;
+        let mut compressed = Vec::<u8>::new();
+        ::DivansParallelCompressorReader::<UnlimitedBuffer>::new(
+            UnlimitedBuffer::new(&data[..]), opts, 20000, 4095).read_to_end(&mut compressed).unwrap();
+        // frames are independent, so threading does not change a single byte
+        assert_eq!(&compressed[..], &framed_compress_tst(&data[..], 20000)[..]);
+        for multithread in [false, true].iter() {
+            let mut decompress = super::DivansDecompressorReader::new(UnlimitedBuffer::new(&compressed[..]), 4095, false, *multithread);
+            let mut decompressed = Vec::<u8>::new();
+            decompress.read_to_end(&mut decompressed).unwrap();
+            assert_eq!(&decompressed[..], &data[..]);
+        }
+        let mut empty = Vec::<u8>::new();
+        ::DivansParallelCompressorReader::<UnlimitedBuffer>::new(
+            UnlimitedBuffer::new(&[]), opts, 20000, 4096).read_to_end(&mut empty).unwrap();
+        assert_eq!(decompress_header_tst(&empty).unwrap().len(), 0);
+    }
+    #[test]
+    fn test_parallel_compressor_prior_snapshot() {
+        let data = include_bytes!("../testdata/alice29");
+        let mut opts = interface::DivansCompressorOptions::default();
+        opts.num_threads = 3;
+        let corpus: Vec<&[u8]> = data[..65536].chunks(16384).collect();
+        let snapshot = ::train_prior_snapshot(&corpus[..], opts).unwrap();
+        let sample = &data[65536..];
+        let mut cold = Vec::<u8>::new();
+        ::DivansParallelCompressorReader::<UnlimitedBuffer>::new(
+            UnlimitedBuffer::new(sample), opts, 8192, 4096).read_to_end(&mut cold).unwrap();
+        let mut compress = ::DivansParallelCompressorReader::<UnlimitedBuffer>::new(
+            UnlimitedBuffer::new(sample), opts, 8192, 4096);
+        compress.load_prior_snapshot(&snapshot[..]).unwrap();
+        let mut warm = Vec::<u8>::new();
+        compress.read_to_end(&mut warm).unwrap();
+        // small frames gain the most from starting with what the corpus taught the model
+        assert!(warm.len() < cold.len());
+        for multithread in [false, true].iter() {
+            let mut decompress = super::DivansDecompressorReader::new(UnlimitedBuffer::new(&warm[..]), 4096, false, *multithread);
+            decompress.load_prior_snapshot(&snapshot[..]).unwrap();
+            let mut decompressed = Vec::<u8>::new();
+            decompress.read_to_end(&mut decompressed).unwrap();
+            assert_eq!(&decompressed[..], sample);
+        }
+        let mut compress = ::DivansParallelCompressorReader::<UnlimitedBuffer>::new(
+            UnlimitedBuffer::new(sample), opts, 8192, 4096);
+        assert_eq!(compress.load_prior_snapshot(&snapshot[..4]), Err(::ErrMsg::PriorSnapshotCorrupt(0)));
+    }
+    #[test]
+    fn test_compression_levels() {
+        let data = include_bytes!("../testdata/alice29");
+        let mut sizes = Vec::<usize>::new();
+        for level in 1u8..13 {
+            let mut compressed = Vec::<u8>::new();
+            ::DivansBrotliHybridCompressorReader::<UnlimitedBuffer>::new(
+                UnlimitedBuffer::new(&data[..65536]), interface::DivansCompressorOptions::level(level).unwrap(), 4096,
+            ).read_to_end(&mut compressed).unwrap();
+            assert_eq!(&decompress_header_tst(&compressed).unwrap()[..], &data[..65536]);
+            sizes.push(compressed.len());
+        }
+        for pair in sizes.windows(2) {
+            assert!(pair[0] >= pair[1], "level sizes not monotonic: {:?}", sizes);
+        }
+        assert!(sizes[0] > sizes[11]);
+        let mut opts = interface::DivansCompressorOptions::default();
+        opts.window_size = Some(18);
+        opts.num_threads = 4;
+        opts.apply_level(12).unwrap();
+        assert_eq!(opts.window_size, Some(18));
+        assert_eq!(opts.num_threads, 4);
+        assert_eq!(opts.divans_ir_optimizer, 2);
+        assert_eq!(opts.prior_bitmask_detection, 1);
+        assert_eq!(opts.dynamic_context_mixing, Some(2));
+        for level in [0u8, 13, 200].iter() {
+            assert_eq!(opts.apply_level(*level), Err(::ErrMsg::BadCompressionLevel(*level)));
+            assert!(interface::DivansCompressorOptions::level(*level).is_err());
+        }
+        // a rejected level leaves the options as they were
+        assert_eq!(opts.quality, Some(10));
+        assert_eq!(opts.divans_ir_optimizer, 2);
+    }
+    // runs each command thread on a std thread but keeps count, as a caller's executor would
+    #[derive(Clone, Default)]
+    struct CountingSpawner {
+        spawned: ::std::sync::Arc<::std::sync::atomic::AtomicUsize>,
+    }
+    impl ::WorkerSpawner for CountingSpawner {
+        type Lock<T> = ::StdWorkerLock<T>;
+        fn new_lock<T>(&self, data: T) -> ::StdWorkerLock<T> {
+            ::StdWorkerLock::new(data)
+        }
+        fn spawn<Job: FnOnce() + Send + 'static>(&self, job: Job) {
+            self.spawned.fetch_add(1, ::std::sync::atomic::Ordering::SeqCst);
+            ::std::thread::spawn(job);
+        }
+    }
+    #[test]
+    fn test_decompressor_uses_caller_spawner() {
+        use ::{Decompressor, DivansDecompressorFactory};
+        let data = include_bytes!("../testdata/alice29");
+        let compressed = framed_compress_tst(&data[..], 20000);
+        let spawner = CountingSpawner::default();
+        let mut decompressor = ::DivansDecompressorFactoryStruct::<super::HeapAlloc<u8>,
+                                                                   super::HeapAlloc<::DefaultCDF16>,
+                                                                   super::HeapAlloc<::StaticCommand>>::new_with_spawner(
+            super::HeapAlloc::<u8>::new(0),
+            super::HeapAlloc::<::DefaultCDF16>::new(::DefaultCDF16::default()),
+            super::HeapAlloc::<::StaticCommand>::new(::StaticCommand::default()),
+            false,
+            true,
+            interface::DivansDecompressorLimits::default(),
+            spawner.clone());
+        let mut decompressed = vec![0u8; data.len()];
+        let mut input_offset = 0usize;
+        let mut output_offset = 0usize;
+        match decompressor.decode(&compressed[..], &mut input_offset, &mut decompressed[..], &mut output_offset) {
+            ::DivansResult::Success => {},
+            other => panic!("unexpected {:?}", other),
+        }
+        decompressor.free();
+        assert_eq!(output_offset, data.len());
+        assert_eq!(&decompressed[..], &data[..]);
+        assert_eq!(spawner.spawned.load(::std::sync::atomic::Ordering::SeqCst), (data.len() + 19999) / 20000);
+    }
+    fn salvage_tst(compressed:&[u8]) -> (Vec<u8>, ::SalvageReport) {
+        let mut salvaged = Vec::<u8>::new();
+        let report = ::salvage_stream(compressed, &mut salvaged, 4096, interface::DivansDecompressorLimits::default()).unwrap();
+        assert_eq!(report.decoded, salvaged.len() as u64);
+        (salvaged, report)
+    }
+    #[test]
+    fn test_salvage_truncated_stream() {
+        let data = include_bytes!("../testdata/alice29");
+        let compressed = compress_for_header_tst(&data[..65536]);
+        let (salvaged, report) = salvage_tst(&compressed[..]);
+        assert_eq!(&salvaged[..], &data[..65536]);
+        assert!(report.damaged.is_empty());
+        // literals are stored towards the end of a frame, so only a late cut leaves some of them
+        for cut in [compressed.len() * 15 / 16, compressed.len() - 1].iter() {
+            let (salvaged, report) = salvage_tst(&compressed[..*cut]);
+            assert!(!salvaged.is_empty());
+            assert_eq!(&salvaged[..], &data[..salvaged.len()]);
+            assert_eq!(report.damaged.len(), 1);
+            assert_eq!(report.damaged[0].compressed_start, 0);
+            assert_eq!(report.damaged[0].compressed_end, *cut as u64);
+            assert_eq!(report.damaged[0].decoded_offset, salvaged.len() as u64);
+            assert_eq!(report.damaged[0].error.msg, ::ErrMsg::UnexpectedEof);
+        }
+        // without its checksum every byte is still recovered, including those not yet flushed
+        let (salvaged, _) = salvage_tst(&compressed[..compressed.len() - 1]);
+        assert_eq!(salvaged.len(), 65536);
+    }
+    #[test]
+    fn test_salvage_resynchronizes_at_next_frame() {
+        let data = include_bytes!("../testdata/alice29");
+        let compressed = framed_compress_tst(&data[..], 20000);
+        let (seek_table, _) = ::SeekTable::read_from(&mut io::Cursor::new(&compressed[..])).unwrap();
+        let frames = seek_table.frames();
+        let mut corrupt = compressed.clone();
+        corrupt[(frames[2].compressed_offset + frames[2].compressed_size / 2) as usize] ^= 0x10;
+        let (salvaged, report) = salvage_tst(&corrupt[..]);
+        assert_eq!(report.damaged.len(), 1);
+        assert_eq!(report.damaged[0].compressed_start, frames[2].compressed_offset);
+        assert_eq!(report.damaged[0].compressed_end, frames[3].compressed_offset);
+        let damage_at = report.damaged[0].decoded_offset as usize;
+        assert!(damage_at >= frames[2].uncompressed_offset as usize);
+        assert_eq!(&salvaged[..frames[2].uncompressed_offset as usize], &data[..frames[2].uncompressed_offset as usize]);
+        let after = data.len() - frames[3].uncompressed_offset as usize;
+        assert_eq!(salvaged.len(), damage_at + after);
+        assert_eq!(&salvaged[damage_at..], &data[frames[3].uncompressed_offset as usize..]);
+        // a file cut short while it was written keeps every complete frame
+        let cut = (frames[3].compressed_offset + 100) as usize;
+        let (salvaged, report) = salvage_tst(&compressed[..cut]);
+        assert!(salvaged.len() >= frames[3].uncompressed_offset as usize);
+        assert_eq!(&salvaged[..], &data[..salvaged.len()]);
+        assert_eq!(report.damaged.len(), 1);
+        assert_eq!(report.damaged[0].compressed_start, frames[3].compressed_offset);
+        assert_eq!(report.damaged[0].error.msg, ::ErrMsg::UnexpectedEof);
+    }
+    #[test]
+    fn test_salvage_trailing_bytes() {
+        let data = include_bytes!("../testdata/alice29");
+        let mut compressed = compress_for_header_tst(&data[..65536]);
+        let end = compressed.len() as u64;
+        compressed.push(0);
+        let (salvaged, report) = salvage_tst(&compressed[..]);
+        assert_eq!(&salvaged[..], &data[..65536]);
+        assert_eq!(report.damaged.len(), 1);
+        assert_eq!(report.damaged[0].compressed_start, end);
+        assert_eq!(report.damaged[0].compressed_end, end + 1);
+        assert_eq!(report.damaged[0].decoded_offset, 65536);
+        assert_eq!(report.damaged[0].error.msg, ::ErrMsg::TrailingInput(1));
+    }
+    #[test]
+    fn test_framed_stream_empty() {
+        let compressed = framed_compress_tst(&[], 4096);
+        let (seek_table, _) = ::SeekTable::read_from(&mut io::Cursor::new(&compressed[..])).unwrap();
+        assert_eq!(seek_table.frames().len(), 1);
+        assert_eq!(seek_table.uncompressed_len(), 0);
+        assert_eq!(decompress_header_tst(&compressed).unwrap().len(), 0);
+    }
+    #[test]
+    fn test_trailing_input_is_rejected() {
+        let data = include_bytes!("../testdata/alice29");
+        let mut compressed = compress_for_header_tst(&data[..65536]);
+        compressed.push(0);
+        // a single stream ends where its last frame does and leaves what follows to the caller
+        for multithread in [false, true].iter() {
+            assert_eq!(&trailer_decode_tst(&compressed[..], *multithread).unwrap().0[..], &data[..65536]);
+            let mut decompress = super::DivansDecompressorReader::new(UnlimitedBuffer::new(&compressed[..]), 4096, false, *multithread);
+            let mut decompressed = Vec::<u8>::new();
+            decompress.read_to_end(&mut decompressed).unwrap();
+            assert_eq!(&decompressed[..], &data[..65536]);
+        }
+        // while the frames of a framed stream announce that more follows them
+        let mut framed = framed_compress_tst(&data[..], 40000);
+        framed.extend(&[0u8; 300][..]);
+        assert_eq!(trailer_decode_tst(&framed[..], false).err(), Some(::ErrMsg::TrailingInput(255)));
+    }
+    #[test]
+    fn test_seek_table_frame_too_large() {
+        let mut seek_table = ::SeekTable::default();
+        seek_table.push(1 << 20, u64::from(u32::max_value()));
+        assert!(seek_table.serialize().is_ok());
+        seek_table.push(1 << 20, 1 << 32);
+        assert_eq!(seek_table.serialize().err(), Some(::ErrMsg::SeekTableFrameTooLarge(1 << 32)));
+    }
+    #[test]
+    fn test_seekable_decompressor_ranges() {
+        let data = include_bytes!("../testdata/asyoulik");
+        let compressed = framed_compress_tst(&data[..], 16384);
+        let mut seekable = ::DivansSeekableDecompressor::new(io::Cursor::new(&compressed[..]), 4096, false, false).unwrap();
+        assert_eq!(seekable.uncompressed_len(), data.len() as u64);
+        for &(start, end) in [(0usize, 1usize), (16383, 16385), (5000, 70000), (0, data.len()),
+                              (data.len() - 3, data.len() + 100), (100, 100)].iter() {
+            let mut output = Vec::<u8>::new();
+            seekable.decode_range(start as u64, end as u64, &mut output).unwrap();
+            assert_eq!(&output[..], &data[start..core::cmp::min(end, data.len())]);
+        }
+    }
+    #[test]
+    fn test_decompressor_reader_seek() {
+        use std::io::{Seek, SeekFrom};
+        let data = include_bytes!("../testdata/alice29");
+        let mut prefixed = vec![7u8; 11];
+        prefixed.extend(framed_compress_tst(&data[..], 32768));
+        let mut cursor = io::Cursor::new(&prefixed[..]);
+        cursor.seek(SeekFrom::Start(11)).unwrap();
+        let mut decompress = super::DivansDecompressorReader::new(cursor, 4096, false, false);
+        let mut buf = [0u8; 100];
+        decompress.read_exact(&mut buf[..]).unwrap();
+        assert_eq!(&buf[..], &data[..100]);
+        assert_eq!(decompress.seek(SeekFrom::Start(100000)).unwrap(), 100000);
+        // an empty read while bytes are still to be discarded is not an end of stream
+        assert_eq!(decompress.read(&mut buf[..0]).unwrap(), 0);
+        decompress.read_exact(&mut buf[..]).unwrap();
+        assert_eq!(&buf[..], &data[100000..100100]);
+        assert_eq!(decompress.seek(SeekFrom::Current(-1000)).unwrap(), 99100);
+        let mut rest = Vec::<u8>::new();
+        decompress.read_to_end(&mut rest).unwrap();
+        assert_eq!(&rest[..], &data[99100..]);
+        assert_eq!(decompress.seek(SeekFrom::End(-50)).unwrap(), data.len() as u64 - 50);
+        decompress.read_exact(&mut buf[..50]).unwrap();
+        assert_eq!(&buf[..50], &data[data.len() - 50..]);
+        decompress.seek(SeekFrom::End(10)).unwrap();
+        assert_eq!(decompress.read(&mut buf[..]).unwrap(), 0);
+        assert!(decompress.seek(SeekFrom::Current(-(data.len() as i64) - 11)).is_err());
+    }
+    #[test]
+    fn test_unframed_stream_is_not_seekable() {
+        use std::io::{Seek, SeekFrom};
+        let data = include_bytes!("../testdata/random_then_unicode");
+        let compressed = compress_for_header_tst(&data[..4096]);
+        let mut decompress = super::DivansDecompressorReader::new(io::Cursor::new(&compressed[..]), 4096, false, false);
+        assert!(decompress.seek(SeekFrom::Start(10)).is_err());
+    }
     #[test]
     fn test_hybrid_reader_compressor_on_alice_small_buffer() {
         hy_reader_tst(include_bytes!("../testdata/alice29"),
@@ -463,6 +1611,16 @@ mod test {
                            prior_bitmask_detection: 1,
                            stride_detection_quality: Some(2),
                            divans_ir_optimizer:0,
+                           cdf_flavor:None,
+                           entropy_coder:None,
+                           content_trailer:false,
+                           num_threads:1,
+                           literal_mixer:false,
+                           literal_sse:false,
+                           literal_hash_size:None,
+                           framed:false,
+                           literal_match:false,
+                           sync_points:false,
                        },
                        1);
     }
@@ -486,6 +1644,16 @@ mod test {
                            speed_detection_quality: None,
                            stride_detection_quality: None,
                            divans_ir_optimizer:1,
+                           cdf_flavor:None,
+                           entropy_coder:None,
+                           content_trailer:false,
+                           num_threads:1,
+                           literal_mixer:false,
+                           literal_sse:false,
+                           literal_hash_size:None,
+                           framed:false,
+                           literal_match:false,
+                           sync_points:false,
                        },
                        4095);
     }
@@ -509,6 +1677,16 @@ mod test {
                            prior_bitmask_detection: 1,
                            stride_detection_quality: None,
                            divans_ir_optimizer:0,
+                           cdf_flavor:None,
+                           entropy_coder:None,
+                           content_trailer:false,
+                           num_threads:1,
+                           literal_mixer:false,
+                           literal_sse:false,
+                           literal_hash_size:None,
+                           framed:false,
+                           literal_match:false,
+                           sync_points:false,
                        },
                        4095);
     }
@@ -532,6 +1710,16 @@ mod test {
                            stride_detection_quality: Some(1),
                            prior_bitmask_detection: 1,
                            divans_ir_optimizer:1,
+                           cdf_flavor:None,
+                           entropy_coder:None,
+                           content_trailer:false,
+                           num_threads:1,
+                           literal_mixer:false,
+                           literal_sse:false,
+                           literal_hash_size:None,
+                           framed:false,
+                           literal_match:false,
+                           sync_points:false,
                        },
                        310000);
     }
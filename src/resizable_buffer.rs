@@ -36,7 +36,7 @@ impl<T:Sized+Default+Clone, AllocT: Allocator<T>> ResizableByteBuffer<T, AllocT>
         if self.data.slice().is_empty() {
             self.data = allocator.alloc_cell(66_000); // some slack room to deal with worst case compression sizes
         } else if self.size + min_size > self.data.slice().len() {
-            let mut cell = allocator.alloc_cell(self.size * 2);
+            let mut cell = allocator.alloc_cell(core::cmp::max(self.size * 2, self.size + min_size));
             cell.slice_mut().split_at_mut(self.size).0.clone_from_slice(self.data.slice().split_at(self.size).0);
             allocator.free_cell(core::mem::replace(&mut self.data, cell));
         }
@@ -51,6 +51,9 @@ impl<T:Sized+Default+Clone, AllocT: Allocator<T>> ResizableByteBuffer<T, AllocT>
     pub fn len(&self) -> usize {
         self.size
     }
+    pub fn clear(&mut self) {
+        self.size = 0;
+    }
     pub fn is_empty(&self) -> bool {
         self.size == 0
     }
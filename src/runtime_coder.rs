@@ -0,0 +1,184 @@
+// Copyright 2017 Dropbox, Inc
+//
+//   Licensed under the Apache License, Version 2.0 (the "License");
+//   you may not use this file except in compliance with the License.
+//   You may obtain a copy of the License at
+//
+//       http://www.apache.org/licenses/LICENSE-2.0
+//
+//   Unless required by applicable law or agreed to in writing, software
+//   distributed under the License is distributed on an "AS IS" BASIS,
+//   WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//   See the License for the specific language governing permissions and
+//   limitations under the License.
+
+use alloc::Allocator;
+use probability::{CDF16, ProbRange};
+use interface::{
+    ArithmeticEncoderOrDecoder,
+    BillingCapability,
+    EntropyCoder,
+    NewWithAllocator,
+    ReadableBytes,
+    WritableBytes,
+};
+use super::DivansResult;
+use ans::{ANSEncoder, ANSDecoder};
+use range_coder::{RangeEncoder, RangeDecoder};
+
+// Entropy coders whose backend is picked from the stream header rather than when the
+// library is built. Both coders of a codec are always constructed with the same backend.
+pub enum RuntimeEncoder<AllocU8:Allocator<u8>> {
+    Ans(ANSEncoder<AllocU8>),
+    Range(RangeEncoder),
+}
+
+impl<AllocU8:Allocator<u8>> NewWithAllocator<AllocU8> for RuntimeEncoder<AllocU8> {
+    fn new(m8: &mut AllocU8) -> Self {
+        Self::new_with_entropy_coder(m8, EntropyCoder::default())
+    }
+    fn new_with_entropy_coder(m8: &mut AllocU8, entropy_coder: EntropyCoder) -> Self {
+        match entropy_coder {
+            EntropyCoder::ANS => RuntimeEncoder::Ans(ANSEncoder::new(m8)),
+            EntropyCoder::Range => RuntimeEncoder::Range(RangeEncoder::new(m8)),
+        }
+    }
+    fn free(&mut self, m8: &mut AllocU8) {
+        match *self {
+            RuntimeEncoder::Ans(ref mut coder) => coder.free(m8),
+            RuntimeEncoder::Range(ref mut coder) => coder.free(m8),
+        }
+    }
+}
+
+impl<AllocU8:Allocator<u8>> ArithmeticEncoderOrDecoder for RuntimeEncoder<AllocU8> {
+    #[inline(always)]
+    fn mov(&mut self) -> Self {
+        match *self {
+            RuntimeEncoder::Ans(ref mut coder) => RuntimeEncoder::Ans(coder.mov()),
+            RuntimeEncoder::Range(ref mut coder) => RuntimeEncoder::Range(coder.mov()),
+        }
+    }
+    #[inline(always)]
+    fn drain_or_fill_internal_buffer_unchecked(&mut self,
+                                               input:&mut ReadableBytes,
+                                               output:&mut WritableBytes) -> DivansResult {
+        match *self {
+            RuntimeEncoder::Ans(ref mut coder) => coder.drain_or_fill_internal_buffer_unchecked(input, output),
+            RuntimeEncoder::Range(ref mut coder) => coder.drain_or_fill_internal_buffer_unchecked(input, output),
+        }
+    }
+    #[inline(always)]
+    fn has_data_to_drain_or_fill(&self) -> bool {
+        match *self {
+            RuntimeEncoder::Ans(ref coder) => coder.has_data_to_drain_or_fill(),
+            RuntimeEncoder::Range(ref coder) => coder.has_data_to_drain_or_fill(),
+        }
+    }
+    #[inline(always)]
+    fn get_or_put_bit_without_billing(&mut self,
+                                      bit: &mut bool,
+                                      prob_of_false: u8) {
+        match *self {
+            RuntimeEncoder::Ans(ref mut coder) => coder.get_or_put_bit_without_billing(bit, prob_of_false),
+            RuntimeEncoder::Range(ref mut coder) => coder.get_or_put_bit_without_billing(bit, prob_of_false),
+        }
+    }
+    #[inline(always)]
+    fn get_or_put_nibble_without_billing<C: CDF16>(&mut self,
+                                                   nibble: &mut u8,
+                                                   prob: &C) -> ProbRange {
+        match *self {
+            RuntimeEncoder::Ans(ref mut coder) => coder.get_or_put_nibble_without_billing(nibble, prob),
+            RuntimeEncoder::Range(ref mut coder) => coder.get_or_put_nibble_without_billing(nibble, prob),
+        }
+    }
+    fn close(&mut self) -> DivansResult {
+        match *self {
+            RuntimeEncoder::Ans(ref mut coder) => coder.close(),
+            RuntimeEncoder::Range(ref mut coder) => coder.close(),
+        }
+    }
+    fn sync(&mut self) -> DivansResult {
+        match *self {
+            RuntimeEncoder::Ans(ref mut coder) => coder.sync(),
+            RuntimeEncoder::Range(ref mut coder) => coder.sync(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub enum RuntimeDecoder {
+    Ans(ANSDecoder),
+    Range(RangeDecoder),
+}
+
+impl<AllocU8:Allocator<u8>> NewWithAllocator<AllocU8> for RuntimeDecoder {
+    fn new(m8: &mut AllocU8) -> Self {
+        Self::new_with_entropy_coder(m8, EntropyCoder::default())
+    }
+    fn new_with_entropy_coder(_m8: &mut AllocU8, entropy_coder: EntropyCoder) -> Self {
+        match entropy_coder {
+            EntropyCoder::ANS => RuntimeDecoder::Ans(ANSDecoder::default()),
+            EntropyCoder::Range => RuntimeDecoder::Range(RangeDecoder::default()),
+        }
+    }
+    fn free(&mut self, _m8: &mut AllocU8) {
+    }
+}
+
+impl ArithmeticEncoderOrDecoder for RuntimeDecoder {
+    #[inline(always)]
+    fn mov(&mut self) -> Self {
+        self.clone()
+    }
+    #[inline(always)]
+    fn drain_or_fill_internal_buffer_unchecked(&mut self,
+                                               input:&mut ReadableBytes,
+                                               output:&mut WritableBytes) -> DivansResult {
+        match *self {
+            RuntimeDecoder::Ans(ref mut coder) => coder.drain_or_fill_internal_buffer_unchecked(input, output),
+            RuntimeDecoder::Range(ref mut coder) => coder.drain_or_fill_internal_buffer_unchecked(input, output),
+        }
+    }
+    #[inline(always)]
+    fn has_data_to_drain_or_fill(&self) -> bool {
+        match *self {
+            RuntimeDecoder::Ans(ref coder) => coder.has_data_to_drain_or_fill(),
+            RuntimeDecoder::Range(ref coder) => coder.has_data_to_drain_or_fill(),
+        }
+    }
+    #[inline(always)]
+    fn get_or_put_bit_without_billing(&mut self,
+                                      bit: &mut bool,
+                                      prob_of_false: u8) {
+        match *self {
+            RuntimeDecoder::Ans(ref mut coder) => coder.get_or_put_bit_without_billing(bit, prob_of_false),
+            RuntimeDecoder::Range(ref mut coder) => coder.get_or_put_bit_without_billing(bit, prob_of_false),
+        }
+    }
+    #[inline(always)]
+    fn get_or_put_nibble_without_billing<C: CDF16>(&mut self,
+                                                   nibble: &mut u8,
+                                                   prob: &C) -> ProbRange {
+        match *self {
+            RuntimeDecoder::Ans(ref mut coder) => coder.get_or_put_nibble_without_billing(nibble, prob),
+            RuntimeDecoder::Range(ref mut coder) => coder.get_or_put_nibble_without_billing(nibble, prob),
+        }
+    }
+    fn close(&mut self) -> DivansResult {
+        match *self {
+            RuntimeDecoder::Ans(ref mut coder) => coder.close(),
+            RuntimeDecoder::Range(ref mut coder) => coder.close(),
+        }
+    }
+    fn sync(&mut self) -> DivansResult {
+        match *self {
+            RuntimeDecoder::Ans(ref mut coder) => coder.sync(),
+            RuntimeDecoder::Range(ref mut coder) => coder.sync(),
+        }
+    }
+}
+
+impl BillingCapability for RuntimeDecoder {
+}
@@ -0,0 +1,517 @@
+// Copyright 2017 Dropbox, Inc
+//
+//   Licensed under the Apache License, Version 2.0 (the "License");
+//   you may not use this file except in compliance with the License.
+//   You may obtain a copy of the License at
+//
+//       http://www.apache.org/licenses/LICENSE-2.0
+//
+//   Unless required by applicable law or agreed to in writing, software
+//   distributed under the License is distributed on an "AS IS" BASIS,
+//   WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//   See the License for the specific language governing permissions and
+//   limitations under the License.
+
+// Seekable streams: the input is cut into frames that are each a complete divans stream,
+// and a seek table describing the frames is appended so any range can be decoded
+// by decoding only the frames that cover it.
+#![cfg(not(feature="no-stdlib"))]
+use core;
+use std::io;
+use std::io::{Read, Seek, SeekFrom};
+use std::vec::Vec;
+use alloc::SliceWrapper;
+use alloc::HeapAlloc;
+use ::brotli;
+use ::byte_util::{read_u32, write_u32};
+use ::interface;
+use ::interface::{Command, Compressor, DivansCompressorFactory, DivansCompressorOptions, BrotliCompressionSetting,
+                  DivansResult, DivansOutputResult, ErrMsg};
+
+pub const DEFAULT_FRAME_SIZE: usize = 1 << 20;
+pub const MAX_FRAME_SIZE: usize = 1 << 30;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct FrameLocation {
+    pub compressed_offset: u64,
+    pub compressed_size: u64,
+    pub uncompressed_offset: u64,
+    pub uncompressed_size: u64,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct SeekTable {
+    frames: Vec<FrameLocation>,
+}
+
+fn corrupt(code: u8) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, ErrMsg::SeekTableCorrupt(code))
+}
+
+impl SeekTable {
+    pub fn push(&mut self, compressed_size: u64, uncompressed_size: u64) {
+        let (compressed_offset, uncompressed_offset) = match self.frames.last() {
+            Some(last) => (last.compressed_offset + last.compressed_size,
+                           last.uncompressed_offset + last.uncompressed_size),
+            None => (0, 0),
+        };
+        self.frames.push(FrameLocation{
+            compressed_offset: compressed_offset,
+            compressed_size: compressed_size,
+            uncompressed_offset: uncompressed_offset,
+            uncompressed_size: uncompressed_size,
+        });
+    }
+    pub fn frames(&self) -> &[FrameLocation] {
+        &self.frames[..]
+    }
+    pub fn uncompressed_len(&self) -> u64 {
+        self.frames.last().map(|f| f.uncompressed_offset + f.uncompressed_size).unwrap_or(0)
+    }
+    // size of all the frames, not counting the seek table itself
+    pub fn frames_compressed_len(&self) -> u64 {
+        self.frames.last().map(|f| f.compressed_offset + f.compressed_size).unwrap_or(0)
+    }
+    pub fn serialized_len(&self) -> usize {
+        interface::SEEK_TABLE_MAGIC.len() + 4 + self.frames.len() * interface::SEEK_TABLE_ENTRY_LENGTH
+            + interface::SEEK_TABLE_FOOTER_LENGTH
+    }
+    // index of the frame holding the given uncompressed offset
+    pub fn frame_index(&self, uncompressed_offset: u64) -> Option<usize> {
+        if uncompressed_offset >= self.uncompressed_len() {
+            return None;
+        }
+        match self.frames.binary_search_by(|f| f.uncompressed_offset.cmp(&uncompressed_offset)) {
+            Ok(mut index) => {
+                // empty frames share an offset with their successor
+                while self.frames[index].uncompressed_size == 0 {
+                    index += 1;
+                }
+                Some(index)
+            },
+            Err(index) => Some(index - 1),
+        }
+    }
+    // the table stores 32 bit sizes, so a frame of 4GiB or more cannot be recorded
+    pub fn serialize(&self) -> Result<Vec<u8>, ErrMsg> {
+        for frame in self.frames.iter() {
+            let size = core::cmp::max(frame.compressed_size, frame.uncompressed_size);
+            if size > u64::from(u32::max_value()) {
+                return Err(ErrMsg::SeekTableFrameTooLarge(size));
+            }
+        }
+        let mut ret = Vec::<u8>::with_capacity(self.serialized_len());
+        ret.extend(&interface::SEEK_TABLE_MAGIC[..]);
+        write_u32(&mut ret, self.frames.len() as u32);
+        for frame in self.frames.iter() {
+            write_u32(&mut ret, frame.compressed_size as u32);
+            write_u32(&mut ret, frame.uncompressed_size as u32);
+        }
+        write_u32(&mut ret, self.frames.len() as u32);
+        ret.extend(&interface::SEEK_TABLE_MAGIC[..]);
+        Ok(ret)
+    }
+    pub fn deserialize(data: &[u8]) -> Result<Self, ErrMsg> {
+        let footer_start = match data.len().checked_sub(interface::SEEK_TABLE_FOOTER_LENGTH) {
+            Some(start) => start,
+            None => return Err(ErrMsg::SeekTableMissing),
+        };
+        if data[footer_start + 4..] != interface::SEEK_TABLE_MAGIC[..] {
+            return Err(ErrMsg::SeekTableMissing);
+        }
+        let num_frames = read_u32(&data[footer_start..]) as usize;
+        let mut ret = SeekTable{frames: Vec::<FrameLocation>::with_capacity(num_frames)};
+        if data.len() != ret.serialized_len() + num_frames * interface::SEEK_TABLE_ENTRY_LENGTH {
+            return Err(ErrMsg::SeekTableCorrupt(0));
+        }
+        if data[..4] != interface::SEEK_TABLE_MAGIC[..] {
+            return Err(ErrMsg::SeekTableCorrupt(1));
+        }
+        if read_u32(&data[4..]) as usize != num_frames {
+            return Err(ErrMsg::SeekTableCorrupt(2));
+        }
+        for entry in data[8..footer_start].chunks(interface::SEEK_TABLE_ENTRY_LENGTH) {
+            ret.push(u64::from(read_u32(entry)), u64::from(read_u32(&entry[4..])));
+        }
+        Ok(ret)
+    }
+    // reads the seek table from the end of the stream, returning it with the position of the first frame
+    pub fn read_from<R:Read+Seek>(reader: &mut R) -> io::Result<(Self, u64)> {
+        let end = try!(reader.seek(SeekFrom::End(0)));
+        if end < interface::SEEK_TABLE_FOOTER_LENGTH as u64 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, ErrMsg::SeekTableMissing));
+        }
+        let mut footer = [0u8; interface::SEEK_TABLE_FOOTER_LENGTH];
+        try!(reader.seek(SeekFrom::End(-(interface::SEEK_TABLE_FOOTER_LENGTH as i64))));
+        try!(reader.read_exact(&mut footer[..]));
+        if footer[4..] != interface::SEEK_TABLE_MAGIC[..] {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, ErrMsg::SeekTableMissing));
+        }
+        let table_len = (interface::SEEK_TABLE_MAGIC.len() + 4 + interface::SEEK_TABLE_FOOTER_LENGTH) as u64
+            + u64::from(read_u32(&footer[..])) * interface::SEEK_TABLE_ENTRY_LENGTH as u64;
+        if table_len > end {
+            return Err(corrupt(3));
+        }
+        let mut data = vec![0u8; table_len as usize];
+        try!(reader.seek(SeekFrom::Start(end - table_len)));
+        try!(reader.read_exact(&mut data[..]));
+        let table = match SeekTable::deserialize(&data[..]) {
+            Ok(table) => table,
+            Err(e) => return Err(io::Error::new(io::ErrorKind::InvalidData, e)),
+        };
+        match (end - table_len).checked_sub(table.frames_compressed_len()) {
+            Some(base) => Ok((table, base)),
+            None => Err(corrupt(4)),
+        }
+    }
+}
+
+type DivansBrotliFactory = ::BrotliDivansHybridCompressorFactory<HeapAlloc<u8>,
+                                                         HeapAlloc<u16>,
+                                                         HeapAlloc<u32>,
+                                                         HeapAlloc<i32>,
+                                                         HeapAlloc<u64>,
+                                                         HeapAlloc<brotli::enc::command::Command>,
+                                                         HeapAlloc<::DefaultCDF16>,
+                                                         HeapAlloc<brotli::enc::util::floatX>,
+                                                         HeapAlloc<brotli::enc::vectorization::Mem256f>,
+                                                         HeapAlloc<brotli::enc::PDF>,
+                                                         HeapAlloc<brotli::enc::StaticCommand>,
+                                                         HeapAlloc<brotli::enc::histogram::HistogramLiteral>,
+                                                         HeapAlloc<brotli::enc::histogram::HistogramCommand>,
+                                                         HeapAlloc<brotli::enc::histogram::HistogramDistance>,
+                                                         HeapAlloc<brotli::enc::cluster::HistogramPair>,
+                                                         HeapAlloc<brotli::enc::histogram::ContextType>,
+                                                         HeapAlloc<brotli::enc::entropy_encode::HuffmanTree>,
+                                                         HeapAlloc<brotli::enc::ZopfliNode>>;
+type DivansBrotliConstructedCompressor = <DivansBrotliFactory as ::DivansCompressorFactory<HeapAlloc<u8>,
+                                                                                           HeapAlloc<u32>,
+                                                                                           HeapAlloc<::DefaultCDF16>>>::ConstructedCompressor;
+type DivansCustomFactory = ::DivansCompressorFactoryStruct<HeapAlloc<u8>,
+                                                         HeapAlloc<::DefaultCDF16>>;
+type DivansCustomConstructedCompressor = <DivansCustomFactory as ::DivansCompressorFactory<HeapAlloc<u8>,
+                                                                                           HeapAlloc<u32>,
+                                                                                           HeapAlloc<::DefaultCDF16>>>::ConstructedCompressor;
+
+pub enum FrameCompressor {
+    Brotli(DivansBrotliConstructedCompressor),
+    Internal(DivansCustomConstructedCompressor),
+}
+
+impl FrameCompressor {
+    pub fn new(mut opts: DivansCompressorOptions) -> Self {
+        opts.framed = true;
+        match opts.use_brotli {
+            BrotliCompressionSetting::UseInternalCommandSelection => FrameCompressor::Internal(
+                DivansCustomFactory::new(HeapAlloc::<u8>::new(0),
+                                         HeapAlloc::<u32>::new(0),
+                                         HeapAlloc::<::DefaultCDF16>::new(::DefaultCDF16::default()),
+                                         opts,
+                                         ())),
+            _ => FrameCompressor::Brotli(
+                DivansBrotliFactory::new(HeapAlloc::<u8>::new(0),
+                                         HeapAlloc::<u32>::new(0),
+                                         HeapAlloc::<::DefaultCDF16>::new(::DefaultCDF16::default()),
+                                         opts,
+                                         (
+                                             HeapAlloc::<u8>::new(0),
+                                             HeapAlloc::<u16>::new(0),
+                                             HeapAlloc::<i32>::new(0),
+                                             HeapAlloc::<brotli::enc::command::Command>::new(brotli::enc::command::Command::default()),
+                                             HeapAlloc::<u64>::new(0),
+                                             HeapAlloc::<brotli::enc::util::floatX>::new(0.0 as brotli::enc::util::floatX),
+                                             HeapAlloc::<brotli::enc::vectorization::Mem256f>::new(brotli::enc::vectorization::Mem256f::default()),
+                                             HeapAlloc::<brotli::enc::histogram::HistogramLiteral>::new(brotli::enc::histogram::HistogramLiteral::default()),
+                                             HeapAlloc::<brotli::enc::histogram::HistogramCommand>::new(brotli::enc::histogram::HistogramCommand::default()),
+                                             HeapAlloc::<brotli::enc::histogram::HistogramDistance>::new(brotli::enc::histogram::HistogramDistance::default()),
+                                             HeapAlloc::<brotli::enc::cluster::HistogramPair>::new(brotli::enc::cluster::HistogramPair::default()),
+                                             HeapAlloc::<brotli::enc::histogram::ContextType>::new(brotli::enc::histogram::ContextType::default()),
+                                             HeapAlloc::<brotli::enc::entropy_encode::HuffmanTree>::new(brotli::enc::entropy_encode::HuffmanTree::default()),
+                                             HeapAlloc::<brotli::enc::ZopfliNode>::new(brotli::enc::ZopfliNode::default()),
+                                             HeapAlloc::<brotli::enc::PDF>::new(brotli::enc::PDF::default()),
+                                             HeapAlloc::<brotli::enc::StaticCommand>::new(brotli::enc::StaticCommand::default()),
+                                         ))),
+        }
+    }
+    // starts the frame from a prior snapshot; must be called before anything is encoded
+    pub fn load_prior_snapshot(&mut self, prior_snapshot: &[u8]) -> Result<(), ErrMsg> {
+        match *self {
+            FrameCompressor::Brotli(ref mut c) => c.load_prior_snapshot(prior_snapshot),
+            FrameCompressor::Internal(ref mut c) => c.load_prior_snapshot(prior_snapshot),
+        }
+    }
+    pub fn free(self) {
+        match self {
+            FrameCompressor::Brotli(c) => {c.free();},
+            FrameCompressor::Internal(c) => {c.free();},
+        }
+    }
+}
+
+impl Compressor for FrameCompressor {
+    fn encode(&mut self,
+              input: &[u8],
+              input_offset: &mut usize,
+              output: &mut [u8],
+              output_offset: &mut usize) -> DivansResult {
+        match *self {
+            FrameCompressor::Brotli(ref mut c) => c.encode(input, input_offset, output, output_offset),
+            FrameCompressor::Internal(ref mut c) => c.encode(input, input_offset, output, output_offset),
+        }
+    }
+    fn encode_commands<SliceType:SliceWrapper<u8>+Default>(&mut self,
+                                                           input:&[Command<SliceType>],
+                                                           input_offset : &mut usize,
+                                                           output :&mut[u8],
+                                                           output_offset: &mut usize) -> DivansOutputResult {
+        match *self {
+            FrameCompressor::Brotli(ref mut c) => c.encode_commands(input, input_offset, output, output_offset),
+            FrameCompressor::Internal(ref mut c) => c.encode_commands(input, input_offset, output, output_offset),
+        }
+    }
+    fn flush(&mut self,
+             output: &mut [u8],
+             output_offset: &mut usize) -> DivansOutputResult {
+        match *self {
+            FrameCompressor::Brotli(ref mut c) => c.flush(output, output_offset),
+            FrameCompressor::Internal(ref mut c) => c.flush(output, output_offset),
+        }
+    }
+    fn sync_flush(&mut self,
+                  output: &mut [u8],
+                  output_offset: &mut usize) -> DivansOutputResult {
+        match *self {
+            FrameCompressor::Brotli(ref mut c) => c.sync_flush(output, output_offset),
+            FrameCompressor::Internal(ref mut c) => c.sync_flush(output, output_offset),
+        }
+    }
+}
+
+fn command_len<SliceType:SliceWrapper<u8>>(cmd: &Command<SliceType>) -> u64 {
+    match *cmd {
+        Command::Literal(ref lit) => lit.data.slice().len() as u64,
+        Command::Copy(ref copy) => u64::from(copy.num_bytes),
+        Command::Dict(ref dict) => u64::from(dict.final_size),
+        _ => 0,
+    }
+}
+
+pub struct DivansFramedCompressor {
+    opts: DivansCompressorOptions,
+    frame_size: usize,
+    frame: Option<FrameCompressor>,
+    frame_uncompressed: u64,
+    frame_compressed: u64,
+    seek_table: SeekTable,
+    serialized_table: Vec<u8>,
+    table_progress: usize,
+}
+
+impl DivansFramedCompressor {
+    pub fn new(opts: DivansCompressorOptions, frame_size: usize) -> Self {
+        DivansFramedCompressor {
+            opts: opts,
+            frame_size: core::cmp::max(1, core::cmp::min(frame_size, MAX_FRAME_SIZE)),
+            frame: None,
+            frame_uncompressed: 0,
+            frame_compressed: 0,
+            seek_table: SeekTable::default(),
+            serialized_table: Vec::<u8>::new(),
+            table_progress: 0,
+        }
+    }
+    pub fn seek_table(&self) -> &SeekTable {
+        &self.seek_table
+    }
+    fn current_frame(&mut self) -> &mut FrameCompressor {
+        if self.frame.is_none() {
+            self.frame = Some(FrameCompressor::new(self.opts));
+        }
+        self.frame.as_mut().unwrap()
+    }
+    fn finish_frame(&mut self, output: &mut [u8], output_offset: &mut usize) -> DivansOutputResult {
+        let old_output_offset = *output_offset;
+        let ret = self.current_frame().flush(output, output_offset);
+        self.frame_compressed += (*output_offset - old_output_offset) as u64;
+        match ret {
+            DivansOutputResult::Success => {},
+            need_something => return need_something,
+        }
+        if let Some(frame) = self.frame.take() {
+            frame.free();
+        }
+        self.seek_table.push(self.frame_compressed, self.frame_uncompressed);
+        self.frame_compressed = 0;
+        self.frame_uncompressed = 0;
+        DivansOutputResult::Success
+    }
+}
+
+impl Compressor for DivansFramedCompressor {
+    fn encode(&mut self,
+              input: &[u8],
+              input_offset: &mut usize,
+              output: &mut [u8],
+              output_offset: &mut usize) -> DivansResult {
+        if !self.serialized_table.is_empty() {
+            return DivansResult::Failure(ErrMsg::NotAllowedToEncodeAfterFlush);
+        }
+        loop {
+            if self.frame_uncompressed == self.frame_size as u64 {
+                match self.finish_frame(output, output_offset) {
+                    DivansOutputResult::Success => {},
+                    need_something => return DivansResult::from(need_something),
+                }
+            }
+            if *input_offset == input.len() {
+                return DivansResult::NeedsMoreInput;
+            }
+            let frame_end = core::cmp::min(input.len(),
+                                           *input_offset + self.frame_size - self.frame_uncompressed as usize);
+            let old_input_offset = *input_offset;
+            let old_output_offset = *output_offset;
+            let ret = self.current_frame().encode(input.split_at(frame_end).0, input_offset, output, output_offset);
+            self.frame_uncompressed += (*input_offset - old_input_offset) as u64;
+            self.frame_compressed += (*output_offset - old_output_offset) as u64;
+            match ret {
+                DivansResult::Success | DivansResult::NeedsMoreInput => {},
+                need_something => return need_something,
+            }
+        }
+    }
+    fn encode_commands<SliceType:SliceWrapper<u8>+Default>(&mut self,
+                                                           input:&[Command<SliceType>],
+                                                           input_offset : &mut usize,
+                                                           output :&mut[u8],
+                                                           output_offset: &mut usize) -> DivansOutputResult {
+        // commands are never split, so the frame holding them may exceed the frame size
+        if !self.serialized_table.is_empty() {
+            return DivansOutputResult::Failure(ErrMsg::NotAllowedToEncodeAfterFlush);
+        }
+        if self.frame_uncompressed >= self.frame_size as u64 {
+            match self.finish_frame(output, output_offset) {
+                DivansOutputResult::Success => {},
+                need_something => return need_something,
+            }
+        }
+        let old_input_offset = *input_offset;
+        let old_output_offset = *output_offset;
+        let ret = self.current_frame().encode_commands(input, input_offset, output, output_offset);
+        for cmd in input[old_input_offset..*input_offset].iter() {
+            self.frame_uncompressed += command_len(cmd);
+        }
+        self.frame_compressed += (*output_offset - old_output_offset) as u64;
+        ret
+    }
+    fn sync_flush(&mut self,
+                  output: &mut [u8],
+                  output_offset: &mut usize) -> DivansOutputResult {
+        if !self.serialized_table.is_empty() {
+            return DivansOutputResult::Failure(ErrMsg::NotAllowedToEncodeAfterFlush);
+        }
+        if self.frame.is_none() {
+            return DivansOutputResult::Success; // every finished frame is already out
+        }
+        let old_output_offset = *output_offset;
+        let ret = self.current_frame().sync_flush(output, output_offset);
+        self.frame_compressed += (*output_offset - old_output_offset) as u64;
+        ret
+    }
+    fn flush(&mut self,
+             output: &mut [u8],
+             output_offset: &mut usize) -> DivansOutputResult {
+        if self.serialized_table.is_empty() {
+            // an empty input still produces a single empty frame so the stream is decodable
+            if self.frame.is_some() || self.frame_uncompressed != 0 || self.seek_table.frames().is_empty() {
+                match self.finish_frame(output, output_offset) {
+                    DivansOutputResult::Success => {},
+                    need_something => return need_something,
+                }
+            }
+            self.serialized_table = match self.seek_table.serialize() {
+                Ok(table) => table,
+                Err(e) => return DivansOutputResult::Failure(e),
+            };
+        }
+        let to_copy = core::cmp::min(self.serialized_table.len() - self.table_progress,
+                                     output.len() - *output_offset);
+        output[*output_offset..*output_offset + to_copy].clone_from_slice(
+            &self.serialized_table[self.table_progress..self.table_progress + to_copy]);
+        *output_offset += to_copy;
+        self.table_progress += to_copy;
+        if self.table_progress == self.serialized_table.len() {
+            DivansOutputResult::Success
+        } else {
+            DivansOutputResult::NeedsMoreOutput
+        }
+    }
+}
+
+pub struct DivansSeekableDecompressor<R:Read+Seek> {
+    reader: R,
+    seek_table: SeekTable,
+    base: u64,
+    buffer_size: usize,
+    skip_crc: bool,
+    multithread: bool,
+}
+
+impl<R:Read+Seek> DivansSeekableDecompressor<R> {
+    pub fn new(mut reader: R, buffer_size: usize, skip_crc: bool, multithread: bool) -> io::Result<Self> {
+        let (seek_table, base) = try!(SeekTable::read_from(&mut reader));
+        Ok(DivansSeekableDecompressor {
+            reader: reader,
+            seek_table: seek_table,
+            base: base,
+            buffer_size: buffer_size,
+            skip_crc: skip_crc,
+            multithread: multithread,
+        })
+    }
+    pub fn seek_table(&self) -> &SeekTable {
+        &self.seek_table
+    }
+    pub fn uncompressed_len(&self) -> u64 {
+        self.seek_table.uncompressed_len()
+    }
+    pub fn into_inner(self) -> R {
+        self.reader
+    }
+    // appends the bytes in [start, end) of the uncompressed stream to output
+    pub fn decode_range(&mut self, start: u64, end: u64, output: &mut Vec<u8>) -> io::Result<()> {
+        let end = core::cmp::min(end, self.uncompressed_len());
+        if start >= end {
+            return Ok(());
+        }
+        let mut frame_index = self.seek_table.frame_index(start).unwrap();
+        let mut position = start;
+        while position < end {
+            let frame = self.seek_table.frames()[frame_index];
+            frame_index += 1;
+            if frame.uncompressed_size == 0 {
+                continue;
+            }
+            try!(self.reader.seek(SeekFrom::Start(self.base + frame.compressed_offset)));
+            let mut decompress = ::DivansDecompressorReader::new(
+                (&mut self.reader).take(frame.compressed_size),
+                self.buffer_size,
+                self.skip_crc,
+                self.multithread);
+            let mut skip = position - frame.uncompressed_offset;
+            let mut local_buffer = vec![0u8; core::cmp::max(self.buffer_size, 4096)];
+            let frame_end = core::cmp::min(end, frame.uncompressed_offset + frame.uncompressed_size);
+            while position < frame_end {
+                let size = try!(decompress.read(&mut local_buffer[..]));
+                if size == 0 {
+                    return Err(io::Error::new(io::ErrorKind::UnexpectedEof, ErrMsg::UnexpectedEof));
+                }
+                let skipped = core::cmp::min(skip, size as u64) as usize;
+                skip -= skipped as u64;
+                let used = core::cmp::min((frame_end - position) as usize, size - skipped);
+                output.extend(&local_buffer[skipped..skipped + used]);
+                position += used as u64;
+            }
+        }
+        Ok(())
+    }
+}
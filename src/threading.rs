@@ -7,6 +7,11 @@ use alloc::{SliceWrapper, SliceWrapperMut, Allocator};
 use alloc_util::RepurposingAlloc;
 use ::alloc_util::UninitializedOnAlloc;
 use cmd_to_raw::DivansRecodeState;
+use core::ops::DerefMut;
+#[cfg(feature="no-stdlib")]
+use core::marker::PhantomData;
+#[cfg(not(feature="no-stdlib"))]
+use std::sync::{Arc, Mutex, MutexGuard, Condvar};
 pub enum ThreadData<AllocU8:Allocator<u8>> {
     Data(AllocatedMemoryRange<u8, AllocU8>),
     Yield,
@@ -32,6 +37,7 @@ pub fn empty_prediction_mode_context_map<ISl:SliceWrapper<u8>+Default>() -> Pred
 pub enum CommandResult {
     Ok,
     Eof,
+    Sync, // the encoder flushed after the last command of this batch
     Err(ErrMsg),
 }
 pub trait PullAllocatedCommand<AllocU8:Allocator<u8>, AllocCommand: Allocator<StaticCommand>> {
@@ -55,9 +61,20 @@ pub trait MainToThread<AllocU8:Allocator<u8>> {
     fn broadcast_err(&mut self, err:ErrMsg);
 }
 
+// points at which the codec would work on its literal coder when that coder lives on another thread
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub enum LiteralCoderOp {
+    Drain,
+    Sync,
+    Close,
+}
+
 pub trait ThreadToMain<AllocU8:Allocator<u8>> {
     const COOPERATIVE: bool;
     const ISOLATED: bool;
+    // the encoder codes literal contents elsewhere: literals, literal block switches and context maps
+    // go out through push_cmd and the literal coder is only touched through push_literal_coder_op
+    const REMOTE_LITERAL_CODER: bool = false;
     #[inline(always)]
     fn pull_data(&mut self) -> ThreadData<AllocU8>;
     #[inline(always)]
@@ -83,8 +100,137 @@ pub trait ThreadToMain<AllocU8:Allocator<u8>> {
     fn push_eof(
         &mut self,
     ) -> DivansOutputResult;
+    fn push_sync(
+        &mut self,
+    ) -> DivansOutputResult;
+    fn push_literal_coder_op(&mut self, _op: LiteralCoderOp) -> DivansOutputResult {
+        DivansOutputResult::Success
+    }
     fn broadcast_err(&mut self, err:ErrMsg);
 }
+// A mutex and condition variable around the state a multithreaded frame shares between the
+// caller's thread and its command thread. Clones are handles to the same state.
+pub trait WorkerLock<T>: Clone {
+    type Guard<'a>: DerefMut<Target=T> where Self: 'a;
+    fn lock(&self) -> Self::Guard<'_>;
+    // None while another thread holds the lock
+    fn try_lock(&self) -> Option<Self::Guard<'_>>;
+    // releases the lock until notify_one is called, or spuriously, and takes it back
+    fn wait<'a>(&'a self, guard: Self::Guard<'a>) -> Self::Guard<'a>;
+    fn notify_one(&self);
+}
+
+// Starts the thread that decodes commands for a multithreaded frame. The job returns once the
+// frame is decoded or either side has failed, so it may run on a pool or on a caller's thread.
+pub trait WorkerSpawner: Clone {
+    type Lock<T>: WorkerLock<T>;
+    fn new_lock<T>(&self, data: T) -> Self::Lock<T>;
+    fn spawn<Job: FnOnce() + Send + 'static>(&self, job: Job);
+}
+
+#[cfg(not(feature="no-stdlib"))]
+pub struct StdWorkerLock<T>(Arc<(Mutex<T>, Condvar)>);
+
+#[cfg(not(feature="no-stdlib"))]
+impl<T> StdWorkerLock<T> {
+    pub fn new(data: T) -> Self {
+        StdWorkerLock(Arc::new((Mutex::new(data), Condvar::new())))
+    }
+}
+
+#[cfg(not(feature="no-stdlib"))]
+impl<T> Clone for StdWorkerLock<T> {
+    fn clone(&self) -> Self {
+        StdWorkerLock(self.0.clone())
+    }
+}
+
+#[cfg(not(feature="no-stdlib"))]
+impl<T> WorkerLock<T> for StdWorkerLock<T> {
+    type Guard<'a> = MutexGuard<'a, T> where Self: 'a;
+    fn lock(&self) -> MutexGuard<'_, T> {
+        (self.0).0.lock().unwrap()
+    }
+    fn try_lock(&self) -> Option<MutexGuard<'_, T>> {
+        (self.0).0.try_lock().ok()
+    }
+    fn wait<'a>(&'a self, guard: MutexGuard<'a, T>) -> MutexGuard<'a, T> {
+        (self.0).1.wait(guard).unwrap()
+    }
+    fn notify_one(&self) {
+        (self.0).1.notify_one()
+    }
+}
+
+#[cfg(not(feature="no-stdlib"))]
+#[derive(Clone,Copy,Debug,Default)]
+pub struct StdThreadSpawner {}
+
+#[cfg(not(feature="no-stdlib"))]
+impl WorkerSpawner for StdThreadSpawner {
+    type Lock<T> = StdWorkerLock<T>;
+    fn new_lock<T>(&self, data: T) -> StdWorkerLock<T> {
+        StdWorkerLock::new(data)
+    }
+    fn spawn<Job: FnOnce() + Send + 'static>(&self, job: Job) {
+        ::std::thread::spawn(job);
+    }
+}
+
+#[cfg(not(feature="no-stdlib"))]
+pub type DefaultSpawner = StdThreadSpawner;
+
+// Without std there are no threads unless the caller passes a spawner. No value of this type
+// exists, so a decompressor built without one decodes every frame on the caller's thread.
+#[cfg(feature="no-stdlib")]
+#[derive(Clone,Copy,Debug)]
+pub enum NoSpawner {}
+
+#[cfg(feature="no-stdlib")]
+pub struct NoLock<T>(NoSpawner, PhantomData<T>);
+
+// no value of the type exists, so there is nothing to send
+#[cfg(feature="no-stdlib")]
+unsafe impl<T> Send for NoLock<T> {}
+
+#[cfg(feature="no-stdlib")]
+impl<T> Clone for NoLock<T> {
+    fn clone(&self) -> Self {
+        match self.0 {}
+    }
+}
+
+#[cfg(feature="no-stdlib")]
+impl<T> WorkerLock<T> for NoLock<T> {
+    type Guard<'a> = &'a mut T where Self: 'a;
+    fn lock(&self) -> &mut T {
+        match self.0 {}
+    }
+    fn try_lock(&self) -> Option<&mut T> {
+        match self.0 {}
+    }
+    fn wait<'a>(&'a self, _guard: &'a mut T) -> &'a mut T {
+        match self.0 {}
+    }
+    fn notify_one(&self) {
+        match self.0 {}
+    }
+}
+
+#[cfg(feature="no-stdlib")]
+impl WorkerSpawner for NoSpawner {
+    type Lock<T> = NoLock<T>;
+    fn new_lock<T>(&self, _data: T) -> NoLock<T> {
+        match *self {}
+    }
+    fn spawn<Job: FnOnce() + Send + 'static>(&self, _job: Job) {
+        match *self {}
+    }
+}
+
+#[cfg(feature="no-stdlib")]
+pub type DefaultSpawner = NoSpawner;
+
 pub const NUM_SERIAL_COMMANDS_BUFFERED: usize = 256;
 pub struct SerialWorker<AllocU8:Allocator<u8>, AllocCommand:Allocator<StaticCommand>> {
     data_len: usize,
@@ -110,13 +256,20 @@ impl<AllocU8:Allocator<u8>, AllocCommand:Allocator<StaticCommand>> SerialWorker<
         true
     }
     pub fn result_space_ready(&self) -> bool {
-        self.result.0.len() > self.result.1 as usize
+        self.result.0.len() > self.result.1 as usize && !self.sync_pending()
     }
     pub fn debug_result_commands_ready(&self) -> usize {
         self.result.1 as usize
     }
     pub fn result_multi_space_ready(&self, space_needed:usize) -> bool {
-        self.result.0.len() - self.result.1 as usize >= space_needed
+        self.result.0.len() - self.result.1 as usize >= space_needed && !self.sync_pending()
+    }
+    // commands past a sync point may not join the batch until the main thread has pulled it
+    pub fn sync_pending(&self) -> bool {
+        if let CommandResult::Sync = self.eof_present_in_result {
+            return true;
+        }
+        false
     }
     pub fn cm_space_ready(&self) -> bool {
         self.cm_len != self.cm.len()
@@ -138,6 +291,11 @@ impl<AllocU8:Allocator<u8>, AllocCommand:Allocator<StaticCommand>> SerialWorker<
             self.eof_present_in_result = CommandResult::Eof; // don't want to override errors here
         }
     }
+    pub fn set_sync_hint(&mut self) {
+        if let CommandResult::Ok = self.eof_present_in_result {
+            self.eof_present_in_result = CommandResult::Sync;
+        }
+    }
     #[cold]
     #[inline(never)]
     fn get_failure(&self) -> DivansOutputResult {
@@ -253,7 +411,11 @@ impl<AllocU8:Allocator<u8>, AllocCommand:Allocator<StaticCommand>> MainToThread<
         core::mem::swap(output, &mut self.result);
         core::mem::swap(consumed_data, &mut self.result_data);
         core::mem::swap(pm, &mut self.result_cm);
-        self.eof_present_in_result
+        let ret = self.eof_present_in_result;
+        if self.sync_pending() {
+            self.eof_present_in_result = CommandResult::Ok;
+        }
+        ret
     }
     fn broadcast_err(&mut self, err:ErrMsg) {
         self.err = Some(err);
@@ -408,6 +570,12 @@ impl <AllocU8:Allocator<u8>, WorkerInterface:ThreadToMain<AllocU8>> ThreadToMain
     ) -> DivansOutputResult {
         self.worker.push_eof()
     }
+    #[inline(always)]
+    fn push_sync(
+        &mut self,
+    ) -> DivansOutputResult {
+        self.worker.push_sync()
+    }
     fn broadcast_err(&mut self, err:ErrMsg) {
         self.worker.broadcast_err(err);
     }
@@ -503,7 +671,7 @@ impl<AllocU8:Allocator<u8>, AllocCommand:Allocator<StaticCommand>> ThreadToMain<
         if self.err.is_some() {
             return self.get_failure();
         }
-        if (self.result.1 as usize) < self.result.0.len() {
+        if self.result_space_ready() {
             let (static_cmd, mut opt_cm) = downcast_command(cmd);
             if let Some(ref mut cm) = opt_cm {
                 if self.result_cm[0].has_context_speeds() {
@@ -550,6 +718,18 @@ impl<AllocU8:Allocator<u8>, AllocCommand:Allocator<StaticCommand>> ThreadToMain<
         }
         DivansOutputResult::Success
     }
+    #[inline(always)]
+    fn push_sync(&mut self,
+    ) -> DivansOutputResult {
+        if self.err.is_some() {
+            return self.get_failure();
+        }
+        if self.sync_pending() {
+            return DivansOutputResult::NeedsMoreOutput;
+        }
+        self.set_sync_hint();
+        DivansOutputResult::Success
+    }
     fn broadcast_err(&mut self, err: ErrMsg) {
         self.broadcast_err_internal(err);
     }
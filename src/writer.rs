@@ -15,6 +15,7 @@ use ::brotli::interface::Nop;
 trait Processor {
    fn process(&mut self, input:&[u8], input_offset:&mut usize, output:&mut [u8], output_offset:&mut usize) -> DivansResult;
    fn close(&mut self, output:&mut [u8], output_offset:&mut usize) -> DivansOutputResult;
+   fn sync(&mut self, output:&mut [u8], output_offset:&mut usize) -> DivansOutputResult;
 }
 
 struct GenWriter<W: Write,
@@ -92,6 +93,26 @@ impl<W:Write, C:Processor, BufferType:SliceWrapperMut<u8>> GenWriter<W,C,BufferT
             has_flushed: !needs_flush,
         }
     }
+    pub fn sync_flush(&mut self) -> Result<(), io::Error> {
+        loop {
+            let mut output_offset = 0;
+            let ret = self.compressor.sync(
+                self.output_buffer.slice_mut(),
+                &mut output_offset);
+            match self.output.write_all(&self.output_buffer.slice_mut()[..output_offset]) {
+                Ok(_) => {},
+                Err(e) => return Err(e),
+            }
+            match ret {
+                DivansOutputResult::Failure(m) => {
+                    return Err(io::Error::new(io::ErrorKind::InvalidInput, m))
+                }
+                DivansOutputResult::NeedsMoreOutput => {},
+                DivansOutputResult::Success => break,
+            }
+        }
+        self.output.flush()
+    }
 }
 type DivansBrotliFactory = ::BrotliDivansHybridCompressorFactory<HeapAlloc<u8>,
                                                          HeapAlloc<u16>,
@@ -121,6 +142,9 @@ impl<T:Compressor> Processor for T {
    fn close(&mut self, output:&mut [u8], output_offset:&mut usize) -> DivansOutputResult{
       self.flush(output, output_offset)
    }
+   fn sync(&mut self, output:&mut [u8], output_offset:&mut usize) -> DivansOutputResult{
+      self.sync_flush(output, output_offset)
+   }
 
 }
 pub struct DivansBrotliHybridCompressorWriter<W:Write>(GenWriter<W,
@@ -136,6 +160,10 @@ impl<W:Write> Write for DivansBrotliHybridCompressorWriter<W> {
     }
 }
 impl<W:Write> DivansBrotliHybridCompressorWriter<W> {
+    // makes everything written so far decodable by the receiver without ending the stream
+    pub fn sync_flush(&mut self) -> Result<(), io::Error> {
+        self.0.sync_flush()
+    }
     pub fn new(writer: W, opts: interface::DivansCompressorOptions, mut buffer_size: usize) -> Self {
        if buffer_size == 0 {
           buffer_size = 4096;
@@ -196,6 +224,9 @@ impl<W:Write> Write for DivansExperimentalCompressorWriter<W> {
     }
 }
 impl<W:Write> DivansExperimentalCompressorWriter<W> {
+    pub fn sync_flush(&mut self) -> Result<(), io::Error> {
+        self.0.sync_flush()
+    }
     pub fn new(writer: W, opts: interface::DivansCompressorOptions, mut buffer_size: usize) -> Self {
        if buffer_size == 0 {
           buffer_size = 4096;
@@ -219,6 +250,74 @@ impl<W:Write> DivansExperimentalCompressorWriter<W> {
     }
 }
 
+pub struct DivansFramedCompressorWriter<W:Write>(GenWriter<W,
+                                                          ::DivansFramedCompressor,
+                                                          <HeapAlloc<u8> as Allocator<u8>>::AllocatedMemory,
+                                                          >);
+impl<W:Write> Write for DivansFramedCompressorWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, io::Error> {
+        self.0.write(buf)
+    }
+	fn flush(&mut self) -> Result<(), io::Error> {
+        self.0.flush()
+    }
+}
+impl<W:Write> DivansFramedCompressorWriter<W> {
+    pub fn sync_flush(&mut self) -> Result<(), io::Error> {
+        self.0.sync_flush()
+    }
+    pub fn new(writer: W, opts: interface::DivansCompressorOptions, frame_size: usize, mut buffer_size: usize) -> Self {
+       if buffer_size == 0 {
+          buffer_size = 4096;
+       }
+       let mut m8 = HeapAlloc::<u8>::new(0);
+       let buffer = m8.alloc_cell(buffer_size);
+       DivansFramedCompressorWriter::<W>(
+           GenWriter::<W,
+                       ::DivansFramedCompressor,
+                       <HeapAlloc<u8> as Allocator<u8>>::AllocatedMemory>::new(
+                          writer,
+                          ::DivansFramedCompressor::new(opts, frame_size),
+                          buffer,
+                          true,
+                       ))
+    }
+}
+
+pub struct DivansParallelCompressorWriter<W:Write>(GenWriter<W,
+                                                          ::DivansParallelCompressor,
+                                                          <HeapAlloc<u8> as Allocator<u8>>::AllocatedMemory,
+                                                          >);
+impl<W:Write> Write for DivansParallelCompressorWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, io::Error> {
+        self.0.write(buf)
+    }
+	fn flush(&mut self) -> Result<(), io::Error> {
+        self.0.flush()
+    }
+}
+impl<W:Write> DivansParallelCompressorWriter<W> {
+    pub fn sync_flush(&mut self) -> Result<(), io::Error> {
+        self.0.sync_flush()
+    }
+    pub fn new(writer: W, opts: interface::DivansCompressorOptions, frame_size: usize, mut buffer_size: usize) -> Self {
+       if buffer_size == 0 {
+          buffer_size = 4096;
+       }
+       let mut m8 = HeapAlloc::<u8>::new(0);
+       let buffer = m8.alloc_cell(buffer_size);
+       DivansParallelCompressorWriter::<W>(
+           GenWriter::<W,
+                       ::DivansParallelCompressor,
+                       <HeapAlloc<u8> as Allocator<u8>>::AllocatedMemory>::new(
+                          writer,
+                          ::DivansParallelCompressor::new(opts, frame_size),
+                          buffer,
+                          true,
+                       ))
+    }
+}
+
 
 type StandardDivansDecompressorFactory = ::DivansDecompressorFactoryStruct<HeapAlloc<u8>,
                                                                            HeapAlloc<::DefaultCDF16>,
@@ -243,6 +342,9 @@ impl Processor for DivansConstructedDecompressor {
            DivansResult::Success => DivansOutputResult::Success,
        }
    }
+   fn sync(&mut self, _output:&mut [u8], _output_offset:&mut usize) -> DivansOutputResult{
+       DivansOutputResult::Success // every decoded byte is handed out as soon as it is available
+   }
 
 }
 pub struct DivansDecompressorWriter<W:Write>(GenWriter<W,
@@ -285,6 +387,8 @@ impl<W:Write> DivansDecompressorWriter<W> {
 #[cfg(test)]
 mod test {
     use core;
+    use core::cell::RefCell;
+    use std::rc::Rc;
     use std::vec::Vec;
     use std::io;
     use std::io::Write;
@@ -433,6 +537,16 @@ mod test {
                            stride_detection_quality: Some(2),
                            prior_bitmask_detection: 1,
                            divans_ir_optimizer:1,
+                           cdf_flavor:None,
+                           entropy_coder:None,
+                           content_trailer:false,
+                           num_threads:1,
+                           literal_mixer:false,
+                           literal_sse:false,
+                           literal_hash_size:None,
+                           framed:false,
+                           literal_match:false,
+                           sync_points:false,
                        },
                        1);
     }
@@ -456,6 +570,16 @@ mod test {
                            prior_bitmask_detection: 0,
                            stride_detection_quality: None,
                            divans_ir_optimizer:0,
+                           cdf_flavor:None,
+                           entropy_coder:None,
+                           content_trailer:false,
+                           num_threads:1,
+                           literal_mixer:false,
+                           literal_sse:false,
+                           literal_hash_size:None,
+                           framed:false,
+                           literal_match:false,
+                           sync_points:false,
                        },
                        4095);
     }
@@ -479,6 +603,16 @@ mod test {
                            prior_bitmask_detection: 1,
                            stride_detection_quality: None,
                            divans_ir_optimizer:1,
+                           cdf_flavor:None,
+                           entropy_coder:None,
+                           content_trailer:false,
+                           num_threads:1,
+                           literal_mixer:false,
+                           literal_sse:false,
+                           literal_hash_size:None,
+                           framed:false,
+                           literal_match:false,
+                           sync_points:false,
                        },
                        4095);
     }
@@ -502,7 +636,109 @@ mod test {
                            speed_detection_quality: None,
                            stride_detection_quality: Some(1),
                            divans_ir_optimizer:0,
+                           cdf_flavor:None,
+                           entropy_coder:None,
+                           content_trailer:false,
+                           num_threads:1,
+                           literal_mixer:false,
+                           literal_sse:false,
+                           literal_hash_size:None,
+                           framed:false,
+                           literal_match:false,
+                           sync_points:false,
                        },
                        3);
     }
+    #[derive(Clone)]
+    struct SharedBuffer(Rc<RefCell<Vec<u8>>>);
+    impl io::Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().extend(buf);
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+    trait SyncWriter : Write {
+        fn sync(&mut self) -> io::Result<()>;
+    }
+    impl<W:Write> SyncWriter for super::DivansBrotliHybridCompressorWriter<W> {
+        fn sync(&mut self) -> io::Result<()> {
+            self.sync_flush()
+        }
+    }
+    impl<W:Write> SyncWriter for super::DivansExperimentalCompressorWriter<W> {
+        fn sync(&mut self) -> io::Result<()> {
+            self.sync_flush()
+        }
+    }
+    fn sync_flush_tst<C:SyncWriter, F:FnOnce(super::DivansDecompressorWriter<SharedBuffer>) -> C>(
+        data: &[u8], chunk_size: usize, multithread: bool, new_compressor: F) {
+        let dest = SharedBuffer(Rc::new(RefCell::new(Vec::new())));
+        let mut compress = new_compressor(super::DivansDecompressorWriter::new(dest.clone(), 4096, false, multithread));
+        let mut offset = 0usize;
+        for chunk in data.chunks(chunk_size) {
+            compress.write_all(chunk).unwrap();
+            offset += chunk.len();
+            compress.sync().unwrap();
+            assert_eq!(&dest.0.borrow()[..], &data[..offset]);
+            compress.sync().unwrap(); // nothing new to sync
+            assert_eq!(dest.0.borrow().len(), offset);
+        }
+        compress.flush().unwrap();
+        assert_eq!(&dest.0.borrow()[..], data);
+    }
+    fn sync_flush_coder_tst(entropy_coder: Option<interface::EntropyCoder>) {
+        let data = &include_bytes!("../testdata/alice29")[..10000];
+        let mut opts = interface::DivansCompressorOptions::default();
+        opts.entropy_coder = entropy_coder;
+        opts.sync_points = true;
+        for multithread in [false, true].iter() {
+            sync_flush_tst(data, 2003, *multithread,
+                           |w| super::DivansExperimentalCompressorWriter::new(w, opts, 4096));
+            sync_flush_tst(data, 4001, *multithread,
+                           |w| super::DivansBrotliHybridCompressorWriter::new(w, opts, 4096));
+        }
+    }
+    #[test]
+    fn test_sync_flush_ans() {
+        sync_flush_coder_tst(None);
+    }
+    #[test]
+    fn test_sync_flush_range() {
+        sync_flush_coder_tst(Some(interface::EntropyCoder::Range));
+    }
+    #[test]
+    fn test_sync_points_need_feature_flag() {
+        let data = &include_bytes!("../testdata/alice29")[..8192];
+        let dest = SharedBuffer(Rc::new(RefCell::new(Vec::new())));
+        let mut compress = super::DivansExperimentalCompressorWriter::new(
+            dest.clone(), interface::DivansCompressorOptions::default(), 4096);
+        compress.write_all(&data[..4096]).unwrap();
+        let err = compress.sync_flush().unwrap_err();
+        assert_eq!(*err.into_inner().unwrap().downcast::<::ErrMsg>().unwrap(), ::ErrMsg::SyncPointsNotEnabled);
+
+        let dest = SharedBuffer(Rc::new(RefCell::new(Vec::new())));
+        let mut opts = interface::DivansCompressorOptions::default();
+        opts.sync_points = true;
+        let mut compress = super::DivansExperimentalCompressorWriter::new(dest.clone(), opts, 4096);
+        compress.write_all(&data[..4096]).unwrap();
+        compress.sync_flush().unwrap();
+        compress.write_all(&data[4096..]).unwrap();
+        compress.flush().unwrap();
+        let mut compressed = dest.0.borrow().clone();
+        let features = interface::StreamFeatures::from_bytes(&compressed[interface::HEADER_FEATURE_FLAGS_INDEX..]).unwrap();
+        assert!(features.sync_points);
+        // a stream that does not announce sync points may not contain one
+        let mut unannounced = features;
+        unannounced.sync_points = false;
+        compressed[interface::HEADER_FEATURE_FLAGS_INDEX..interface::HEADER_FEATURE_FLAGS_INDEX + interface::HEADER_FEATURE_FLAGS_LENGTH]
+            .clone_from_slice(&unannounced.to_bytes()[..]);
+        for multithread in [false, true].iter() {
+            let mut decompress = super::DivansDecompressorWriter::new(UnlimitedBuffer::new(&[]), 4096, false, *multithread);
+            let err = decompress.write_all(&compressed[..]).and_then(|_| decompress.flush()).unwrap_err();
+            assert_eq!(*err.into_inner().unwrap().downcast::<::ErrMsg>().unwrap(), ::ErrMsg::CommandCodeOutOfBounds(8));
+        }
+    }
 }
@@ -0,0 +1,151 @@
+// Copyright 2017 Dropbox, Inc
+//
+//   Licensed under the Apache License, Version 2.0 (the "License");
+//   you may not use this file except in compliance with the License.
+//   You may obtain a copy of the License at
+//
+//       http://www.apache.org/licenses/LICENSE-2.0
+//
+//   Unless required by applicable law or agreed to in writing, software
+//   distributed under the License is distributed on an "AS IS" BASIS,
+//   WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//   See the License for the specific language governing permissions and
+//   limitations under the License.
+
+// streaming XXH64 with a zero seed, used to hash the content of a stream for its trailer
+use core;
+use byte_util::{read_u32, read_u64};
+
+const PRIME64_1: u64 = 0x9E37_79B1_85EB_CA87;
+const PRIME64_2: u64 = 0xC2B2_AE3D_27D4_EB4F;
+const PRIME64_3: u64 = 0x1656_67B1_9E37_79F9;
+const PRIME64_4: u64 = 0x85EB_CA77_C2B2_AE63;
+const PRIME64_5: u64 = 0x27D4_EB2F_1656_67C5;
+const STRIPE_LENGTH: usize = 32;
+
+fn round(acc: u64, input: u64) -> u64 {
+    acc.wrapping_add(input.wrapping_mul(PRIME64_2)).rotate_left(31).wrapping_mul(PRIME64_1)
+}
+
+fn merge_round(acc: u64, val: u64) -> u64 {
+    (acc ^ round(0, val)).wrapping_mul(PRIME64_1).wrapping_add(PRIME64_4)
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct XxHash64 {
+    acc: [u64; 4],
+    buffer: [u8; STRIPE_LENGTH],
+    buffered: usize,
+    total_length: u64,
+}
+
+impl Default for XxHash64 {
+    fn default() -> Self {
+        XxHash64 {
+            acc: [PRIME64_1.wrapping_add(PRIME64_2),
+                  PRIME64_2,
+                  0,
+                  0u64.wrapping_sub(PRIME64_1)],
+            buffer: [0u8; STRIPE_LENGTH],
+            buffered: 0,
+            total_length: 0,
+        }
+    }
+}
+
+impl XxHash64 {
+    fn consume_stripe(acc: &mut [u64; 4], stripe: &[u8]) {
+        for (index, lane) in acc.iter_mut().enumerate() {
+            *lane = round(*lane, read_u64(&stripe[index * 8..]));
+        }
+    }
+}
+
+impl core::hash::Hasher for XxHash64 {
+    fn write(&mut self, mut data: &[u8]) {
+        self.total_length += data.len() as u64;
+        if self.buffered != 0 {
+            let to_copy = core::cmp::min(STRIPE_LENGTH - self.buffered, data.len());
+            self.buffer[self.buffered..self.buffered + to_copy].clone_from_slice(&data[..to_copy]);
+            self.buffered += to_copy;
+            data = &data[to_copy..];
+            if self.buffered != STRIPE_LENGTH {
+                return;
+            }
+            Self::consume_stripe(&mut self.acc, &self.buffer);
+            self.buffered = 0;
+        }
+        while data.len() >= STRIPE_LENGTH {
+            Self::consume_stripe(&mut self.acc, &data[..STRIPE_LENGTH]);
+            data = &data[STRIPE_LENGTH..];
+        }
+        self.buffer[..data.len()].clone_from_slice(data);
+        self.buffered = data.len();
+    }
+    fn finish(&self) -> u64 {
+        let mut hash = if self.total_length >= STRIPE_LENGTH as u64 {
+            let mut hash = self.acc[0].rotate_left(1)
+                .wrapping_add(self.acc[1].rotate_left(7))
+                .wrapping_add(self.acc[2].rotate_left(12))
+                .wrapping_add(self.acc[3].rotate_left(18));
+            for lane in self.acc.iter() {
+                hash = merge_round(hash, *lane);
+            }
+            hash
+        } else {
+            self.acc[2].wrapping_add(PRIME64_5)
+        };
+        hash = hash.wrapping_add(self.total_length);
+        let mut tail = &self.buffer[..self.buffered];
+        while tail.len() >= 8 {
+            hash = (hash ^ round(0, read_u64(tail))).rotate_left(27).wrapping_mul(PRIME64_1).wrapping_add(PRIME64_4);
+            tail = &tail[8..];
+        }
+        if tail.len() >= 4 {
+            hash = (hash ^ u64::from(read_u32(tail)).wrapping_mul(PRIME64_1))
+                .rotate_left(23).wrapping_mul(PRIME64_2).wrapping_add(PRIME64_3);
+            tail = &tail[4..];
+        }
+        for byte in tail.iter() {
+            hash = (hash ^ u64::from(*byte).wrapping_mul(PRIME64_5)).rotate_left(11).wrapping_mul(PRIME64_1);
+        }
+        hash ^= hash >> 33;
+        hash = hash.wrapping_mul(PRIME64_2);
+        hash ^= hash >> 29;
+        hash = hash.wrapping_mul(PRIME64_3);
+        hash ^ (hash >> 32)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use core::hash::Hasher;
+    use super::XxHash64;
+    fn xxh64(data: &[u8]) -> u64 {
+        let mut hasher = XxHash64::default();
+        hasher.write(data);
+        hasher.finish()
+    }
+    #[test]
+    fn test_xxh64_known_values() {
+        assert_eq!(xxh64(b""), 0xEF46DB3751D8E999);
+        assert_eq!(xxh64(b"a"), 0xD24EC4F1A98C6E5B);
+        assert_eq!(xxh64(b"abc"), 0x44BC2CF5AD770999);
+        assert_eq!(xxh64(b"The quick brown fox jumps over the lazy dog"), 0x0B242D361FDA71BC);
+    }
+    #[test]
+    fn test_xxh64_split_writes() {
+        let mut data = [0u8; 1000];
+        for (index, item) in data.iter_mut().enumerate() {
+            *item = (index * 7 + index / 13) as u8;
+        }
+        let whole = xxh64(&data[..]);
+        for split in [1usize, 5, 31, 32, 33, 64, 999].iter() {
+            let mut hasher = XxHash64::default();
+            for chunk in data.chunks(*split) {
+                hasher.write(chunk);
+            }
+            assert_eq!(hasher.finish(), whole);
+        }
+    }
+}